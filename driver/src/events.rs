@@ -0,0 +1,678 @@
+//! Delivery of the hypervisor events to user mode through inverted calls.
+//!
+//! A client opens `\\.\Matrix` and keeps `IOCTL_WAIT_EVENTS` requests pending. Each request is held by the driver
+//! until events are raised, and is then completed with them, so the client learns about events as soon as they
+//! happen without polling. The output buffer receives UTF-8 text, one event per line, prefixed by its source,
+//! e.g. `driver: DriverEvent { .. }`. A client keeps several requests pending to receive events while it handles
+//! the previous ones.
+//!
+//! Events are raised in VM-exit context, where IRPs cannot be completed. Every event rings the doorbell of the
+//! hypervisor (see `hypervisor::utils::doorbell`). While requests are pending, a DPC compares the doorbell with
+//! the rings it last handled every `POLL_PERIOD_MS`, which only reads a counter while nothing happens. When the
+//! doorbell moved, the event sources are drained into a staging buffer and as many whole lines as fit are copied
+//! into each pending request. The timer of the DPC is only armed while requests are pending, so the processors
+//! are not woken up while no client is connected, and the events stay in their sources meanwhile, so nothing is
+//! drained from under other consumers. Events that do not fit into the staging buffer are dropped and reported
+//! by a `dropped: <count>` line.
+//!
+//! With the `introspection` feature, `IOCTL_READ_GUEST_FILE` reads a file of the guest from the raw sectors of its
+//! volume, see `hypervisor::utils::ntfs::read_guest_file`. The input buffer holds the offset in the file to read
+//! from as a little-endian u64, followed by the path of the file in UTF-8, e.g. `C:\Windows\System32\config\SAM`.
+//! The output buffer receives the size of the file as a little-endian u64, followed by the bytes read, and the
+//! request completes with the number of bytes written.
+//!
+//! Only SYSTEM and the administrators can open the device, see `DEVICE_SDDL`.
+//!
+//! Reference: https://www.osr.com/nt-insider/2013-issue1/inverted-call-model-kmdf/ and
+//! https://learn.microsoft.com/en-us/windows-hardware/drivers/kernel/cancel-safe-irp-queues
+
+use {
+    crate::HYPERVISOR,
+    alloc::{boxed::Box, vec::Vec},
+    core::{
+        fmt::{self, Write},
+        mem, ptr,
+        sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+    },
+    hypervisor::{
+        intel::vmm::Hypervisor,
+        utils::{doorbell, sync::SpinLock},
+    },
+    wdk_sys::{
+        ntddk::{
+            IoCreateSymbolicLink, IoDeleteDevice, IoDeleteSymbolicLink, IoReleaseCancelSpinLock,
+            IofCompleteRequest, KeCancelTimer, KeFlushQueuedDpcs, KeInitializeDpc,
+            KeInitializeTimer, KeSetTimer,
+        },
+        BOOLEAN, DEVICE_OBJECT, DRIVER_OBJECT, FILE_DEVICE_SECURE_OPEN, FILE_DEVICE_UNKNOWN, GUID,
+        IO_NO_INCREMENT, IO_STACK_LOCATION, IRP, IRP_MJ_CLEANUP, IRP_MJ_CLOSE, IRP_MJ_CREATE,
+        IRP_MJ_DEVICE_CONTROL, KDPC, KTIMER, LARGE_INTEGER, NTSTATUS, NT_SUCCESS, PVOID,
+        SL_PENDING_RETURNED, STATUS_BUFFER_TOO_SMALL, STATUS_CANCELLED,
+        STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_DEVICE_REQUEST, STATUS_PENDING,
+        STATUS_SUCCESS, UNICODE_STRING,
+    },
+};
+
+#[cfg(feature = "introspection")]
+use {
+    hypervisor::{error::HypervisorError, utils::ntfs},
+    wdk_sys::{STATUS_INVALID_PARAMETER, STATUS_NOT_FOUND, STATUS_UNSUCCESSFUL},
+};
+
+/// The name of the device.
+const DEVICE_NAME: &str = "\\Device\\Matrix";
+
+/// The symbolic link user mode opens the device with, as `\\.\Matrix`.
+const SYMBOLIC_LINK_NAME: &str = "\\??\\Matrix";
+
+/// The security descriptor of the device: SYSTEM and the built-in administrators get full access, nobody else
+/// gets any. Protected, so it inherits no other entries.
+const DEVICE_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+/// The device class of the device, under which an administrator can override `DEVICE_SDDL` in the registry.
+/// {8f0b5c1e-3d4a-4b6e-9a27-6c1d2e5f7a90}
+const DEVICE_CLASS_GUID: GUID = GUID {
+    Data1: 0x8f0b_5c1e,
+    Data2: 0x3d4a,
+    Data3: 0x4b6e,
+    Data4: [0x9a, 0x27, 0x6c, 0x1d, 0x2e, 0x5f, 0x7a, 0x90],
+};
+
+/// Waits for events, see the module documentation. METHOD_BUFFERED with FILE_READ_ACCESS, function 0x800.
+pub const IOCTL_WAIT_EVENTS: u32 = (FILE_DEVICE_UNKNOWN << 16) | (1 << 14) | (0x800 << 2);
+
+/// Reads a file of the guest, see the module documentation. METHOD_BUFFERED with FILE_READ_ACCESS, function
+/// 0x801.
+#[cfg(feature = "introspection")]
+pub const IOCTL_READ_GUEST_FILE: u32 = (FILE_DEVICE_UNKNOWN << 16) | (1 << 14) | (0x801 << 2);
+
+/// The length of the offset and of the file size in front of the path and of the bytes of
+/// `IOCTL_READ_GUEST_FILE`.
+#[cfg(feature = "introspection")]
+const FILE_HEADER_LEN: usize = mem::size_of::<u64>();
+
+/// The maximum length of a line, longer lines are truncated. Output buffers must hold at least one line.
+pub const LINE_LEN: usize = 256;
+
+/// The number of requests that can be pending at once.
+const MAX_WAITERS: usize = 8;
+
+/// The size of the buffer holding the events drained but not delivered yet.
+const STAGING_LEN: usize = 16 * 1024;
+
+/// The period of the DPC checking the doorbell, in milliseconds. Rounded up to the clock tick by the kernel.
+const POLL_PERIOD_MS: i64 = 1;
+
+#[link(name = "wdmsec")]
+extern "system" {
+    /// Creates a device object with the security descriptor of an SDDL string, see wdmsec.h.
+    fn IoCreateDeviceSecure(
+        driver_object: *mut DRIVER_OBJECT,
+        device_extension_size: u32,
+        device_name: *mut UNICODE_STRING,
+        device_type: u32,
+        device_characteristics: u32,
+        exclusive: BOOLEAN,
+        default_sddl_string: *const UNICODE_STRING,
+        device_class_guid: *const GUID,
+        device_object: *mut *mut DEVICE_OBJECT,
+    ) -> NTSTATUS;
+}
+
+/// A request pending in `WAITERS`.
+struct PendingIrp(*mut IRP);
+
+// The IRP is owned by the driver until it is completed, whichever processor completes it.
+unsafe impl Send for PendingIrp {}
+
+/// The pending `IOCTL_WAIT_EVENTS` requests.
+static WAITERS: SpinLock<[Option<PendingIrp>; MAX_WAITERS]> =
+    SpinLock::new("event_waiters", [const { None }; MAX_WAITERS]);
+
+/// The events drained but not delivered yet.
+static STAGING: SpinLock<Staging> = SpinLock::new("event_staging", Staging::new());
+
+/// The rings of the doorbell the events were last drained at.
+static DRAINED_RINGS: AtomicU64 = AtomicU64::new(0);
+
+/// The device and the timer of the DPC, while the channel is open.
+static CHANNEL: AtomicPtr<Channel> = AtomicPtr::new(ptr::null_mut());
+
+/// Whether the timer of the DPC is armed, or the DPC runs and re-arms it. Cleared by the DPC, under the lock of
+/// `WAITERS`, once no request is pending.
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+/// The kernel objects of the channel, which must not move while they are in use.
+struct Channel {
+    device: *mut DEVICE_OBJECT,
+    timer: KTIMER,
+    dpc: KDPC,
+}
+
+/// Whole lines of text, appended until full.
+struct Staging {
+    bytes: [u8; STAGING_LEN],
+    len: usize,
+
+    /// The number of events that did not fit, not reported yet.
+    dropped: u64,
+}
+
+impl Staging {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; STAGING_LEN],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Appends a line, or counts it as dropped if it does not fit.
+    fn push(&mut self, line: &Line) {
+        let line = line.as_bytes();
+        match self.bytes.get_mut(self.len..self.len + line.len()) {
+            Some(free) => {
+                free.copy_from_slice(line);
+                self.len += line.len();
+            }
+            None => self.dropped += 1,
+        }
+    }
+
+    /// Removes as many whole lines as fit into `output` from the front, and copies them there.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes copied.
+    fn take_into(&mut self, output: &mut [u8]) -> usize {
+        let available = self.len.min(output.len());
+        let Some(taken) = self
+            .bytes
+            .get(..available)
+            .and_then(|bytes| bytes.iter().rposition(|&byte| byte == b'\n'))
+            .map(|newline| newline + 1)
+        else {
+            return 0;
+        };
+
+        if let (Some(output), Some(lines)) = (output.get_mut(..taken), self.bytes.get(..taken)) {
+            output.copy_from_slice(lines);
+        }
+        self.bytes.copy_within(taken..self.len, 0);
+        self.len -= taken;
+
+        taken
+    }
+}
+
+/// A line formatted on the stack, truncated to `LINE_LEN` and always ending with a newline.
+struct Line {
+    bytes: [u8; LINE_LEN],
+    len: usize,
+}
+
+impl Line {
+    /// Formats the line of an event.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The name of the source of the event.
+    /// * `event` - The event.
+    fn event(source: &str, event: &dyn fmt::Debug) -> Self {
+        let mut line = Self {
+            bytes: [0; LINE_LEN],
+            len: 0,
+        };
+        let _ = write!(line, "{}: {:?}", source, event);
+
+        // The newline replaces the last byte of a truncated line.
+        line.len = line.len.min(LINE_LEN - 1);
+        if let Some(byte) = line.bytes.get_mut(line.len) {
+            *byte = b'\n';
+        }
+        line.len += 1;
+
+        line
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        self.bytes.get(..self.len).unwrap_or(&self.bytes)
+    }
+}
+
+impl Write for Line {
+    /// Appends to the line, silently truncating once it is full.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = self.bytes.get_mut(self.len..).unwrap_or_default();
+        let count = s.len().min(free.len());
+        if let (Some(free), Some(bytes)) = (free.get_mut(..count), s.as_bytes().get(..count)) {
+            free.copy_from_slice(bytes);
+        }
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Creates the device and installs the dispatch routines. The DPC checking the doorbell starts with the first
+/// request, see `queue_waiter`.
+///
+/// # Arguments
+///
+/// * `driver` - The driver object.
+///
+/// # Returns
+///
+/// The status of the creation of the device or of its symbolic link.
+pub fn init(driver: &mut DRIVER_OBJECT) -> NTSTATUS {
+    let mut device_name = wide(DEVICE_NAME);
+    let mut sddl = wide(DEVICE_SDDL);
+    let mut device: *mut DEVICE_OBJECT = ptr::null_mut();
+
+    let status = unsafe {
+        IoCreateDeviceSecure(
+            driver,
+            0,
+            &mut unicode_string(&mut device_name),
+            FILE_DEVICE_UNKNOWN,
+            FILE_DEVICE_SECURE_OPEN,
+            0,
+            &unicode_string(&mut sddl),
+            &DEVICE_CLASS_GUID,
+            &mut device,
+        )
+    };
+    if !NT_SUCCESS(status) {
+        log::error!("Failed to create the event device: {:#x}", status);
+        return status;
+    }
+
+    let mut link_name = wide(SYMBOLIC_LINK_NAME);
+    let status = unsafe {
+        IoCreateSymbolicLink(
+            &mut unicode_string(&mut link_name),
+            &mut unicode_string(&mut device_name),
+        )
+    };
+    if !NT_SUCCESS(status) {
+        log::error!("Failed to create the event device link: {:#x}", status);
+        unsafe { IoDeleteDevice(device) };
+        return status;
+    }
+
+    for major in [
+        IRP_MJ_CREATE,
+        IRP_MJ_CLOSE,
+        IRP_MJ_CLEANUP,
+        IRP_MJ_DEVICE_CONTROL,
+    ] {
+        if let Some(dispatch) = driver.MajorFunction.get_mut(major as usize) {
+            *dispatch = Some(dispatch_irp);
+        }
+    }
+
+    // Events raised before the first client connected are delivered to it.
+    DRAINED_RINGS.store(0, Ordering::Relaxed);
+    POLLING.store(false, Ordering::Relaxed);
+
+    let channel = Box::into_raw(Box::new(Channel {
+        device,
+        timer: unsafe { mem::zeroed() },
+        dpc: unsafe { mem::zeroed() },
+    }));
+
+    unsafe {
+        KeInitializeTimer(&mut (*channel).timer);
+        KeInitializeDpc(&mut (*channel).dpc, Some(deliver_dpc), ptr::null_mut());
+    }
+
+    CHANNEL.store(channel, Ordering::Release);
+    log::debug!("Event device created");
+
+    STATUS_SUCCESS
+}
+
+/// Stops the DPC, fails the pending requests and deletes the device. Must be called before the hypervisor is
+/// dropped.
+pub fn shutdown() {
+    let channel = CHANNEL.swap(ptr::null_mut(), Ordering::AcqRel);
+    if channel.is_null() {
+        return;
+    }
+
+    // A DPC that loaded the channel before it was cleared may re-arm the timer once more, which the second
+    // cancellation catches. DPCs running after that see no channel.
+    for _ in 0..2 {
+        unsafe {
+            KeCancelTimer(&mut (*channel).timer);
+            KeFlushQueuedDpcs();
+        }
+    }
+
+    for irp in take_waiters(MAX_WAITERS, |_| true).into_iter().flatten() {
+        unsafe { complete(irp.0, STATUS_CANCELLED, 0) };
+    }
+
+    let mut link_name = wide(SYMBOLIC_LINK_NAME);
+    unsafe {
+        IoDeleteSymbolicLink(&mut unicode_string(&mut link_name));
+        IoDeleteDevice((*channel).device);
+        drop(Box::from_raw(channel));
+    }
+}
+
+/// Handles the requests to the device.
+unsafe extern "C" fn dispatch_irp(_device: *mut DEVICE_OBJECT, irp: *mut IRP) -> NTSTATUS {
+    let stack = current_stack_location(irp);
+
+    match u32::from((*stack).MajorFunction) {
+        IRP_MJ_CREATE | IRP_MJ_CLOSE => complete(irp, STATUS_SUCCESS, 0),
+        IRP_MJ_CLEANUP => {
+            // The requests of a closed handle are not cancelled by the I/O manager.
+            let file = (*stack).FileObject;
+            let closed = take_waiters(MAX_WAITERS, |waiter| {
+                (*current_stack_location(waiter)).FileObject == file
+            });
+            for waiter in closed.into_iter().flatten() {
+                complete(waiter.0, STATUS_CANCELLED, 0);
+            }
+            complete(irp, STATUS_SUCCESS, 0)
+        }
+        IRP_MJ_DEVICE_CONTROL => {
+            let parameters = (*stack).Parameters.DeviceIoControl;
+            match parameters.IoControlCode {
+                IOCTL_WAIT_EVENTS if (parameters.OutputBufferLength as usize) < LINE_LEN => {
+                    complete(irp, STATUS_BUFFER_TOO_SMALL, 0)
+                }
+                IOCTL_WAIT_EVENTS => queue_waiter(irp),
+                #[cfg(feature = "introspection")]
+                IOCTL_READ_GUEST_FILE => read_guest_file(irp),
+                _ => complete(irp, STATUS_INVALID_DEVICE_REQUEST, 0),
+            }
+        }
+        _ => complete(irp, STATUS_INVALID_DEVICE_REQUEST, 0),
+    }
+}
+
+/// Holds an `IOCTL_WAIT_EVENTS` request until events are raised, see `deliver_dpc`.
+///
+/// # Returns
+///
+/// `STATUS_PENDING`, or the status the request was completed with if it could not be held.
+unsafe fn queue_waiter(irp: *mut IRP) -> NTSTATUS {
+    let mut waiters = WAITERS.lock();
+    let Some(slot) = waiters.iter_mut().find(|slot| slot.is_none()) else {
+        drop(waiters);
+        return complete(irp, STATUS_INSUFFICIENT_RESOURCES, 0);
+    };
+
+    // A request cancelled before its cancel routine is set is never seen by it.
+    swap_cancel_routine(irp, Some(cancel_waiter));
+    if (*irp).Cancel != 0 && swap_cancel_routine(irp, None) {
+        drop(waiters);
+        return complete(irp, STATUS_CANCELLED, 0);
+    }
+
+    (*current_stack_location(irp)).Control |= SL_PENDING_RETURNED as u8;
+    *slot = Some(PendingIrp(irp));
+    drop(waiters);
+
+    if !POLLING.swap(true, Ordering::AcqRel) {
+        arm_timer();
+    }
+
+    STATUS_PENDING
+}
+
+/// Arms the timer of the DPC checking the doorbell for one `POLL_PERIOD_MS`, unless the channel is closing.
+fn arm_timer() {
+    let channel = CHANNEL.load(Ordering::Acquire);
+    if channel.is_null() {
+        return;
+    }
+
+    let due = LARGE_INTEGER {
+        QuadPart: -10_000 * POLL_PERIOD_MS,
+    };
+    unsafe { KeSetTimer(&mut (*channel).timer, due, &mut (*channel).dpc) };
+}
+
+/// Cancels a pending request, unless it is being completed.
+unsafe extern "C" fn cancel_waiter(_device: *mut DEVICE_OBJECT, irp: *mut IRP) {
+    IoReleaseCancelSpinLock((*irp).CancelIrql);
+
+    let removed = WAITERS
+        .lock()
+        .iter_mut()
+        .find(|slot| slot.as_ref().is_some_and(|waiter| waiter.0 == irp))
+        .and_then(Option::take);
+
+    if removed.is_some() {
+        complete(irp, STATUS_CANCELLED, 0);
+    }
+}
+
+/// Removes pending requests matching a predicate, leaving the ones being cancelled to `cancel_waiter`.
+///
+/// # Arguments
+///
+/// * `limit` - The maximum number of requests to remove.
+/// * `predicate` - Whether to remove a request.
+///
+/// # Returns
+///
+/// The requests removed, which the caller must complete.
+fn take_waiters(
+    limit: usize,
+    mut predicate: impl FnMut(*mut IRP) -> bool,
+) -> [Option<PendingIrp>; MAX_WAITERS] {
+    let mut taken = [const { None }; MAX_WAITERS];
+    let mut count = 0;
+    let mut waiters = WAITERS.lock();
+
+    for slot in waiters.iter_mut() {
+        if count == limit {
+            break;
+        }
+
+        let Some(waiter) = slot else {
+            continue;
+        };
+
+        // A request whose cancel routine is already gone is completed by `cancel_waiter`.
+        if !predicate(waiter.0) || !unsafe { swap_cancel_routine(waiter.0, None) } {
+            continue;
+        }
+
+        if let Some(entry) = taken.get_mut(count) {
+            *entry = slot.take();
+            count += 1;
+        }
+    }
+
+    drop(waiters);
+    taken
+}
+
+/// Checks the doorbell and completes the pending requests with the events raised since the last check, then
+/// re-arms its timer while requests are still pending.
+unsafe extern "C" fn deliver_dpc(_dpc: *mut KDPC, _context: PVOID, _arg1: PVOID, _arg2: PVOID) {
+    if poll_events() {
+        arm_timer();
+    }
+}
+
+/// Delivers the events raised since the last check to the pending requests.
+///
+/// # Returns
+///
+/// Whether requests are still pending. If not, `POLLING` was cleared, so the next request re-arms the timer.
+unsafe fn poll_events() -> bool {
+    if !any_waiter() {
+        return false;
+    }
+
+    let rings = doorbell::EVENTS.rings();
+    if rings != DRAINED_RINGS.load(Ordering::Relaxed) {
+        // The hypervisor is being set up or torn down, the next tick tries again.
+        let Some(hypervisor) = HYPERVISOR.try_lock() else {
+            return true;
+        };
+
+        if let Some(hypervisor) = hypervisor.as_ref() {
+            DRAINED_RINGS.store(rings, Ordering::Relaxed);
+            drain_events(hypervisor, &mut STAGING.lock());
+        }
+    }
+
+    while STAGING.lock().len != 0 {
+        let Some(irp) = take_waiters(1, |_| true).into_iter().flatten().next() else {
+            break;
+        };
+        deliver(irp.0);
+    }
+
+    any_waiter()
+}
+
+/// Returns whether requests are pending, and clears `POLLING` if not, both under the lock of `WAITERS`, so a
+/// request queued concurrently either is seen here or finds `POLLING` cleared and re-arms the timer itself.
+fn any_waiter() -> bool {
+    let waiters = WAITERS.lock();
+    let pending = waiters.iter().any(Option::is_some);
+    if !pending {
+        POLLING.store(false, Ordering::Release);
+    }
+
+    pending
+}
+
+/// Completes an `IOCTL_READ_GUEST_FILE` request with the bytes of the file, see the module documentation.
+///
+/// Runs in the context of the caller at PASSIVE_LEVEL, as reading the volume requires.
+#[cfg(feature = "introspection")]
+unsafe fn read_guest_file(irp: *mut IRP) -> NTSTATUS {
+    let parameters = (*current_stack_location(irp)).Parameters.DeviceIoControl;
+    let buffer = (*irp).AssociatedIrp.SystemBuffer as *mut u8;
+
+    // The input and the output share the system buffer, so the request is copied out before anything is written.
+    let input = core::slice::from_raw_parts(buffer, parameters.InputBufferLength as usize).to_vec();
+    let (Some(offset), Some(path)) = (
+        input
+            .get(..FILE_HEADER_LEN)
+            .and_then(|bytes| <[u8; FILE_HEADER_LEN]>::try_from(bytes).ok())
+            .map(u64::from_le_bytes),
+        input
+            .get(FILE_HEADER_LEN..)
+            .and_then(|bytes| core::str::from_utf8(bytes).ok()),
+    ) else {
+        return complete(irp, STATUS_INVALID_PARAMETER, 0);
+    };
+
+    let output = core::slice::from_raw_parts_mut(buffer, parameters.OutputBufferLength as usize);
+    let Some((size, data)) = output.split_at_mut_checked(FILE_HEADER_LEN) else {
+        return complete(irp, STATUS_BUFFER_TOO_SMALL, 0);
+    };
+
+    match ntfs::read_guest_file(path, offset, data) {
+        Ok((read, file_size)) => {
+            size.copy_from_slice(&file_size.to_le_bytes());
+            complete(irp, STATUS_SUCCESS, FILE_HEADER_LEN + read)
+        }
+        Err(HypervisorError::NtfsFileNotFound) => complete(irp, STATUS_NOT_FOUND, 0),
+        Err(error) => {
+            log::error!("Failed to read guest file {}: {:?}", path, error);
+            complete(irp, STATUS_UNSUCCESSFUL, 0)
+        }
+    }
+}
+
+/// Drains the event sources of the hypervisor into the staging buffer.
+///
+/// # Arguments
+///
+/// * `hypervisor` - The hypervisor.
+/// * `staging` - The staging buffer.
+fn drain_events(hypervisor: &Hypervisor, staging: &mut Staging) {
+    if staging.dropped != 0 {
+        let dropped = mem::take(&mut staging.dropped);
+        staging.push(&Line::event("dropped", &dropped));
+    }
+
+    #[cfg(feature = "devices")]
+    hypervisor.drain_keyboard_events(|event| staging.push(&Line::event("keyboard", event)));
+    #[cfg(feature = "devices")]
+    hypervisor.drain_io_accesses(|event| staging.push(&Line::event("io", event)));
+    hypervisor.drain_smi_events(|event| staging.push(&Line::event("smi", event)));
+    hypervisor.drain_region_violations(|event| staging.push(&Line::event("region", event)));
+    hypervisor.drain_tamper_events(|event| staging.push(&Line::event("tamper", event)));
+    hypervisor.drain_driver_events(|event| staging.push(&Line::event("driver", event)));
+    #[cfg(feature = "introspection")]
+    hypervisor.drain_fault_events(|event| staging.push(&Line::event("fault", event)));
+    #[cfg(feature = "introspection")]
+    hypervisor.drain_poison_touches(|event| staging.push(&Line::event("poison", event)));
+}
+
+/// Completes a request taken from `WAITERS` with as many staged lines as fit into its output buffer.
+unsafe fn deliver(irp: *mut IRP) {
+    let length = (*current_stack_location(irp))
+        .Parameters
+        .DeviceIoControl
+        .OutputBufferLength;
+    let output = core::slice::from_raw_parts_mut(
+        (*irp).AssociatedIrp.SystemBuffer as *mut u8,
+        length as usize,
+    );
+
+    let copied = STAGING.lock().take_into(output);
+    complete(irp, STATUS_SUCCESS, copied);
+}
+
+/// Completes a request.
+///
+/// # Returns
+///
+/// The status, for the dispatch routine to return.
+unsafe fn complete(irp: *mut IRP, status: NTSTATUS, information: usize) -> NTSTATUS {
+    (*irp).IoStatus.__bindgen_anon_1.Status = status;
+    (*irp).IoStatus.Information = information as u64;
+    IofCompleteRequest(irp, IO_NO_INCREMENT as i8);
+    status
+}
+
+/// Returns the stack location of the driver in a request, as `IoGetCurrentIrpStackLocation` does.
+unsafe fn current_stack_location(irp: *mut IRP) -> *mut IO_STACK_LOCATION {
+    (*irp)
+        .Tail
+        .Overlay
+        .__bindgen_anon_2
+        .__bindgen_anon_1
+        .CurrentStackLocation
+}
+
+/// Sets the cancel routine of a request atomically, as `IoSetCancelRoutine` does.
+///
+/// # Returns
+///
+/// Whether a cancel routine was set before, i.e. the request is not being cancelled.
+unsafe fn swap_cancel_routine(
+    irp: *mut IRP,
+    routine: Option<unsafe extern "C" fn(*mut DEVICE_OBJECT, *mut IRP)>,
+) -> bool {
+    let field = ptr::addr_of_mut!((*irp).CancelRoutine) as *mut usize;
+    let routine = routine.map_or(0, |routine| routine as usize);
+    AtomicUsize::from_ptr(field).swap(routine, Ordering::AcqRel) != 0
+}
+
+/// Encodes a name as a null-terminated wide string.
+fn wide(name: &str) -> Vec<u16> {
+    name.encode_utf16().chain(core::iter::once(0)).collect()
+}
+
+/// Returns a `UNICODE_STRING` of a wide string from `wide`, valid as long as it is.
+fn unicode_string(wide: &mut [u16]) -> UNICODE_STRING {
+    UNICODE_STRING {
+        Length: ((wide.len() - 1) * 2) as u16,
+        MaximumLength: (wide.len() * 2) as u16,
+        Buffer: wide.as_mut_ptr(),
+    }
+}
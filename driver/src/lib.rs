@@ -37,18 +37,24 @@ use {
             },
             vmm::Hypervisor,
         },
-        utils::{alloc::PhysicalAllocator, nt::update_ntoskrnl_cr3, ssdt::ssdt_hook::SsdtHook},
+        utils::{
+            addresses::Hva, alloc::PhysicalAllocator, early_console, logger,
+            nt::update_ntoskrnl_cr3, ssdt::ssdt_hook::SsdtHook, sync::SpinLock,
+        },
     },
     log::LevelFilter,
     log::{self},
     wdk_sys::{
-        ntddk::MmIsAddressValid, DRIVER_OBJECT, NTSTATUS, PUNICODE_STRING, STATUS_SUCCESS,
-        STATUS_UNSUCCESSFUL,
+        ntddk::MmIsAddressValid, DRIVER_OBJECT, NTSTATUS, NT_SUCCESS, PUNICODE_STRING,
+        STATUS_SUCCESS, STATUS_UNSUCCESSFUL,
     },
 };
 
+pub mod events;
 pub mod expanded_stack;
 pub mod hook;
+#[cfg(feature = "exfil-policy-pack")]
+pub mod policy_pack;
 
 /// The main entry point for the driver.
 ///
@@ -74,19 +80,20 @@ pub unsafe extern "system" fn driver_entry(
     // Due to post-vmlaunch issues with the kernel logger, we transition to using a serial port logger.
     // This logger writes to the host OS via VMware Workstation.
 
-    // Initialize the COM2 port logger with level filter set to Info.
-
-    com_logger::builder()
-        .base(0x2f8)
-        .filter(LevelFilter::Debug)
-        .setup();
+    // Initialize the COM2 port logger with level filter set to Debug. Records of all processors go
+    // through a lock-free ring buffer, so logging from VM-exit context never blocks.
+    if let Err(err) = logger::init(logger::COM2, LevelFilter::Debug) {
+        // Nothing is logged without the logger, so report the failure on the early console instead.
+        early_console::emergency(format_args!("Logger initialization failed: {:?}", err));
+        return STATUS_UNSUCCESSFUL;
+    }
 
     log::debug!("Driver Entry called");
 
     // Remove if manually mapping the kernel driver
     driver.DriverUnload = Some(driver_unload);
 
-    with_expanded_stack(|| {
+    let status = with_expanded_stack(|| {
         match virtualize_system() {
             Ok(_) => log::info!("Virtualized system successfully!"),
             Err(err) => {
@@ -101,7 +108,20 @@ pub unsafe extern "system" fn driver_entry(
         unsafe { MmIsAddressValid(0 as _) };
 
         STATUS_SUCCESS
-    })
+    });
+
+    // User-mode clients receive the events of the hypervisor through the device, which runs without it.
+    if status == STATUS_SUCCESS {
+        let status = events::init(driver);
+        if !NT_SUCCESS(status) {
+            log::error!("Event device creation failed: {:#x}", status);
+            // The unload callback is not called when the entry fails, so devirtualize before failing.
+            driver_unload(driver);
+            return status;
+        }
+    }
+
+    status
 }
 
 /// The unload callback for the driver.
@@ -116,15 +136,21 @@ pub unsafe extern "system" fn driver_entry(
 /// Note: Remove if manually mapping the kernel driver
 pub extern "C" fn driver_unload(_driver: *mut DRIVER_OBJECT) {
     log::trace!("Driver unloaded successfully!");
-    if let Some(mut hypervisor) = unsafe { HYPERVISOR.take() } {
-        drop(hypervisor);
-    }
+    // The pending requests of the clients are failed before the hypervisor goes away.
+    events::shutdown();
+
+    // Take the hypervisor out first, devirtualizing must not happen with the lock held.
+    let hypervisor = HYPERVISOR.lock().take();
+    drop(hypervisor);
+
+    #[cfg(feature = "introspection")]
+    hypervisor::utils::ntfs::release_volumes();
 }
 
 /// The main hypervisor object.
 ///
-/// This static mutable option holds the global instance of the hypervisor used by this driver.
-static mut HYPERVISOR: Option<Hypervisor> = None;
+/// This option holds the global instance of the hypervisor used by this driver.
+static HYPERVISOR: SpinLock<Option<Hypervisor>> = SpinLock::new("hypervisor", None);
 
 /// Attempts to virtualize the system.
 ///
@@ -157,7 +183,7 @@ fn virtualize_system() -> Result<(), HypervisorError> {
     let ssdt_nt_create_file_addy = SsdtHook::find_ssdt_function_address(0x0055, false)?;
 
     let nt_create_file_syscall_hook = Hook::hook_function_ptr(
-        ssdt_nt_create_file_addy.function_address as _,
+        Hva::from_ptr(ssdt_nt_create_file_addy.function_address),
         hook::nt_create_file as *const (),
     )
     .ok_or(HypervisorError::HookError)?;
@@ -166,7 +192,17 @@ fn virtualize_system() -> Result<(), HypervisorError> {
         hook::NT_CREATE_FILE_ORIGINAL.store(inline_hook.trampoline_address(), Ordering::Relaxed);
     }
 
-    let hook_manager = HookManager::new(vec![mm_is_address_valid, nt_create_file_syscall_hook]);
+    let mut hooks = vec![mm_is_address_valid, nt_create_file_syscall_hook];
+
+    // Example 3: Clipboard and file exfiltration detection policy pack
+    //
+    //
+    #[cfg(feature = "exfil-policy-pack")]
+    hooks.extend(policy_pack::hooks(
+        policy_pack::ExfilPolicyConfig::default(),
+    )?);
+
+    let hook_manager = HookManager::new(hooks);
 
     let mut primary_ept: Box<Ept, PhysicalAllocator> =
         unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
@@ -201,7 +237,10 @@ fn virtualize_system() -> Result<(), HypervisorError> {
         Err(err) => return Err(err),
     };
 
-    unsafe { HYPERVISOR = Some(hv) };
+    #[cfg(feature = "exfil-policy-pack")]
+    policy_pack::guard(&mut hv)?;
+
+    *HYPERVISOR.lock() = Some(hv);
 
     Ok(())
 }
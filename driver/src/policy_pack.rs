@@ -0,0 +1,336 @@
+//! A ready-made detection configuration for data leaving the guest through the clipboard and files, built with
+//! the `exfil-policy-pack` feature.
+//!
+//! The pack demonstrates how the subsystems of the hypervisor compose for EDR-style monitoring:
+//! - Syscall interception: `NtWriteFile` is hooked through its export, and the win32k syscall reading the
+//!   clipboard through the shadow SSDT when its number is configured, as syscall numbers differ per build.
+//! - Hook filters: with `clipboard_format` set, reads of other clipboard formats are filtered out in root mode
+//!   and run the original routine without reaching the handler.
+//! - Event telemetry: clipboard reads and file writes are recorded in an `EventLog`, and a file write of at least
+//!   `write_threshold` bytes by a process that read the clipboard within `window_tsc` is reported as a
+//!   detection, both in the log and with `log::warn!`, so it also reaches the binary telemetry stream.
+//! - Region guarding: the trampolines of the hooks live on a page of their own, which the `deny-trampolines`
+//!   permission profile makes read-only once the system is virtualized, so the hooks cannot be redirected by
+//!   patching them.
+//!
+//! The win32k tables are only mapped in session space, so the clipboard syscall can only be resolved when the
+//! driver is loaded from a thread of a GUI session. Otherwise the pack monitors file writes only.
+//!
+//! Reference: https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/ntifs/nf-ntifs-ntwritefile
+
+#![allow(non_snake_case)]
+
+use {
+    alloc::vec::Vec,
+    core::{
+        mem,
+        sync::atomic::{AtomicU64, Ordering},
+    },
+    hypervisor::{
+        error::HypervisorError,
+        intel::{
+            ept::{
+                filter::{Comparison, HookFilter},
+                hooks::{Hook, HookType},
+                paging::AccessType,
+                policy::{PermissionProfile, ViolationResponse},
+            },
+            vmm::Hypervisor,
+        },
+        utils::{
+            addresses::{Gpa, Hva, PhysicalAddress},
+            event_log::EventLog,
+            ssdt::ssdt_hook::SsdtHook,
+            sync::SpinLock,
+            timestamp::Timestamp,
+        },
+    },
+    wdk_sys::{
+        ntddk::PsGetCurrentProcessId, HANDLE, NTSTATUS, PLARGE_INTEGER, PULONG, PVOID, ULONG,
+    },
+};
+
+/// The number of events kept until they are drained.
+const EVENT_LOG_LEN: usize = 128;
+
+/// The number of processes whose last clipboard read is remembered.
+const CLIPBOARD_READERS: usize = 16;
+
+/// The profile keeping the trampolines of the hooks read-only.
+const TRAMPOLINE_PROFILE: PermissionProfile = PermissionProfile::new(
+    "deny-trampolines",
+    AccessType::READ_EXECUTE,
+    ViolationResponse::Deny,
+);
+
+/// The configuration of the pack.
+#[derive(Debug, Clone, Copy)]
+pub struct ExfilPolicyConfig {
+    /// The win32k syscall number of `NtUserGetClipboardData` on the running build, or `None` to leave the
+    /// clipboard unmonitored.
+    pub clipboard_syscall: Option<i32>,
+
+    /// The only clipboard format whose reads are recorded, e.g. 13 for `CF_UNICODETEXT`, or `None` to record
+    /// the reads of every format.
+    pub clipboard_format: Option<u32>,
+
+    /// The number of bytes a file write must reach to be reported after a clipboard read.
+    pub write_threshold: u32,
+
+    /// How long after a clipboard read a file write is attributed to it, in guest TSC ticks.
+    pub window_tsc: u64,
+}
+
+impl Default for ExfilPolicyConfig {
+    fn default() -> Self {
+        Self {
+            clipboard_syscall: None,
+            clipboard_format: None,
+            write_threshold: 4096,
+            window_tsc: 10_000_000_000,
+        }
+    }
+}
+
+/// What a process did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExfilEventKind {
+    /// The process read the clipboard.
+    ClipboardRead,
+
+    /// The process wrote to a file.
+    FileWrite { length: u32 },
+
+    /// The process wrote to a file shortly after reading the clipboard.
+    Detection { length: u32 },
+}
+
+/// An event recorded by the pack.
+#[derive(Debug, Clone, Copy)]
+pub struct ExfilEvent {
+    /// What the process did.
+    pub kind: ExfilEventKind,
+
+    /// The ID of the process.
+    pub process_id: u64,
+
+    /// When it happened.
+    pub timestamp: Timestamp,
+}
+
+/// The addresses of the original routines, alone on their page so it can be made read-only.
+#[repr(C, align(4096))]
+struct Trampolines {
+    nt_write_file: AtomicU64,
+    nt_user_get_clipboard_data: AtomicU64,
+}
+
+static TRAMPOLINES: Trampolines = Trampolines {
+    nt_write_file: AtomicU64::new(0),
+    nt_user_get_clipboard_data: AtomicU64::new(0),
+};
+
+/// The configuration in effect, set by `hooks`.
+static CONFIG: SpinLock<Option<ExfilPolicyConfig>> = SpinLock::new("exfil_config", None);
+
+/// The events not drained yet.
+static EVENTS: EventLog<ExfilEvent, EVENT_LOG_LEN> = EventLog::new("exfil_events");
+
+/// The last clipboard read of recent readers, as (process ID, guest TSC), overwritten in FIFO order.
+static CLIPBOARD_READS: SpinLock<([(u64, u64); CLIPBOARD_READERS], usize)> =
+    SpinLock::new("exfil_clipboard_reads", ([(0, 0); CLIPBOARD_READERS], 0));
+
+/// Creates the hooks of the pack, to be handed to the `HookManager`.
+///
+/// # Arguments
+///
+/// * `config` - The configuration of the pack.
+///
+/// # Returns
+///
+/// The hooks, or an error if `NtWriteFile` could not be hooked. A clipboard syscall that cannot be resolved is
+/// skipped with a warning.
+pub fn hooks(config: ExfilPolicyConfig) -> Result<Vec<Hook>, HypervisorError> {
+    *CONFIG.lock() = Some(config);
+
+    let mut hooks = Vec::new();
+
+    let nt_write_file = Hook::hook_function("NtWriteFile", nt_write_file as *const ())
+        .ok_or(HypervisorError::HookError)?;
+    store_trampoline(&nt_write_file, &TRAMPOLINES.nt_write_file);
+    hooks.push(nt_write_file);
+
+    if let Some(number) = config.clipboard_syscall {
+        let clipboard = SsdtHook::find_ssdt_function_address(number, true).and_then(|entry| {
+            Hook::hook_function_ptr(
+                Hva::from_ptr(entry.function_address),
+                nt_user_get_clipboard_data as *const (),
+            )
+            .ok_or(HypervisorError::HookError)
+        });
+
+        let clipboard = match config.clipboard_format {
+            Some(format) => clipboard.and_then(|hook| {
+                let filter = HookFilter::new().argument(0, Comparison::Equal, format as u64)?;
+                Ok(hook.with_filter(filter))
+            }),
+            None => clipboard,
+        };
+
+        match clipboard {
+            Ok(hook) => {
+                store_trampoline(&hook, &TRAMPOLINES.nt_user_get_clipboard_data);
+                hooks.push(hook);
+            }
+            Err(error) => log::warn!("Not monitoring the clipboard: {:?}", error),
+        }
+    }
+
+    log::info!("Exfiltration policy pack: {} hooks", hooks.len());
+
+    Ok(hooks)
+}
+
+/// Makes the trampolines of the hooks read-only. Called once the system is virtualized.
+///
+/// # Arguments
+///
+/// * `hypervisor` - The hypervisor running the hooks.
+pub fn guard(hypervisor: &mut Hypervisor) -> Result<(), HypervisorError> {
+    let start = Gpa::new(PhysicalAddress::pa_from_va(&TRAMPOLINES as *const _ as u64));
+    let end = Gpa::new(start.as_u64() + mem::size_of::<Trampolines>() as u64);
+
+    hypervisor.apply_profile(TRAMPOLINE_PROFILE, &[start..end])
+}
+
+/// Hands the pending events to a consumer, oldest first.
+///
+/// # Returns
+///
+/// The number of events drained.
+pub fn drain_events(consumer: impl FnMut(&ExfilEvent)) -> usize {
+    EVENTS.drain(consumer)
+}
+
+/// Stores the trampoline of a function hook.
+fn store_trampoline(hook: &Hook, trampoline: &AtomicU64) {
+    if let HookType::Function { ref inline_hook } = hook.hook_type {
+        trampoline.store(inline_hook.trampoline_address() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Returns the ID of the current process.
+fn current_process_id() -> u64 {
+    unsafe { PsGetCurrentProcessId() as u64 }
+}
+
+/// Records an event and reports a file write following a clipboard read of the same process.
+fn record(kind: ExfilEventKind) {
+    let process_id = current_process_id();
+    let timestamp = Timestamp::now();
+
+    let Some(config) = *CONFIG.lock() else {
+        return;
+    };
+
+    let kind = match kind {
+        ExfilEventKind::ClipboardRead => {
+            let mut reads = CLIPBOARD_READS.lock();
+            let (entries, next) = &mut *reads;
+
+            match entries.iter_mut().find(|(pid, _)| *pid == process_id) {
+                Some(entry) => entry.1 = timestamp.guest_tsc,
+                None => {
+                    if let Some(entry) = entries.get_mut(*next) {
+                        *entry = (process_id, timestamp.guest_tsc);
+                    }
+                    *next = (*next + 1) % CLIPBOARD_READERS;
+                }
+            }
+
+            kind
+        }
+        ExfilEventKind::FileWrite { length } if length >= config.write_threshold => {
+            let recent = CLIPBOARD_READS.lock().0.iter().any(|&(pid, tsc)| {
+                pid == process_id
+                    && tsc != 0
+                    && timestamp.guest_tsc.wrapping_sub(tsc) <= config.window_tsc
+            });
+
+            match recent {
+                true => {
+                    log::warn!(
+                        "Process {} wrote {} bytes to a file after reading the clipboard",
+                        process_id,
+                        length
+                    );
+                    ExfilEventKind::Detection { length }
+                }
+                false => kind,
+            }
+        }
+        _ => kind,
+    };
+
+    EVENTS.push(ExfilEvent {
+        kind,
+        process_id,
+        timestamp,
+    });
+}
+
+type NtWriteFileType = extern "C" fn(
+    FileHandle: HANDLE,
+    Event: HANDLE,
+    ApcRoutine: PVOID,
+    ApcContext: PVOID,
+    IoStatusBlock: PVOID,
+    Buffer: PVOID,
+    Length: ULONG,
+    ByteOffset: PLARGE_INTEGER,
+    Key: PULONG,
+) -> NTSTATUS;
+
+/// Records the write and calls the original `NtWriteFile`.
+extern "C" fn nt_write_file(
+    file_handle: HANDLE,
+    event: HANDLE,
+    apc_routine: PVOID,
+    apc_context: PVOID,
+    io_status_block: PVOID,
+    buffer: PVOID,
+    length: ULONG,
+    byte_offset: PLARGE_INTEGER,
+    key: PULONG,
+) -> NTSTATUS {
+    record(ExfilEventKind::FileWrite { length });
+
+    let fn_ptr = TRAMPOLINES.nt_write_file.load(Ordering::Relaxed);
+    let fn_ptr = unsafe { mem::transmute::<u64, NtWriteFileType>(fn_ptr) };
+
+    fn_ptr(
+        file_handle,
+        event,
+        apc_routine,
+        apc_context,
+        io_status_block,
+        buffer,
+        length,
+        byte_offset,
+        key,
+    )
+}
+
+type NtUserGetClipboardDataType = extern "C" fn(Format: ULONG, Data: PVOID) -> HANDLE;
+
+/// Records the clipboard read and calls the original `NtUserGetClipboardData`.
+extern "C" fn nt_user_get_clipboard_data(format: ULONG, data: PVOID) -> HANDLE {
+    record(ExfilEventKind::ClipboardRead);
+
+    let fn_ptr = TRAMPOLINES
+        .nt_user_get_clipboard_data
+        .load(Ordering::Relaxed);
+    let fn_ptr = unsafe { mem::transmute::<u64, NtUserGetClipboardDataType>(fn_ptr) };
+
+    fn_ptr(format, data)
+}
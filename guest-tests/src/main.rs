@@ -0,0 +1,153 @@
+//! Guest-side assertion binary for the integration test suite.
+//!
+//! This program runs inside the Windows guest after the hypervisor driver has been loaded.
+//! Every check prints a single line in the form `TEST <name> PASS|FAIL [details]`, which is
+//! parsed by `cargo xtask test` from the guest serial log. Checks that can only be verified
+//! from the hypervisor side print a `MARKER <name>` line instead, so the harness can correlate
+//! them with the hypervisor log.
+//!
+//! Pass `--developer-mode` when the driver was built with the `developer-mode` feature. The hypervisor
+//! then identifies itself, so the stealth checks of CPUID leaf 1 are replaced by checks of the
+//! diagnostics leaf.
+
+use {
+    hypervisor_core::paravirt::{CPUID_DIAGNOSTICS, CPUID_VENDOR, VENDOR_SIGNATURE},
+    std::{arch::x86_64::__cpuid, env, fs, process::ExitCode},
+};
+
+/// CPUID.01H:ECX bit indicating that a hypervisor is present.
+const HYPERVISOR_PRESENT_BIT: u32 = 1 << 31;
+
+/// CPUID.01H:ECX bit indicating VMX support.
+const VMX_SUPPORT_BIT: u32 = 1 << 5;
+
+/// Returns ECX of the given CPUID leaf.
+#[allow(unused_unsafe)] // `__cpuid` is only safe on newer toolchains.
+fn cpuid_ecx(leaf: u32) -> u32 {
+    unsafe { __cpuid(leaf) }.ecx
+}
+
+/// The outcome of a single check.
+struct Outcome {
+    name: &'static str,
+    passed: bool,
+    details: String,
+}
+
+/// The hypervisor must hide its presence from CPUID leaf 1.
+fn cpuid_hypervisor_bit_hidden() -> Outcome {
+    let ecx = cpuid_ecx(0x1);
+
+    Outcome {
+        name: "cpuid_hypervisor_bit_hidden",
+        passed: ecx & HYPERVISOR_PRESENT_BIT == 0,
+        details: format!("ecx={:#x}", ecx),
+    }
+}
+
+/// In developer mode, the hypervisor must advertise its presence in CPUID leaf 1.
+fn cpuid_hypervisor_bit_exposed() -> Outcome {
+    let ecx = cpuid_ecx(0x1);
+
+    Outcome {
+        name: "cpuid_hypervisor_bit_exposed",
+        passed: ecx & HYPERVISOR_PRESENT_BIT != 0,
+        details: format!("ecx={:#x}", ecx),
+    }
+}
+
+/// In developer mode, the hypervisor must identify itself and answer the diagnostics leaf.
+#[allow(unused_unsafe)] // `__cpuid` is only safe on newer toolchains.
+fn cpuid_diagnostics_leaf() -> Outcome {
+    let vendor = unsafe { __cpuid(CPUID_VENDOR) };
+
+    let mut signature = [0u8; 12];
+    signature[0..4].copy_from_slice(&vendor.ebx.to_le_bytes());
+    signature[4..8].copy_from_slice(&vendor.ecx.to_le_bytes());
+    signature[8..12].copy_from_slice(&vendor.edx.to_le_bytes());
+
+    let diagnostics = unsafe { __cpuid(CPUID_DIAGNOSTICS) };
+
+    Outcome {
+        name: "cpuid_diagnostics_leaf",
+        passed: signature == VENDOR_SIGNATURE
+            && vendor.eax >= CPUID_DIAGNOSTICS
+            && diagnostics.eax != 0
+            && diagnostics.ebx != 0,
+        details: format!(
+            "signature={:?} max_leaf={:#x} version={} processors={} build={:#x}",
+            String::from_utf8_lossy(&signature),
+            vendor.eax,
+            diagnostics.eax,
+            diagnostics.ebx,
+            diagnostics.edx
+        ),
+    }
+}
+
+/// The hypervisor must hide VMX support from CPUID leaf 1.
+fn cpuid_vmx_hidden() -> Outcome {
+    let ecx = cpuid_ecx(0x1);
+
+    Outcome {
+        name: "cpuid_vmx_hidden",
+        passed: ecx & VMX_SUPPORT_BIT == 0,
+        details: format!("ecx={:#x}", ecx),
+    }
+}
+
+/// Creates a file to drive the hooked `NtCreateFile` system call.
+///
+/// Whether the hook fired is verified by the harness against the hypervisor log.
+fn nt_create_file_hook() -> Outcome {
+    let path = env::temp_dir().join("guest-tests.tmp");
+    let result = fs::write(&path, b"matrix").and_then(|_| fs::remove_file(&path));
+
+    println!("MARKER nt_create_file");
+
+    Outcome {
+        name: "nt_create_file_called",
+        passed: result.is_ok(),
+        details: format!("{:?}", result),
+    }
+}
+
+fn main() -> ExitCode {
+    let developer_mode = env::args().any(|arg| arg == "--developer-mode");
+
+    let checks: Vec<fn() -> Outcome> = if developer_mode {
+        vec![
+            cpuid_hypervisor_bit_exposed,
+            cpuid_diagnostics_leaf,
+            cpuid_vmx_hidden,
+            nt_create_file_hook,
+        ]
+    } else {
+        vec![
+            cpuid_hypervisor_bit_hidden,
+            cpuid_vmx_hidden,
+            nt_create_file_hook,
+        ]
+    };
+
+    let mut failed = 0;
+
+    for check in checks {
+        let outcome = check();
+        let status = if outcome.passed { "PASS" } else { "FAIL" };
+
+        println!("TEST {} {} {}", outcome.name, status, outcome.details);
+
+        if !outcome.passed {
+            failed += 1;
+        }
+    }
+
+    println!("DONE {} failed", failed);
+
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,279 @@
+//! The codes of the hypercalls, passed in RAX, and their results, returned in RAX.
+//!
+//! The items referred to in the documentation of the codes belong to the `hypervisor` crate, which serves them,
+//! see its `intel::hypercall` module for the calling convention. The codes of the introspection hypercalls only
+//! exist with the `introspection` feature, which the `hypervisor` crate enables along with its own.
+
+/// The first word of the message authenticating an `AgentChallenge` ("AGNTCHAL"), which tells it apart from the
+/// one-word answers of `AgentRespond`.
+pub const AGENT_CHALLENGE_TAG: u64 = u64::from_le_bytes(*b"AGNTCHAL");
+
+/// The result of a hypercall, returned in RAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum HypercallStatus {
+    /// The hypercall succeeded.
+    Success = 0,
+
+    /// The hypercall code is unknown. Not returned, as unknown codes raise #UD instead; the value stays reserved.
+    InvalidCode = 1,
+
+    /// A parameter is invalid.
+    InvalidParameter = 2,
+
+    /// The hypercall is not allowed in the current state.
+    AccessDenied = 3,
+
+    /// The feature behind the hypercall is not enabled.
+    NotSupported = 4,
+
+    /// The resources needed to serve the hypercall are exhausted.
+    InsufficientResources = 5,
+
+    /// The hypercall was cancelled before it completed, as it ran longer than the root operation timeout of the
+    /// hypervisor or its client session was closed. The outputs describe the part completed.
+    Cancelled = 6,
+
+    /// The snapshot read by the hypercall was replaced since the caller started reading it, or is being taken on
+    /// another processor. The caller starts over from the first part.
+    Retry = 7,
+}
+
+/// The hypercalls understood by the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum HypercallCode {
+    /// Identifies the hypervisor, see `ParavirtInterface::identify`. Requires `ParavirtFeatures::DIAGNOSTICS`.
+    Identify = 0x1,
+
+    /// Opens a client session, see `ClientSessions::open`.
+    ///
+    /// RBX: the `ClientRole`.
+    /// RCX, RDX: the low and high halves of the admin key, for an admin session.
+    /// Returns the session token in RBX.
+    SessionOpen = 0x10,
+
+    /// Closes the client session whose token is in R8.
+    SessionClose = 0x11,
+
+    /// Registers the guest agent, see `AgentMonitor::register`.
+    ///
+    /// RBX: the guest physical address of an array of page addresses to protect.
+    /// RCX: the number of entries in the array.
+    /// Returns the 128-bit MAC key in RBX (low half) and RCX (high half).
+    AgentRegister = 0x100,
+
+    /// Requests a liveness challenge.
+    ///
+    /// RBX: the SipHash-2-4 of `[AGENT_CHALLENGE_TAG, n]` under the key returned by `AgentRegister`, where `n` is
+    /// the number of challenges issued so far plus one.
+    /// Returns the nonce in RBX.
+    AgentChallenge = 0x101,
+
+    /// Answers the pending liveness challenge.
+    ///
+    /// RBX: the SipHash-2-4 of the nonce under the key returned by `AgentRegister`.
+    AgentRespond = 0x102,
+
+    /// Ends a fuzzing iteration, see `CoverageMap::reset`. The pages of the coverage target are made
+    /// non-executable again.
+    ///
+    /// Returns the number of pages executed during the iteration in RBX, and the number of pages executed for
+    /// the first time in RCX.
+    #[cfg(feature = "introspection")]
+    CoverageReset = 0x200,
+
+    /// Copies the coverage bitmap of the current iteration, see `CoverageMap::copy_bitmap`.
+    ///
+    /// RBX: the guest physical address of the buffer, which must not cross a page boundary.
+    /// RCX: the size of the buffer, at least the size of the bitmap.
+    /// Returns the number of pages of the coverage target, i.e. the number of bits, in RBX.
+    #[cfg(feature = "introspection")]
+    CoverageRead = 0x201,
+
+    /// Flips bits of guest memory, see `FaultInjector::flip_bits`.
+    ///
+    /// RBX: the guest physical address of the quadword, 8-byte aligned.
+    /// RCX: the bits to flip.
+    #[cfg(feature = "introspection")]
+    FaultFlipBits = 0x300,
+
+    /// Fails the next calls to a routine, which returns a value to its caller without running.
+    ///
+    /// RBX: the guest virtual address of the routine.
+    /// RCX: the number of calls to fail.
+    /// RDX: the value returned, e.g. NULL for an allocator.
+    #[cfg(feature = "introspection")]
+    FaultFailCalls = 0x301,
+
+    /// Raises an exception when the guest executes an instruction.
+    ///
+    /// RBX: the guest virtual address of the instruction.
+    /// RCX: the vector, either 14 (#PF) or 18 (#MC).
+    /// RDX: for a page fault, the faulting linear address loaded into CR2.
+    #[cfg(feature = "introspection")]
+    FaultRaiseException = 0x302,
+
+    /// Disarms all call and exception faults.
+    #[cfg(feature = "introspection")]
+    FaultClear = 0x303,
+
+    /// Dumps the entries of the primary EPT translating a guest physical address range, see `Ept::dump`.
+    ///
+    /// RBX: the guest physical address of the first page.
+    /// RCX: the number of 4KB pages, at least one.
+    /// RDX: the guest physical address of a page the guest may write, receiving the text cut off where the page is
+    /// full.
+    /// Returns the length of the text in RBX and the number of problems found in the part dumped in RCX.
+    /// Cancelled once it runs longer than `HypervisorBuilder::root_operation_timeout` or its session is closed.
+    #[cfg(feature = "introspection")]
+    EptDump = 0x400,
+
+    /// Exports the configuration the hypervisor is running with, see `EffectiveConfig`.
+    ///
+    /// RBX: the guest physical address of a page receiving the text, page aligned.
+    /// RCX: the offset into the text of the first byte copied, to read a text longer than a page. Offset 0 takes a
+    /// new snapshot of the configuration, the following offsets read from it.
+    /// RDX: the generation of the snapshot returned by the read at offset 0, ignored at offset 0.
+    /// Returns the number of bytes copied in RBX, the length of the whole text in RCX and the generation of the
+    /// snapshot in RDX, or `HypercallStatus::Retry` once the snapshot was replaced.
+    #[cfg(feature = "introspection")]
+    GetEffectiveConfig = 0x500,
+
+    /// Poisons a range of guest memory, see `HeapPoison::poison`. The pages holding it become slow to access.
+    ///
+    /// RBX: the guest linear address of the range, translated with the current CR3.
+    /// RCX: the length of the range in bytes.
+    /// Cancelled like `EptDump` while the range is translated, before anything is poisoned.
+    #[cfg(feature = "introspection")]
+    HeapPoison = 0x600,
+
+    /// Removes the poison from a range of guest memory, see `HeapPoison::unpoison`.
+    ///
+    /// RBX: the guest linear address of the range, translated with the current CR3.
+    /// RCX: the length of the range in bytes.
+    /// Returns the number of bytes the poison was removed from in RBX. Cancelled like `EptDump`, keeping the
+    /// poison removed so far.
+    #[cfg(feature = "introspection")]
+    HeapUnpoison = 0x601,
+
+    /// Exports a snapshot of the metrics of the hypervisor, see `intel::metrics`.
+    ///
+    /// RBX: the guest physical address of a page receiving the snapshot, page aligned.
+    /// RCX: the offset into the snapshot of the first byte copied, to read a snapshot longer than a page.
+    /// Returns the number of bytes copied in RBX and the length of the whole snapshot in RCX.
+    #[cfg(feature = "introspection")]
+    GetMetrics = 0x700,
+
+    /// Lists the guest processes, see `ProcessControl::export`. Requires `HypervisorBuilder::process_control`.
+    ///
+    /// RBX: the guest physical address of a page receiving `PROCESS_RECORD_LEN`-byte records, page aligned.
+    /// RCX: the index of the first process copied, to read a list longer than a page.
+    /// Returns the number of records copied in RBX and the number of processes in RCX. Cancelled like `EptDump`,
+    /// returning the same for the processes walked.
+    #[cfg(feature = "introspection")]
+    ListProcesses = 0x800,
+
+    /// Queues a guest process for termination, see `ProcessControl::request_termination`. Requires
+    /// `HypervisorBuilder::process_control`.
+    ///
+    /// RBX: the ID of the process.
+    /// Cancelled like `EptDump` while the process is looked up.
+    #[cfg(feature = "introspection")]
+    TerminateProcess = 0x801,
+
+    /// Lists the commands offered by the hypervisor, see `intel::capabilities`.
+    ///
+    /// RBX: the guest physical address of a page receiving the text, page aligned.
+    /// RCX: the offset into the text of the first byte copied, to read a text longer than a page.
+    /// Returns the number of bytes copied in RBX and the length of the whole text in RCX.
+    #[cfg(feature = "introspection")]
+    ListCapabilities = 0x900,
+}
+
+impl HypercallCode {
+    /// The hypercalls served by this build, in the order of their codes. Decoding and the capability registry
+    /// both go through this table, see `intel::capabilities`.
+    pub const ALL: &'static [Self] = &[
+        Self::Identify,
+        Self::SessionOpen,
+        Self::SessionClose,
+        Self::AgentRegister,
+        Self::AgentChallenge,
+        Self::AgentRespond,
+        #[cfg(feature = "introspection")]
+        Self::CoverageReset,
+        #[cfg(feature = "introspection")]
+        Self::CoverageRead,
+        #[cfg(feature = "introspection")]
+        Self::FaultFlipBits,
+        #[cfg(feature = "introspection")]
+        Self::FaultFailCalls,
+        #[cfg(feature = "introspection")]
+        Self::FaultRaiseException,
+        #[cfg(feature = "introspection")]
+        Self::FaultClear,
+        #[cfg(feature = "introspection")]
+        Self::EptDump,
+        #[cfg(feature = "introspection")]
+        Self::GetEffectiveConfig,
+        #[cfg(feature = "introspection")]
+        Self::HeapPoison,
+        #[cfg(feature = "introspection")]
+        Self::HeapUnpoison,
+        #[cfg(feature = "introspection")]
+        Self::GetMetrics,
+        #[cfg(feature = "introspection")]
+        Self::ListProcesses,
+        #[cfg(feature = "introspection")]
+        Self::TerminateProcess,
+        #[cfg(feature = "introspection")]
+        Self::ListCapabilities,
+    ];
+
+    /// Decodes a hypercall code, or returns `None` if it is unknown.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        Self::ALL.iter().copied().find(|&code| code as u64 == value)
+    }
+
+    /// Returns the access the hypercall requires from a client session.
+    pub fn access(&self) -> HypercallAccess {
+        match self {
+            Self::Identify
+            | Self::SessionOpen
+            | Self::SessionClose
+            | Self::AgentRegister
+            | Self::AgentChallenge
+            | Self::AgentRespond => HypercallAccess::Public,
+            #[cfg(feature = "introspection")]
+            Self::CoverageRead
+            | Self::EptDump
+            | Self::GetEffectiveConfig
+            | Self::GetMetrics
+            | Self::ListProcesses
+            | Self::ListCapabilities => HypercallAccess::ReadOnly,
+            #[cfg(feature = "introspection")]
+            Self::CoverageReset
+            | Self::FaultFlipBits
+            | Self::FaultFailCalls
+            | Self::FaultRaiseException
+            | Self::FaultClear
+            | Self::HeapPoison
+            | Self::HeapUnpoison
+            | Self::TerminateProcess => HypercallAccess::Control,
+        }
+    }
+}
+
+/// The access a hypercall requires, see `HypercallCode::access`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HypercallAccess {
+    /// No session is required.
+    Public,
+
+    /// Any session is allowed, as the hypercall does not change the state of the hypervisor or the guest.
+    ReadOnly,
+
+    /// An admin session is required.
+    Control,
+}
@@ -0,0 +1,15 @@
+//! The guest-visible interface of the hypervisor.
+//!
+//! Everything a guest, a client or a test needs to detect and talk to the hypervisor, without depending on the
+//! Intel backend or on the Windows kernel: the CPUID leaves and signatures of the paravirtual interface, the
+//! codes and results of the hypercalls, the layout of the metrics snapshot and the format of the telemetry stream.
+//! The `hypervisor` crate re-exports these items from the modules that implement them, `intel::paravirt`,
+//! `intel::hypercall`, `intel::metrics` and `utils::telemetry`, and changes to them are changes to the ABI of the
+//! hypervisor.
+
+#![no_std]
+
+pub mod hypercall;
+pub mod metrics;
+pub mod paravirt;
+pub mod telemetry;
@@ -0,0 +1,403 @@
+//! The metrics snapshot exported through the `GetMetrics` hypercall, and its conversion to the Prometheus text
+//! exposition format.
+//!
+//! A snapshot is a little-endian binary blob: a header followed by fixed-size samples. Each sample belongs to a
+//! `MetricFamily`, a counter or a gauge, and carries one label value, e.g. the basic exit reason of the exit
+//! counters. The hypervisor writes the samples grouped by family, so the conversion emits the `# HELP` and
+//! `# TYPE` lines of a family once, before its samples, as Prometheus requires.
+//!
+//! Families unknown to the client are skipped, so an older client keeps working with a newer hypervisor.
+//!
+//! Reference: https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use core::fmt::{self, Write};
+
+/// The magic at the start of a snapshot ("MVMS").
+pub const METRICS_MAGIC: [u8; 4] = *b"MVMS";
+
+/// The version of the snapshot layout.
+pub const METRICS_VERSION: u16 = 1;
+
+/// The length of the header: magic, version, reserved word and number of samples.
+pub const METRICS_HEADER_LEN: usize = 12;
+
+/// The length of a sample: family, label, reserved dword and value.
+pub const METRICS_SAMPLE_LEN: usize = 16;
+
+/// The names of the memory categories, the label values of `MetricFamily::MemoryBytes`.
+pub const MEMORY_CATEGORIES: [&str; 7] = [
+    "ept",
+    "stack",
+    "bitmap",
+    "vmx_region",
+    "page_tables",
+    "descriptor_tables",
+    "heap",
+];
+
+/// Whether a metric only grows or goes up and down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl MetricKind {
+    /// Returns the name of the kind in a `# TYPE` line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+        }
+    }
+}
+
+/// The metric families of a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum MetricFamily {
+    /// The VM exits handled, labelled by basic exit reason.
+    VmExits = 1,
+
+    /// The TSC cycles spent handling VM exits, labelled by basic exit reason.
+    VmExitCycles = 2,
+
+    /// The longest VM exit handled, in TSC cycles, labelled by basic exit reason.
+    VmExitMaxCycles = 3,
+
+    /// The memory consumed by the hypervisor in bytes, labelled by category, see `MEMORY_CATEGORIES`.
+    MemoryBytes = 4,
+
+    /// The cap of the memory consumed by the hypervisor in bytes, absent if not capped.
+    MemoryCapBytes = 5,
+
+    /// The number of virtualized processors.
+    Processors = 6,
+}
+
+impl MetricFamily {
+    /// Decodes a family, or returns `None` if it is unknown.
+    pub fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(Self::VmExits),
+            2 => Some(Self::VmExitCycles),
+            3 => Some(Self::VmExitMaxCycles),
+            4 => Some(Self::MemoryBytes),
+            5 => Some(Self::MemoryCapBytes),
+            6 => Some(Self::Processors),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the family.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::VmExits => "hypervisor_vmexits_total",
+            Self::VmExitCycles => "hypervisor_vmexit_cycles_total",
+            Self::VmExitMaxCycles => "hypervisor_vmexit_max_cycles",
+            Self::MemoryBytes => "hypervisor_memory_bytes",
+            Self::MemoryCapBytes => "hypervisor_memory_cap_bytes",
+            Self::Processors => "hypervisor_processors",
+        }
+    }
+
+    /// Returns the description of the family in its `# HELP` line.
+    pub fn help(&self) -> &'static str {
+        match self {
+            Self::VmExits => "VM exits handled, by basic exit reason.",
+            Self::VmExitCycles => "TSC cycles spent handling VM exits, by basic exit reason.",
+            Self::VmExitMaxCycles => "Longest VM exit handled in TSC cycles, by basic exit reason.",
+            Self::MemoryBytes => "Memory consumed by the hypervisor, by category.",
+            Self::MemoryCapBytes => "Cap of the memory consumed by the hypervisor.",
+            Self::Processors => "Virtualized processors.",
+        }
+    }
+
+    /// Returns the kind of the family.
+    pub fn kind(&self) -> MetricKind {
+        match self {
+            Self::VmExits | Self::VmExitCycles => MetricKind::Counter,
+            Self::VmExitMaxCycles | Self::MemoryBytes | Self::MemoryCapBytes | Self::Processors => {
+                MetricKind::Gauge
+            }
+        }
+    }
+
+    /// Returns the name of the label of the samples, or `None` if they are not labelled.
+    pub fn label(&self) -> Option<&'static str> {
+        match self {
+            Self::VmExits | Self::VmExitCycles | Self::VmExitMaxCycles => Some("reason"),
+            Self::MemoryBytes => Some("category"),
+            Self::MemoryCapBytes | Self::Processors => None,
+        }
+    }
+}
+
+/// A sample of a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// The family, kept raw so unknown families can be skipped.
+    pub family: u16,
+
+    /// The label value, see `MetricFamily::label`.
+    pub label: u16,
+
+    /// The value.
+    pub value: u64,
+}
+
+impl Sample {
+    /// Creates a sample.
+    pub fn new(family: MetricFamily, label: u16, value: u64) -> Self {
+        Self {
+            family: family as u16,
+            label,
+            value,
+        }
+    }
+
+    /// Encodes the sample.
+    pub fn encode(&self) -> [u8; METRICS_SAMPLE_LEN] {
+        let mut bytes = [0u8; METRICS_SAMPLE_LEN];
+        bytes[0..2].copy_from_slice(&self.family.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.label.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a sample, or returns `None` if the bytes are too short.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(Self {
+            family: u16::from_le_bytes(bytes.get(0..2)?.try_into().ok()?),
+            label: u16::from_le_bytes(bytes.get(2..4)?.try_into().ok()?),
+            value: u64::from_le_bytes(bytes.get(8..16)?.try_into().ok()?),
+        })
+    }
+}
+
+/// Encodes the header of a snapshot.
+///
+/// # Arguments
+///
+/// * `samples` - The number of samples following the header.
+pub fn encode_header(samples: u32) -> [u8; METRICS_HEADER_LEN] {
+    let mut bytes = [0u8; METRICS_HEADER_LEN];
+    bytes[0..4].copy_from_slice(&METRICS_MAGIC);
+    bytes[4..6].copy_from_slice(&METRICS_VERSION.to_le_bytes());
+    bytes[8..12].copy_from_slice(&samples.to_le_bytes());
+    bytes
+}
+
+/// Why a snapshot could not be converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsError {
+    /// The snapshot does not start with `METRICS_MAGIC`.
+    BadMagic,
+
+    /// The snapshot was written with a layout this client does not know.
+    UnsupportedVersion(u16),
+
+    /// The snapshot ends before its last sample.
+    Truncated,
+
+    /// The output refused the text.
+    Format,
+}
+
+impl From<fmt::Error> for MetricsError {
+    fn from(_: fmt::Error) -> Self {
+        Self::Format
+    }
+}
+
+/// Iterates over the samples of a snapshot.
+///
+/// # Arguments
+///
+/// * `snapshot` - The snapshot, as returned by the hypercall.
+///
+/// # Returns
+///
+/// The samples, or an error if the header is invalid or the snapshot truncated.
+pub fn samples(snapshot: &[u8]) -> Result<impl Iterator<Item = Sample> + '_, MetricsError> {
+    let header = snapshot
+        .get(..METRICS_HEADER_LEN)
+        .ok_or(MetricsError::Truncated)?;
+
+    if header[0..4] != METRICS_MAGIC {
+        return Err(MetricsError::BadMagic);
+    }
+
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != METRICS_VERSION {
+        return Err(MetricsError::UnsupportedVersion(version));
+    }
+
+    let count = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    let body = count
+        .checked_mul(METRICS_SAMPLE_LEN)
+        .and_then(|len| snapshot.get(METRICS_HEADER_LEN..METRICS_HEADER_LEN.checked_add(len)?))
+        .ok_or(MetricsError::Truncated)?;
+
+    Ok(body
+        .chunks_exact(METRICS_SAMPLE_LEN)
+        .filter_map(Sample::decode))
+}
+
+/// Converts a snapshot to the Prometheus text exposition format.
+///
+/// # Arguments
+///
+/// * `snapshot` - The snapshot, as returned by the hypercall.
+/// * `output` - Receives the text.
+///
+/// # Returns
+///
+/// The number of samples written, or an error if the snapshot is invalid or the output failed.
+pub fn write_prometheus(snapshot: &[u8], output: &mut impl Write) -> Result<usize, MetricsError> {
+    let mut current = None;
+    let mut written = 0;
+
+    for sample in samples(snapshot)? {
+        let Some(family) = MetricFamily::from_u16(sample.family) else {
+            continue;
+        };
+
+        if current != Some(family) {
+            writeln!(output, "# HELP {} {}", family.name(), family.help())?;
+            writeln!(
+                output,
+                "# TYPE {} {}",
+                family.name(),
+                family.kind().as_str()
+            )?;
+            current = Some(family);
+        }
+
+        write!(output, "{}", family.name())?;
+        match (family.label(), family) {
+            (Some(label), MetricFamily::MemoryBytes) => {
+                match MEMORY_CATEGORIES.get(sample.label as usize) {
+                    Some(category) => write!(output, "{{{}=\"{}\"}}", label, category)?,
+                    None => write!(output, "{{{}=\"{}\"}}", label, sample.label)?,
+                }
+            }
+            (Some(label), _) => write!(output, "{{{}=\"{}\"}}", label, sample.label)?,
+            (None, _) => {}
+        }
+        writeln!(output, " {}", sample.value)?;
+
+        written += 1;
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {super::*, std::string::String, std::vec::Vec};
+
+    /// Encodes a snapshot of samples, as the hypervisor exports it.
+    fn snapshot(samples: &[Sample]) -> Vec<u8> {
+        let mut bytes = encode_header(samples.len() as u32).to_vec();
+        for sample in samples {
+            bytes.extend_from_slice(&sample.encode());
+        }
+        bytes
+    }
+
+    #[test]
+    fn header_layout() {
+        let header = encode_header(0x0102_0304);
+
+        assert_eq!(&header[0..4], b"MVMS");
+        assert_eq!(&header[4..6], &METRICS_VERSION.to_le_bytes());
+        assert_eq!(&header[6..8], &[0, 0]);
+        assert_eq!(&header[8..12], &[0x04, 0x03, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn sample_layout() {
+        let sample = Sample::new(MetricFamily::MemoryBytes, 0x0605, 0x0102_0304_0506_0708);
+        let bytes = sample.encode();
+
+        assert_eq!(
+            bytes,
+            [0x04, 0x00, 0x05, 0x06, 0, 0, 0, 0, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+        );
+        assert_eq!(Sample::decode(&bytes), Some(sample));
+        assert_eq!(Sample::decode(&bytes[..15]), None);
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        let expected = [
+            Sample::new(MetricFamily::VmExits, 10, 3),
+            Sample::new(MetricFamily::VmExitCycles, 10, 900),
+            Sample::new(MetricFamily::Processors, 0, 4),
+        ];
+        let bytes = snapshot(&expected);
+
+        let decoded: Vec<Sample> = samples(&bytes).unwrap().collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let mut bytes = snapshot(&[Sample::new(MetricFamily::Processors, 0, 1)]);
+
+        assert!(matches!(
+            samples(&bytes[..METRICS_HEADER_LEN - 1]),
+            Err(MetricsError::Truncated)
+        ));
+        assert!(matches!(
+            samples(&bytes[..bytes.len() - 1]),
+            Err(MetricsError::Truncated)
+        ));
+
+        bytes[4] = 0xFF;
+        assert!(matches!(
+            samples(&bytes),
+            Err(MetricsError::UnsupportedVersion(0x00FF))
+        ));
+
+        bytes[0] = 0;
+        assert!(matches!(samples(&bytes), Err(MetricsError::BadMagic)));
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let padding = Sample {
+            family: 0,
+            label: 0,
+            value: 0,
+        };
+        let bytes = snapshot(&[
+            Sample::new(MetricFamily::VmExits, 10, 3),
+            Sample::new(MetricFamily::VmExits, 48, 7),
+            Sample::new(MetricFamily::MemoryBytes, 0, 4096),
+            Sample::new(MetricFamily::MemoryBytes, 99, 1),
+            Sample::new(MetricFamily::Processors, 0, 4),
+            padding,
+        ]);
+
+        let mut text = String::new();
+        assert_eq!(write_prometheus(&bytes, &mut text), Ok(5));
+        assert_eq!(
+            text,
+            "# HELP hypervisor_vmexits_total VM exits handled, by basic exit reason.\n\
+             # TYPE hypervisor_vmexits_total counter\n\
+             hypervisor_vmexits_total{reason=\"10\"} 3\n\
+             hypervisor_vmexits_total{reason=\"48\"} 7\n\
+             # HELP hypervisor_memory_bytes Memory consumed by the hypervisor, by category.\n\
+             # TYPE hypervisor_memory_bytes gauge\n\
+             hypervisor_memory_bytes{category=\"ept\"} 4096\n\
+             hypervisor_memory_bytes{category=\"99\"} 1\n\
+             # HELP hypervisor_processors Virtualized processors.\n\
+             # TYPE hypervisor_processors gauge\n\
+             hypervisor_processors 4\n"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! The CPUID leaves of the paravirtual interface offered to cooperative guests.
+//!
+//! Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/feature-discovery
+
+use bitflags::bitflags;
+
+/// The first CPUID leaf reserved for hypervisors.
+pub const CPUID_HYPERVISOR_BASE: u32 = 0x4000_0000;
+
+/// The last CPUID leaf reserved for hypervisors.
+pub const CPUID_HYPERVISOR_LIMIT: u32 = 0x4000_00FF;
+
+/// CPUID leaf reporting the vendor signature and the maximum hypervisor leaf.
+pub const CPUID_VENDOR: u32 = 0x4000_0000;
+
+/// CPUID leaf reporting the interface signature and the offered features.
+pub const CPUID_FEATURES: u32 = 0x4000_0001;
+
+/// CPUID leaf reporting the guest physical address of the hypercall page.
+pub const CPUID_HYPERCALL_PAGE: u32 = 0x4000_0002;
+
+/// CPUID leaf reporting diagnostics, when offered.
+pub const CPUID_DIAGNOSTICS: u32 = 0x4000_0003;
+
+/// The version of the diagnostics leaf and of the identification hypercall.
+pub const DIAGNOSTICS_VERSION: u32 = 1;
+
+/// The vendor signature returned in EBX, ECX and EDX of leaf 0x40000000.
+pub const VENDOR_SIGNATURE: [u8; 12] = *b"MatrixVisor\0";
+
+/// The interface signature returned in EAX of leaf 0x40000001 ("MVI0").
+pub const INTERFACE_SIGNATURE: u32 = u32::from_le_bytes(*b"MVI0");
+
+bitflags! {
+    /// The features advertised to cooperative guests in EBX of leaf 0x40000001.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ParavirtFeatures: u32 {
+        /// The guest may issue hypercalls through VMCALL.
+        const HYPERCALLS = 1 << 0;
+
+        /// A hypercall page is provided, see leaf 0x40000002.
+        const HYPERCALL_PAGE = 1 << 1;
+
+        /// The diagnostics leaf 0x40000003 and the identification hypercall are provided.
+        const DIAGNOSTICS = 1 << 2;
+
+        /// The hypervisor identifies itself to test automation.
+        const DEVELOPER_MODE = Self::HYPERCALLS.bits() | Self::DIAGNOSTICS.bits();
+    }
+}
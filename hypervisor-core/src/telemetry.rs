@@ -0,0 +1,130 @@
+//! A stable binary format for the event stream, meant to be captured to disk and parsed offline.
+//!
+//! The stream consists of a file header followed by any number of records. All integers are
+//! little-endian. The layout is versioned by `SCHEMA_VERSION`; fields are only ever appended to the
+//! record header, and readers skip over bytes they do not know by using the lengths in the headers.
+//!
+//! File header (16 bytes):
+//!
+//! | Offset | Size | Field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 4    | Magic `MVTL`                           |
+//! | 4      | 2    | Schema version                         |
+//! | 6      | 2    | Header length in bytes                 |
+//! | 8      | 8    | Reserved, zero                         |
+//!
+//! Record (40 byte header followed by the message):
+//!
+//! | Offset | Size | Field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 4    | Magic `MVRC`                           |
+//! | 4      | 2    | Record length in bytes, header included |
+//! | 6      | 1    | Kind, see `RecordKind`                 |
+//! | 7      | 1    | Level (1 = Error ... 5 = Trace)        |
+//! | 8      | 8    | Sequence number                        |
+//! | 16     | 4    | Processor index                        |
+//! | 20     | 4    | Reserved, zero                         |
+//! | 24     | 8    | Host TSC                               |
+//! | 32     | 8    | Guest TSC                              |
+//! | 40     | n    | Message, UTF-8                         |
+//!
+//! The record magic allows a reader to resynchronize on a stream that was captured mid-way.
+//! A parser for std environments lives in the `telemetry-parser` crate.
+
+/// The magic at the start of the stream.
+pub const FILE_MAGIC: [u8; 4] = *b"MVTL";
+
+/// The magic at the start of each record.
+pub const RECORD_MAGIC: [u8; 4] = *b"MVRC";
+
+/// The version of the format described above.
+pub const SCHEMA_VERSION: u16 = 1;
+
+/// The length of the file header in bytes.
+pub const FILE_HEADER_LEN: usize = 16;
+
+/// The length of the record header in bytes.
+pub const RECORD_HEADER_LEN: usize = 40;
+
+/// The kind of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordKind {
+    /// A log record.
+    Log = 1,
+
+    /// Records were dropped; the sequence number holds the number of dropped records.
+    Dropped = 2,
+}
+
+/// Encodes the file header.
+pub fn encode_file_header() -> [u8; FILE_HEADER_LEN] {
+    let mut header = [0u8; FILE_HEADER_LEN];
+
+    let mut writer = Writer::new(&mut header);
+    writer.put(&FILE_MAGIC);
+    writer.put(&SCHEMA_VERSION.to_le_bytes());
+    writer.put(&(FILE_HEADER_LEN as u16).to_le_bytes());
+
+    header
+}
+
+/// The fixed fields of a record.
+pub struct RecordHeader {
+    pub kind: RecordKind,
+    pub level: u8,
+    pub sequence: u64,
+    pub cpu: u32,
+    pub host_tsc: u64,
+    pub guest_tsc: u64,
+}
+
+impl RecordHeader {
+    /// Encodes the header followed by the message.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message, truncated to what fits into `buffer`.
+    /// * `buffer` - The buffer to encode into.
+    ///
+    /// # Returns
+    ///
+    /// The encoded bytes, a prefix of `buffer`.
+    pub fn encode<'a>(&self, message: &[u8], buffer: &'a mut [u8]) -> &'a [u8] {
+        let capacity = buffer.len().saturating_sub(RECORD_HEADER_LEN);
+        let message = message.get(..capacity).unwrap_or(message);
+        let len = RECORD_HEADER_LEN + message.len();
+
+        let mut writer = Writer::new(buffer);
+        writer.put(&RECORD_MAGIC);
+        writer.put(&(len as u16).to_le_bytes());
+        writer.put(&[self.kind as u8, self.level]);
+        writer.put(&self.sequence.to_le_bytes());
+        writer.put(&self.cpu.to_le_bytes());
+        writer.put(&0u32.to_le_bytes());
+        writer.put(&self.host_tsc.to_le_bytes());
+        writer.put(&self.guest_tsc.to_le_bytes());
+        writer.put(message);
+
+        buffer.get(..len).unwrap_or(buffer)
+    }
+}
+
+/// Writes bytes sequentially into a buffer, silently truncating once it is full.
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    offset: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, offset: 0 }
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        if let Some(dst) = self.buffer.get_mut(self.offset..self.offset + bytes.len()) {
+            dst.copy_from_slice(bytes);
+            self.offset += bytes.len();
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::intel::{ept::paging::EptLevel, intrinsics::VmFail};
 use alloc::ffi::NulError;
 use thiserror_no_std::Error;
 
@@ -21,23 +22,26 @@ pub enum HypervisorError {
     #[error("Failed to convert from virtual address to physical address")]
     VirtualToPhysicalAddressFailed,
 
-    #[error("Failed to execute VMXON")]
-    VMXONFailed,
+    #[error("Failed to execute VMXON: {0}")]
+    VMXONFailed(VmFail),
 
-    #[error("Failed to execute VMXOFF")]
-    VMXOFFFailed,
+    #[error("Failed to execute VMXOFF: {0}")]
+    VMXOFFFailed(VmFail),
 
-    #[error("Failed to execute VMCLEAR")]
-    VMCLEARFailed,
+    #[error("Failed to execute VMCLEAR: {0}")]
+    VMCLEARFailed(VmFail),
 
-    #[error("Failed to execute VMPTRLD")]
-    VMPTRLDFailed,
+    #[error("Failed to execute VMPTRLD: {0}")]
+    VMPTRLDFailed(VmFail),
 
-    #[error("Failed to execute VMREAD")]
-    VMREADFailed,
+    #[error("Failed to execute VMPTRST: {0}")]
+    VMPTRSTFailed(VmFail),
 
-    #[error("Failed to execute VMWRITE")]
-    VMWRITEFailed,
+    #[error("Failed to execute VMREAD: {0}")]
+    VMREADFailed(VmFail),
+
+    #[error("Failed to execute VMWRITE: {0}")]
+    VMWRITEFailed(VmFail),
 
     #[error("Failed to execute VMLAUNCH")]
     VMLAUNCHFailed,
@@ -96,6 +100,9 @@ pub enum HypervisorError {
     #[error("VMX is not initialized")]
     VmxNotInitialized,
 
+    #[error("The hypervisor did not serve a host call")]
+    HostCallFailed,
+
     #[error("Hook error")]
     HookError,
 
@@ -155,4 +162,164 @@ pub enum HypervisorError {
 
     #[error("Failed to parse hexadecimal string")]
     HexParseError,
+
+    #[error("Hypervisor memory cap exceeded")]
+    MemoryCapExceeded,
+
+    #[error("Unknown hook namespace")]
+    UnknownHookNamespace,
+
+    #[error("Hook namespace quota exceeded")]
+    HookQuotaExceeded,
+
+    #[error("Hyper-V/VBS is active and does not expose VMX to this partition")]
+    HyperVActive,
+
+    #[error("Requested VMX control value does not fit the control field")]
+    InvalidVmxControlValue,
+
+    #[error("Invalid VM-exit interruption information")]
+    InvalidInterruptionInformation,
+
+    #[error("Invalid exception vector")]
+    InvalidExceptionVector,
+
+    #[error("Unhandled exception")]
+    UnhandledException,
+
+    #[error("Logger is already initialized")]
+    LoggerAlreadyInitialized,
+
+    #[error("Timed out waiting for a lock")]
+    LockTimeout,
+
+    #[error("Processor index exceeds the maximum number of vCPUs")]
+    ProcessorIndexOutOfRange,
+
+    #[error("Per-CPU slot is already claimed by another processor")]
+    PerCpuSlotTaken,
+
+    #[error("More processors than the maximum number of vCPUs")]
+    TooManyProcessors,
+
+    #[error("Requested memory type conflicts with the MTRRs")]
+    MemoryTypeConflict,
+
+    #[error("Sandbox code blob is empty or too large")]
+    SandboxCodeTooLarge,
+
+    #[error("Sandbox EPT table pool exhausted")]
+    SandboxTablesExhausted,
+
+    #[error("No idle sandbox is attached to the processor")]
+    SandboxUnavailable,
+
+    #[error("EPT region is empty, beyond the mapped memory or overlaps a protected region")]
+    InvalidRegion,
+
+    #[error("EPT is not supported by the processor")]
+    EPTUnsupported,
+
+    #[error("The processor does not maintain the accessed and dirty flags in the EPT entries")]
+    EptAccessedDirtyUnsupported,
+
+    #[error("Too many events pending injection")]
+    EventQueueFull,
+
+    #[error("Failed to register the image load notification routine")]
+    ImageNotifyRegistrationFailed,
+
+    #[error("Too many guest drivers blocked at once")]
+    TooManyBlockedDrivers,
+
+    #[error("Coverage target module is not loaded")]
+    CoverageModuleNotFound,
+
+    #[error("Coverage target module is too large")]
+    CoverageTargetTooLarge,
+
+    #[error("VM entry failed before a known-good guest state was captured")]
+    VmEntryFailed,
+
+    #[error("The processor has no LBR stack that can be virtualized")]
+    LbrUnsupported,
+
+    #[error("Hook filter already holds the maximum number of predicates")]
+    TooManyFilterPredicates,
+
+    #[error("Hook filter argument is not passed in a register")]
+    InvalidFilterArgument,
+
+    #[error("Control-register access exit with an unsupported exit qualification")]
+    UnsupportedControlRegisterAccess,
+
+    #[error(
+        "The TSC multiplier is zero, or needs TSC scaling which the processor does not support"
+    )]
+    TscScalingUnsupported,
+
+    #[error("No processor is selected for virtualization")]
+    NoProcessorSelected,
+
+    #[error("TSC virtualization requires every processor to be virtualized")]
+    PartialTscVirtualization,
+
+    #[error("Faults on monitored memory can only be delayed, not answered with #GP")]
+    InvalidRateLimit,
+
+    #[error(
+        "{0} requires every processor to be virtualized, as threads escape it on the native ones"
+    )]
+    PartialSecurityFeature(&'static str),
+
+    #[error("EPT misconfiguration at {gpa:#x}: {level:?} entry {entry:#018x}")]
+    EptMisconfiguration {
+        gpa: u64,
+        level: EptLevel,
+        entry: u64,
+    },
+
+    #[error(
+        "The layout of the guest process structures could not be decoded from the kernel exports"
+    )]
+    ProcessLayoutUnknown,
+
+    #[error("Process control requires client sessions")]
+    ProcessControlWithoutSessions,
+
+    #[error("The xAPIC page could not be mapped to send NMIs to the other processors")]
+    XApicMappingFailed,
+
+    #[error("The worker thread terminating the queued guest processes could not be started")]
+    WorkerThreadFailed,
+
+    #[error("The guest volume could not be opened or read")]
+    NtfsVolumeUnreadable,
+
+    #[error("The guest volume is not NTFS or its structures are corrupted")]
+    NtfsCorrupted,
+
+    #[error("The guest file does not exist")]
+    NtfsFileNotFound,
+
+    #[error("The guest file is compressed, encrypted or too fragmented to be read")]
+    NtfsUnsupportedFile,
+
+    #[error("More host breakpoints were set than there are debug address registers")]
+    TooManyHostBreakpoints,
+
+    #[error("A host breakpoint is not canonical, not aligned to its length, or executes more than a byte")]
+    InvalidHostBreakpoint,
+
+    #[error("The processor does not support the VMX-preemption timer")]
+    PreemptionTimerUnsupported,
+
+    #[error("The period of the VMX-preemption timer is shorter than the minimum")]
+    PreemptionTimerPeriodTooShort,
+
+    #[error("The processor cannot run the guest from the INIT state, it lacks the wait-for-SIPI activity state, unrestricted guest or IA32_EFER loading")]
+    WaitForSipiUnsupported,
+
+    #[error("The ChaCha20 block function does not match the test vector of RFC 8439")]
+    RngSelfTestFailed,
 }
@@ -0,0 +1,501 @@
+//! Liveness and tamper detection of a cooperative in-guest agent.
+//!
+//! A guest agent, typically a kernel driver, registers itself through the `AgentRegister` hypercall and
+//! names the guest physical pages of its code and data that must not change. The hypervisor write-protects
+//! these pages in the EPT and hands the agent a freshly generated 128-bit key. From then on the agent proves
+//! it is alive by requesting a challenge nonce (`AgentChallenge`) and answering with the SipHash-2-4 of the
+//! nonce under the key (`AgentRespond`). Every nonce is answered at most once, so recorded answers cannot be
+//! replayed.
+//!
+//! Both hypercalls can be issued by any kernel code, so both are authenticated with the key: a challenge by the
+//! MAC of the challenge counter, so other code cannot replace the pending nonce, and an answer by the MAC of
+//! the nonce. Calls failing the check are refused and counted, see `AgentMonitor::rejected_calls`, but raise no
+//! tamper event, as anyone could otherwise make the hypervisor enact the `TamperResponse`.
+//!
+//! A tamper event is raised when:
+//! - The agent has not answered a challenge within the configured timeout. This is checked on every VM exit,
+//!   and cleared by the next valid answer.
+//! - A protected page is written to. The protection of the page is lifted for the write to complete, and the
+//!   agent is considered compromised from then on.
+//!
+//! Only a single agent can register, once, so a compromised guest cannot take over the channel by
+//! registering again. The write protection is applied to the EPT shared by all processors before the agent is
+//! registered, and the other processors are kicked to flush their translations, see `invept_broadcast`.
+
+use {
+    crate::{
+        intel::{
+            debugger::{DebuggerMonitor, SuspendReason},
+            ept::paging::_512GB,
+            hypercall::{HypercallStatus, AGENT_CHALLENGE_TAG},
+        },
+        utils::{
+            addresses::Gpa, event_log::EventLog, instructions::rdtsc, siphash::siphash24,
+            sync::SpinLock, timestamp::Timestamp,
+        },
+    },
+    core::sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+#[cfg(feature = "introspection")]
+use crate::intel::{
+    capabilities::{Capability, Subsystem},
+    hypercall::HypercallCode,
+};
+
+/// The hypercalls of the guest agent monitor, see `intel::capabilities`.
+#[cfg(feature = "introspection")]
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "agent",
+    enabled: |shared_data| shared_data.agent_monitor.is_enabled(),
+    commands: &[
+        Capability {
+            code: HypercallCode::AgentRegister,
+            summary: "Registers the guest agent and write-protects its pages",
+        },
+        Capability {
+            code: HypercallCode::AgentChallenge,
+            summary: "Requests a liveness challenge",
+        },
+        Capability {
+            code: HypercallCode::AgentRespond,
+            summary: "Answers the pending liveness challenge",
+        },
+    ],
+};
+
+/// The maximum number of pages an agent can have protected.
+pub const MAX_AGENT_PAGES: usize = 16;
+
+/// The default heartbeat timeout in TSC ticks (roughly ten seconds on current processors).
+pub const DEFAULT_HEARTBEAT_TIMEOUT: u64 = 30_000_000_000;
+
+/// The number of events kept until they are drained.
+const EVENT_LOG_LEN: usize = 32;
+
+/// What to do, in addition to raising an event, when the agent is tampered with or stops responding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TamperResponse {
+    /// Only raise the event.
+    #[default]
+    Report,
+
+    /// Suspend the hooks, as the guest can no longer be trusted to leave them alone.
+    SuspendHooks,
+}
+
+/// The configuration of the agent monitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AgentMonitorConfig {
+    /// The time in TSC ticks after which an agent that has not answered a challenge is unresponsive.
+    pub heartbeat_timeout: u64,
+
+    /// What to do when a tamper event is raised.
+    pub response: TamperResponse,
+}
+
+impl Default for AgentMonitorConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            response: TamperResponse::default(),
+        }
+    }
+}
+
+/// The state of the agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AgentStatus {
+    /// No agent has registered yet.
+    Unregistered = 0,
+
+    /// The agent answers the challenges in time.
+    Alive = 1,
+
+    /// The agent has not answered a challenge within the timeout.
+    Unresponsive = 2,
+
+    /// A protected page of the agent was modified.
+    Tampered = 3,
+}
+
+impl AgentStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Alive,
+            2 => Self::Unresponsive,
+            3 => Self::Tampered,
+            _ => Self::Unregistered,
+        }
+    }
+}
+
+/// The kind of a tamper event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TamperKind {
+    /// The agent has not answered a challenge since the given TSC.
+    Unresponsive { last_heartbeat: u64 },
+
+    /// A protected page was written to.
+    PageModified { guest_pa: Gpa },
+}
+
+/// A tamper event.
+#[derive(Debug, Clone, Copy)]
+pub struct TamperEvent {
+    /// What happened.
+    pub kind: TamperKind,
+
+    /// The guest RIP of the VM exit on which the event was raised.
+    pub rip: u64,
+
+    /// When the event was raised.
+    pub timestamp: Timestamp,
+}
+
+/// The registration and challenge state, only touched by hypercalls and writes to protected pages.
+struct AgentState {
+    /// The key of the MAC.
+    key: [u64; 2],
+
+    /// The nonce of the pending challenge.
+    nonce: Option<u64>,
+
+    /// The number of challenges issued, which the agent authenticates the next one with.
+    challenges: u64,
+
+    /// The guest physical addresses of the protected pages. Entries are cleared once their protection is lifted.
+    pages: [Option<Gpa>; MAX_AGENT_PAGES],
+}
+
+/// Tracks the liveness and integrity of the guest agent.
+pub struct AgentMonitor {
+    /// The configuration, or `None` if the agent hypercalls are not served.
+    config: Option<AgentMonitorConfig>,
+
+    /// The `AgentStatus`.
+    status: AtomicU8,
+
+    /// The TSC of the last valid answer, or of the registration.
+    last_heartbeat: AtomicU64,
+
+    /// The registration and challenge state.
+    state: SpinLock<AgentState>,
+
+    /// The tamper events not drained yet.
+    events: EventLog<TamperEvent, EVENT_LOG_LEN>,
+
+    /// The number of challenges and answers refused for a wrong MAC.
+    rejected: AtomicU64,
+}
+
+impl AgentMonitor {
+    /// Creates the monitor.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration, or `None` to refuse the agent hypercalls.
+    pub fn new(config: Option<AgentMonitorConfig>) -> Self {
+        Self {
+            config,
+            status: AtomicU8::new(AgentStatus::Unregistered as u8),
+            last_heartbeat: AtomicU64::new(0),
+            state: SpinLock::new(
+                "agent_state",
+                AgentState {
+                    key: [0; 2],
+                    nonce: None,
+                    challenges: 0,
+                    pages: [None; MAX_AGENT_PAGES],
+                },
+            ),
+            events: EventLog::new("agent_events"),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns whether the agent hypercalls are served.
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    /// Returns the state of the agent.
+    pub fn status(&self) -> AgentStatus {
+        AgentStatus::from_u8(self.status.load(Ordering::Acquire))
+    }
+
+    /// Checks that the agent can register with the given pages, before the caller write-protects them.
+    ///
+    /// # Arguments
+    ///
+    /// * `pages` - The guest physical addresses of the pages to protect.
+    ///
+    /// # Returns
+    ///
+    /// `Ok` if the registration can proceed, or the status to return to the guest.
+    pub fn check_registration(&self, pages: &[Gpa]) -> Result<(), HypercallStatus> {
+        if !self.is_enabled() {
+            return Err(HypercallStatus::NotSupported);
+        }
+
+        // The EPT only maps the first 512GB, higher addresses would alias lower pages.
+        if pages.len() > MAX_AGENT_PAGES
+            || pages
+                .iter()
+                .any(|gpa| !gpa.is_page_aligned() || gpa.as_u64() >= _512GB)
+        {
+            return Err(HypercallStatus::InvalidParameter);
+        }
+
+        match self.status() {
+            AgentStatus::Unregistered => Ok(()),
+            _ => Err(HypercallStatus::AccessDenied),
+        }
+    }
+
+    /// Registers the agent and generates its key.
+    ///
+    /// The caller must have write-protected the pages in the EPT, and lift the protection again if the
+    /// registration fails, e.g. because another processor registered an agent in the meantime.
+    ///
+    /// # Arguments
+    ///
+    /// * `pages` - The guest physical addresses of the pages to protect, page aligned.
+    /// * `key` - The key of the MAC, drawn from the random number generator of the processor, see `Vmx::rng`.
+    ///
+    /// # Returns
+    ///
+    /// The key on success, or the status to return to the guest.
+    pub fn register(&self, pages: &[Gpa], key: [u64; 2]) -> Result<[u64; 2], HypercallStatus> {
+        let mut state = self.state.lock();
+        self.check_registration(pages)?;
+
+        state.key = key;
+        state.nonce = None;
+        state.challenges = 0;
+
+        for (slot, &gpa) in state.pages.iter_mut().zip(pages) {
+            *slot = Some(gpa);
+        }
+
+        self.last_heartbeat.store(rdtsc(), Ordering::Relaxed);
+        self.status
+            .store(AgentStatus::Alive as u8, Ordering::Release);
+
+        log::info!(
+            "Guest agent registered with {} protected pages",
+            pages.len()
+        );
+
+        Ok(key)
+    }
+
+    /// Issues a new challenge, replacing the pending one.
+    ///
+    /// # Arguments
+    ///
+    /// * `mac` - The SipHash-2-4 of `[AGENT_CHALLENGE_TAG, n]` under the key, where `n` is the number of
+    ///   challenges issued so far plus one.
+    /// * `nonce` - The nonce, drawn from the random number generator of the processor, see `Vmx::rng`.
+    ///
+    /// # Returns
+    ///
+    /// The nonce to answer, or the status to return to the guest.
+    pub fn challenge(&self, mac: u64, nonce: u64) -> Result<u64, HypercallStatus> {
+        self.check_registered()?;
+
+        let mut state = self.state.lock();
+        let counter = state.challenges + 1;
+
+        if siphash24(state.key, &[AGENT_CHALLENGE_TAG, counter]) != mac {
+            return Err(self.reject());
+        }
+
+        state.challenges = counter;
+        state.nonce = Some(nonce);
+
+        Ok(nonce)
+    }
+
+    /// Checks the answer to the pending challenge and refreshes the liveness of the agent.
+    ///
+    /// A wrong answer leaves the challenge pending, so other code cannot void the challenge of the agent.
+    ///
+    /// # Arguments
+    ///
+    /// * `mac` - The SipHash-2-4 of the nonce under the key.
+    ///
+    /// # Returns
+    ///
+    /// `Ok` if the answer is valid, or the status to return to the guest.
+    pub fn respond(&self, mac: u64) -> Result<(), HypercallStatus> {
+        self.check_registered()?;
+
+        let valid = {
+            let mut state = self.state.lock();
+            let key = state.key;
+            state
+                .nonce
+                .take_if(|nonce| siphash24(key, &[*nonce]) == mac)
+                .is_some()
+        };
+
+        if !valid {
+            return Err(self.reject());
+        }
+
+        self.last_heartbeat.store(rdtsc(), Ordering::Relaxed);
+
+        if self
+            .status
+            .compare_exchange(
+                AgentStatus::Unresponsive as u8,
+                AgentStatus::Alive as u8,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            log::info!("Guest agent is responsive again");
+        }
+
+        Ok(())
+    }
+
+    /// Raises a tamper event if the agent has not answered a challenge within the timeout.
+    ///
+    /// Called on every VM exit, so it only reads atomics unless the timeout has just expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `rip` - The guest RIP of the VM exit.
+    /// * `debugger` - The debugger monitor, used to suspend the hooks if configured.
+    pub fn check_liveness(&self, rip: u64, debugger: &DebuggerMonitor) {
+        let Some(config) = self.config else {
+            return;
+        };
+
+        if self.status() != AgentStatus::Alive {
+            return;
+        }
+
+        let last_heartbeat = self.last_heartbeat.load(Ordering::Relaxed);
+        if rdtsc().wrapping_sub(last_heartbeat) <= config.heartbeat_timeout {
+            return;
+        }
+
+        // Only the processor winning the transition raises the event.
+        if self
+            .status
+            .compare_exchange(
+                AgentStatus::Alive as u8,
+                AgentStatus::Unresponsive as u8,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            self.raise(TamperKind::Unresponsive { last_heartbeat }, rip, debugger);
+        }
+    }
+
+    /// Handles a write to a guest physical page, which is a tamper event if the page is protected.
+    ///
+    /// The protection of the page is given up, so the caller must restore write access to it in the EPT.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address written to.
+    /// * `rip` - The guest RIP of the write.
+    /// * `debugger` - The debugger monitor, used to suspend the hooks if configured.
+    ///
+    /// # Returns
+    ///
+    /// The protected page containing the address, or `None` if it is not protected.
+    pub fn handle_write(&self, guest_pa: Gpa, rip: u64, debugger: &DebuggerMonitor) -> Option<Gpa> {
+        if self.status() == AgentStatus::Unregistered {
+            return None;
+        }
+
+        let page = guest_pa.page_base();
+
+        self.state
+            .lock()
+            .pages
+            .iter_mut()
+            .find(|slot| **slot == Some(page))?
+            .take();
+
+        self.status
+            .store(AgentStatus::Tampered as u8, Ordering::Release);
+        self.raise(TamperKind::PageModified { guest_pa }, rip, debugger);
+
+        Some(page)
+    }
+
+    /// Returns whether a guest physical address lies in a page protected for the agent.
+    pub fn protects(&self, guest_pa: Gpa) -> bool {
+        let page = guest_pa.page_base();
+
+        self.status() != AgentStatus::Unregistered && self.state.lock().pages.contains(&Some(page))
+    }
+
+    /// Hands the pending tamper events to a consumer, oldest first, and removes them.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    pub fn drain_events(&self, consumer: impl FnMut(&TamperEvent)) -> usize {
+        self.events.drain(consumer)
+    }
+
+    /// Returns the total number of tamper events raised, including the ones that were overwritten.
+    pub fn total_events(&self) -> u64 {
+        self.events.total()
+    }
+
+    /// Returns the number of challenges and answers refused for a wrong MAC.
+    pub fn rejected_calls(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Fails unless the agent hypercalls are served and the agent has registered.
+    fn check_registered(&self) -> Result<(), HypercallStatus> {
+        if !self.is_enabled() {
+            return Err(HypercallStatus::NotSupported);
+        }
+
+        match self.status() {
+            AgentStatus::Unregistered => Err(HypercallStatus::AccessDenied),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a challenge or answer refused for a wrong MAC.
+    ///
+    /// # Returns
+    ///
+    /// The status to return to the guest.
+    fn reject(&self) -> HypercallStatus {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        log::trace!("Guest agent hypercall refused for a wrong MAC");
+
+        HypercallStatus::AccessDenied
+    }
+
+    /// Records a tamper event and enacts the configured response.
+    fn raise(&self, kind: TamperKind, rip: u64, debugger: &DebuggerMonitor) {
+        log::warn!("Guest agent tamper event at {:#x}: {:?}", rip, kind);
+
+        self.events.push(TamperEvent {
+            kind,
+            rip,
+            timestamp: Timestamp::now(),
+        });
+
+        if self.config.map(|config| config.response) == Some(TamperResponse::SuspendHooks) {
+            debugger.suspend(SuspendReason::AgentTampered);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Tracking of the IA32_APIC_BASE writes of the guest, which relocate the local APIC or switch its mode.
+//!
+//! When enabled with `HypervisorBuilder::apic_base_tracking`, writes to IA32_APIC_BASE exit and are checked
+//! as the processor would, raising #GP(0) for reserved bits and for the invalid mode transitions (x2APIC to
+//! xAPIC, disabled to x2APIC), instead of faulting in VMX root operation. Valid writes are carried out and the
+//! state of the hypervisor follows the APIC of the processor:
+//! - Switching to x2APIC mode intercepts the x2APIC MSRs if `HypervisorBuilder::x2apic_interception` is set,
+//!   even if the processor did not run in x2APIC mode when it was virtualized.
+//! - Relocating the xAPIC page makes the new page uncacheable in the EPT, as MMIO has to be. The previous page
+//!   stays uncacheable, as other processors may still map their APIC there.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 11.4.4 Local APIC Status and
+//! Location, 11.12.1 Detecting and Enabling x2APIC Mode and 11.12.5 x2APIC State Transitions.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{ept::mtrr::MemoryType, invept::invept_all_processors, vmx::Vmx, x2apic},
+        utils::{
+            addresses::Gpa,
+            instructions::{rdmsr, wrmsr},
+        },
+    },
+    x86::{bits64::paging::BASE_PAGE_SIZE, cpuid::cpuid, msr},
+};
+
+/// IA32_APIC_BASE bit marking the bootstrap processor (BSP).
+const APIC_BASE_BSP: u64 = 1 << 8;
+
+/// IA32_APIC_BASE bit enabling x2APIC mode (EXTD).
+const APIC_BASE_EXTD: u64 = 1 << 10;
+
+/// IA32_APIC_BASE bit enabling the local APIC (EN).
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// The bits of IA32_APIC_BASE below the APIC page that are not reserved.
+const APIC_BASE_FLAGS: u64 = APIC_BASE_BSP | APIC_BASE_EXTD | APIC_BASE_ENABLE;
+
+/// The mode of a local APIC, selected by IA32_APIC_BASE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// The local APIC is disabled.
+    Disabled,
+
+    /// The local APIC is accessed through its memory-mapped page.
+    XApic,
+
+    /// The local APIC is accessed through the x2APIC MSRs.
+    X2Apic,
+
+    /// EXTD without EN, which the processor refuses.
+    Invalid,
+}
+
+/// The value of IA32_APIC_BASE of a processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicBase(u64);
+
+impl ApicBase {
+    /// Reads IA32_APIC_BASE of the current processor.
+    pub fn current() -> Self {
+        Self(rdmsr(msr::IA32_APIC_BASE))
+    }
+
+    /// Wraps a value of IA32_APIC_BASE.
+    pub fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    /// Returns the raw value.
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the mode of the local APIC.
+    pub fn mode(&self) -> ApicMode {
+        match (self.0 & APIC_BASE_ENABLE != 0, self.0 & APIC_BASE_EXTD != 0) {
+            (false, false) => ApicMode::Disabled,
+            (true, false) => ApicMode::XApic,
+            (true, true) => ApicMode::X2Apic,
+            (false, true) => ApicMode::Invalid,
+        }
+    }
+
+    /// Returns the physical address of the xAPIC page.
+    pub fn page(&self) -> Gpa {
+        Gpa::new(self.0).page_base()
+    }
+
+    /// Returns whether the processor accepts a write of IA32_APIC_BASE replacing this value, or raises #GP(0).
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value written.
+    pub fn accepts(&self, value: u64) -> bool {
+        let reserved = !(physical_address_mask() | APIC_BASE_FLAGS);
+        if value & reserved != 0 {
+            return false;
+        }
+
+        // Leaving x2APIC mode goes through the disabled state, and x2APIC mode is entered from xAPIC mode.
+        !matches!(
+            (self.mode(), Self::new(value).mode()),
+            (_, ApicMode::Invalid)
+                | (ApicMode::X2Apic, ApicMode::XApic)
+                | (ApicMode::Disabled, ApicMode::X2Apic)
+        )
+    }
+}
+
+/// Returns the mask of the bits of a page address supported by the processor (MAXPHYADDR).
+fn physical_address_mask() -> u64 {
+    const CPUID_ADDRESS_SIZES: u32 = 0x8000_0008;
+
+    // Processors without the leaf support 36-bit physical addresses.
+    let bits = match cpuid!(0x8000_0000).eax >= CPUID_ADDRESS_SIZES {
+        true => cpuid!(CPUID_ADDRESS_SIZES).eax & 0xFF,
+        false => 36,
+    };
+
+    ((1u64 << bits) - 1) & !0xFFF
+}
+
+/// Carries out a write of the guest to IA32_APIC_BASE and updates the interceptions for the new state of the
+/// local APIC.
+///
+/// # Arguments
+///
+/// * `vmx` - The VMX instance of the processor.
+/// * `value` - The value written.
+///
+/// # Returns
+///
+/// `false` if the processor would refuse the write, in which case the caller raises #GP(0).
+pub fn write(vmx: &mut Vmx, value: u64) -> Result<bool, HypervisorError> {
+    let old = vmx.apic_base;
+    if !old.accepts(value) {
+        log::trace!("Invalid IA32_APIC_BASE write: {:#x}", value);
+        return Ok(false);
+    }
+
+    wrmsr(msr::IA32_APIC_BASE, value);
+    let new = ApicBase::new(value);
+    vmx.apic_base = new;
+
+    if old.mode() != new.mode() {
+        log::debug!(
+            "Local APIC switched from {:?} to {:?}",
+            old.mode(),
+            new.mode()
+        );
+    }
+
+    let shared_data = vmx.shared_data();
+
+    if new.mode() == ApicMode::X2Apic
+        && old.mode() != ApicMode::X2Apic
+        && shared_data.x2apic_interception
+    {
+        for msr in x2apic::x2apic_msrs() {
+            shared_data.msr_bitmap.intercept_msr(msr, true, true);
+        }
+    }
+
+    if new.mode() == ApicMode::XApic && (old.mode() != ApicMode::XApic || old.page() != new.page())
+    {
+        log::debug!("Local APIC page at {:#x}", new.page());
+
+        // The processor already moved its APIC, so a failure only leaves the page with its memory type of RAM,
+        // e.g. when it lies beyond the memory the EPT maps, and must not stop the hypervisor.
+        let page = new.page();
+        match shared_data
+            .set_memory_type(page..page + BASE_PAGE_SIZE as u64, MemoryType::Uncacheable)
+        {
+            Ok(()) => invept_all_processors(),
+            Err(e) => log::warn!(
+                "Failed to map the local APIC page {:#x} uncacheable: {:?}",
+                page,
+                e
+            ),
+        }
+    }
+
+    Ok(true)
+}
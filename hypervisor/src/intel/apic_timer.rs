@@ -0,0 +1,89 @@
+//! Detection of the local APIC timer mode, and the TSC-deadline timer of a guest with a virtualized TSC.
+//!
+//! The APIC timer counts in one of three modes, selected by bits 18:17 of the LVT Timer register:
+//! - One-shot and periodic: the timer counts down the initial count at the bus clock, independently of the TSC.
+//!   The hypervisor never touches these registers, so the timers of the guest are not affected by it.
+//! - TSC-deadline: the timer fires once the TSC reaches the value written to IA32_TSC_DEADLINE. The processor
+//!   compares it with its own TSC, not with the TSC of the guest, as TSC offsetting and scaling only apply to
+//!   RDTSC, RDTSCP and RDMSR of IA32_TIME_STAMP_COUNTER.
+//!
+//! Once the TSC is virtualized, see `intel::tsc`, the deadlines of the guest are in guest ticks and would fire
+//! early or late, more so as the compensation of the time spent in VMX root operation lowers the offset on every
+//! exit. IA32_TSC_DEADLINE is therefore intercepted: writes are translated into the TSC of the processor, reads
+//! return the deadline of the guest, and a pending deadline is reprogrammed after every exit that changed the
+//! offset, see `VirtualTsc::end_exit`. The hypervisor programs no timer of its own.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 11.5.4 APIC Timer and 11.5.4.1
+//! TSC-Deadline Mode.
+
+use {
+    crate::{
+        intel::x2apic,
+        utils::instructions::{rdmsr, wrmsr},
+    },
+    x86::cpuid::cpuid,
+};
+
+/// The MSR holding the deadline of the APIC timer in TSC-deadline mode.
+pub const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// The x2APIC MSR of the LVT Timer register.
+const X2APIC_LVT_TIMER: u32 = 0x832;
+
+/// The position of the timer mode in the LVT Timer register.
+const LVT_TIMER_MODE_SHIFT: u32 = 17;
+
+/// CPUID.01H:ECX bit indicating support for the TSC-deadline mode.
+const CPUID_01_ECX_TSC_DEADLINE: u32 = 1 << 24;
+
+/// The mode of the local APIC timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicTimerMode {
+    /// The timer counts down the initial count once.
+    OneShot,
+
+    /// The timer counts down the initial count repeatedly.
+    Periodic,
+
+    /// The timer fires when the TSC reaches IA32_TSC_DEADLINE.
+    TscDeadline,
+}
+
+/// Returns whether the processor supports the TSC-deadline mode.
+pub fn has_tsc_deadline() -> bool {
+    cpuid!(0x1).ecx & CPUID_01_ECX_TSC_DEADLINE != 0
+}
+
+/// Returns the mode of the APIC timer of the current processor.
+///
+/// # Returns
+///
+/// The mode, or `None` if the local APIC runs in xAPIC mode, where the LVT Timer register is only reachable
+/// through the memory mapped APIC page.
+pub fn timer_mode() -> Option<ApicTimerMode> {
+    if !x2apic::is_x2apic_enabled() {
+        return None;
+    }
+
+    match (rdmsr(X2APIC_LVT_TIMER) >> LVT_TIMER_MODE_SHIFT) & 0b11 {
+        0 => Some(ApicTimerMode::OneShot),
+        1 => Some(ApicTimerMode::Periodic),
+        2 => Some(ApicTimerMode::TscDeadline),
+        _ => None,
+    }
+}
+
+/// Returns the pending deadline of the TSC-deadline timer of the current processor, zero if it is disarmed or
+/// has fired.
+pub fn tsc_deadline() -> u64 {
+    rdmsr(IA32_TSC_DEADLINE)
+}
+
+/// Arms the TSC-deadline timer of the current processor, or disarms it with zero.
+///
+/// # Arguments
+///
+/// * `deadline` - The TSC of the processor at which the timer fires.
+pub fn set_tsc_deadline(deadline: u64) {
+    wrmsr(IA32_TSC_DEADLINE, deadline);
+}
@@ -0,0 +1,232 @@
+//! The report logged once the processors are virtualized.
+//!
+//! Instead of one line per detected feature and enabled subsystem, spread over the initialization, the
+//! hypervisor logs a single block at the info level: the processor model and microcode, the VMX capabilities the
+//! hypervisor depends on, the controls written to the VMCS, the enabled subsystems and the memory footprint. The
+//! block is meant to be pasted as is into a support request. The details are in the debug log and the effective
+//! configuration, see `intel::effective_config`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Appendix A VMX Capability Reporting
+//! Facility, and CPUID—CPU Identification, Leaves 01H and 80000002H-80000004H.
+
+use {
+    crate::{
+        intel::{
+            controls::TscMode,
+            paravirt::ParavirtFeatures,
+            shared_data::SharedData,
+            vmexit::{invd::CacheFlushPolicy, mwait::MwaitPolicy},
+        },
+        utils::{cpu, footprint::MemoryFootprint},
+    },
+    core::fmt,
+};
+
+/// The report of the capabilities and the configuration of the hypervisor.
+pub struct BootReport<'a> {
+    /// The shared data, holding the platform snapshot and the configuration.
+    shared_data: &'a SharedData,
+
+    /// The number of processors of the system.
+    processor_count: usize,
+
+    /// The memory consumed by the hypervisor.
+    footprint: MemoryFootprint,
+}
+
+impl<'a> BootReport<'a> {
+    /// Creates the report, capturing the current memory footprint.
+    ///
+    /// # Arguments
+    ///
+    /// * `shared_data` - The shared data of the virtualized processors.
+    /// * `processor_count` - The number of processors of the system.
+    pub fn new(shared_data: &'a SharedData, processor_count: usize) -> Self {
+        Self {
+            shared_data,
+            processor_count,
+            footprint: MemoryFootprint::current(),
+        }
+    }
+
+    fn write_cpu(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let brand = cpu::brand_string();
+        let brand = core::str::from_utf8(&brand)
+            .unwrap_or("")
+            .trim_matches(|c: char| c == '\0' || c == ' ');
+
+        // Reference: CPUID—CPU Identification, Figure 3-6. Version Information Returned by CPUID in EAX.
+        let signature = self
+            .shared_data
+            .platform_info
+            .cpuid(0x1, 0x0)
+            .map_or(0, |leaf| leaf.eax);
+        let stepping = signature & 0xF;
+        let mut family = (signature >> 8) & 0xF;
+        let mut model = (signature >> 4) & 0xF;
+        if family == 0x6 || family == 0xF {
+            model |= ((signature >> 16) & 0xF) << 4;
+        }
+        if family == 0xF {
+            family += (signature >> 20) & 0xFF;
+        }
+
+        writeln!(
+            f,
+            "CPU: {} (family {:#x}, model {:#x}, stepping {:#x})",
+            if brand.is_empty() { "unknown" } else { brand },
+            family,
+            model,
+            stepping
+        )?;
+
+        let cores = self.shared_data.core_capabilities.lock();
+        let mut microcodes = cores.iter().flatten().map(|core| core.microcode);
+        match microcodes.next() {
+            Some(first) if microcodes.all(|microcode| microcode == first) => {
+                writeln!(f, "Microcode: {:#x}", first)?
+            }
+            Some(_) => writeln!(
+                f,
+                "Microcode: differs between cores, see the effective configuration"
+            )?,
+            None => writeln!(f, "Microcode: unknown")?,
+        }
+
+        writeln!(
+            f,
+            "Processors: {} of {} virtualized ({}), hybrid: {}",
+            cores.iter().flatten().count(),
+            self.processor_count,
+            self.shared_data.cpu_set,
+            cpu::is_hybrid()
+        )?;
+        writeln!(
+            f,
+            "Host hypervisor: {}",
+            self.shared_data.platform_info.host_hypervisor
+        )
+    }
+
+    fn write_vmx(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let platform = &self.shared_data.platform_info;
+
+        writeln!(
+            f,
+            "VMX: revision {:#x}, true controls: {}, EPT A/D: {}, VPID: {}, virtual NMIs: {}, TSC scaling: {}",
+            platform.vmx.basic as u32 & 0x7FFF_FFFF,
+            platform.has_true_controls(),
+            platform.has_ept_accessed_dirty(),
+            cpu::has_vpid(),
+            cpu::has_virtual_nmis(),
+            cpu::has_tsc_scaling()
+        )?;
+
+        let controls = self.shared_data.vmcs_controls;
+        writeln!(
+            f,
+            "Controls: pin-based {:#x}, primary {:#x}, secondary {:#x}, exit {:#x}, entry {:#x}",
+            controls.pinbased, controls.primary, controls.secondary, controls.exit, controls.entry
+        )
+    }
+
+    fn write_subsystems(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shared_data = self.shared_data;
+
+        // The device monitors are compiled out without the `devices` feature.
+        #[cfg(feature = "devices")]
+        let (keyboard_guard, io_monitor) = (
+            shared_data.keyboard_guard.is_enabled(),
+            shared_data.io_monitor.is_enabled(),
+        );
+        #[cfg(not(feature = "devices"))]
+        let (keyboard_guard, io_monitor) = (false, false);
+
+        let subsystems = [
+            ("secondary-ept", cfg!(feature = "secondary-ept")),
+            ("paravirt", shared_data.paravirt.is_enabled()),
+            (
+                "developer-mode",
+                shared_data.paravirt.offers(ParavirtFeatures::DIAGNOSTICS),
+            ),
+            ("debugger-monitor", shared_data.debugger.is_enabled()),
+            ("keyboard-guard", keyboard_guard),
+            ("io-monitor", io_monitor),
+            ("exit-history", cfg!(feature = "tracing")),
+            ("agent-monitor", shared_data.agent_monitor.is_enabled()),
+            ("driver-blocker", shared_data.driver_blocker.is_enabled()),
+            ("client-sessions", shared_data.client_sessions.is_enabled()),
+            ("thrash-detection", shared_data.thrash_policy.is_some()),
+            ("lbr-virtualization", shared_data.lbr_stack.is_some()),
+            ("cpuid-topology", shared_data.cpuid_topology.is_some()),
+            ("cr3-exiting", shared_data.cr3_observer.is_some()),
+            ("invlpg-exiting", shared_data.invlpg_exiting),
+            (
+                "descriptor-table-exiting",
+                shared_data.descriptor_table_exiting,
+            ),
+            ("hlt-exiting", shared_data.hlt_callback.is_some()),
+            (
+                "wbinvd-exiting",
+                shared_data.cache_flush_policy == CacheFlushPolicy::Skip,
+            ),
+            (
+                "mwait-exiting",
+                shared_data.mwait_policy != MwaitPolicy::Native,
+            ),
+            (
+                "host-breakpoints",
+                shared_data.host_breakpoints.iter().any(Option::is_some),
+            ),
+            ("pause-loop-exiting", shared_data.pause_loop.is_some()),
+            ("encls-exiting", shared_data.encls_exiting.is_some()),
+            ("fixed-rng-seed", shared_data.rng_seed.is_some()),
+            ("apic-base-tracking", shared_data.apic_base_tracking),
+            (
+                "ept-violation-callback",
+                shared_data.ept_violation_callback.is_some(),
+            ),
+            ("smm-monitoring", shared_data.smm_monitor.is_enabled()),
+            (
+                "tsc-virtualization",
+                shared_data.tsc_config.mode != TscMode::Native,
+            ),
+            #[cfg(feature = "introspection")]
+            ("exit-heat-map", shared_data.heat_map.is_enabled()),
+            #[cfg(feature = "introspection")]
+            ("coverage", shared_data.coverage.is_enabled()),
+            #[cfg(feature = "introspection")]
+            ("fault-injection", shared_data.fault_injector.is_enabled()),
+            #[cfg(feature = "introspection")]
+            ("heap-poisoning", shared_data.heap_poison.is_enabled()),
+            #[cfg(feature = "introspection")]
+            ("process-control", shared_data.processes.is_enabled()),
+        ];
+
+        write!(f, "Subsystems:")?;
+        let mut enabled = subsystems.iter().filter(|(_, enabled)| *enabled).peekable();
+        if enabled.peek().is_none() {
+            write!(f, " none")?;
+        }
+        for (name, _) in enabled {
+            write!(f, " {}", name)?;
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for BootReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "===== {} {} =====",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )?;
+        self.write_cpu(f)?;
+        self.write_vmx(f)?;
+        self.write_subsystems(f)?;
+        writeln!(f, "Memory: {}", self.footprint)?;
+        write!(f, "=====")
+    }
+}
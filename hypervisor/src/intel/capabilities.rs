@@ -0,0 +1,129 @@
+//! A registry of the commands offered to clients, so they can discover what the running hypervisor supports
+//! instead of hardcoding it.
+//!
+//! Every subsystem serving hypercalls declares them next to its code as a `Subsystem` constant, with a summary of
+//! each command and whether the subsystem is enabled at runtime. The registry walks `HypercallCode::ALL`, the table
+//! hypercalls are decoded with, and `subsystem` maps every code to its subsystem. A hypercall compiled out by a
+//! crate feature is therefore absent from the registry, and a new one cannot be added without a subsystem.
+//!
+//! The registry is exported as text, one command per line with `key=value` fields and the free-form summary
+//! last, through the `ListCapabilities` hypercall or `Hypervisor::capabilities`:
+//!
+//! ```text
+//! name=CoverageReset code=0x200 subsystem=coverage access=control enabled=false summary=Ends a fuzzing iteration
+//! ```
+//!
+//! The access is the one required from a client session, see `HypercallCode::access`: `public` commands need
+//! none, `read-only` commands any session, and `control` commands an admin session.
+
+use {
+    crate::intel::{
+        agent_monitor, coverage, effective_config, ept, fault_injection, heap_poison,
+        hypercall::{HypercallAccess, HypercallCode},
+        metrics, paravirt, processes, sessions,
+        shared_data::SharedData,
+    },
+    core::fmt,
+};
+
+/// A command offered by a subsystem.
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    /// The hypercall serving the command.
+    pub code: HypercallCode,
+
+    /// What the command does, on a single line.
+    pub summary: &'static str,
+}
+
+/// The commands of a subsystem.
+#[derive(Clone, Copy)]
+pub struct Subsystem {
+    /// The name of the subsystem, in lowercase.
+    pub name: &'static str,
+
+    /// Returns whether the subsystem is enabled. The commands of a disabled subsystem fail with
+    /// `HypercallStatus::NotSupported` or a similar status.
+    pub enabled: fn(&SharedData) -> bool,
+
+    /// The commands of the subsystem.
+    pub commands: &'static [Capability],
+}
+
+/// The commands of this registry.
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "capabilities",
+    enabled: |_| true,
+    commands: &[Capability {
+        code: HypercallCode::ListCapabilities,
+        summary: "Lists the commands offered by the hypervisor",
+    }],
+};
+
+/// Returns the subsystem serving a hypercall.
+pub fn subsystem(code: HypercallCode) -> &'static Subsystem {
+    match code {
+        HypercallCode::Identify => &paravirt::CAPABILITIES,
+        HypercallCode::SessionOpen | HypercallCode::SessionClose => &sessions::CAPABILITIES,
+        HypercallCode::AgentRegister
+        | HypercallCode::AgentChallenge
+        | HypercallCode::AgentRespond => &agent_monitor::CAPABILITIES,
+        HypercallCode::CoverageReset | HypercallCode::CoverageRead => &coverage::CAPABILITIES,
+        HypercallCode::FaultFlipBits
+        | HypercallCode::FaultFailCalls
+        | HypercallCode::FaultRaiseException
+        | HypercallCode::FaultClear => &fault_injection::CAPABILITIES,
+        HypercallCode::EptDump => &ept::dump::CAPABILITIES,
+        HypercallCode::GetEffectiveConfig => &effective_config::CAPABILITIES,
+        HypercallCode::HeapPoison | HypercallCode::HeapUnpoison => &heap_poison::CAPABILITIES,
+        HypercallCode::GetMetrics => &metrics::CAPABILITIES,
+        HypercallCode::ListProcesses | HypercallCode::TerminateProcess => &processes::CAPABILITIES,
+        HypercallCode::ListCapabilities => &CAPABILITIES,
+    }
+}
+
+/// The registry as seen by the running hypervisor.
+pub struct CapabilityList<'a> {
+    /// The state shared between the processors, telling which subsystems are enabled.
+    shared_data: &'a SharedData,
+}
+
+impl<'a> CapabilityList<'a> {
+    /// Creates the view of the registry.
+    ///
+    /// # Arguments
+    ///
+    /// * `shared_data` - The state shared between the processors.
+    pub fn new(shared_data: &'a SharedData) -> Self {
+        Self { shared_data }
+    }
+}
+
+impl fmt::Display for CapabilityList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &code in HypercallCode::ALL {
+            let subsystem = subsystem(code);
+            let enabled = (subsystem.enabled)(self.shared_data);
+
+            let summary = subsystem
+                .commands
+                .iter()
+                .find(|command| command.code == code)
+                .map_or("", |command| command.summary);
+
+            let access = match code.access() {
+                HypercallAccess::Public => "public",
+                HypercallAccess::ReadOnly => "read-only",
+                HypercallAccess::Control => "control",
+            };
+
+            writeln!(
+                f,
+                "name={:?} code={:#x} subsystem={} access={} enabled={} summary={}",
+                code, code as u64, subsystem.name, access, enabled, summary
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -2,7 +2,13 @@
 //! Provides mechanisms for adjusting VMX controls based on certain conditions
 //! and capabilities, ensuring safe and effective VMX operations.
 
-use x86::msr;
+use {
+    crate::{error::HypervisorError, intel::evmcs, utils::cpu},
+    x86::{
+        msr,
+        vmx::vmcs::control::{PrimaryControls, SecondaryControls},
+    },
+};
 
 /// Enumerates the types of VMX control fields.
 #[derive(Clone, Copy)]
@@ -14,6 +20,26 @@ pub enum VmxControl {
     VmEntry,
 }
 
+/// The VM-execution, VM-exit and VM-entry controls written to the VMCS, after adjustment to the capabilities of
+/// the processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VmcsControls {
+    /// The pin-based VM-execution controls.
+    pub pinbased: u64,
+
+    /// The primary processor-based VM-execution controls.
+    pub primary: u64,
+
+    /// The secondary processor-based VM-execution controls.
+    pub secondary: u64,
+
+    /// The VM-exit controls.
+    pub exit: u64,
+
+    /// The VM-entry controls.
+    pub entry: u64,
+}
+
 /// Adjusts the VMX controls based on the requested value and capabilities.
 ///
 /// # Arguments
@@ -23,14 +49,14 @@ pub enum VmxControl {
 ///
 /// # Returns
 ///
-/// Returns the adjusted control value based on system capabilities and the requested value.
-pub fn adjust_vmx_controls(control: VmxControl, requested_value: u64) -> u64 {
-    const IA32_VMX_BASIC_VMX_CONTROLS_FLAG: u64 = 1 << 55;
-
-    let vmx_basic = unsafe { msr::rdmsr(msr::IA32_VMX_BASIC) };
-    let true_cap_msr_supported = (vmx_basic & IA32_VMX_BASIC_VMX_CONTROLS_FLAG) != 0;
-
-    let cap_msr = match (control, true_cap_msr_supported) {
+/// Returns the adjusted control value based on system capabilities and the requested value, without the controls
+/// the enlightened VMCS has no place for while it is used, see `evmcs::unsupported_controls`,
+/// or `HypervisorError::InvalidVmxControlValue` if the requested value does not fit a 32-bit control field.
+pub fn adjust_vmx_controls(
+    control: VmxControl,
+    requested_value: u64,
+) -> Result<u64, HypervisorError> {
+    let cap_msr = match (control, cpu::has_true_vmx_controls()) {
         (VmxControl::PinBased, true) => msr::IA32_VMX_TRUE_PINBASED_CTLS,
         (VmxControl::PinBased, false) => msr::IA32_VMX_PINBASED_CTLS,
         (VmxControl::ProcessorBased, true) => msr::IA32_VMX_TRUE_PROCBASED_CTLS,
@@ -46,8 +72,149 @@ pub fn adjust_vmx_controls(control: VmxControl, requested_value: u64) -> u64 {
     let capabilities = unsafe { msr::rdmsr(cap_msr) };
     let allowed0 = capabilities as u32;
     let allowed1 = (capabilities >> 32) as u32;
-    let mut effective_value = u32::try_from(requested_value).unwrap();
+    let mut effective_value =
+        u32::try_from(requested_value).map_err(|_| HypervisorError::InvalidVmxControlValue)?;
     effective_value |= allowed0;
-    effective_value &= allowed1;
-    u64::from(effective_value)
+    effective_value &= allowed1 & !evmcs::unsupported_controls(control);
+    Ok(u64::from(effective_value))
+}
+
+/// How the guest reads the time-stamp counter with RDTSC and RDTSCP, see `intel::tsc`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TscMode {
+    /// The guest reads the TSC of the processor, without exiting.
+    #[default]
+    Native,
+
+    /// The guest reads the TSC of the processor, scaled and offset by the processor, without exiting.
+    Offsetting,
+
+    /// RDTSC and RDTSCP exit, and the hypervisor returns the scaled and offset TSC.
+    Exiting,
+}
+
+/// Returns the VM-execution controls implementing a TSC mode.
+///
+/// # Arguments
+///
+/// * `mode` - How the guest reads the TSC.
+/// * `scaling` - Whether the processor scales the TSC the guest reads, only used when offsetting.
+///
+/// # Returns
+///
+/// The primary and secondary processor-based controls to set, to be adjusted with `adjust_vmx_controls`.
+pub fn tsc_controls(mode: TscMode, scaling: bool) -> (u64, u64) {
+    match (mode, scaling) {
+        (TscMode::Native, _) => (0, 0),
+        (TscMode::Offsetting, false) => (PrimaryControls::USE_TSC_OFFSETTING.bits() as u64, 0),
+        (TscMode::Offsetting, true) => (
+            PrimaryControls::USE_TSC_OFFSETTING.bits() as u64,
+            SecondaryControls::USE_TSC_SCALING.bits() as u64,
+        ),
+        // RDTSCP exits as well, as it is enabled in the secondary controls.
+        (TscMode::Exiting, _) => (PrimaryControls::RDTSC_EXITING.bits() as u64, 0),
+    }
+}
+
+/// The PAUSE-loop exiting window of the guest, see `intel::spin_monitor`.
+///
+/// The processor considers PAUSE instructions executed at CPL 0 less than `gap` TSC ticks apart to belong to the
+/// same spin loop, and exits once a loop has been spinning for more than `window` TSC ticks.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM
+/// Exits Conditionally (PAUSE) and 25.6.13 Controls for PAUSE-Loop Exiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PauseLoopConfig {
+    /// The maximum number of TSC ticks between two PAUSE instructions of the same loop, written to PLE_Gap.
+    pub gap: u32,
+
+    /// The number of TSC ticks a loop spins before exiting, written to PLE_Window.
+    pub window: u32,
+}
+
+impl Default for PauseLoopConfig {
+    /// The gap and window KVM uses by default, which let short spins complete without exiting.
+    fn default() -> Self {
+        Self {
+            gap: 128,
+            window: 4096,
+        }
+    }
+}
+
+/// Returns the VM-execution controls implementing PAUSE-loop exiting.
+///
+/// # Arguments
+///
+/// * `config` - The window of the guest, or `None` to let PAUSE run without exiting.
+///
+/// # Returns
+///
+/// The secondary processor-based controls to set, to be adjusted with `adjust_vmx_controls`.
+pub fn pause_loop_controls(config: Option<PauseLoopConfig>) -> u64 {
+    match config {
+        Some(_) => SecondaryControls::PAUSE_LOOP_EXITING.bits() as u64,
+        None => 0,
+    }
+}
+
+/// The ENCLS leaf functions that exit, see `vmexit::encls`. Bit n makes the leaf n, selected by EAX, exit, and bit
+/// 63 the leaves 63 and above.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.16 ENCLS-Exiting Bitmap and
+/// 38.1 INTEL SGX INSTRUCTION SYNTAX AND OPERATION (ENCLS leaf functions).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EnclsExitingBitmap(pub u64);
+
+impl EnclsExitingBitmap {
+    /// ECREATE, creating an enclave.
+    pub const ECREATE: u32 = 0x00;
+
+    /// EADD, adding a page to an uninitialized enclave.
+    pub const EADD: u32 = 0x01;
+
+    /// EINIT, initializing an enclave.
+    pub const EINIT: u32 = 0x02;
+
+    /// EEXTEND, measuring a page of an uninitialized enclave.
+    pub const EEXTEND: u32 = 0x06;
+
+    /// The leaves building and launching an enclave, blocking them keeps the guest from creating enclaves while
+    /// the existing ones keep running.
+    pub const ENCLAVE_CREATION: Self =
+        Self((1 << Self::ECREATE) | (1 << Self::EADD) | (1 << Self::EINIT) | (1 << Self::EEXTEND));
+
+    /// Returns the bitmap with a leaf added.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf function, as in EAX.
+    pub fn with_leaf(self, leaf: u32) -> Self {
+        Self(self.0 | (1 << leaf.min(63)))
+    }
+
+    /// Returns whether a leaf exits.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf function, as in EAX.
+    pub fn contains(&self, leaf: u32) -> bool {
+        self.0 & (1 << leaf.min(63)) != 0
+    }
+}
+
+/// Returns the VM-execution controls implementing ENCLS exiting.
+///
+/// # Arguments
+///
+/// * `bitmap` - The leaves that exit, or `None` to let ENCLS run without exiting.
+///
+/// # Returns
+///
+/// The secondary processor-based controls to set, to be adjusted with `adjust_vmx_controls`.
+pub fn encls_controls(bitmap: Option<EnclsExitingBitmap>) -> u64 {
+    match bitmap {
+        Some(_) => SecondaryControls::ENCLS_EXITING.bits() as u64,
+        None => 0,
+    }
 }
@@ -0,0 +1,300 @@
+//! Code coverage of a guest module, collected through EPT execute permissions for coverage-guided fuzzing.
+//!
+//! The pages of the target module are resolved to guest physical pages when the hypervisor is built and
+//! mapped without execute permission in the EPT. The first instruction fetch from such a page causes an EPT
+//! violation, which marks the page in the coverage bitmap and makes the page executable again, so every
+//! page costs a single VM exit per fuzzing iteration. Coverage is therefore collected at page granularity.
+//!
+//! Two bitmaps are kept, one bit per page of the module: the pages executed since the last reset, and the
+//! pages ever executed. A fuzzing controller reads the former through the `CoverageRead` hypercall or
+//! `Hypervisor::coverage`, and starts the next iteration with `CoverageReset`, which reports how many pages
+//! were executed for the first time and removes the execute permission again.
+//!
+//! Pages of the module that are not resident when the hypervisor is built are not covered. A reset through
+//! the hypercall kicks the other processors to flush their translations, so the fuzzing target can run on any
+//! processor.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.2 EPT Violations.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            capabilities::{Capability, Subsystem},
+            hypercall::HypercallCode,
+        },
+        utils::{
+            addresses::{Gpa, Hva},
+            ssdt::sys_info::Sysinfo,
+        },
+    },
+    alloc::vec::Vec,
+    core::sync::atomic::{AtomicU64, Ordering},
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The hypercalls of the coverage map, see `intel::capabilities`.
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "coverage",
+    enabled: |shared_data| shared_data.coverage.is_enabled(),
+    commands: &[
+        Capability {
+            code: HypercallCode::CoverageReset,
+            summary: "Ends a fuzzing iteration",
+        },
+        Capability {
+            code: HypercallCode::CoverageRead,
+            summary: "Copies the coverage bitmap of the current iteration",
+        },
+    ],
+};
+
+/// The maximum number of pages of the target module, so the bitmap fits in a page.
+pub const MAX_COVERAGE_PAGES: usize = BASE_PAGE_SIZE * 8;
+
+/// The outcome of a fuzzing iteration, returned when the coverage is reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CoverageSummary {
+    /// The number of pages executed during the iteration.
+    pub hit_pages: u64,
+
+    /// The number of pages executed for the first time during the iteration.
+    pub new_pages: u64,
+}
+
+/// The coverage of the target module.
+pub struct CoverageMap {
+    /// Whether coverage is collected.
+    enabled: bool,
+
+    /// The name of the target module.
+    module: &'static str,
+
+    /// The base address of the module image.
+    base: Hva,
+
+    /// The number of pages of the module image.
+    page_count: usize,
+
+    /// The guest physical addresses of the resident pages, with their page index in the module, sorted by
+    /// address.
+    frames: Vec<(Gpa, usize)>,
+
+    /// The pages executed since the last reset, one bit per page.
+    current: Vec<AtomicU64>,
+
+    /// The pages ever executed, one bit per page.
+    seen: Vec<AtomicU64>,
+
+    /// The number of pages executed for the first time since the last reset.
+    new_pages: AtomicU64,
+}
+
+impl CoverageMap {
+    /// Creates a coverage map that collects nothing.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            module: "",
+            base: Hva::new(0),
+            page_count: 0,
+            frames: Vec::new(),
+            current: Vec::new(),
+            seen: Vec::new(),
+            new_pages: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates the coverage map of a loaded kernel module.
+    ///
+    /// Must be called at PASSIVE_LEVEL, as the module list is queried from the kernel.
+    ///
+    /// # Arguments
+    ///
+    /// * `module` - The name of the target module, e.g. `"target.sys"`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the coverage map, or an error if the module is not loaded or larger than
+    /// `MAX_COVERAGE_PAGES` pages.
+    pub fn capture(module: &'static str) -> Result<Self, HypervisorError> {
+        let (base, size) = Sysinfo::new()?
+            .get_module_base(module)
+            .ok_or(HypervisorError::CoverageModuleNotFound)?;
+
+        let base = Hva::from_ptr(base);
+        let page_count = (size as usize).div_ceil(BASE_PAGE_SIZE);
+        if page_count > MAX_COVERAGE_PAGES {
+            return Err(HypervisorError::CoverageTargetTooLarge);
+        }
+
+        let mut frames: Vec<(Gpa, usize)> = (0..page_count)
+            .filter_map(|index| {
+                let page = base + (index * BASE_PAGE_SIZE) as u64;
+                Some((page.to_hpa()?.to_gpa(), index))
+            })
+            .collect();
+
+        frames.sort_unstable();
+        frames.dedup_by_key(|(pa, _)| *pa);
+
+        log::info!(
+            "Collecting coverage of {} at {:#x}: {} of {} pages resident",
+            module,
+            base,
+            frames.len(),
+            page_count
+        );
+
+        let words = page_count.div_ceil(64);
+
+        Ok(Self {
+            enabled: true,
+            module,
+            base,
+            page_count,
+            frames,
+            current: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            seen: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            new_pages: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns whether coverage is collected.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the name of the target module.
+    pub fn module(&self) -> &'static str {
+        self.module
+    }
+
+    /// Returns the base address of the target module image.
+    pub fn base(&self) -> Hva {
+        self.base
+    }
+
+    /// Returns the number of pages of the target module, i.e. the number of bits of the bitmap.
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    /// Returns the size of the bitmap in bytes.
+    pub fn bitmap_len(&self) -> usize {
+        self.page_count.div_ceil(8)
+    }
+
+    /// Returns the guest physical addresses of the covered pages.
+    pub fn frames(&self) -> impl Iterator<Item = Gpa> + '_ {
+        self.frames.iter().map(|&(pa, _)| pa)
+    }
+
+    /// Marks the page of an instruction fetch as executed.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address of the fetch.
+    ///
+    /// # Returns
+    ///
+    /// The guest physical address of the page to make executable again, or `None` if it is not covered.
+    pub fn record(&self, guest_pa: Gpa) -> Option<Gpa> {
+        let page = guest_pa.page_base();
+
+        let position = self
+            .frames
+            .binary_search_by_key(&page, |&(pa, _)| pa)
+            .ok()?;
+        let &(_, index) = self.frames.get(position)?;
+
+        let (word, bit) = (index / 64, 1u64 << (index % 64));
+
+        if let Some(current) = self.current.get(word) {
+            current.fetch_or(bit, Ordering::Relaxed);
+        }
+
+        if let Some(seen) = self.seen.get(word) {
+            if seen.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+                log::trace!(
+                    "New coverage: {}+{:#x}",
+                    self.module,
+                    index * BASE_PAGE_SIZE
+                );
+                self.new_pages.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        Some(page)
+    }
+
+    /// Returns whether a page of the target module was executed since the last reset.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the page in the module image.
+    pub fn is_hit(&self, index: usize) -> bool {
+        self.current
+            .get(index / 64)
+            .is_some_and(|word| word.load(Ordering::Relaxed) & (1 << (index % 64)) != 0)
+    }
+
+    /// Returns the number of pages executed since the last reset.
+    pub fn hit_pages(&self) -> u64 {
+        Self::count(&self.current)
+    }
+
+    /// Returns the number of pages ever executed.
+    pub fn total_pages(&self) -> u64 {
+        Self::count(&self.seen)
+    }
+
+    /// Copies the pages executed since the last reset into a bitmap, bit `n % 8` of byte `n / 8` standing for
+    /// page `n` of the module.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - Receives the bitmap, at most `bitmap_len` bytes.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes copied.
+    pub fn copy_bitmap(&self, buffer: &mut [u8]) -> usize {
+        let len = buffer.len().min(self.bitmap_len());
+
+        for (chunk, word) in buffer[..len].chunks_mut(8).zip(self.current.iter()) {
+            let bytes = word.load(Ordering::Relaxed).to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+
+        len
+    }
+
+    /// Ends a fuzzing iteration by clearing the pages executed since the last reset.
+    ///
+    /// The caller must remove the execute permission from the `frames` again and invalidate the EPT.
+    ///
+    /// # Returns
+    ///
+    /// The coverage of the iteration.
+    pub fn reset(&self) -> CoverageSummary {
+        let hit_pages = self
+            .current
+            .iter()
+            .map(|word| u64::from(word.swap(0, Ordering::Relaxed).count_ones()))
+            .sum();
+
+        CoverageSummary {
+            hit_pages,
+            new_pages: self.new_pages.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the number of bits set in a bitmap.
+    fn count(bitmap: &[AtomicU64]) -> u64 {
+        bitmap
+            .iter()
+            .map(|word| u64::from(word.load(Ordering::Relaxed).count_ones()))
+            .sum()
+    }
+}
@@ -0,0 +1,177 @@
+//! Selection of the processors the hypervisor virtualizes.
+//!
+//! By default every processor is virtualized. `HypervisorBuilder::virtualized_processors` restricts virtualization
+//! to a set of systemwide processor indexes, e.g. to keep core 0 native for latency-sensitive work or to debug a
+//! single processor, and `HypervisorBuilder::exclude_core_type` leaves the cores of a type native on hybrid
+//! processors, e.g. the E-cores. The processors left out still get a `Vcpu`, so indexes keep matching the ones of
+//! the OS, but never enter VMX operation.
+//!
+//! Threads migrate freely between virtualized and native processors, and all state the guest observes has to stay
+//! consistent across such a migration:
+//! - Operations run by the hypervisor on every processor switch to each processor in turn and skip the native
+//!   ones, see `utils::processor::is_virtualized`.
+//! - The TSC of a native processor cannot be offset or scaled, so TSC virtualization requires every processor to
+//!   be virtualized, see `HypervisorError::PartialTscVirtualization`.
+//! - EPT hooks and memory protections only apply while the accessing thread runs on a virtualized processor, so
+//!   a thread could escape them by migrating to a native one. A partial set is therefore refused while any of
+//!   them is enabled, see `HypervisorError::PartialSecurityFeature`, and permission profiles cannot be applied
+//!   later on.
+//! - VMCALL raises #UD on a native processor. A client relying on the hypercalls has to pin its threads to
+//!   `Hypervisor::cpu_set`.
+//!
+//! The set has a bit for each processor up to `MAX_VCPUS`, the most the hypervisor supports.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 24.7 Enabling and Entering VMX
+//! Operation and 10.9 Programming Considerations for Hardware Multi-Threading Capable Processors.
+
+use {crate::utils::processor::MAX_VCPUS, core::fmt};
+
+/// The number of 64-bit words of a `CpuSet`.
+const WORDS: usize = MAX_VCPUS.div_ceil(64);
+
+/// A set of systemwide processor indexes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuSet([u64; WORDS]);
+
+impl Default for CpuSet {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl CpuSet {
+    /// Returns the set of every processor.
+    pub const fn all() -> Self {
+        Self([u64::MAX; WORDS])
+    }
+
+    /// Returns the empty set.
+    pub const fn empty() -> Self {
+        Self([0; WORDS])
+    }
+
+    /// Returns the set of the processors among the first 64 whose bits are set in the mask, as in an affinity
+    /// mask. Use `with` for the processors past the first 64.
+    ///
+    /// # Arguments
+    ///
+    /// * `mask` - Bit `n` selects processor `n`.
+    pub const fn from_mask(mask: u64) -> Self {
+        let mut words = [0; WORDS];
+        words[0] = mask;
+        Self(words)
+    }
+
+    /// Returns the set with the given processor added.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The systemwide index of the processor, ignored past `MAX_VCPUS`.
+    pub const fn with(self, index: u32) -> Self {
+        match Self::bit(index) {
+            Some((word, bit)) => {
+                let mut words = self.0;
+                words[word] |= bit;
+                Self(words)
+            }
+            None => self,
+        }
+    }
+
+    /// Returns the set with the given processor removed, e.g. `CpuSet::all().without(0)` to keep core 0 native.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The systemwide index of the processor, ignored past `MAX_VCPUS`.
+    pub const fn without(self, index: u32) -> Self {
+        match Self::bit(index) {
+            Some((word, bit)) => {
+                let mut words = self.0;
+                words[word] &= !bit;
+                Self(words)
+            }
+            None => self,
+        }
+    }
+
+    /// Returns whether the set contains the given processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The systemwide index of the processor.
+    pub const fn contains(&self, index: u32) -> bool {
+        match Self::bit(index) {
+            Some((word, bit)) => self.0[word] & bit != 0,
+            None => false,
+        }
+    }
+
+    /// Returns the number of processors of the set among the first `count` processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The number of processors of the system.
+    pub fn count_within(&self, count: u32) -> u32 {
+        (0..count).filter(|&index| self.contains(index)).count() as u32
+    }
+
+    /// Returns whether the set contains none of the first `count` processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The number of processors of the system.
+    pub fn is_empty_within(&self, count: u32) -> bool {
+        (0..count).all(|index| !self.contains(index))
+    }
+
+    /// Returns whether the set contains all of the first `count` processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The number of processors of the system.
+    pub fn is_full_within(&self, count: u32) -> bool {
+        (0..count).all(|index| self.contains(index))
+    }
+
+    /// Returns the word and the bit of the given processor, or `None` past `MAX_VCPUS`.
+    const fn bit(index: u32) -> Option<(usize, u64)> {
+        if (index as usize) < MAX_VCPUS {
+            Some((index as usize / 64, 1 << (index % 64)))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for CpuSet {
+    /// Formats the set as comma separated processor indexes, with consecutive ones as `start-end` ranges.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        let mut index = 0;
+
+        while index < MAX_VCPUS as u32 {
+            if !self.contains(index) {
+                index += 1;
+                continue;
+            }
+
+            let start = index;
+            while self.contains(index + 1) {
+                index += 1;
+            }
+
+            match start == index {
+                true => write!(f, "{}{}", separator, start)?,
+                false => write!(f, "{}{}-{}", separator, start, index)?,
+            }
+            separator = ",";
+            index += 1;
+        }
+
+        if separator.is_empty() {
+            write!(f, "none")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,422 @@
+//! Virtualization of the debug registers, so the hypervisor can own hardware breakpoints in the guest.
+//!
+//! Host breakpoints are set with `HypervisorBuilder::host_breakpoint` and take the breakpoint slots from DR3 down,
+//! as debuggers allocate them from DR0 up. They are checked when the hypervisor is built, see
+//! `HostBreakpoint::validate`. When any is set, MOV DR exits and debug exceptions are intercepted:
+//! - DR0 to DR3 and DR7 read by the guest are shadows holding the values it wrote last. The processor runs the
+//!   guest with the host breakpoints in their slots, and with the guest breakpoints in the other slots.
+//! - A guest breakpoint in a slot of the host is kept in the shadows but never triggers.
+//! - DR6 belongs to the guest: debug exceptions causing a VM exit do not update it, so hits of the host
+//!   breakpoints never show up in it.
+//! - A debug exception caused by a host breakpoint invokes its callback in VMX root operation and is not
+//!   delivered to the guest, unless a guest breakpoint or single-step triggered along with it.
+//!
+//! While a guest debugging session suspends the hooks, the host breakpoints yield: the processor runs the guest
+//! with the guest breakpoints in all slots, and the host breakpoints are armed again once the hooks resume.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 18.2 Debug Registers, 26.1.3
+//! Instructions That Cause VM Exits Conditionally (MOV DR) and Table 28-4. Exit Qualification for MOV DR.
+
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            support::{try_vmread, try_vmwrite},
+            vmexit::invlpg::is_canonical,
+            vmx::Vmx,
+        },
+        utils::{
+            capture::GuestRegisters,
+            instructions::{cr4, dr7_write, dr_address, dr_address_write},
+        },
+    },
+    x86::{controlregs::Cr4, vmx::vmcs::guest},
+};
+
+/// The number of breakpoint slots, DR0 to DR3.
+pub const BREAKPOINT_SLOTS: usize = 4;
+
+/// The B0 to B3 bits of DR6, reporting the breakpoints whose condition was met.
+const DR6_BREAKPOINT_HITS: u64 = 0xF;
+
+/// The bits of DR7 that read as 1.
+const DR7_RESERVED_ONE: u64 = 1 << 10;
+
+/// The bits of DR7 that read as 0: 12, 14 and 15.
+const DR7_RESERVED_ZERO: u64 = (1 << 12) | (1 << 14) | (1 << 15);
+
+/// DR7.GD, making MOV DR raise a debug exception. The processor clears it when delivering one.
+const DR7_GD: u64 = 1 << 13;
+
+/// DR7.GE, making data breakpoints precise on older processors.
+const DR7_GE: u64 = 1 << 9;
+
+/// The bits 63:32 of DR6 and DR7, whose setting raises #GP(0).
+pub const DR_UPPER_BITS: u64 = 0xFFFF_FFFF_0000_0000;
+
+/// What accesses trigger a breakpoint, as encoded in the R/W field of DR7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointCondition {
+    /// Instruction execution. The length must be `BreakpointLength::Byte`.
+    Execute = 0b00,
+
+    /// Data writes.
+    Write = 0b01,
+
+    /// Data reads and writes, but not instruction fetches.
+    ReadWrite = 0b11,
+}
+
+/// The size of the memory location of a breakpoint, as encoded in the LEN field of DR7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointLength {
+    Byte = 0b00,
+    Word = 0b01,
+    Qword = 0b10,
+    Dword = 0b11,
+}
+
+impl BreakpointLength {
+    /// Returns the size of the memory location in bytes.
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Self::Byte => 1,
+            Self::Word => 2,
+            Self::Dword => 4,
+            Self::Qword => 8,
+        }
+    }
+}
+
+/// Called in VMX root operation when a host breakpoint triggers, with the guest registers and the slot of the
+/// breakpoint. `GuestRegisters::rip` is the instruction of an execution breakpoint, which runs when the guest
+/// resumes, or the instruction after the access of a data breakpoint.
+pub type HostBreakpointCallback = fn(&mut GuestRegisters, &mut Vmx, slot: usize);
+
+/// A hardware breakpoint owned by the hypervisor, see `HypervisorBuilder::host_breakpoint`.
+#[derive(Clone, Copy)]
+pub struct HostBreakpoint {
+    /// The linear address of the breakpoint in the guest, aligned to its length.
+    pub address: u64,
+
+    /// What accesses trigger it.
+    pub condition: BreakpointCondition,
+
+    /// The size of the memory location.
+    pub length: BreakpointLength,
+
+    /// Invoked when it triggers.
+    pub callback: HostBreakpointCallback,
+}
+
+impl HostBreakpoint {
+    /// Checks that the processor monitors the breakpoint as requested: an execution breakpoint covers a single
+    /// byte, and the address is canonical and aligned to the length, as the processor ignores its low bits.
+    /// Called when the hypervisor is built, while the system still runs natively with the paging mode of the guest.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Err(HypervisorError::InvalidHostBreakpoint)` if the breakpoint would not trigger as
+    /// requested.
+    pub fn validate(&self) -> Result<(), HypervisorError> {
+        let la57 = cr4().contains(Cr4::CR4_ENABLE_LA57);
+
+        if self.condition == BreakpointCondition::Execute && self.length != BreakpointLength::Byte {
+            log::error!(
+                "Execution breakpoint {:#x} must be a byte long, not {:?}",
+                self.address,
+                self.length
+            );
+            return Err(HypervisorError::InvalidHostBreakpoint);
+        }
+
+        if self.address & (self.length.bytes() - 1) != 0 {
+            log::error!(
+                "Breakpoint {:#x} is not aligned to its length {:?}",
+                self.address,
+                self.length
+            );
+            return Err(HypervisorError::InvalidHostBreakpoint);
+        }
+
+        if !is_canonical(self.address, la57) {
+            log::error!("Breakpoint {:#x} is not canonical", self.address);
+            return Err(HypervisorError::InvalidHostBreakpoint);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the DR7 bits enabling the breakpoint in a slot, as a global breakpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot of the breakpoint, 0 to 3.
+    fn dr7_bits(&self, slot: usize) -> u64 {
+        let control = (self.condition as u64) | ((self.length as u64) << 2);
+        (1 << (slot * 2 + 1)) | (control << (16 + slot * 4))
+    }
+}
+
+/// Returns the DR7 bits of a breakpoint slot: its enable bits and its R/W and LEN fields.
+///
+/// # Arguments
+///
+/// * `slot` - The slot, 0 to 3.
+fn dr7_slot_mask(slot: usize) -> u64 {
+    (0b11 << (slot * 2)) | (0xF << (16 + slot * 4))
+}
+
+/// The debug registers of the guest on a processor, see the module documentation.
+pub struct DebugRegisters {
+    /// The host breakpoints, by slot.
+    host: [Option<HostBreakpoint>; BREAKPOINT_SLOTS],
+
+    /// DR0 to DR3 as last written by the guest.
+    guest: [u64; BREAKPOINT_SLOTS],
+
+    /// DR7 as last written by the guest.
+    guest_dr7: u64,
+
+    /// Whether MOV DR exits, i.e. any host breakpoint is set.
+    virtualized: bool,
+
+    /// Whether the host breakpoints gave their slots to the guest, see `set_yielded`.
+    yielded: bool,
+}
+
+impl DebugRegisters {
+    /// Creates debug registers that are not virtualized.
+    pub const fn new() -> Self {
+        Self {
+            host: [None; BREAKPOINT_SLOTS],
+            guest: [0; BREAKPOINT_SLOTS],
+            guest_dr7: DR7_RESERVED_ONE,
+            virtualized: false,
+            yielded: false,
+        }
+    }
+
+    /// Whether the guest reads and writes the shadows.
+    pub fn is_virtualized(&self) -> bool {
+        self.virtualized
+    }
+
+    /// Takes the current debug registers of the processor as those of the guest and arms the host breakpoints.
+    /// Does nothing if there is none. Called when virtualizing the processor, after the guest DR7 was written to
+    /// the VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host breakpoints, by slot.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Err` if the guest DR7 could not be read or written.
+    pub fn virtualize(
+        &mut self,
+        host: &[Option<HostBreakpoint>; BREAKPOINT_SLOTS],
+    ) -> Result<(), HypervisorError> {
+        if host.iter().all(Option::is_none) {
+            return Ok(());
+        }
+
+        self.host = *host;
+        self.guest_dr7 = try_vmread(guest::DR7)?;
+        for (slot, (shadow, breakpoint)) in self.guest.iter_mut().zip(host).enumerate() {
+            *shadow = dr_address(slot);
+            if let Some(breakpoint) = breakpoint {
+                dr_address_write(slot, breakpoint.address);
+            }
+        }
+        self.virtualized = true;
+
+        try_vmwrite(guest::DR7, self.hardware_dr7())
+    }
+
+    /// Loads the debug registers of the guest into the processor, once it left VMX operation, so the host
+    /// breakpoints do not outlive the hypervisor. Does nothing if they are not virtualized.
+    pub fn restore_guest(&self) {
+        if !self.virtualized {
+            return;
+        }
+
+        for (slot, value) in self.guest.iter().enumerate() {
+            dr_address_write(slot, *value);
+        }
+        dr7_write(self.guest_dr7);
+    }
+
+    /// Returns DR7 as read by the guest.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the shadow, or an error if the guest DR7 could not be read.
+    pub fn guest_dr7(&mut self) -> Result<u64, HypervisorError> {
+        // The processor clears GD when delivering a debug exception, without the shadow noticing.
+        if try_vmread(guest::DR7)? & DR7_GD == 0 {
+            self.guest_dr7 &= !DR7_GD;
+        }
+
+        Ok(self.guest_dr7)
+    }
+
+    /// Returns a debug address register as read by the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The register, 0 to 3.
+    pub fn read_address(&self, slot: usize) -> u64 {
+        self.guest.get(slot).copied().unwrap_or_default()
+    }
+
+    /// Writes a debug address register for the guest. The processor takes it unless the slot is the host's.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The register, 0 to 3.
+    /// * `value` - The linear address written by the guest.
+    pub fn write_address(&mut self, slot: usize, value: u64) {
+        let Some(shadow) = self.guest.get_mut(slot) else {
+            return;
+        };
+
+        *shadow = value;
+        if self.yielded || self.host_breakpoint(slot).is_none() {
+            dr_address_write(slot, value);
+        }
+    }
+
+    /// Writes DR7 for the guest, and loads it merged with the host breakpoints into the guest state.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value written by the guest, with bits 63:32 clear.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Err` if the guest DR7 could not be written.
+    pub fn write_dr7(&mut self, value: u64) -> Result<(), HypervisorError> {
+        self.guest_dr7 = (value & !DR7_RESERVED_ZERO) | DR7_RESERVED_ONE;
+        try_vmwrite(guest::DR7, self.hardware_dr7())
+    }
+
+    /// Gives the slots of the host breakpoints to the guest, or takes them back. Does nothing if the debug
+    /// registers are not virtualized or already in the requested state.
+    ///
+    /// # Arguments
+    ///
+    /// * `yielded` - Whether the guest breakpoints take the slots of the host breakpoints.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Err` if the guest DR7 could not be written.
+    pub fn set_yielded(&mut self, yielded: bool) -> Result<(), HypervisorError> {
+        if !self.virtualized || self.yielded == yielded {
+            return Ok(());
+        }
+
+        self.yielded = yielded;
+        for (slot, (shadow, breakpoint)) in self.guest.iter().zip(&self.host).enumerate() {
+            match (breakpoint, yielded) {
+                (Some(_), true) => dr_address_write(slot, *shadow),
+                (Some(breakpoint), false) => dr_address_write(slot, breakpoint.address),
+                (None, _) => {}
+            }
+        }
+
+        try_vmwrite(guest::DR7, self.hardware_dr7())
+    }
+
+    /// Returns the host breakpoint in a slot, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot, 0 to 3.
+    pub fn host_breakpoint(&self, slot: usize) -> Option<HostBreakpoint> {
+        self.host.get(slot).copied().flatten()
+    }
+
+    /// Returns the slots of the host breakpoints that triggered a debug exception.
+    ///
+    /// # Arguments
+    ///
+    /// * `exceptions` - The debug exceptions of the exit qualification, in the DR6 format.
+    ///
+    /// # Returns
+    ///
+    /// The hits, as a mask of slots.
+    pub fn host_hits(&self, exceptions: u64) -> u64 {
+        exceptions & self.host_slots()
+    }
+
+    /// Returns the debug exceptions the guest takes, without the hits of the host breakpoints.
+    ///
+    /// The processor may report breakpoints that are not enabled along with one that is, so the exception is
+    /// only delivered if a guest breakpoint that is enabled triggered, or if another debug condition was met.
+    ///
+    /// # Arguments
+    ///
+    /// * `exceptions` - The debug exceptions of the exit qualification, in the DR6 format.
+    ///
+    /// # Returns
+    ///
+    /// The debug exceptions to deliver, zero if there are none.
+    pub fn guest_exceptions(&self, exceptions: u64) -> u64 {
+        if !self.virtualized {
+            return exceptions;
+        }
+
+        let exceptions = exceptions & !self.host_slots();
+        let enabled = (0..BREAKPOINT_SLOTS)
+            .filter(|slot| self.guest_dr7 & (0b11 << (slot * 2)) != 0)
+            .fold(0, |mask, slot| mask | (1 << slot));
+
+        match exceptions & !DR6_BREAKPOINT_HITS != 0 || exceptions & enabled != 0 {
+            true => exceptions,
+            false => 0,
+        }
+    }
+
+    /// Returns the slots of the armed host breakpoints, as a mask in the DR6 format.
+    fn host_slots(&self) -> u64 {
+        if self.yielded {
+            return 0;
+        }
+
+        self.host
+            .iter()
+            .enumerate()
+            .filter(|(_, breakpoint)| breakpoint.is_some())
+            .fold(0, |mask, (slot, _)| mask | (1 << slot))
+    }
+
+    /// Returns the DR7 the processor runs the guest with: the one of the guest, with the host breakpoints
+    /// replacing the guest breakpoints in their slots unless they yielded.
+    fn hardware_dr7(&self) -> u64 {
+        if self.yielded {
+            return self.guest_dr7;
+        }
+
+        self.host
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, breakpoint)| breakpoint.map(|breakpoint| (slot, breakpoint)))
+            .fold(self.guest_dr7 | DR7_GE, |dr7, (slot, breakpoint)| {
+                (dr7 & !dr7_slot_mask(slot)) | breakpoint.dr7_bits(slot)
+            })
+    }
+}
+
+impl Default for DebugRegisters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
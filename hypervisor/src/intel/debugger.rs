@@ -0,0 +1,221 @@
+//! Detection of guest kernel debugging sessions.
+//!
+//! Stealth hooks redirect execution through breakpoints and swapped EPT views, which fights with a kernel
+//! debugger stepping through or setting breakpoints in the same code. This module watches for debugger
+//! activity in the guest and, depending on the configured policy, suspends the hooks while a debugging
+//! session is active: hooked functions then run their original code through the trampoline instead of the
+//! handler, so developers can debug the guest without the hypervisor getting in the way.
+//!
+//! The spoofing a debugger would trip over is paused along with the hooks, unless they were suspended because
+//! the guest agent was tampered with: the host breakpoints give their debug registers back to the guest, see
+//! `DebugRegisters::set_yielded`, and the time spent in VMX root operation is no longer hidden from the TSC of the
+//! guest, see `VirtualTsc::pause_compensation`. Every processor applies it on its next VM exit.
+//!
+//! The following signals are considered:
+//! - `KdDebuggerEnabled` is set, i.e. KD is attached.
+//! - A storm of breakpoints that do not belong to any hook.
+//! - Enabled hardware breakpoints in the guest's DR7.
+
+use {
+    crate::utils::{instructions::rdtsc, nt::get_ntoskrnl_export},
+    core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+
+/// The number of foreign breakpoints within `INT3_STORM_WINDOW` considered a debugging session.
+const INT3_STORM_THRESHOLD: u32 = 8;
+
+/// The window in TSC ticks in which foreign breakpoints are counted (roughly a second on current processors).
+const INT3_STORM_WINDOW: u64 = 3_000_000_000;
+
+/// The DR7 bits enabling the four hardware breakpoints (L0-L3 and G0-G3).
+const DR7_BREAKPOINT_ENABLE_MASK: u64 = 0xFF;
+
+/// What to do when a guest debugging session is detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DebuggerPolicy {
+    /// Keep the hooks active regardless of debugger activity.
+    #[default]
+    Ignore,
+
+    /// Suspend the hooks while a debugging session is detected.
+    SuspendHooks,
+}
+
+/// The reason the hooks were suspended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SuspendReason {
+    /// The hooks are not suspended.
+    None = 0,
+
+    /// The hooks were suspended on request.
+    Manual = 1,
+
+    /// KD is attached to the guest kernel.
+    KernelDebugger = 2,
+
+    /// A storm of breakpoints not belonging to any hook was observed.
+    BreakpointStorm = 3,
+
+    /// The guest enabled hardware breakpoints.
+    DebugRegisters = 4,
+
+    /// The guest agent was tampered with or stopped responding.
+    AgentTampered = 5,
+}
+
+impl SuspendReason {
+    fn from_u32(value: u32) -> Self {
+        match value {
+            1 => Self::Manual,
+            2 => Self::KernelDebugger,
+            3 => Self::BreakpointStorm,
+            4 => Self::DebugRegisters,
+            5 => Self::AgentTampered,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Watches for guest debugger activity and tracks whether the hooks are suspended.
+#[derive(Debug)]
+pub struct DebuggerMonitor {
+    /// The configured policy.
+    policy: DebuggerPolicy,
+
+    /// The address of `KdDebuggerEnabled`, resolved at initialization, or 0 if it is not exported.
+    kd_debugger_enabled: u64,
+
+    /// The TSC at which the current breakpoint counting window started.
+    window_start: AtomicU64,
+
+    /// The number of foreign breakpoints observed in the current window.
+    breakpoints: AtomicU32,
+
+    /// Whether the hooks are currently suspended.
+    suspended: AtomicBool,
+
+    /// The `SuspendReason` the hooks were suspended for.
+    reason: AtomicU32,
+}
+
+impl DebuggerMonitor {
+    /// Creates the monitor.
+    ///
+    /// Must be called before virtualization, as the `KdDebuggerEnabled` export is resolved here.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - What to do when a debugging session is detected.
+    pub fn new(policy: DebuggerPolicy) -> Self {
+        let kd_debugger_enabled = match policy {
+            DebuggerPolicy::Ignore => 0,
+            DebuggerPolicy::SuspendHooks => get_ntoskrnl_export("KdDebuggerEnabled") as u64,
+        };
+
+        Self {
+            policy,
+            kd_debugger_enabled,
+            window_start: AtomicU64::new(0),
+            breakpoints: AtomicU32::new(0),
+            suspended: AtomicBool::new(false),
+            reason: AtomicU32::new(SuspendReason::None as u32),
+        }
+    }
+
+    /// Returns the configured policy.
+    pub fn policy(&self) -> DebuggerPolicy {
+        self.policy
+    }
+
+    /// Returns whether debugger activity is being watched for.
+    pub fn is_enabled(&self) -> bool {
+        self.policy != DebuggerPolicy::Ignore
+    }
+
+    /// Returns whether the hooks are currently suspended.
+    pub fn hooks_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Acquire)
+    }
+
+    /// Returns whether the spoofing is paused for a debugging session, i.e. the hooks are suspended for any
+    /// reason but tampering with the guest agent.
+    pub fn spoofing_suspended(&self) -> bool {
+        self.hooks_suspended() && self.suspend_reason() != SuspendReason::AgentTampered
+    }
+
+    /// Returns the reason the hooks were suspended for.
+    pub fn suspend_reason(&self) -> SuspendReason {
+        SuspendReason::from_u32(self.reason.load(Ordering::Relaxed))
+    }
+
+    /// Suspends the hooks.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the hooks are suspended.
+    pub fn suspend(&self, reason: SuspendReason) {
+        if !self.suspended.swap(true, Ordering::AcqRel) {
+            self.reason.store(reason as u32, Ordering::Relaxed);
+            log::warn!("Suspending hooks: {:?}", reason);
+        }
+    }
+
+    /// Resumes the hooks and restarts detection.
+    pub fn resume(&self) {
+        if self.suspended.swap(false, Ordering::AcqRel) {
+            log::info!("Resuming hooks");
+        }
+
+        self.reason
+            .store(SuspendReason::None as u32, Ordering::Relaxed);
+        self.breakpoints.store(0, Ordering::Relaxed);
+    }
+
+    /// Records a breakpoint exception that does not belong to any hook.
+    pub fn record_foreign_breakpoint(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let now = rdtsc();
+        let window_start = self.window_start.load(Ordering::Relaxed);
+
+        if now.wrapping_sub(window_start) > INT3_STORM_WINDOW {
+            self.window_start.store(now, Ordering::Relaxed);
+            self.breakpoints.store(1, Ordering::Relaxed);
+            return;
+        }
+
+        if self.breakpoints.fetch_add(1, Ordering::Relaxed) + 1 >= INT3_STORM_THRESHOLD {
+            self.suspend(SuspendReason::BreakpointStorm);
+        }
+    }
+
+    /// Checks the guest's debug state for an active debugging session.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_dr7` - The guest's DR7.
+    pub fn observe(&self, guest_dr7: u64) {
+        if !self.is_enabled() || self.hooks_suspended() {
+            return;
+        }
+
+        if guest_dr7 & DR7_BREAKPOINT_ENABLE_MASK != 0 {
+            self.suspend(SuspendReason::DebugRegisters);
+        } else if self.kernel_debugger_enabled() {
+            self.suspend(SuspendReason::KernelDebugger);
+        }
+    }
+
+    /// Returns whether KD is attached to the guest kernel.
+    fn kernel_debugger_enabled(&self) -> bool {
+        if self.kd_debugger_enabled == 0 {
+            return false;
+        }
+
+        // KdDebuggerEnabled is a BOOLEAN in non-paged kernel memory.
+        unsafe { core::ptr::read_volatile(self.kd_debugger_enabled as *const u8) != 0 }
+    }
+}
@@ -4,6 +4,7 @@
 use {
     crate::{
         error::HypervisorError,
+        intel::ipi,
         utils::alloc::KernelAlloc,
         utils::instructions::{sgdt, sidt},
     },
@@ -59,6 +60,7 @@ impl DescriptorTables {
 
         descriptor_tables.copy_current_gdt();
         descriptor_tables.copy_current_idt();
+        ipi::install_nmi_handler(&mut descriptor_tables.interrupt_descriptor_table);
 
         log::trace!("Initialized descriptor tables for host");
         Ok(())
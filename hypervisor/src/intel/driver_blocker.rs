@@ -0,0 +1,860 @@
+//! Blocking of deny-listed guest drivers, such as known vulnerable drivers.
+//!
+//! A driver is denied by its file name, by the SHA-256 of its file, as vulnerable driver lists publish, or by
+//! the identity of its image: the PE timestamp and image size, the pair symbol servers index images with,
+//! which tells apart versions of a driver sharing a name. The drivers already loaded when the hypervisor
+//! starts are only reported, as their code may be running.
+//!
+//! Drivers loaded afterwards are checked from an image load notification, called once the image is mapped
+//! and before its entry point runs. The pages of a denied image lose their execute permission in the EPT, so
+//! every call into the image causes an EPT violation, which is answered as if the called function returned
+//! `STATUS_ACCESS_DENIED`. The first call is the entry point, so the load fails and the kernel unloads the
+//! image, and its pages get back the permissions they had before the image was blocked.
+//!
+//! The kernel does not notify image unloads, so the blocked images are checked against the module list on
+//! every image load: the images unloaded are forgotten, and the pages of an image unloaded without its entry
+//! point being called get their permissions back then.
+//!
+//! Pages of the image that are not resident when it is mapped are not blocked.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.2 EPT Violations,
+//! Microsoft PE Format: Optional Header Standard Fields, PsSetLoadImageNotifyRoutine, ZwCreateFile and
+//! ZwReadFile.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::paging::AccessType,
+            host_call,
+            shared_data::{PageAccess, SharedData},
+        },
+        utils::{
+            addresses::{Gva, Hva},
+            event_log::EventLog,
+            sha256::{Sha256, HASH_LEN},
+            ssdt::sys_info::Sysinfo,
+            sync::SpinLock,
+            timestamp::Timestamp,
+        },
+    },
+    alloc::{vec, vec::Vec},
+    bstr::ByteSlice,
+    core::{
+        fmt,
+        sync::atomic::{AtomicPtr, Ordering},
+    },
+    wdk_sys::{
+        ntddk::ZwClose, HANDLE, IO_STATUS_BLOCK, LARGE_INTEGER, NTSTATUS, NT_SUCCESS,
+        OBJECT_ATTRIBUTES, PHANDLE, PIO_STATUS_BLOCK, PLARGE_INTEGER, POBJECT_ATTRIBUTES,
+        PUNICODE_STRING, PVOID, UNICODE_STRING,
+    },
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The status a call into a blocked image returns.
+pub const STATUS_ACCESS_DENIED: u64 = 0xC000_0022;
+
+/// The maximum number of images blocked at once.
+pub const MAX_BLOCKED_IMAGES: usize = 16;
+
+/// The maximum length of the image names kept in events, longer names are truncated.
+pub const IMAGE_NAME_LEN: usize = 32;
+
+/// The number of events kept until they are drained.
+const EVENT_LOG_LEN: usize = 32;
+
+/// The `SystemModeImage` bit of the image information properties.
+const IMAGE_INFO_SYSTEM_MODE: u32 = 1 << 8;
+
+/// The largest driver file hashed, larger files are not matched by hash.
+const MAX_HASHED_FILE_LEN: u64 = 64 * 1024 * 1024;
+
+/// The length of the reads of a driver file being hashed.
+const HASH_READ_LEN: usize = 0x10000;
+
+/// The status of a read at the end of a file.
+const STATUS_END_OF_FILE: NTSTATUS = 0xC000_0011_u32 as NTSTATUS;
+
+/// The shared data the image load notification routine blocks images in, null while not registered.
+static SHARED_DATA: AtomicPtr<SharedData> = AtomicPtr::new(core::ptr::null_mut());
+
+/// A driver to block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeniedDriver {
+    /// Any image with this file name, compared case-insensitively, e.g. `"RTCore64.sys"`.
+    Name(&'static str),
+
+    /// The image with this PE timestamp and image size, whatever its name.
+    Image {
+        time_date_stamp: u32,
+        size_of_image: u32,
+    },
+
+    /// The image whose file has this SHA-256, whatever its name.
+    Sha256([u8; HASH_LEN]),
+}
+
+impl DeniedDriver {
+    /// Returns whether an image matches the entry, without hashing its file.
+    fn matches(&self, name: &ImageName, identity: Option<ImageIdentity>) -> bool {
+        match *self {
+            Self::Name(denied) => name.as_bytes().eq_ignore_ascii_case(denied.as_bytes()),
+            Self::Image {
+                time_date_stamp,
+                size_of_image,
+            } => identity.is_some_and(|identity| {
+                identity.time_date_stamp == time_date_stamp
+                    && identity.size_of_image == size_of_image
+            }),
+            Self::Sha256(_) => false,
+        }
+    }
+}
+
+/// The file name of an image, truncated to `IMAGE_NAME_LEN` bytes, with non-ASCII characters replaced.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ImageName {
+    bytes: [u8; IMAGE_NAME_LEN],
+    len: usize,
+}
+
+impl ImageName {
+    /// Extracts the file name from a path made of bytes, as in the kernel module list.
+    fn from_path_bytes(path: &[u8]) -> Self {
+        let path = path.split_str(b"\0").next().unwrap_or(&[]);
+        let name = path.rsplit_str(b"\\").next().unwrap_or(path);
+
+        Self::from_chars(name.iter().map(|&byte| u16::from(byte)))
+    }
+
+    /// Extracts the file name from a UTF-16 path, as in the image load notifications.
+    fn from_path_utf16(path: &[u16]) -> Self {
+        let name = path
+            .rsplit(|&unit| unit == u16::from(b'\\'))
+            .next()
+            .unwrap_or(path);
+
+        Self::from_chars(name.iter().copied())
+    }
+
+    fn from_chars(chars: impl Iterator<Item = u16>) -> Self {
+        let mut name = Self {
+            bytes: [0; IMAGE_NAME_LEN],
+            len: 0,
+        };
+
+        for (byte, char) in name.bytes.iter_mut().zip(chars) {
+            *byte = u8::try_from(char).ok().filter(u8::is_ascii).unwrap_or(b'?');
+            name.len += 1;
+        }
+
+        name
+    }
+
+    /// Returns the name as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.get(..self.len).unwrap_or_default()
+    }
+
+    /// Returns the name.
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap_or("?")
+    }
+}
+
+impl fmt::Debug for ImageName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for ImageName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The identity of an image, read from its PE headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageIdentity {
+    /// The `TimeDateStamp` of the file header.
+    pub time_date_stamp: u32,
+
+    /// The `SizeOfImage` of the optional header.
+    pub size_of_image: u32,
+
+    /// The `AddressOfEntryPoint` of the optional header, relative to the image base.
+    pub entry_point: u32,
+}
+
+impl ImageIdentity {
+    /// Reads the identity of a mapped image.
+    ///
+    /// Must be called at PASSIVE_LEVEL, as the headers may be paged out.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - The address the image is mapped at.
+    /// * `size` - The size of the mapping.
+    ///
+    /// # Returns
+    ///
+    /// The identity, or `None` if the mapping does not start with valid PE headers.
+    pub fn read(base: Hva, size: u64) -> Option<Self> {
+        let read_u32 = |offset: u64| -> Option<u32> {
+            (offset + 4 <= size.min(BASE_PAGE_SIZE as u64))
+                .then(|| unsafe { core::ptr::read_unaligned((base + offset).as_ptr()) })
+        };
+
+        // "MZ", then the offset of the "PE\0\0" signature.
+        if read_u32(0)? & 0xFFFF != 0x5A4D {
+            return None;
+        }
+
+        let nt_headers = u64::from(read_u32(0x3C)?);
+        if read_u32(nt_headers)? != 0x4550 {
+            return None;
+        }
+
+        Some(Self {
+            time_date_stamp: read_u32(nt_headers + 8)?,
+            entry_point: read_u32(nt_headers + 40)?,
+            size_of_image: read_u32(nt_headers + 80)?,
+        })
+    }
+}
+
+/// What happened to a denied driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverEventKind {
+    /// The driver was already loaded when the hypervisor started, and is not blocked.
+    AlreadyLoaded,
+
+    /// The driver was mapped and its pages made non-executable.
+    LoadBlocked,
+
+    /// A call into the driver was failed with `STATUS_ACCESS_DENIED`.
+    ExecutionBlocked,
+}
+
+/// An event concerning a denied driver.
+#[derive(Debug, Clone, Copy)]
+pub struct DriverEvent {
+    /// What happened.
+    pub kind: DriverEventKind,
+
+    /// The file name of the image.
+    pub name: ImageName,
+
+    /// The address the image is mapped at.
+    pub base: Gva,
+
+    /// The size of the image.
+    pub size: u64,
+
+    /// The guest instruction pointer of the blocked call, 0 for the other events.
+    pub rip: u64,
+
+    /// When the event happened.
+    pub timestamp: Timestamp,
+}
+
+/// An image whose pages are non-executable.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockedImage {
+    /// The file name of the image.
+    pub name: ImageName,
+
+    /// The address the image is mapped at.
+    pub base: Gva,
+
+    /// The size of the image.
+    pub size: u64,
+
+    /// The address of the entry point.
+    pub entry_point: Gva,
+
+    /// Whether the pages are still non-executable. Cleared once their permissions are restored.
+    pub active: bool,
+}
+
+/// A blocked image and the permissions its pages had before.
+struct TrackedImage {
+    image: BlockedImage,
+    pages: Vec<PageAccess>,
+}
+
+impl BlockedImage {
+    /// Returns whether the image contains the address.
+    pub fn contains(&self, address: Gva) -> bool {
+        (self.base..self.base + self.size).contains(&address)
+    }
+
+    /// Returns the addresses of the pages of the image.
+    pub fn pages(&self) -> impl Iterator<Item = Gva> {
+        let base = self.base;
+
+        (0..self.size)
+            .step_by(BASE_PAGE_SIZE)
+            .map(move |offset| base + offset)
+    }
+}
+
+/// Checks guest drivers against the deny list and keeps track of the blocked ones.
+pub struct DriverBlocker {
+    /// The drivers to block.
+    deny_list: Vec<DeniedDriver>,
+
+    /// The blocked images, including the ones unblocked since, until they are unloaded or their slot is
+    /// reused.
+    images: SpinLock<[Option<TrackedImage>; MAX_BLOCKED_IMAGES]>,
+
+    /// The events not drained yet.
+    events: EventLog<DriverEvent, EVENT_LOG_LEN>,
+}
+
+impl DriverBlocker {
+    /// Creates a blocker for the given drivers, which does nothing if the list is empty.
+    pub fn new(deny_list: Vec<DeniedDriver>) -> Self {
+        Self {
+            deny_list,
+            images: SpinLock::new("blocked_images", core::array::from_fn(|_| None)),
+            events: EventLog::new("driver_events"),
+        }
+    }
+
+    /// Returns the drivers to block.
+    pub fn deny_list(&self) -> &[DeniedDriver] {
+        &self.deny_list
+    }
+
+    /// Returns whether any driver is denied.
+    pub fn is_enabled(&self) -> bool {
+        !self.deny_list.is_empty()
+    }
+
+    /// Returns whether an image is denied.
+    ///
+    /// Must be called at PASSIVE_LEVEL, as the file of the image is hashed if the deny list holds hashes.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The file name of the image.
+    /// * `identity` - The identity of the image, if its headers could be read.
+    /// * `path` - The path of the file of the image, as UTF-16.
+    pub fn is_denied(
+        &self,
+        name: &ImageName,
+        identity: Option<ImageIdentity>,
+        path: &[u16],
+    ) -> bool {
+        if self
+            .deny_list
+            .iter()
+            .any(|denied| denied.matches(name, identity))
+        {
+            return true;
+        }
+
+        let mut hashes = self
+            .deny_list
+            .iter()
+            .filter_map(|denied| match denied {
+                DeniedDriver::Sha256(hash) => Some(hash),
+                _ => None,
+            })
+            .peekable();
+
+        // The file is only read if a hash can match.
+        if hashes.peek().is_none() {
+            return false;
+        }
+
+        hash_file(path).is_some_and(|hash| hashes.any(|denied| *denied == hash))
+    }
+
+    /// Reports the denied drivers that are already loaded.
+    ///
+    /// Must be called at PASSIVE_LEVEL, as the module list is queried from the kernel.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of denied drivers loaded, or an error if the module list could not be
+    /// queried.
+    pub fn scan_loaded(&self) -> Result<usize, HypervisorError> {
+        let sys_info = Sysinfo::new()?;
+        let mut found = 0;
+
+        for module in sys_info.modules() {
+            let name = ImageName::from_path_bytes(&module.image_name);
+            let base = Hva::from_ptr(module.image_base);
+            let size = u64::from(module.size);
+            let path: Vec<u16> = module
+                .image_name
+                .split_str(b"\0")
+                .next()
+                .unwrap_or_default()
+                .iter()
+                .map(|&byte| u16::from(byte))
+                .collect();
+
+            if !self.is_denied(&name, ImageIdentity::read(base, size), &path) {
+                continue;
+            }
+
+            log::warn!(
+                "Denied driver {} is already loaded at {:#x}, not blocking it",
+                name,
+                base
+            );
+
+            self.record(DriverEvent {
+                kind: DriverEventKind::AlreadyLoaded,
+                name,
+                base: base.to_gva(),
+                size,
+                rip: 0,
+                timestamp: Timestamp::now(),
+            });
+            found += 1;
+        }
+
+        Ok(found)
+    }
+
+    /// Keeps track of an image whose pages are made non-executable.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The image.
+    /// * `pages` - The permissions of the pages of the image before they are made non-executable.
+    ///
+    /// # Returns
+    ///
+    /// `HypervisorError::TooManyBlockedDrivers` if `MAX_BLOCKED_IMAGES` images are blocked already.
+    pub fn track(
+        &self,
+        image: BlockedImage,
+        pages: Vec<PageAccess>,
+    ) -> Result<(), HypervisorError> {
+        let mut images = self.images.lock();
+
+        let Some(slot) = images
+            .iter_mut()
+            .find(|slot| !slot.as_ref().is_some_and(|tracked| tracked.image.active))
+        else {
+            return Err(HypervisorError::TooManyBlockedDrivers);
+        };
+
+        *slot = Some(TrackedImage { image, pages });
+
+        Ok(())
+    }
+
+    /// Returns the blocked image containing the address, including one unblocked since.
+    pub fn find(&self, address: Gva) -> Option<BlockedImage> {
+        self.images
+            .lock()
+            .iter()
+            .flatten()
+            .map(|tracked| tracked.image)
+            .find(|image| image.contains(address))
+    }
+
+    /// Marks an image as unblocked, before the permissions of its pages are restored.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - The address the image is mapped at.
+    ///
+    /// # Returns
+    ///
+    /// Whether the image was still blocked, i.e. whether the caller has to restore its pages, see
+    /// `DriverBlocker::saved_page`.
+    pub fn unblock(&self, base: Gva) -> bool {
+        let mut images = self.images.lock();
+
+        images
+            .iter_mut()
+            .flatten()
+            .find(|tracked| tracked.image.base == base)
+            .is_some_and(|tracked| core::mem::replace(&mut tracked.image.active, false))
+    }
+
+    /// Returns the permissions a page of a blocked image had before it was blocked.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - The address the image is mapped at.
+    /// * `index` - The index of the page among the resident pages of the image.
+    pub fn saved_page(&self, base: Gva, index: usize) -> Option<PageAccess> {
+        self.images
+            .lock()
+            .iter()
+            .flatten()
+            .find(|tracked| tracked.image.base == base)
+            .and_then(|tracked| tracked.pages.get(index).copied())
+    }
+
+    /// Forgets the images that are not loaded anymore.
+    ///
+    /// # Arguments
+    ///
+    /// * `is_loaded` - Returns whether an image is still loaded at an address.
+    ///
+    /// # Returns
+    ///
+    /// The saved permissions of the pages of the images forgotten that were still blocked, to restore.
+    fn forget_unloaded(&self, is_loaded: impl Fn(Gva) -> bool) -> Vec<PageAccess> {
+        let mut images = self.images.lock();
+        let mut blocked_pages = Vec::new();
+
+        for slot in images.iter_mut() {
+            if slot
+                .as_ref()
+                .is_some_and(|tracked| !is_loaded(tracked.image.base))
+            {
+                if let Some(tracked) = slot.take().filter(|tracked| tracked.image.active) {
+                    log::warn!(
+                        "Denied driver {} unloaded before its entry point ran",
+                        tracked.image.name
+                    );
+                    blocked_pages.extend(tracked.pages);
+                }
+            }
+        }
+
+        blocked_pages
+    }
+
+    /// Records an event.
+    pub fn record(&self, event: DriverEvent) {
+        log::warn!(
+            "Denied driver {} at {:#x}: {:?}",
+            event.name,
+            event.base,
+            event.kind
+        );
+
+        self.events.push(event);
+    }
+
+    /// Hands the pending events to a consumer, oldest first, and removes them.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    pub fn drain_events(&self, consumer: impl FnMut(&DriverEvent)) -> usize {
+        self.events.drain(consumer)
+    }
+
+    /// Returns the total number of events raised, including the ones that were overwritten.
+    pub fn total_events(&self) -> u64 {
+        self.events.total()
+    }
+}
+
+/// Checks the drivers loaded from now on against the deny list of the shared data.
+///
+/// The shared data must stay alive and must not move until `unregister` is called.
+///
+/// # Arguments
+///
+/// * `shared_data` - The shared data holding the deny list and the EPTs.
+///
+/// # Returns
+///
+/// A `Result` which is `Ok` if the image load notification routine was registered.
+pub fn register(shared_data: &mut SharedData) -> Result<(), HypervisorError> {
+    SHARED_DATA.store(shared_data, Ordering::Release);
+
+    let status = unsafe { PsSetLoadImageNotifyRoutine(load_image_notify) };
+    if !NT_SUCCESS(status) {
+        SHARED_DATA.store(core::ptr::null_mut(), Ordering::Release);
+        log::error!("PsSetLoadImageNotifyRoutine failed: {:#x}", status);
+        return Err(HypervisorError::ImageNotifyRegistrationFailed);
+    }
+
+    Ok(())
+}
+
+/// Stops checking the drivers loaded from now on, if `register` was called.
+pub fn unregister() {
+    if SHARED_DATA
+        .swap(core::ptr::null_mut(), Ordering::AcqRel)
+        .is_null()
+    {
+        return;
+    }
+
+    unsafe { PsRemoveLoadImageNotifyRoutine(load_image_notify) };
+}
+
+/// Called by the kernel at PASSIVE_LEVEL once an image is mapped, before its entry point runs.
+extern "C" fn load_image_notify(
+    full_image_name: PUNICODE_STRING,
+    _process_id: HANDLE,
+    image_info: *mut ImageInfo,
+) {
+    let shared_data = SHARED_DATA.load(Ordering::Acquire);
+    if shared_data.is_null() || full_image_name.is_null() || image_info.is_null() {
+        return;
+    }
+
+    let image_info = unsafe { &*image_info };
+    if image_info.properties & IMAGE_INFO_SYSTEM_MODE == 0 {
+        return;
+    }
+
+    let full_image_name = unsafe { &*full_image_name };
+    let path = match full_image_name.Buffer.is_null() {
+        true => &[][..],
+        false => unsafe {
+            core::slice::from_raw_parts(
+                full_image_name.Buffer,
+                usize::from(full_image_name.Length) / 2,
+            )
+        },
+    };
+
+    let shared_data = unsafe { &mut *shared_data };
+    if let Err(error) = restore_unloaded(shared_data) {
+        log::error!("Failed to restore the pages of unloaded drivers: {}", error);
+    }
+
+    let name = ImageName::from_path_utf16(path);
+    let base = Hva::from_ptr(image_info.image_base);
+    let size = image_info.image_size as u64;
+
+    if let Err(error) = block_image(shared_data, name, base, size, path) {
+        log::error!("Failed to block driver {}: {}", name, error);
+    }
+}
+
+/// Forgets the blocked images missing from the module list and restores the permissions of their pages.
+fn restore_unloaded(shared_data: &mut SharedData) -> Result<(), HypervisorError> {
+    if !shared_data.driver_blocker.is_enabled() {
+        return Ok(());
+    }
+
+    let sys_info = Sysinfo::new()?;
+    let loaded: Vec<Gva> = sys_info
+        .modules()
+        .map(|module| Hva::from_ptr(module.image_base).to_gva())
+        .collect();
+
+    let pages = shared_data
+        .driver_blocker
+        .forget_unloaded(|base| loaded.contains(&base));
+    if pages.is_empty() {
+        return Ok(());
+    }
+
+    for page in pages {
+        shared_data.restore_page_access(page)?;
+    }
+
+    host_call::flush_ept_on_all_processors(shared_data.host_call_key)
+}
+
+/// Makes the pages of an image non-executable if it is denied.
+fn block_image(
+    shared_data: &mut SharedData,
+    name: ImageName,
+    base: Hva,
+    size: u64,
+    path: &[u16],
+) -> Result<(), HypervisorError> {
+    let identity = ImageIdentity::read(base, size);
+    if !shared_data.driver_blocker.is_denied(&name, identity, path) {
+        return Ok(());
+    }
+
+    let base = base.to_gva();
+    let image = BlockedImage {
+        name,
+        base,
+        size,
+        entry_point: base + identity.map_or(0, |identity| u64::from(identity.entry_point)),
+        active: true,
+    };
+
+    // Pages that are not resident have no physical address.
+    let pages = image
+        .pages()
+        .filter_map(|page| page.kernel_to_hva().and_then(Hva::to_hpa))
+        .map(|pa| shared_data.page_access(pa.to_gpa()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    shared_data.driver_blocker.track(image, pages.clone())?;
+
+    for page in pages {
+        shared_data.set_page_access(page.guest_pa, page.primary & !AccessType::EXECUTE)?;
+    }
+
+    // The image notify routine runs in the guest, so every processor flushes with a host call.
+    host_call::flush_ept_on_all_processors(shared_data.host_call_key)?;
+
+    shared_data.driver_blocker.record(DriverEvent {
+        kind: DriverEventKind::LoadBlocked,
+        name,
+        base,
+        size,
+        rip: 0,
+        timestamp: Timestamp::now(),
+    });
+
+    Ok(())
+}
+
+/// Computes the SHA-256 of a file. Must be called at PASSIVE_LEVEL.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file, as UTF-16, e.g. `\SystemRoot\System32\drivers\RTCore64.sys`.
+///
+/// # Returns
+///
+/// The hash, or `None` if the file could not be read or is larger than `MAX_HASHED_FILE_LEN`.
+fn hash_file(path: &[u16]) -> Option<[u8; HASH_LEN]> {
+    let mut path = path.to_vec();
+    let len = u16::try_from(path.len() * 2).ok()?;
+
+    let mut name = UNICODE_STRING {
+        Length: len,
+        MaximumLength: len,
+        Buffer: path.as_mut_ptr(),
+    };
+    let mut attributes = OBJECT_ATTRIBUTES {
+        Length: core::mem::size_of::<OBJECT_ATTRIBUTES>() as u32,
+        ObjectName: &mut name,
+        Attributes: OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+        ..Default::default()
+    };
+    let mut io_status = IO_STATUS_BLOCK::default();
+    let mut handle: HANDLE = core::ptr::null_mut();
+
+    let status = unsafe {
+        ZwCreateFile(
+            &mut handle,
+            GENERIC_READ | SYNCHRONIZE,
+            &mut attributes,
+            &mut io_status,
+            core::ptr::null_mut(),
+            0,
+            FILE_SHARE_READ,
+            FILE_OPEN,
+            FILE_SYNCHRONOUS_IO_NONALERT | FILE_NON_DIRECTORY_FILE,
+            core::ptr::null_mut(),
+            0,
+        )
+    };
+    if !NT_SUCCESS(status) {
+        log::warn!("Failed to open a driver file to hash it: {:#x}", status);
+        return None;
+    }
+
+    let mut sha256 = Sha256::new();
+    let mut buffer = vec![0u8; HASH_READ_LEN];
+    let mut offset = 0u64;
+
+    let hash = loop {
+        let mut byte_offset = LARGE_INTEGER {
+            QuadPart: offset as i64,
+        };
+
+        let status = unsafe {
+            ZwReadFile(
+                handle,
+                core::ptr::null_mut(),
+                core::ptr::null_mut(),
+                core::ptr::null_mut(),
+                &mut io_status,
+                buffer.as_mut_ptr() as PVOID,
+                buffer.len() as u32,
+                &mut byte_offset,
+                core::ptr::null_mut(),
+            )
+        };
+
+        let read = io_status.Information as usize;
+        match status {
+            STATUS_END_OF_FILE => break Some(sha256.finalize()),
+            status if !NT_SUCCESS(status) => break None,
+            _ if read == 0 => break Some(sha256.finalize()),
+            _ => {}
+        }
+
+        sha256.update(buffer.get(..read).unwrap_or_default());
+        offset += read as u64;
+
+        if offset > MAX_HASHED_FILE_LEN {
+            break None;
+        }
+    };
+
+    unsafe { ZwClose(handle) };
+    hash
+}
+
+/// The information about a mapped image, passed to the image load notification routines.
+#[repr(C)]
+struct ImageInfo {
+    properties: u32,
+    image_base: PVOID,
+    image_selector: u32,
+    image_size: usize,
+    image_section_number: u32,
+}
+
+/// The image load notification routine.
+type LoadImageNotifyRoutine =
+    extern "C" fn(full_image_name: PUNICODE_STRING, process_id: HANDLE, image_info: *mut ImageInfo);
+
+/// The access rights and options of the driver file handle.
+const GENERIC_READ: u32 = 0x8000_0000;
+const SYNCHRONIZE: u32 = 0x0010_0000;
+const FILE_SHARE_READ: u32 = 0x0000_0001;
+const FILE_OPEN: u32 = 0x0000_0001;
+const FILE_SYNCHRONOUS_IO_NONALERT: u32 = 0x0000_0020;
+const FILE_NON_DIRECTORY_FILE: u32 = 0x0000_0040;
+const OBJ_CASE_INSENSITIVE: u32 = 0x0000_0040;
+const OBJ_KERNEL_HANDLE: u32 = 0x0000_0200;
+
+#[link(name = "ntoskrnl")]
+extern "system" {
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/ntddk/nf-ntddk-pssetloadimagenotifyroutine
+    fn PsSetLoadImageNotifyRoutine(notify_routine: LoadImageNotifyRoutine) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/ntddk/nf-ntddk-psremoveloadimagenotifyroutine
+    fn PsRemoveLoadImageNotifyRoutine(notify_routine: LoadImageNotifyRoutine) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-zwcreatefile
+    fn ZwCreateFile(
+        file_handle: PHANDLE,
+        desired_access: u32,
+        object_attributes: POBJECT_ATTRIBUTES,
+        io_status_block: PIO_STATUS_BLOCK,
+        allocation_size: PLARGE_INTEGER,
+        file_attributes: u32,
+        share_access: u32,
+        create_disposition: u32,
+        create_options: u32,
+        ea_buffer: PVOID,
+        ea_length: u32,
+    ) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-zwreadfile
+    fn ZwReadFile(
+        file_handle: HANDLE,
+        event: HANDLE,
+        apc_routine: PVOID,
+        apc_context: PVOID,
+        io_status_block: PIO_STATUS_BLOCK,
+        buffer: PVOID,
+        length: u32,
+        byte_offset: PLARGE_INTEGER,
+        key: *mut u32,
+    ) -> NTSTATUS;
+}
@@ -0,0 +1,797 @@
+//! Export of the configuration the hypervisor is actually running with.
+//!
+//! The configuration requested through the `HypervisorBuilder` is not necessarily what runs: features are
+//! refused by the processor, intercepts are added by other features, hooks are evicted, and hybrid cores differ
+//! in their capabilities. The effective configuration is read back from the live state instead, and rendered
+//! as text, so an operator can diff what they asked for against what is running:
+//! - One `key=value` per line, grouped in `[section]` headers, always in the same order.
+//! - Numbers in hex, lists comma separated, ranges as `start-end` inclusive.
+//! - Policies as lowercase names, and structured settings as `field:value` pairs, comma separated.
+//!
+//! The sections are:
+//! - `[build]`: the version and the crate features.
+//! - `[stealth]`: the paravirtual interface and the client sessions.
+//! - `[intercepts]`: the intercepted MSRs and I/O ports, and the policies deciding about the exits.
+//! - `[ept]`: the EPT settings and the regions protected by permission profiles.
+//! - `[hooks]`: the hook namespaces and the installed hooks with their filters.
+//! - `[core.N]`: the capabilities of each virtualized core, captured on the core itself.
+//! - `[vmcs]`: the VM-execution controls of the core serving the export, only available in root mode.
+//!
+//! The text is rendered once into a `ConfigSnapshot` when read from offset 0, and the following pages are
+//! copied from the snapshot, so a reader never gets parts of different configurations and the configuration is
+//! not formatted again for every page. A reader whose snapshot was replaced in between starts over.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Appendix A VMX Capability Reporting
+//! Facility and 25.6 VM-Execution Control Fields.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            apic_timer::{self, ApicTimerMode},
+            capabilities::{Capability, Subsystem},
+            controls::TscMode,
+            debugger::DebuggerPolicy,
+            ept::{
+                filter::HookFilter, hooks::HookType, paging::AccessType, policy::ViolationResponse,
+                thrashing::ThrashStrategy,
+            },
+            hypercall::HypercallCode,
+            msr_bitmap::{HIGH_MSRS_END, HIGH_MSRS_START, LOW_MSRS_END},
+            msr_policy::MsrPolicy,
+            paravirt::{BuildFeatures, ParavirtFeatures},
+            rate_limit::{Backoff, Quota},
+            shared_data::SharedData,
+            support::try_vmread,
+            tsx::TsxPolicy,
+            vmexit::{
+                invd::CacheFlushPolicy, mwait::MwaitPolicy, triple_fault::TripleFaultPolicy,
+                vmx_instruction::VmxInstructionResponse,
+            },
+        },
+        utils::{cpu::CoreType, processor::processor_count, sync::SpinLock, text::TextWindow},
+    },
+    alloc::{boxed::Box, vec},
+    core::fmt::{self, Write},
+    x86::vmx::vmcs::control,
+};
+
+#[cfg(feature = "devices")]
+use crate::intel::keyboard_guard::KeyboardProtection;
+
+/// The capacity of a `ConfigSnapshot`. A longer text is cut off after its last line that fits, followed by
+/// `SNAPSHOT_TRUNCATED`.
+const SNAPSHOT_CAPACITY: usize = 0x10000;
+
+/// The line ending a snapshot that was cut off.
+const SNAPSHOT_TRUNCATED: &str = "truncated=true\n";
+
+/// The hypercalls of the configuration export, see `intel::capabilities`.
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "effective-config",
+    enabled: |_| true,
+    commands: &[Capability {
+        code: HypercallCode::GetEffectiveConfig,
+        summary: "Exports the configuration the hypervisor is running with",
+    }],
+};
+
+/// The VM-execution controls of the current VMCS.
+#[derive(Debug, Clone, Copy)]
+pub struct VmcsControls {
+    pub pinbased: u64,
+    pub procbased: u64,
+    pub procbased2: u64,
+    pub exit: u64,
+    pub entry: u64,
+    pub exception_bitmap: u64,
+    pub eptp: u64,
+    pub vpid: u64,
+}
+
+impl VmcsControls {
+    /// Reads the controls of the current VMCS.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the controls, or an error if a field could not be read, e.g. outside of root mode.
+    pub fn capture() -> Result<Self, HypervisorError> {
+        Ok(Self {
+            pinbased: try_vmread(control::PINBASED_EXEC_CONTROLS)?,
+            procbased: try_vmread(control::PRIMARY_PROCBASED_EXEC_CONTROLS)?,
+            procbased2: try_vmread(control::SECONDARY_PROCBASED_EXEC_CONTROLS)?,
+            exit: try_vmread(control::VMEXIT_CONTROLS)?,
+            entry: try_vmread(control::VMENTRY_CONTROLS)?,
+            exception_bitmap: try_vmread(control::EXCEPTION_BITMAP)?,
+            eptp: try_vmread(control::EPTP_FULL)?,
+            vpid: try_vmread(control::VPID)?,
+        })
+    }
+}
+
+/// The outcome of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigExport {
+    /// The number of bytes copied into the buffer.
+    pub copied: usize,
+
+    /// The length of the whole text, from which the caller can tell whether more remains after the offset.
+    pub total: usize,
+
+    /// The generation of the snapshot the text was copied from, see `ConfigSnapshot`, or 0 for a text formatted
+    /// on every call.
+    pub generation: u64,
+}
+
+/// The text of the effective configuration, rendered once and then read part by part.
+pub struct ConfigSnapshot {
+    /// The snapshot, only tried to lock so no processor spins while another one renders.
+    state: SpinLock<SnapshotState>,
+}
+
+/// The text held by a `ConfigSnapshot`.
+struct SnapshotState {
+    /// The buffer the text is rendered into, `SNAPSHOT_CAPACITY` bytes long.
+    text: Box<[u8]>,
+
+    /// The length of the text.
+    len: usize,
+
+    /// Identifies the text, incremented every time it is rendered.
+    generation: u64,
+}
+
+impl Default for ConfigSnapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigSnapshot {
+    /// Creates an empty snapshot, allocating its buffer.
+    pub fn new() -> Self {
+        Self {
+            state: SpinLock::new(
+                "config_snapshot",
+                SnapshotState {
+                    text: vec![0; SNAPSHOT_CAPACITY].into_boxed_slice(),
+                    len: 0,
+                    generation: 0,
+                },
+            ),
+        }
+    }
+
+    /// Copies part of the snapshot into a buffer. A read from offset 0 renders a new snapshot first.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration rendered by a read from offset 0.
+    /// * `buffer` - Receives the text from `offset` on, as much as fits.
+    /// * `offset` - The offset into the text of the first byte to copy.
+    /// * `generation` - The generation returned by the read from offset 0, ignored at offset 0.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes copied, the length of the whole text and the generation of the snapshot, or `None` if
+    /// the snapshot was rendered again since `generation` or is in use on another processor, in which case the
+    /// caller starts over from offset 0.
+    pub fn read(
+        &self,
+        config: &EffectiveConfig<'_>,
+        buffer: &mut [u8],
+        offset: usize,
+        generation: u64,
+    ) -> Option<ConfigExport> {
+        let mut state = self.state.try_lock()?;
+
+        if offset == 0 {
+            state.render(config);
+        } else if generation != state.generation {
+            return None;
+        }
+
+        let mut window = TextWindow::new(buffer, offset);
+
+        // A window created with `TextWindow::new` never fails a write.
+        let _ = window.write_bytes(state.text.get(..state.len).unwrap_or_default());
+
+        Some(ConfigExport {
+            copied: window.copied(),
+            total: state.len,
+            generation: state.generation,
+        })
+    }
+}
+
+impl SnapshotState {
+    /// Renders the configuration into the buffer, replacing the previous text.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration.
+    fn render(&mut self, config: &EffectiveConfig<'_>) {
+        let capacity = self.text.len().saturating_sub(SNAPSHOT_TRUNCATED.len());
+        let text = self.text.get_mut(..capacity).unwrap_or_default();
+
+        let mut window = TextWindow::until_full(text);
+        let complete = write!(window, "{}", config).is_ok();
+        let mut len = window.copied();
+
+        // A text that does not fit is cut off after its last complete line, and marked as such.
+        if !complete {
+            len = text
+                .get(..len)
+                .and_then(|text| text.iter().rposition(|&byte| byte == b'\n'))
+                .map_or(0, |newline| newline + 1);
+
+            if let Some(marker) = self.text.get_mut(len..len + SNAPSHOT_TRUNCATED.len()) {
+                marker.copy_from_slice(SNAPSHOT_TRUNCATED.as_bytes());
+                len += SNAPSHOT_TRUNCATED.len();
+            }
+        }
+
+        self.len = len;
+        self.generation += 1;
+    }
+}
+
+/// The effective configuration, formatted from the live state.
+pub struct EffectiveConfig<'a> {
+    /// The state shared between the processors.
+    shared_data: &'a SharedData,
+
+    /// The controls of the current VMCS, if exported from root mode.
+    vmcs: Option<VmcsControls>,
+}
+
+impl<'a> EffectiveConfig<'a> {
+    /// Creates the view of the effective configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `shared_data` - The state shared between the processors.
+    pub fn new(shared_data: &'a SharedData) -> Self {
+        Self {
+            shared_data,
+            vmcs: None,
+        }
+    }
+
+    /// Adds the `[vmcs]` section with the controls of the current VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `vmcs` - The controls, see `VmcsControls::capture`.
+    pub fn with_vmcs(mut self, vmcs: VmcsControls) -> Self {
+        self.vmcs = Some(vmcs);
+        self
+    }
+
+    fn write_build(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[build]")?;
+        writeln!(f, "version={}", env!("CARGO_PKG_VERSION"))?;
+        write!(f, "features=")?;
+        write_names(f, BuildFeatures::current().iter_names())?;
+        writeln!(f)
+    }
+
+    fn write_stealth(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shared_data = self.shared_data;
+        let paravirt = &shared_data.paravirt;
+
+        writeln!(f, "[stealth]")?;
+        writeln!(f, "paravirt={}", paravirt.is_enabled())?;
+        write!(f, "paravirt_features=")?;
+        write_names(
+            f,
+            paravirt
+                .features()
+                .unwrap_or(ParavirtFeatures::empty())
+                .iter_names(),
+        )?;
+        writeln!(f)?;
+        writeln!(f, "hypercall_page={:#x}", paravirt.hypercall_page())?;
+        write!(f, "cpuid_masking=")?;
+        write_names(f, shared_data.cpuid_masking.iter_names())?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "client_sessions={}",
+            shared_data.client_sessions.is_enabled()
+        )?;
+        writeln!(f, "rng_seeded={}", shared_data.rng_seed.is_some())
+    }
+
+    fn write_intercepts(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shared_data = self.shared_data;
+        let msr_bitmap = &shared_data.msr_bitmap;
+        let msrs = || (0..=LOW_MSRS_END).chain(HIGH_MSRS_START..=HIGH_MSRS_END);
+
+        writeln!(f, "[intercepts]")?;
+        write!(f, "msr_read=")?;
+        write_ranges(f, msrs().filter(|&msr| msr_bitmap.is_intercepted(msr).0))?;
+        write!(f, "\nmsr_write=")?;
+        write_ranges(f, msrs().filter(|&msr| msr_bitmap.is_intercepted(msr).1))?;
+        writeln!(f)?;
+
+        let mut result = Ok(());
+        shared_data.msr_policies.for_each_rule(|rule| {
+            result = result.and_then(|()| {
+                let policy = match rule.policy {
+                    MsrPolicy::Passthrough => "passthrough",
+                    MsrPolicy::Deny => "deny",
+                    MsrPolicy::Emulate(_) => "emulate",
+                };
+                writeln!(f, "msr_policy.{:#x}={}", rule.msr, policy)
+            });
+        });
+        result?;
+
+        write!(f, "cpu_set=")?;
+        write_ranges(
+            f,
+            (0..processor_count()).filter(|&index| shared_data.cpu_set.contains(index)),
+        )?;
+        writeln!(f)?;
+        writeln!(f, "cr3_exiting={}", shared_data.cr3_observer.is_some())?;
+        writeln!(f, "invlpg_exiting={}", shared_data.invlpg_exiting)?;
+        writeln!(
+            f,
+            "descriptor_table_exiting={}",
+            shared_data.descriptor_table_exiting
+        )?;
+        writeln!(f, "hlt_exiting={}", shared_data.hlt_callback.is_some())?;
+        writeln!(
+            f,
+            "cache_flush_policy={}",
+            match shared_data.cache_flush_policy {
+                CacheFlushPolicy::Execute => "execute",
+                CacheFlushPolicy::Skip => "skip",
+            }
+        )?;
+        writeln!(
+            f,
+            "mwait_policy={}",
+            match shared_data.mwait_policy {
+                MwaitPolicy::Native => "native",
+                MwaitPolicy::Pause => "pause",
+            }
+        )?;
+        writeln!(
+            f,
+            "host_breakpoints={}",
+            shared_data.host_breakpoints.iter().flatten().count()
+        )?;
+        match shared_data.pause_loop {
+            Some(config) => writeln!(
+                f,
+                "pause_loop_exiting=gap:{},window:{}",
+                config.gap, config.window
+            )?,
+            None => writeln!(f, "pause_loop_exiting=off")?,
+        }
+        match shared_data.encls_exiting {
+            Some(bitmap) => writeln!(f, "encls_exiting={:#x}", bitmap.0)?,
+            None => writeln!(f, "encls_exiting=off")?,
+        }
+        writeln!(
+            f,
+            "triple_fault_policy={}",
+            match shared_data.triple_fault_policy {
+                TripleFaultPolicy::BugCheck => "bugcheck",
+                TripleFaultPolicy::Halt => "halt",
+            }
+        )?;
+        writeln!(f, "apic_base_tracking={}", shared_data.apic_base_tracking)?;
+        writeln!(f, "x2apic_interception={}", shared_data.x2apic_interception)?;
+        writeln!(
+            f,
+            "ept_violation_callback={}",
+            shared_data.ept_violation_callback.is_some()
+        )?;
+        let smi_counts = shared_data.smm_monitor.counts();
+        writeln!(
+            f,
+            "smm_monitoring={} smis_guest={} smis_root={}",
+            shared_data.smm_monitor.is_enabled(),
+            smi_counts.guest,
+            smi_counts.root
+        )?;
+        writeln!(
+            f,
+            "vmx_instructions={}",
+            match shared_data.vmx_instruction_response {
+                VmxInstructionResponse::UndefinedOpcode => "undefined-opcode",
+                VmxInstructionResponse::FailInvalid => "fail-invalid",
+            }
+        )?;
+        write!(f, "io_ports=")?;
+        write_ranges(
+            f,
+            (0..=u16::MAX)
+                .filter(|&port| shared_data.io_bitmap.is_intercepted(port))
+                .map(u32::from),
+        )?;
+        writeln!(f)?;
+        write!(f, "rate_limit.hypercall=")?;
+        write_quota(f, shared_data.rate_limits.hypercall)?;
+        write!(f, "\nrate_limit.monitored_fault=")?;
+        write_quota(f, shared_data.rate_limits.monitored_fault)?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "debugger_policy={}",
+            match shared_data.debugger.policy() {
+                DebuggerPolicy::Ignore => "ignore",
+                DebuggerPolicy::SuspendHooks => "suspend-hooks",
+            }
+        )?;
+        writeln!(
+            f,
+            "hooks_suspended={}",
+            shared_data.debugger.hooks_suspended()
+        )?;
+        #[cfg(feature = "devices")]
+        writeln!(
+            f,
+            "keyboard_protection={}",
+            match shared_data.keyboard_guard.protection() {
+                KeyboardProtection::Disabled => "disabled",
+                KeyboardProtection::Monitor => "monitor",
+                KeyboardProtection::Block => "block",
+            }
+        )?;
+        match shared_data.thrash_policy {
+            Some(policy) => writeln!(
+                f,
+                "thrash_policy=max_switches:{:#x},window:{:#x},strategy:{},cooldown:{:#x},max_cooldown:{:#x}",
+                policy.max_switches,
+                policy.window,
+                match policy.strategy {
+                    ThrashStrategy::SingleStep => "single-step",
+                    ThrashStrategy::DisableHook => "disable-hook",
+                },
+                policy.cooldown,
+                policy.max_cooldown
+            )?,
+            None => writeln!(f, "thrash_policy=off")?,
+        }
+        writeln!(
+            f,
+            "entry_failure_retries={}",
+            shared_data.entry_failure_retries
+        )?;
+        writeln!(
+            f,
+            "root_operation_timeout={}",
+            shared_data.root_operation_timeout
+        )?;
+        let tsc = shared_data.tsc_config;
+        writeln!(
+            f,
+            "tsc_mode={}",
+            match tsc.mode {
+                TscMode::Native => "native",
+                TscMode::Offsetting => "offsetting",
+                TscMode::Exiting => "exiting",
+            }
+        )?;
+        writeln!(f, "tsc_multiplier={:#x}", tsc.multiplier)?;
+        writeln!(f, "tsc_compensation={}", tsc.compensate_root_time)?;
+        writeln!(
+            f,
+            "apic_timer_mode={}",
+            match apic_timer::timer_mode() {
+                Some(ApicTimerMode::OneShot) => "one-shot",
+                Some(ApicTimerMode::Periodic) => "periodic",
+                Some(ApicTimerMode::TscDeadline) => "tsc-deadline",
+                None => "xapic",
+            }
+        )?;
+        match shared_data.lbr_stack {
+            Some(stack) => writeln!(
+                f,
+                "lbr_stack=depth:{:#x},info:{}",
+                stack.depth, stack.has_info
+            )?,
+            None => writeln!(f, "lbr_stack=off")?,
+        }
+        writeln!(
+            f,
+            "tsx_policy={}",
+            match shared_data.tsx.policy {
+                TsxPolicy::Passthrough => "passthrough",
+                TsxPolicy::Disable => "disable",
+            }
+        )?;
+        writeln!(f, "guest_rtm={}", shared_data.tsx.guest_rtm())?;
+        match shared_data.cpuid_topology {
+            Some(config) => writeln!(
+                f,
+                "cpuid_topology=threads:{},cores:{}",
+                config.threads_per_core, config.cores_per_package
+            )?,
+            None => writeln!(f, "cpuid_topology=native")?,
+        }
+        writeln!(f, "exit_heat_map={}", shared_data.heat_map.is_enabled())?;
+        writeln!(
+            f,
+            "agent_monitor={}",
+            shared_data.agent_monitor.is_enabled()
+        )?;
+        writeln!(
+            f,
+            "fault_injection={}",
+            shared_data.fault_injector.is_enabled()
+        )?;
+        match shared_data.heap_poison.is_enabled() {
+            true => writeln!(
+                f,
+                "heap_poisoning={} poison_touches={}",
+                response_name(shared_data.heap_poison.response()),
+                shared_data.heap_poison.total_touches()
+            )?,
+            false => writeln!(f, "heap_poisoning=none")?,
+        }
+        writeln!(f, "process_control={}", shared_data.processes.is_enabled())?;
+        writeln!(
+            f,
+            "denied_drivers={}",
+            shared_data.driver_blocker.deny_list().len()
+        )?;
+
+        match shared_data.coverage.is_enabled() {
+            true => writeln!(f, "coverage_target={}", shared_data.coverage.module()),
+            false => writeln!(f, "coverage_target=none"),
+        }
+    }
+
+    fn write_ept(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shared_data = self.shared_data;
+
+        writeln!(f, "[ept]")?;
+        writeln!(f, "primary_eptp={:#x}", shared_data.primary_eptp)?;
+        #[cfg(feature = "secondary-ept")]
+        writeln!(f, "secondary_eptp={:#x}", shared_data.secondary_eptp)?;
+        writeln!(f, "accessed_dirty={}", shared_data.ept_accessed_dirty)?;
+
+        let mut result = Ok(());
+        shared_data.ept_policy.for_each_region(|region| {
+            result = result.and_then(|()| {
+                writeln!(
+                    f,
+                    "region.{:#x}-{:#x}={},{},{}",
+                    region.start.as_u64(),
+                    region.end.as_u64() - 1,
+                    region.profile.name,
+                    AccessDisplay(region.profile.access),
+                    response_name(region.profile.response)
+                )
+            });
+        });
+
+        result
+    }
+
+    fn write_hooks(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[hooks]")?;
+
+        // The configuration is also exported in VMX root operation, where waiting for a writer that the guest
+        // preempted on this processor would never end.
+        let Some(hook_manager) = self.shared_data.hook_manager.try_read() else {
+            return writeln!(f, "busy=true");
+        };
+
+        for namespace in hook_manager.namespaces.iter() {
+            writeln!(
+                f,
+                "namespace.{}={},quota={},hooks={}",
+                namespace.id,
+                namespace.name,
+                namespace.quota,
+                hook_manager.hooks_in_namespace(namespace.id).count()
+            )?;
+        }
+
+        for hook in hook_manager.hooks.iter() {
+            match &hook.hook_type {
+                HookType::Function { inline_hook } => writeln!(
+                    f,
+                    "hook.{:#x}=function,namespace={},handler={:#x},filter={}",
+                    hook.original_va,
+                    hook.namespace,
+                    inline_hook.handler_address(),
+                    FilterDisplay(hook.filter.as_ref())
+                )?,
+                HookType::Page => writeln!(
+                    f,
+                    "hook.{:#x}=page,namespace={}",
+                    hook.original_va, hook.namespace
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_cores(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cores = self.shared_data.core_capabilities.lock();
+
+        for core in cores.iter().flatten() {
+            writeln!(f, "[core.{}]", core.index)?;
+            writeln!(f, "apic_id={:#x}", core.apic_id)?;
+            match core.core_type {
+                Some(CoreType::Efficiency) => writeln!(f, "core_type=efficiency")?,
+                Some(CoreType::Performance) => writeln!(f, "core_type=performance")?,
+                Some(CoreType::Unknown(core_type)) => writeln!(f, "core_type={:#x}", core_type)?,
+                None => writeln!(f, "core_type=none")?,
+            }
+            writeln!(f, "microcode={:#x}", core.microcode)?;
+            writeln!(f, "procbased_ctls2={:#x}", core.procbased_ctls2)?;
+            writeln!(f, "misc={:#x}", core.misc)?;
+            writeln!(f, "ept_vpid_cap={:#x}", core.ept_vpid_cap)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_vmcs(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(vmcs) = &self.vmcs else {
+            return Ok(());
+        };
+
+        writeln!(f, "[vmcs]")?;
+        writeln!(f, "pinbased={:#x}", vmcs.pinbased)?;
+        writeln!(f, "procbased={:#x}", vmcs.procbased)?;
+        writeln!(f, "procbased2={:#x}", vmcs.procbased2)?;
+        writeln!(f, "exit={:#x}", vmcs.exit)?;
+        writeln!(f, "entry={:#x}", vmcs.entry)?;
+        writeln!(f, "exception_bitmap={:#x}", vmcs.exception_bitmap)?;
+        writeln!(f, "eptp={:#x}", vmcs.eptp)?;
+        writeln!(f, "vpid={:#x}", vmcs.vpid)
+    }
+}
+
+impl fmt::Display for EffectiveConfig<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_build(f)?;
+        self.write_stealth(f)?;
+        self.write_intercepts(f)?;
+        self.write_ept(f)?;
+        self.write_hooks(f)?;
+        self.write_cores(f)?;
+        self.write_vmcs(f)
+    }
+}
+
+/// Formats the filter of a hook, or `none` for a hook without filter.
+struct FilterDisplay<'a>(Option<&'a HookFilter>);
+
+impl fmt::Display for FilterDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(filter) => write!(f, "{}", filter),
+            None => write!(f, "none"),
+        }
+    }
+}
+
+/// Formats EPT permissions as `rwx`, with `-` for the permissions not granted.
+struct AccessDisplay(AccessType);
+
+impl fmt::Display for AccessDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (access, name) in [
+            (AccessType::READ, 'r'),
+            (AccessType::WRITE, 'w'),
+            (AccessType::EXECUTE, 'x'),
+        ] {
+            f.write_char(match self.0.contains(access) {
+                true => name,
+                false => '-',
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the name of the response to the violations of a region or the poison.
+fn response_name(response: ViolationResponse) -> &'static str {
+    match response {
+        ViolationResponse::Monitor => "monitor",
+        ViolationResponse::Deny => "deny",
+    }
+}
+
+/// Writes the quota of a class of exits, or `off`.
+fn write_quota(f: &mut fmt::Formatter<'_>, quota: Option<Quota>) -> fmt::Result {
+    let Some(quota) = quota else {
+        return write!(f, "off");
+    };
+
+    write!(
+        f,
+        "max_exits:{:#x},window:{:#x},backoff:",
+        quota.max_exits, quota.window
+    )?;
+    match quota.backoff {
+        Backoff::InjectGp => write!(f, "inject-gp"),
+        Backoff::Delay(ticks) => write!(f, "delay:{:#x}", ticks),
+    }
+}
+
+/// Writes flag names comma separated, or `none`.
+fn write_names<'n>(
+    f: &mut fmt::Formatter<'_>,
+    names: impl Iterator<Item = (&'n str, impl Sized)>,
+) -> fmt::Result {
+    let mut separator = "";
+
+    for (name, _) in names {
+        write!(f, "{}{}", separator, name)?;
+        separator = ",";
+    }
+
+    if separator.is_empty() {
+        write!(f, "none")?;
+    }
+
+    Ok(())
+}
+
+/// Writes ascending values as comma separated ranges of consecutive values, or `none`.
+fn write_ranges(f: &mut fmt::Formatter<'_>, values: impl Iterator<Item = u32>) -> fmt::Result {
+    let mut separator = "";
+    let mut range: Option<(u32, u32)> = None;
+
+    let mut write_range = |f: &mut fmt::Formatter<'_>, (start, end): (u32, u32)| {
+        let result = match start == end {
+            true => write!(f, "{}{:#x}", separator, start),
+            false => write!(f, "{}{:#x}-{:#x}", separator, start, end),
+        };
+        separator = ",";
+        result
+    };
+
+    for value in values {
+        range = match range {
+            Some((start, end)) if end.checked_add(1) == Some(value) => Some((start, value)),
+            Some(previous) => {
+                write_range(f, previous)?;
+                Some((value, value))
+            }
+            None => Some((value, value)),
+        };
+    }
+
+    match range {
+        Some(last) => write_range(f, last),
+        None => write!(f, "none"),
+    }
+}
+
+/// Copies part of a text formatted in place into a buffer, without allocating.
+///
+/// # Arguments
+///
+/// * `text` - The text, formatted with `Display`.
+/// * `buffer` - Receives the text from `offset` on, as much as fits.
+/// * `offset` - The offset into the text of the first byte to copy.
+///
+/// # Returns
+///
+/// The number of bytes copied and the length of the whole text.
+pub fn export_text(text: &dyn fmt::Display, buffer: &mut [u8], offset: usize) -> ConfigExport {
+    let mut window = TextWindow::new(buffer, offset);
+
+    // The window never fails, it only stops copying once full.
+    let _ = write!(window, "{}", text);
+
+    ConfigExport {
+        copied: window.copied(),
+        total: window.total(),
+        generation: 0,
+    }
+}
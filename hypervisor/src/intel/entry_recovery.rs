@@ -0,0 +1,517 @@
+//! Recovery from VM-entry failures.
+//!
+//! A VM entry that fails the checks on the guest state, fails to load an MSR of the VM-entry MSR-load area or
+//! runs into a machine check does not enter the guest. Instead, it exits with bit 31 of the exit reason set,
+//! and only the exit reason and the exit qualification are saved. Resuming without changing the VMCS fails
+//! the same way, so the processor would be stuck in VMX root operation.
+//!
+//! The guest state the processor saved on a VM exit passed the checks of the VM entry before it, so it is known
+//! to be good. Reading it takes about twenty VMREADs, so it is not captured on every VM exit: the exit handlers
+//! about to write a field the checks of the VM entry are likely to reject, a control register, DR7, IA32_EFER, a
+//! segment or the VM-entry controls, call `EntryRecovery::preserve` first, which captures the guest state along
+//! with the general-purpose registers once per exit.
+//! A failed VM entry is recovered from as follows:
+//! 1. The failure is recorded with its exit qualification.
+//! 2. If the exit before it preserved the guest state, the snapshot is written back to the VMCS and the VM entry
+//!    is retried, which discards what the exit handler wrote and makes the guest run the exiting instruction
+//!    again. A machine check during VM entry is retried without changing the guest state.
+//! 3. Once a configured number of retries fails in a row, or right away for a failure to load an MSR, which
+//!    lies in the controls of the hypervisor rather than in the guest state, the processor is devirtualized
+//!    and resumes the guest natively from the snapshot. The other processors stay virtualized.
+//!
+//! An exit that did not preserve the guest state left it as the processor saved it, apart from fields such as
+//! RIP, RSP and RFLAGS, and may have had side effects outside of it, e.g. an MSR written for the guest or a hypercall
+//! served. Going back to the state before the exit would run them twice, and retrying the same state fails the
+//! same way, so the processor is devirtualized right away and resumes the guest natively from the state the
+//! exit handler left, past the exiting instruction.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.8 VM-Entry Failures During
+//! or After Loading Guest State and 28.2.1 Basic VM-Exit Information.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            guest_memory::GuestMemory,
+            support::{try_vmread, try_vmwrite},
+            vmerror::VmxBasicExitReason,
+            vmexit::cr::cr4_read_shadow,
+        },
+        utils::{
+            addresses::Gva,
+            capture::GuestRegisters,
+            instructions::{
+                cr0, cr0_write, cr3, cr3_write, cr4, cr4_write, dr7_write, lgdt, lidt, rdmsr, wrmsr,
+            },
+            nt::{get_ntoskrnl_export, NTOSKRNL_CR3},
+        },
+    },
+    core::sync::atomic::{AtomicU64, Ordering},
+    x86::{
+        controlregs::{Cr0, Cr4},
+        dtables::DescriptorTablePointer,
+        msr,
+        vmx::vmcs::{control, guest},
+    },
+};
+
+/// Bit 31 of the exit reason, set if the exit was caused by a failed VM entry.
+pub const VM_ENTRY_FAILURE: u64 = 1 << 31;
+
+/// The number of failed VM entries retried in a row before a processor is devirtualized, unless configured
+/// with `HypervisorBuilder::entry_failure_retries`.
+pub const DEFAULT_ENTRY_RETRIES: u32 = 3;
+
+/// The offset of `KPCR.Prcb.CurrentThread`, unchanged on x64 since Windows XP.
+const CURRENT_THREAD_OFFSET: u64 = 0x188;
+
+/// The offset of `KPROCESS.DirectoryTableBase`, unchanged on x64 since Windows XP.
+const DIRECTORY_TABLE_BASE_OFFSET: u64 = 0x28;
+
+/// The code of `IoGetCurrentProcess` up to the offset of `KTHREAD.ApcState.Process`:
+/// `mov rax, gs:[188h]` and `mov rax, [rax + disp32]`.
+const IO_GET_CURRENT_PROCESS: [u8; 12] = [
+    0x65, 0x48, 0x8B, 0x04, 0x25, 0x88, 0x01, 0x00, 0x00, 0x48, 0x8B, 0x80,
+];
+
+/// The offset of `KTHREAD.ApcState.Process`, which changes between Windows builds, or zero if unknown.
+static THREAD_PROCESS_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// IA32_EFER.LME and IA32_EFER.LMA, which cannot change while paging is enabled.
+const EFER_LONG_MODE: u64 = (1 << 8) | (1 << 10);
+
+/// Decodes the offset of the process of a thread from `IoGetCurrentProcess`, so the guest can be resumed
+/// natively from user mode, see `GuestSnapshot::resume_cr3`.
+///
+/// Must be called in VMX non-root operation, as it reads the kernel directly. An unexpected code is logged and
+/// leaves the offset unknown.
+pub fn resolve_thread_layout() {
+    let address = get_ntoskrnl_export("IoGetCurrentProcess") as *const u8;
+    if address.is_null() {
+        log::warn!("IoGetCurrentProcess not found, user-mode state cannot be resumed natively");
+        return;
+    }
+
+    let code = unsafe { core::slice::from_raw_parts(address, IO_GET_CURRENT_PROCESS.len() + 4) };
+    let (Some(prefix), Some(Ok(displacement))) = (
+        code.get(..IO_GET_CURRENT_PROCESS.len()),
+        code.get(IO_GET_CURRENT_PROCESS.len()..)
+            .map(<[u8; 4]>::try_from),
+    ) else {
+        return;
+    };
+
+    if prefix != IO_GET_CURRENT_PROCESS {
+        log::warn!("Unexpected code of IoGetCurrentProcess: {:02x?}", code);
+        return;
+    }
+
+    THREAD_PROCESS_OFFSET.store(u32::from_le_bytes(displacement) as u64, Ordering::Relaxed);
+}
+
+/// The guest state saved by the processor on a VM exit, known to pass the checks of a VM entry.
+///
+/// Besides the registers and the VM-entry controls, the fields mirror the guest-state fields of the VMCS of the
+/// same name.
+#[derive(Clone, Copy, Default)]
+pub struct GuestSnapshot {
+    /// The general-purpose and XMM registers.
+    pub registers: GuestRegisters,
+
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub dr7: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub cs_selector: u64,
+    pub ss_selector: u64,
+    pub efer: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub gdtr_base: u64,
+    pub gdtr_limit: u64,
+    pub idtr_base: u64,
+    pub idtr_limit: u64,
+    pub interruptibility_state: u64,
+    pub activity_state: u64,
+    pub pending_debug_exceptions: u64,
+
+    /// The VM-entry controls, as IA-32e mode guest follows IA32_EFER.LMA of the guest.
+    pub entry_controls: u64,
+}
+
+impl GuestSnapshot {
+    /// Captures the guest state from the current VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The general-purpose and XMM registers saved on the VM exit.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the snapshot, or an error if a field could not be read.
+    pub fn capture(registers: &GuestRegisters) -> Result<Self, HypervisorError> {
+        Ok(Self {
+            registers: *registers,
+            cr0: try_vmread(guest::CR0)?,
+            cr3: try_vmread(guest::CR3)?,
+            cr4: try_vmread(guest::CR4)?,
+            dr7: try_vmread(guest::DR7)?,
+            rip: try_vmread(guest::RIP)?,
+            rsp: try_vmread(guest::RSP)?,
+            rflags: try_vmread(guest::RFLAGS)?,
+            cs_selector: try_vmread(guest::CS_SELECTOR)?,
+            ss_selector: try_vmread(guest::SS_SELECTOR)?,
+            efer: try_vmread(guest::IA32_EFER_FULL)?,
+            fs_base: try_vmread(guest::FS_BASE)?,
+            gs_base: try_vmread(guest::GS_BASE)?,
+            gdtr_base: try_vmread(guest::GDTR_BASE)?,
+            gdtr_limit: try_vmread(guest::GDTR_LIMIT)?,
+            idtr_base: try_vmread(guest::IDTR_BASE)?,
+            idtr_limit: try_vmread(guest::IDTR_LIMIT)?,
+            interruptibility_state: try_vmread(guest::INTERRUPTIBILITY_STATE)?,
+            activity_state: try_vmread(guest::ACTIVITY_STATE)?,
+            pending_debug_exceptions: try_vmread(guest::PENDING_DBG_EXCEPTIONS)?,
+            entry_controls: try_vmread(control::VMENTRY_CONTROLS)?,
+        })
+    }
+
+    /// Writes the guest state back to the current VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The general-purpose and XMM registers, overwritten with those of the snapshot.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether all fields were written.
+    pub fn restore(&self, registers: &mut GuestRegisters) -> Result<(), HypervisorError> {
+        *registers = self.registers;
+
+        try_vmwrite(guest::CR0, self.cr0)?;
+        try_vmwrite(guest::CR3, self.cr3)?;
+        try_vmwrite(guest::CR4, self.cr4)?;
+        try_vmwrite(guest::DR7, self.dr7)?;
+        try_vmwrite(guest::RIP, self.rip)?;
+        try_vmwrite(guest::RSP, self.rsp)?;
+        try_vmwrite(guest::RFLAGS, self.rflags)?;
+        try_vmwrite(guest::CS_SELECTOR, self.cs_selector)?;
+        try_vmwrite(guest::SS_SELECTOR, self.ss_selector)?;
+        try_vmwrite(guest::IA32_EFER_FULL, self.efer)?;
+        try_vmwrite(guest::FS_BASE, self.fs_base)?;
+        try_vmwrite(guest::GS_BASE, self.gs_base)?;
+        try_vmwrite(guest::GDTR_BASE, self.gdtr_base)?;
+        try_vmwrite(guest::GDTR_LIMIT, self.gdtr_limit)?;
+        try_vmwrite(guest::IDTR_BASE, self.idtr_base)?;
+        try_vmwrite(guest::IDTR_LIMIT, self.idtr_limit)?;
+        try_vmwrite(guest::INTERRUPTIBILITY_STATE, self.interruptibility_state)?;
+        try_vmwrite(guest::ACTIVITY_STATE, self.activity_state)?;
+        try_vmwrite(guest::PENDING_DBG_EXCEPTIONS, self.pending_debug_exceptions)?;
+        try_vmwrite(control::VMENTRY_CONTROLS, self.entry_controls)?;
+
+        Ok(())
+    }
+
+    /// Returns the CR3 to resume the guest natively with.
+    ///
+    /// The guest resumes with the `IRETQ` of `resume_guest`, which runs from the image of the hypervisor and pops
+    /// its frame from the host stack. With kernel VA shadowing, the CR3 of user mode only maps the few kernel
+    /// pages needed to enter the kernel, so loading it before the `IRETQ` faults. A CR3 that does not map the
+    /// code and the stack of the hypervisor as the host does is replaced with the kernel CR3 of the current
+    /// process, which maps user mode as well and is switched back to the user one by the kernel on its next
+    /// return to user mode.
+    ///
+    /// Must be called in VMX root operation, as it reads the `KPCR` of the guest through `IA32_KERNEL_GS_BASE`.
+    ///
+    /// # Returns
+    ///
+    /// The CR3 of the snapshot, the kernel CR3 of the current process, or, if neither maps the hypervisor, the
+    /// CR3 of the System process, which at least lets the guest fault instead of the hypervisor.
+    pub fn resume_cr3(&self) -> u64 {
+        if maps_hypervisor(self.cr3) {
+            return self.cr3;
+        }
+
+        // With CPL 3, SWAPGS moved the base of the KPCR to IA32_KERNEL_GS_BASE.
+        let kpcr = match self.cs_selector & 0b11 {
+            3 => rdmsr(msr::IA32_KERNEL_GSBASE),
+            _ => self.gs_base,
+        };
+
+        let kernel = GuestMemory::supervisor(NTOSKRNL_CR3.load(Ordering::Acquire));
+        let read = |address: u64| {
+            let mut value = [0u8; 8];
+            kernel
+                .read(Gva::new(address), &mut value)
+                .ok()
+                .map(|()| u64::from_le_bytes(value))
+        };
+
+        let offset = THREAD_PROCESS_OFFSET.load(Ordering::Relaxed);
+        let kernel_cr3 = match offset {
+            0 => None,
+            _ => read(kpcr + CURRENT_THREAD_OFFSET)
+                .and_then(|thread| read(thread + offset))
+                .and_then(|process| read(process + DIRECTORY_TABLE_BASE_OFFSET)),
+        };
+
+        match kernel_cr3 {
+            Some(kernel_cr3) if maps_hypervisor(kernel_cr3) => kernel_cr3,
+            _ => {
+                log::error!(
+                    "No CR3 mapping the hypervisor found for guest CR3 {:#x}, resuming with the System one",
+                    self.cr3
+                );
+                NTOSKRNL_CR3.load(Ordering::Acquire)
+            }
+        }
+    }
+
+    /// Loads the parts of the guest state that differ from the host state into the processor, once it left
+    /// VMX operation.
+    ///
+    /// The host runs on the page tables of the system process and on copies of the descriptor tables of the
+    /// guest, so the guest state is complete after loading its control registers, EFER, descriptor tables and
+    /// segment bases. CR4.VMXE is cleared, as the processor left VMX operation, and EFER keeps its long mode
+    /// bits, which cannot change while paging is enabled. The general-purpose registers, RIP, CS, RFLAGS, RSP
+    /// and SS are loaded by `resume_guest` last.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The CR3 to load, see `resume_cr3`.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called after VMXOFF, with interrupts disabled, right before resuming the guest.
+    pub unsafe fn load(&self, cr3: u64) {
+        cr3_write(cr3);
+
+        // Bits unknown to `x86` are kept, the guest may use them.
+        if cr0().bits() as u64 != self.cr0 {
+            cr0_write(Cr0::from_bits_unchecked(self.cr0 as usize));
+        }
+
+        let cr4_value = cr4_read_shadow(self.cr4);
+        if cr4().bits() as u64 != cr4_value {
+            cr4_write(Cr4::from_bits_unchecked(cr4_value as usize));
+        }
+
+        let efer = rdmsr(msr::IA32_EFER);
+        let efer_value = (self.efer & !EFER_LONG_MODE) | (efer & EFER_LONG_MODE);
+        if efer != efer_value {
+            wrmsr(msr::IA32_EFER, efer_value);
+        }
+
+        lgdt(&DescriptorTablePointer {
+            limit: self.gdtr_limit as u16,
+            base: self.gdtr_base as *const u64,
+        });
+        lidt(&DescriptorTablePointer {
+            limit: self.idtr_limit as u16,
+            base: self.idtr_base as *const u64,
+        });
+        wrmsr(msr::IA32_FS_BASE, self.fs_base);
+        wrmsr(msr::IA32_GS_BASE, self.gs_base);
+        dr7_write(self.dr7);
+    }
+}
+
+/// Whether an address space maps the code of `resume_guest` and the current stack to the same physical pages
+/// as the host, so it can be loaded before the `IRETQ` resuming the guest natively.
+///
+/// # Arguments
+///
+/// * `address_space` - The CR3 of the address space.
+fn maps_hypervisor(address_space: u64) -> bool {
+    let host = GuestMemory::supervisor(cr3());
+    let guest = GuestMemory::supervisor(address_space);
+    let stack = 0u8;
+
+    [
+        crate::intel::vmlaunch::resume_guest as *const () as u64,
+        core::ptr::addr_of!(stack) as u64,
+    ]
+    .into_iter()
+    .all(|address| {
+        let address = Gva::new(address);
+        matches!(
+            (host.translate(address, false), guest.translate(address, true)),
+            (Ok(expected), Ok(actual)) if expected == actual
+        )
+    })
+}
+
+/// A VM entry that failed.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryFailure {
+    /// The basic exit reason, with the entry failure bit masked off.
+    pub reason: VmxBasicExitReason,
+
+    /// The exit qualification, the index of the failed MSR entry for a failure to load an MSR.
+    pub qualification: u64,
+
+    /// The guest RIP the VM entry was attempted at.
+    pub rip: u64,
+}
+
+/// How a failed VM entry is recovered from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Retry the VM entry with the guest state of the snapshot.
+    Retry,
+
+    /// Leave VMX operation on the processor and resume the guest natively from the snapshot.
+    Devirtualize,
+}
+
+/// The VM-entry failure recovery of a single processor.
+///
+/// Only ever accessed by the VM-exit handler of its own processor, so no synchronization is needed.
+pub struct EntryRecovery {
+    /// The guest state preserved on the last VM exit, or `None` if it did not preserve it.
+    snapshot: Option<GuestSnapshot>,
+
+    /// Whether the processor exited at least once, so the guest state in the VMCS was saved by the processor.
+    exited: bool,
+
+    /// The number of failed VM entries retried in a row before the processor is devirtualized.
+    max_retries: u32,
+
+    /// The number of failed VM entries in a row.
+    retries: u32,
+
+    /// The total number of failed VM entries.
+    failures: u64,
+
+    /// The last failed VM entry.
+    last_failure: Option<EntryFailure>,
+}
+
+impl EntryRecovery {
+    /// Creates the recovery of a processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_retries` - The number of failed VM entries retried in a row before the processor is devirtualized.
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            snapshot: None,
+            exited: false,
+            max_retries,
+            retries: 0,
+            failures: 0,
+            last_failure: None,
+        }
+    }
+
+    /// Starts a VM exit not caused by a failed VM entry, dropping the snapshot of the previous one.
+    ///
+    /// The previous VM entry succeeded, so the failed entries in a row are reset as well.
+    pub fn begin_exit(&mut self) {
+        self.snapshot = None;
+        self.exited = true;
+        self.retries = 0;
+    }
+
+    /// Captures the guest state saved on the current VM exit, unless it was already, before an exit handler
+    /// writes a field the checks of the VM entry are likely to reject, see the module documentation.
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The general-purpose and XMM registers, not yet changed by the exit handler.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether the guest state was captured.
+    pub fn preserve(&mut self, registers: &GuestRegisters) -> Result<(), HypervisorError> {
+        if self.snapshot.is_none() {
+            self.snapshot = Some(GuestSnapshot::capture(registers)?);
+        }
+        Ok(())
+    }
+
+    /// Captures the current guest state as the one to resume natively from, replacing the snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The general-purpose and XMM registers.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether the guest state was captured.
+    pub fn capture(&mut self, registers: &GuestRegisters) -> Result<(), HypervisorError> {
+        self.snapshot = Some(GuestSnapshot::capture(registers)?);
+        Ok(())
+    }
+
+    /// Records a failed VM entry and decides how to recover from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `failure` - The failed VM entry.
+    /// * `registers` - The general-purpose and XMM registers, restored from the snapshot for a retry.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the recovery, or an error if the processor never exited, e.g. when VMLAUNCH itself
+    /// failed, or if the guest state could not be read from or written to the VMCS.
+    pub fn recover(
+        &mut self,
+        failure: EntryFailure,
+        registers: &mut GuestRegisters,
+    ) -> Result<Recovery, HypervisorError> {
+        self.failures += 1;
+        self.last_failure = Some(failure);
+
+        log::error!(
+            "VM entry failed at RIP {:#x}: {} (qualification {:#x}, attempt {})",
+            failure.rip,
+            failure.reason,
+            failure.qualification,
+            self.retries + 1
+        );
+
+        if !self.exited {
+            return Err(HypervisorError::VmEntryFailed);
+        }
+
+        let exhausted = self.retries >= self.max_retries;
+
+        if failure.reason == VmxBasicExitReason::VmEntryFailureMachineCheckEvent && !exhausted {
+            self.retries += 1;
+            return Ok(Recovery::Retry);
+        }
+
+        // The exit handler wrote no guest state worth going back to, see the module documentation.
+        let Some(snapshot) = self.snapshot else {
+            self.capture(registers)?;
+            return Ok(Recovery::Devirtualize);
+        };
+
+        if exhausted || failure.reason == VmxBasicExitReason::VmEntryFailureMsrLoading {
+            return Ok(Recovery::Devirtualize);
+        }
+
+        self.retries += 1;
+        snapshot.restore(registers)?;
+
+        Ok(Recovery::Retry)
+    }
+
+    /// Returns the guest state to resume natively from, or `None` if none was captured.
+    pub fn snapshot(&self) -> Option<&GuestSnapshot> {
+        self.snapshot.as_ref()
+    }
+
+    /// Returns the total number of failed VM entries.
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    /// Returns the last failed VM entry.
+    pub fn last_failure(&self) -> Option<EntryFailure> {
+        self.last_failure
+    }
+}
@@ -0,0 +1,436 @@
+//! Human-readable dump of the EPT, for debugging mapping problems.
+//!
+//! `Ept::dump` walks the tables for every page of a guest physical address range and prints the PML4, PDPT,
+//! PD and PT entries with their permissions, memory types and the address they reference. Entries shared by
+//! consecutive pages are printed once, and a 2MB page is printed once for all its 4KB pages.
+//!
+//! Every entry is checked for what the processor reports as an EPT misconfiguration, and for references to
+//! tables other than those of the EPT. The problems found are printed below the entry, prefixed with `!`:
+//!
+//! ```text
+//! EPT walk of 0x1000-0x3000
+//! PML4E[  0] 0x000000001234a007 RWX    -> PDPT 0x1234a000
+//! PDPTE[  0] 0x000000001234b007 RWX    -> PD   0x1234b000
+//! PDE  [  0] 0x000000001234c007 RWX    -> PT   0x1234c000
+//! PTE  [  1] 0x0000000000001032 -W- WB -> 0x1000
+//!   ! writable but not readable
+//! PTE  [  2] 0x0000000000002037 RWX WB -> 0x2000
+//! 5 entries, 1 issue
+//! ```
+//!
+//! The EPT misconfiguration handler logs the dump of the faulting page, and `Ept::misconfigured_entry` points out
+//! the entry responsible.
+//!
+//! Dumps of large ranges walk many pages, so `EptDump::cancellable` makes a dump stop at the page where its
+//! `CancellationToken` is cancelled, printing `Cancelled at` with the address and the reason before the summary.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.2 EPT Translation Mechanism
+//! and 29.3.3.1 EPT Misconfigurations.
+
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::ept::paging::{Entry, Ept, EptLevel},
+        utils::{
+            addresses::{Gpa, Hpa},
+            cancellation::CancellationToken,
+        },
+    },
+    core::{
+        fmt::{self, Write},
+        ops::Range,
+    },
+    x86::bits64::paging::{
+        pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE,
+        LARGE_PAGE_SIZE,
+    },
+};
+
+#[cfg(feature = "introspection")]
+use crate::intel::{
+    capabilities::{Capability, Subsystem},
+    hypercall::HypercallCode,
+};
+
+/// The hypercalls of the EPT dump, see `intel::capabilities`.
+#[cfg(feature = "introspection")]
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "ept-dump",
+    enabled: |_| true,
+    commands: &[Capability {
+        code: HypercallCode::EptDump,
+        summary: "Dumps the entries of the primary EPT translating a guest physical address range",
+    }],
+};
+
+/// The number of pages walked between two checks of the cancellation token of a dump.
+pub const CANCELLATION_CHECK_PAGES: usize = 64;
+
+impl EptLevel {
+    /// Returns the name of the entries of the level, padded to the same width.
+    fn entry_name(&self) -> &'static str {
+        match self {
+            Self::Pml4 => "PML4E",
+            Self::Pdpt => "PDPTE",
+            Self::Pd => "PDE  ",
+            Self::Pt => "PTE  ",
+        }
+    }
+
+    /// Returns the name of the table referenced by the entries of the level, padded to the same width.
+    fn table_name(&self) -> &'static str {
+        match self {
+            Self::Pml4 => "PDPT",
+            Self::Pdpt => "PD  ",
+            Self::Pd | Self::Pt => "PT  ",
+        }
+    }
+}
+
+/// A problem found in an EPT entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptIssue {
+    /// The entry allows writes but not reads, which is a misconfiguration.
+    WriteWithoutRead,
+
+    /// The memory type of the leaf entry is reserved, which is a misconfiguration.
+    ReservedMemoryType(u64),
+
+    /// Bits reserved in an entry referencing a table are set, which is a misconfiguration.
+    ReservedBits,
+
+    /// A PDPTE maps a 1GB page, which the EPT of the hypervisor never does.
+    UnexpectedLargePage,
+
+    /// The address of a 2MB page is not 2MB aligned, which is a misconfiguration.
+    UnalignedLargePage,
+
+    /// The entry references another table than the one of the EPT.
+    TableMismatch { expected: Hpa, found: Hpa },
+}
+
+impl fmt::Display for EptIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WriteWithoutRead => write!(f, "writable but not readable"),
+            Self::ReservedMemoryType(memory_type) => {
+                write!(f, "reserved memory type {}", memory_type)
+            }
+            Self::ReservedBits => write!(f, "reserved bits set"),
+            Self::UnexpectedLargePage => write!(f, "unexpected 1GB page"),
+            Self::UnalignedLargePage => write!(f, "2MB page not 2MB aligned"),
+            Self::TableMismatch { expected, found } => {
+                write!(f, "references {:#x} instead of {:#x}", found, expected)
+            }
+        }
+    }
+}
+
+/// Checks an EPT entry for problems.
+///
+/// Entries that are not present are not checked, as the processor ignores their other bits.
+///
+/// # Arguments
+///
+/// * `entry` - The entry to check.
+/// * `level` - The level of the entry.
+/// * `table` - The host physical address of the table the entry should reference, `None` for a leaf.
+///
+/// # Returns
+///
+/// The problems found.
+pub fn check_entry(entry: Entry, level: EptLevel, table: Option<Hpa>) -> [Option<EptIssue>; 3] {
+    if !is_present(entry) {
+        return [None; 3];
+    }
+
+    let permissions = (entry.writable() && !entry.readable()).then_some(EptIssue::WriteWithoutRead);
+
+    match table {
+        None => {
+            let memory_type = matches!(entry.memory_type(), 2 | 3 | 7)
+                .then_some(EptIssue::ReservedMemoryType(entry.memory_type()));
+            let alignment = (level == EptLevel::Pd
+                && entry.pfn() & (LARGE_PAGE_SIZE as u64 / BASE_PAGE_SIZE as u64 - 1) != 0)
+                .then_some(EptIssue::UnalignedLargePage);
+
+            [permissions, memory_type, alignment]
+        }
+        Some(expected) => {
+            let reserved = if entry.memory_type() != 0 || (level == EptLevel::Pml4 && entry.large())
+            {
+                Some(EptIssue::ReservedBits)
+            } else if level == EptLevel::Pdpt && entry.large() {
+                Some(EptIssue::UnexpectedLargePage)
+            } else {
+                None
+            };
+
+            let found = Hpa::new(entry.pfn() << BASE_PAGE_SHIFT);
+            let reference =
+                (found != expected).then_some(EptIssue::TableMismatch { expected, found });
+
+            [permissions, reserved, reference]
+        }
+    }
+}
+
+/// Returns whether an entry allows any access, and thus whether the processor uses it.
+fn is_present(entry: Entry) -> bool {
+    entry.readable() || entry.writable() || entry.executable()
+}
+
+/// Returns the name of a memory type.
+fn memory_type_name(memory_type: u64) -> &'static str {
+    match memory_type {
+        0 => "UC",
+        1 => "WC",
+        4 => "WT",
+        5 => "WP",
+        6 => "WB",
+        _ => "??",
+    }
+}
+
+/// The number of entries printed and problems found by a dump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DumpSummary {
+    /// The number of entries printed.
+    pub entries: usize,
+
+    /// The number of problems found.
+    pub issues: usize,
+
+    /// Whether the destination filled up before the end of the dump.
+    pub truncated: bool,
+
+    /// Whether the dump was cancelled before the end of the range.
+    pub cancelled: bool,
+}
+
+/// A dump of the EPT entries translating a guest physical address range, see `Ept::dump`.
+pub struct EptDump<'a> {
+    ept: &'a Ept,
+    range: Range<Gpa>,
+    cancellation: Option<&'a CancellationToken>,
+}
+
+impl<'a> EptDump<'a> {
+    /// Makes the dump stop once the token is cancelled, checked every `CANCELLATION_CHECK_PAGES` pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The cancellation token of the operation.
+    pub fn cancellable(mut self, token: &'a CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Writes the dump, up to where the destination fills up.
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - The destination of the text.
+    ///
+    /// # Returns
+    ///
+    /// The summary of the part of the dump written.
+    pub fn write_to(&self, out: &mut impl Write) -> DumpSummary {
+        let mut summary = DumpSummary::default();
+
+        if self.write_walk(out, &mut summary).is_err() {
+            summary.truncated = true;
+        }
+
+        summary
+    }
+
+    /// Writes the walk of the range and the summary line.
+    fn write_walk(&self, out: &mut impl Write, summary: &mut DumpSummary) -> fmt::Result {
+        writeln!(
+            out,
+            "EPT walk of {:#x}-{:#x}",
+            self.range.start, self.range.end
+        )?;
+
+        // The index and value of the last entry printed per level, to print shared entries once.
+        let mut printed: [Option<(usize, u64)>; 3] = [None; 3];
+        let mut guest_pa = self.range.start.page_base();
+        let mut pages = 0usize;
+
+        while guest_pa < self.range.end {
+            pages += 1;
+            if pages.is_multiple_of(CANCELLATION_CHECK_PAGES) {
+                if let Some(reason) = self.cancellation.and_then(CancellationToken::reason) {
+                    summary.cancelled = true;
+                    writeln!(out, "Cancelled at {:#x}: {:?}", guest_pa, reason)?;
+                    break;
+                }
+            }
+
+            let walk = match self.ept.walk(guest_pa) {
+                Ok(walk) => walk,
+                Err(error) => {
+                    writeln!(out, "Walk of {:#x} failed: {:?}", guest_pa, error)?;
+                    break;
+                }
+            };
+
+            let address = VAddr::from(guest_pa.as_u64());
+            let upper = [
+                (EptLevel::Pml4, pml4_index(address), walk.pml4e, walk.pdpt),
+                (EptLevel::Pdpt, pdpt_index(address), walk.pdpte, walk.pd),
+            ];
+
+            for ((level, index, entry, table), last) in upper.into_iter().zip(printed.iter_mut()) {
+                if *last != Some((index, entry.raw())) {
+                    *last = Some((index, entry.raw()));
+                    self.write_entry(out, summary, level, index, entry, Some(table))?;
+                }
+            }
+
+            let pd_index = pd_index(address);
+            let step = match walk.pte {
+                None => {
+                    self.write_entry(out, summary, EptLevel::Pd, pd_index, walk.pde, None)?;
+                    LARGE_PAGE_SIZE as u64 - (guest_pa.as_u64() & (LARGE_PAGE_SIZE as u64 - 1))
+                }
+                Some(pte) => {
+                    if let Some(last) = printed.get_mut(2) {
+                        if *last != Some((pd_index, walk.pde.raw())) {
+                            *last = Some((pd_index, walk.pde.raw()));
+                            self.write_entry(
+                                out,
+                                summary,
+                                EptLevel::Pd,
+                                pd_index,
+                                walk.pde,
+                                Some(walk.pt),
+                            )?;
+                        }
+                    }
+                    self.write_entry(out, summary, EptLevel::Pt, pt_index(address), pte, None)?;
+                    BASE_PAGE_SIZE as u64
+                }
+            };
+
+            guest_pa = guest_pa.page_base() + step;
+        }
+
+        writeln!(
+            out,
+            "{} entries, {} issue{}",
+            summary.entries,
+            summary.issues,
+            if summary.issues == 1 { "" } else { "s" }
+        )
+    }
+
+    /// Writes an entry and the problems found in it.
+    fn write_entry(
+        &self,
+        out: &mut impl Write,
+        summary: &mut DumpSummary,
+        level: EptLevel,
+        index: usize,
+        entry: Entry,
+        table: Option<Hpa>,
+    ) -> fmt::Result {
+        summary.entries += 1;
+
+        write!(
+            out,
+            "{}[{:3}] {:#018x} {}{}{}",
+            level.entry_name(),
+            index,
+            entry.raw(),
+            if entry.readable() { 'R' } else { '-' },
+            if entry.writable() { 'W' } else { '-' },
+            if entry.executable() { 'X' } else { '-' },
+        )?;
+
+        let target = entry.pfn() << BASE_PAGE_SHIFT;
+        match table {
+            Some(_) => writeln!(out, "    -> {} {:#x}", level.table_name(), target)?,
+            None => writeln!(
+                out,
+                " {} -> {:#x}",
+                memory_type_name(entry.memory_type()),
+                target
+            )?,
+        }
+
+        for issue in check_entry(entry, level, table).into_iter().flatten() {
+            summary.issues += 1;
+            writeln!(out, "  ! {}", issue)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for EptDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.write_to(f).truncated {
+            true => Err(fmt::Error),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Ept {
+    /// Dumps the entries translating a guest physical address range, see `ept::dump`.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The guest physical address range, walked in 4KB steps.
+    ///
+    /// # Returns
+    ///
+    /// The dump, written with `EptDump::write_to` or formatted with `Display`.
+    pub fn dump(&self, range: Range<Gpa>) -> EptDump<'_> {
+        EptDump {
+            ept: self,
+            range,
+            cancellation: None,
+        }
+    }
+
+    /// Finds the entry responsible for an EPT misconfiguration of a guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address the processor failed to translate.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the level and the entry of the first problem found along the walk, or the leaf entry
+    /// if there is none, as the processor may have used a translation cached before the tables were fixed.
+    pub fn misconfigured_entry(&self, guest_pa: Gpa) -> Result<(EptLevel, Entry), HypervisorError> {
+        let walk = self.walk(guest_pa)?;
+
+        // A large PDE is the leaf of the walk, and references no page table.
+        let entries = [
+            (EptLevel::Pml4, walk.pml4e, Some(walk.pdpt)),
+            (EptLevel::Pdpt, walk.pdpte, Some(walk.pd)),
+            (EptLevel::Pd, walk.pde, walk.pte.map(|_| walk.pt)),
+        ];
+        let pte = walk.pte.map(|pte| (EptLevel::Pt, pte, None));
+
+        let faulty = entries
+            .into_iter()
+            .chain(pte)
+            .find(|&(level, entry, table)| {
+                check_entry(entry, level, table).iter().any(Option::is_some)
+            });
+
+        Ok(faulty.map_or(walk.leaf(), |(level, entry, _)| (level, entry)))
+    }
+}
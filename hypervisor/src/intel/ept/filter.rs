@@ -0,0 +1,359 @@
+//! Conditional filters of function hooks, evaluated in root mode.
+//!
+//! A broad hook, e.g. on a system call taken by every process, transfers execution to its handler far more
+//! often than the handler cares about. A `HookFilter` attached to the hook narrows it down before the handler
+//! runs: when the `#BP` of the hook exits to the hypervisor, the filter is evaluated against the guest state,
+//! and only a matching call is transferred to the handler. Any other call runs the original code through the
+//! trampoline, as if the hook was not there, which saves the handler, and the events it generates, entirely.
+//!
+//! A filter matches if all of its conditions hold:
+//! - The guest CR3 equals the given one, selecting the calling process.
+//! - The return address on top of the guest stack, the caller RIP, lies in the given range, e.g. a module.
+//! - Every register predicate holds. With `Register::argument`, the first four arguments of the Windows x64
+//!   calling convention are compared against a value.
+//!
+//! The conditions are only evaluated if given, and the cheapest ones first: the registers are already saved,
+//! CR3 is a single VMREAD, and the caller RIP requires walking the guest page tables.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.10.1 Process-Context Identifiers
+//! (PCIDs) and 4.5 4-Level Paging and 5-Level Paging.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{guest_memory::GuestMemory, support::try_vmread},
+        utils::{addresses::Gva, capture::GuestRegisters},
+    },
+    core::{fmt, ops::Range},
+    x86::vmx::vmcs::guest,
+};
+
+/// The number of register predicates a filter holds.
+pub const MAX_PREDICATES: usize = 4;
+
+/// The bits of CR3 holding the PCID when CR4.PCIDE is set, or the page table flags otherwise.
+const CR3_PCID_MASK: u64 = 0xFFF;
+
+/// A general-purpose register of the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
+    Rdi,
+    Rsi,
+    Rbp,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Register {
+    /// Returns the register holding an argument of the Windows x64 calling convention.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the argument, starting at 0.
+    ///
+    /// # Returns
+    ///
+    /// The register, or `None` if the argument is passed on the stack.
+    pub fn argument(index: usize) -> Option<Self> {
+        match index {
+            0 => Some(Self::Rcx),
+            1 => Some(Self::Rdx),
+            2 => Some(Self::R8),
+            3 => Some(Self::R9),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the register.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Rax => "rax",
+            Self::Rbx => "rbx",
+            Self::Rcx => "rcx",
+            Self::Rdx => "rdx",
+            Self::Rdi => "rdi",
+            Self::Rsi => "rsi",
+            Self::Rbp => "rbp",
+            Self::R8 => "r8",
+            Self::R9 => "r9",
+            Self::R10 => "r10",
+            Self::R11 => "r11",
+            Self::R12 => "r12",
+            Self::R13 => "r13",
+            Self::R14 => "r14",
+            Self::R15 => "r15",
+        }
+    }
+
+    /// Returns the value of the register.
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The general-purpose registers of the guest.
+    pub fn value(&self, registers: &GuestRegisters) -> u64 {
+        match self {
+            Self::Rax => registers.rax,
+            Self::Rbx => registers.rbx,
+            Self::Rcx => registers.rcx,
+            Self::Rdx => registers.rdx,
+            Self::Rdi => registers.rdi,
+            Self::Rsi => registers.rsi,
+            Self::Rbp => registers.rbp,
+            Self::R8 => registers.r8,
+            Self::R9 => registers.r9,
+            Self::R10 => registers.r10,
+            Self::R11 => registers.r11,
+            Self::R12 => registers.r12,
+            Self::R13 => registers.r13,
+            Self::R14 => registers.r14,
+            Self::R15 => registers.r15,
+        }
+    }
+}
+
+/// How a register is compared against the value of a predicate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    /// The register equals the value.
+    Equal,
+
+    /// The register differs from the value.
+    NotEqual,
+
+    /// The register is below the value, unsigned.
+    Below,
+
+    /// The register is above or equal to the value, unsigned.
+    AboveOrEqual,
+
+    /// All bits of the value are set in the register.
+    AllBitsSet,
+
+    /// No bit of the value is set in the register.
+    NoBitSet,
+}
+
+/// A condition on a general-purpose register of the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Predicate {
+    /// The register compared.
+    pub register: Register,
+
+    /// How the register is compared.
+    pub comparison: Comparison,
+
+    /// The value the register is compared against.
+    pub value: u64,
+}
+
+impl Predicate {
+    /// Evaluates the predicate.
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The general-purpose registers of the guest.
+    pub fn holds(&self, registers: &GuestRegisters) -> bool {
+        let register = self.register.value(registers);
+
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Below => register < self.value,
+            Comparison::AboveOrEqual => register >= self.value,
+            Comparison::AllBitsSet => register & self.value == self.value,
+            Comparison::NoBitSet => register & self.value == 0,
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let register = self.register.name();
+
+        match self.comparison {
+            Comparison::Equal => write!(f, "{} == {:#x}", register, self.value),
+            Comparison::NotEqual => write!(f, "{} != {:#x}", register, self.value),
+            Comparison::Below => write!(f, "{} < {:#x}", register, self.value),
+            Comparison::AboveOrEqual => write!(f, "{} >= {:#x}", register, self.value),
+            Comparison::AllBitsSet => {
+                write!(f, "{} & {:#x} == {:#x}", register, self.value, self.value)
+            }
+            Comparison::NoBitSet => write!(f, "{} & {:#x} == 0", register, self.value),
+        }
+    }
+}
+
+/// The conditions a call must meet to be transferred to the handler of a function hook.
+#[derive(Debug, Clone, Default)]
+pub struct HookFilter {
+    /// The guest CR3 of the calling process.
+    cr3: Option<u64>,
+
+    /// The range the return address of the call lies in.
+    caller: Option<Range<u64>>,
+
+    /// The register predicates, all of which must hold.
+    predicates: [Option<Predicate>; MAX_PREDICATES],
+}
+
+impl HookFilter {
+    /// Creates a filter without conditions, matching every call.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only matches calls from the process with the given page tables.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The CR3 of the process. The PCID bits are ignored.
+    pub fn cr3(mut self, cr3: u64) -> Self {
+        self.cr3 = Some(cr3 & !CR3_PCID_MASK);
+        self
+    }
+
+    /// Only matches calls whose return address lies in the given range.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The guest virtual addresses of the callers, e.g. the image of a module.
+    pub fn caller(mut self, range: Range<u64>) -> Self {
+        self.caller = Some(range);
+        self
+    }
+
+    /// Only matches calls for which a register predicate holds.
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - The predicate.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the filter, or `HypervisorError::TooManyFilterPredicates` if it already holds
+    /// `MAX_PREDICATES` predicates.
+    pub fn predicate(mut self, predicate: Predicate) -> Result<Self, HypervisorError> {
+        let slot = self
+            .predicates
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(HypervisorError::TooManyFilterPredicates)?;
+
+        *slot = Some(predicate);
+
+        Ok(self)
+    }
+
+    /// Only matches calls for which an argument of the Windows x64 calling convention compares to a value.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the argument, at most 3.
+    /// * `comparison` - How the argument is compared.
+    /// * `value` - The value the argument is compared against.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the filter, or an error if the argument is not passed in a register or the filter
+    /// holds `MAX_PREDICATES` predicates already.
+    pub fn argument(
+        self,
+        index: usize,
+        comparison: Comparison,
+        value: u64,
+    ) -> Result<Self, HypervisorError> {
+        let register = Register::argument(index).ok_or(HypervisorError::InvalidFilterArgument)?;
+
+        self.predicate(Predicate {
+            register,
+            comparison,
+            value,
+        })
+    }
+
+    /// Evaluates the filter against the guest state of a hooked call.
+    ///
+    /// A caller whose return address cannot be read, because the guest stack is not present, does not match.
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The general-purpose registers of the guest, with RSP pointing to the return address.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether the call matches, or an error if the guest state could not be read.
+    pub fn matches(&self, registers: &GuestRegisters) -> Result<bool, HypervisorError> {
+        if !self
+            .predicates
+            .iter()
+            .flatten()
+            .all(|predicate| predicate.holds(registers))
+        {
+            return Ok(false);
+        }
+
+        if let Some(cr3) = self.cr3 {
+            if try_vmread(guest::CR3)? & !CR3_PCID_MASK != cr3 {
+                return Ok(false);
+            }
+        }
+
+        if let Some(caller) = &self.caller {
+            let mut return_address = [0u8; 8];
+            if GuestMemory::current()?
+                .read(Gva::new(registers.rsp), &mut return_address)
+                .is_err()
+            {
+                return Ok(false);
+            }
+
+            if !caller.contains(&u64::from_le_bytes(return_address)) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl fmt::Display for HookFilter {
+    /// Formats the conditions joined by `&&`, or `any` for a filter without conditions.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+
+        if let Some(cr3) = self.cr3 {
+            write!(f, "cr3 == {:#x}", cr3)?;
+            separator = " && ";
+        }
+
+        if let Some(caller) = &self.caller {
+            write!(
+                f,
+                "{}caller in {:#x}..{:#x}",
+                separator, caller.start, caller.end
+            )?;
+            separator = " && ";
+        }
+
+        for predicate in self.predicates.iter().flatten() {
+            write!(f, "{}{}", separator, predicate)?;
+            separator = " && ";
+        }
+
+        if separator.is_empty() {
+            write!(f, "any")?;
+        }
+
+        Ok(())
+    }
+}
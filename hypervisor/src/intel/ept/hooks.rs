@@ -3,24 +3,59 @@
 //! of system behavior at a low level. The module is designed for use in scenarios requiring direct
 //! interaction with system internals, such as in kernel and hypervisor development.
 //!
+//! All hooks on the same 4KB page share a single shadow copy of that page, which is reference counted by the
+//! `HookManager`. The secondary EPT maps the page to the shadow copy as long as any hook references it, and
+//! tearing down one hook only restores its own bytes, so overlapping hooks never undo each other.
+//!
+//! The exit handlers do not look up hooks in the `HookManager`, which is only locked by updates. They read a
+//! `HookTable` instead, an immutable snapshot of the function hooks and of the hooked pages published through RCU
+//! whenever the hooks change, so finding a hook takes no lock, and a processor updating the hooks never waits on
+//! another one spinning for the lock in VMX root operation.
+//!
+//! Hooks placed with `Hook::with_write_sync` may share their page with data the guest legitimately writes, e.g.
+//! import thunks or writable data next to code. The read/write view maps such a page read-only, and a write of
+//! the guest is single-stepped with write access before the bytes it changed are merged into the shadow page,
+//! see `HookedPage::sync`. The execute view then runs the code the guest wrote, except for the bytes
+//! replaced by the hooks, whose trampolines keep running the code they were built from.
+//!
 //! Credits to Matthias: https://github.com/not-matthias/amd_hypervisor/blob/main/hypervisor/src/hook.rs
 
 use {
     crate::{
         error::HypervisorError,
-        intel::ept::paging::{AccessType, Ept},
+        intel::{
+            ept::{
+                filter::HookFilter,
+                paging::{AccessType, Ept},
+            },
+        },
         utils::{
-            addresses::PhysicalAddress,
+            addresses::{Gpa, Gva, Hpa, Hva},
             alloc::PhysicalAllocator,
+            capture::GuestRegisters,
             function_hook::FunctionHook,
             nt::{get_ntoskrnl_export, RtlCopyMemory},
+            rcu::Rcu,
         },
     },
-    alloc::{boxed::Box, vec::Vec},
-    x86::current::paging::{PAddr, VAddr, BASE_PAGE_SIZE},
+    alloc::{
+        boxed::Box,
+        string::{String, ToString},
+        vec::Vec,
+    },
+    x86::current::paging::BASE_PAGE_SIZE,
     x86_64::instructions::interrupts::without_interrupts,
 };
 
+/// The collection holding the hooks of the `HookManager`.
+pub type HookList = Vec<Hook>;
+
+/// Identifier of a hook namespace.
+pub type NamespaceId = u32;
+
+/// The namespace hooks are placed in when no namespace is specified.
+pub const DEFAULT_NAMESPACE: NamespaceId = 0;
+
 /// Enum representing different types of hooks that can be applied.
 pub enum HookType {
     /// Hook for intercepting and possibly modifying function execution.
@@ -33,28 +68,39 @@ pub enum HookType {
 /// Represents a hook in the system, either on a function or a page.
 pub struct Hook {
     /// Original virtual address of the target function or page.
-    pub original_va: u64,
+    pub original_va: Hva,
 
     /// Original physical address of the target function or page.
-    pub original_pa: PhysicalAddress,
+    pub original_pa: Gpa,
 
     /// Virtual address where the hook is placed.
-    pub hook_va: u64,
+    pub hook_va: Hva,
 
     /// Physical address of the hook.
-    pub hook_pa: PhysicalAddress,
+    pub hook_pa: Hpa,
 
-    /// Contents of the original page where the hook is placed.
-    pub page: Box<[u8]>,
+    /// A private copy of the original page, taken when the hook is created. It is dropped once the hook is
+    /// added to a `HookManager` and moved onto the shadow page shared by all hooks on the same page.
+    pub page: Option<Box<[u8]>>,
 
     /// Virtual address of the page containing the hook.
-    pub page_va: u64,
+    pub page_va: Hva,
 
     /// Physical address of the page containing the hook.
-    pub page_pa: PhysicalAddress,
+    pub page_pa: Hpa,
 
     /// Type of the hook (Function or Page).
     pub hook_type: HookType,
+
+    /// The namespace owning this hook.
+    pub namespace: NamespaceId,
+
+    /// The conditions a call must meet to be transferred to the handler of a function hook, or `None` to
+    /// transfer every call.
+    pub filter: Option<HookFilter>,
+
+    /// Whether the writes of the guest to the page are propagated to the shadow page.
+    pub write_sync: bool,
 }
 
 impl Hook {
@@ -73,9 +119,9 @@ impl Hook {
     /// * `Option<Box<[u8]>>` - A boxed slice containing the copied page data.
     ///
     /// Reference: https://docs.microsoft.com/en-us/windows-hardware/drivers/kernel/when-should-code-and-data-be-pageable-
-    fn copy_page(address: u64) -> Option<Box<[u8]>> {
-        let page_address = PAddr::from(address).align_down_to_base_page();
-        if page_address.is_zero() {
+    fn copy_page(address: Hva) -> Option<Box<[u8]>> {
+        let page_address = address.page_base();
+        if page_address.as_u64() == 0 {
             log::error!("Invalid page address: {:#x}", address);
             return None;
         }
@@ -86,7 +132,7 @@ impl Hook {
             unsafe {
                 RtlCopyMemory(
                     page.as_mut_ptr() as _,
-                    page_address.as_u64() as *mut u64,
+                    page_address.as_mut_ptr::<u64>() as _,
                     BASE_PAGE_SIZE,
                 )
             };
@@ -104,10 +150,9 @@ impl Hook {
     ///
     /// # Returns
     ///
-    /// * `u64` - The adjusted address of the function within the new page.
-    fn address_in_page(page_start: u64, address: u64) -> u64 {
-        let base_offset = VAddr::from(address).base_page_offset();
-        page_start + base_offset
+    /// * `Hva` - The adjusted address of the function within the new page.
+    fn address_in_page(page_start: Hva, address: Hva) -> Hva {
+        page_start + address.page_offset()
     }
 
     /// Creates a hook on a function by its pointer.
@@ -123,41 +168,44 @@ impl Hook {
     /// # Returns
     ///
     /// * `Option<Self>` - An instance of `Hook` if successful, or `None` if an error occurred.
-    pub fn hook_function_ptr(function_ptr: u64, handler: *const ()) -> Option<Self> {
-        let original_pa = PhysicalAddress::from_va(function_ptr);
+    pub fn hook_function_ptr(function_ptr: Hva, handler: *const ()) -> Option<Self> {
+        let original_pa = Self::physical_address(function_ptr)?.to_gpa();
 
         // Copy the page where the function resides to prevent modifying the original page.
         let page = Self::copy_page(function_ptr)?;
-        let page_va = page.as_ptr() as *mut u64 as u64;
-        let page_pa = PhysicalAddress::from_va(page_va);
+        let page_va = Hva::from_ptr(page.as_ptr());
+        let page_pa = Self::physical_address(page_va)?;
 
         // Calculate the virtual and physical address of the function in the copied page.
         let hook_va = Self::address_in_page(page_va, function_ptr);
-        let hook_pa = PhysicalAddress::from_va(hook_va);
+        let hook_pa = page_pa + hook_va.page_offset();
 
         log::debug!("Handler address: {:#x}", handler as u64);
 
         log::debug!("Original virtual address: {:#x}", function_ptr);
-        log::debug!("Original physical address: {:#x}", original_pa.as_u64());
+        log::debug!("Original physical address: {:#x}", original_pa);
 
         log::debug!("Page virtual address: {:#x}", page_va);
-        log::debug!("Page physical address: {:#x}", page_pa.as_u64());
+        log::debug!("Page physical address: {:#x}", page_pa);
 
         log::debug!("Hook virtual address: {:#x}", hook_va);
-        log::debug!("Hook physical address: {:#x}", hook_pa.as_u64());
+        log::debug!("Hook physical address: {:#x}", hook_pa);
 
         // Create an inline hook at the new address in the copied page.
-        let inline_hook = FunctionHook::new(function_ptr, hook_va, handler)?;
+        let inline_hook = FunctionHook::new(function_ptr.as_u64(), hook_va.as_u64(), handler)?;
 
         Some(Self {
             original_va: function_ptr,
             original_pa,
             hook_va,
             hook_pa,
-            page,
+            page: Some(page),
             page_va,
             page_pa,
             hook_type: HookType::Function { inline_hook },
+            namespace: DEFAULT_NAMESPACE,
+            filter: None,
+            write_sync: false,
         })
     }
 
@@ -187,7 +235,7 @@ impl Hook {
         log::debug!("Function to be hooked: {} {:p}", function_name, address);
 
         // Utilize the previously defined function for hooking by address.
-        Self::hook_function_ptr(address as u64, handler)
+        Self::hook_function_ptr(Hva::from_ptr(address), handler)
     }
 
     /// Creates a hook on a specific page.
@@ -201,13 +249,13 @@ impl Hook {
     /// # Returns
     ///
     /// * `Option<Self>` - An instance of `Hook` if successful, or `None` if an error occurred.
-    pub fn hook_page(address: u64) -> Option<Self> {
-        let original_pa = PhysicalAddress::from_va(address);
+    pub fn hook_page(address: Hva) -> Option<Self> {
+        let original_pa = Self::physical_address(address)?.to_gpa();
 
         // Copy the target page for hooking.
         let page = Self::copy_page(address)?;
-        let page_va = page.as_ptr() as *mut u64 as u64;
-        let page_pa = PhysicalAddress::from_va(page_va);
+        let page_va = Hva::from_ptr(page.as_ptr());
+        let page_pa = Self::physical_address(page_va)?;
 
         // In case of a page hook, the virtual and physical addresses are the same as the copied page.
         Some(Self {
@@ -217,10 +265,363 @@ impl Hook {
             page_pa,
             hook_va: page_va,
             hook_pa: page_pa,
-            page,
+            page: Some(page),
             hook_type: HookType::Page,
+            namespace: DEFAULT_NAMESPACE,
+            filter: None,
+            write_sync: false,
         })
     }
+
+    /// Attaches a filter to a function hook, so that only the calls it matches are transferred to the handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - The conditions a call must meet. Page hooks have no handler and ignore it.
+    pub fn with_filter(mut self, filter: HookFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Propagates the writes of the guest to the page of the hook into the shadow page, for targets in pages
+    /// the guest legitimately writes. Writes to the page exit while the hook is enabled.
+    pub fn with_write_sync(mut self) -> Self {
+        self.write_sync = true;
+        self
+    }
+
+    /// Returns the guest physical address of the 4KB page containing the target.
+    pub fn original_page(&self) -> Gpa {
+        self.original_pa.page_base()
+    }
+
+    /// Returns the host physical address of the 4KB page containing the hook, which the original page is
+    /// remapped to in the secondary EPT.
+    pub fn hooked_copy_page(&self) -> Hpa {
+        self.hook_pa.page_base()
+    }
+
+    /// Returns the host physical address mapped by a host virtual address, logging an error if it is not mapped.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The host virtual address.
+    fn physical_address(address: Hva) -> Option<Hpa> {
+        let pa = address.to_hpa();
+        if pa.is_none() {
+            log::error!("Address is not mapped: {:#x}", address);
+        }
+
+        pa
+    }
+}
+
+/// The part of a function hook the `#BP` exit handler needs.
+#[derive(Debug, Clone)]
+pub struct HookEntry {
+    /// The hooked guest virtual address, where the `int3` is placed.
+    pub address: Gva,
+
+    /// The address of the handler of the hook.
+    pub handler: Gva,
+
+    /// The address of the trampoline running the original code.
+    pub trampoline: Gva,
+
+    /// The conditions a call must meet to be transferred to the handler.
+    pub filter: Option<HookFilter>,
+}
+
+impl HookEntry {
+    /// Returns where a call of the hooked function continues.
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The general-purpose registers of the guest at the hooked instruction.
+    /// * `hooks_suspended` - Whether the hooks are suspended for a debugging session.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the handler, or the trampoline if the hooks are suspended or the filter does not
+    /// match the call, or an error if the guest state could not be read.
+    pub fn target(
+        &self,
+        registers: &GuestRegisters,
+        hooks_suspended: bool,
+    ) -> Result<Gva, HypervisorError> {
+        if hooks_suspended {
+            // Run the original code while the hooks are suspended for a debugging session.
+            log::trace!("Hooks suspended, getting trampoline address");
+            return Ok(self.trampoline);
+        }
+
+        if let Some(filter) = &self.filter {
+            if !filter.matches(registers)? {
+                // Run the original code for calls the filter of the hook is not interested in.
+                log::trace!("Call filtered out, getting trampoline address");
+                return Ok(self.trampoline);
+            }
+        }
+
+        log::trace!("Getting handler address");
+        Ok(self.handler)
+    }
+}
+
+/// The number of words of the mask of the bytes replaced by function hooks in a page.
+const HOOKED_MASK_WORDS: usize = BASE_PAGE_SIZE / u64::BITS as usize;
+
+/// The part of a hooked 4KB page the EPT violation and monitor trap flag exit handlers need.
+#[derive(Debug, Clone)]
+pub struct HookedPage {
+    /// Physical address of the original page.
+    pub original_page: Gpa,
+
+    /// Virtual address of the shadow page.
+    pub shadow_page_va: Hva,
+
+    /// Whether the writes of the guest to the original page are propagated to the shadow page.
+    pub write_sync: bool,
+
+    /// The bytes replaced by the function hooks on the page, one bit per offset into it.
+    hooked: Box<[u64; HOOKED_MASK_WORDS]>,
+}
+
+impl HookedPage {
+    /// Builds the entry of a shadow page.
+    ///
+    /// # Arguments
+    ///
+    /// * `shadow` - The shadow page.
+    /// * `hooks` - The hooks, of which those on other pages are skipped.
+    fn new(shadow: &ShadowPage, hooks: &[&Hook]) -> Self {
+        let mut hooked = Box::new([0u64; HOOKED_MASK_WORDS]);
+
+        for hook in hooks
+            .iter()
+            .filter(|hook| hook.original_page() == shadow.original_page_pa)
+        {
+            let HookType::Function { inline_hook } = &hook.hook_type else {
+                continue;
+            };
+
+            let start = inline_hook
+                .hook_address()
+                .saturating_sub(shadow.page_va.as_u64()) as usize;
+            let end = (start + inline_hook.shellcode_len()).min(BASE_PAGE_SIZE);
+            for offset in start..end {
+                if let Some(word) = hooked.get_mut(offset / u64::BITS as usize) {
+                    *word |= 1 << (offset % u64::BITS as usize);
+                }
+            }
+        }
+
+        Self {
+            original_page: shadow.original_page_pa,
+            shadow_page_va: shadow.page_va,
+            write_sync: shadow.write_sync,
+            hooked,
+        }
+    }
+
+    /// Returns the permissions of the original page in the read/write view while the hooks are enabled.
+    pub fn read_write_view_access(&self) -> AccessType {
+        match self.write_sync {
+            true => AccessType::READ,
+            false => AccessType::READ_WRITE,
+        }
+    }
+
+    /// Returns whether a byte of the page is replaced by a function hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset of the byte into the page.
+    fn is_hooked(&self, offset: usize) -> bool {
+        self.hooked
+            .get(offset / u64::BITS as usize)
+            .is_some_and(|word| word & (1 << (offset % u64::BITS as usize)) != 0)
+    }
+
+    /// Copies the bytes the guest changed in the original page into its shadow page, except for the bytes
+    /// replaced by the hooks.
+    ///
+    /// Called in VMX root operation after a write of the guest was single-stepped, so the shadow page is written
+    /// through its address like the hooks write their shellcode. Only the bytes that differ are written, the
+    /// bytes of the hooks never change while the execute view runs the page.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes propagated, zero if the page is not hooked with `Hook::with_write_sync` or is not
+    /// mapped in the host.
+    pub fn sync(&self) -> usize {
+        if !self.write_sync {
+            return 0;
+        }
+
+        let Some(va) = self.original_page.to_hva() else {
+            log::error!("Hooked page {:#x} is not mapped", self.original_page);
+            return 0;
+        };
+
+        let original = va.as_ptr::<u8>();
+        let copy = self.shadow_page_va.as_mut_ptr::<u8>();
+
+        let mut propagated = 0;
+        for offset in 0..BASE_PAGE_SIZE {
+            let (value, current) = unsafe {
+                (
+                    original.add(offset).read_volatile(),
+                    copy.add(offset).read_volatile(),
+                )
+            };
+
+            if value != current && !self.is_hooked(offset) {
+                unsafe { copy.add(offset).write_volatile(value) };
+                propagated += 1;
+            }
+        }
+
+        if propagated > 0 {
+            log::trace!(
+                "Propagated {} bytes written to {:#x} into its shadow page",
+                propagated,
+                self.original_page
+            );
+        }
+
+        propagated
+    }
+}
+
+/// A snapshot of the function hooks, sorted by address, and of the hooked pages, sorted by guest physical
+/// address, read by the exit handlers without locks.
+#[derive(Debug, Clone, Default)]
+pub struct HookTable {
+    entries: Vec<HookEntry>,
+    pages: Vec<HookedPage>,
+}
+
+impl HookTable {
+    /// Builds the table of the given hooks and shadow pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `hooks` - The hooks, of which the page hooks only contribute their page.
+    /// * `shadow_pages` - The shadow pages still referenced by the hooks.
+    pub fn from_hooks<'a>(
+        hooks: impl Iterator<Item = &'a Hook>,
+        shadow_pages: impl Iterator<Item = &'a ShadowPage>,
+    ) -> Self {
+        let hooks: Vec<&Hook> = hooks.collect();
+
+        let mut entries: Vec<HookEntry> = hooks
+            .iter()
+            .filter_map(|hook| match &hook.hook_type {
+                HookType::Function { inline_hook } => Some(HookEntry {
+                    address: hook.original_va.to_gva(),
+                    handler: Gva::new(inline_hook.handler_address()),
+                    trampoline: Gva::new(inline_hook.trampoline_address() as u64),
+                    filter: hook.filter.clone(),
+                }),
+                HookType::Page => None,
+            })
+            .collect();
+
+        entries.sort_unstable_by_key(|entry| entry.address);
+
+        let mut pages: Vec<HookedPage> = shadow_pages
+            .map(|shadow| HookedPage::new(shadow, &hooks))
+            .collect();
+
+        pages.sort_unstable_by_key(|page| page.original_page);
+
+        Self { entries, pages }
+    }
+
+    /// Finds the function hook placed at an address.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The guest virtual address of the `int3`.
+    pub fn lookup(&self, address: Gva) -> Option<&HookEntry> {
+        self.entries
+            .binary_search_by_key(&address, |entry| entry.address)
+            .ok()
+            .and_then(|index| self.entries.get(index))
+    }
+
+    /// Finds a hooked page.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_page` - The guest physical address of the original page.
+    pub fn hooked_page(&self, original_page: Gpa) -> Option<&HookedPage> {
+        self.pages
+            .binary_search_by_key(&original_page, |page| page.original_page)
+            .ok()
+            .and_then(|index| self.pages.get(index))
+    }
+
+    /// Returns the number of function hooks in the table.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the table holds no function hook.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// A copy of a hooked 4KB page, shared by all hooks placed on that page.
+pub struct ShadowPage {
+    /// Physical address of the original page.
+    pub original_page_pa: Gpa,
+
+    /// Virtual address of the original page.
+    pub original_page_va: Hva,
+
+    /// Contents of the shadow page, including the shellcode of all hooks on it.
+    pub page: Box<[u8]>,
+
+    /// Virtual address of the shadow page.
+    pub page_va: Hva,
+
+    /// Physical address of the shadow page.
+    pub page_pa: Hpa,
+
+    /// The number of hooks placed on this page.
+    pub refcount: usize,
+
+    /// Whether the writes of the guest to the original page are propagated to this page, set if any hook on
+    /// the page asked for it.
+    pub write_sync: bool,
+}
+
+impl ShadowPage {
+    /// Returns the permissions of the original page in the read/write view while the hooks are enabled.
+    pub fn read_write_view_access(&self) -> AccessType {
+        match self.write_sync {
+            true => AccessType::READ,
+            false => AccessType::READ_WRITE,
+        }
+    }
+}
+
+/// A namespace isolating the hooks registered by a single client (driver or agent).
+///
+/// Each namespace has its own quota, and all of its hooks can be torn down at once
+/// without disturbing the hooks owned by other namespaces.
+pub struct HookNamespace {
+    /// The identifier of the namespace.
+    pub id: NamespaceId,
+
+    /// A human readable name of the client owning the namespace.
+    pub name: String,
+
+    /// The maximum number of hooks the namespace is allowed to register.
+    pub quota: usize,
 }
 
 /// Manages the lifecycle and control of various hooks.
@@ -230,7 +631,16 @@ impl Hook {
 /// modifying the Extended Page Tables (EPT) to facilitate the hooking mechanism.
 pub struct HookManager {
     /// A collection of hooks managed by the HookManager.
-    pub hooks: Vec<Hook>,
+    pub hooks: HookList,
+
+    /// The shadow pages shared by the hooks, one per hooked 4KB page.
+    pub shadow_pages: Vec<ShadowPage>,
+
+    /// The registered hook namespaces.
+    pub namespaces: Vec<HookNamespace>,
+
+    /// The identifier handed out to the next registered namespace.
+    next_namespace: NamespaceId,
 }
 
 impl HookManager {
@@ -238,13 +648,328 @@ impl HookManager {
     ///
     /// # Arguments
     ///
-    /// * `hooks` - A vector of `Hook` instances to be managed.
-    pub fn new(hooks: Vec<Hook>) -> Box<Self> {
-        let hooks = Self { hooks };
+    /// * `hooks` - The `Hook` instances to be managed, placed in the default namespace.
+    pub fn new(hooks: HookList) -> Box<Self> {
+        let default_namespace = HookNamespace {
+            id: DEFAULT_NAMESPACE,
+            name: "default".to_string(),
+            quota: usize::MAX,
+        };
+
+        let mut hooks = Self {
+            hooks,
+            shadow_pages: Vec::new(),
+            namespaces: alloc::vec![default_namespace],
+            next_namespace: DEFAULT_NAMESPACE + 1,
+        };
+
+        for hook in hooks.hooks.iter_mut() {
+            Self::attach_shadow_page(&mut hooks.shadow_pages, hook);
+        }
+
         let instance = Box::new(hooks);
         instance
     }
 
+    /// Moves a hook onto the shadow page of its original page, creating the shadow page from the hook's
+    /// private copy if it is the first hook on that page.
+    ///
+    /// # Arguments
+    ///
+    /// * `shadow_pages` - The shadow pages of the manager.
+    /// * `hook` - The hook to attach, which must not be enabled yet.
+    fn attach_shadow_page(shadow_pages: &mut Vec<ShadowPage>, hook: &mut Hook) {
+        let Some(page) = hook.page.take() else {
+            log::warn!(
+                "Hook {:#x} is already attached to a shadow page",
+                hook.original_va
+            );
+            return;
+        };
+
+        let original_page_pa = hook.original_page();
+        let offset = hook.hook_va - hook.page_va;
+
+        let shadow = match shadow_pages
+            .iter_mut()
+            .position(|shadow| shadow.original_page_pa == original_page_pa)
+        {
+            Some(index) => {
+                // The private copy is dropped; the hook shares the existing shadow page instead.
+                log::debug!("Sharing shadow page of {:#x}", original_page_pa);
+                &mut shadow_pages[index]
+            }
+            None => {
+                let page_va = Hva::from_ptr(page.as_ptr());
+                shadow_pages.push(ShadowPage {
+                    original_page_pa,
+                    original_page_va: hook.original_va.page_base(),
+                    page,
+                    page_va,
+                    page_pa: hook.page_pa,
+                    refcount: 0,
+                    write_sync: false,
+                });
+                let index = shadow_pages.len() - 1;
+                &mut shadow_pages[index]
+            }
+        };
+
+        shadow.refcount += 1;
+        shadow.write_sync |= hook.write_sync;
+
+        hook.page_va = shadow.page_va;
+        hook.page_pa = shadow.page_pa;
+        hook.hook_va = shadow.page_va + offset;
+        hook.hook_pa = shadow.page_pa + offset;
+
+        if let HookType::Function { inline_hook } = &mut hook.hook_type {
+            inline_hook.relocate(hook.hook_va.as_u64());
+        }
+    }
+
+    /// Drops a hook's reference to its shadow page.
+    ///
+    /// If other hooks still reference the page, only the bytes overwritten by this hook are restored in the
+    /// shadow page. Otherwise, the EPT entries are restored to the original page with full permissions; the
+    /// shadow page itself is freed by the caller once the EPT caches are invalidated.
+    ///
+    /// # Arguments
+    ///
+    /// * `shadow_pages` - The shadow pages of the manager.
+    /// * `hook` - The hook being torn down.
+    /// * `primary_ept` - A mutable reference to the primary EPT.
+    /// * `secondary_ept` - A mutable reference to the secondary EPT.
+    fn release_shadow_page(
+        shadow_pages: &mut [ShadowPage],
+        hook: &Hook,
+        primary_ept: &mut Box<Ept, PhysicalAllocator>,
+        secondary_ept: &mut Box<Ept, PhysicalAllocator>,
+    ) -> Result<(), HypervisorError> {
+        let original_page = hook.original_page();
+
+        let Some(shadow) = shadow_pages
+            .iter_mut()
+            .find(|shadow| shadow.original_page_pa == original_page)
+        else {
+            return Ok(());
+        };
+
+        shadow.refcount = shadow.refcount.saturating_sub(1);
+
+        if shadow.refcount > 0 {
+            if let HookType::Function { inline_hook } = &hook.hook_type {
+                log::debug!(
+                    "Restoring original bytes of hook {:#x} in shared shadow page",
+                    hook.original_va
+                );
+
+                unsafe {
+                    RtlCopyMemory(
+                        inline_hook.hook_address() as *mut u64 as _,
+                        hook.original_va.as_mut_ptr::<u64>() as _,
+                        inline_hook.shellcode_len(),
+                    )
+                };
+            }
+
+            return Ok(());
+        }
+
+        log::debug!("Restoring original page in EPTs: {:#x}", original_page);
+
+        primary_ept.change_page_flags(original_page, AccessType::READ_WRITE_EXECUTE)?;
+        secondary_ept.remap_page(
+            original_page,
+            original_page.to_hpa(),
+            AccessType::READ_WRITE_EXECUTE,
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the shadow page of an original page, if it is hooked.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_page` - The guest physical address of the original page.
+    pub fn shadow_page(&self, original_page: Gpa) -> Option<&ShadowPage> {
+        self.shadow_pages
+            .iter()
+            .find(|shadow| shadow.original_page_pa == original_page)
+    }
+
+    /// Registers a new hook namespace for a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - A human readable name of the client owning the namespace.
+    /// * `quota` - The maximum number of hooks the namespace is allowed to register.
+    ///
+    /// # Returns
+    ///
+    /// * `NamespaceId` - The identifier of the newly registered namespace.
+    pub fn register_namespace(&mut self, name: &str, quota: usize) -> NamespaceId {
+        let id = self.next_namespace;
+        self.next_namespace += 1;
+
+        log::debug!(
+            "Registering hook namespace {} ({}) with quota {}",
+            id,
+            name,
+            quota
+        );
+
+        self.namespaces.push(HookNamespace {
+            id,
+            name: name.to_string(),
+            quota,
+        });
+
+        id
+    }
+
+    /// Adds a hook to the given namespace, enforcing the namespace's quota.
+    ///
+    /// The hook still needs to be enabled with `enable_hooks` before it takes effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace the hook is registered in.
+    /// * `hook` - The hook to add.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - `Ok` if the hook was added, or an error if the namespace is unknown or its quota is exhausted.
+    pub fn add_hook(
+        &mut self,
+        namespace: NamespaceId,
+        mut hook: Hook,
+    ) -> Result<(), HypervisorError> {
+        let quota = self
+            .namespaces
+            .iter()
+            .find(|ns| ns.id == namespace)
+            .ok_or(HypervisorError::UnknownHookNamespace)?
+            .quota;
+
+        if self.hooks_in_namespace(namespace).count() >= quota {
+            log::error!(
+                "Hook namespace {} exhausted its quota of {}",
+                namespace,
+                quota
+            );
+            return Err(HypervisorError::HookQuotaExceeded);
+        }
+
+        hook.namespace = namespace;
+
+        self.hooks.push(hook);
+
+        if let Some(hook) = self.hooks.last_mut() {
+            Self::attach_shadow_page(&mut self.shadow_pages, hook);
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over the hooks owned by the given namespace.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace to look up.
+    pub fn hooks_in_namespace(&self, namespace: NamespaceId) -> impl Iterator<Item = &Hook> {
+        self.hooks
+            .iter()
+            .filter(move |hook| hook.namespace == namespace)
+    }
+
+    /// Returns a table of the hooks, to be published to the exit handlers.
+    pub fn table(&self) -> HookTable {
+        HookTable::from_hooks(self.hooks.iter(), self.shadow_pages.iter())
+    }
+
+    /// Restores the EPT entries of the hooks of a namespace, the first step of evicting it.
+    ///
+    /// The EPT entries are restored to the original pages with full permissions, unless another hook still
+    /// references the same shadow page, in which case only the bytes of the evicted hooks are restored in it. The
+    /// caller then flushes the EPT derived translations of all processors, with `host_call` from the guest, before
+    /// finishing with `evict_namespace`, so no processor still runs the shadow pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace to evict.
+    /// * `primary_ept` - A mutable reference to the primary EPT.
+    /// * `secondary_ept` - A mutable reference to the secondary EPT.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - `Ok` if the EPTs were restored, or an error if the namespace is unknown or the EPTs could not be restored.
+    pub fn release_namespace(
+        &mut self,
+        namespace: NamespaceId,
+        primary_ept: &mut Box<Ept, PhysicalAllocator>,
+        secondary_ept: &mut Box<Ept, PhysicalAllocator>,
+    ) -> Result<(), HypervisorError> {
+        let namespace_info = self
+            .namespaces
+            .iter()
+            .find(|ns| ns.id == namespace)
+            .ok_or(HypervisorError::UnknownHookNamespace)?;
+
+        log::debug!(
+            "Evicting hook namespace {} ({})",
+            namespace,
+            namespace_info.name
+        );
+
+        for hook in self.hooks.iter().filter(|hook| hook.namespace == namespace) {
+            Self::release_shadow_page(&mut self.shadow_pages, hook, primary_ept, secondary_ept)?;
+        }
+
+        Ok(())
+    }
+
+    /// Evicts a namespace released with `release_namespace`, once the EPT derived translations of all processors
+    /// were flushed.
+    ///
+    /// A hook table without the evicted hooks is published before they are freed, so no exit handler still
+    /// transfers execution to their trampolines.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace to evict.
+    /// * `hook_table` - The hook table read by the exit handlers.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - `Ok` if the namespace was evicted, or an error if it is unknown.
+    pub fn evict_namespace(
+        &mut self,
+        namespace: NamespaceId,
+        hook_table: &Rcu<HookTable>,
+    ) -> Result<(), HypervisorError> {
+        let position = self
+            .namespaces
+            .iter()
+            .position(|ns| ns.id == namespace)
+            .ok_or(HypervisorError::UnknownHookNamespace)?;
+
+        let remaining = self.hooks.iter().filter(|hook| hook.namespace != namespace);
+        let referenced = self.shadow_pages.iter().filter(|shadow| shadow.refcount > 0);
+        let table = HookTable::from_hooks(remaining, referenced);
+        hook_table.update(|_| Ok::<_, HypervisorError>((table, ())))?;
+
+        self.hooks.retain(|hook| hook.namespace != namespace);
+        self.shadow_pages.retain(|shadow| shadow.refcount > 0);
+
+        if namespace != DEFAULT_NAMESPACE {
+            self.namespaces.remove(position);
+        }
+
+        Ok(())
+    }
+
     /// Enables all the hooks managed by the `HookManager`.
     ///
     /// It sets the necessary permissions on the primary and secondary Extended Page Tables (EPTs)
@@ -279,32 +1004,35 @@ impl HookManager {
                 inline_hook.enable();
             }
 
-            let original_page = hook.original_pa.align_down_to_large_page().as_u64();
-            let hooked_copy_page = hook.hook_pa.align_down_to_large_page().as_u64();
+            let original_page = hook.original_pa.large_page_base();
+            let hooked_copy_page = hook.hook_pa.large_page_base();
 
             log::debug!(
                 "Splitting 2MB page to 4KB pages for Primary EPT: {:#x}",
                 original_page
             );
-            primary_ept.split_2mb_to_4kb(original_page, AccessType::READ_WRITE_EXECUTE)?;
+            Self::split_if_large(primary_ept, original_page)?;
 
             log::debug!(
                 "Splitting 2MB page to 4KB pages for Secondary EPT: {:#x}",
                 hooked_copy_page
             );
-            secondary_ept.split_2mb_to_4kb(original_page, AccessType::READ_WRITE_EXECUTE)?;
+            Self::split_if_large(secondary_ept, original_page)?;
 
             // Align addresses to their base page sizes for accurate permission modification.
-            let original_page = hook.original_pa.align_down_to_base_page().as_u64();
-            let hooked_copy_page = hook.hook_pa.align_down_to_base_page().as_u64();
+            let original_page = hook.original_page();
+            let hooked_copy_page = hook.hooked_copy_page();
 
             log::debug!(
                 "Changing permissions for page to Read-Write (RW) only: {:#x}",
                 original_page
             );
 
-            // Modify the page permission in the primary EPT to ReadWrite.
-            primary_ept.change_page_flags(original_page, AccessType::READ_WRITE)?;
+            // Modify the page permission in the primary EPT to ReadWrite, or Read if the writes are propagated.
+            let access = self
+                .shadow_page(original_page)
+                .map_or(AccessType::READ_WRITE, ShadowPage::read_write_view_access);
+            primary_ept.change_page_flags(original_page, access)?;
 
             log::debug!(
                 "Changing permissions for hook page to Execute (X) only: {:#x}",
@@ -322,6 +1050,22 @@ impl HookManager {
         Ok(())
     }
 
+    /// Splits a 2MB page into 4KB pages, unless another hook in the same 2MB region already did.
+    ///
+    /// # Arguments
+    ///
+    /// * `ept` - The EPT to split the page in.
+    /// * `guest_pa` - The guest physical address of the 2MB page.
+    fn split_if_large(
+        ept: &mut Box<Ept, PhysicalAllocator>,
+        guest_pa: Gpa,
+    ) -> Result<(), HypervisorError> {
+        match ept.split_2mb_to_4kb(guest_pa, AccessType::READ_WRITE_EXECUTE) {
+            Ok(()) | Err(HypervisorError::PageAlreadySplit) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
     /// Tries to find a hook for the specified hook virtual address.
     ///
     /// # Arguments
@@ -331,7 +1075,7 @@ impl HookManager {
     /// # Returns
     ///
     /// * `Option<&Hook>` - A reference to the hook if found, or `None` if not found.
-    pub fn find_hook_by_address(&self, address: u64) -> Option<&Hook> {
+    pub fn find_hook_by_address(&self, address: Hva) -> Option<&Hook> {
         for hook in self.hooks.iter() {
             if hook.original_va == address {
                 return Some(hook);
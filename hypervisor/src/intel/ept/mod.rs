@@ -1,3 +1,8 @@
+#[cfg(feature = "introspection")]
+pub mod dump;
+pub mod filter;
 pub mod hooks;
 pub mod mtrr;
 pub mod paging;
+pub mod policy;
+pub mod thrashing;
@@ -5,11 +5,50 @@
 //! Credits to Neri https://github.com/neri/maystorm/blob/develop/system/src/arch/x64/cpu.rs
 
 use {
-    crate::utils::{addresses::PhysicalAddress, instructions::rdmsr},
+    crate::{
+        error::HypervisorError,
+        utils::{addresses::PhysicalAddress, instructions::rdmsr},
+    },
     alloc::vec::Vec,
-    x86::msr::{IA32_MTRRCAP, IA32_MTRR_PHYSBASE0, IA32_MTRR_PHYSMASK0},
+    x86::msr::{
+        IA32_MTRRCAP, IA32_MTRR_DEF_TYPE, IA32_MTRR_FIX16K_80000, IA32_MTRR_FIX16K_A0000,
+        IA32_MTRR_FIX4K_C0000, IA32_MTRR_FIX4K_C8000, IA32_MTRR_FIX4K_D0000, IA32_MTRR_FIX4K_D8000,
+        IA32_MTRR_FIX4K_E0000, IA32_MTRR_FIX4K_E8000, IA32_MTRR_FIX4K_F0000, IA32_MTRR_FIX4K_F8000,
+        IA32_MTRR_FIX64K_00000, IA32_MTRR_PHYSBASE0, IA32_MTRR_PHYSMASK0,
+    },
 };
 
+/// The default memory type in IA32_MTRR_DEF_TYPE.
+const DEF_TYPE_MASK: u8 = 0xFF;
+
+/// The fixed-range MTRRs enable flag (FE) in IA32_MTRR_DEF_TYPE.
+const DEF_TYPE_FIXED_ENABLE: u64 = 1 << 10;
+
+/// The MTRR enable flag (E) in IA32_MTRR_DEF_TYPE.
+const DEF_TYPE_ENABLE: u64 = 1 << 11;
+
+/// The fixed-range MTRRs support flag (FIX) in IA32_MTRRCAP.
+const MTRRCAP_FIXED: u64 = 1 << 8;
+
+/// The end of the first megabyte, covered by the fixed-range MTRRs.
+const FIXED_RANGES_END: u64 = 0x10_0000;
+
+/// The fixed-range MTRRs, with the base address and the size of the eight ranges each of them holds the memory
+/// types of.
+const FIXED_RANGE_MTRRS: [(u32, u64, u64); 11] = [
+    (IA32_MTRR_FIX64K_00000, 0x0_0000, 0x1_0000),
+    (IA32_MTRR_FIX16K_80000, 0x8_0000, 0x4000),
+    (IA32_MTRR_FIX16K_A0000, 0xA_0000, 0x4000),
+    (IA32_MTRR_FIX4K_C0000, 0xC_0000, 0x1000),
+    (IA32_MTRR_FIX4K_C8000, 0xC_8000, 0x1000),
+    (IA32_MTRR_FIX4K_D0000, 0xD_0000, 0x1000),
+    (IA32_MTRR_FIX4K_D8000, 0xD_8000, 0x1000),
+    (IA32_MTRR_FIX4K_E0000, 0xE_0000, 0x1000),
+    (IA32_MTRR_FIX4K_E8000, 0xE_8000, 0x1000),
+    (IA32_MTRR_FIX4K_F0000, 0xF_0000, 0x1000),
+    (IA32_MTRR_FIX4K_F8000, 0xF_8000, 0x1000),
+];
+
 /// Represents the different types of memory as defined by MTRRs.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemoryType {
@@ -105,6 +144,155 @@ impl Mtrr {
         memory_type.or(Some(MemoryType::WriteBack))
     }
 
+    /// Validates that a memory type can be applied to a physical address range without contradicting the MTRRs.
+    ///
+    /// The EPT memory type takes the place of the MTRR type when the effective memory type is determined,
+    /// so overriding it can make memory more cacheable than the platform allows. A type is accepted if it is
+    /// uncacheable, if it matches the MTRR type of every part of the range, if these parts are write-back, or if
+    /// it is write-combining over uncacheable memory (e.g. a framebuffer). Caching memory the MTRRs mark as
+    /// uncacheable, which is typically MMIO, is rejected.
+    ///
+    /// The MTRR type of a part of the range is looked up like the processor does: all memory is uncacheable
+    /// while the MTRRs are disabled, the fixed-range MTRRs take precedence in the first megabyte if they are
+    /// enabled, and memory not covered by a variable-range MTRR has the default type of IA32_MTRR_DEF_TYPE.
+    /// Unlike `find`, the MTRRs are read again, including the write-back ranges.
+    ///
+    /// # Arguments
+    /// * `range` - The physical address range the memory type is applied to.
+    /// * `memory_type` - The requested memory type.
+    ///
+    /// # Returns
+    /// `Ok(())` if the memory type is consistent with the MTRRs, or `HypervisorError::MemoryTypeConflict` otherwise.
+    pub fn validate(
+        range: core::ops::Range<u64>,
+        memory_type: MemoryType,
+    ) -> Result<(), HypervisorError> {
+        let def_type = rdmsr(IA32_MTRR_DEF_TYPE);
+
+        if def_type & DEF_TYPE_ENABLE == 0 {
+            return Self::check_type(memory_type, range, MemoryType::Uncacheable);
+        }
+
+        let mut start = range.start;
+
+        if def_type & DEF_TYPE_FIXED_ENABLE != 0 && rdmsr(IA32_MTRRCAP) & MTRRCAP_FIXED != 0 {
+            for (fixed, fixed_type) in Self::fixed_ranges() {
+                if range.start < fixed.end && range.end > fixed.start {
+                    Self::check_type(memory_type, fixed, fixed_type)?;
+                }
+            }
+
+            start = start.max(FIXED_RANGES_END);
+        }
+
+        if start >= range.end {
+            return Ok(());
+        }
+        let range = start..range.end;
+
+        let variable: Vec<MtrrRangeDescriptor> = Self::indexes()
+            .map(Self::get)
+            .filter(|item| item.is_enabled)
+            .map(|item| MtrrRangeDescriptor {
+                base_address: item.base.pa(),
+                end_address: Self::calculate_end_address(item.base.pa(), item.mask),
+                memory_type: item.mem_type,
+            })
+            .filter(|d| range.start <= d.end_address && range.end > d.base_address)
+            .collect();
+
+        for descriptor in &variable {
+            Self::check_type(
+                memory_type,
+                descriptor.base_address..descriptor.end_address + 1,
+                descriptor.memory_type,
+            )?;
+        }
+
+        if !Self::covers(&variable, &range) {
+            Self::check_type(
+                memory_type,
+                range,
+                Self::from_raw(def_type as u8 & DEF_TYPE_MASK),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a requested memory type is compatible with the MTRR type of a range, see `validate`.
+    ///
+    /// # Arguments
+    /// * `requested` - The requested memory type.
+    /// * `range` - The physical address range of the MTRR type.
+    /// * `mtrr` - The MTRR type of the range.
+    ///
+    /// # Returns
+    /// `Ok(())` if the types are compatible, or `HypervisorError::MemoryTypeConflict` otherwise.
+    fn check_type(
+        requested: MemoryType,
+        range: core::ops::Range<u64>,
+        mtrr: MemoryType,
+    ) -> Result<(), HypervisorError> {
+        let compatible = match (requested, mtrr) {
+            (MemoryType::Uncacheable, _) => true,
+            (_, MemoryType::WriteBack) => true,
+            (MemoryType::WriteCombining, MemoryType::Uncacheable) => true,
+            (requested, mtrr) => requested == mtrr,
+        };
+
+        if !compatible {
+            log::error!(
+                "Memory type {:?} conflicts with MTRR range 0x{:x}-0x{:x} ({:?})",
+                requested,
+                range.start,
+                range.end,
+                mtrr
+            );
+            return Err(HypervisorError::MemoryTypeConflict);
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether a physical address range is entirely covered by variable-range MTRRs.
+    ///
+    /// # Arguments
+    /// * `variable` - The enabled variable-range MTRRs overlapping the range.
+    /// * `range` - The physical address range.
+    fn covers(variable: &[MtrrRangeDescriptor], range: &core::ops::Range<u64>) -> bool {
+        let mut covered = range.start;
+
+        while covered < range.end {
+            match variable
+                .iter()
+                .find(|d| d.base_address <= covered && covered <= d.end_address)
+            {
+                Some(descriptor) => covered = descriptor.end_address + 1,
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Reads the fixed-range MTRRs, which split the first megabyte into ranges of 64KB, 16KB and 4KB.
+    ///
+    /// # Returns
+    /// An iterator over the physical address ranges and their memory types.
+    ///
+    /// # Reference
+    /// Intel® 64 and IA-32 Architectures Software Developer's Manual: 12.11.2.2 Fixed Range MTRRs
+    fn fixed_ranges() -> impl Iterator<Item = (core::ops::Range<u64>, MemoryType)> {
+        FIXED_RANGE_MTRRS.into_iter().flat_map(|(msr, base, size)| {
+            let types = rdmsr(msr).to_le_bytes();
+            (0..8u64).zip(types).map(move |(index, memory_type)| {
+                let start = base + index * size;
+                (start..start + size, Self::from_raw(memory_type))
+            })
+        })
+    }
+
     /// Calculates the end address of an MTRR memory range.
     ///
     /// # Arguments
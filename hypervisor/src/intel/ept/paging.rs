@@ -5,15 +5,29 @@
 //!
 //! Credits to the work by Satoshi (https://github.com/tandasat/Hello-VT-rp/blob/main/hypervisor/src/intel_vt/epts.rs) and Matthias (https://github.com/not-matthias/amd_hypervisor/blob/main/hypervisor/src/svm/nested_page_table.rs).
 
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
 use {
     crate::{
         error::HypervisorError,
         intel::ept::mtrr::{MemoryType, Mtrr},
-        utils::addresses::PhysicalAddress,
+        utils::{
+            addresses::{Gpa, Hpa, PhysicalAddress},
+            processor::any_virtualized,
+        },
     },
     bitfield::bitfield,
     bitflags::bitflags,
-    core::ptr::addr_of,
+    core::{
+        ops::Range,
+        ptr::{addr_of, addr_of_mut},
+        sync::atomic::{AtomicU64, Ordering},
+    },
     x86::bits64::paging::{
         pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE,
         LARGE_PAGE_SIZE, PAGE_SIZE_ENTRIES,
@@ -22,7 +36,7 @@ use {
 
 bitflags! {
     /// Represents the different access permissions for an EPT entry.
-    #[derive(Debug, Clone, Copy)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct AccessType: u8 {
         /// The EPT entry allows read access.
         const READ = 0b001;
@@ -41,6 +55,22 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The accessed and dirty flags the processor set on an EPT entry.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.5 Accessed and Dirty Flags for EPT
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct PageAccess: u8 {
+        /// The page was accessed since the flags were last cleared.
+        const ACCESSED = 0b01;
+        /// The page was written since the flags were last cleared.
+        const DIRTY = 0b10;
+    }
+}
+
+/// EPTP bit enabling the accessed and dirty flags in the EPT entries.
+pub const EPTP_ACCESSED_DIRTY_ENABLE: u64 = 1 << 6;
+
 pub const _512GB: u64 = 512 * 1024 * 1024 * 1024;
 pub const _1GB: u64 = 1024 * 1024 * 1024;
 pub const _2MB: usize = 2 * 1024 * 1024;
@@ -82,7 +112,8 @@ impl Ept {
         let mut mtrr = Mtrr::new();
 
         for pa in (0.._512GB).step_by(_2MB) {
-            self.map_2mb(pa, pa, access_type, &mut mtrr)?;
+            let guest_pa = Gpa::new(pa);
+            self.map_2mb(guest_pa, guest_pa.to_hpa(), access_type, &mut mtrr)?;
         }
 
         Ok(())
@@ -105,7 +136,8 @@ impl Ept {
         let mut mtrr = Mtrr::new();
 
         for pa in (0.._512GB).step_by(BASE_PAGE_SIZE) {
-            self.map_4kb(pa, pa, access_type, &mut mtrr)?;
+            let guest_pa = Gpa::new(pa);
+            self.map_4kb(guest_pa, guest_pa.to_hpa(), access_type, &mut mtrr)?;
         }
 
         Ok(())
@@ -125,11 +157,12 @@ impl Ept {
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
     pub fn map_2mb(
         &mut self,
-        guest_pa: u64,
-        host_pa: u64,
+        guest_pa: Gpa,
+        host_pa: Hpa,
         access_type: AccessType,
         mtrr: &mut Mtrr,
     ) -> Result<(), HypervisorError> {
+        Self::table_indices(guest_pa)?;
         self.map_pml4(guest_pa, access_type)?;
         self.map_pdpt(guest_pa, access_type)?;
         self.map_pde(guest_pa, host_pa, access_type, mtrr)?;
@@ -150,11 +183,12 @@ impl Ept {
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
     pub fn map_4kb(
         &mut self,
-        guest_pa: u64,
-        host_pa: u64,
+        guest_pa: Gpa,
+        host_pa: Hpa,
         access_type: AccessType,
         mtrr: &mut Mtrr,
     ) -> Result<(), HypervisorError> {
+        Self::table_indices(guest_pa)?;
         self.map_pml4(guest_pa, access_type)?;
         self.map_pdpt(guest_pa, access_type)?;
         self.map_pdt(guest_pa, access_type)?;
@@ -173,9 +207,14 @@ impl Ept {
     /// # Returns
     ///
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
-    fn map_pml4(&mut self, guest_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
-        let pml4_index = pml4_index(VAddr::from(guest_pa));
-        let pml4_entry = &mut self.pml4.0.entries[pml4_index];
+    fn map_pml4(&mut self, guest_pa: Gpa, access_type: AccessType) -> Result<(), HypervisorError> {
+        let pml4_index = pml4_index(VAddr::from(guest_pa.as_u64()));
+        let pml4_entry = self
+            .pml4
+            .0
+            .entries
+            .get_mut(pml4_index)
+            .ok_or(HypervisorError::InvalidPml4Entry)?;
 
         if !pml4_entry.readable() {
             pml4_entry.set_readable(access_type.contains(AccessType::READ));
@@ -198,18 +237,24 @@ impl Ept {
     /// # Returns
     ///
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
-    fn map_pdpt(&mut self, guest_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
-        let pdpt_index = pdpt_index(VAddr::from(guest_pa));
-        let pdpt_entry = &mut self.pdpt.0.entries[pdpt_index];
+    fn map_pdpt(&mut self, guest_pa: Gpa, access_type: AccessType) -> Result<(), HypervisorError> {
+        let pdpt_index = pdpt_index(VAddr::from(guest_pa.as_u64()));
+        let pd = self
+            .pd
+            .get(pdpt_index)
+            .ok_or(HypervisorError::InvalidPdptEntry)? as *const Pd;
+        let pdpt_entry = self
+            .pdpt
+            .0
+            .entries
+            .get_mut(pdpt_index)
+            .ok_or(HypervisorError::InvalidPdptEntry)?;
 
         if !pdpt_entry.readable() {
             pdpt_entry.set_readable(access_type.contains(AccessType::READ));
             pdpt_entry.set_writable(access_type.contains(AccessType::WRITE));
             pdpt_entry.set_executable(access_type.contains(AccessType::EXECUTE));
-            pdpt_entry.set_pfn(
-                PhysicalAddress::pa_from_va(addr_of!(self.pd[pdpt_index]) as u64)
-                    >> BASE_PAGE_SHIFT,
-            );
+            pdpt_entry.set_pfn(PhysicalAddress::pa_from_va(pd as u64) >> BASE_PAGE_SHIFT);
         }
 
         Ok(())
@@ -225,19 +270,21 @@ impl Ept {
     /// # Returns
     ///
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
-    fn map_pdt(&mut self, guest_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
-        let pdpt_index = pdpt_index(VAddr::from(guest_pa));
-        let pd_index = pd_index(VAddr::from(guest_pa));
-        let pd_entry = &mut self.pd[pdpt_index].0.entries[pd_index];
+    fn map_pdt(&mut self, guest_pa: Gpa, access_type: AccessType) -> Result<(), HypervisorError> {
+        let pdpt_index = pdpt_index(VAddr::from(guest_pa.as_u64()));
+        let pd_index = pd_index(VAddr::from(guest_pa.as_u64()));
+        let pt = self
+            .pt
+            .get(pdpt_index)
+            .and_then(|pts| pts.get(pd_index))
+            .ok_or(HypervisorError::InvalidPdEntry)? as *const Pt;
+        let pd_entry = self.pd_entry_mut(pdpt_index, pd_index)?;
 
         if !pd_entry.readable() {
             pd_entry.set_readable(access_type.contains(AccessType::READ));
             pd_entry.set_writable(access_type.contains(AccessType::WRITE));
             pd_entry.set_executable(access_type.contains(AccessType::EXECUTE));
-            pd_entry.set_pfn(
-                PhysicalAddress::pa_from_va(addr_of!(self.pt[pdpt_index][pd_index]) as u64)
-                    >> BASE_PAGE_SHIFT,
-            );
+            pd_entry.set_pfn(PhysicalAddress::pa_from_va(pt as u64) >> BASE_PAGE_SHIFT);
         }
 
         Ok(())
@@ -256,17 +303,17 @@ impl Ept {
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
     fn map_pde(
         &mut self,
-        guest_pa: u64,
-        host_pa: u64,
+        guest_pa: Gpa,
+        host_pa: Hpa,
         access_type: AccessType,
         mtrr: &mut Mtrr,
     ) -> Result<(), HypervisorError> {
-        let pdpt_index = pdpt_index(VAddr::from(guest_pa));
-        let pd_index = pd_index(VAddr::from(guest_pa));
-        let pd_entry = &mut self.pd[pdpt_index].0.entries[pd_index];
+        let pdpt_index = pdpt_index(VAddr::from(guest_pa.as_u64()));
+        let pd_index = pd_index(VAddr::from(guest_pa.as_u64()));
+        let pd_entry = self.pd_entry_mut(pdpt_index, pd_index)?;
 
         let memory_type = mtrr
-            .find(guest_pa..guest_pa + LARGE_PAGE_SIZE as u64)
+            .find(guest_pa.as_u64()..guest_pa.as_u64() + LARGE_PAGE_SIZE as u64)
             .unwrap_or(MemoryType::Uncacheable);
 
         if !pd_entry.readable() {
@@ -275,7 +322,7 @@ impl Ept {
             pd_entry.set_executable(access_type.contains(AccessType::EXECUTE));
             pd_entry.set_memory_type(memory_type as u64);
             pd_entry.set_large(true);
-            pd_entry.set_pfn(host_pa >> BASE_PAGE_SHIFT);
+            pd_entry.set_pfn(host_pa.pfn());
         } else {
             log::warn!(
                 "Attempted to map an already-mapped 2MB page: {:x}",
@@ -299,18 +346,18 @@ impl Ept {
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
     fn map_pt(
         &mut self,
-        guest_pa: u64,
-        host_pa: u64,
+        guest_pa: Gpa,
+        host_pa: Hpa,
         access_type: AccessType,
         mtrr: &mut Mtrr,
     ) -> Result<(), HypervisorError> {
-        let pdpt_index = pdpt_index(VAddr::from(guest_pa));
-        let pd_index = pd_index(VAddr::from(guest_pa));
-        let pt_index = pt_index(VAddr::from(guest_pa));
-        let pt_entry = &mut self.pt[pdpt_index][pd_index].0.entries[pt_index];
+        let pdpt_index = pdpt_index(VAddr::from(guest_pa.as_u64()));
+        let pd_index = pd_index(VAddr::from(guest_pa.as_u64()));
+        let pt_index = pt_index(VAddr::from(guest_pa.as_u64()));
+        let pt_entry = self.pt_entry_mut(pdpt_index, pd_index, pt_index)?;
 
         let memory_type = mtrr
-            .find(guest_pa..guest_pa + BASE_PAGE_SIZE as u64)
+            .find(guest_pa.as_u64()..guest_pa.as_u64() + BASE_PAGE_SIZE as u64)
             .unwrap_or(MemoryType::Uncacheable);
 
         if !pt_entry.readable() {
@@ -318,7 +365,7 @@ impl Ept {
             pt_entry.set_writable(access_type.contains(AccessType::WRITE));
             pt_entry.set_executable(access_type.contains(AccessType::EXECUTE));
             pt_entry.set_memory_type(memory_type as u64);
-            pt_entry.set_pfn(host_pa >> BASE_PAGE_SHIFT);
+            pt_entry.set_pfn(host_pa.pfn());
         } else {
             log::warn!(
                 "Attempted to map an already-mapped 4KB page: {:x}",
@@ -345,21 +392,17 @@ impl Ept {
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
     pub fn change_page_flags(
         &mut self,
-        guest_pa: u64,
+        guest_pa: Gpa,
         access_type: AccessType,
     ) -> Result<(), HypervisorError> {
-        let guest_pa = VAddr::from(guest_pa);
-
-        if !guest_pa.is_large_page_aligned() && !guest_pa.is_base_page_aligned() {
+        if !guest_pa.is_page_aligned() {
             log::error!("Page is not aligned: {:#x}", guest_pa);
             return Err(HypervisorError::UnalignedAddressError);
         }
 
-        let pdpt_index = pdpt_index(guest_pa);
-        let pd_index = pd_index(guest_pa);
-        let pt_index = pt_index(guest_pa);
+        let (pdpt_index, pd_index, pt_index) = Self::table_indices(guest_pa)?;
 
-        let pd_entry = &mut self.pd[pdpt_index].0.entries[pd_index];
+        let pd_entry = self.pd_entry_mut(pdpt_index, pd_index)?;
 
         if pd_entry.large() {
             log::trace!("Changing the permissions of a 2mb page");
@@ -369,7 +412,7 @@ impl Ept {
         } else {
             log::trace!("Changing the permissions of a 4kb page");
 
-            let pt_entry = &mut self.pt[pdpt_index][pd_index].0.entries[pt_index];
+            let pt_entry = self.pt_entry_mut(pdpt_index, pd_index, pt_index)?;
             pt_entry.set_readable(access_type.contains(AccessType::READ));
             pt_entry.set_writable(access_type.contains(AccessType::WRITE));
             pt_entry.set_executable(access_type.contains(AccessType::EXECUTE));
@@ -378,6 +421,177 @@ impl Ept {
         Ok(())
     }
 
+    /// Reads, and optionally clears, the accessed and dirty flags of the page mapping the given address.
+    ///
+    /// The flags are only maintained by the processor if the EPTP enables them (`EPTP_ACCESSED_DIRTY_ENABLE`).
+    /// Unlike write-protection based tracking, this does not change the permissions of the page and does
+    /// not cause any VM exits.
+    ///
+    /// After clearing the flags, the caller must invalidate the EPT (INVEPT) for the processor to set
+    /// them again on the next access, as it may otherwise keep using cached translations.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - A guest physical address within the 4KB or 2MB page.
+    /// * `clear` - Whether to clear the flags after reading them.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the flags of the page that were set.
+    pub fn harvest_accessed_dirty(
+        &mut self,
+        guest_pa: Gpa,
+        clear: bool,
+    ) -> Result<PageAccess, HypervisorError> {
+        let (pdpt_index, pd_index, pt_index) = Self::table_indices(guest_pa)?;
+
+        let entry = if self.pd_entry_mut(pdpt_index, pd_index)?.large() {
+            self.pd_entry_mut(pdpt_index, pd_index)?
+        } else {
+            self.pt_entry_mut(pdpt_index, pd_index, pt_index)?
+        };
+
+        let flags = if clear {
+            // The processor sets the flags with locked operations while the EPT is in use, so they are cleared
+            // with a compare-exchange to not lose a flag set between the read and the write.
+            // SAFETY: The entry is a naturally aligned u64 in a table owned by this EPT.
+            let atomic = unsafe { AtomicU64::from_ptr(addr_of_mut!(entry.0)) };
+            let previous = atomic
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                    let mut cleared = Entry(value);
+                    cleared.set_accessed(false);
+                    cleared.set_dirty(false);
+                    Some(cleared.0)
+                })
+                .unwrap_or_else(|value| value);
+            Entry(previous)
+        } else {
+            *entry
+        };
+
+        let mut access = PageAccess::empty();
+        access.set(PageAccess::ACCESSED, flags.accessed());
+        access.set(PageAccess::DIRTY, flags.dirty());
+
+        Ok(access)
+    }
+
+    /// Reads, and optionally clears, the accessed and dirty flags of all pages in a range.
+    ///
+    /// See `harvest_accessed_dirty` for the requirements on the EPTP and the invalidation after clearing.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The guest physical address range to walk, in 4KB steps.
+    /// * `clear` - Whether to clear the flags after reading them.
+    /// * `callback` - Called with the guest physical address and the flags of every accessed page.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of accessed pages.
+    pub fn harvest_accessed_dirty_range(
+        &mut self,
+        range: Range<Gpa>,
+        clear: bool,
+        mut callback: impl FnMut(Gpa, PageAccess),
+    ) -> Result<usize, HypervisorError> {
+        let mut accessed = 0;
+        let mut guest_pa = range.start.page_base();
+
+        while guest_pa < range.end {
+            let (pdpt_index, pd_index, _) = Self::table_indices(guest_pa)?;
+            let large = self.pd_entry_mut(pdpt_index, pd_index)?.large();
+
+            // A 2MB page is reported once, with its base address.
+            let (page_pa, page_size) = if large {
+                (guest_pa.large_page_base(), LARGE_PAGE_SIZE as u64)
+            } else {
+                (guest_pa, BASE_PAGE_SIZE as u64)
+            };
+
+            let access = self.harvest_accessed_dirty(page_pa, clear)?;
+            if !access.is_empty() {
+                accessed += 1;
+                callback(page_pa, access);
+            }
+
+            guest_pa = page_pa + page_size;
+        }
+
+        Ok(accessed)
+    }
+
+    /// Overrides the memory type of the pages in a guest physical address range.
+    ///
+    /// The requested type is validated against the MTRRs first (see `Mtrr::validate`). 2MB pages only
+    /// partially covered by the range are split into 4KB pages, keeping their permissions.
+    ///
+    /// The caller must invalidate the EPT (INVEPT) afterwards for the new type to take effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The 4KB aligned guest physical address range.
+    /// * `memory_type` - The memory type to apply.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful.
+    pub fn set_memory_type(
+        &mut self,
+        range: Range<Gpa>,
+        memory_type: MemoryType,
+    ) -> Result<(), HypervisorError> {
+        log::trace!(
+            "Setting memory type of {:#x}-{:#x} to {:?}",
+            range.start,
+            range.end,
+            memory_type
+        );
+
+        if !range.start.is_page_aligned() || !range.end.is_page_aligned() || range.is_empty() {
+            log::error!(
+                "Range is not page aligned: {:#x}-{:#x}",
+                range.start,
+                range.end
+            );
+            return Err(HypervisorError::UnalignedAddressError);
+        }
+
+        Mtrr::validate(range.start.as_u64()..range.end.as_u64(), memory_type)?;
+
+        let mut guest_pa = range.start;
+
+        while guest_pa < range.end {
+            let (pdpt_index, pd_index, pt_index) = Self::table_indices(guest_pa)?;
+            let pd_entry = self.pd_entry_mut(pdpt_index, pd_index)?;
+
+            if pd_entry.large() {
+                let large_pa = guest_pa.large_page_base();
+
+                // The whole 2MB page is covered by the range, so it keeps its size.
+                if large_pa == guest_pa && guest_pa + LARGE_PAGE_SIZE as u64 <= range.end {
+                    pd_entry.set_memory_type(memory_type as u64);
+                    guest_pa += LARGE_PAGE_SIZE as u64;
+                    continue;
+                }
+
+                let mut access_type = AccessType::empty();
+                access_type.set(AccessType::READ, pd_entry.readable());
+                access_type.set(AccessType::WRITE, pd_entry.writable());
+                access_type.set(AccessType::EXECUTE, pd_entry.executable());
+
+                self.split_2mb_to_4kb(large_pa, access_type)?;
+            }
+
+            self.pt_entry_mut(pdpt_index, pd_index, pt_index)?
+                .set_memory_type(memory_type as u64);
+
+            guest_pa += BASE_PAGE_SIZE as u64;
+        }
+
+        Ok(())
+    }
+
     /// Splits a large 2MB page into 512 smaller 4KB pages for a given guest physical address.
     ///
     /// This is necessary to apply more granular hooks and reduce the number of
@@ -393,16 +607,14 @@ impl Ept {
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
     pub fn split_2mb_to_4kb(
         &mut self,
-        guest_pa: u64,
+        guest_pa: Gpa,
         access_type: AccessType,
     ) -> Result<(), HypervisorError> {
         log::trace!("Splitting 2mb page into 4kb pages: {:x}", guest_pa);
 
-        let guest_pa = VAddr::from(guest_pa);
-
-        let pdpt_index = pdpt_index(guest_pa);
-        let pd_index = pd_index(guest_pa);
-        let pd_entry = &mut self.pd[pdpt_index].0.entries[pd_index];
+        let (pdpt_index, pd_index, _) = Self::table_indices(guest_pa)?;
+        let guest_pa = VAddr::from(guest_pa.as_u64());
+        let pd_entry = self.pd_entry_mut(pdpt_index, pd_index)?;
 
         // We can only split large pages and not page directories.
         // If it's a page directory, it is already split.
@@ -419,8 +631,8 @@ impl Ept {
 
         // Map the unmapped physical memory again to 4KB pages.
         for i in 0..PAGE_SIZE_ENTRIES {
-            let pa = (guest_pa.as_usize() + i * BASE_PAGE_SIZE) as u64;
-            self.map_4kb(pa, pa, access_type, &mut mtrr)?;
+            let page = Gpa::new((guest_pa.as_usize() + i * BASE_PAGE_SIZE) as u64);
+            self.map_4kb(page, page.to_hpa(), access_type, &mut mtrr)?;
         }
 
         Ok(())
@@ -437,10 +649,11 @@ impl Ept {
     /// Credits: Jess / jessiep_
     pub fn remap_page(
         &mut self,
-        guest_pa: u64,
-        host_pa: u64,
+        guest_pa: Gpa,
+        host_pa: Hpa,
         access_type: AccessType,
     ) -> Result<(), HypervisorError> {
+        Self::table_indices(guest_pa)?;
         let mut mtrr = Mtrr::new();
 
         self.map_pt(guest_pa, host_pa, access_type, &mut mtrr)?;
@@ -485,6 +698,158 @@ impl Ept {
         Self::unmap_2mb(entry);
     }
 
+    /// Returns the indices into the PDPT, the page directory and the page table of a guest physical address.
+    ///
+    /// Only the first PML4 entry is used, so addresses at or above 512GB, whose indices would alias the ones of
+    /// a lower address, are refused.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the indices, or `HypervisorError::InvalidPml4Entry` if the address is not mapped by
+    /// the first PML4 entry.
+    fn table_indices(guest_pa: Gpa) -> Result<(usize, usize, usize), HypervisorError> {
+        if guest_pa.as_u64() >= _512GB {
+            log::error!("Guest physical address out of range: {:#x}", guest_pa);
+            return Err(HypervisorError::InvalidPml4Entry);
+        }
+
+        let address = VAddr::from(guest_pa.as_u64());
+        Ok((pdpt_index(address), pd_index(address), pt_index(address)))
+    }
+
+    /// Returns the page directory entry at the given indices.
+    ///
+    /// # Arguments
+    ///
+    /// * `pdpt_index` - The index into the page directory pointer table.
+    /// * `pd_index` - The index into the page directory.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the entry, or `HypervisorError::InvalidPdEntry` if an index is out of range.
+    fn pd_entry_mut(
+        &mut self,
+        pdpt_index: usize,
+        pd_index: usize,
+    ) -> Result<&mut Entry, HypervisorError> {
+        self.pd
+            .get_mut(pdpt_index)
+            .and_then(|pd| pd.0.entries.get_mut(pd_index))
+            .ok_or(HypervisorError::InvalidPdEntry)
+    }
+
+    /// Returns the page table entry at the given indices.
+    ///
+    /// # Arguments
+    ///
+    /// * `pdpt_index` - The index into the page directory pointer table.
+    /// * `pd_index` - The index into the page directory.
+    /// * `pt_index` - The index into the page table.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the entry, or `HypervisorError::InvalidPml1Entry` if an index is out of range.
+    fn pt_entry_mut(
+        &mut self,
+        pdpt_index: usize,
+        pd_index: usize,
+        pt_index: usize,
+    ) -> Result<&mut Entry, HypervisorError> {
+        self.pt
+            .get_mut(pdpt_index)
+            .and_then(|pts| pts.get_mut(pd_index))
+            .and_then(|pt| pt.0.entries.get_mut(pt_index))
+            .ok_or(HypervisorError::InvalidPml1Entry)
+    }
+
+    /// Walks the tables for a guest physical address, the way the processor translates it.
+    ///
+    /// Unlike the processor, the walk does not stop at entries that are not present, so that the dump of the
+    /// tables shows what the entries below them hold (see `ept::dump`).
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to translate.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the entries walked, or an error if an index is out of range.
+    pub fn walk(&self, guest_pa: Gpa) -> Result<EptWalk, HypervisorError> {
+        let (pdpt_index, pd_index, pt_index) = Self::table_indices(guest_pa)?;
+        let address = VAddr::from(guest_pa.as_u64());
+
+        let pml4e = *self
+            .pml4
+            .0
+            .entries
+            .get(pml4_index(address))
+            .ok_or(HypervisorError::InvalidPml4Entry)?;
+        let pdpte = *self
+            .pdpt
+            .0
+            .entries
+            .get(pdpt_index)
+            .ok_or(HypervisorError::InvalidPdptEntry)?;
+        let pd = self
+            .pd
+            .get(pdpt_index)
+            .ok_or(HypervisorError::InvalidPdEntry)?;
+        let pde = *pd
+            .0
+            .entries
+            .get(pd_index)
+            .ok_or(HypervisorError::InvalidPdEntry)?;
+        let pt = self
+            .pt
+            .get(pdpt_index)
+            .and_then(|pts| pts.get(pd_index))
+            .ok_or(HypervisorError::InvalidPml1Entry)?;
+
+        let pte = match pde.large() {
+            true => None,
+            false => Some(
+                *pt.0
+                    .entries
+                    .get(pt_index)
+                    .ok_or(HypervisorError::InvalidPml1Entry)?,
+            ),
+        };
+
+        Ok(EptWalk {
+            pml4e,
+            pdpte,
+            pde,
+            pte,
+            pdpt: Hpa::new(PhysicalAddress::pa_from_va(addr_of!(self.pdpt) as u64)),
+            pd: Hpa::new(PhysicalAddress::pa_from_va(pd as *const Pd as u64)),
+            pt: Hpa::new(PhysicalAddress::pa_from_va(pt as *const Pt as u64)),
+        })
+    }
+
+    /// Returns the permissions of the page mapping a guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - A guest physical address within the 4KB or 2MB page.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the permissions, or an error if an index is out of range.
+    pub fn page_access(&self, guest_pa: Gpa) -> Result<AccessType, HypervisorError> {
+        let (_, entry) = self.walk(guest_pa)?.leaf();
+
+        let mut access_type = AccessType::empty();
+        access_type.set(AccessType::READ, entry.readable());
+        access_type.set(AccessType::WRITE, entry.writable());
+        access_type.set(AccessType::EXECUTE, entry.executable());
+
+        Ok(access_type)
+    }
+
     /// Creates an Extended Page Table Pointer (EPTP) with a Write-Back memory type and a 4-level page walk.
     ///
     /// This function is used in the setup of Intel VT-x virtualization, specifically for configuring the EPT.
@@ -520,6 +885,63 @@ impl Ept {
     }
 }
 
+impl Drop for Ept {
+    /// Checks that no processor can still walk the tables.
+    ///
+    /// Every processor flushes its EPT-derived translations with an all-context INVEPT right before leaving
+    /// VMX operation (see `Vmx::teardown`), so the tables may only be freed once no processor is virtualized.
+    fn drop(&mut self) {
+        if any_virtualized() {
+            log::error!("Freeing EPT tables while processors are still virtualized");
+        }
+    }
+}
+
+/// The level of an EPT entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptLevel {
+    Pml4,
+    Pdpt,
+    Pd,
+    Pt,
+}
+
+/// The entries walked to translate a guest physical address, see `Ept::walk`.
+#[derive(Debug, Clone, Copy)]
+pub struct EptWalk {
+    /// The PML4 entry.
+    pub pml4e: Entry,
+
+    /// The page-directory-pointer-table entry.
+    pub pdpte: Entry,
+
+    /// The page-directory entry, which maps a 2MB page if it is large.
+    pub pde: Entry,
+
+    /// The page-table entry, or `None` if the page-directory entry maps a 2MB page.
+    pub pte: Option<Entry>,
+
+    /// The host physical address of the page-directory-pointer table the PML4 entry should reference.
+    pub pdpt: Hpa,
+
+    /// The host physical address of the page directory the page-directory-pointer-table entry should reference.
+    pub pd: Hpa,
+
+    /// The host physical address of the page table the page-directory entry should reference, if not large.
+    pub pt: Hpa,
+}
+
+impl EptWalk {
+    /// Returns the level and the entry mapping the page: the page-table entry, or the page-directory entry of a
+    /// 2MB page.
+    pub fn leaf(&self) -> (EptLevel, Entry) {
+        match self.pte {
+            Some(pte) => (EptLevel::Pt, pte),
+            None => (EptLevel::Pd, self.pde),
+        }
+    }
+}
+
 /// Represents an EPT PML4 Entry (PML4E) that references a Page-Directory-Pointer Table.
 ///
 /// PML4 is the top level in the EPT paging hierarchy.
@@ -576,12 +998,15 @@ bitfield! {
     /// * `executable` - If set, code can be executed from the memory region.
     /// * `memory_type` - The memory type (e.g., WriteBack, Uncacheable).
     /// * `large` - If set, this entry maps a large page.
+    /// * `accessed` - Set by the processor when the entry is used for a translation, if enabled in the EPTP.
+    /// * `dirty` - Set by the processor when the page is written, if enabled in the EPTP (leaf entries only).
     /// * `pfn` - The Page Frame Number, indicating the physical address.
     /// * `verify_guest_paging` - Additional flag for guest paging verification.
     /// * `paging_write_access` - Additional flag for paging write access.
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.2 EPT Translation Mechanism
     #[derive(Clone, Copy)]
+    #[repr(transparent)]
     pub struct Entry(u64);
     impl Debug;
 
@@ -591,7 +1016,16 @@ bitfield! {
     pub executable, set_executable: 2;
     pub memory_type, set_memory_type: 5, 3;
     pub large, set_large: 7;
+    pub accessed, set_accessed: 8;
+    pub dirty, set_dirty: 9;
     pub pfn, set_pfn: 51, 12;
     pub verify_guest_paging, set_verify_guest_paging: 57;
     pub paging_write_access, set_paging_write_access: 58;
 }
+
+impl Entry {
+    /// Returns the raw value of the entry.
+    pub fn raw(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,318 @@
+//! Region-based EPT permission profiles.
+//!
+//! A `PermissionProfile` names a set of EPT permissions and what to do when the guest violates them. Profiles
+//! are applied to whole sets of guest physical regions in one call, and the policy engine remembers which
+//! region is governed by which profile, so the EPT violations on these regions can be attributed and answered,
+//! and the regions can later be released by profile name.
+//!
+//! Violations are answered depending on the profile:
+//! - `Monitor`: the access is recorded, and the guest single-steps over it with the monitor trap flag while
+//!   the page also allows the access attempted. The protection is restored on the following MTF exit, and the
+//!   other processors are kicked to flush their translations of the page, see `invept_broadcast`.
+//! - `Deny`: the access is recorded and #GP(0) is injected instead of carrying it out.
+//!
+//! The regions are looked up by the EPT violation handlers of all processors, so they are published through
+//! RCU: an update builds a new sorted list and swaps it in, and the handlers look it up without a lock.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.2 EPT Violations
+//! and 26.5.2 Monitor Trap Flag.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::ept::paging::{AccessType, _512GB},
+        utils::{addresses::Gpa, event_log::EventLog, rcu::Rcu, timestamp::Timestamp},
+    },
+    alloc::vec::Vec,
+    core::{convert::Infallible, ops::Range},
+    x86::current::paging::BASE_PAGE_SIZE,
+};
+
+/// The number of violations kept until they are drained.
+const EVENT_LOG_LEN: usize = 64;
+
+/// What to do when the guest violates the permissions of a profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationResponse {
+    /// Record the access and let it complete.
+    Monitor,
+
+    /// Record the access and inject #GP(0) instead of carrying it out.
+    Deny,
+}
+
+/// A reusable set of EPT permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PermissionProfile {
+    /// The name under which the regions of the profile are released.
+    pub name: &'static str,
+
+    /// The EPT permissions of the pages in the regions.
+    pub access: AccessType,
+
+    /// What to do when the guest violates the permissions.
+    pub response: ViolationResponse,
+}
+
+impl PermissionProfile {
+    /// Reports code execution in the regions, which remain readable and writable.
+    pub const MONITOR_EXEC: Self = Self::new(
+        "monitor-exec",
+        AccessType::READ_WRITE,
+        ViolationResponse::Monitor,
+    );
+
+    /// Refuses writes to the regions, which remain readable and executable.
+    pub const DENY_WRITE: Self = Self::new(
+        "deny-write",
+        AccessType::READ_EXECUTE,
+        ViolationResponse::Deny,
+    );
+
+    /// Refuses any access to the regions.
+    pub const INVISIBLE: Self =
+        Self::new("invisible", AccessType::empty(), ViolationResponse::Deny);
+
+    /// Creates a custom profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name under which the regions of the profile are released.
+    /// * `access` - The EPT permissions of the pages in the regions.
+    /// * `response` - What to do when the guest violates the permissions.
+    pub const fn new(name: &'static str, access: AccessType, response: ViolationResponse) -> Self {
+        Self {
+            name,
+            access,
+            response,
+        }
+    }
+}
+
+/// A guest physical region governed by a profile.
+#[derive(Debug, Clone, Copy)]
+pub struct ProtectedRegion {
+    /// The first guest physical address of the region, page aligned.
+    pub start: Gpa,
+
+    /// The guest physical address following the region, page aligned.
+    pub end: Gpa,
+
+    /// The profile governing the region.
+    pub profile: PermissionProfile,
+}
+
+impl ProtectedRegion {
+    /// Returns whether the region contains the guest physical address.
+    pub fn contains(&self, guest_pa: Gpa) -> bool {
+        (self.start..self.end).contains(&guest_pa)
+    }
+
+    /// Returns the guest physical addresses of the pages in the region.
+    pub fn pages(&self) -> impl Iterator<Item = Gpa> {
+        (self.start.as_u64()..self.end.as_u64())
+            .step_by(BASE_PAGE_SIZE)
+            .map(Gpa::new)
+    }
+}
+
+/// A recorded violation of a profile.
+#[derive(Debug, Clone, Copy)]
+pub struct RegionViolation {
+    /// The guest physical address accessed.
+    pub guest_pa: Gpa,
+
+    /// The guest RIP of the access.
+    pub rip: u64,
+
+    /// The name of the profile governing the region.
+    pub profile: &'static str,
+
+    /// The kind of access attempted.
+    pub access: AccessType,
+
+    /// How the violation was answered.
+    pub response: ViolationResponse,
+
+    /// When the violation happened.
+    pub timestamp: Timestamp,
+}
+
+/// The policy engine, remembering the regions governed by each profile.
+pub struct EptPolicy {
+    /// The protected regions, sorted by start address and never overlapping.
+    regions: Rcu<Vec<ProtectedRegion>>,
+
+    /// The violations not drained yet.
+    violations: EventLog<RegionViolation, EVENT_LOG_LEN>,
+}
+
+impl Default for EptPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EptPolicy {
+    /// Creates a policy without any protected region.
+    pub fn new() -> Self {
+        Self {
+            regions: Rcu::new(Vec::new()),
+            violations: EventLog::new("ept_policy_violations"),
+        }
+    }
+
+    /// Puts regions under a profile.
+    ///
+    /// The caller must then change the EPT permissions of the regions to those of the profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `profile` - The profile governing the regions.
+    /// * `ranges` - The guest physical regions, page aligned.
+    ///
+    /// # Returns
+    ///
+    /// The added regions, or an error if a region is not page aligned, is empty, lies beyond the 512GB mapped by
+    /// the EPT or overlaps another one.
+    pub fn add(
+        &self,
+        profile: PermissionProfile,
+        ranges: &[Range<Gpa>],
+    ) -> Result<Vec<ProtectedRegion>, HypervisorError> {
+        let added: Vec<ProtectedRegion> = ranges
+            .iter()
+            .map(|range| ProtectedRegion {
+                start: range.start,
+                end: range.end,
+                profile,
+            })
+            .collect();
+
+        if added
+            .iter()
+            .any(|region| !region.start.is_page_aligned() || !region.end.is_page_aligned())
+        {
+            return Err(HypervisorError::UnalignedAddressError);
+        }
+
+        self.regions.update(|current| {
+            let mut regions = Vec::with_capacity(current.len() + added.len());
+            regions.extend_from_slice(current);
+            regions.extend_from_slice(&added);
+            regions.sort_unstable_by_key(|region| region.start);
+
+            // The EPT only maps the first 512GB, higher addresses would alias lower pages.
+            let valid = regions
+                .iter()
+                .all(|region| region.start < region.end && region.end.as_u64() <= _512GB)
+                && regions
+                    .windows(2)
+                    .all(|pair| matches!(pair, [a, b] if a.end <= b.start));
+
+            if !valid {
+                return Err(HypervisorError::InvalidRegion);
+            }
+
+            Ok((regions, ()))
+        })?;
+
+        log::debug!(
+            "Applied profile {} to {} regions",
+            profile.name,
+            added.len()
+        );
+
+        Ok(added)
+    }
+
+    /// Returns the regions governed by the profile with the given name.
+    pub fn regions_of(&self, name: &str) -> Vec<ProtectedRegion> {
+        let mut regions = self.snapshot();
+        regions.retain(|region| region.profile.name == name);
+        regions
+    }
+
+    /// Releases the regions governed by the profile with the given name.
+    ///
+    /// The caller must restore the EPT permissions of the regions beforehand, see `regions_of`.
+    ///
+    /// # Returns
+    ///
+    /// The number of regions released.
+    pub fn remove(&self, name: &str) -> usize {
+        let removed = self.regions.update(|current| {
+            let mut regions = current.clone();
+            regions.retain(|region| region.profile.name != name);
+            let removed = current.len() - regions.len();
+
+            Ok::<_, Infallible>((regions, removed))
+        });
+
+        match removed {
+            Ok(removed) => removed,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Returns the region containing the guest physical address, if it is protected.
+    pub fn lookup(&self, guest_pa: Gpa) -> Option<ProtectedRegion> {
+        let regions = self.regions.read();
+        let index = regions.partition_point(|region| region.start <= guest_pa);
+
+        index
+            .checked_sub(1)
+            .and_then(|i| regions.get(i))
+            .filter(|region| region.contains(guest_pa))
+            .copied()
+    }
+
+    /// Returns a snapshot of the protected regions, sorted by start address.
+    pub fn regions(&self) -> Vec<ProtectedRegion> {
+        self.snapshot()
+    }
+
+    /// Visits the protected regions in order of their start address, without copying them.
+    ///
+    /// Outside of an exit handler, interrupts stay disabled during the visit, so `visit` must not allocate.
+    pub fn for_each_region(&self, visit: impl FnMut(&ProtectedRegion)) {
+        self.regions.read().iter().for_each(visit);
+    }
+
+    /// Records a violation.
+    pub fn record(&self, violation: RegionViolation) {
+        log::trace!(
+            "{:?} access to {:#x} from {:#x} violates profile {}",
+            violation.access,
+            violation.guest_pa,
+            violation.rip,
+            violation.profile
+        );
+
+        self.violations.push(violation);
+    }
+
+    /// Hands the pending violations to a consumer, oldest first, and removes them.
+    ///
+    /// # Returns
+    ///
+    /// The number of violations drained.
+    pub fn drain_violations(&self, consumer: impl FnMut(&RegionViolation)) -> usize {
+        self.violations.drain(consumer)
+    }
+
+    /// Returns the total number of violations recorded, including the ones that were overwritten.
+    pub fn total_violations(&self) -> u64 {
+        self.violations.total()
+    }
+
+    /// Copies the protected regions.
+    ///
+    /// Reading the regions outside of an exit handler disables interrupts, so the copy is allocated before.
+    fn snapshot(&self) -> Vec<ProtectedRegion> {
+        let len = self.regions.read().len();
+        let mut regions = Vec::with_capacity(len);
+        regions.extend_from_slice(&self.regions.read());
+        regions
+    }
+}
@@ -0,0 +1,316 @@
+//! Detection and mitigation of EPT view thrashing on hooked pages.
+//!
+//! A hooked page is read/write-only in the primary EPT and execute-only in the secondary EPT, so every switch
+//! between executing the page and accessing its data swaps the EPTP, at the cost of a VM exit and an EPT
+//! invalidation. Code reading data from the page it executes from, e.g. jump tables or literals embedded in
+//! the code, ping-pongs between the two views. Each processor counts the view switches per page in windows
+//! of TSC ticks, and once a page switches more often than the configured threshold in a window, it is
+//! handled with the configured strategy:
+//! - `ThrashStrategy::SingleStep`: the processor stays in the execute view of the page. Data accesses to the
+//!   page are single-stepped in the read/write view with the monitor trap flag, after which the execute view
+//!   is restored, so the hook keeps firing and the processor never runs in the read/write view for longer
+//!   than an instruction.
+//! - `ThrashStrategy::DisableHook`: the hook is disabled by making the page executable in the primary EPT,
+//!   so the original code runs without VM exits. The hook is enabled again once a cooldown has elapsed. A
+//!   page thrashing again is disabled for twice as long as the previous time, up to the maximum cooldown.
+//!
+//! The strategy stays in effect for the rest of the window. Disabling a hook only flushes the EPT of the
+//! processor doing it, as the other processors pick the permission granted up on their next EPT invalidation.
+//! Enabling it again takes the permission away, so the other processors are kicked to flush their translations
+//! right away, see `invept_broadcast`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.2 Monitor Trap Flag and
+//! 29.3.3.2 EPT Violations.
+
+use {
+    crate::utils::{addresses::Gpa, instructions::rdtsc, sync::SpinLock},
+    alloc::vec::Vec,
+    core::sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The number of pages whose view switches are counted at once by each processor.
+const TRACKED_PAGES: usize = 4;
+
+/// The maximum number of hooks disabled, or remembered for their backoff, at once.
+pub const MAX_DISABLED_HOOKS: usize = 16;
+
+/// How a thrashing page is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrashStrategy {
+    /// Single-step data accesses in the read/write view and return to the execute view.
+    SingleStep,
+
+    /// Disable the hook of the page for a cooldown.
+    DisableHook,
+}
+
+/// The configuration of the thrashing detection, shared between processors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrashPolicy {
+    /// The number of view switches of a page per window above which the page is thrashing.
+    pub max_switches: u32,
+
+    /// The length of a window in TSC ticks.
+    pub window: u64,
+
+    /// How a thrashing page is handled.
+    pub strategy: ThrashStrategy,
+
+    /// The number of TSC ticks a hook is disabled for the first time, for `ThrashStrategy::DisableHook`.
+    pub cooldown: u64,
+
+    /// The maximum number of TSC ticks a hook is disabled for.
+    pub max_cooldown: u64,
+}
+
+/// The view switches of a page counted in the current window.
+#[derive(Debug, Clone, Copy)]
+struct PageSwitches {
+    /// The guest physical address of the page.
+    page: Gpa,
+
+    /// The TSC at which the current window started.
+    window_start: u64,
+
+    /// The number of view switches in the current window.
+    switches: u32,
+}
+
+/// The thrashing detector of a single processor.
+///
+/// Only ever accessed by the VM-exit handler of its own processor, so no synchronization is needed.
+#[derive(Debug, Clone, Copy)]
+pub struct ThrashDetector {
+    /// The configuration, or `None` if thrashing is not detected.
+    policy: Option<ThrashPolicy>,
+
+    /// The pages whose view switches are counted.
+    pages: [Option<PageSwitches>; TRACKED_PAGES],
+
+    /// The slot replaced when a page not counted yet switches views.
+    next_slot: usize,
+
+    /// The total number of view switches while a page was thrashing.
+    thrashing: u64,
+}
+
+impl ThrashDetector {
+    /// Creates a thrashing detector.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The configuration, or `None` to never report thrashing.
+    pub fn new(policy: Option<ThrashPolicy>) -> Self {
+        Self {
+            policy,
+            pages: [None; TRACKED_PAGES],
+            next_slot: 0,
+            thrashing: 0,
+        }
+    }
+
+    /// Accounts for a view switch of a page, or a data access single-stepped for it.
+    ///
+    /// # Arguments
+    ///
+    /// * `page` - The guest physical address of the page.
+    ///
+    /// # Returns
+    ///
+    /// The strategy to apply if the page is thrashing, or `None` otherwise.
+    pub fn record(&mut self, page: Gpa) -> Option<ThrashStrategy> {
+        let policy = self.policy?;
+        let now = rdtsc();
+
+        let slot = match self
+            .pages
+            .iter()
+            .position(|slot| slot.is_some_and(|switches| switches.page == page))
+        {
+            Some(index) => index,
+            None => {
+                let index = self.next_slot;
+                self.next_slot = (self.next_slot + 1) % TRACKED_PAGES;
+                index
+            }
+        };
+
+        let switches = self.pages.get_mut(slot)?;
+        if !switches.is_some_and(|switches| switches.page == page) {
+            *switches = Some(PageSwitches {
+                page,
+                window_start: now,
+                switches: 0,
+            });
+        }
+        let switches = switches.as_mut()?;
+
+        if now.wrapping_sub(switches.window_start) > policy.window {
+            switches.window_start = now;
+            switches.switches = 0;
+        }
+
+        switches.switches = switches.switches.saturating_add(1);
+
+        if switches.switches <= policy.max_switches {
+            return None;
+        }
+
+        self.thrashing += 1;
+
+        if switches.switches == policy.max_switches + 1 {
+            log::debug!(
+                "Page {:#x} is thrashing, switching to {:?}",
+                page,
+                policy.strategy
+            );
+        }
+
+        Some(policy.strategy)
+    }
+
+    /// Returns the total number of view switches while a page was thrashing.
+    pub fn thrashing(&self) -> u64 {
+        self.thrashing
+    }
+}
+
+/// A hook disabled because its page was thrashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisabledHook {
+    /// The guest physical address of the hooked page.
+    pub page: Gpa,
+
+    /// The number of TSC ticks the hook was disabled for the last time.
+    pub cooldown: u64,
+
+    /// The TSC at which the hook is enabled again, or `None` once it was.
+    pub until: Option<u64>,
+
+    /// The number of times the hook was disabled.
+    pub strikes: u32,
+}
+
+/// The hooks disabled by `ThrashStrategy::DisableHook`, shared between processors.
+pub struct ThrashGuard {
+    /// The hooks disabled, and those enabled again whose backoff is remembered.
+    hooks: SpinLock<[Option<DisabledHook>; MAX_DISABLED_HOOKS]>,
+
+    /// The earliest TSC at which a hook is enabled again, `u64::MAX` if none is disabled.
+    next_expiry: AtomicU64,
+}
+
+impl Default for ThrashGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ThrashGuard {
+    /// Creates the guard with no hook disabled.
+    pub fn new() -> Self {
+        Self {
+            hooks: SpinLock::new("thrash_guard", [None; MAX_DISABLED_HOOKS]),
+            next_expiry: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    /// Disables the hook of a thrashing page, doubling its previous cooldown if it was disabled before.
+    ///
+    /// The caller must make the page executable in the primary EPT and invalidate the EPT.
+    ///
+    /// # Arguments
+    ///
+    /// * `page` - The guest physical address of the hooked page.
+    /// * `policy` - The configuration of the thrashing detection.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the hook was disabled, or `false` if it is disabled already or no slot is free.
+    pub fn disable(&self, page: Gpa, policy: &ThrashPolicy) -> bool {
+        let now = rdtsc();
+        let mut hooks = self.hooks.lock();
+
+        let slot = match hooks
+            .iter()
+            .position(|slot| slot.is_some_and(|hook| hook.page == page))
+        {
+            Some(index) => hooks.get_mut(index),
+            None => hooks
+                .iter_mut()
+                .find(|slot| !slot.is_some_and(|hook| hook.until.is_some())),
+        };
+
+        let Some(slot) = slot else {
+            log::warn!("No slot left to disable the hook of {:#x}", page);
+            return false;
+        };
+
+        let previous = slot.filter(|hook| hook.page == page);
+        if previous.is_some_and(|hook| hook.until.is_some()) {
+            return false;
+        }
+
+        let cooldown = match previous {
+            Some(hook) => hook.cooldown.saturating_mul(2).min(policy.max_cooldown),
+            None => policy.cooldown.min(policy.max_cooldown),
+        };
+        let until = now.saturating_add(cooldown);
+
+        *slot = Some(DisabledHook {
+            page,
+            cooldown,
+            until: Some(until),
+            strikes: previous.map_or(0, |hook| hook.strikes) + 1,
+        });
+
+        self.next_expiry.fetch_min(until, Ordering::AcqRel);
+
+        log::info!(
+            "Disabled the hook of thrashing page {:#x} for {} ticks",
+            page,
+            cooldown
+        );
+
+        true
+    }
+
+    /// Takes the hooks whose cooldown has elapsed.
+    ///
+    /// The caller must make the pages read/write-only in the primary EPT again and invalidate the EPT.
+    ///
+    /// # Returns
+    ///
+    /// The guest physical addresses of the hooked pages to enable again.
+    pub fn take_expired(&self) -> [Option<Gpa>; MAX_DISABLED_HOOKS] {
+        let mut pages = [None; MAX_DISABLED_HOOKS];
+
+        let now = rdtsc();
+        if now < self.next_expiry.load(Ordering::Acquire) {
+            return pages;
+        }
+
+        let mut hooks = self.hooks.lock();
+        let mut next_expiry = u64::MAX;
+
+        for (page, hook) in pages.iter_mut().zip(hooks.iter_mut().flatten()) {
+            match hook.until {
+                Some(until) if until <= now => {
+                    hook.until = None;
+                    *page = Some(hook.page);
+                }
+                Some(until) => next_expiry = next_expiry.min(until),
+                None => {}
+            }
+        }
+
+        self.next_expiry.store(next_expiry, Ordering::Release);
+
+        pages
+    }
+
+    /// Returns the hooks disabled, and those enabled again whose backoff is remembered.
+    pub fn hooks(&self) -> Vec<DisabledHook> {
+        self.hooks.lock().iter().flatten().copied().collect()
+    }
+}
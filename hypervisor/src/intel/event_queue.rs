@@ -0,0 +1,791 @@
+//! A per-processor queue of the events waiting to be injected into the guest.
+//!
+//! Only one event can be injected per VM entry, yet several may be pending at once: the event whose delivery
+//! was interrupted by the VM exit (the IDT-vectoring information) must be re-injected, a handler may raise an
+//! exception on top of it, and an NMI or an external interrupt may have to wait until the guest can take it.
+//! Instead of the last injection silently overwriting the previous ones, the events are queued and, on VM
+//! entry, the one with the highest priority that the guest can accept is injected. Interrupts the guest
+//! cannot take yet stay queued, and the guest exits again as soon as its interrupt window, or NMI window,
+//! opens. Exceptions raised by the current instruction are discarded once a higher priority event is
+//! delivered, as done by the processor, since they are raised again when the instruction is executed again.
+//!
+//! An exception raised while delivering another one is merged into a double fault where required.
+//!
+//! The processor loads CR2 when it delivers a page fault and DR6 when it delivers a debug exception, but VM entry
+//! does not when it injects them. Page faults and debug exceptions therefore carry an `ExceptionPayload`, loaded
+//! into CR2 or DR6 only when the exception is actually injected, so a fault superseded by another event or
+//! discarded does not clobber the state the guest sees. The debug exceptions still pending in the VMCS are
+//! reported by an injected #DB and cleared, and an instruction emulated while the guest single-steps records
+//! its single-step trap there, see `raise_single_step_trap`, adding to the pending state instead of replacing it.
+//!
+//! NMI-window exiting requires virtual NMIs. Without them, a blocked NMI is only retried on the next VM
+//! entry, whatever its cause.
+//!
+//! Whether the guest can take an event is decided from a `DeliveryState` read from the VMCS right before the
+//! injection, after the handler has changed RFLAGS or the interruptibility state, rather than from the state saved
+//! at the exit:
+//! - External interrupts need RFLAGS.IF set, no STI or MOV SS shadow, and a priority class (vector bits 7:4)
+//!   above the task priority of the guest in CR8, as the local APIC would require. CR8 is not virtualized, so the
+//!   live CR8 is the guest's. An interrupt masked by the TPR waits on CR8-load exiting rather than on the
+//!   interrupt window, which would otherwise exit again on every instruction while IF is set. A TPR lowered
+//!   through the local APIC page does not exit, the interrupt is then retried on the next VM exit.
+//! - NMIs need no STI, MOV SS or NMI blocking.
+//! - A guest in the HLT activity state only takes external interrupts, NMIs, debug exceptions and machine
+//!   checks, a guest in the shutdown state NMIs and machine checks, and a guest waiting for a SIPI no event, as
+//!   VM entry requires.
+//!
+//! The STI and MOV SS shadows only cover the instruction following STI or MOV SS. An instruction emulated on
+//! behalf of the guest retires it, see `retire_interrupt_shadow`, so the shadow does not block the events of the
+//! next VM entry.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 6.8.3 Masking Exceptions and Interrupts
+//! When Switching Stacks, 6.9 PRIORITY AMONG CONCURRENT EXCEPTIONS AND INTERRUPTS, 6.15 Interrupt 8—Double Fault
+//! Exception (#DF), 10.8.3.1 Task and Processor Priorities, 25.3 CHANGES TO INSTRUCTION
+//! BEHAVIOR IN VMX NON-ROOT OPERATION (virtual NMIs), 27.2.3 Information About NMI Unblocking Due to IRET,
+//! 27.2.4 Information for VM Exits During Event Delivery, 27.6 EVENT INJECTION, 27.7.3 Delivery of Pending Debug
+//! Exceptions after VM Entry and 18.2.3 Debug Status Register (DR6).
+
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            support::{try_vmread, try_vmwrite},
+            vmerror::{ExceptionInterrupt, InterruptionType},
+        },
+        utils::{
+            addresses::Gva,
+            cpu,
+            instructions::{cr2_write, cr8, dr6, dr6_write},
+        },
+    },
+    x86::vmx::vmcs::{self, control::PrimaryControls},
+};
+
+/// The maximum number of events pending at once.
+pub const MAX_PENDING_EVENTS: usize = 8;
+
+/// The valid bit of the interruption-information fields.
+const INTERRUPTION_INFO_VALID: u32 = 1 << 31;
+
+/// The "error code valid" bit of the interruption-information fields.
+const INTERRUPTION_INFO_ERROR_CODE: u32 = 1 << 11;
+
+/// RFLAGS.IF.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Blocking by STI in the guest interruptibility state.
+const BLOCKING_BY_STI: u64 = 1 << 0;
+
+/// Blocking by MOV SS in the guest interruptibility state.
+const BLOCKING_BY_MOV_SS: u64 = 1 << 1;
+
+/// Blocking by NMI in the guest interruptibility state.
+const BLOCKING_BY_NMI: u64 = 1 << 3;
+
+/// The HLT guest activity state.
+const ACTIVITY_STATE_HLT: u64 = 1;
+
+/// The shutdown guest activity state.
+const ACTIVITY_STATE_SHUTDOWN: u64 = 2;
+
+/// The wait-for-SIPI guest activity state.
+const ACTIVITY_STATE_WAIT_FOR_SIPI: u64 = 3;
+
+/// The bits of DR6, and of the pending debug exceptions, reporting debug exceptions: B0-B3, BD and BS.
+pub const DR6_DEBUG_EXCEPTIONS: u64 = 0xF | (1 << 13) | (1 << 14);
+
+/// The BS bit of DR6 and of the pending debug exceptions, reporting a single-step trap.
+const DR6_BS: u64 = 1 << 14;
+
+/// RFLAGS.TF.
+const RFLAGS_TF: u64 = 1 << 8;
+
+/// The priority of an event, highest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EventPriority {
+    /// Machine checks.
+    MachineCheck,
+
+    /// Debug exceptions and traps on the previous instruction (INT1).
+    Trap,
+
+    /// Non-maskable interrupts.
+    Nmi,
+
+    /// Maskable external interrupts.
+    ExternalInterrupt,
+
+    /// Faults and software exceptions raised by the current instruction.
+    Exception,
+}
+
+/// The guest state deciding which events the guest can take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryState {
+    /// The guest RFLAGS.
+    pub rflags: u64,
+
+    /// The guest interruptibility state.
+    pub interruptibility: u64,
+
+    /// The guest activity state.
+    pub activity_state: u64,
+
+    /// The task priority of the guest, CR8.
+    pub tpr: u64,
+}
+
+impl DeliveryState {
+    /// Reads the state of the guest from the VMCS and CR8.
+    pub fn capture() -> Result<Self, HypervisorError> {
+        Ok(Self {
+            rflags: try_vmread(vmcs::guest::RFLAGS)?,
+            interruptibility: try_vmread(vmcs::guest::INTERRUPTIBILITY_STATE)?,
+            activity_state: try_vmread(vmcs::guest::ACTIVITY_STATE)?,
+            tpr: cr8(),
+        })
+    }
+}
+
+/// The class of an exception, deciding whether two exceptions merge into a double fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExceptionClass {
+    Benign,
+    Contributory,
+    PageFault,
+    DoubleFault,
+}
+
+/// The processor state an exception loads when it is delivered, which VM entry does not load for an injected one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionPayload {
+    /// The faulting linear address of a page fault, loaded into CR2.
+    PageFault(Gva),
+
+    /// The debug exceptions reported by a debug exception, set in DR6 (`DR6_DEBUG_EXCEPTIONS`).
+    Debug(u64),
+}
+
+impl ExceptionPayload {
+    /// Loads the payload into the guest state, as the processor does when it delivers the exception.
+    ///
+    /// The debug exceptions pending in the VMCS are reported in DR6 as well and cleared, as the injected #DB
+    /// delivers them.
+    fn deliver(&self) -> Result<(), HypervisorError> {
+        match *self {
+            Self::PageFault(address) => cr2_write(address.as_u64()),
+            Self::Debug(exceptions) => {
+                let pending = try_vmread(vmcs::guest::PENDING_DBG_EXCEPTIONS)?;
+                dr6_write(dr6() | ((exceptions | pending) & DR6_DEBUG_EXCEPTIONS));
+                try_vmwrite(
+                    vmcs::guest::PENDING_DBG_EXCEPTIONS,
+                    pending & !DR6_DEBUG_EXCEPTIONS,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An event waiting to be injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingEvent {
+    /// The vector of the interrupt or exception.
+    pub vector: u8,
+
+    /// The type of the event.
+    pub interruption_type: InterruptionType,
+
+    /// The error code pushed by the exception, if any.
+    pub error_code: Option<u32>,
+
+    /// The length of the instruction raising a software interrupt or exception, 0 otherwise.
+    pub instruction_len: u32,
+
+    /// The state loaded on delivery of a page fault or debug exception, if any.
+    pub payload: Option<ExceptionPayload>,
+}
+
+impl PendingEvent {
+    /// Creates a hardware exception.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The exception.
+    /// * `error_code` - The error code pushed by the exception, if any.
+    pub fn exception(vector: ExceptionInterrupt, error_code: Option<u32>) -> Self {
+        Self {
+            vector: vector as u8,
+            interruption_type: InterruptionType::HardwareException,
+            error_code,
+            instruction_len: 0,
+            payload: None,
+        }
+    }
+
+    /// Creates a page fault, loading CR2 with the faulting address when it is injected.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The faulting linear address.
+    /// * `error_code` - The page-fault error code.
+    pub fn page_fault(address: Gva, error_code: u32) -> Self {
+        Self::exception(ExceptionInterrupt::PageFault, Some(error_code))
+            .with_payload(ExceptionPayload::PageFault(address))
+    }
+
+    /// Creates a debug exception, setting the debug exceptions it reports in DR6 when it is injected.
+    ///
+    /// # Arguments
+    ///
+    /// * `exceptions` - The debug exceptions, in the format of DR6.
+    pub fn debug(exceptions: u64) -> Self {
+        Self::exception(ExceptionInterrupt::Debug, None)
+            .with_payload(ExceptionPayload::Debug(exceptions & DR6_DEBUG_EXCEPTIONS))
+    }
+
+    /// Attaches the state loaded when the event is injected.
+    pub fn with_payload(mut self, payload: ExceptionPayload) -> Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    /// Creates an external interrupt.
+    pub fn external_interrupt(vector: u8) -> Self {
+        Self {
+            vector,
+            interruption_type: InterruptionType::ExternalInterrupt,
+            error_code: None,
+            instruction_len: 0,
+            payload: None,
+        }
+    }
+
+    /// Creates a non-maskable interrupt.
+    pub fn nmi() -> Self {
+        Self {
+            vector: ExceptionInterrupt::NonMaskableInterrupt as u8,
+            interruption_type: InterruptionType::NonMaskableInterrupt,
+            error_code: None,
+            instruction_len: 0,
+            payload: None,
+        }
+    }
+
+    /// Decodes an event from the format of the interruption-information fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `info` - The interruption information.
+    /// * `error_code` - The error code, used if the information says one is delivered.
+    /// * `instruction_len` - The instruction length, used for software interrupts and exceptions.
+    ///
+    /// # Returns
+    ///
+    /// The event, or `None` if the information is not valid.
+    pub fn from_interruption_info(
+        info: u32,
+        error_code: u32,
+        instruction_len: u32,
+    ) -> Option<Self> {
+        if info & INTERRUPTION_INFO_VALID == 0 {
+            return None;
+        }
+
+        let interruption_type = InterruptionType::from_bits(((info >> 8) & 0x7) as u8)?;
+
+        let instruction_len = match interruption_type {
+            InterruptionType::SoftwareInterrupt
+            | InterruptionType::PrivilegedSoftwareException
+            | InterruptionType::SoftwareException => instruction_len,
+            _ => 0,
+        };
+
+        Some(Self {
+            vector: info as u8,
+            interruption_type,
+            error_code: (info & INTERRUPTION_INFO_ERROR_CODE != 0).then_some(error_code),
+            instruction_len,
+            payload: None,
+        })
+    }
+
+    /// Encodes the event in the format of the VM-entry interruption-information field.
+    pub fn interruption_info(&self) -> u32 {
+        let error_code = match self.error_code {
+            Some(_) => INTERRUPTION_INFO_ERROR_CODE,
+            None => 0,
+        };
+
+        u32::from(self.vector)
+            | ((self.interruption_type as u32) << 8)
+            | error_code
+            | INTERRUPTION_INFO_VALID
+    }
+
+    /// Returns the priority of the event.
+    pub fn priority(&self) -> EventPriority {
+        match self.interruption_type {
+            InterruptionType::NonMaskableInterrupt => EventPriority::Nmi,
+            InterruptionType::ExternalInterrupt => EventPriority::ExternalInterrupt,
+            InterruptionType::PrivilegedSoftwareException => EventPriority::Trap,
+            InterruptionType::HardwareException
+                if self.vector == ExceptionInterrupt::MachineCheck as u8 =>
+            {
+                EventPriority::MachineCheck
+            }
+            InterruptionType::HardwareException
+                if self.vector == ExceptionInterrupt::Debug as u8 =>
+            {
+                EventPriority::Trap
+            }
+            _ => EventPriority::Exception,
+        }
+    }
+
+    /// Returns whether the guest can take the event in its current state.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The guest state deciding which events it can take.
+    pub fn is_deliverable(&self, state: &DeliveryState) -> bool {
+        let interruptibility = state.interruptibility;
+
+        let unblocked = match self.interruption_type {
+            InterruptionType::ExternalInterrupt => {
+                state.rflags & RFLAGS_IF != 0
+                    && interruptibility & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) == 0
+                    && !self.is_masked_by_tpr(state.tpr)
+            }
+            InterruptionType::NonMaskableInterrupt => {
+                interruptibility & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS | BLOCKING_BY_NMI) == 0
+            }
+            _ => true,
+        };
+
+        // VM entry only injects the events that would wake the guest from its activity state.
+        let awakes = match state.activity_state {
+            ACTIVITY_STATE_HLT => {
+                matches!(
+                    self.priority(),
+                    EventPriority::MachineCheck
+                        | EventPriority::Nmi
+                        | EventPriority::ExternalInterrupt
+                ) || (self.interruption_type == InterruptionType::HardwareException
+                    && self.vector == ExceptionInterrupt::Debug as u8)
+            }
+            ACTIVITY_STATE_SHUTDOWN => matches!(
+                self.priority(),
+                EventPriority::MachineCheck | EventPriority::Nmi
+            ),
+            ACTIVITY_STATE_WAIT_FOR_SIPI => false,
+            _ => true,
+        };
+
+        unblocked && awakes
+    }
+
+    /// Returns whether the event is an external interrupt whose priority class does not exceed the task priority.
+    ///
+    /// # Arguments
+    ///
+    /// * `tpr` - The task priority of the guest, CR8.
+    pub fn is_masked_by_tpr(&self, tpr: u64) -> bool {
+        self.interruption_type == InterruptionType::ExternalInterrupt
+            && u64::from(self.vector >> 4) <= tpr
+    }
+
+    /// Returns the class of a hardware exception, or `None` for any other event.
+    fn exception_class(&self) -> Option<ExceptionClass> {
+        if self.interruption_type != InterruptionType::HardwareException {
+            return None;
+        }
+
+        let class = match ExceptionInterrupt::from_u32(u32::from(self.vector)) {
+            Some(
+                ExceptionInterrupt::DivisionError
+                | ExceptionInterrupt::InvalidTSS
+                | ExceptionInterrupt::SegmentNotPresent
+                | ExceptionInterrupt::StackSegmentFault
+                | ExceptionInterrupt::GeneralProtectionFault,
+            ) => ExceptionClass::Contributory,
+            Some(ExceptionInterrupt::PageFault) => ExceptionClass::PageFault,
+            Some(ExceptionInterrupt::DoubleFault) => ExceptionClass::DoubleFault,
+            _ => ExceptionClass::Benign,
+        };
+
+        Some(class)
+    }
+}
+
+/// The events pending on a processor.
+#[derive(Debug, Default)]
+pub struct EventQueue {
+    /// The pending events, oldest first.
+    events: [Option<PendingEvent>; MAX_PENDING_EVENTS],
+
+    /// Whether interrupt-window exiting is enabled in the VMCS.
+    interrupt_window: bool,
+
+    /// Whether NMI-window exiting is enabled in the VMCS.
+    nmi_window: bool,
+
+    /// Whether CR8-load exiting is enabled in the VMCS for an interrupt masked by the TPR.
+    tpr_window: bool,
+
+    /// Whether CR8-load exiting was already enabled when the TPR window opened, so it is kept when it closes.
+    cr8_load_exiting: bool,
+}
+
+impl EventQueue {
+    /// Creates an empty queue.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of pending events.
+    pub fn len(&self) -> usize {
+        self.events.iter().flatten().count()
+    }
+
+    /// Returns whether no event is pending.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Discards the pending events, e.g. when the guest state they were raised in is discarded.
+    pub fn clear(&mut self) {
+        self.events = Default::default();
+    }
+
+    /// Discards the pending exceptions and software interrupts, keeping the NMIs, machine checks and external
+    /// interrupts, e.g. when the code that raised the exceptions is discarded.
+    pub fn retain_interrupts(&mut self) {
+        for slot in self.events.iter_mut() {
+            let interrupt = slot.is_some_and(|event| {
+                matches!(
+                    event.priority(),
+                    EventPriority::MachineCheck
+                        | EventPriority::Nmi
+                        | EventPriority::ExternalInterrupt
+                )
+            });
+            if !interrupt {
+                *slot = None;
+            }
+        }
+        self.compact();
+    }
+
+    /// Queues an event.
+    ///
+    /// A hardware exception queued while another one is pending is treated as raised during the delivery of
+    /// the first one: a contributory exception or a page fault on top of a contributory exception or a page
+    /// fault becomes a double fault, and any other exception replaces the first one, which is raised again
+    /// when the instruction is executed again.
+    ///
+    /// # Returns
+    ///
+    /// `HypervisorError::EventQueueFull` if too many events are pending.
+    pub fn push(&mut self, event: PendingEvent) -> Result<(), HypervisorError> {
+        if let Some(class) = event.exception_class() {
+            let pending = self.events.iter_mut().find(|slot| {
+                slot.as_ref()
+                    .is_some_and(|pending| pending.exception_class().is_some())
+            });
+
+            if let Some(Some(first)) = pending {
+                *first = match (first.exception_class(), class) {
+                    (
+                        Some(ExceptionClass::DoubleFault),
+                        ExceptionClass::Contributory | ExceptionClass::PageFault,
+                    ) => {
+                        // The processor would shut down. Keep the double fault, the guest handles it as fatal.
+                        log::error!(
+                            "Exception {} raised while delivering a double fault",
+                            event.vector
+                        );
+                        *first
+                    }
+                    (Some(ExceptionClass::Contributory), ExceptionClass::Contributory)
+                    | (
+                        Some(ExceptionClass::PageFault),
+                        ExceptionClass::Contributory | ExceptionClass::PageFault,
+                    ) => {
+                        log::trace!(
+                            "Exception {} raised while delivering exception {}, merging into #DF",
+                            event.vector,
+                            first.vector
+                        );
+                        // A page fault loads CR2 when it is detected, even if it is delivered as a double fault.
+                        if let Some(payload) = event.payload {
+                            payload.deliver()?;
+                        }
+                        PendingEvent::exception(ExceptionInterrupt::DoubleFault, Some(0))
+                    }
+                    _ => event,
+                };
+
+                return Ok(());
+            }
+        }
+
+        let Some(slot) = self.events.iter_mut().find(|slot| slot.is_none()) else {
+            log::error!("Event queue full, dropping {:?}", event);
+            return Err(HypervisorError::EventQueueFull);
+        };
+
+        *slot = Some(event);
+
+        Ok(())
+    }
+
+    /// Removes and returns the highest priority event the guest can take, the oldest among equals.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The guest state deciding which events it can take.
+    pub fn pop(&mut self, state: &DeliveryState) -> Option<PendingEvent> {
+        let index = self
+            .events
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.map(|event| (i, event)))
+            .filter(|(_, event)| event.is_deliverable(state))
+            .min_by_key(|(i, event)| (event.priority(), *i))
+            .map(|(i, _)| i)?;
+
+        let event = self.events.get_mut(index).and_then(Option::take);
+        self.compact();
+        event
+    }
+
+    /// Queues the event whose delivery caused the VM exit, so it is injected again.
+    ///
+    /// Must be called before the exit is handled.
+    pub fn capture_idt_vectoring(&mut self) -> Result<(), HypervisorError> {
+        let info = try_vmread(vmcs::ro::IDT_VECTORING_INFO)? as u32;
+        if info & INTERRUPTION_INFO_VALID == 0 {
+            return Ok(());
+        }
+
+        let event = PendingEvent::from_interruption_info(
+            info,
+            try_vmread(vmcs::ro::IDT_VECTORING_ERR_CODE)? as u32,
+            try_vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN)? as u32,
+        );
+
+        match event {
+            Some(event) => {
+                log::trace!("Re-injecting interrupted event {:?}", event);
+                self.push(event)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Injects the next event on VM entry.
+    ///
+    /// The event injected by the exit handler through `EventInjection`, if any, is queued first. The highest
+    /// priority event the guest can take is then injected and the remaining exceptions are discarded.
+    /// Interrupt-window exiting is enabled while an external interrupt waits for the guest to enable
+    /// interrupts, CR8-load exiting while it waits for the guest to lower its task priority, and NMI-window
+    /// exiting while an NMI waits for the guest to unblock NMIs.
+    ///
+    /// Must be called after the exit is handled, once the guest state is final.
+    pub fn inject(&mut self) -> Result<(), HypervisorError> {
+        let injected = try_vmread(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD)? as u32;
+
+        if injected & INTERRUPTION_INFO_VALID != 0 {
+            let event = PendingEvent::from_interruption_info(
+                injected,
+                try_vmread(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE)? as u32,
+                try_vmread(vmcs::control::VMENTRY_INSTRUCTION_LEN)? as u32,
+            );
+
+            if let Some(event) = event {
+                self.push(event)?;
+            }
+        }
+
+        if self.is_empty() && !self.interrupt_window && !self.nmi_window && !self.tpr_window {
+            return Ok(());
+        }
+
+        let state = DeliveryState::capture()?;
+
+        match self.pop(&state) {
+            Some(event) => {
+                log::trace!("Injecting {:?}", event);
+
+                if let Some(payload) = event.payload {
+                    payload.deliver()?;
+                }
+
+                if let Some(error_code) = event.error_code {
+                    try_vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code)?;
+                }
+                try_vmwrite(
+                    vmcs::control::VMENTRY_INSTRUCTION_LEN,
+                    event.instruction_len,
+                )?;
+                try_vmwrite(
+                    vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
+                    event.interruption_info(),
+                )?;
+            }
+            None => try_vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, 0u32)?,
+        }
+
+        for slot in self.events.iter_mut() {
+            if slot.is_some_and(|event| event.priority() == EventPriority::Exception) {
+                *slot = None;
+            }
+        }
+        self.compact();
+
+        let waiting =
+            |filter: &dyn Fn(&PendingEvent) -> bool| self.events.iter().flatten().any(filter);
+
+        let interrupt_window = waiting(&|event| {
+            event.priority() == EventPriority::ExternalInterrupt
+                && !event.is_masked_by_tpr(state.tpr)
+        });
+        let tpr_window = waiting(&|event| event.is_masked_by_tpr(state.tpr));
+        let nmi_window =
+            waiting(&|event| event.priority() == EventPriority::Nmi) && cpu::has_virtual_nmis();
+
+        self.set_window_exiting(interrupt_window, nmi_window, tpr_window)
+    }
+
+    /// Restores the virtual-NMI blocking removed by an IRET that caused the VM exit.
+    ///
+    /// An IRET faulting or causing an EPT violation has already unblocked NMIs when the VM exit occurs. As
+    /// the IRET is executed again on VM entry, NMIs must be blocked again until it completes, unless the
+    /// exit occurred during event delivery.
+    ///
+    /// # Arguments
+    ///
+    /// * `nmi_unblocking_due_to_iret` - The "NMI unblocking due to IRET" bit of the exit information.
+    pub fn restore_virtual_nmi_blocking(
+        &self,
+        nmi_unblocking_due_to_iret: bool,
+    ) -> Result<(), HypervisorError> {
+        if !nmi_unblocking_due_to_iret || !cpu::has_virtual_nmis() {
+            return Ok(());
+        }
+
+        if try_vmread(vmcs::ro::IDT_VECTORING_INFO)? as u32 & INTERRUPTION_INFO_VALID != 0 {
+            return Ok(());
+        }
+
+        let interruptibility = try_vmread(vmcs::guest::INTERRUPTIBILITY_STATE)?;
+        try_vmwrite(
+            vmcs::guest::INTERRUPTIBILITY_STATE,
+            interruptibility | BLOCKING_BY_NMI,
+        )
+    }
+
+    /// Enables or disables interrupt-window, NMI-window and CR8-load exiting, skipping the VMCS access if they
+    /// are already set. CR8-load exiting enabled by the VMCS setup stays enabled.
+    fn set_window_exiting(
+        &mut self,
+        interrupt_window: bool,
+        nmi_window: bool,
+        tpr_window: bool,
+    ) -> Result<(), HypervisorError> {
+        if self.interrupt_window == interrupt_window
+            && self.nmi_window == nmi_window
+            && self.tpr_window == tpr_window
+        {
+            return Ok(());
+        }
+
+        let controls = try_vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS)?;
+        let cr8_load = PrimaryControls::CR8_LOAD_EXITING.bits() as u64;
+        if tpr_window && !self.tpr_window {
+            self.cr8_load_exiting = controls & cr8_load != 0;
+        }
+
+        let windows = [
+            (PrimaryControls::INTERRUPT_WINDOW_EXITING, interrupt_window),
+            (PrimaryControls::NMI_WINDOW_EXITING, nmi_window),
+            (
+                PrimaryControls::CR8_LOAD_EXITING,
+                tpr_window || self.cr8_load_exiting,
+            ),
+        ];
+
+        let controls = windows
+            .iter()
+            .fold(controls, |controls, (window, enable)| match enable {
+                true => controls | window.bits() as u64,
+                false => controls & !(window.bits() as u64),
+            });
+
+        try_vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, controls)?;
+        self.interrupt_window = interrupt_window;
+        self.nmi_window = nmi_window;
+        self.tpr_window = tpr_window;
+
+        Ok(())
+    }
+
+    /// Moves the pending events to the front of the queue, keeping their order.
+    fn compact(&mut self) {
+        let mut next = 0;
+
+        for i in 0..MAX_PENDING_EVENTS {
+            let event = self.events.get_mut(i).and_then(Option::take);
+            if let Some(slot) = self.events.get_mut(next) {
+                if event.is_some() {
+                    *slot = event;
+                    next += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Records the single-step trap of an instruction emulated on behalf of the guest, if the guest single-steps.
+///
+/// The trap is added to the pending debug exceptions of the VMCS, keeping those already pending, and delivered by
+/// the processor after VM entry, as it would have after the instruction. Must be called whenever the guest RIP is
+/// advanced past an emulated instruction.
+///
+/// # Arguments
+///
+/// * `rflags` - The guest RFLAGS.
+pub fn raise_single_step_trap(rflags: u64) -> Result<(), HypervisorError> {
+    if rflags & RFLAGS_TF == 0 {
+        return Ok(());
+    }
+
+    let pending = try_vmread(vmcs::guest::PENDING_DBG_EXCEPTIONS)?;
+    try_vmwrite(vmcs::guest::PENDING_DBG_EXCEPTIONS, pending | DR6_BS)
+}
+
+/// Retires the STI or MOV SS shadow of the guest once an instruction is emulated on its behalf.
+///
+/// The shadow only covers the instruction following STI or MOV SS. That instruction completed in VMX root
+/// operation, so the events it blocked can be taken on VM entry. Must be called whenever the guest RIP is advanced
+/// past an emulated instruction.
+pub fn retire_interrupt_shadow() -> Result<(), HypervisorError> {
+    let interruptibility = try_vmread(vmcs::guest::INTERRUPTIBILITY_STATE)?;
+    if interruptibility & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) == 0 {
+        return Ok(());
+    }
+
+    try_vmwrite(
+        vmcs::guest::INTERRUPTIBILITY_STATE,
+        interruptibility & !(BLOCKING_BY_STI | BLOCKING_BY_MOV_SS),
+    )
+}
@@ -2,11 +2,20 @@
 //! It handles the representation, manipulation, and injection of various types of events.
 
 #![allow(dead_code)]
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
 
 use {
-    crate::intel::{
-        support::vmwrite,
-        vmerror::{ExceptionInterrupt, InterruptionType},
+    crate::{
+        error::HypervisorError,
+        intel::{
+            support::try_vmwrite,
+            vmerror::{ExceptionInterrupt, InterruptionType},
+        },
     },
     bitfield::bitfield,
     x86::vmx::vmcs,
@@ -107,12 +116,12 @@ impl EventInjection {
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
     /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
-    pub fn vmentry_inject_gp(error_code: u32) {
-        vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
-        vmwrite(
+    pub fn vmentry_inject_gp(error_code: u32) -> Result<(), HypervisorError> {
+        try_vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code)?;
+        try_vmwrite(
             vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
             EventInjection::general_protection(),
-        );
+        )
     }
 
     /// Injects a page fault into the guest.
@@ -126,12 +135,12 @@ impl EventInjection {
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
     /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
-    pub fn vmentry_inject_pf(error_code: u32) {
-        vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
-        vmwrite(
+    pub fn vmentry_inject_pf(error_code: u32) -> Result<(), HypervisorError> {
+        try_vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code)?;
+        try_vmwrite(
             vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
             EventInjection::page_fault(),
-        );
+        )
     }
 
     /// Injects a breakpoint exception into the guest.
@@ -141,11 +150,11 @@ impl EventInjection {
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
     /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
-    pub fn vmentry_inject_bp() {
-        vmwrite(
+    pub fn vmentry_inject_bp() -> Result<(), HypervisorError> {
+        try_vmwrite(
             vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
             EventInjection::breakpoint(),
-        );
+        )
     }
 
     /// Injects an undefined opcode exception into the guest.
@@ -155,10 +164,10 @@ impl EventInjection {
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
     /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
-    pub fn vmentry_inject_ud() {
-        vmwrite(
+    pub fn vmentry_inject_ud() -> Result<(), HypervisorError> {
+        try_vmwrite(
             vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
             EventInjection::undefined_opcode(),
-        );
+        )
     }
 }
@@ -0,0 +1,622 @@
+//! The enlightened VMCS (eVMCS), used instead of VMREAD and VMWRITE when running as an L1 under Hyper-V.
+//!
+//! Nested under Hyper-V, every VMREAD and VMWRITE traps to L0. With the enlightened VMCS, the VMCS is instead an
+//! ordinary page in a format documented by Hyper-V, which the hypervisor reads and writes in memory, and which L0
+//! consumes on VM entry and fills in on VM exit. The eVMCS is made current by writing its physical address to the
+//! VP assist page of the processor instead of executing VMPTRLD; VMCLEAR, VMLAUNCH and VMRESUME are unchanged.
+//!
+//! `support::try_vmread` and `support::try_vmwrite` go through the eVMCS of the processor once it is loaded, so
+//! the rest of the hypervisor keeps addressing fields by their VMCS encoding. The fields and controls without a
+//! place in version 1 of the format (posted interrupts, the VMX-preemption timer, TSC scaling, PAUSE-loop exiting,
+//! VM functions, ...) are reported as unsupported.
+//!
+//! Every write marks all fields dirty, so that L0 reloads them on the next VM entry, and every VM exit marks them
+//! clean again, see `begin_exit`.
+//!
+//! Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/nested-virtualization
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{controls::VmxControl, percpu::PerCpu, vmcs::Vmcs},
+        utils::{
+            addresses::{Hpa, PhysicalAddress},
+            instructions::rdmsr,
+            processor::current_processor_index,
+        },
+    },
+    core::{
+        mem::{offset_of, size_of},
+        ptr::{self, NonNull},
+        sync::atomic::{AtomicBool, Ordering},
+    },
+    static_assertions::const_assert,
+    x86::vmx::vmcs::{
+        control::{self, EntryControls, ExitControls, PinbasedControls, SecondaryControls},
+        guest, host, ro,
+    },
+};
+
+/// The version of the enlightened VMCS format implemented by `EnlightenedVmcs`, written as its revision ID.
+pub const EVMCS_VERSION: u8 = 1;
+
+/// The MSR enabling the VP assist page and holding its page frame number.
+const HV_X64_MSR_VP_ASSIST_PAGE: u32 = 0x4000_0073;
+
+/// HV_X64_MSR_VP_ASSIST_PAGE bit enabling the VP assist page.
+const VP_ASSIST_PAGE_ENABLE: u64 = 1 << 0;
+
+/// The `hv_clean_fields` value telling L0 that no field changed since the last VM exit.
+const CLEAN_FIELDS_ALL: u32 = 0xFFFF;
+
+/// Whether the enlightened VMCS is used on the processors that can load it, see `enable`.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The enlightened VMCS loaded on each processor.
+static CURRENT: PerCpu<EnlightenedVmcs> = PerCpu::new();
+
+/// Version 1 of the enlightened VMCS format. It is laid out in the VMCS region of the processor.
+#[repr(C)]
+pub struct EnlightenedVmcs {
+    revision_id: u32,
+    abort: u32,
+
+    host_es_selector: u16,
+    host_cs_selector: u16,
+    host_ss_selector: u16,
+    host_ds_selector: u16,
+    host_fs_selector: u16,
+    host_gs_selector: u16,
+    host_tr_selector: u16,
+    padding16_1: u16,
+
+    host_ia32_pat: u64,
+    host_ia32_efer: u64,
+    host_cr0: u64,
+    host_cr3: u64,
+    host_cr4: u64,
+    host_ia32_sysenter_esp: u64,
+    host_ia32_sysenter_eip: u64,
+    host_rip: u64,
+    host_ia32_sysenter_cs: u32,
+
+    pin_based_vm_exec_control: u32,
+    vm_exit_controls: u32,
+    secondary_vm_exec_control: u32,
+
+    io_bitmap_a: u64,
+    io_bitmap_b: u64,
+    msr_bitmap: u64,
+
+    guest_es_selector: u16,
+    guest_cs_selector: u16,
+    guest_ss_selector: u16,
+    guest_ds_selector: u16,
+    guest_fs_selector: u16,
+    guest_gs_selector: u16,
+    guest_ldtr_selector: u16,
+    guest_tr_selector: u16,
+
+    guest_es_limit: u32,
+    guest_cs_limit: u32,
+    guest_ss_limit: u32,
+    guest_ds_limit: u32,
+    guest_fs_limit: u32,
+    guest_gs_limit: u32,
+    guest_ldtr_limit: u32,
+    guest_tr_limit: u32,
+    guest_gdtr_limit: u32,
+    guest_idtr_limit: u32,
+
+    guest_es_ar_bytes: u32,
+    guest_cs_ar_bytes: u32,
+    guest_ss_ar_bytes: u32,
+    guest_ds_ar_bytes: u32,
+    guest_fs_ar_bytes: u32,
+    guest_gs_ar_bytes: u32,
+    guest_ldtr_ar_bytes: u32,
+    guest_tr_ar_bytes: u32,
+
+    guest_es_base: u64,
+    guest_cs_base: u64,
+    guest_ss_base: u64,
+    guest_ds_base: u64,
+    guest_fs_base: u64,
+    guest_gs_base: u64,
+    guest_ldtr_base: u64,
+    guest_tr_base: u64,
+    guest_gdtr_base: u64,
+    guest_idtr_base: u64,
+
+    padding64_1: [u64; 3],
+
+    vm_exit_msr_store_addr: u64,
+    vm_exit_msr_load_addr: u64,
+    vm_entry_msr_load_addr: u64,
+
+    cr3_target_value0: u64,
+    cr3_target_value1: u64,
+    cr3_target_value2: u64,
+    cr3_target_value3: u64,
+
+    page_fault_error_code_mask: u32,
+    page_fault_error_code_match: u32,
+
+    cr3_target_count: u32,
+    vm_exit_msr_store_count: u32,
+    vm_exit_msr_load_count: u32,
+    vm_entry_msr_load_count: u32,
+
+    tsc_offset: u64,
+    virtual_apic_page_addr: u64,
+    vmcs_link_pointer: u64,
+
+    guest_ia32_debugctl: u64,
+    guest_ia32_pat: u64,
+    guest_ia32_efer: u64,
+
+    guest_pdptr0: u64,
+    guest_pdptr1: u64,
+    guest_pdptr2: u64,
+    guest_pdptr3: u64,
+
+    guest_pending_dbg_exceptions: u64,
+    guest_sysenter_esp: u64,
+    guest_sysenter_eip: u64,
+
+    guest_activity_state: u32,
+    guest_sysenter_cs: u32,
+
+    cr0_guest_host_mask: u64,
+    cr4_guest_host_mask: u64,
+    cr0_read_shadow: u64,
+    cr4_read_shadow: u64,
+    guest_cr0: u64,
+    guest_cr3: u64,
+    guest_cr4: u64,
+    guest_dr7: u64,
+
+    host_fs_base: u64,
+    host_gs_base: u64,
+    host_tr_base: u64,
+    host_gdtr_base: u64,
+    host_idtr_base: u64,
+    host_rsp: u64,
+
+    ept_pointer: u64,
+
+    virtual_processor_id: u16,
+    padding16_2: [u16; 3],
+
+    padding64_2: [u64; 5],
+    guest_physical_address: u64,
+
+    vm_instruction_error: u32,
+    vm_exit_reason: u32,
+    vm_exit_intr_info: u32,
+    vm_exit_intr_error_code: u32,
+    idt_vectoring_info_field: u32,
+    idt_vectoring_error_code: u32,
+    vm_exit_instruction_len: u32,
+    vmx_instruction_info: u32,
+
+    exit_qualification: u64,
+    exit_io_instruction_ecx: u64,
+    exit_io_instruction_esi: u64,
+    exit_io_instruction_edi: u64,
+    exit_io_instruction_eip: u64,
+
+    guest_linear_address: u64,
+    guest_rsp: u64,
+    guest_rflags: u64,
+
+    guest_interruptibility_info: u32,
+    cpu_based_vm_exec_control: u32,
+    exception_bitmap: u32,
+    vm_entry_controls: u32,
+    vm_entry_intr_info_field: u32,
+    vm_entry_exception_error_code: u32,
+    vm_entry_instruction_len: u32,
+    tpr_threshold: u32,
+
+    guest_rip: u64,
+
+    hv_clean_fields: u32,
+    padding32_1: u32,
+    hv_synthetic_controls: u32,
+    hv_enlightenments_control: u32,
+    hv_vp_id: u32,
+    padding32_2: u32,
+    hv_vm_id: u64,
+    partition_assist_page: u64,
+    padding64_4: [u64; 4],
+    guest_bndcfgs: u64,
+    guest_ia32_perf_global_ctrl: u64,
+    guest_ia32_s_cet: u64,
+    guest_ssp: u64,
+    guest_ia32_int_ssp_table_addr: u64,
+    guest_ia32_lbr_ctl: u64,
+    padding64_5: [u64; 2],
+    xss_exit_bitmap: u64,
+    encls_exiting_bitmap: u64,
+    host_ia32_perf_global_ctrl: u64,
+    tsc_multiplier: u64,
+    host_ia32_s_cet: u64,
+    host_ssp: u64,
+    host_ia32_int_ssp_table_addr: u64,
+    padding64_6: u64,
+}
+
+// The eVMCS takes the place of the VMCS in its region.
+const_assert!(size_of::<EnlightenedVmcs>() <= size_of::<Vmcs>());
+
+/// The start of the VP assist page of a processor, shared with Hyper-V.
+#[repr(C)]
+pub struct VpAssistPage {
+    apic_assist: u32,
+    reserved1: u32,
+    vtl_entry_reason: u32,
+    vtl_reserved: u32,
+    vtl_ret_x64rax: u64,
+    vtl_ret_x64rcx: u64,
+    nested_control: u64,
+
+    /// Whether VM entries use the eVMCS in `current_nested_vmcs`.
+    enlighten_vmentry: u8,
+    reserved2: [u8; 7],
+
+    /// The physical address of the current eVMCS.
+    current_nested_vmcs: u64,
+}
+
+impl EnlightenedVmcs {
+    /// Returns the offset of the field with the given VMCS encoding, or `None` if the format has no place for it.
+    /// The width of the field follows from the encoding, see `FieldWidth`.
+    fn offset_of_field(field: u32) -> Option<usize> {
+        let offset = match field {
+            host::ES_SELECTOR => offset_of!(Self, host_es_selector),
+            host::CS_SELECTOR => offset_of!(Self, host_cs_selector),
+            host::SS_SELECTOR => offset_of!(Self, host_ss_selector),
+            host::DS_SELECTOR => offset_of!(Self, host_ds_selector),
+            host::FS_SELECTOR => offset_of!(Self, host_fs_selector),
+            host::GS_SELECTOR => offset_of!(Self, host_gs_selector),
+            host::TR_SELECTOR => offset_of!(Self, host_tr_selector),
+            host::IA32_PAT_FULL => offset_of!(Self, host_ia32_pat),
+            host::IA32_EFER_FULL => offset_of!(Self, host_ia32_efer),
+            host::CR0 => offset_of!(Self, host_cr0),
+            host::CR3 => offset_of!(Self, host_cr3),
+            host::CR4 => offset_of!(Self, host_cr4),
+            host::IA32_SYSENTER_ESP => offset_of!(Self, host_ia32_sysenter_esp),
+            host::IA32_SYSENTER_EIP => offset_of!(Self, host_ia32_sysenter_eip),
+            host::RIP => offset_of!(Self, host_rip),
+            host::IA32_SYSENTER_CS => offset_of!(Self, host_ia32_sysenter_cs),
+            host::FS_BASE => offset_of!(Self, host_fs_base),
+            host::GS_BASE => offset_of!(Self, host_gs_base),
+            host::TR_BASE => offset_of!(Self, host_tr_base),
+            host::GDTR_BASE => offset_of!(Self, host_gdtr_base),
+            host::IDTR_BASE => offset_of!(Self, host_idtr_base),
+            host::RSP => offset_of!(Self, host_rsp),
+
+            control::PINBASED_EXEC_CONTROLS => offset_of!(Self, pin_based_vm_exec_control),
+            control::PRIMARY_PROCBASED_EXEC_CONTROLS => offset_of!(Self, cpu_based_vm_exec_control),
+            control::SECONDARY_PROCBASED_EXEC_CONTROLS => {
+                offset_of!(Self, secondary_vm_exec_control)
+            }
+            control::VMEXIT_CONTROLS => offset_of!(Self, vm_exit_controls),
+            control::VMENTRY_CONTROLS => offset_of!(Self, vm_entry_controls),
+            control::IO_BITMAP_A_ADDR_FULL => offset_of!(Self, io_bitmap_a),
+            control::IO_BITMAP_B_ADDR_FULL => offset_of!(Self, io_bitmap_b),
+            control::MSR_BITMAPS_ADDR_FULL => offset_of!(Self, msr_bitmap),
+            control::VMEXIT_MSR_STORE_ADDR_FULL => offset_of!(Self, vm_exit_msr_store_addr),
+            control::VMEXIT_MSR_LOAD_ADDR_FULL => offset_of!(Self, vm_exit_msr_load_addr),
+            control::VMENTRY_MSR_LOAD_ADDR_FULL => offset_of!(Self, vm_entry_msr_load_addr),
+            control::CR3_TARGET_VALUE0 => offset_of!(Self, cr3_target_value0),
+            control::CR3_TARGET_VALUE1 => offset_of!(Self, cr3_target_value1),
+            control::CR3_TARGET_VALUE2 => offset_of!(Self, cr3_target_value2),
+            control::CR3_TARGET_VALUE3 => offset_of!(Self, cr3_target_value3),
+            control::PAGE_FAULT_ERR_CODE_MASK => offset_of!(Self, page_fault_error_code_mask),
+            control::PAGE_FAULT_ERR_CODE_MATCH => offset_of!(Self, page_fault_error_code_match),
+            control::CR3_TARGET_COUNT => offset_of!(Self, cr3_target_count),
+            control::VMEXIT_MSR_STORE_COUNT => offset_of!(Self, vm_exit_msr_store_count),
+            control::VMEXIT_MSR_LOAD_COUNT => offset_of!(Self, vm_exit_msr_load_count),
+            control::VMENTRY_MSR_LOAD_COUNT => offset_of!(Self, vm_entry_msr_load_count),
+            control::TSC_OFFSET_FULL => offset_of!(Self, tsc_offset),
+            control::VIRT_APIC_ADDR_FULL => offset_of!(Self, virtual_apic_page_addr),
+            control::CR0_GUEST_HOST_MASK => offset_of!(Self, cr0_guest_host_mask),
+            control::CR4_GUEST_HOST_MASK => offset_of!(Self, cr4_guest_host_mask),
+            control::CR0_READ_SHADOW => offset_of!(Self, cr0_read_shadow),
+            control::CR4_READ_SHADOW => offset_of!(Self, cr4_read_shadow),
+            control::EPTP_FULL => offset_of!(Self, ept_pointer),
+            control::VPID => offset_of!(Self, virtual_processor_id),
+            control::EXCEPTION_BITMAP => offset_of!(Self, exception_bitmap),
+            control::VMENTRY_INTERRUPTION_INFO_FIELD => offset_of!(Self, vm_entry_intr_info_field),
+            control::VMENTRY_EXCEPTION_ERR_CODE => offset_of!(Self, vm_entry_exception_error_code),
+            control::VMENTRY_INSTRUCTION_LEN => offset_of!(Self, vm_entry_instruction_len),
+            control::TPR_THRESHOLD => offset_of!(Self, tpr_threshold),
+            control::XSS_EXITING_BITMAP_FULL => offset_of!(Self, xss_exit_bitmap),
+            control::ENCLS_EXITING_BITMAP_FULL => offset_of!(Self, encls_exiting_bitmap),
+
+            guest::ES_SELECTOR => offset_of!(Self, guest_es_selector),
+            guest::CS_SELECTOR => offset_of!(Self, guest_cs_selector),
+            guest::SS_SELECTOR => offset_of!(Self, guest_ss_selector),
+            guest::DS_SELECTOR => offset_of!(Self, guest_ds_selector),
+            guest::FS_SELECTOR => offset_of!(Self, guest_fs_selector),
+            guest::GS_SELECTOR => offset_of!(Self, guest_gs_selector),
+            guest::LDTR_SELECTOR => offset_of!(Self, guest_ldtr_selector),
+            guest::TR_SELECTOR => offset_of!(Self, guest_tr_selector),
+            guest::ES_LIMIT => offset_of!(Self, guest_es_limit),
+            guest::CS_LIMIT => offset_of!(Self, guest_cs_limit),
+            guest::SS_LIMIT => offset_of!(Self, guest_ss_limit),
+            guest::DS_LIMIT => offset_of!(Self, guest_ds_limit),
+            guest::FS_LIMIT => offset_of!(Self, guest_fs_limit),
+            guest::GS_LIMIT => offset_of!(Self, guest_gs_limit),
+            guest::LDTR_LIMIT => offset_of!(Self, guest_ldtr_limit),
+            guest::TR_LIMIT => offset_of!(Self, guest_tr_limit),
+            guest::GDTR_LIMIT => offset_of!(Self, guest_gdtr_limit),
+            guest::IDTR_LIMIT => offset_of!(Self, guest_idtr_limit),
+            guest::ES_ACCESS_RIGHTS => offset_of!(Self, guest_es_ar_bytes),
+            guest::CS_ACCESS_RIGHTS => offset_of!(Self, guest_cs_ar_bytes),
+            guest::SS_ACCESS_RIGHTS => offset_of!(Self, guest_ss_ar_bytes),
+            guest::DS_ACCESS_RIGHTS => offset_of!(Self, guest_ds_ar_bytes),
+            guest::FS_ACCESS_RIGHTS => offset_of!(Self, guest_fs_ar_bytes),
+            guest::GS_ACCESS_RIGHTS => offset_of!(Self, guest_gs_ar_bytes),
+            guest::LDTR_ACCESS_RIGHTS => offset_of!(Self, guest_ldtr_ar_bytes),
+            guest::TR_ACCESS_RIGHTS => offset_of!(Self, guest_tr_ar_bytes),
+            guest::ES_BASE => offset_of!(Self, guest_es_base),
+            guest::CS_BASE => offset_of!(Self, guest_cs_base),
+            guest::SS_BASE => offset_of!(Self, guest_ss_base),
+            guest::DS_BASE => offset_of!(Self, guest_ds_base),
+            guest::FS_BASE => offset_of!(Self, guest_fs_base),
+            guest::GS_BASE => offset_of!(Self, guest_gs_base),
+            guest::LDTR_BASE => offset_of!(Self, guest_ldtr_base),
+            guest::TR_BASE => offset_of!(Self, guest_tr_base),
+            guest::GDTR_BASE => offset_of!(Self, guest_gdtr_base),
+            guest::IDTR_BASE => offset_of!(Self, guest_idtr_base),
+            guest::LINK_PTR_FULL => offset_of!(Self, vmcs_link_pointer),
+            guest::IA32_DEBUGCTL_FULL => offset_of!(Self, guest_ia32_debugctl),
+            guest::IA32_PAT_FULL => offset_of!(Self, guest_ia32_pat),
+            guest::IA32_EFER_FULL => offset_of!(Self, guest_ia32_efer),
+            guest::PDPTE0_FULL => offset_of!(Self, guest_pdptr0),
+            guest::PDPTE1_FULL => offset_of!(Self, guest_pdptr1),
+            guest::PDPTE2_FULL => offset_of!(Self, guest_pdptr2),
+            guest::PDPTE3_FULL => offset_of!(Self, guest_pdptr3),
+            guest::PENDING_DBG_EXCEPTIONS => offset_of!(Self, guest_pending_dbg_exceptions),
+            guest::IA32_SYSENTER_ESP => offset_of!(Self, guest_sysenter_esp),
+            guest::IA32_SYSENTER_EIP => offset_of!(Self, guest_sysenter_eip),
+            guest::ACTIVITY_STATE => offset_of!(Self, guest_activity_state),
+            guest::IA32_SYSENTER_CS => offset_of!(Self, guest_sysenter_cs),
+            guest::CR0 => offset_of!(Self, guest_cr0),
+            guest::CR3 => offset_of!(Self, guest_cr3),
+            guest::CR4 => offset_of!(Self, guest_cr4),
+            guest::DR7 => offset_of!(Self, guest_dr7),
+            guest::RSP => offset_of!(Self, guest_rsp),
+            guest::RIP => offset_of!(Self, guest_rip),
+            guest::RFLAGS => offset_of!(Self, guest_rflags),
+            guest::INTERRUPTIBILITY_STATE => offset_of!(Self, guest_interruptibility_info),
+
+            ro::GUEST_PHYSICAL_ADDR_FULL => offset_of!(Self, guest_physical_address),
+            ro::VM_INSTRUCTION_ERROR => offset_of!(Self, vm_instruction_error),
+            ro::EXIT_REASON => offset_of!(Self, vm_exit_reason),
+            ro::VMEXIT_INTERRUPTION_INFO => offset_of!(Self, vm_exit_intr_info),
+            ro::VMEXIT_INTERRUPTION_ERR_CODE => offset_of!(Self, vm_exit_intr_error_code),
+            ro::IDT_VECTORING_INFO => offset_of!(Self, idt_vectoring_info_field),
+            ro::IDT_VECTORING_ERR_CODE => offset_of!(Self, idt_vectoring_error_code),
+            ro::VMEXIT_INSTRUCTION_LEN => offset_of!(Self, vm_exit_instruction_len),
+            ro::VMEXIT_INSTRUCTION_INFO => offset_of!(Self, vmx_instruction_info),
+            ro::EXIT_QUALIFICATION => offset_of!(Self, exit_qualification),
+            ro::IO_RCX => offset_of!(Self, exit_io_instruction_ecx),
+            ro::IO_RSI => offset_of!(Self, exit_io_instruction_esi),
+            ro::IO_RDI => offset_of!(Self, exit_io_instruction_edi),
+            ro::IO_RIP => offset_of!(Self, exit_io_instruction_eip),
+            ro::GUEST_LINEAR_ADDR => offset_of!(Self, guest_linear_address),
+
+            _ => return None,
+        };
+
+        Some(offset)
+    }
+
+    /// Reads a field by its VMCS encoding.
+    ///
+    /// # Returns
+    ///
+    /// The value of the field, zero-extended, or `None` if the format has no place for it.
+    pub fn read(&self, field: u32) -> Option<u64> {
+        let offset = Self::offset_of_field(field)?;
+        let address = unsafe { (self as *const Self as *const u8).add(offset) };
+
+        let value = unsafe {
+            match FieldWidth::of(field) {
+                FieldWidth::Word => ptr::read_volatile(address as *const u16) as u64,
+                FieldWidth::Dword => ptr::read_volatile(address as *const u32) as u64,
+                FieldWidth::Qword => ptr::read_volatile(address as *const u64),
+            }
+        };
+
+        Some(value)
+    }
+
+    /// Writes a field by its VMCS encoding, truncated to the width of the field, and marks all fields dirty.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the format has no place for the field.
+    pub fn write(&mut self, field: u32, value: u64) -> Option<()> {
+        let offset = Self::offset_of_field(field)?;
+        let address = unsafe { (self as *mut Self as *mut u8).add(offset) };
+
+        unsafe {
+            match FieldWidth::of(field) {
+                FieldWidth::Word => ptr::write_volatile(address as *mut u16, value as u16),
+                FieldWidth::Dword => ptr::write_volatile(address as *mut u32, value as u32),
+                FieldWidth::Qword => ptr::write_volatile(address as *mut u64, value),
+            }
+            ptr::write_volatile(&mut self.hv_clean_fields, 0);
+        }
+
+        Some(())
+    }
+}
+
+/// The width of a VMCS field, from bits 14:13 of its encoding.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.11.2 VMREAD, VMWRITE, and
+/// Encodings of VMCS Fields
+enum FieldWidth {
+    Word,
+    Dword,
+    /// 64-bit and natural-width fields, the latter being 64 bits wide on processors supporting Intel 64.
+    Qword,
+}
+
+impl FieldWidth {
+    fn of(field: u32) -> Self {
+        match (field >> 13) & 0b11 {
+            0 => Self::Word,
+            2 => Self::Dword,
+            _ => Self::Qword,
+        }
+    }
+}
+
+/// Uses the enlightened VMCS on the processors virtualized from now on, when Hyper-V recommends it, see
+/// `hyperv::Enlightenments::evmcs_supported`. Must be called before the processors are virtualized, as it
+/// changes the controls `controls::adjust_vmx_controls` allows.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns whether the enlightened VMCS is used on the processors that can load it.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns the controls that have no equivalent in the enlightened VMCS, and are cleared by
+/// `controls::adjust_vmx_controls` while it is enabled.
+///
+/// # Arguments
+///
+/// * `control` - The type of VMX control.
+pub fn unsupported_controls(control: VmxControl) -> u32 {
+    if !is_enabled() {
+        return 0;
+    }
+
+    match control {
+        VmxControl::PinBased => {
+            (PinbasedControls::POSTED_INTERRUPTS | PinbasedControls::VMX_PREEMPTION_TIMER).bits()
+        }
+        VmxControl::ProcessorBased => 0,
+        VmxControl::ProcessorBased2 => (SecondaryControls::VIRTUALIZE_APIC
+            | SecondaryControls::VIRTUALIZE_APIC_REGISTER
+            | SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY
+            | SecondaryControls::PAUSE_LOOP_EXITING
+            | SecondaryControls::ENABLE_VM_FUNCTIONS
+            | SecondaryControls::VMCS_SHADOWING
+            | SecondaryControls::ENABLE_PML
+            | SecondaryControls::EPT_VIOLATION_VE
+            | SecondaryControls::SUB_PAGE_EPT
+            | SecondaryControls::USE_TSC_SCALING)
+            .bits(),
+        VmxControl::VmExit => (ExitControls::LOAD_IA32_PERF_GLOBAL_CTRL
+            | ExitControls::SAVE_VMX_PREEMPTION_TIMER)
+            .bits(),
+        VmxControl::VmEntry => EntryControls::LOAD_IA32_PERF_GLOBAL_CTRL.bits(),
+    }
+}
+
+/// Returns the VP assist page of the current processor, through which its eVMCS is loaded.
+///
+/// # Returns
+///
+/// The VP assist page, or `None` if the enlightened VMCS is not enabled or the processor has no VP assist page, in
+/// which case the VMCS is loaded with VMPTRLD.
+pub fn vp_assist_page() -> Option<NonNull<VpAssistPage>> {
+    if !is_enabled() {
+        return None;
+    }
+
+    // Windows enables the VP assist page of each processor when Hyper-V offers it.
+    let vp_assist_msr = rdmsr(HV_X64_MSR_VP_ASSIST_PAGE);
+    let vp_assist = match vp_assist_msr & VP_ASSIST_PAGE_ENABLE {
+        0 => None,
+        _ => Hpa::new(vp_assist_msr & !0xFFF).to_hva(),
+    };
+
+    let vp_assist = vp_assist.and_then(|hva| NonNull::new(hva.as_mut_ptr::<VpAssistPage>()));
+    if vp_assist.is_none() {
+        log::warn!(
+            "The VP assist page is not enabled, using VMPTRLD instead of the enlightened VMCS"
+        );
+    }
+
+    vp_assist
+}
+
+/// Makes the VMCS region of the current processor its current eVMCS, in place of VMPTRLD. The region must have
+/// been cleared with VMCLEAR, with `EVMCS_VERSION` as its revision ID.
+///
+/// # Arguments
+///
+/// * `vmcs_region` - The VMCS region of the processor.
+/// * `vp_assist` - The VP assist page of the processor, see `vp_assist_page`.
+///
+/// # Returns
+///
+/// A `Result` indicating whether the eVMCS was loaded.
+pub fn load(
+    vmcs_region: &mut Vmcs,
+    mut vp_assist: NonNull<VpAssistPage>,
+) -> Result<(), HypervisorError> {
+    let evmcs = vmcs_region as *mut Vmcs as *mut EnlightenedVmcs;
+    let evmcs_pa = PhysicalAddress::pa_from_va(evmcs as u64);
+    if evmcs_pa == 0 {
+        return Err(HypervisorError::VirtualToPhysicalAddressFailed);
+    }
+
+    CURRENT.claim(current_processor_index(), evmcs)?;
+
+    unsafe {
+        ptr::write_volatile(&mut (*evmcs).hv_clean_fields, 0);
+
+        let vp_assist = vp_assist.as_mut();
+        ptr::write_volatile(&mut vp_assist.current_nested_vmcs, evmcs_pa);
+        ptr::write_volatile(&mut vp_assist.enlighten_vmentry, 1);
+    }
+
+    log::trace!("Enlightened VMCS loaded at {:#x}", evmcs_pa);
+
+    Ok(())
+}
+
+/// Stops using the eVMCS of the current processor, after it was cleared with VMCLEAR and before VMXOFF.
+///
+/// # Arguments
+///
+/// * `vp_assist` - The VP assist page the eVMCS was loaded through.
+pub fn unload(mut vp_assist: NonNull<VpAssistPage>) {
+    unsafe {
+        let vp_assist = vp_assist.as_mut();
+        ptr::write_volatile(&mut vp_assist.enlighten_vmentry, 0);
+        ptr::write_volatile(&mut vp_assist.current_nested_vmcs, 0);
+    }
+
+    CURRENT.release(current_processor_index());
+}
+
+/// Returns the eVMCS loaded on the current processor, if any.
+pub fn current() -> Option<*mut EnlightenedVmcs> {
+    if !is_enabled() {
+        return None;
+    }
+
+    CURRENT.current()
+}
+
+/// Marks all fields of the eVMCS of the current processor clean. Called on every VM exit, before any field is
+/// written, so that L0 reloads nothing on the next VM entry unless a field was written while handling the exit.
+pub fn begin_exit() {
+    if let Some(evmcs) = current() {
+        unsafe { ptr::write_volatile(&mut (*evmcs).hv_clean_fields, CLEAN_FIELDS_ALL) };
+    }
+}
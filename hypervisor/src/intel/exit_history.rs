@@ -0,0 +1,66 @@
+//! The last VM exits of a processor, kept for post-mortem dumps.
+//!
+//! Every VM exit is recorded with its basic exit reason, guest RIP and TSC in a small ring owned by the
+//! processor, overwriting the oldest record. The ring is only read when something went wrong, e.g. by the
+//! triple fault handler, see `vmexit::triple_fault`, to show what the guest and the hypervisor did right
+//! before.
+//!
+//! Only the exit handler of the processor accesses its ring, so recording takes no lock and no atomic operation.
+
+use crate::intel::vmerror::VmxBasicExitReason;
+
+/// The number of VM exits kept per processor.
+pub const EXIT_HISTORY_LEN: usize = 16;
+
+/// A VM exit in the history.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitRecord {
+    /// The basic exit reason.
+    pub reason: VmxBasicExitReason,
+
+    /// The guest RIP of the exit.
+    pub rip: u64,
+
+    /// The TSC of the host when the exit was recorded.
+    pub tsc: u64,
+}
+
+/// The last VM exits of a processor.
+pub struct ExitHistory {
+    /// The records, `next` being the oldest once the ring wrapped around.
+    records: [Option<ExitRecord>; EXIT_HISTORY_LEN],
+
+    /// The index of the slot the next record goes to.
+    next: usize,
+}
+
+impl ExitHistory {
+    /// Creates an empty history.
+    pub const fn new() -> Self {
+        Self {
+            records: [None; EXIT_HISTORY_LEN],
+            next: 0,
+        }
+    }
+
+    /// Records a VM exit, overwriting the oldest one if the history is full.
+    pub fn record(&mut self, record: ExitRecord) {
+        if let Some(slot) = self.records.get_mut(self.next) {
+            *slot = Some(record);
+        }
+
+        self.next = (self.next + 1) % EXIT_HISTORY_LEN;
+    }
+
+    /// Returns the recorded VM exits, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &ExitRecord> {
+        let (newer, older) = self.records.split_at(self.next);
+        older.iter().chain(newer).flatten()
+    }
+}
+
+impl Default for ExitHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,356 @@
+//! Controlled fault injection into the guest, for resilience testing of guest drivers.
+//!
+//! A test controller in the guest kernel requests faults through hypercalls:
+//! - Bits of guest memory are flipped immediately, emulating memory corruption.
+//! - The next calls to a routine, typically a pool allocator, fail: the routine returns the requested value,
+//!   e.g. NULL, to its caller without running.
+//! - A page fault or a machine check is raised when the guest executes an instruction.
+//!
+//! Call and exception faults are triggered by instruction fetches: the page holding the trigger instruction
+//! is mapped without execute permission in the EPT, and the resulting EPT violation injects the fault when
+//! the guest RIP is the trigger address. Other instructions of the page are single-stepped with the monitor
+//! trap flag while the page has the permissions it had before the first trigger was armed, after which it is
+//! non-executable again. Once all triggers of a page are spent, the page gets these permissions back. Taking
+//! the execute permission away kicks the other processors to flush their translations, see `invept_broadcast`.
+//!
+//! Fault injection is a test facility that can crash the guest, and must be enabled explicitly.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.2 Monitor Trap Flag and
+//! 29.3.3.2 EPT Violations.
+
+use crate::{
+    intel::{
+        capabilities::{Capability, Subsystem},
+        hypercall::{HypercallCode, HypercallStatus},
+        shared_data::PageAccess,
+    },
+    utils::{
+        addresses::{Gpa, Gva},
+        event_log::EventLog,
+        sync::SpinLock,
+        timestamp::Timestamp,
+    },
+};
+
+/// The hypercalls of the fault injector, see `intel::capabilities`.
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "fault-injection",
+    enabled: |shared_data| shared_data.fault_injector.is_enabled(),
+    commands: &[
+        Capability {
+            code: HypercallCode::FaultFlipBits,
+            summary: "Flips bits of guest memory",
+        },
+        Capability {
+            code: HypercallCode::FaultFailCalls,
+            summary: "Fails the next calls to a routine",
+        },
+        Capability {
+            code: HypercallCode::FaultRaiseException,
+            summary: "Raises an exception when the guest executes an instruction",
+        },
+        Capability {
+            code: HypercallCode::FaultClear,
+            summary: "Disarms all call and exception faults",
+        },
+    ],
+};
+
+/// The maximum number of triggers armed at once.
+pub const MAX_FAULT_TRIGGERS: usize = 8;
+
+/// The number of events kept until they are drained.
+const EVENT_LOG_LEN: usize = 32;
+
+/// A fault injected when the guest reaches a trigger instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// The routine at the trigger address returns the given value to its caller without running.
+    FailCall { return_value: u64 },
+
+    /// A page fault on the given linear address is raised.
+    PageFault { address: Gva, error_code: u32 },
+
+    /// A machine check is raised.
+    MachineCheck,
+}
+
+/// An armed fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultTrigger {
+    /// The guest linear address of the trigger instruction.
+    pub address: Gva,
+
+    /// The guest physical address of the page holding the trigger instruction.
+    pub page: Gpa,
+
+    /// The fault to inject.
+    pub kind: FaultKind,
+
+    /// The number of times the fault is still injected.
+    pub remaining: u64,
+}
+
+/// What a fault event reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultEventKind {
+    /// Bits of guest memory were flipped.
+    BitsFlipped { guest_pa: Gpa, mask: u64 },
+
+    /// A fault was injected at a trigger instruction.
+    Injected(FaultKind),
+}
+
+/// A fault injected into the guest.
+#[derive(Debug, Clone, Copy)]
+pub struct FaultEvent {
+    /// What was injected.
+    pub kind: FaultEventKind,
+
+    /// The guest RIP when the fault was injected.
+    pub rip: u64,
+
+    /// When the fault was injected.
+    pub timestamp: Timestamp,
+}
+
+/// The armed triggers and the permissions their pages had before.
+struct Triggers {
+    /// The armed triggers.
+    triggers: [Option<FaultTrigger>; MAX_FAULT_TRIGGERS],
+
+    /// The permissions of the pages holding triggers, before their first trigger was armed. A page never holds
+    /// less than one trigger, so there are at most as many pages as triggers.
+    pages: [Option<PageAccess>; MAX_FAULT_TRIGGERS],
+}
+
+impl Triggers {
+    /// Returns whether a guest physical page holds a trigger.
+    fn holds(&self, page: Gpa) -> bool {
+        self.triggers
+            .iter()
+            .flatten()
+            .any(|trigger| trigger.page == page)
+    }
+
+    /// Returns the permissions a page holding a trigger had before.
+    fn saved(&self, page: Gpa) -> Option<PageAccess> {
+        self.pages
+            .iter()
+            .flatten()
+            .find(|saved| saved.guest_pa == page)
+            .copied()
+    }
+}
+
+/// Injects the faults requested by the guest test controller.
+pub struct FaultInjector {
+    /// Whether the fault injection hypercalls are served.
+    enabled: bool,
+
+    /// The armed triggers.
+    triggers: SpinLock<Triggers>,
+
+    /// The faults injected, until they are drained.
+    events: EventLog<FaultEvent, EVENT_LOG_LEN>,
+}
+
+impl FaultInjector {
+    /// Creates the fault injector.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether the fault injection hypercalls are served.
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            triggers: SpinLock::new(
+                "fault_triggers",
+                Triggers {
+                    triggers: [None; MAX_FAULT_TRIGGERS],
+                    pages: [None; MAX_FAULT_TRIGGERS],
+                },
+            ),
+            events: EventLog::new("fault_events"),
+        }
+    }
+
+    /// Returns whether the fault injection hypercalls are served.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Flips bits of a guest quadword.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address of the quadword, 8-byte aligned.
+    /// * `mask` - The bits to flip.
+    /// * `rip` - The guest RIP of the request.
+    ///
+    /// # Returns
+    ///
+    /// `HypercallStatus::InvalidParameter` if the address is not aligned or not mapped.
+    pub fn flip_bits(&self, guest_pa: Gpa, mask: u64, rip: u64) -> Result<(), HypercallStatus> {
+        if guest_pa.as_u64() & 0b111 != 0 {
+            return Err(HypercallStatus::InvalidParameter);
+        }
+
+        let Some(va) = guest_pa.to_hva() else {
+            return Err(HypercallStatus::InvalidParameter);
+        };
+
+        unsafe {
+            let quadword = va.as_mut_ptr::<u64>();
+            quadword.write_volatile(quadword.read_volatile() ^ mask);
+        }
+
+        self.record(FaultEvent {
+            kind: FaultEventKind::BitsFlipped { guest_pa, mask },
+            rip,
+            timestamp: Timestamp::now(),
+        });
+
+        Ok(())
+    }
+
+    /// Arms a trigger, replacing the trigger armed at the same address of the same page if any.
+    ///
+    /// The caller must remove the execute permission from the page of the trigger and invalidate the EPT on all
+    /// processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `trigger` - The trigger to arm.
+    /// * `access` - The current permissions of the page of the trigger, kept if it holds no trigger yet.
+    ///
+    /// # Returns
+    ///
+    /// The permissions the page had before its first trigger was armed, or
+    /// `HypercallStatus::InsufficientResources` if `MAX_FAULT_TRIGGERS` triggers are armed already.
+    pub fn arm(
+        &self,
+        trigger: FaultTrigger,
+        access: PageAccess,
+    ) -> Result<PageAccess, HypercallStatus> {
+        let mut triggers = self.triggers.lock();
+
+        let index = triggers
+            .triggers
+            .iter()
+            .position(|slot| {
+                slot.is_some_and(|armed| {
+                    armed.address == trigger.address && armed.page == trigger.page
+                })
+            })
+            .or_else(|| triggers.triggers.iter().position(Option::is_none));
+        let Some(index) = index else {
+            return Err(HypercallStatus::InsufficientResources);
+        };
+
+        let saved = match triggers.saved(trigger.page) {
+            Some(saved) => saved,
+            None => {
+                // Fewer pages than triggers are armed, so a slot is free.
+                let Some(slot) = triggers.pages.iter_mut().find(|slot| slot.is_none()) else {
+                    return Err(HypercallStatus::InsufficientResources);
+                };
+                let saved = PageAccess {
+                    guest_pa: trigger.page,
+                    ..access
+                };
+                *slot = Some(saved);
+                saved
+            }
+        };
+
+        log::info!("Arming fault {:?} at {:#x}", trigger.kind, trigger.address);
+        if let Some(slot) = triggers.triggers.get_mut(index) {
+            *slot = Some(trigger);
+        }
+
+        Ok(saved)
+    }
+
+    /// Returns the permissions a page holding a trigger had before its first trigger was armed, or `None` if no
+    /// trigger is armed on the page.
+    pub fn saved_access(&self, page: Gpa) -> Option<PageAccess> {
+        self.triggers.lock().saved(page.page_base())
+    }
+
+    /// Takes the fault to inject at a guest instruction, disarming the trigger once it is spent.
+    ///
+    /// The caller must restore the permissions returned, once the last trigger of the page is spent.
+    ///
+    /// # Arguments
+    ///
+    /// * `page` - The guest physical page of the instruction fetch.
+    /// * `rip` - The guest RIP of the instruction fetch.
+    ///
+    /// # Returns
+    ///
+    /// The fault to inject, with the permissions the page had before if it no longer holds a trigger, or
+    /// `None` if no trigger is armed at the instruction.
+    pub fn fire(&self, page: Gpa, rip: Gva) -> Option<(FaultKind, Option<PageAccess>)> {
+        let mut triggers = self.triggers.lock();
+
+        let slot = triggers.triggers.iter_mut().find(|slot| {
+            slot.is_some_and(|trigger| trigger.address == rip && trigger.page == page)
+        })?;
+        let trigger = slot.as_mut()?;
+
+        let kind = trigger.kind;
+        trigger.remaining = trigger.remaining.saturating_sub(1);
+        if trigger.remaining != 0 {
+            return Some((kind, None));
+        }
+
+        *slot = None;
+        if triggers.holds(page) {
+            return Some((kind, None));
+        }
+
+        let released = triggers
+            .pages
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|saved| saved.guest_pa == page))
+            .and_then(Option::take);
+
+        Some((kind, released))
+    }
+
+    /// Disarms all triggers.
+    ///
+    /// The caller must restore the permissions of the pages of the triggers and invalidate the EPT.
+    ///
+    /// # Returns
+    ///
+    /// The permissions the pages of the disarmed triggers had before.
+    pub fn disarm_all(&self) -> [Option<PageAccess>; MAX_FAULT_TRIGGERS] {
+        let mut triggers = self.triggers.lock();
+        triggers.triggers = [None; MAX_FAULT_TRIGGERS];
+
+        core::mem::replace(&mut triggers.pages, [None; MAX_FAULT_TRIGGERS])
+    }
+
+    /// Records an injected fault.
+    pub fn record(&self, event: FaultEvent) {
+        log::warn!("Injected fault at {:#x}: {:?}", event.rip, event.kind);
+
+        self.events.push(event);
+    }
+
+    /// Hands the pending events to a consumer, oldest first, and removes them.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    pub fn drain_events(&self, consumer: impl FnMut(&FaultEvent)) -> usize {
+        self.events.drain(consumer)
+    }
+
+    /// Returns the total number of faults injected, including the events that were overwritten.
+    pub fn total_events(&self) -> u64 {
+        self.events.total()
+    }
+}
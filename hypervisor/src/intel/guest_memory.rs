@@ -0,0 +1,463 @@
+//! Access to guest memory through the guest's own page tables.
+//!
+//! Instructions emulated on behalf of the guest, such as string I/O, access memory by linear address. The
+//! address is translated by walking the guest paging structures referenced by the guest CR3, with the access
+//! rights the processor would check, and the guest physical address is then accessed through its host
+//! mapping, as guest physical memory is identity mapped by the EPT. Accesses the processor would refuse are
+//! reported as the page fault the guest has to receive instead.
+//!
+//! Writes carried out on behalf of an instruction of the guest go through `GuestMemory::write_checked`, which
+//! also honors the EPT of the current view: the page is written where the EPT maps it, and a page the EPT does
+//! not let the guest write, such as a hooked or protected page, is left untouched.
+//!
+//! Only 4-level and 5-level paging are supported, as used by a 64-bit guest. Protection keys and SMAP are not
+//! checked.
+//!
+//! Hypercalls copying their output to a page the guest names by guest physical address map it with
+//! `output_page`, which honors the EPT of the current view the same way.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.5 4-LEVEL PAGING AND 5-LEVEL
+//! PAGING, 4.6 ACCESS RIGHTS and 4.7 PAGE-FAULT EXCEPTIONS.
+
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::paging::{Ept, EptLevel},
+            event_queue::{EventQueue, PendingEvent},
+            support::try_vmread,
+        },
+        utils::addresses::{Gpa, Gva, Hpa, Hva},
+    },
+    x86::{
+        bits64::paging::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE},
+        vmx::vmcs::guest,
+    },
+};
+
+/// The bits of a paging-structure entry holding the physical address.
+const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The present bit of a paging-structure entry.
+const ENTRY_PRESENT: u64 = 1 << 0;
+
+/// The read/write bit of a paging-structure entry.
+const ENTRY_WRITABLE: u64 = 1 << 1;
+
+/// The user/supervisor bit of a paging-structure entry.
+const ENTRY_USER: u64 = 1 << 2;
+
+/// The page size bit of a PDPTE or PDE.
+const ENTRY_LARGE: u64 = 1 << 7;
+
+/// The page-fault error code bit set when the page was present.
+const PF_PRESENT: u32 = 1 << 0;
+
+/// The page-fault error code bit set for writes.
+const PF_WRITE: u32 = 1 << 1;
+
+/// The page-fault error code bit set for accesses with CPL 3.
+const PF_USER: u32 = 1 << 2;
+
+/// The position of the DPL in the segment access rights.
+const ACCESS_RIGHTS_DPL_SHIFT: u64 = 5;
+
+/// CR0.WP.
+const CR0_WP: u64 = 1 << 16;
+
+/// CR4.LA57.
+const CR4_LA57: u64 = 1 << 12;
+
+/// The page fault raised by a guest access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestPageFault {
+    /// The faulting linear address, loaded into CR2.
+    pub address: Gva,
+
+    /// The page-fault error code.
+    pub error_code: u32,
+}
+
+impl GuestPageFault {
+    /// Injects the page fault into the guest.
+    ///
+    /// CR2 is not switched on VM exits, so the event queue loads it with the faulting address when the page fault
+    /// is injected, for the guest to find.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - The event queue of the processor.
+    pub fn inject(&self, events: &mut EventQueue) -> Result<(), HypervisorError> {
+        log::trace!("Injecting {:?}", self);
+
+        events.push(PendingEvent::page_fault(self.address, self.error_code))
+    }
+}
+
+/// Whether the EPT lets the guest write memory, see `GuestMemory::write_checked`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestWrite {
+    /// The memory was, or can be, written.
+    Permitted,
+
+    /// The EPT does not let the guest write the page of the guest physical address, so nothing was written.
+    DeniedByEpt(Gpa),
+}
+
+/// The host virtual address, the offset in the access and the length accessed of the (at most two) pages an
+/// access spans.
+type Pages = [Option<(Hva, usize, usize)>; 2];
+
+/// Why the pages of an access cannot be accessed.
+enum PagesError {
+    /// The guest paging structures refuse the access.
+    PageFault(GuestPageFault),
+
+    /// The EPT does not let the guest write the page.
+    DeniedByEpt(Gpa),
+}
+
+impl From<GuestPageFault> for PagesError {
+    fn from(fault: GuestPageFault) -> Self {
+        Self::PageFault(fault)
+    }
+}
+
+/// The view of guest memory of the current guest context.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestMemory {
+    /// The guest CR3.
+    cr3: u64,
+
+    /// Whether the guest uses 5-level paging.
+    la57: bool,
+
+    /// Whether supervisor writes honor read-only pages (CR0.WP).
+    write_protect: bool,
+
+    /// Whether the guest runs with CPL 3.
+    user: bool,
+}
+
+impl GuestMemory {
+    /// Captures the paging mode and the privilege level of the guest from the VMCS.
+    pub fn current() -> Result<Self, HypervisorError> {
+        let cpl = (try_vmread(guest::SS_ACCESS_RIGHTS)? >> ACCESS_RIGHTS_DPL_SHIFT) & 0b11;
+
+        Ok(Self {
+            cr3: try_vmread(guest::CR3)?,
+            la57: try_vmread(guest::CR4)? & CR4_LA57 != 0,
+            write_protect: try_vmread(guest::CR0)? & CR0_WP != 0,
+            user: cpl == 3,
+        })
+    }
+
+    /// Accesses the address space of a CR3 with supervisor privileges, with the paging mode of the current
+    /// processor, which is the one of the guest. Usable in VMX root and non-root operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The CR3 of the address space, e.g. the one of the System process for kernel memory.
+    pub fn supervisor(cr3: u64) -> Self {
+        Self {
+            cr3,
+            la57: unsafe { x86::controlregs::cr4() }.bits() as u64 & CR4_LA57 != 0,
+            write_protect: unsafe { x86::controlregs::cr0() }.bits() as u64 & CR0_WP != 0,
+            user: false,
+        }
+    }
+
+    /// Translates a linear address to a guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The linear address.
+    /// * `write` - Whether the access is a write.
+    ///
+    /// # Returns
+    ///
+    /// The guest physical address, or the page fault the access raises.
+    pub fn translate(&self, address: Gva, write: bool) -> Result<Gpa, GuestPageFault> {
+        let levels: u32 = if self.la57 { 5 } else { 4 };
+
+        let mut error_code = 0;
+        if write {
+            error_code |= PF_WRITE;
+        }
+        if self.user {
+            error_code |= PF_USER;
+        }
+
+        let fault = |error_code| GuestPageFault {
+            address,
+            error_code,
+        };
+
+        let mut table = self.cr3 & ENTRY_ADDRESS_MASK;
+        let mut writable = true;
+        let mut user = true;
+
+        for level in (1..=levels).rev() {
+            let shift = 12 + 9 * (level - 1);
+            let index = (address.as_u64() >> shift) & 0x1FF;
+
+            let Some(entry) = read_entry(Gpa::new(table + index * 8)) else {
+                return Err(fault(error_code));
+            };
+
+            if entry & ENTRY_PRESENT == 0 {
+                return Err(fault(error_code));
+            }
+
+            writable &= entry & ENTRY_WRITABLE != 0;
+            user &= entry & ENTRY_USER != 0;
+
+            // 1-GByte and 2-MByte pages end the walk at the PDPTE and PDE.
+            let page_end = level == 1 || ((level == 2 || level == 3) && entry & ENTRY_LARGE != 0);
+            if !page_end {
+                table = entry & ENTRY_ADDRESS_MASK;
+                continue;
+            }
+
+            error_code |= PF_PRESENT;
+
+            if self.user && !user {
+                return Err(fault(error_code));
+            }
+
+            if write && !writable && (self.user || self.write_protect) {
+                return Err(fault(error_code));
+            }
+
+            let page_mask = (1u64 << shift) - 1;
+            return Ok(Gpa::new(
+                (entry & ENTRY_ADDRESS_MASK & !page_mask) | (address.as_u64() & page_mask),
+            ));
+        }
+
+        Err(fault(error_code))
+    }
+
+    /// Reads guest memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The linear address to read from.
+    /// * `buffer` - Receives the memory, at most a page, which may cross a page boundary.
+    ///
+    /// # Returns
+    ///
+    /// The page fault raised by the read, in which case nothing is read.
+    pub fn read(&self, address: Gva, buffer: &mut [u8]) -> Result<(), GuestPageFault> {
+        let pages = self.pages(address, buffer.len(), false)?;
+
+        for (va, offset, len) in pages.into_iter().flatten() {
+            if let Some(chunk) = buffer.get_mut(offset..offset + len) {
+                unsafe { core::ptr::copy_nonoverlapping(va.as_ptr(), chunk.as_mut_ptr(), len) };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes guest memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The linear address to write to.
+    /// * `data` - The data to write, at most a page, which may cross a page boundary.
+    ///
+    /// # Returns
+    ///
+    /// The page fault raised by the write, in which case nothing is written.
+    pub fn write(&self, address: Gva, data: &[u8]) -> Result<(), GuestPageFault> {
+        let pages = self.pages(address, data.len(), true)?;
+
+        for (va, offset, len) in pages.into_iter().flatten() {
+            if let Some(chunk) = data.get(offset..offset + len) {
+                unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), va.as_mut_ptr(), len) };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that guest memory can be accessed, without accessing it.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The linear address of the access.
+    /// * `len` - The length of the access, at most a page.
+    /// * `write` - Whether the access is a write.
+    ///
+    /// # Returns
+    ///
+    /// The page fault the access would raise.
+    pub fn probe(&self, address: Gva, len: usize, write: bool) -> Result<(), GuestPageFault> {
+        self.pages(address, len, write).map(|_| ())
+    }
+
+    /// Writes guest memory as an instruction of the guest would, honoring the EPT of the current view.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The linear address to write to.
+    /// * `data` - The data to write, at most a page, which may cross a page boundary.
+    /// * `ept` - The EPT of the current view.
+    ///
+    /// # Returns
+    ///
+    /// Whether the EPT permitted the write, or the page fault raised by it. Nothing is written unless it is
+    /// `Ok(GuestWrite::Permitted)`.
+    pub fn write_checked(
+        &self,
+        address: Gva,
+        data: &[u8],
+        ept: &Ept,
+    ) -> Result<GuestWrite, GuestPageFault> {
+        let pages = match self.ept_pages(address, data.len(), ept) {
+            Ok(pages) => pages,
+            Err(PagesError::PageFault(fault)) => return Err(fault),
+            Err(PagesError::DeniedByEpt(guest_pa)) => return Ok(GuestWrite::DeniedByEpt(guest_pa)),
+        };
+
+        for (va, offset, len) in pages.into_iter().flatten() {
+            if let Some(chunk) = data.get(offset..offset + len) {
+                unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), va.as_mut_ptr(), len) };
+            }
+        }
+
+        Ok(GuestWrite::Permitted)
+    }
+
+    /// Checks that guest memory can be written by `write_checked`, without writing it.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The linear address of the write.
+    /// * `len` - The length of the write, at most a page.
+    /// * `ept` - The EPT of the current view.
+    ///
+    /// # Returns
+    ///
+    /// Whether the EPT permits the write, or the page fault the write would raise.
+    pub fn probe_checked(
+        &self,
+        address: Gva,
+        len: usize,
+        ept: &Ept,
+    ) -> Result<GuestWrite, GuestPageFault> {
+        match self.ept_pages(address, len, ept) {
+            Ok(_) => Ok(GuestWrite::Permitted),
+            Err(PagesError::PageFault(fault)) => Err(fault),
+            Err(PagesError::DeniedByEpt(guest_pa)) => Ok(GuestWrite::DeniedByEpt(guest_pa)),
+        }
+    }
+
+    /// Translates the (at most two) pages spanned by an access of at most a page, before any of them is
+    /// accessed, so a fault on the second page leaves the first one untouched.
+    ///
+    /// # Returns
+    ///
+    /// For each page, the host virtual address, the offset in the access and the length accessed.
+    fn pages(&self, address: Gva, len: usize, write: bool) -> Result<Pages, GuestPageFault> {
+        match self.mapped_pages(address, len, write, |guest_pa| Ok(guest_pa.to_hpa())) {
+            Ok(pages) => Ok(pages),
+            Err(PagesError::PageFault(fault)) => Err(fault),
+            // The identity mapping denies nothing.
+            Err(PagesError::DeniedByEpt(_)) => Ok([None; 2]),
+        }
+    }
+
+    /// Translates the pages spanned by a write like `pages`, to where the EPT maps them.
+    fn ept_pages(&self, address: Gva, len: usize, ept: &Ept) -> Result<Pages, PagesError> {
+        self.mapped_pages(address, len, true, |guest_pa| {
+            writable_in_ept(guest_pa, ept).ok_or(PagesError::DeniedByEpt(guest_pa))
+        })
+    }
+
+    /// Translates the pages spanned by an access with the guest paging structures, and then to host physical
+    /// addresses with `host`.
+    fn mapped_pages(
+        &self,
+        address: Gva,
+        len: usize,
+        write: bool,
+        host: impl Fn(Gpa) -> Result<Hpa, PagesError>,
+    ) -> Result<Pages, PagesError> {
+        let mut pages = [None; 2];
+        let mut offset = 0;
+
+        for page in pages.iter_mut() {
+            if offset >= len {
+                break;
+            }
+
+            let linear = address + offset as u64;
+            let in_page = BASE_PAGE_SIZE - linear.page_offset() as usize;
+            let chunk = in_page.min(len - offset);
+
+            let Some(va) = host(self.translate(linear, write)?)?.to_hva() else {
+                return Err(PagesError::PageFault(GuestPageFault {
+                    address: linear,
+                    error_code: if write { PF_WRITE } else { 0 },
+                }));
+            };
+
+            *page = Some((va, offset, chunk));
+            offset += chunk;
+        }
+
+        Ok(pages)
+    }
+}
+
+/// Maps the page a hypercall copies its output to.
+///
+/// # Arguments
+///
+/// * `address` - The guest physical address of the page, which must be page aligned.
+/// * `ept` - The EPT of the current view, which must let the guest write the page.
+///
+/// # Returns
+///
+/// The page, or `None` if the address is not page aligned or the guest may not write the page, e.g. because
+/// it is hooked or protected.
+pub fn output_page(address: Gpa, ept: &Ept) -> Option<&'static mut [u8]> {
+    if !address.is_page_aligned() {
+        return None;
+    }
+
+    let va = writable_in_ept(address, ept)?.to_hva()?;
+    Some(unsafe { core::slice::from_raw_parts_mut(va.as_mut_ptr::<u8>(), BASE_PAGE_SIZE) })
+}
+
+/// Returns the host physical address the EPT maps a guest physical address to, or `None` if the EPT does not
+/// let the guest write it.
+fn writable_in_ept(guest_pa: Gpa, ept: &Ept) -> Option<Hpa> {
+    let (level, entry) = ept.walk(guest_pa).ok()?.leaf();
+    if !entry.writable() {
+        return None;
+    }
+
+    let page_mask = match level {
+        EptLevel::Pt => BASE_PAGE_SIZE as u64 - 1,
+        _ => LARGE_PAGE_SIZE as u64 - 1,
+    };
+    Some(Hpa::new(
+        ((entry.pfn() << 12) & !page_mask) | (guest_pa.as_u64() & page_mask),
+    ))
+}
+
+/// Reads a paging-structure entry, or returns `None` if its guest physical address is not mapped.
+fn read_entry(pa: Gpa) -> Option<u64> {
+    pa.to_hva()
+        .map(|va| unsafe { core::ptr::read_volatile(va.as_ptr()) })
+}
@@ -0,0 +1,383 @@
+//! EPT-assisted poisoning of guest heap red zones, a memory error detector for guest drivers.
+//!
+//! A driver under test, or a debug allocator, poisons the red zones around its allocations and the freed
+//! allocations themselves through hypercalls, without instrumenting its code. The pages holding poisoned zones
+//! are mapped without any permission in the EPT, and every access to them exits:
+//! - An access to a poisoned zone is recorded as a `PoisonTouch`, with the guest registers, CR3 and the
+//!   processor, and answered as configured with `HypervisorBuilder::heap_poisoning`: `ViolationResponse::Monitor`
+//!   lets it complete, `ViolationResponse::Deny` injects #GP(0) instead.
+//! - An access to the rest of the page, e.g. a neighbouring allocation, is single-stepped with the monitor trap
+//!   flag while the page has the permissions it had before its first zone was poisoned. The page is protected
+//!   again on the following MTF exit. An access those permissions do not allow is handled as without poisoning.
+//!
+//! Poisoning takes permissions away, so the other processors are kicked out of the guest to flush their stale
+//! translations, see `invept::invept_broadcast`. The permissions of a page are restored once its last zone is
+//! removed. Removing the poison from the middle of a zone keeps the rest of it poisoned.
+//!
+//! Red zones are byte granular, but an access is attributed by its first byte: an unaligned access starting just
+//! before a zone and reaching into it is not detected. Pages holding zones are slow to access, so the zones
+//! should be kept to the allocations under test.
+//!
+//! Heap poisoning is a test facility and must be enabled explicitly.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.2 EPT Violations and
+//! 26.5.2 Monitor Trap Flag.
+
+use crate::{
+    intel::{
+        capabilities::{Capability, Subsystem},
+        ept::paging::AccessType,
+        ept::policy::ViolationResponse,
+        hypercall::{HypercallCode, HypercallStatus},
+        shared_data::PageAccess,
+    },
+    utils::{
+        addresses::{Gpa, Gva},
+        capture::GuestRegisters,
+        event_log::EventLog,
+        sync::SpinLock,
+        timestamp::Timestamp,
+    },
+};
+
+/// The hypercalls of the heap poisoning, see `intel::capabilities`.
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "heap-poison",
+    enabled: |shared_data| shared_data.heap_poison.is_enabled(),
+    commands: &[
+        Capability {
+            code: HypercallCode::HeapPoison,
+            summary: "Poisons a range of guest memory",
+        },
+        Capability {
+            code: HypercallCode::HeapUnpoison,
+            summary: "Removes the poison from a range of guest memory",
+        },
+    ],
+};
+
+/// The maximum number of zones poisoned at once. A zone crossing a page boundary takes one per page.
+pub const MAX_POISONED_ZONES: usize = 64;
+
+/// The number of touches kept until they are drained.
+const EVENT_LOG_LEN: usize = 32;
+
+/// A poisoned zone of guest memory, within a single page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoisonedZone {
+    /// The guest linear address of the zone, as poisoned by the guest.
+    pub address: Gva,
+
+    /// The guest physical address of the zone.
+    pub guest_pa: Gpa,
+
+    /// The length of the zone in bytes.
+    pub len: u32,
+}
+
+impl PoisonedZone {
+    /// Returns whether the zone contains the guest physical address.
+    pub fn contains(&self, guest_pa: Gpa) -> bool {
+        guest_pa >= self.guest_pa && guest_pa - self.guest_pa < u64::from(self.len)
+    }
+
+    /// Returns whether the zone overlaps a guest physical range.
+    fn overlaps(&self, guest_pa: Gpa, len: u32) -> bool {
+        self.guest_pa.as_u64() < guest_pa.as_u64() + u64::from(len)
+            && guest_pa.as_u64() < self.guest_pa.as_u64() + u64::from(self.len)
+    }
+
+    /// Returns the part of the zone from one guest physical address to another, or `None` if it is empty.
+    fn slice(&self, start: u64, end: u64) -> Option<Self> {
+        let start = start.max(self.guest_pa.as_u64());
+        let end = end.min(self.guest_pa.as_u64() + u64::from(self.len));
+        if start >= end {
+            return None;
+        }
+
+        let offset = start - self.guest_pa.as_u64();
+        Some(Self {
+            address: self.address + offset,
+            guest_pa: self.guest_pa + offset,
+            len: (end - start) as u32,
+        })
+    }
+}
+
+/// An access of the guest to a poisoned zone.
+#[derive(Debug, Clone, Copy)]
+pub struct PoisonTouch {
+    /// The zone accessed.
+    pub zone: PoisonedZone,
+
+    /// The guest physical address accessed.
+    pub guest_pa: Gpa,
+
+    /// The guest linear address accessed, if the processor reported one.
+    pub guest_la: Option<Gva>,
+
+    /// The kind of access attempted.
+    pub access: AccessType,
+
+    /// How the access was answered.
+    pub response: ViolationResponse,
+
+    /// The index of the processor.
+    pub processor: u32,
+
+    /// The guest CR3, identifying the process.
+    pub cr3: u64,
+
+    /// The guest registers at the access, including RIP and RSP.
+    pub registers: GuestRegisters,
+
+    /// When the access happened.
+    pub timestamp: Timestamp,
+}
+
+/// The poisoned zones and the permissions their pages had before.
+struct Zones {
+    /// The poisoned zones.
+    zones: [Option<PoisonedZone>; MAX_POISONED_ZONES],
+
+    /// The permissions of the pages holding poisoned zones, before their first zone was poisoned. A page never
+    /// holds less than one zone, so there are at most as many pages as zones.
+    pages: [Option<PageAccess>; MAX_POISONED_ZONES],
+}
+
+impl Zones {
+    /// Returns whether a guest physical page holds a poisoned zone.
+    fn holds(&self, page: Gpa) -> bool {
+        self.zones
+            .iter()
+            .flatten()
+            .any(|zone| zone.guest_pa.page_base() == page)
+    }
+
+    /// Forgets the permissions of a page that no longer holds a poisoned zone.
+    ///
+    /// # Returns
+    ///
+    /// The permissions of the page before it was poisoned, or `None` if it still holds a zone.
+    fn release(&mut self, page: Gpa) -> Option<PageAccess> {
+        if self.holds(page) {
+            return None;
+        }
+
+        self.pages
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|saved| saved.guest_pa == page))
+            .and_then(Option::take)
+    }
+}
+
+/// The poisoned zones of guest memory.
+pub struct HeapPoison {
+    /// How accesses to poisoned zones are answered, or `None` if the hypercalls are not served.
+    response: Option<ViolationResponse>,
+
+    /// The poisoned zones.
+    zones: SpinLock<Zones>,
+
+    /// The touches recorded, until they are drained.
+    touches: EventLog<PoisonTouch, EVENT_LOG_LEN>,
+}
+
+impl HeapPoison {
+    /// Creates the set of poisoned zones.
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - How accesses to poisoned zones are answered, or `None` if the hypercalls are not served.
+    pub fn new(response: Option<ViolationResponse>) -> Self {
+        Self {
+            response,
+            zones: SpinLock::new(
+                "poisoned_zones",
+                Zones {
+                    zones: [None; MAX_POISONED_ZONES],
+                    pages: [None; MAX_POISONED_ZONES],
+                },
+            ),
+            touches: EventLog::new("poison_touches"),
+        }
+    }
+
+    /// Returns whether the heap poisoning hypercalls are served.
+    pub fn is_enabled(&self) -> bool {
+        self.response.is_some()
+    }
+
+    /// Returns how accesses to poisoned zones are answered.
+    pub fn response(&self) -> ViolationResponse {
+        self.response.unwrap_or(ViolationResponse::Monitor)
+    }
+
+    /// Poisons a zone.
+    ///
+    /// The caller must remove all permissions from the page of the zone and invalidate the EPT on all
+    /// processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `zone` - The zone to poison.
+    /// * `access` - The current permissions of the page of the zone, kept if it holds no zone yet.
+    ///
+    /// # Returns
+    ///
+    /// `HypercallStatus::InvalidParameter` if the zone is empty or crosses a page boundary, and
+    /// `HypercallStatus::InsufficientResources` if `MAX_POISONED_ZONES` zones are poisoned already.
+    pub fn poison(&self, zone: PoisonedZone, access: PageAccess) -> Result<(), HypercallStatus> {
+        if zone.len == 0 || zone.guest_pa.page_offset() + u64::from(zone.len) > 0x1000 {
+            return Err(HypercallStatus::InvalidParameter);
+        }
+
+        let page = zone.guest_pa.page_base();
+        let mut zones = self.zones.lock();
+
+        let Some(index) = zones.zones.iter().position(Option::is_none) else {
+            return Err(HypercallStatus::InsufficientResources);
+        };
+
+        if !zones.holds(page) {
+            // Fewer pages than zones are poisoned, so a slot is free.
+            let Some(slot) = zones.pages.iter_mut().find(|slot| slot.is_none()) else {
+                return Err(HypercallStatus::InsufficientResources);
+            };
+            *slot = Some(PageAccess {
+                guest_pa: page,
+                ..access
+            });
+        }
+
+        log::debug!("Poisoning {:?}", zone);
+        if let Some(slot) = zones.zones.get_mut(index) {
+            *slot = Some(zone);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the poison from a guest physical range within a page. The parts of the zones outside of the
+    /// range stay poisoned.
+    ///
+    /// The caller must restore the permissions returned and invalidate the EPT.
+    ///
+    /// # Returns
+    ///
+    /// The permissions the page had before it was poisoned, if it no longer holds a poisoned zone, or
+    /// `HypercallStatus::InsufficientResources` if a zone split in two does not fit, in which case nothing was
+    /// removed.
+    pub fn unpoison(&self, guest_pa: Gpa, len: u32) -> Result<Option<PageAccess>, HypercallStatus> {
+        let start = guest_pa.as_u64();
+        let end = start + u64::from(len);
+        let mut zones = self.zones.lock();
+
+        let splits = zones
+            .zones
+            .iter()
+            .flatten()
+            .filter(|zone| zone.slice(0, start).is_some() && zone.slice(end, u64::MAX).is_some())
+            .count();
+        let free = zones.zones.iter().filter(|slot| slot.is_none()).count();
+        if splits > free {
+            return Err(HypercallStatus::InsufficientResources);
+        }
+
+        // A zone keeps its part before the range, and the part after it if there is none before.
+        let mut remainders = [None; MAX_POISONED_ZONES];
+
+        for (slot, remainder) in zones.zones.iter_mut().zip(remainders.iter_mut()) {
+            let Some(zone) = slot.filter(|zone| zone.overlaps(guest_pa, len)) else {
+                continue;
+            };
+
+            let (before, after) = (zone.slice(0, start), zone.slice(end, u64::MAX));
+            match before {
+                Some(_) => (*slot, *remainder) = (before, after),
+                None => *slot = after,
+            }
+        }
+
+        for remainder in remainders.into_iter().flatten() {
+            if let Some(slot) = zones.zones.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(remainder);
+            }
+        }
+
+        Ok(zones.release(guest_pa.page_base()))
+    }
+
+    /// Removes a zone poisoned with `poison`, e.g. when poisoning the rest of a range failed.
+    ///
+    /// The caller must restore the permissions returned and invalidate the EPT.
+    ///
+    /// # Returns
+    ///
+    /// The permissions the page had before it was poisoned, if it no longer holds a poisoned zone.
+    pub fn remove(&self, zone: PoisonedZone) -> Option<PageAccess> {
+        let mut zones = self.zones.lock();
+
+        if let Some(slot) = zones.zones.iter_mut().find(|slot| **slot == Some(zone)) {
+            *slot = None;
+        }
+
+        zones.release(zone.guest_pa.page_base())
+    }
+
+    /// Returns whether a guest physical page holds a poisoned zone.
+    pub fn is_poisoned(&self, page: Gpa) -> bool {
+        self.zones.lock().holds(page.page_base())
+    }
+
+    /// Returns the permissions a page holding a poisoned zone had before it was poisoned.
+    pub fn saved_access(&self, page: Gpa) -> Option<PageAccess> {
+        let page = page.page_base();
+
+        self.zones
+            .lock()
+            .pages
+            .iter()
+            .flatten()
+            .find(|saved| saved.guest_pa == page)
+            .copied()
+    }
+
+    /// Returns the poisoned zone containing a guest physical address, if any.
+    pub fn lookup(&self, guest_pa: Gpa) -> Option<PoisonedZone> {
+        self.zones
+            .lock()
+            .zones
+            .iter()
+            .flatten()
+            .find(|zone| zone.contains(guest_pa))
+            .copied()
+    }
+
+    /// Records an access to a poisoned zone.
+    pub fn record(&self, touch: PoisonTouch) {
+        log::warn!(
+            "{:?} access to poisoned {:#x} ({:#x}) from {:#x}",
+            touch.access,
+            touch.guest_pa,
+            touch.zone.address,
+            touch.registers.rip
+        );
+
+        self.touches.push(touch);
+    }
+
+    /// Hands the pending touches to a consumer, oldest first, and removes them.
+    ///
+    /// # Returns
+    ///
+    /// The number of touches drained.
+    pub fn drain_touches(&self, consumer: impl FnMut(&PoisonTouch)) -> usize {
+        self.touches.drain(consumer)
+    }
+
+    /// Returns the total number of touches recorded, including the ones that were overwritten.
+    pub fn total_touches(&self) -> u64 {
+        self.touches.total()
+    }
+}
@@ -0,0 +1,207 @@
+//! A heat map of the VM exits caused by each guest module.
+//!
+//! The address ranges of the loaded kernel modules are captured when the hypervisor is built. Every VM exit
+//! is then attributed to the module containing the exiting RIP and counted per basic exit reason, which
+//! shows which guest components interact most with the hypervisor. Exits from addresses outside of any
+//! captured module, including all user-mode code and modules loaded later, are counted as unattributed.
+//!
+//! The module list is immutable once captured and the counters are atomic, so recording an exit takes no
+//! lock and costs a binary search over the modules.
+
+use {
+    crate::{
+        error::HypervisorError, intel::vmerror::VmxBasicExitReason, utils::ssdt::sys_info::Sysinfo,
+    },
+    alloc::{string::String, vec::Vec},
+    bstr::ByteSlice,
+    core::sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The number of basic exit reasons, i.e. the highest basic exit reason plus one.
+const EXIT_REASON_COUNT: usize = VmxBasicExitReason::InstructionTimeout as usize + 1;
+
+/// The name under which the exits outside of any captured module are reported.
+const UNATTRIBUTED: &str = "<unattributed>";
+
+/// The VM exits attributed to a guest module.
+pub struct ModuleExits {
+    /// The file name of the module.
+    pub name: String,
+
+    /// The base address of the module image.
+    pub base: u64,
+
+    /// The size of the module image.
+    pub size: u64,
+
+    /// The number of VM exits, indexed by basic exit reason.
+    counts: [AtomicU64; EXIT_REASON_COUNT],
+}
+
+impl ModuleExits {
+    /// Creates the counters of a module.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The file name of the module.
+    /// * `base` - The base address of the module image.
+    /// * `size` - The size of the module image.
+    pub fn new(name: String, base: u64, size: u64) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            name,
+            base,
+            size,
+            counts: [ZERO; EXIT_REASON_COUNT],
+        }
+    }
+
+    /// Returns the number of VM exits with the given reason.
+    pub fn count(&self, reason: VmxBasicExitReason) -> u64 {
+        self.counts
+            .get(reason as usize)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of VM exits for all reasons.
+    pub fn total(&self) -> u64 {
+        self.counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns the exit reasons with a non-zero count, and their counts.
+    pub fn histogram(&self) -> impl Iterator<Item = (VmxBasicExitReason, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter_map(|(reason, count)| {
+                let count = count.load(Ordering::Relaxed);
+                let reason = VmxBasicExitReason::from_u32(reason as u32)?;
+                (count != 0).then_some((reason, count))
+            })
+    }
+
+    /// Returns whether the address lies within the module image.
+    fn contains(&self, address: u64) -> bool {
+        address >= self.base && address - self.base < self.size
+    }
+
+    /// Counts a VM exit.
+    fn record(&self, reason: VmxBasicExitReason) {
+        if let Some(count) = self.counts.get(reason as usize) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Clears the counters.
+    fn reset(&self) {
+        for count in self.counts.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The VM exits of all guest modules.
+pub struct ExitHeatMap {
+    /// Whether VM exits are recorded.
+    enabled: bool,
+
+    /// The captured modules, sorted by base address.
+    modules: Vec<ModuleExits>,
+
+    /// The VM exits from addresses outside of any captured module.
+    unattributed: ModuleExits,
+}
+
+impl ExitHeatMap {
+    /// Creates a heat map that records nothing.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            modules: Vec::new(),
+            unattributed: ModuleExits::new(String::from(UNATTRIBUTED), 0, 0),
+        }
+    }
+
+    /// Creates a heat map of the currently loaded kernel modules.
+    ///
+    /// Must be called at PASSIVE_LEVEL, as the module list is queried from the kernel.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the heat map, or an error if the module list could not be queried.
+    pub fn capture() -> Result<Self, HypervisorError> {
+        let sys_info = Sysinfo::new()?;
+
+        let mut modules: Vec<ModuleExits> = sys_info
+            .modules()
+            .map(|module| {
+                let path = module.image_name.split_str(b"\0").next().unwrap_or(&[]);
+                let name = path.rsplit_str(b"\\").next().unwrap_or(path);
+
+                ModuleExits::new(
+                    String::from_utf8_lossy(name).into(),
+                    module.image_base as u64,
+                    module.size as u64,
+                )
+            })
+            .collect();
+
+        modules.sort_unstable_by_key(|module| module.base);
+
+        log::debug!("Captured {} modules for the exit heat map", modules.len());
+
+        Ok(Self {
+            enabled: true,
+            modules,
+            unattributed: ModuleExits::new(String::from(UNATTRIBUTED), 0, 0),
+        })
+    }
+
+    /// Returns whether VM exits are recorded.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Attributes a VM exit to the module containing the exiting instruction.
+    ///
+    /// # Arguments
+    ///
+    /// * `rip` - The guest RIP of the VM exit.
+    /// * `reason` - The basic exit reason.
+    pub fn record(&self, rip: u64, reason: VmxBasicExitReason) {
+        let index = self.modules.partition_point(|module| module.base <= rip);
+
+        match index.checked_sub(1).and_then(|i| self.modules.get(i)) {
+            Some(module) if module.contains(rip) => module.record(reason),
+            _ => self.unattributed.record(reason),
+        }
+    }
+
+    /// Returns the captured modules followed by the unattributed exits.
+    pub fn modules(&self) -> impl Iterator<Item = &ModuleExits> {
+        self.modules
+            .iter()
+            .chain(core::iter::once(&self.unattributed))
+    }
+
+    /// Returns the modules that caused VM exits, hottest first.
+    pub fn hottest(&self) -> Vec<&ModuleExits> {
+        let mut modules: Vec<&ModuleExits> = self
+            .modules()
+            .filter(|module| module.total() != 0)
+            .collect();
+
+        modules.sort_unstable_by_key(|module| core::cmp::Reverse(module.total()));
+        modules
+    }
+
+    /// Clears all counters.
+    pub fn reset(&self) {
+        self.modules().for_each(ModuleExits::reset);
+    }
+}
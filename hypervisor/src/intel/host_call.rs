@@ -0,0 +1,132 @@
+//! Requests of the hypervisor's own code in the guest to the hypervisor of the processor it runs on.
+//!
+//! The `Hypervisor` API runs in the guest, where INVEPT, VMCLEAR and VMXOFF cause VM exits instead of executing.
+//! Work that needs VMX root operation is requested with a VMCALL instead, separate from the hypercalls of
+//! `intel::hypercall`:
+//! - RAX holds `HOST_CALL_MAGIC` on entry and the `HostCallStatus` on return.
+//! - RCX holds the key generated when the hypervisor was built, see `SharedData::host_call_key`.
+//! - RDX holds the `HostCall`.
+//!
+//! The key only lives in memory of the hypervisor, so other guest code cannot make the processors leave VMX
+//! operation. A VMCALL with the wrong key is handled as an unknown hypercall, raising #UD.
+
+use {
+    crate::{
+        error::HypervisorError,
+        utils::processor::{is_virtualized, processor_count, ProcessorExecutor},
+    },
+    core::arch::asm,
+};
+
+/// The value of RAX identifying a host call ("hostcall" in ASCII).
+pub const HOST_CALL_MAGIC: u64 = 0x6c6c_6163_7473_6f68;
+
+/// The work a host call requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum HostCall {
+    /// Flushes the EPT derived translations of the processor.
+    FlushEpt = 1,
+
+    /// Leaves VMX operation on the processor, which resumes the guest natively after the VMCALL, see
+    /// `Vmx::teardown`.
+    Devirtualize = 2,
+
+    /// Runs the sandbox attached to the `Vmx` of the processor, see `intel::sandbox`. The call returns once the
+    /// run ended.
+    Detonate = 3,
+}
+
+impl HostCall {
+    /// Returns the host call of a value of RDX, or `None` if it is unknown.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        [Self::FlushEpt, Self::Devirtualize, Self::Detonate]
+            .into_iter()
+            .find(|&call| call as u64 == value)
+    }
+}
+
+/// The status of a host call, returned in RAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum HostCallStatus {
+    /// The work was done.
+    Success = 0,
+
+    /// The work could not be done right now, e.g. while events wait to be injected into the guest, and the call
+    /// must be made again.
+    Retry = 1,
+
+    /// The host call is unknown.
+    Unknown = 2,
+
+    /// The work could not be done, e.g. because no sandbox is attached to the processor.
+    Failed = 3,
+}
+
+/// The number of times a host call answered with `HostCallStatus::Retry` is made again before giving up.
+const MAX_RETRIES: u32 = 1000;
+
+/// Makes a host call on the current processor, which must be virtualized.
+///
+/// # Arguments
+///
+/// * `call` - The work to request.
+/// * `key` - The key of the hypervisor, see `SharedData::host_call_key`.
+///
+/// # Returns
+///
+/// A `Result` which is `Err(HypervisorError::HostCallFailed)` if the hypervisor did not do the work.
+pub fn host_call(call: HostCall, key: u64) -> Result<(), HypervisorError> {
+    for _ in 0..MAX_RETRIES {
+        let status: u64;
+
+        // RBX is reserved by the compiler, so the arguments are passed in RCX and RDX.
+        unsafe {
+            asm!(
+                "vmcall",
+                inout("rax") HOST_CALL_MAGIC => status,
+                in("rcx") key,
+                in("rdx") call as u64,
+                options(nostack),
+            )
+        };
+
+        match status {
+            s if s == HostCallStatus::Success as u64 => return Ok(()),
+            s if s == HostCallStatus::Retry as u64 => core::hint::spin_loop(),
+            _ => break,
+        }
+    }
+
+    log::error!("Host call {:?} failed", call);
+    Err(HypervisorError::HostCallFailed)
+}
+
+/// Flushes the EPT derived translations of every virtualized processor, from the guest.
+///
+/// Runs a `HostCall::FlushEpt` on each processor in turn, so every processor flushed once this returns. Must be
+/// called at PASSIVE_LEVEL, and never with interrupts disabled, e.g. while holding a lock of `utils::sync`.
+///
+/// # Arguments
+///
+/// * `key` - The key of the hypervisor, see `SharedData::host_call_key`.
+///
+/// # Returns
+///
+/// A `Result` which is `Err` if execution could not be switched to a processor or its flush failed.
+pub fn flush_ept_on_all_processors(key: u64) -> Result<(), HypervisorError> {
+    for index in 0..processor_count() {
+        let Some(executor) = ProcessorExecutor::switch_to_processor(index) else {
+            return Err(HypervisorError::ProcessorSwitchFailed);
+        };
+
+        if is_virtualized() {
+            host_call(HostCall::FlushEpt, key)?;
+        }
+
+        drop(executor);
+    }
+
+    Ok(())
+}
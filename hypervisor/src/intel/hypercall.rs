@@ -0,0 +1,22 @@
+//! The hypercall interface offered to cooperative guests through VMCALL.
+//!
+//! Hypercalls are only served while the paravirtual interface advertises `ParavirtFeatures::HYPERCALLS`,
+//! and only from CPL 0. Otherwise VMCALL raises #UD as on bare metal, respectively #GP(0).
+//!
+//! Calling convention:
+//! - RAX holds the `HypercallCode` on entry and the `HypercallStatus` on return.
+//! - RBX, RCX and RDX hold the input parameters, RBX and RCX the output values.
+//! - R8 holds the session token when client sessions are configured, see `intel::sessions`.
+//!
+//! An unknown code raises #UD and leaves the registers untouched, so VMCALLs issued by other software, e.g.
+//! for the hypervisor it expects, fault as on bare metal.
+//!
+//! The coverage, fault injection, EPT dump, configuration export, heap poisoning, metrics, process and capability
+//! hypercalls only exist with the `introspection` feature. Without it, their codes are unknown like any other.
+//!
+//! The codes and results are defined in the `hypervisor-core` crate, so clients encode hypercalls with the same
+//! types the hypervisor decodes them with.
+
+pub use hypervisor_core::hypercall::{
+    HypercallAccess, HypercallCode, HypercallStatus, AGENT_CHALLENGE_TAG,
+};
@@ -0,0 +1,105 @@
+//! A hypervisor-populated hypercall page, in the spirit of the Hyper-V and Xen hypercall pages.
+//!
+//! A cooperative guest allocates a page and writes its guest physical address to the hypercall page
+//! MSR. The hypervisor fills the page with the thunk issuing a hypercall on the detected vendor
+//! (`VMCALL` on Intel, `VMMCALL` on AMD) followed by `RET`, so guest agents simply `call` into the page
+//! and never have to know which instruction to use.
+//!
+//! The MSR is only emulated while the paravirtual interface is exposed.
+//!
+//! Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/hypercall-interface
+
+use {
+    crate::{
+        intel::paravirt::ParavirtInterface,
+        utils::{
+            addresses::Gpa,
+            cpu::{self, CpuVendor},
+        },
+    },
+    x86::current::paging::BASE_PAGE_SIZE,
+};
+
+/// The synthetic MSR through which the guest sets up the hypercall page.
+///
+/// Bit 0 enables the page, bits 63:12 hold the guest physical address of the page.
+pub const HYPERCALL_PAGE_MSR: u32 = 0x4000_0100;
+
+/// The enable bit of the hypercall page MSR.
+const HYPERCALL_PAGE_ENABLE: u64 = 1 << 0;
+
+/// The mask of the page address in the hypercall page MSR.
+const HYPERCALL_PAGE_ADDRESS_MASK: u64 = !(BASE_PAGE_SIZE as u64 - 1);
+
+/// `VMCALL; RET`
+const INTEL_THUNK: [u8; 4] = [0x0F, 0x01, 0xC1, 0xC3];
+
+/// `VMMCALL; RET`
+const AMD_THUNK: [u8; 4] = [0x0F, 0x01, 0xD9, 0xC3];
+
+/// `INT3`, used to fill the remainder of the page.
+const FILL_BYTE: u8 = 0xCC;
+
+/// Returns the hypercall thunk for the processor vendor, which determines the hypercall instruction.
+pub fn hypercall_thunk(vendor: CpuVendor) -> &'static [u8] {
+    match vendor {
+        CpuVendor::Intel => &INTEL_THUNK,
+        CpuVendor::Amd => &AMD_THUNK,
+    }
+}
+
+/// Returns the value of the hypercall page MSR as read by the guest.
+///
+/// # Arguments
+///
+/// * `paravirt` - The paravirtual interface.
+pub fn read_msr(paravirt: &ParavirtInterface) -> u64 {
+    match paravirt.hypercall_page() {
+        0 => 0,
+        gpa => gpa | HYPERCALL_PAGE_ENABLE,
+    }
+}
+
+/// Handles a guest write to the hypercall page MSR.
+///
+/// Populates the page with the thunk of the detected vendor and publishes its address through
+/// the paravirtual CPUID leaves, or withdraws the page if the enable bit is clear.
+///
+/// # Arguments
+///
+/// * `paravirt` - The paravirtual interface.
+/// * `value` - The value written by the guest.
+///
+/// # Returns
+///
+/// `true` if the write was accepted, `false` if the guest should receive #GP.
+pub fn write_msr(paravirt: &ParavirtInterface, value: u64) -> bool {
+    if value & HYPERCALL_PAGE_ENABLE == 0 {
+        log::debug!("Hypercall page disabled");
+        paravirt.set_hypercall_page(0);
+        return true;
+    }
+
+    let gpa = Gpa::new(value & HYPERCALL_PAGE_ADDRESS_MASK);
+
+    let Some(vendor) = cpu::vendor() else {
+        log::error!("Unknown processor vendor, cannot populate the hypercall page");
+        return false;
+    };
+
+    let Some(va) = gpa.to_hva() else {
+        log::error!("Hypercall page {:#x} is not mapped", gpa);
+        return false;
+    };
+
+    let page = unsafe { core::slice::from_raw_parts_mut(va.as_mut_ptr::<u8>(), BASE_PAGE_SIZE) };
+    let thunk = hypercall_thunk(vendor);
+    let (head, tail) = page.split_at_mut(thunk.len());
+    head.copy_from_slice(thunk);
+    tail.fill(FILL_BYTE);
+
+    log::debug!("Hypercall page populated at {:#x} for {:?}", gpa, vendor);
+    paravirt.set_hypercall_page(gpa.as_u64());
+
+    true
+}
@@ -0,0 +1,148 @@
+//! Hyper-V enlightenments used when running as an L1 (nested) hypervisor under Hyper-V.
+//!
+//! When nested under Hyper-V, every VMX instruction and every INVEPT is trapped and emulated by L0,
+//! which makes flushing and VMCS accesses considerably more expensive than on bare metal. Hyper-V
+//! advertises which nested-friendly paths it supports through its CPUID leaves; this module discovers
+//! them so the rest of the hypervisor can pick the cheaper path.
+//!
+//! The discovered enlightenments are used to reduce flushing (single-context INVEPT of the active EPTP
+//! instead of all-context INVEPT on every EPTP switch), and to access the VMCS in memory in the
+//! enlightened VMCS (eVMCS) format instead of with VMREAD/VMWRITE, see `intel::evmcs`.
+//!
+//! Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/nested-virtualization
+
+use {
+    crate::intel::{
+        evmcs::EVMCS_VERSION,
+        nested::{HostHypervisor, CPUID_HYPERVISOR_VENDOR},
+    },
+    core::fmt,
+    x86::cpuid::cpuid,
+};
+
+/// CPUID leaf reporting the implementation recommendations.
+const CPUID_HYPERV_RECOMMENDATIONS: u32 = 0x4000_0004;
+
+/// CPUID leaf reporting the nested hypervisor feature identification.
+const CPUID_HYPERV_NESTED_FEATURES: u32 = 0x4000_000A;
+
+/// CPUID.40000004H:EAX bit recommending the use of the enlightened VMCS.
+const RECOMMEND_ENLIGHTENED_VMCS: u32 = 1 << 14;
+
+/// CPUID.4000000AH:EAX bit indicating support for direct virtual flush hypercalls.
+const NESTED_DIRECT_FLUSH: u32 = 1 << 17;
+
+/// CPUID.4000000AH:EAX bit indicating support for HvFlushGuestPhysicalAddressSpace and HvFlushGuestPhysicalAddressList.
+const NESTED_FLUSH_GUEST_PHYSICAL: u32 = 1 << 18;
+
+/// CPUID.4000000AH:EAX bit indicating support for the enlightened MSR bitmap.
+const NESTED_ENLIGHTENED_MSR_BITMAP: u32 = 1 << 19;
+
+/// The enlightenments offered by Hyper-V to a nested hypervisor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Enlightenments {
+    /// Whether we run nested under Hyper-V at all.
+    pub nested_under_hyperv: bool,
+
+    /// The raw implementation recommendations (CPUID.40000004H:EAX).
+    pub recommendations: u32,
+
+    /// The raw nested feature identification (CPUID.4000000AH:EAX).
+    pub nested_features: u32,
+}
+
+impl Enlightenments {
+    /// Discovers the enlightenments offered by the hypervisor underneath us.
+    ///
+    /// # Arguments
+    ///
+    /// * `host_hypervisor` - The detected hypervisor running underneath us.
+    ///
+    /// # Returns
+    ///
+    /// The discovered `Enlightenments`, empty when not nested under Hyper-V.
+    pub fn detect(host_hypervisor: HostHypervisor) -> Self {
+        if !host_hypervisor.is_hyperv() {
+            return Self::default();
+        }
+
+        let max_leaf = cpuid!(CPUID_HYPERVISOR_VENDOR).eax;
+
+        let read_leaf = |leaf: u32| {
+            if max_leaf >= leaf {
+                cpuid!(leaf).eax
+            } else {
+                0
+            }
+        };
+
+        Self {
+            nested_under_hyperv: true,
+            recommendations: read_leaf(CPUID_HYPERV_RECOMMENDATIONS),
+            nested_features: read_leaf(CPUID_HYPERV_NESTED_FEATURES),
+        }
+    }
+
+    /// Returns whether Hyper-V recommends using the enlightened VMCS.
+    pub fn evmcs_recommended(&self) -> bool {
+        self.recommendations & RECOMMEND_ENLIGHTENED_VMCS != 0
+    }
+
+    /// Returns the supported enlightened VMCS version range as `(low, high)`, if any.
+    pub fn evmcs_version(&self) -> Option<(u8, u8)> {
+        let low = self.nested_features as u8;
+        let high = (self.nested_features >> 8) as u8;
+
+        if self.evmcs_recommended() && low != 0 {
+            Some((low, high))
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether the enlightened VMCS is to be used: Hyper-V recommends it and supports the version
+    /// implemented by `evmcs::EnlightenedVmcs`.
+    pub fn evmcs_supported(&self) -> bool {
+        matches!(self.evmcs_version(), Some((low, high)) if (low..=high).contains(&EVMCS_VERSION))
+    }
+
+    /// Returns whether the direct virtual flush hypercalls are available.
+    pub fn direct_flush(&self) -> bool {
+        self.nested_features & NESTED_DIRECT_FLUSH != 0
+    }
+
+    /// Returns whether the guest physical address space flush hypercalls are available.
+    pub fn flush_guest_physical(&self) -> bool {
+        self.nested_features & NESTED_FLUSH_GUEST_PHYSICAL != 0
+    }
+
+    /// Returns whether the enlightened MSR bitmap is available.
+    pub fn enlightened_msr_bitmap(&self) -> bool {
+        self.nested_features & NESTED_ENLIGHTENED_MSR_BITMAP != 0
+    }
+
+    /// Returns whether TLB flushing should be kept to the minimum required.
+    ///
+    /// When nested, each INVEPT is emulated by L0, so only the mappings of the active EPTP are
+    /// flushed instead of all contexts.
+    pub fn reduced_flush(&self) -> bool {
+        self.nested_under_hyperv
+    }
+}
+
+impl fmt::Display for Enlightenments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.nested_under_hyperv {
+            return write!(f, "None");
+        }
+
+        write!(
+            f,
+            "eVMCS: {:?}, Direct Flush: {}, Flush Guest Physical: {}, Enlightened MSR Bitmap: {}",
+            self.evmcs_version(),
+            self.direct_flush(),
+            self.flush_guest_physical(),
+            self.enlightened_msr_bitmap()
+        )
+    }
+}
@@ -0,0 +1,292 @@
+//! The VMX instructions, as the only inline assembly executing them.
+//!
+//! Every instruction reports its outcome in RFLAGS: CF set for VMfailInvalid, when there is no current VMCS to
+//! hold an error number, and ZF set for VMfailValid, whose error number is in the VM-instruction error field. The
+//! flags are read right after the instruction, so the compiler cannot clobber them in between, and are never
+//! declared preserved. The error number of a VMfailValid is read right away too and returned in `VmFail::Valid`,
+//! before another instruction can overwrite it.
+//!
+//! None of the blocks is declared `nomem`: the instructions read their memory operands, and reads and writes of
+//! the current VMCS must not be moved across one another, nor across the instructions loading another one.
+//!
+//! VMLAUNCH and VMRESUME stay in the `vmlaunch` stub, as they are executed after loading the guest registers and
+//! only return on failure.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.2 Conventions and 31.3 VMX
+//! Instructions.
+
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
+use {
+    crate::intel::{invvpid::InvvpidDescriptor, vmerror::VmInstructionError},
+    core::fmt,
+    x86::vmx::vmcs,
+};
+
+/// The failure of a VMX instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmFail {
+    /// CF was set: the instruction failed without a current VMCS to report why.
+    Invalid,
+
+    /// ZF was set: the instruction failed with the given value of the VM-instruction error field of the current
+    /// VMCS, see `VmInstructionError`.
+    Valid(u32),
+}
+
+impl fmt::Display for VmFail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "VMfailInvalid"),
+            Self::Valid(error) => match VmInstructionError::from_u32(*error) {
+                Some(error) => write!(f, "VMfailValid: {}", error),
+                None => write!(f, "VMfailValid: unknown error {:#x}", error),
+            },
+        }
+    }
+}
+
+/// Converts the flags captured after a VMX instruction to its outcome.
+///
+/// # Arguments
+///
+/// * `cf` - CF after the instruction, as set by SETC.
+/// * `zf` - ZF after the instruction, as set by SETZ.
+fn vm_result(cf: u8, zf: u8) -> Result<(), VmFail> {
+    match (cf, zf) {
+        (0, 0) => Ok(()),
+        // A VMfailValid is only reported in VMX root operation with a current VMCS, which holds its error number.
+        (0, _) => Err(VmFail::Valid(unsafe { instruction_error() })),
+        _ => Err(VmFail::Invalid),
+    }
+}
+
+/// Reads the VM-instruction error field of the current VMCS, explaining the last VMfailValid.
+///
+/// # Safety
+///
+/// Must be called in VMX root operation, with a current VMCS.
+unsafe fn instruction_error() -> u32 {
+    let value: u64;
+    core::arch::asm!(
+        "vmread {value}, {field}",
+        field = in(reg) u64::from(vmcs::ro::VM_INSTRUCTION_ERROR),
+        value = out(reg) value,
+        options(nostack),
+    );
+    value as u32
+}
+
+/// Executes VMXON, entering VMX root operation.
+///
+/// # Arguments
+///
+/// * `vmxon_region` - The physical address of the VMXON region.
+///
+/// # Safety
+///
+/// The region must be initialized with the VMCS revision identifier, and CR0 and CR4 hold the values VMX
+/// operation requires.
+pub unsafe fn vmxon(vmxon_region: u64) -> Result<(), VmFail> {
+    let (cf, zf): (u8, u8);
+    core::arch::asm!(
+        "vmxon [{region}]",
+        "setc {cf}",
+        "setz {zf}",
+        region = in(reg) &vmxon_region,
+        cf = out(reg_byte) cf,
+        zf = out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+/// Executes VMXOFF, leaving VMX operation.
+///
+/// # Safety
+///
+/// Must be called in VMX root operation, the processor then runs natively.
+pub unsafe fn vmxoff() -> Result<(), VmFail> {
+    let (cf, zf): (u8, u8);
+    core::arch::asm!(
+        "vmxoff",
+        "setc {cf}",
+        "setz {zf}",
+        cf = out(reg_byte) cf,
+        zf = out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+/// Executes VMCLEAR, flushing a VMCS to memory and marking it clear.
+///
+/// # Arguments
+///
+/// * `vmcs_region` - The physical address of the VMCS.
+///
+/// # Safety
+///
+/// Must be called in VMX root operation. A VMCS cleared while it is current is no longer current.
+pub unsafe fn vmclear(vmcs_region: u64) -> Result<(), VmFail> {
+    let (cf, zf): (u8, u8);
+    core::arch::asm!(
+        "vmclear [{region}]",
+        "setc {cf}",
+        "setz {zf}",
+        region = in(reg) &vmcs_region,
+        cf = out(reg_byte) cf,
+        zf = out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+/// Executes VMPTRLD, making a VMCS current.
+///
+/// # Arguments
+///
+/// * `vmcs_region` - The physical address of the VMCS.
+///
+/// # Safety
+///
+/// Must be called in VMX root operation, with a VMCS initialized with the VMCS revision identifier.
+pub unsafe fn vmptrld(vmcs_region: u64) -> Result<(), VmFail> {
+    let (cf, zf): (u8, u8);
+    core::arch::asm!(
+        "vmptrld [{region}]",
+        "setc {cf}",
+        "setz {zf}",
+        region = in(reg) &vmcs_region,
+        cf = out(reg_byte) cf,
+        zf = out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+/// Executes VMPTRST, returning the physical address of the current VMCS.
+///
+/// # Safety
+///
+/// Must be called in VMX root operation.
+pub unsafe fn vmptrst() -> Result<u64, VmFail> {
+    let mut vmcs_region = 0u64;
+    let (cf, zf): (u8, u8);
+    core::arch::asm!(
+        "vmptrst [{region}]",
+        "setc {cf}",
+        "setz {zf}",
+        region = in(reg) &mut vmcs_region,
+        cf = out(reg_byte) cf,
+        zf = out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf).map(|_| vmcs_region)
+}
+
+/// Executes VMREAD, reading a field of the current VMCS.
+///
+/// # Arguments
+///
+/// * `field` - The encoding of the field.
+///
+/// # Safety
+///
+/// Must be called in VMX root operation.
+pub unsafe fn vmread(field: u32) -> Result<u64, VmFail> {
+    let value: u64;
+    let (cf, zf): (u8, u8);
+    core::arch::asm!(
+        "vmread {value}, {field}",
+        "setc {cf}",
+        "setz {zf}",
+        field = in(reg) u64::from(field),
+        value = out(reg) value,
+        cf = out(reg_byte) cf,
+        zf = out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf).map(|_| value)
+}
+
+/// Executes VMWRITE, writing a field of the current VMCS.
+///
+/// # Arguments
+///
+/// * `field` - The encoding of the field.
+/// * `value` - The value, truncated to the width of the field.
+///
+/// # Safety
+///
+/// Must be called in VMX root operation. Writing the guest or host state changes what runs after the next VM
+/// entry or exit.
+pub unsafe fn vmwrite(field: u32, value: u64) -> Result<(), VmFail> {
+    let (cf, zf): (u8, u8);
+    core::arch::asm!(
+        "vmwrite {field}, {value}",
+        "setc {cf}",
+        "setz {zf}",
+        field = in(reg) u64::from(field),
+        value = in(reg) value,
+        cf = out(reg_byte) cf,
+        zf = out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+/// Executes INVEPT, invalidating the cached translations derived from EPT.
+///
+/// # Arguments
+///
+/// * `invept_type` - The type of invalidation, see `invept::InveptType`.
+/// * `descriptor` - The 128-bit INVEPT descriptor: the EPTP, then zero.
+///
+/// # Safety
+///
+/// Must be called in VMX root operation.
+pub unsafe fn invept(invept_type: u64, descriptor: &[u64; 2]) -> Result<(), VmFail> {
+    let (cf, zf): (u8, u8);
+    core::arch::asm!(
+        "invept {kind}, [{descriptor}]",
+        "setc {cf}",
+        "setz {zf}",
+        kind = in(reg) invept_type,
+        descriptor = in(reg) descriptor,
+        cf = out(reg_byte) cf,
+        zf = out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+/// Executes INVVPID, invalidating the cached translations tagged with a VPID.
+///
+/// # Arguments
+///
+/// * `invvpid_type` - The type of invalidation, see `invvpid::InvvpidType`.
+/// * `descriptor` - The INVVPID descriptor.
+///
+/// # Safety
+///
+/// Must be called in VMX root operation.
+pub unsafe fn invvpid(invvpid_type: u64, descriptor: &InvvpidDescriptor) -> Result<(), VmFail> {
+    let (cf, zf): (u8, u8);
+    core::arch::asm!(
+        "invvpid {kind}, [{descriptor}]",
+        "setc {cf}",
+        "setz {zf}",
+        kind = in(reg) invvpid_type,
+        descriptor = in(reg) descriptor,
+        cf = out(reg_byte) cf,
+        zf = out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
@@ -4,6 +4,11 @@
 //! that cache translations derived from EPT. It's used to ensure that modifications to EPT entries don't cause
 //! inconsistencies due to stale cached translations.
 
+use {
+    crate::intel::{intrinsics, ipi},
+    core::sync::atomic::{AtomicU64, Ordering},
+};
+
 /// Represents the types of INVEPT operations.
 #[repr(u64)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -28,21 +33,15 @@ pub enum InveptType {
 ///   concatenating the EPTP's memory type (bits 2:0), page-walk length (bits 5:3), and address of the EPTP
 ///   (bits 63:12). For All Contexts INVEPT, this value is ignored.
 ///
-/// # Safety
-/// This function is unsafe because it involves inline assembly and direct interaction with CPU features.
+/// A failure, e.g. for an invalidation type the processor does not support, is logged.
 fn invept(invept_type: InveptType, eptp: u64) {
     // The INVEPT descriptor is a 128-bit value. The first 64-bits (low part) should be 0 for All-Contexts
     // and the EPTP for Single-Context. The second 64-bits (high part) should always be 0.
     let descriptor: [u64; 2] = [eptp, 0];
 
-    unsafe {
-        core::arch::asm!(
-        "invept {0}, [{1}]",
-        in(reg) invept_type as u64,
-        in(reg) &descriptor,
-        options(nostack)
-        );
-    };
+    if let Err(fail) = unsafe { intrinsics::invept(invept_type as u64, &descriptor) } {
+        log::error!("INVEPT {:?} failed: {}", invept_type, fail);
+    }
 }
 
 /// Invalidates entries in the TLB and other processor structures that cache translations derived from EPT.
@@ -69,3 +68,67 @@ pub fn invept_all_contexts() {
     // The EPT pointer is irrelevant for this type of operation and is thus set to 0.
     invept(InveptType::AllContexts, 0);
 }
+
+/// The generation of the shared EPTs, incremented by `invept_all_processors` whenever an exit handler changed
+/// them.
+static EPT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Invalidates the EPT derived translations of all processors after an exit handler changed the shared EPTs.
+///
+/// INVEPT only affects the processor executing it, and an exit handler cannot run code on other processors. The
+/// current processor is flushed right away, and every other processor flushes before it handles its next VM exit,
+/// see `EptFlush::sync`. Until then, another processor may still use a stale translation: a stale restriction
+/// causes an EPT violation, which is retried after the flush, while a stale permission lets its accesses through
+/// until that VM exit.
+///
+/// Code running in the guest, where INVEPT causes a VM exit, flushes every processor synchronously with
+/// `host_call::flush_ept_on_all_processors` instead.
+pub fn invept_all_processors() {
+    EPT_GENERATION.fetch_add(1, Ordering::SeqCst);
+    invept_all_contexts();
+}
+
+/// Invalidates the EPT derived translations of all processors after an exit handler took permissions away from
+/// the guest in the shared EPTs.
+///
+/// Like `invept_all_processors`, but the other virtualized processors are kicked out of the guest with an NMI, see
+/// `ipi`, so a stale permission is not used past the kick. Falls back to the lazy flush of `invept_all_processors`
+/// if they cannot be kicked, which is logged.
+pub fn invept_broadcast() {
+    invept_all_processors();
+
+    if !ipi::kick_other_processors() {
+        log::trace!("Other processors flush their EPT derived translations at their next VM exit");
+    }
+}
+
+/// The generation of the shared EPTs a processor last flushed its translations for.
+#[derive(Debug, Default)]
+pub struct EptFlush {
+    /// The generation of the last flush.
+    generation: u64,
+
+    /// Whether the last call to `sync` flushed the translations.
+    flushed: bool,
+}
+
+impl EptFlush {
+    /// Flushes the translations of the current processor if the shared EPTs changed since its last flush.
+    ///
+    /// Called at the start of every VM exit.
+    pub fn sync(&mut self) {
+        let generation = EPT_GENERATION.load(Ordering::SeqCst);
+
+        self.flushed = generation != self.generation;
+        if self.flushed {
+            self.generation = generation;
+            invept_all_contexts();
+        }
+    }
+
+    /// Returns whether the translations were flushed at the start of the current VM exit, in which case an EPT
+    /// violation may have been caused by a stale translation.
+    pub fn flushed(&self) -> bool {
+        self.flushed
+    }
+}
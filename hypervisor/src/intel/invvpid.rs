@@ -4,6 +4,8 @@
 //! that are associated with a specific Virtual Processor Identifier (VPID). This is essential in virtualization
 //! environments to maintain consistency of memory translations across different virtual processors.
 
+use crate::{intel::intrinsics, utils::cpu};
+
 pub const VPID_TAG: u16 = 0x1;
 
 /// Represents the types of INVVPID operations.
@@ -45,24 +47,28 @@ pub struct InvvpidDescriptor {
 /// # Arguments
 /// * `invvpid_type` - The type of invalidation to perform.
 /// * `descriptor` - The INVVPID descriptor.
+///
+/// A failure, e.g. for an invalidation type the processor does not support, is logged.
 fn invvpid(invvpid_type: InvvpidType, descriptor: &InvvpidDescriptor) {
-    let descriptor_ptr = descriptor as *const _ as u64;
-    unsafe {
-        core::arch::asm!(
-        "invvpid {0}, [{1}]",
-        in(reg) invvpid_type as u64,
-        in(reg) descriptor_ptr,
-        options(nostack)
-        );
+    if let Err(fail) = unsafe { intrinsics::invvpid(invvpid_type as u64, descriptor) } {
+        log::error!("INVVPID {:?} failed: {}", invvpid_type, fail);
     }
 }
 
 /// Invalidates TLB and paging-structure cache entries associated with a specific linear address and VPID.
 ///
+/// Processors without the individual-address type invalidate all the mappings of the VPID instead, which
+/// includes those of the address.
+///
 /// # Arguments
 /// * `vpid` - Virtual Processor Identifier.
 /// * `linear_address` - Specific linear address whose mappings are to be invalidated.
 pub fn invvpid_individual_address(vpid: u16, linear_address: u64) {
+    if !cpu::has_invvpid_individual_address() {
+        invvpid_single_context(vpid);
+        return;
+    }
+
     let descriptor = InvvpidDescriptor {
         vpid,
         reserved: [0; 3], // Reserved fields, must be zero
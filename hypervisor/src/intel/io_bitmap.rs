@@ -0,0 +1,76 @@
+//! This module provides utilities and structures to manage the I/O bitmaps in VMX.
+//! The I/O bitmaps control which I/O ports cause a VM exit when accessed with IN, INS, OUT or OUTS
+//! in a virtualized environment.
+
+use {
+    crate::{error::HypervisorError, utils::alloc::PhysicalAllocator},
+    alloc::boxed::Box,
+};
+
+/// Represents the I/O bitmaps used in VMX.
+///
+/// The VM-execution control fields include the 64-bit physical addresses of I/O bitmaps A and B, which are
+/// each 4-KByte in size. Bitmap A contains one bit for each I/O port in the range 0000H through 7FFFH and
+/// bitmap B for the ports in the range 8000H through FFFFH. Accessing a port whose bit is set causes a VM exit.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.4 I/O-Bitmap Addresses
+#[repr(C, align(4096))]
+pub struct IoBitmap {
+    /// Bitmap A, for the I/O ports 0000H through 7FFFH.
+    pub bitmap_a: [u8; 0x1000],
+
+    /// Bitmap B, for the I/O ports 8000H through FFFFH.
+    pub bitmap_b: [u8; 0x1000],
+}
+
+impl IoBitmap {
+    /// Sets up the I/O bitmaps, with no port intercepted.
+    ///
+    /// # Returns
+    /// * A `Result` containing the boxed I/O bitmaps, or an error if the allocation failed.
+    pub fn new() -> Result<Box<IoBitmap, PhysicalAllocator>, HypervisorError> {
+        log::trace!("Setting up I/O Bitmap");
+
+        let instance = unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
+
+        log::trace!("I/O Bitmap setup successfully!");
+
+        Ok(instance)
+    }
+
+    /// Enables or disables the interception of an I/O port.
+    ///
+    /// # Arguments
+    /// * `port` - The I/O port.
+    /// * `enable` - Whether accesses to the port cause a VM exit.
+    pub fn intercept_port(&mut self, port: u16, enable: bool) {
+        let (bitmap, index) = match port {
+            0x0000..=0x7FFF => (&mut self.bitmap_a, port),
+            _ => (&mut self.bitmap_b, port - 0x8000),
+        };
+
+        if let Some(byte) = bitmap.get_mut(index as usize / 8) {
+            let bit = 1 << (index % 8);
+            if enable {
+                *byte |= bit;
+            } else {
+                *byte &= !bit;
+            }
+        }
+    }
+
+    /// Returns whether an I/O port is intercepted.
+    ///
+    /// # Arguments
+    /// * `port` - The I/O port.
+    pub fn is_intercepted(&self, port: u16) -> bool {
+        let (bitmap, index) = match port {
+            0x0000..=0x7FFF => (&self.bitmap_a, port),
+            _ => (&self.bitmap_b, port - 0x8000),
+        };
+
+        bitmap
+            .get(index as usize / 8)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+}
@@ -0,0 +1,160 @@
+//! Monitoring of the I/O ports accessed by the guest, e.g. legacy devices such as the keyboard controller.
+//!
+//! Ports are monitored with `HypervisorBuilder::io_intercept` or at runtime with `Hypervisor::set_io_intercept`,
+//! which intercepts them in the I/O bitmaps shared by all processors. Every IN, OUT, INS and OUTS of the guest
+//! to a monitored port then exits, is carried out on behalf of the guest by `vmexit::io`, and is recorded as an
+//! `IoAccess`, with the value transferred by IN and OUT. The accesses are drained with
+//! `Hypervisor::drain_io_accesses`.
+//!
+//! The ports of the keyboard controller stay intercepted while keyboard protection needs them, see
+//! `keyboard_guard`, and their accesses are checked by it before being recorded.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.4 I/O-Bitmap Addresses and
+//! Table 28-5. Exit Qualification for I/O Instructions.
+
+use {
+    crate::utils::{event_log::EventLog, timestamp::Timestamp},
+    core::{
+        ops::RangeInclusive,
+        sync::atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// The number of accesses kept until they are drained.
+const EVENT_LOG_LEN: usize = 64;
+
+/// The number of words of the set of monitored ports, one bit per port.
+const PORT_WORDS: usize = 0x10000 / 64;
+
+/// An access of the guest to a monitored I/O port.
+#[derive(Debug, Clone, Copy)]
+pub struct IoAccess {
+    /// The accessed port.
+    pub port: u16,
+
+    /// The size of the access in bytes (1, 2 or 4).
+    pub size: u8,
+
+    /// Whether the access was a write (OUT, OUTS) rather than a read (IN, INS).
+    pub write: bool,
+
+    /// Whether the access was a string instruction (INS, OUTS).
+    pub string: bool,
+
+    /// Whether the string instruction had a REP prefix.
+    pub rep: bool,
+
+    /// The value read or written by IN or OUT, or `None` for a string instruction.
+    pub value: Option<u32>,
+
+    /// Whether the access was suppressed by keyboard protection.
+    pub blocked: bool,
+
+    /// The guest instruction pointer of the access.
+    pub rip: u64,
+
+    /// The guest CR3 at the time of the access, identifying the process.
+    pub cr3: u64,
+
+    /// The index of the processor.
+    pub processor: u32,
+
+    /// When the access happened.
+    pub timestamp: Timestamp,
+}
+
+/// The monitored I/O ports and their accesses.
+pub struct IoMonitor {
+    /// The monitored ports, one bit per port.
+    ports: [AtomicU64; PORT_WORDS],
+
+    /// The accesses not drained yet.
+    accesses: EventLog<IoAccess, EVENT_LOG_LEN>,
+}
+
+impl IoMonitor {
+    /// Creates the monitor, with no port monitored.
+    pub fn new() -> Self {
+        Self {
+            ports: core::array::from_fn(|_| AtomicU64::new(0)),
+            accesses: EventLog::new("io_accesses"),
+        }
+    }
+
+    /// Starts or stops monitoring a range of ports.
+    ///
+    /// The caller intercepts the ports in the I/O bitmaps, after this call so no intercepted access goes
+    /// unrecorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `ports` - The ports.
+    /// * `monitor` - Whether the accesses to the ports are recorded.
+    pub fn set_monitored(&self, ports: RangeInclusive<u16>, monitor: bool) {
+        for port in ports {
+            let (word, bit) = Self::position(port);
+            if let Some(word) = self.ports.get(word) {
+                match monitor {
+                    true => word.fetch_or(bit, Ordering::Release),
+                    false => word.fetch_and(!bit, Ordering::Release),
+                };
+            }
+        }
+    }
+
+    /// Returns whether a port is monitored.
+    pub fn is_monitored(&self, port: u16) -> bool {
+        let (word, bit) = Self::position(port);
+        self.ports
+            .get(word)
+            .is_some_and(|word| word.load(Ordering::Acquire) & bit != 0)
+    }
+
+    /// Returns whether any port is monitored.
+    pub fn is_enabled(&self) -> bool {
+        self.ports
+            .iter()
+            .any(|word| word.load(Ordering::Relaxed) != 0)
+    }
+
+    /// Records an access to a monitored port.
+    pub fn record(&self, access: IoAccess) {
+        log::trace!(
+            "I/O {} of port {:#x} from {:#x}",
+            if access.write { "write" } else { "read" },
+            access.port,
+            access.rip
+        );
+
+        self.accesses.push(access);
+    }
+
+    /// Hands the pending accesses to a consumer, oldest first, and removes them.
+    ///
+    /// # Arguments
+    ///
+    /// * `consumer` - Called for each access.
+    ///
+    /// # Returns
+    ///
+    /// The number of accesses drained.
+    pub fn drain_accesses(&self, consumer: impl FnMut(&IoAccess)) -> usize {
+        self.accesses.drain(consumer)
+    }
+
+    /// Returns the total number of accesses recorded, including the ones that were overwritten.
+    pub fn total_accesses(&self) -> u64 {
+        self.accesses.total()
+    }
+
+    /// Returns the word and the bit of a port in the set of monitored ports.
+    fn position(port: u16) -> (usize, u64) {
+        (port as usize / 64, 1 << (port % 64))
+    }
+}
+
+impl Default for IoMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
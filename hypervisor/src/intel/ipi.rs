@@ -0,0 +1,322 @@
+//! NMIs sent from VMX root operation to the other virtualized processors, to make them leave the guest right
+//! away.
+//!
+//! An exit handler cannot run code on other processors, see `invept::invept_all_processors`. A change of the
+//! shared EPTs that takes permissions away from the guest must however not wait for the next VM exit of the
+//! other processors, so they are kicked with an NMI:
+//! - A processor running the guest exits on the NMI, as NMIs are intercepted, and flushes its EPT derived
+//!   translations at the start of the exit, see `EptFlush::sync`. The NMI is then dropped instead of being
+//!   reflected to the guest.
+//! - A processor in VMX root operation receives the NMI through its host IDT, whose NMI handler flushes the
+//!   translations itself, so they are gone before the processor enters the guest again.
+//!
+//! Each processor kicked has a pending kick flag, set before the NMI is sent and consumed by the handler. An NMI
+//! arriving while the flag is set is taken for the kick: a real NMI received at that moment is dropped with it.
+//! NMIs that are not kicks reach the NMI handler of the kernel as before.
+//!
+//! Only processors that exited once are kicked, as they run with the host IDT, and only if NMIs are intercepted,
+//! see `cpu::has_virtual_nmis`: an NMI the kernel does not expect bug checks the system.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 11.6.1 Interrupt Command Register
+//! (ICR), 11.12.9 ICR Operation in x2APIC Mode, 6.14.1 64-Bit Mode IDT and 26.2 Other Causes of VM Exits.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{invept::invept_all_contexts, x2apic::is_x2apic_enabled},
+        utils::{
+            cpu,
+            instructions::{rdmsr, wrmsr},
+            processor::{current_processor_index, MAX_VCPUS},
+        },
+    },
+    core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering},
+    wdk_sys::{LARGE_INTEGER, PVOID},
+    x86::{cpuid::cpuid, msr},
+};
+
+/// The vector of the NMI in the IDT.
+const NMI_VECTOR: usize = 2;
+
+/// The x2APIC MSR of the ICR.
+const X2APIC_ICR: u32 = 0x830;
+
+/// The offsets of the low and high halves of the ICR in the xAPIC page.
+const XAPIC_ICR_LOW: usize = 0x300;
+const XAPIC_ICR_HIGH: usize = 0x310;
+
+/// The position of the destination in the high half of the xAPIC ICR.
+const XAPIC_DESTINATION_SHIFT: u32 = 24;
+
+/// The NMI delivery mode of the ICR, with a physical destination and no shorthand.
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// The size of the xAPIC page mapped.
+const XAPIC_PAGE_SIZE: u64 = 0x1000;
+
+/// The bits of IA32_APIC_BASE holding the address of the xAPIC page.
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// `MmNonCached` of `MEMORY_CACHING_TYPE`.
+const MM_NON_CACHED: i32 = 0;
+
+/// An APIC ID marking a processor that cannot be kicked.
+const NOT_KICKABLE: u32 = u32::MAX;
+
+/// The APIC IDs of the processors that can be kicked, by processor index.
+static APIC_IDS: [AtomicU32; MAX_VCPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNREGISTERED: AtomicU32 = AtomicU32::new(NOT_KICKABLE);
+    [UNREGISTERED; MAX_VCPUS]
+};
+
+/// The pending kicks, by processor index.
+static KICKS: [AtomicBool; MAX_VCPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const IDLE: AtomicBool = AtomicBool::new(false);
+    [IDLE; MAX_VCPUS]
+};
+
+/// The NMI handler of the kernel, which the host NMI handler passes the NMIs that are not kicks to.
+#[no_mangle]
+static KERNEL_NMI_HANDLER: AtomicU64 = AtomicU64::new(0);
+
+/// The xAPIC page mapped with `map_xapic`, or null in x2APIC mode.
+static XAPIC_PAGE: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+
+extern "C" {
+    /// The NMI handler of the host IDT, see `host_nmi`.
+    fn host_nmi_stub();
+}
+
+core::arch::global_asm!(
+    r#"
+.global host_nmi_stub
+host_nmi_stub:
+    // The volatile registers are saved, the stack is aligned on 16 bytes after the interrupt frame and them.
+    push rax
+    push rcx
+    push rdx
+    push r8
+    push r9
+    push r10
+    push r11
+    sub rsp, 0x80
+    movaps [rsp + 0x20], xmm0
+    movaps [rsp + 0x30], xmm1
+    movaps [rsp + 0x40], xmm2
+    movaps [rsp + 0x50], xmm3
+    movaps [rsp + 0x60], xmm4
+    movaps [rsp + 0x70], xmm5
+
+    call host_nmi
+
+    movaps xmm0, [rsp + 0x20]
+    movaps xmm1, [rsp + 0x30]
+    movaps xmm2, [rsp + 0x40]
+    movaps xmm3, [rsp + 0x50]
+    movaps xmm4, [rsp + 0x60]
+    movaps xmm5, [rsp + 0x70]
+    add rsp, 0x80
+    test al, al
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdx
+    pop rcx
+    pop rax
+    jz 2f
+    iretq
+
+    // Not a kick, the kernel handles the NMI.
+2:
+    jmp qword ptr [rip + KERNEL_NMI_HANDLER]
+"#
+);
+
+/// Called by `host_nmi_stub` for every NMI received in VMX root operation.
+///
+/// # Returns
+///
+/// Whether the NMI was a kick, which is done with once the translations are flushed.
+#[no_mangle]
+extern "C" fn host_nmi() -> bool {
+    if !take_kick() {
+        return false;
+    }
+
+    invept_all_contexts();
+    true
+}
+
+/// Replaces the NMI handler of a copy of the IDT with the host NMI handler, see `host_nmi`.
+///
+/// The gate keeps its selector, type and interrupt stack, so the handler runs on the NMI stack of the kernel.
+///
+/// # Arguments
+///
+/// * `idt` - The IDT of the host, as 8-byte halves of its 16-byte gates.
+pub fn install_nmi_handler(idt: &mut [u64]) {
+    let Some([low, high]) = idt
+        .get_mut(NMI_VECTOR * 2..NMI_VECTOR * 2 + 2)
+        .and_then(|gate| <&mut [u64; 2]>::try_from(gate).ok())
+    else {
+        return;
+    };
+
+    let kernel_handler = (*low & 0xFFFF) | ((*low >> 48) << 16) | (*high << 32);
+    let handler = host_nmi_stub as *const () as u64;
+
+    // Every processor copies the IDT of the kernel, never one the handler is installed in already.
+    if kernel_handler == handler {
+        return;
+    }
+    KERNEL_NMI_HANDLER.store(kernel_handler, Ordering::Release);
+
+    *low = (*low & 0x0000_FFFF_FFFF_0000) | (handler & 0xFFFF) | ((handler >> 16) << 48);
+    *high = (*high & !0xFFFF_FFFF) | (handler >> 32);
+}
+
+/// Maps the xAPIC page, unless the local APIC runs in x2APIC mode. Must be called at PASSIVE_LEVEL before the
+/// processors are virtualized.
+///
+/// # Returns
+///
+/// A `Result` which is `Err(HypervisorError::XApicMappingFailed)` if the page could not be mapped.
+pub fn map_xapic() -> Result<(), HypervisorError> {
+    if is_x2apic_enabled() || !XAPIC_PAGE.load(Ordering::Acquire).is_null() {
+        return Ok(());
+    }
+
+    let address = LARGE_INTEGER {
+        QuadPart: (rdmsr(msr::IA32_APIC_BASE) & APIC_BASE_ADDRESS_MASK) as i64,
+    };
+
+    let page = unsafe { MmMapIoSpace(address, XAPIC_PAGE_SIZE, MM_NON_CACHED) };
+    if page.is_null() {
+        return Err(HypervisorError::XApicMappingFailed);
+    }
+
+    XAPIC_PAGE.store(page as *mut u8, Ordering::Release);
+    Ok(())
+}
+
+/// Unmaps the xAPIC page mapped with `map_xapic`, once no processor is virtualized anymore.
+pub fn unmap_xapic() {
+    let page = XAPIC_PAGE.swap(core::ptr::null_mut(), Ordering::AcqRel);
+    if !page.is_null() {
+        unsafe { MmUnmapIoSpace(page as PVOID, XAPIC_PAGE_SIZE) };
+    }
+}
+
+/// Lets the current processor be kicked, once it runs with the host IDT. Called on every VM exit.
+pub fn register_processor() {
+    let index = current_processor_index() as usize;
+    let Some(slot) = APIC_IDS.get(index) else {
+        return;
+    };
+
+    if slot.load(Ordering::Relaxed) == NOT_KICKABLE {
+        slot.store(current_apic_id(), Ordering::Release);
+    }
+}
+
+/// Stops kicking the current processor, before it leaves VMX operation.
+pub fn unregister_processor() {
+    let index = current_processor_index() as usize;
+
+    if let Some(slot) = APIC_IDS.get(index) {
+        slot.store(NOT_KICKABLE, Ordering::Release);
+    }
+    if let Some(kick) = KICKS.get(index) {
+        kick.store(false, Ordering::Release);
+    }
+}
+
+/// Kicks the other virtualized processors out of the guest.
+///
+/// # Returns
+///
+/// Whether the processors were kicked, which is not the case if NMIs are not intercepted or the local APIC cannot
+/// be accessed.
+pub fn kick_other_processors() -> bool {
+    if !cpu::has_virtual_nmis() {
+        return false;
+    }
+
+    let x2apic = is_x2apic_enabled();
+    let xapic_page = XAPIC_PAGE.load(Ordering::Acquire);
+    if !x2apic && xapic_page.is_null() {
+        return false;
+    }
+
+    let current = current_processor_index() as usize;
+
+    for (index, (apic_id, kick)) in APIC_IDS.iter().zip(&KICKS).enumerate() {
+        let apic_id = apic_id.load(Ordering::Acquire);
+        if index == current || apic_id == NOT_KICKABLE {
+            continue;
+        }
+
+        // An NMI still pending delivers the flush as well.
+        if kick.swap(true, Ordering::AcqRel) {
+            continue;
+        }
+
+        match x2apic {
+            true => wrmsr(
+                X2APIC_ICR,
+                (u64::from(apic_id) << 32) | u64::from(ICR_DELIVERY_NMI),
+            ),
+            false => unsafe { send_xapic_nmi(xapic_page, apic_id) },
+        }
+    }
+
+    true
+}
+
+/// Consumes the pending kick of the current processor.
+///
+/// # Returns
+///
+/// Whether a kick was pending, in which case the NMI received is the kick and must not be reflected.
+pub fn take_kick() -> bool {
+    KICKS
+        .get(current_processor_index() as usize)
+        .is_some_and(|kick| kick.swap(false, Ordering::AcqRel))
+}
+
+/// Sends an NMI through the xAPIC ICR.
+///
+/// The high half of the ICR is restored, as the guest may have been interrupted between writing both halves.
+unsafe fn send_xapic_nmi(page: *mut u8, apic_id: u32) {
+    let high = page.add(XAPIC_ICR_HIGH) as *mut u32;
+    let low = page.add(XAPIC_ICR_LOW) as *mut u32;
+
+    let guest_high = core::ptr::read_volatile(high);
+    core::ptr::write_volatile(high, apic_id << XAPIC_DESTINATION_SHIFT);
+    core::ptr::write_volatile(low, ICR_DELIVERY_NMI);
+    core::ptr::write_volatile(high, guest_high);
+}
+
+/// Returns the APIC ID of the current processor, as the ICR addresses it.
+fn current_apic_id() -> u32 {
+    match is_x2apic_enabled() {
+        true => rdmsr(msr::IA32_X2APIC_APICID) as u32,
+        false => cpuid!(0x1).ebx >> 24,
+    }
+}
+
+#[link(name = "ntoskrnl")]
+extern "system" {
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-mmmapiospace
+    fn MmMapIoSpace(
+        physical_address: LARGE_INTEGER,
+        number_of_bytes: u64,
+        cache_type: i32,
+    ) -> PVOID;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-mmunmapiospace
+    fn MmUnmapIoSpace(base_address: PVOID, number_of_bytes: u64);
+}
@@ -0,0 +1,151 @@
+//! Protection of the keyboard against sniffing through the i8042 controller.
+//!
+//! Keyloggers running in the guest kernel can bypass the input stack entirely by polling the PS/2 keyboard
+//! controller: reading the status port (0x64) until the output buffer is full, then reading the scan code
+//! from the data port (0x60). When keyboard protection is enabled, both ports are intercepted through the
+//! I/O bitmap and every access is checked against an allow list of guest code ranges, typically the
+//! keyboard port driver. Accesses from anywhere else raise an event, and are either still carried out
+//! (`Monitor`) or suppressed (`Block`), in which case reads return 0 and writes are dropped.
+//!
+//! Only the legacy i8042 ports are covered; USB keyboards are read through their host controller and
+//! are not protected.
+
+use {
+    crate::utils::{event_log::EventLog, timestamp::Timestamp},
+    alloc::vec::Vec,
+    core::ops::Range,
+};
+
+/// The i8042 data port, holding the scan codes.
+pub const I8042_DATA_PORT: u16 = 0x60;
+
+/// The i8042 status (read) and command (write) port.
+pub const I8042_COMMAND_PORT: u16 = 0x64;
+
+/// The guest modules allowed to access the keyboard controller in addition to the configured ones:
+/// the keyboard port driver, and the kernel, which resets the system through the controller.
+pub const DEFAULT_ALLOWED_MODULES: [&str; 2] = ["i8042prt.sys", "ntoskrnl.exe"];
+
+/// The number of events kept until they are drained.
+const EVENT_LOG_LEN: usize = 64;
+
+/// What to do with keyboard controller accesses from code that is not allow-listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyboardProtection {
+    /// The keyboard controller ports are not intercepted.
+    #[default]
+    Disabled,
+
+    /// Raise an event and carry out the access.
+    Monitor,
+
+    /// Raise an event and suppress the access.
+    Block,
+}
+
+/// An access to the keyboard controller from code that is not allow-listed.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyboardAccess {
+    /// The guest instruction pointer of the access.
+    pub rip: u64,
+
+    /// The guest CR3 at the time of the access, identifying the process.
+    pub cr3: u64,
+
+    /// The accessed port.
+    pub port: u16,
+
+    /// Whether the access was a write (OUT) rather than a read (IN).
+    pub write: bool,
+
+    /// Whether the access was suppressed.
+    pub blocked: bool,
+
+    /// When the access happened.
+    pub timestamp: Timestamp,
+}
+
+/// Checks keyboard controller accesses against the allow list and records the offending ones.
+pub struct KeyboardGuard {
+    /// The configured protection.
+    protection: KeyboardProtection,
+
+    /// The guest code ranges allowed to access the keyboard controller.
+    allowed: Vec<Range<u64>>,
+
+    /// The events not drained yet.
+    events: EventLog<KeyboardAccess, EVENT_LOG_LEN>,
+}
+
+impl KeyboardGuard {
+    /// Creates the guard.
+    ///
+    /// # Arguments
+    ///
+    /// * `protection` - What to do with accesses from code that is not allow-listed.
+    /// * `allowed` - The guest code ranges allowed to access the keyboard controller.
+    pub fn new(protection: KeyboardProtection, allowed: Vec<Range<u64>>) -> Self {
+        Self {
+            protection,
+            allowed,
+            events: EventLog::new("keyboard_events"),
+        }
+    }
+
+    /// Returns the configured protection.
+    pub fn protection(&self) -> KeyboardProtection {
+        self.protection
+    }
+
+    /// Returns whether the keyboard controller ports are intercepted.
+    pub fn is_enabled(&self) -> bool {
+        self.protection != KeyboardProtection::Disabled
+    }
+
+    /// Returns whether the accesses to a port are checked by the guard, i.e. the port belongs to the keyboard
+    /// controller and keyboard protection is enabled.
+    pub fn guards(&self, port: u16) -> bool {
+        self.is_enabled() && matches!(port, I8042_DATA_PORT | I8042_COMMAND_PORT)
+    }
+
+    /// Returns whether the code at the given guest address may access the keyboard controller.
+    pub fn is_allowed(&self, rip: u64) -> bool {
+        self.allowed.iter().any(|range| range.contains(&rip))
+    }
+
+    /// Records an access from code that is not allow-listed.
+    ///
+    /// # Arguments
+    ///
+    /// * `access` - The offending access.
+    pub fn record(&self, access: KeyboardAccess) {
+        log::warn!(
+            "Keyboard controller {} of port {:#x} from {:#x} (CR3 {:#x}){}",
+            if access.write { "write" } else { "read" },
+            access.port,
+            access.rip,
+            access.cr3,
+            if access.blocked { ", blocked" } else { "" }
+        );
+
+        self.events.push(access);
+    }
+
+    /// Hands the pending events to a consumer, oldest first, and removes them.
+    ///
+    /// # Arguments
+    ///
+    /// * `consumer` - Called for each event.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    pub fn drain_events(&self, consumer: impl FnMut(&KeyboardAccess)) -> usize {
+        self.events.drain(consumer)
+    }
+
+    /// Returns the total number of events raised, including the ones that were overwritten.
+    pub fn total_events(&self) -> u64 {
+        self.events.total()
+    }
+}
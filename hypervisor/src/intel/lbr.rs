@@ -0,0 +1,222 @@
+//! Virtualization of the Last Branch Record (LBR) stack.
+//!
+//! IA32_DEBUGCTL, which enables the LBRs, is saved on VM exit and loaded on VM entry through the "save debug
+//! controls" and "load debug controls" VMX controls, so a guest recording branches keeps recording them
+//! across VM exits. A VM exit clears IA32_DEBUGCTL, so no branch is recorded while in root mode.
+//!
+//! Optionally, enabled with `HypervisorBuilder::lbr_virtualization`, the LBR stack itself is part of the guest
+//! state: it is stored into the VM-exit MSR-store area on every VM exit and loaded back from the same area,
+//! used as the VM-entry MSR-load area, on every VM entry. The guest then finds its branches exactly as it left
+//! them, regardless of what root mode did with the LBR MSRs, e.g. a debugger attached to the host recording
+//! branches of its own.
+//!
+//! Only the legacy, model-specific LBR stack is virtualized. CPUID does not enumerate its depth, which is looked
+//! up by family and model in the table of the SDM, and processors missing from the table are not virtualized.
+//! The LBR format reported in IA32_PERF_CAPABILITIES tells whether the entries have LBR_INFO MSRs. Architectural
+//! LBRs are not supported.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 18.4.8 LBR Stack, Table 18-4. LBR
+//! Stack Size and TOS Pointer Range, 25.7.2 VM-Exit Controls for MSRs and 25.8.2 VM-Entry Controls for MSRs.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::support::try_vmwrite,
+        utils::{addresses::PhysicalAddress, alloc::PhysicalAllocator, cpu, instructions::rdmsr},
+    },
+    alloc::boxed::Box,
+    x86::{cpuid::cpuid, vmx::vmcs},
+};
+
+/// CPUID.01H:ECX bit indicating support for IA32_PERF_CAPABILITIES.
+const CPUID_01_ECX_PDCM: u32 = 1 << 15;
+
+/// CPUID.(EAX=07H,ECX=0):EDX bit indicating support for architectural LBRs.
+const CPUID_07_EDX_ARCH_LBR: u32 = 1 << 19;
+
+/// The MSR reporting the LBR format in bits 5:0.
+const IA32_PERF_CAPABILITIES: u32 = 0x345;
+
+/// The LBR format of processors without LBRs.
+const LBR_FORMAT_NONE: u64 = 0x3F;
+
+/// The LBR formats with LBR_INFO MSRs, of Skylake and of Tremont. The timed format of Goldmont Plus, in between,
+/// has none.
+const LBR_FORMATS_INFO: [u64; 2] = [5, 7];
+
+/// The MSR filtering the branches recorded.
+const MSR_LBR_SELECT: u32 = 0x1C8;
+
+/// The MSR pointing to the most recent entry of the stack.
+const MSR_LASTBRANCH_TOS: u32 = 0x1C9;
+
+/// The first MSR of the source addresses.
+const MSR_LASTBRANCH_0_FROM_IP: u32 = 0x680;
+
+/// The first MSR of the destination addresses.
+const MSR_LASTBRANCH_0_TO_IP: u32 = 0x6C0;
+
+/// The first MSR of the branch information.
+const MSR_LBR_INFO_0: u32 = 0xDC0;
+
+/// The number of entries of an MSR area, enough for the deepest LBR stack with LBR_INFO MSRs.
+pub const MSR_AREA_ENTRIES: usize = 128;
+
+/// The LBR stack of the processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LbrStack {
+    /// The number of entries of the stack.
+    pub depth: u32,
+
+    /// Whether the entries have an LBR_INFO MSR besides their source and destination.
+    pub has_info: bool,
+}
+
+impl LbrStack {
+    /// Detects the LBR stack of the processor.
+    ///
+    /// # Returns
+    ///
+    /// The LBR stack, or `None` if the processor has no legacy LBR stack.
+    pub fn detect() -> Option<Self> {
+        if cpuid!(0x1).ecx & CPUID_01_ECX_PDCM == 0
+            || cpuid!(0x7, 0x0).edx & CPUID_07_EDX_ARCH_LBR != 0
+        {
+            return None;
+        }
+
+        let depth = legacy_depth()?;
+
+        let format = rdmsr(IA32_PERF_CAPABILITIES) & 0x3F;
+        if format == LBR_FORMAT_NONE {
+            return None;
+        }
+
+        Some(Self {
+            depth,
+            has_info: LBR_FORMATS_INFO.contains(&format),
+        })
+    }
+
+    /// Returns the MSRs holding the state of the stack.
+    pub fn msrs(&self) -> impl Iterator<Item = u32> {
+        let depth = self.depth;
+        let info_depth = if self.has_info { depth } else { 0 };
+
+        [MSR_LBR_SELECT, MSR_LASTBRANCH_TOS]
+            .into_iter()
+            .chain((0..depth).map(|index| MSR_LASTBRANCH_0_FROM_IP + index))
+            .chain((0..depth).map(|index| MSR_LASTBRANCH_0_TO_IP + index))
+            .chain((0..info_depth).map(|index| MSR_LBR_INFO_0 + index))
+    }
+}
+
+/// Returns the depth of the legacy LBR stack of the processor, from Table 18-4 of the SDM.
+///
+/// # Returns
+///
+/// The number of entries, or `None` if the processor is not in the table, e.g. a NetBurst processor, whose LBR
+/// MSRs are at other addresses.
+fn legacy_depth() -> Option<u32> {
+    let (family, model) = cpu::family_model();
+    if family != 0x6 {
+        return None;
+    }
+
+    match model {
+        // Core 2 and Penryn.
+        0x0F | 0x17 | 0x1D => Some(4),
+
+        // Bonnell, Saltwell, Silvermont, Airmont and Knights Landing/Mill.
+        0x1C | 0x26 | 0x27 | 0x35 | 0x36 | 0x37 | 0x4A | 0x4C | 0x4D | 0x5A | 0x5D | 0x57
+        | 0x85 => Some(8),
+
+        // Nehalem, Westmere, Sandy Bridge, Ivy Bridge, Haswell and Broadwell.
+        0x1A | 0x1E | 0x1F | 0x2E | 0x25 | 0x2C | 0x2F | 0x2A | 0x2D | 0x3A | 0x3E | 0x3C
+        | 0x3F | 0x45 | 0x46 | 0x3D | 0x47 | 0x4F | 0x56 => Some(16),
+
+        // Goldmont, Goldmont Plus and Tremont.
+        0x5C | 0x5F | 0x7A | 0x86 | 0x96 | 0x9C => Some(32),
+
+        // Skylake and its successors up to Sapphire Rapids, which introduced architectural LBRs.
+        0x4E | 0x5E | 0x55 | 0x8E | 0x9E | 0x66 | 0x6A | 0x6C | 0x7D | 0x7E | 0x8C | 0x8D
+        | 0xA5 | 0xA6 | 0xA7 => Some(32),
+
+        _ => None,
+    }
+}
+
+/// An entry of a VM-exit MSR-store or VM-entry MSR-load area.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 25-15. Format of an
+/// MSR Entry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsrEntry {
+    /// The index of the MSR.
+    pub index: u32,
+
+    /// Reserved, must be zero.
+    pub reserved: u32,
+
+    /// The value of the MSR.
+    pub value: u64,
+}
+
+/// The MSR area holding the LBR stack of the guest of a processor, used both as the VM-exit MSR-store area
+/// and as the VM-entry MSR-load area.
+#[repr(C, align(4096))]
+pub struct MsrArea {
+    /// The entries of the area, of which the first `count` are in use.
+    pub entries: [MsrEntry; MSR_AREA_ENTRIES],
+
+    /// The number of entries in use.
+    pub count: usize,
+}
+
+impl MsrArea {
+    /// Allocates the MSR area of a processor for its LBR stack, with the current values of the stack.
+    ///
+    /// # Arguments
+    ///
+    /// * `stack` - The LBR stack of the processor.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the area, or an error if it could not be allocated.
+    pub fn for_lbr_stack(
+        stack: &LbrStack,
+    ) -> Result<Box<Self, PhysicalAllocator>, HypervisorError> {
+        let mut area: Box<Self, PhysicalAllocator> =
+            unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
+
+        for (entry, index) in area.entries.iter_mut().zip(stack.msrs()) {
+            *entry = MsrEntry {
+                index,
+                reserved: 0,
+                value: rdmsr(index),
+            };
+            area.count += 1;
+        }
+
+        Ok(area)
+    }
+
+    /// Makes the area the VM-exit MSR-store area and the VM-entry MSR-load area of the current VMCS.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether the VMCS fields were written.
+    pub fn activate(&self) -> Result<(), HypervisorError> {
+        let pa = PhysicalAddress::pa_from_va(self.entries.as_ptr() as u64);
+
+        try_vmwrite(vmcs::control::VMEXIT_MSR_STORE_ADDR_FULL, pa)?;
+        try_vmwrite(vmcs::control::VMEXIT_MSR_STORE_COUNT, self.count as u64)?;
+        try_vmwrite(vmcs::control::VMENTRY_MSR_LOAD_ADDR_FULL, pa)?;
+        try_vmwrite(vmcs::control::VMENTRY_MSR_LOAD_COUNT, self.count as u64)?;
+
+        log::debug!("Virtualized {} LBR MSRs", self.count);
+
+        Ok(())
+    }
+}
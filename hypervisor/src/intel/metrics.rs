@@ -0,0 +1,241 @@
+//! Metrics of the running hypervisor, exported as a snapshot through the `GetMetrics` hypercall.
+//!
+//! Every VM exit is counted per basic exit reason, with the TSC cycles spent in VMX root operation handling it,
+//! from reading its exit reason to the injection of the events before the next VM entry. Each processor has counters
+//! of its own, which only it writes, so recording an exit takes neither a lock nor a cache line shared with the
+//! other processors. A snapshot sums them and combines them with gauges read from the live state: the memory
+//! footprint, its cap and the number of virtualized processors.
+//!
+//! The snapshot uses the binary layout of `hypervisor_core::metrics`, which the client converts to the Prometheus
+//! text format with `write_prometheus`, so the hypervisor never formats text for it. Like the effective
+//! configuration, the snapshot is copied into a caller-provided buffer from an offset, to be read page by page.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table C-1. Basic Exit Reasons and
+//! 18.17 Time-Stamp Counter.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            capabilities::{Capability, Subsystem},
+            hypercall::HypercallCode,
+            shared_data::SharedData,
+            vmerror::VmxBasicExitReason,
+        },
+        utils::{
+            footprint::MemoryFootprint,
+            processor::{current_processor_index, processor_count, MAX_VCPUS},
+            text::TextWindow,
+        },
+    },
+    alloc::boxed::Box,
+    core::sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The hypercalls of the metrics export, see `intel::capabilities`.
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "metrics",
+    enabled: |_| true,
+    commands: &[Capability {
+        code: HypercallCode::GetMetrics,
+        summary: "Exports a snapshot of the metrics of the hypervisor",
+    }],
+};
+
+pub use hypervisor_core::metrics::{
+    encode_header, samples, write_prometheus, MetricFamily, MetricKind, MetricsError, Sample,
+    MEMORY_CATEGORIES, METRICS_HEADER_LEN, METRICS_MAGIC, METRICS_SAMPLE_LEN, METRICS_VERSION,
+};
+
+/// The number of basic exit reasons, i.e. the highest basic exit reason plus one.
+const EXIT_REASON_COUNT: usize = VmxBasicExitReason::InstructionTimeout as usize + 1;
+
+/// The counters of the VM exits of one basic exit reason on one processor.
+struct ExitCounters {
+    /// The number of VM exits handled.
+    count: AtomicU64,
+
+    /// The TSC cycles spent handling them.
+    cycles: AtomicU64,
+
+    /// The most TSC cycles spent handling one of them.
+    max_cycles: AtomicU64,
+}
+
+/// The VM exit counters of one processor, on cache lines no other processor writes.
+#[repr(C, align(64))]
+struct ProcessorExits {
+    /// The counters, indexed by basic exit reason.
+    exits: [ExitCounters; EXIT_REASON_COUNT],
+}
+
+/// The VM exit counters of the processors.
+pub struct ExitMetrics {
+    /// The counters of each processor, indexed by processor index.
+    processors: Box<[ProcessorExits; MAX_VCPUS]>,
+}
+
+impl ExitMetrics {
+    /// Creates the counters, all zero.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Err(HypervisorError::MemoryAllocationFailed)` if the counters could not be allocated.
+    pub fn new() -> Result<Self, HypervisorError> {
+        // Too large for the stack, so they are zeroed in place, a valid value of the atomics.
+        let processors =
+            unsafe { Box::<[ProcessorExits; MAX_VCPUS]>::try_new_zeroed()?.assume_init() };
+
+        Ok(Self { processors })
+    }
+
+    /// Records a VM exit handled by the current processor.
+    ///
+    /// Only the current processor writes its counters, so they are updated without locked instructions.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - The basic exit reason.
+    /// * `cycles` - The TSC cycles spent handling the exit.
+    pub fn record(&self, reason: u16, cycles: u64) {
+        let Some(counters) = self
+            .processors
+            .get(current_processor_index() as usize)
+            .and_then(|processor| processor.exits.get(reason as usize))
+        else {
+            return;
+        };
+
+        let count = counters.count.load(Ordering::Relaxed);
+        counters
+            .count
+            .store(count.wrapping_add(1), Ordering::Relaxed);
+
+        let total = counters.cycles.load(Ordering::Relaxed);
+        counters
+            .cycles
+            .store(total.wrapping_add(cycles), Ordering::Relaxed);
+
+        if cycles > counters.max_cycles.load(Ordering::Relaxed) {
+            counters.max_cycles.store(cycles, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of VM exits handled for a basic exit reason, by all processors.
+    pub fn count(&self, reason: VmxBasicExitReason) -> u64 {
+        self.value(MetricFamily::VmExits, reason as usize)
+    }
+
+    /// Returns the value of a family for a basic exit reason, combined across the processors: the largest of
+    /// their maxima, or the sum of their counters.
+    fn value(&self, family: MetricFamily, reason: usize) -> u64 {
+        let values = self
+            .processors
+            .iter()
+            .filter_map(|processor| processor.exits.get(reason))
+            .map(|counters| match family {
+                MetricFamily::VmExitCycles => counters.cycles.load(Ordering::Relaxed),
+                MetricFamily::VmExitMaxCycles => counters.max_cycles.load(Ordering::Relaxed),
+                _ => counters.count.load(Ordering::Relaxed),
+            });
+
+        match family {
+            MetricFamily::VmExitMaxCycles => values.max().unwrap_or(0),
+            _ => values.fold(0, u64::wrapping_add),
+        }
+    }
+
+    /// Returns the samples of a family, for the exit reasons that were seen.
+    fn exit_samples(&self, family: MetricFamily) -> impl Iterator<Item = Sample> + '_ {
+        (0..EXIT_REASON_COUNT)
+            .filter(|&reason| self.value(MetricFamily::VmExits, reason) != 0)
+            .map(move |reason| Sample::new(family, reason as u16, self.value(family, reason)))
+    }
+}
+
+/// The outcome of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsExport {
+    /// The number of bytes copied into the buffer.
+    pub copied: usize,
+
+    /// The length of the whole snapshot, from which the caller can tell whether more remains after the offset.
+    pub total: usize,
+}
+
+/// Takes a snapshot of the metrics and copies part of it into a buffer.
+///
+/// The counters keep changing while the snapshot is taken, so the samples are counted first and the snapshot
+/// always has the length announced in its header. A client reading page by page starts over if the length of
+/// the snapshot changed between the pages.
+///
+/// # Arguments
+///
+/// * `shared_data` - The state shared between the processors.
+/// * `buffer` - Receives the snapshot from `offset` on, as much as fits.
+/// * `offset` - The offset into the snapshot of the first byte to copy.
+///
+/// # Returns
+///
+/// The number of bytes copied and the length of the whole snapshot.
+pub fn export(shared_data: &SharedData, buffer: &mut [u8], offset: usize) -> MetricsExport {
+    let count = snapshot(shared_data).count();
+
+    // A window created with `TextWindow::new` never fails a write.
+    let mut window = TextWindow::new(buffer, offset);
+
+    let _ = window.write_bytes(&encode_header(count as u32));
+    for sample in snapshot(shared_data).take(count) {
+        let _ = window.write_bytes(&sample.encode());
+    }
+
+    // Exit reasons first seen after counting leave the snapshot shorter than announced. It is padded with
+    // samples of family 0, which is never assigned, so clients skip them as an unknown family.
+    let len = METRICS_HEADER_LEN + count * METRICS_SAMPLE_LEN;
+    let padding = Sample {
+        family: 0,
+        label: 0,
+        value: 0,
+    };
+    while window.total() < len {
+        let _ = window.write_bytes(&padding.encode());
+    }
+
+    MetricsExport {
+        copied: window.copied(),
+        total: len,
+    }
+}
+
+/// Returns the samples of a snapshot, grouped by family.
+fn snapshot(shared_data: &SharedData) -> impl Iterator<Item = Sample> + '_ {
+    let metrics = &shared_data.metrics;
+    let footprint = MemoryFootprint::current();
+    let memory = [
+        footprint.ept,
+        footprint.stacks,
+        footprint.bitmaps,
+        footprint.vmx_regions,
+        footprint.page_tables,
+        footprint.descriptor_tables,
+        footprint.heap,
+    ];
+
+    metrics
+        .exit_samples(MetricFamily::VmExits)
+        .chain(metrics.exit_samples(MetricFamily::VmExitCycles))
+        .chain(metrics.exit_samples(MetricFamily::VmExitMaxCycles))
+        .chain(memory.into_iter().enumerate().map(|(category, bytes)| {
+            Sample::new(MetricFamily::MemoryBytes, category as u16, bytes)
+        }))
+        .chain(
+            footprint
+                .cap
+                .map(|cap| Sample::new(MetricFamily::MemoryCapBytes, 0, cap)),
+        )
+        .chain(core::iter::once(Sample::new(
+            MetricFamily::Processors,
+            0,
+            shared_data.cpu_set.count_within(processor_count()) as u64,
+        )))
+}
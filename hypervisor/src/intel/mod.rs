@@ -1,14 +1,71 @@
+pub mod agent_monitor;
+pub mod apic_base;
+pub mod apic_timer;
+pub mod boot_report;
+#[cfg(feature = "introspection")]
+pub mod capabilities;
 pub mod controls;
+#[cfg(feature = "introspection")]
+pub mod coverage;
+pub mod cpu_set;
+pub mod debug_registers;
+pub mod debugger;
 pub mod descriptor;
+pub mod driver_blocker;
+#[cfg(feature = "introspection")]
+pub mod effective_config;
+pub mod entry_recovery;
 pub mod ept;
+pub mod evmcs;
+pub mod event_queue;
 pub mod events;
+#[cfg(feature = "tracing")]
+pub mod exit_history;
+#[cfg(feature = "introspection")]
+pub mod fault_injection;
+pub mod guest_memory;
+#[cfg(feature = "introspection")]
+pub mod heap_poison;
+#[cfg(feature = "introspection")]
+pub mod heat_map;
+pub mod host_call;
+pub mod hypercall;
+pub mod hypercall_page;
+pub mod hyperv;
+pub mod intrinsics;
 pub mod invept;
 pub mod invvpid;
+pub mod ipi;
+pub mod io_bitmap;
+#[cfg(feature = "devices")]
+pub mod io_monitor;
+#[cfg(feature = "devices")]
+pub mod keyboard_guard;
+pub mod lbr;
+#[cfg(feature = "introspection")]
+pub mod metrics;
 pub mod msr_bitmap;
+pub mod msr_policy;
+pub mod nested;
 pub mod paging;
+pub mod paravirt;
+pub mod percpu;
+pub mod platform;
+pub mod preemption_timer;
+#[cfg(feature = "introspection")]
+pub mod processes;
+pub mod rate_limit;
+pub mod sandbox;
 pub mod segmentation;
+pub mod sessions;
 pub mod shared_data;
+pub mod single_step;
+pub mod smm;
+pub mod spin_monitor;
 pub mod support;
+pub mod topology;
+pub mod tsc;
+pub mod tsx;
 pub mod vcpu;
 pub mod vmcs;
 pub mod vmerror;
@@ -18,3 +75,4 @@ pub mod vmm;
 pub mod vmstack;
 pub mod vmx;
 pub mod vmxon;
+pub mod x2apic;
@@ -3,7 +3,7 @@
 //! in a virtualized environment.
 
 use {
-    crate::utils::alloc::PhysicalAllocator,
+    crate::utils::{alloc::PhysicalAllocator, processor::any_virtualized},
     alloc::boxed::Box,
     core::mem::MaybeUninit,
     wdk_sys::{
@@ -12,6 +12,15 @@ use {
     },
 };
 
+/// The last MSR of the low range covered by the bitmap.
+pub const LOW_MSRS_END: u32 = 0x1FFF;
+
+/// The first MSR of the high range covered by the bitmap.
+pub const HIGH_MSRS_START: u32 = 0xC000_0000;
+
+/// The last MSR of the high range covered by the bitmap.
+pub const HIGH_MSRS_END: u32 = 0xC000_1FFF;
+
 /// Represents the MSR Bitmap structure used in VMX.
 ///
 /// In processors that support the 1-setting of the “use MSR bitmaps” VM-execution control,
@@ -63,6 +72,68 @@ impl MsrBitmap {
         instance
     }
 
+    /// Causes RDMSR and/or WRMSR of the given MSR to VM exit.
+    ///
+    /// MSRs outside of the ranges covered by the bitmap always cause VM exits and are ignored.
+    ///
+    /// # Arguments
+    /// * `msr` - The MSR to intercept.
+    /// * `read` - Whether RDMSR of the MSR causes a VM exit.
+    /// * `write` - Whether WRMSR of the MSR causes a VM exit.
+    pub fn intercept_msr(&mut self, msr: u32, read: bool, write: bool) {
+        let (read_bitmap, write_bitmap, offset) = match msr {
+            0..=LOW_MSRS_END => (&mut self.read_low_msrs, &mut self.write_low_msrs, msr),
+            HIGH_MSRS_START..=HIGH_MSRS_END => (
+                &mut self.read_high_msrs,
+                &mut self.write_high_msrs,
+                msr - HIGH_MSRS_START,
+            ),
+            _ => return,
+        };
+
+        let byte = (offset / 8) as usize;
+        let bit = 1u8 << (offset % 8);
+
+        if let (Some(read_byte), Some(write_byte)) =
+            (read_bitmap.get_mut(byte), write_bitmap.get_mut(byte))
+        {
+            if read {
+                *read_byte |= bit;
+            }
+            if write {
+                *write_byte |= bit;
+            }
+        }
+    }
+
+    /// Returns whether RDMSR and WRMSR of the given MSR cause VM exits.
+    ///
+    /// # Arguments
+    /// * `msr` - The MSR to look up.
+    ///
+    /// # Returns
+    /// * `(read, write)` - Whether RDMSR respectively WRMSR of the MSR cause a VM exit, always for MSRs outside
+    ///   of the ranges covered by the bitmap.
+    pub fn is_intercepted(&self, msr: u32) -> (bool, bool) {
+        let (read_bitmap, write_bitmap, offset) = match msr {
+            0..=LOW_MSRS_END => (&self.read_low_msrs, &self.write_low_msrs, msr),
+            HIGH_MSRS_START..=HIGH_MSRS_END => (
+                &self.read_high_msrs,
+                &self.write_high_msrs,
+                msr - HIGH_MSRS_START,
+            ),
+            _ => return (true, true),
+        };
+
+        let byte = (offset / 8) as usize;
+        let bit = 1u8 << (offset % 8);
+
+        match (read_bitmap.get(byte), write_bitmap.get(byte)) {
+            (Some(read_byte), Some(write_byte)) => (read_byte & bit != 0, write_byte & bit != 0),
+            _ => (true, true),
+        }
+    }
+
     /// Initializes the MSR Bitmap.
     ///
     /// # Arguments
@@ -81,3 +152,14 @@ impl MsrBitmap {
         unsafe { RtlClearAllBits(bitmap_header_ptr as _) }
     }
 }
+
+impl Drop for MsrBitmap {
+    /// Checks that no processor still consults the bitmap.
+    ///
+    /// The bitmap is referenced by the VMCS of every processor, so it may only be freed once no processor is virtualized.
+    fn drop(&mut self) {
+        if any_virtualized() {
+            log::error!("Freeing the MSR bitmap while processors are still virtualized");
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Per-MSR policies answering the RDMSR and WRMSR exits of the guest.
+//!
+//! An `MsrPolicy` decides what happens to the accesses of the guest to an MSR:
+//! - `Passthrough`: the access is carried out on the hardware, as for any MSR without a policy.
+//! - `Deny`: #GP(0) is injected, as for an MSR the processor does not implement.
+//! - `Emulate`: an `MsrEmulator` answers the access without the hardware MSR being read or written.
+//!
+//! Denying or emulating an MSR intercepts both RDMSR and WRMSR of it in the MSR bitmap. MSRs outside of the
+//! ranges covered by the bitmap always exit. Setting an MSR back to `Passthrough` leaves the bitmap unchanged, as
+//! the hypervisor may intercept the MSR for its own purposes, e.g. the x2APIC or TSX control MSRs; such accesses
+//! keep exiting and are passed through by the handler.
+//!
+//! The policies apply after the handling of the hypervisor itself: the accesses the hypervisor answers, such as
+//! the virtualized TSC, the hypercall page or the writes to IA32_APIC_BASE, and those it faults, such as the
+//! x2APIC registers outside of x2APIC mode, never reach a policy, so a policy cannot override them.
+//!
+//! Every processor has its own table, seeded with the policies set on the `HypervisorBuilder` and changed with
+//! `Vcpu::set_msr_policy`. The MSR bitmap is shared, so an MSR intercepted for one processor exits on all of
+//! them, and is passed through by the handler on those without a policy for it. The tables are changed from
+//! other processors, so they are published through RCU: an update builds a new sorted table and swaps it in, and
+//! the handler looks it up without a lock.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.9 MSR-Bitmap Address
+//! and 26.1.3 Instructions That Cause VM Exits Conditionally.
+
+use {crate::utils::rcu::Rcu, alloc::vec::Vec, core::convert::Infallible};
+
+/// An access of the guest to an MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrAccess {
+    /// RDMSR.
+    Read,
+
+    /// WRMSR of the value.
+    Write(u64),
+}
+
+/// Answers the accesses of the guest to an emulated MSR, in VMX root operation.
+///
+/// # Arguments
+///
+/// * `msr` - The MSR accessed.
+/// * `access` - The access of the guest.
+///
+/// # Returns
+///
+/// The value read by the guest for RDMSR, any value for a WRMSR that is accepted, or `None` to inject #GP(0).
+pub type MsrEmulator = fn(msr: u32, access: MsrAccess) -> Option<u64>;
+
+/// What happens to the accesses of the guest to an MSR.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum MsrPolicy {
+    /// The access is carried out on the hardware.
+    #[default]
+    Passthrough,
+
+    /// The access raises #GP(0) in the guest.
+    Deny,
+
+    /// The access is answered by the emulator.
+    Emulate(MsrEmulator),
+}
+
+impl MsrPolicy {
+    /// Returns whether accesses to the MSR must exit for the policy to be applied.
+    pub fn is_intercepting(&self) -> bool {
+        !matches!(self, Self::Passthrough)
+    }
+}
+
+/// The policy of a single MSR.
+#[derive(Debug, Clone, Copy)]
+pub struct MsrRule {
+    /// The MSR.
+    pub msr: u32,
+
+    /// The policy applied to it.
+    pub policy: MsrPolicy,
+}
+
+/// The table of MSR policies, holding an entry for every MSR that is not passed through.
+pub struct MsrPolicies {
+    /// The rules, sorted by MSR.
+    rules: Rcu<Vec<MsrRule>>,
+}
+
+impl Default for MsrPolicies {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for MsrPolicies {
+    fn clone(&self) -> Self {
+        Self {
+            rules: Rcu::new(self.rules()),
+        }
+    }
+}
+
+impl MsrPolicies {
+    /// Creates a table passing every MSR through.
+    pub fn new() -> Self {
+        Self {
+            rules: Rcu::new(Vec::new()),
+        }
+    }
+
+    /// Sets the policy of an MSR, replacing its previous one.
+    ///
+    /// The caller must intercept the MSR in the MSR bitmap if the policy requires it, see
+    /// `MsrPolicy::is_intercepting`.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR.
+    /// * `policy` - The policy applied to the accesses of the guest to it.
+    pub fn set(&self, msr: u32, policy: MsrPolicy) {
+        let updated = self.rules.update(|current| {
+            let mut rules = current.clone();
+
+            match (rules.binary_search_by_key(&msr, |rule| rule.msr), policy) {
+                (Ok(index), MsrPolicy::Passthrough) => {
+                    rules.remove(index);
+                }
+                (Ok(index), policy) => {
+                    if let Some(rule) = rules.get_mut(index) {
+                        rule.policy = policy;
+                    }
+                }
+                (Err(_), MsrPolicy::Passthrough) => {}
+                (Err(index), policy) => rules.insert(index, MsrRule { msr, policy }),
+            }
+
+            Ok::<_, Infallible>((rules, ()))
+        });
+
+        match updated {
+            Ok(()) => log::debug!("MSR {:#x} policy: {:?}", msr, policy),
+            Err(never) => match never {},
+        }
+    }
+
+    /// Returns the policy of an MSR, `MsrPolicy::Passthrough` if none was set.
+    pub fn lookup(&self, msr: u32) -> MsrPolicy {
+        let rules = self.rules.read();
+
+        rules
+            .binary_search_by_key(&msr, |rule| rule.msr)
+            .ok()
+            .and_then(|index| rules.get(index))
+            .map_or(MsrPolicy::Passthrough, |rule| rule.policy)
+    }
+
+    /// Returns the rules of all MSRs that are not passed through, sorted by MSR.
+    pub fn rules(&self) -> Vec<MsrRule> {
+        let len = self.rules.read().len();
+        let mut rules = Vec::with_capacity(len);
+        rules.extend_from_slice(&self.rules.read());
+        rules
+    }
+
+    /// Visits the rules in order of their MSR, without copying them.
+    ///
+    /// Outside of an exit handler, interrupts stay disabled during the visit, so `visit` must not allocate.
+    pub fn for_each_rule(&self, visit: impl FnMut(&MsrRule)) {
+        self.rules.read().iter().for_each(visit);
+    }
+}
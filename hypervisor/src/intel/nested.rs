@@ -0,0 +1,103 @@
+//! Detection of an already running hypervisor.
+//!
+//! When Hyper-V or Virtualization Based Security (VBS) is active, or when running inside a lab VM,
+//! this hypervisor is not the most privileged software on the processor and operates as an L1 under
+//! another hypervisor. Detecting this up front allows reporting a distinct error instead of crashing
+//! or failing VMXON silently.
+//!
+//! Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/feature-discovery
+
+use {crate::utils::cpu, core::fmt, x86::cpuid::cpuid};
+
+/// CPUID leaf reporting the hypervisor vendor and the maximum hypervisor leaf.
+pub const CPUID_HYPERVISOR_VENDOR: u32 = 0x4000_0000;
+
+/// CPUID leaf reporting the Hyper-V partition privileges.
+const CPUID_HYPERV_FEATURES: u32 = 0x4000_0003;
+
+/// Hyper-V partition privilege (EBX of leaf 0x40000003) granted only to the root partition.
+const HYPERV_CREATE_PARTITIONS: u32 = 1 << 0;
+
+/// The hypervisor already running underneath us, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostHypervisor {
+    /// No hypervisor is present; we run on bare metal.
+    #[default]
+    None,
+
+    /// Microsoft Hyper-V. `root_partition` is set when the OS is the root partition, which is the
+    /// case when Hyper-V or VBS is enabled on the host itself.
+    HyperV { root_partition: bool },
+
+    /// Linux KVM.
+    Kvm,
+
+    /// VMware Workstation / ESXi.
+    VMware,
+
+    /// Xen.
+    Xen,
+
+    /// A hypervisor with an unrecognized vendor signature.
+    Unknown,
+}
+
+impl HostHypervisor {
+    /// Detects the hypervisor running underneath us, if any.
+    ///
+    /// # Returns
+    ///
+    /// The detected `HostHypervisor`.
+    pub fn detect() -> Self {
+        if !cpu::has_hypervisor() {
+            return Self::None;
+        }
+
+        let vendor = cpuid!(CPUID_HYPERVISOR_VENDOR);
+
+        let mut signature = [0u8; 12];
+        signature[0..4].copy_from_slice(&vendor.ebx.to_le_bytes());
+        signature[4..8].copy_from_slice(&vendor.ecx.to_le_bytes());
+        signature[8..12].copy_from_slice(&vendor.edx.to_le_bytes());
+
+        match &signature {
+            b"Microsoft Hv" => {
+                let root_partition = vendor.eax >= CPUID_HYPERV_FEATURES
+                    && cpuid!(CPUID_HYPERV_FEATURES).ebx & HYPERV_CREATE_PARTITIONS != 0;
+                Self::HyperV { root_partition }
+            }
+            b"KVMKVMKVM\0\0\0" => Self::Kvm,
+            b"VMwareVMware" => Self::VMware,
+            b"XenVMMXenVMM" => Self::Xen,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Returns whether we are running as an L1 under another hypervisor.
+    pub fn is_present(&self) -> bool {
+        *self != Self::None
+    }
+
+    /// Returns whether the hypervisor underneath us is Hyper-V.
+    pub fn is_hyperv(&self) -> bool {
+        matches!(self, Self::HyperV { .. })
+    }
+}
+
+impl fmt::Display for HostHypervisor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::HyperV {
+                root_partition: true,
+            } => write!(f, "Hyper-V (root partition, Hyper-V/VBS active)"),
+            Self::HyperV {
+                root_partition: false,
+            } => write!(f, "Hyper-V (guest partition)"),
+            Self::Kvm => write!(f, "KVM"),
+            Self::VMware => write!(f, "VMware"),
+            Self::Xen => write!(f, "Xen"),
+            Self::Unknown => write!(f, "Unknown"),
+        }
+    }
+}
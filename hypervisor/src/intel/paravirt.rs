@@ -0,0 +1,233 @@
+//! An optional self-identifying paravirtual interface for cooperative guests.
+//!
+//! When enabled, the hypervisor answers the CPUID leaves reserved for hypervisors (0x40000000 and up)
+//! with its own vendor signature, the features it offers and the address of the hypercall page, and
+//! advertises itself through CPUID.01H:ECX[31]. When disabled (stealth mode, the default), these leaves
+//! are left untouched and the hypervisor present bit stays hidden.
+//!
+//! Developer mode (`ParavirtFeatures::DEVELOPER_MODE`, or the `developer-mode` feature of the crate) is the
+//! inverse of stealth mode for test automation: on top of the hypercalls, it exposes a diagnostics leaf and
+//! answers the identification hypercall, so a test can verify explicitly that the guest runs under this
+//! hypervisor, and which build it is.
+//!
+//! The leaves, signatures and features seen by the guest are defined in the `hypervisor-core` crate, so clients
+//! can detect the hypervisor without depending on this one.
+//!
+//! Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/feature-discovery
+
+use {
+    crate::utils::processor::{current_processor_index, processor_count},
+    bitflags::bitflags,
+    core::sync::atomic::{AtomicU64, Ordering},
+};
+
+#[cfg(feature = "introspection")]
+use crate::intel::{
+    capabilities::{Capability, Subsystem},
+    hypercall::HypercallCode,
+};
+
+pub use hypervisor_core::paravirt::{
+    ParavirtFeatures, CPUID_DIAGNOSTICS, CPUID_FEATURES, CPUID_HYPERCALL_PAGE,
+    CPUID_HYPERVISOR_BASE, CPUID_HYPERVISOR_LIMIT, CPUID_VENDOR, DIAGNOSTICS_VERSION,
+    INTERFACE_SIGNATURE, VENDOR_SIGNATURE,
+};
+
+/// The hypercalls of the paravirtual interface, see `intel::capabilities`.
+#[cfg(feature = "introspection")]
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "paravirt",
+    enabled: |shared_data| shared_data.paravirt.offers(ParavirtFeatures::DIAGNOSTICS),
+    commands: &[Capability {
+        code: HypercallCode::Identify,
+        summary: "Identifies the hypervisor, with the diagnostics of developer mode",
+    }],
+};
+
+bitflags! {
+    /// The crate features the hypervisor was built with, reported in EDX of leaf 0x40000003.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct BuildFeatures: u32 {
+        /// The `secondary-ept` feature.
+        const SECONDARY_EPT = 1 << 0;
+
+        /// The `shellcode-hook` feature.
+        const SHELLCODE_HOOK = 1 << 1;
+
+        /// The `developer-mode` feature.
+        const DEVELOPER_MODE = 1 << 2;
+
+        /// The `introspection` feature.
+        const INTROSPECTION = 1 << 3;
+
+        /// The `silent` feature.
+        const SILENT = 1 << 4;
+    }
+}
+
+impl BuildFeatures {
+    /// Returns the features of the running build.
+    pub fn current() -> Self {
+        let mut features = Self::empty();
+        features.set(Self::SECONDARY_EPT, cfg!(feature = "secondary-ept"));
+        features.set(Self::SHELLCODE_HOOK, cfg!(feature = "shellcode-hook"));
+        features.set(Self::DEVELOPER_MODE, cfg!(feature = "developer-mode"));
+        features.set(Self::INTROSPECTION, cfg!(feature = "introspection"));
+        features.set(Self::SILENT, cfg!(feature = "silent"));
+        features
+    }
+}
+
+/// The result of a CPUID leaf as returned to the guest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuidLeafValue {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// The configuration and state of the paravirtual interface.
+#[derive(Debug)]
+pub struct ParavirtInterface {
+    /// The advertised features, or `None` when running in stealth mode.
+    features: Option<ParavirtFeatures>,
+
+    /// The guest physical address of the hypercall page, or 0 if there is none.
+    hypercall_page: AtomicU64,
+
+    /// The number of processors, reported by the diagnostics leaf.
+    processor_count: u32,
+}
+
+impl ParavirtInterface {
+    /// Creates the paravirtual interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - The features to advertise, or `None` to stay in stealth mode.
+    pub fn new(features: Option<ParavirtFeatures>) -> Self {
+        Self {
+            features,
+            hypercall_page: AtomicU64::new(0),
+            processor_count: processor_count(),
+        }
+    }
+
+    /// Returns the advertised features, or `None` in stealth mode.
+    pub fn features(&self) -> Option<ParavirtFeatures> {
+        self.features
+    }
+
+    /// Returns whether the interface is exposed to the guest.
+    pub fn is_enabled(&self) -> bool {
+        self.features.is_some()
+    }
+
+    /// Returns whether the interface is exposed and advertises the given features.
+    pub fn offers(&self, features: ParavirtFeatures) -> bool {
+        self.features
+            .is_some_and(|offered| offered.contains(features))
+    }
+
+    /// Returns the guest physical address of the hypercall page, or 0 if there is none.
+    pub fn hypercall_page(&self) -> u64 {
+        self.hypercall_page.load(Ordering::Acquire)
+    }
+
+    /// Answers the identification hypercall.
+    ///
+    /// # Returns
+    ///
+    /// The values returned in RBX and RCX, or `None` if diagnostics are not offered:
+    /// - RBX: the interface signature in bits 31:0 and `DIAGNOSTICS_VERSION` in bits 63:32.
+    /// - RCX: the `BuildFeatures` in bits 31:0 and the number of processors in bits 63:32.
+    pub fn identify(&self) -> Option<(u64, u64)> {
+        if !self.offers(ParavirtFeatures::DIAGNOSTICS) {
+            return None;
+        }
+
+        Some((
+            u64::from(INTERFACE_SIGNATURE) | (u64::from(DIAGNOSTICS_VERSION) << 32),
+            u64::from(BuildFeatures::current().bits()) | (u64::from(self.processor_count) << 32),
+        ))
+    }
+
+    /// Publishes the guest physical address of the hypercall page.
+    ///
+    /// # Arguments
+    ///
+    /// * `gpa` - The guest physical address, or 0 to withdraw the page.
+    pub fn set_hypercall_page(&self, gpa: u64) {
+        self.hypercall_page.store(gpa, Ordering::Release);
+    }
+
+    /// Answers a CPUID leaf in the hypervisor range.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The CPUID leaf (EAX).
+    ///
+    /// # Returns
+    ///
+    /// The values to return to the guest, or `None` if the leaf is not emulated, either because the
+    /// interface is disabled or the leaf is outside the hypervisor range.
+    pub fn cpuid(&self, leaf: u32) -> Option<CpuidLeafValue> {
+        let features = self.features?;
+
+        if !(CPUID_HYPERVISOR_BASE..=CPUID_HYPERVISOR_LIMIT).contains(&leaf) {
+            return None;
+        }
+
+        let hypercall_page = self.hypercall_page();
+        let mut features = features;
+        features.set(ParavirtFeatures::HYPERCALL_PAGE, hypercall_page != 0);
+
+        let value = match leaf {
+            CPUID_VENDOR => {
+                let word = |i: usize| {
+                    let mut bytes = [0u8; 4];
+                    if let Some(chunk) = VENDOR_SIGNATURE.get(i * 4..i * 4 + 4) {
+                        bytes.copy_from_slice(chunk);
+                    }
+                    u32::from_le_bytes(bytes)
+                };
+
+                let max_leaf = if features.contains(ParavirtFeatures::DIAGNOSTICS) {
+                    CPUID_DIAGNOSTICS
+                } else {
+                    CPUID_HYPERCALL_PAGE
+                };
+
+                CpuidLeafValue {
+                    eax: max_leaf,
+                    ebx: word(0),
+                    ecx: word(1),
+                    edx: word(2),
+                }
+            }
+            CPUID_FEATURES => CpuidLeafValue {
+                eax: INTERFACE_SIGNATURE,
+                ebx: features.bits(),
+                ..Default::default()
+            },
+            CPUID_HYPERCALL_PAGE => CpuidLeafValue {
+                eax: hypercall_page as u32,
+                ebx: (hypercall_page >> 32) as u32,
+                ..Default::default()
+            },
+            CPUID_DIAGNOSTICS if features.contains(ParavirtFeatures::DIAGNOSTICS) => {
+                CpuidLeafValue {
+                    eax: DIAGNOSTICS_VERSION,
+                    ebx: self.processor_count,
+                    ecx: current_processor_index(),
+                    edx: BuildFeatures::current().bits(),
+                }
+            }
+            // Leaves past the maximum hypervisor leaf are reserved and read as zero.
+            _ => CpuidLeafValue::default(),
+        };
+
+        Some(value)
+    }
+}
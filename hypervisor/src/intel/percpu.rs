@@ -0,0 +1,110 @@
+//! Statically allocated per-processor areas indexed by the systemwide processor index.
+//!
+//! Each processor claims the slot of its index when it is virtualized. The index is dense (0 to the number of
+//! active processors), unlike the APIC ID, which has gaps on most topologies and can exceed the table even on
+//! small systems. The table is sized at compile
+//! time by `MAX_VCPUS` and slots are claimed with a single compare-exchange, so processors virtualizing
+//! concurrently never race on a growing collection, and any code running on a processor (including the
+//! VM-exit handlers) can find its own data without taking a lock.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::vcpu::Vcpu,
+        utils::processor::{current_processor_index, MAX_VCPUS},
+    },
+    core::{
+        marker::PhantomData,
+        ptr,
+        sync::atomic::{AtomicPtr, Ordering},
+    },
+};
+
+/// A table of per-processor pointers with a fixed number of slots, indexed by processor index.
+///
+/// The slots are type-erased, so the table can be created in a constant context.
+pub struct PerCpu<T> {
+    slots: [AtomicPtr<()>; MAX_VCPUS],
+    _marker: PhantomData<*mut T>,
+}
+
+// The table only hands out raw pointers; dereferencing them is up to the caller.
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// Creates an empty table.
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+        Self {
+            slots: [EMPTY; MAX_VCPUS],
+            _marker: PhantomData,
+        }
+    }
+
+    /// Claims the slot of the given processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The systemwide index of the processor.
+    /// * `data` - The data of the processor, which must outlive the claim.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Err(HypervisorError::ProcessorIndexOutOfRange)` if the index exceeds `MAX_VCPUS`,
+    /// or `Err(HypervisorError::PerCpuSlotTaken)` if another processor already holds the slot.
+    pub fn claim(&self, index: u32, data: *mut T) -> Result<(), HypervisorError> {
+        let slot = self
+            .slots
+            .get(index as usize)
+            .ok_or(HypervisorError::ProcessorIndexOutOfRange)?;
+
+        let data = data as *mut ();
+
+        match slot.compare_exchange(ptr::null_mut(), data, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => Ok(()),
+            Err(current) if current == data => Ok(()),
+            Err(_) => Err(HypervisorError::PerCpuSlotTaken),
+        }
+    }
+
+    /// Releases the slot of the given processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The systemwide index of the processor.
+    pub fn release(&self, index: u32) {
+        if let Some(slot) = self.slots.get(index as usize) {
+            slot.store(ptr::null_mut(), Ordering::Release);
+        }
+    }
+
+    /// Returns the data registered for the given processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The systemwide index of the processor.
+    ///
+    /// # Returns
+    ///
+    /// A pointer to the data, or `None` if the slot is not claimed.
+    pub fn get(&self, index: u32) -> Option<*mut T> {
+        let data = self.slots.get(index as usize)?.load(Ordering::Acquire);
+        (!data.is_null()).then_some(data as *mut T)
+    }
+
+    /// Returns the data registered for the current processor.
+    pub fn current(&self) -> Option<*mut T> {
+        self.get(current_processor_index())
+    }
+}
+
+impl<T> Default for PerCpu<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The virtual processors, indexed by processor index.
+pub static VCPUS: PerCpu<Vcpu> = PerCpu::new();
@@ -0,0 +1,345 @@
+//! Captures a snapshot of the platform's VMX related capability MSRs and CPUID leaves.
+//!
+//! The snapshot is taken once at initialization and retained in the shared data, so that
+//! diagnostics and quirk decisions can consult a single source of truth instead of issuing
+//! scattered one-off `rdmsr` / `cpuid` calls.
+//!
+//! Hybrid processors do not report the same capabilities on all cores, so the capabilities that may differ
+//! are additionally captured on every core as it is virtualized, see `CoreCapabilities`.
+
+use {
+    crate::{
+        intel::{hyperv::Enlightenments, nested::HostHypervisor},
+        utils::{
+            cpu::{self, CoreType},
+            instructions::{rdmsr, wrmsr},
+        },
+    },
+    core::fmt,
+    x86::{cpuid::cpuid, msr},
+};
+
+/// The VMX basic flag indicating support for the TRUE capability MSRs.
+const IA32_VMX_BASIC_TRUE_CONTROLS_FLAG: u64 = 1 << 55;
+
+/// IA32_VMX_PROCBASED_CTLS allowed-1 bit of the "activate secondary controls" control.
+const PROCBASED_CTLS_SECONDARY_CONTROLS: u64 = 1 << (32 + 31);
+
+/// IA32_VMX_PROCBASED_CTLS2 allowed-1 bits of the "enable EPT" and "enable VPID" controls.
+const PROCBASED_CTLS2_EPT_OR_VPID: u64 = (1 << (32 + 1)) | (1 << (32 + 5));
+
+/// IA32_VMX_EPT_VPID_CAP bit indicating support for the EPT accessed and dirty flags.
+const EPT_VPID_CAP_ACCESSED_DIRTY_FLAG: u64 = 1 << 21;
+
+/// The MSR reporting the microcode revision in bits 63:32.
+const IA32_BIOS_SIGN_ID: u32 = 0x8B;
+
+/// The CPUID leaves captured as part of the platform snapshot.
+const CPUID_LEAVES: [(u32, u32); 3] = [(0x0, 0x0), (0x1, 0x0), (0x7, 0x0)];
+
+/// A single captured CPUID leaf.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuidSnapshot {
+    pub leaf: u32,
+    pub sub_leaf: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// The VMX capability MSRs of the processor.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Appendix A VMX CAPABILITY REPORTING FACILITY
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VmxCapabilities {
+    /// IA32_VMX_BASIC.
+    pub basic: u64,
+
+    /// IA32_VMX_PINBASED_CTLS or IA32_VMX_TRUE_PINBASED_CTLS if supported.
+    pub pinbased_ctls: u64,
+
+    /// IA32_VMX_PROCBASED_CTLS or IA32_VMX_TRUE_PROCBASED_CTLS if supported.
+    pub procbased_ctls: u64,
+
+    /// IA32_VMX_PROCBASED_CTLS2.
+    pub procbased_ctls2: u64,
+
+    /// IA32_VMX_EXIT_CTLS or IA32_VMX_TRUE_EXIT_CTLS if supported.
+    pub exit_ctls: u64,
+
+    /// IA32_VMX_ENTRY_CTLS or IA32_VMX_TRUE_ENTRY_CTLS if supported.
+    pub entry_ctls: u64,
+
+    /// IA32_VMX_MISC.
+    pub misc: u64,
+
+    /// IA32_VMX_CR0_FIXED0.
+    pub cr0_fixed0: u64,
+
+    /// IA32_VMX_CR0_FIXED1.
+    pub cr0_fixed1: u64,
+
+    /// IA32_VMX_CR4_FIXED0.
+    pub cr4_fixed0: u64,
+
+    /// IA32_VMX_CR4_FIXED1.
+    pub cr4_fixed1: u64,
+
+    /// IA32_VMX_VMCS_ENUM.
+    pub vmcs_enum: u64,
+
+    /// IA32_VMX_EPT_VPID_CAP.
+    pub ept_vpid_cap: u64,
+}
+
+impl VmxCapabilities {
+    /// Captures the VMX capability MSRs of the current processor.
+    ///
+    /// This must only be called after VMX support has been confirmed, as reading the VMX capability MSRs on a
+    /// processor without VMX support raises #GP. IA32_VMX_PROCBASED_CTLS2 and IA32_VMX_EPT_VPID_CAP only exist
+    /// with the secondary controls, respectively with EPT or VPIDs, and are reported as zero otherwise.
+    ///
+    /// # Returns
+    ///
+    /// A `VmxCapabilities` containing the captured values.
+    pub fn capture() -> Self {
+        let basic = rdmsr(msr::IA32_VMX_BASIC);
+        let true_ctls = (basic & IA32_VMX_BASIC_TRUE_CONTROLS_FLAG) != 0;
+
+        let pick = |true_msr: u32, msr: u32| {
+            if true_ctls {
+                rdmsr(true_msr)
+            } else {
+                rdmsr(msr)
+            }
+        };
+
+        let procbased_ctls = pick(
+            msr::IA32_VMX_TRUE_PROCBASED_CTLS,
+            msr::IA32_VMX_PROCBASED_CTLS,
+        );
+        let procbased_ctls2 = match procbased_ctls & PROCBASED_CTLS_SECONDARY_CONTROLS {
+            0 => 0,
+            _ => rdmsr(msr::IA32_VMX_PROCBASED_CTLS2),
+        };
+        let ept_vpid_cap = match procbased_ctls2 & PROCBASED_CTLS2_EPT_OR_VPID {
+            0 => 0,
+            _ => rdmsr(msr::IA32_VMX_EPT_VPID_CAP),
+        };
+
+        Self {
+            basic,
+            pinbased_ctls: pick(
+                msr::IA32_VMX_TRUE_PINBASED_CTLS,
+                msr::IA32_VMX_PINBASED_CTLS,
+            ),
+            procbased_ctls,
+            procbased_ctls2,
+            exit_ctls: pick(msr::IA32_VMX_TRUE_EXIT_CTLS, msr::IA32_VMX_EXIT_CTLS),
+            entry_ctls: pick(msr::IA32_VMX_TRUE_ENTRY_CTLS, msr::IA32_VMX_ENTRY_CTLS),
+            misc: rdmsr(msr::IA32_VMX_MISC),
+            cr0_fixed0: rdmsr(msr::IA32_VMX_CR0_FIXED0),
+            cr0_fixed1: rdmsr(msr::IA32_VMX_CR0_FIXED1),
+            cr4_fixed0: rdmsr(msr::IA32_VMX_CR4_FIXED0),
+            cr4_fixed1: rdmsr(msr::IA32_VMX_CR4_FIXED1),
+            vmcs_enum: rdmsr(msr::IA32_VMX_VMCS_ENUM),
+            ept_vpid_cap,
+        }
+    }
+
+    /// Returns whether the TRUE VMX capability MSRs were used for the control snapshots.
+    pub fn has_true_controls(&self) -> bool {
+        (self.basic & IA32_VMX_BASIC_TRUE_CONTROLS_FLAG) != 0
+    }
+}
+
+/// A snapshot of the platform MSRs and CPUID leaves relevant to the hypervisor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlatformInfo {
+    /// The VMX capability MSRs.
+    pub vmx: VmxCapabilities,
+
+    /// IA32_FEATURE_CONTROL.
+    pub feature_control: u64,
+
+    /// IA32_MTRRCAP.
+    pub mtrr_cap: u64,
+
+    /// IA32_MTRR_DEF_TYPE.
+    pub mtrr_def_type: u64,
+
+    /// The captured CPUID leaves.
+    pub cpuid: [CpuidSnapshot; CPUID_LEAVES.len()],
+
+    /// The hypervisor already running underneath us, if any.
+    pub host_hypervisor: HostHypervisor,
+
+    /// The enlightenments offered when running nested under Hyper-V.
+    pub enlightenments: Enlightenments,
+}
+
+impl PlatformInfo {
+    /// Captures the platform MSRs and CPUID leaves of the current processor.
+    ///
+    /// This must only be called after VMX support has been confirmed, as reading the VMX
+    /// capability MSRs on a processor without VMX support raises #GP.
+    ///
+    /// # Returns
+    ///
+    /// A `PlatformInfo` containing the captured values.
+    pub fn capture() -> Self {
+        log::trace!("Capturing platform information");
+
+        let vmx = VmxCapabilities::capture();
+
+        let mut cpuid_snapshots = [CpuidSnapshot::default(); CPUID_LEAVES.len()];
+        for (snapshot, (leaf, sub_leaf)) in cpuid_snapshots.iter_mut().zip(CPUID_LEAVES) {
+            let result = cpuid!(leaf, sub_leaf);
+            *snapshot = CpuidSnapshot {
+                leaf,
+                sub_leaf,
+                eax: result.eax,
+                ebx: result.ebx,
+                ecx: result.ecx,
+                edx: result.edx,
+            };
+        }
+
+        let host_hypervisor = HostHypervisor::detect();
+
+        Self {
+            vmx,
+            feature_control: rdmsr(msr::IA32_FEATURE_CONTROL),
+            mtrr_cap: rdmsr(msr::IA32_MTRRCAP),
+            mtrr_def_type: rdmsr(msr::IA32_MTRR_DEF_TYPE),
+            cpuid: cpuid_snapshots,
+            host_hypervisor,
+            enlightenments: Enlightenments::detect(host_hypervisor),
+        }
+    }
+
+    /// Looks up a captured CPUID leaf.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The CPUID leaf (EAX).
+    /// * `sub_leaf` - The CPUID sub-leaf (ECX).
+    ///
+    /// # Returns
+    ///
+    /// The captured leaf, or `None` if the leaf is not part of the snapshot.
+    pub fn cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<&CpuidSnapshot> {
+        self.cpuid
+            .iter()
+            .find(|s| s.leaf == leaf && s.sub_leaf == sub_leaf)
+    }
+
+    /// Returns whether the TRUE VMX capability MSRs were used for the control snapshots.
+    pub fn has_true_controls(&self) -> bool {
+        self.vmx.has_true_controls()
+    }
+
+    /// Returns whether the processor supports the accessed and dirty flags for EPT.
+    pub fn has_ept_accessed_dirty(&self) -> bool {
+        (self.vmx.ept_vpid_cap & EPT_VPID_CAP_ACCESSED_DIRTY_FLAG) != 0
+    }
+}
+
+/// The capabilities of a single core, captured on the core itself.
+#[derive(Debug, Clone, Copy)]
+pub struct CoreCapabilities {
+    /// The index of the processor.
+    pub index: u32,
+
+    /// The APIC ID of the processor.
+    pub apic_id: u32,
+
+    /// The type of the core, or `None` if the processor is not hybrid.
+    pub core_type: Option<CoreType>,
+
+    /// The microcode revision, from IA32_BIOS_SIGN_ID.
+    pub microcode: u32,
+
+    /// IA32_VMX_PROCBASED_CTLS2.
+    pub procbased_ctls2: u64,
+
+    /// IA32_VMX_MISC.
+    pub misc: u64,
+
+    /// IA32_VMX_EPT_VPID_CAP.
+    pub ept_vpid_cap: u64,
+}
+
+impl CoreCapabilities {
+    /// Captures the capabilities of the current core.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the processor.
+    /// * `apic_id` - The APIC ID of the processor.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 10.11.7.1 Determining the
+    /// Signature and Update Revision of a Microcode Update.
+    pub fn capture(index: u32, apic_id: u32) -> Self {
+        // The revision is only loaded into IA32_BIOS_SIGN_ID by CPUID leaf 1 after clearing it.
+        wrmsr(IA32_BIOS_SIGN_ID, 0);
+        let _ = cpuid!(0x1);
+        let microcode = (rdmsr(IA32_BIOS_SIGN_ID) >> 32) as u32;
+
+        Self {
+            index,
+            apic_id,
+            core_type: cpu::core_type(),
+            microcode,
+            procbased_ctls2: rdmsr(msr::IA32_VMX_PROCBASED_CTLS2),
+            misc: rdmsr(msr::IA32_VMX_MISC),
+            ept_vpid_cap: rdmsr(msr::IA32_VMX_EPT_VPID_CAP),
+        }
+    }
+}
+
+impl fmt::Display for PlatformInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "IA32_VMX_BASIC: {:#018x}", self.vmx.basic)?;
+        writeln!(
+            f,
+            "IA32_VMX_PINBASED_CTLS: {:#018x}",
+            self.vmx.pinbased_ctls
+        )?;
+        writeln!(
+            f,
+            "IA32_VMX_PROCBASED_CTLS: {:#018x}",
+            self.vmx.procbased_ctls
+        )?;
+        writeln!(
+            f,
+            "IA32_VMX_PROCBASED_CTLS2: {:#018x}",
+            self.vmx.procbased_ctls2
+        )?;
+        writeln!(f, "IA32_VMX_EXIT_CTLS: {:#018x}", self.vmx.exit_ctls)?;
+        writeln!(f, "IA32_VMX_ENTRY_CTLS: {:#018x}", self.vmx.entry_ctls)?;
+        writeln!(f, "IA32_VMX_MISC: {:#018x}", self.vmx.misc)?;
+        writeln!(f, "IA32_VMX_CR0_FIXED0: {:#018x}", self.vmx.cr0_fixed0)?;
+        writeln!(f, "IA32_VMX_CR0_FIXED1: {:#018x}", self.vmx.cr0_fixed1)?;
+        writeln!(f, "IA32_VMX_CR4_FIXED0: {:#018x}", self.vmx.cr4_fixed0)?;
+        writeln!(f, "IA32_VMX_CR4_FIXED1: {:#018x}", self.vmx.cr4_fixed1)?;
+        writeln!(f, "IA32_VMX_VMCS_ENUM: {:#018x}", self.vmx.vmcs_enum)?;
+        writeln!(f, "IA32_VMX_EPT_VPID_CAP: {:#018x}", self.vmx.ept_vpid_cap)?;
+        writeln!(f, "IA32_FEATURE_CONTROL: {:#018x}", self.feature_control)?;
+        writeln!(f, "IA32_MTRRCAP: {:#018x}", self.mtrr_cap)?;
+        writeln!(f, "IA32_MTRR_DEF_TYPE: {:#018x}", self.mtrr_def_type)?;
+        writeln!(f, "Host hypervisor: {}", self.host_hypervisor)?;
+        write!(f, "Enlightenments: {}", self.enlightenments)?;
+
+        for s in self.cpuid.iter() {
+            write!(
+                f,
+                "\nCPUID {:#x}:{:#x}: EAX: {:#x}, EBX: {:#x}, ECX: {:#x}, EDX: {:#x}",
+                s.leaf, s.sub_leaf, s.eax, s.ebx, s.ecx, s.edx
+            )?;
+        }
+
+        Ok(())
+    }
+}
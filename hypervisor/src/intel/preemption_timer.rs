@@ -0,0 +1,183 @@
+//! A periodic entry into VMX root operation with the VMX-preemption timer.
+//!
+//! While the timer is active, it counts down from the value in the VMCS while the guest runs, and the guest exits
+//! when it reaches zero. Its value is saved on every VM exit, so time spent in the guest counts across exits and
+//! the period is not restarted by unrelated ones. On expiry, the period is loaded again and the callback is
+//! invoked in VMX root operation, which suits housekeeping that cannot wait for the guest, like draining log
+//! buffers.
+//!
+//! The timer is set with `Vcpu::set_preemption_timer` from any context and starts, or stops, at the next VM exit
+//! of the processor. It counts down by 1 every time the TSC bit given by `cpu::preemption_timer_rate` changes,
+//! and does not count while the processor is in a C-state deeper than C2.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.1 VMX-Preemption Timer and
+//! 27.2 Recording VM-Exit Information and Updating VM-Entry Control Fields (Saving the VMX-Preemption Timer).
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            support::{try_vmread, try_vmwrite},
+            vmx::Vmx,
+        },
+        utils::{capture::GuestRegisters, sync::SpinLock},
+    },
+    core::sync::atomic::{AtomicBool, Ordering},
+    x86::vmx::vmcs::{
+        self,
+        control::{ExitControls, PinbasedControls},
+    },
+};
+
+/// The shortest period accepted, in timer ticks. A shorter one would have the processor spend its time in VM exits
+/// instead of running the guest.
+pub const MIN_PREEMPTION_TIMER_TICKS: u32 = 1000;
+
+/// Called in VMX root operation every time the timer expires, with the guest registers. A changed RIP, RSP or
+/// RFLAGS must also be written to the guest state of the VMCS.
+pub type PreemptionTimerCallback = fn(&mut GuestRegisters, &mut Vmx);
+
+/// The period and callback of an active timer.
+#[derive(Clone, Copy)]
+struct TimerSetting {
+    /// The ticks loaded into the timer every time it expires.
+    ticks: u32,
+
+    /// Invoked every time the timer expires.
+    callback: PreemptionTimerCallback,
+}
+
+/// The VMX-preemption timer of a processor.
+pub struct PreemptionTimer {
+    /// Whether a request is waiting in `requested`, checked on every VM exit without taking the lock.
+    pending: AtomicBool,
+
+    /// The setting requested from outside of the processor's exit handlers, `None` to stop the timer.
+    requested: SpinLock<Option<TimerSetting>>,
+
+    /// The setting of the running timer, if any.
+    active: Option<TimerSetting>,
+}
+
+impl PreemptionTimer {
+    /// Creates the timer of a processor that is not running.
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            requested: SpinLock::new("preemption_timer_request", None),
+            active: None,
+        }
+    }
+
+    /// Requests the timer from any context, replacing a request not applied yet. It is applied at the next VM
+    /// exit of the processor, see `apply_request`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ticks` - The period in timer ticks, zero to stop the timer.
+    /// * `callback` - Invoked every time the timer expires.
+    ///
+    /// # Returns
+    ///
+    /// `HypervisorError::PreemptionTimerPeriodTooShort` if the period is below `MIN_PREEMPTION_TIMER_TICKS`.
+    pub fn request(
+        &self,
+        ticks: u32,
+        callback: PreemptionTimerCallback,
+    ) -> Result<(), HypervisorError> {
+        let setting = match ticks {
+            0 => None,
+            ticks if ticks < MIN_PREEMPTION_TIMER_TICKS => {
+                return Err(HypervisorError::PreemptionTimerPeriodTooShort)
+            }
+            ticks => Some(TimerSetting { ticks, callback }),
+        };
+
+        *self.requested.lock() = setting;
+        self.pending.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Starts or stops the timer as requested with `request`, if requested. Called on every VM exit.
+    pub fn apply_request(&mut self) -> Result<(), HypervisorError> {
+        if !self.pending.swap(false, Ordering::Acquire) {
+            return Ok(());
+        }
+
+        // The lock may be held by the guest code this exit interrupted, which cannot release it before the guest
+        // resumes. The request is then left pending for a later exit instead of spinning forever.
+        let Some(mut requested) = self.requested.try_lock() else {
+            self.pending.store(true, Ordering::Release);
+            return Ok(());
+        };
+        let setting = requested.take();
+        drop(requested);
+
+        match setting {
+            Some(setting) => {
+                log::trace!("Starting the preemption timer with {} ticks", setting.ticks);
+                try_vmwrite(
+                    vmcs::guest::VMX_PREEMPTION_TIMER_VALUE,
+                    setting.ticks as u64,
+                )?;
+            }
+            None => log::trace!("Stopping the preemption timer"),
+        }
+
+        self.active = setting;
+        set_preemption_timer(setting.is_some())
+    }
+
+    /// Returns whether the timer is running.
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Handles the expiry of the timer by loading the period again and invoking the callback.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Err` if the timer value could not be written, or
+    /// `HypervisorError::UnhandledVmExit` if the timer is not running.
+    pub fn expire(
+        guest_registers: &mut GuestRegisters,
+        vmx: &mut Vmx,
+    ) -> Result<(), HypervisorError> {
+        let setting = vmx
+            .preemption_timer
+            .active
+            .ok_or(HypervisorError::UnhandledVmExit)?;
+
+        try_vmwrite(
+            vmcs::guest::VMX_PREEMPTION_TIMER_VALUE,
+            setting.ticks as u64,
+        )?;
+        (setting.callback)(guest_registers, vmx);
+
+        Ok(())
+    }
+}
+
+impl Default for PreemptionTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Activates or deactivates the VMX-preemption timer of the current processor, along with the saving of its value
+/// on VM exits.
+fn set_preemption_timer(enable: bool) -> Result<(), HypervisorError> {
+    let pinbased = try_vmread(vmcs::control::PINBASED_EXEC_CONTROLS)?;
+    let exit = try_vmread(vmcs::control::VMEXIT_CONTROLS)?;
+    let timer = PinbasedControls::VMX_PREEMPTION_TIMER.bits() as u64;
+    let save = ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits() as u64;
+
+    let (pinbased, exit) = match enable {
+        true => (pinbased | timer, exit | save),
+        false => (pinbased & !timer, exit & !save),
+    };
+
+    try_vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, pinbased)?;
+    try_vmwrite(vmcs::control::VMEXIT_CONTROLS, exit)
+}
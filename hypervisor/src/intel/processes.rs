@@ -0,0 +1,739 @@
+//! Listing and termination of the guest processes, for incident response.
+//!
+//! The processes are enumerated by walking the `ActiveProcessLinks` list of the `EPROCESS` structures of the
+//! guest kernel from VMX root operation, through the guest page tables of the System process, see
+//! `GuestMemory::supervisor`. Each process is reported with its PID, the 15 characters of its image name kept
+//! by the kernel and the CR3 of its address space. The layout of `EPROCESS` changes between Windows builds, so
+//! the offsets are decoded once from the accessors exported by the kernel, `PsGetProcessId` and
+//! `PsGetProcessImageFileName`, when the hypervisor is built.
+//!
+//! The walk does not synchronize with the guest kernel: a process created or exiting during the walk may be
+//! missed, and a list found corrupted ends the walk with what was read so far.
+//!
+//! A process cannot be terminated from VMX root operation, as the kernel has to run down its threads and
+//! handles. The termination hypercall checks that the process exists and queues it, and the processes queued
+//! are terminated with `ZwTerminateProcess` by a system thread started with the hypervisor, which polls the
+//! queue every `WORKER_INTERVAL_MS` at PASSIVE_LEVEL. The System and Idle processes cannot be terminated, nor
+//! can critical processes, whose termination bug checks the system.
+//!
+//! Process control is only enabled with client sessions, so only an admin session can terminate processes.
+//!
+//! Reference: Microsoft Windows Driver Kit: PsGetProcessId, ZwOpenProcess, ZwQueryInformationProcess,
+//! ZwTerminateProcess and PsCreateSystemThread.
+
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            capabilities::{Capability, Subsystem},
+            guest_memory::GuestMemory,
+            hypercall::{HypercallCode, HypercallStatus},
+        },
+        utils::{
+            addresses::Gva,
+            cancellation::CancellationToken,
+            nt::{get_ntoskrnl_export, PsInitialSystemProcess, NTOSKRNL_CR3},
+            sync::SpinLock,
+        },
+    },
+    bstr::ByteSlice,
+    core::sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+    wdk_sys::{
+        ntddk::ZwClose, _CLIENT_ID, HANDLE, LARGE_INTEGER, NTSTATUS, NT_SUCCESS, OBJECT_ATTRIBUTES,
+        PHANDLE, POBJECT_ATTRIBUTES, PVOID,
+    },
+};
+
+/// The hypercalls of the process control, see `intel::capabilities`.
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "processes",
+    enabled: |shared_data| shared_data.processes.is_enabled(),
+    commands: &[
+        Capability {
+            code: HypercallCode::ListProcesses,
+            summary: "Lists the guest processes",
+        },
+        Capability {
+            code: HypercallCode::TerminateProcess,
+            summary: "Queues a guest process for termination",
+        },
+    ],
+};
+
+/// The length of the image name kept in `EPROCESS.ImageFileName`, without the terminating null.
+pub const IMAGE_NAME_LEN: usize = 15;
+
+/// The length of a process record copied by the `ListProcesses` hypercall: PID, CR3 and null-terminated
+/// image name.
+pub const PROCESS_RECORD_LEN: usize = 32;
+
+/// The maximum number of processes walked, so a list looping on itself ends the walk.
+pub const MAX_PROCESSES: usize = 4096;
+
+/// The maximum number of processes queued for termination at once.
+pub const MAX_PENDING_TERMINATIONS: usize = 8;
+
+/// The exit status of the processes terminated.
+pub const TERMINATION_EXIT_STATUS: NTSTATUS = 1;
+
+/// The interval at which the worker thread terminates the processes queued, in milliseconds.
+pub const WORKER_INTERVAL_MS: i64 = 100;
+
+/// The PID of the Idle process.
+const IDLE_PID: u64 = 0;
+
+/// The PID of the System process.
+const SYSTEM_PID: u64 = 4;
+
+/// The offset of `KPROCESS.DirectoryTableBase`, unchanged on x64 since Windows XP.
+const DIRECTORY_TABLE_BASE_OFFSET: u64 = 0x28;
+
+/// The encoding of `mov rax, [rcx + disp32]`, the body of `PsGetProcessId`.
+const MOV_RAX_RCX_DISP32: [u8; 3] = [0x48, 0x8B, 0x81];
+
+/// The encoding of `lea rax, [rcx + disp32]`, the body of `PsGetProcessImageFileName`.
+const LEA_RAX_RCX_DISP32: [u8; 3] = [0x48, 0x8D, 0x81];
+
+/// The access right to terminate a process.
+const PROCESS_TERMINATE: u32 = 0x0001;
+
+/// The access right to query the information of a process.
+const PROCESS_QUERY_INFORMATION: u32 = 0x0400;
+
+/// `ProcessBreakOnTermination` of `PROCESSINFOCLASS`, whether the process is critical.
+const PROCESS_BREAK_ON_TERMINATION: u32 = 29;
+
+/// `STATUS_ACCESS_DENIED`, returned for a critical process.
+const STATUS_ACCESS_DENIED: NTSTATUS = 0xC000_0022_u32 as NTSTATUS;
+
+/// The access right to wait on a thread.
+const SYNCHRONIZE: u32 = 0x0010_0000;
+
+/// `KernelMode` of `KPROCESSOR_MODE`.
+const KERNEL_MODE: i8 = 0;
+
+/// `Executive` of `KWAIT_REASON`.
+const EXECUTIVE: u32 = 0;
+
+/// The attribute of a handle only accessible from kernel mode.
+const OBJ_KERNEL_HANDLE: u32 = 0x0200;
+
+/// The offsets of the `EPROCESS` fields read by the walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EprocessLayout {
+    /// The offset of `UniqueProcessId`.
+    pub unique_process_id: u64,
+
+    /// The offset of `ActiveProcessLinks`, which follows `UniqueProcessId`.
+    pub active_process_links: u64,
+
+    /// The offset of `ImageFileName`.
+    pub image_file_name: u64,
+
+    /// The address of `PsActiveProcessHead`, the head of the list, which is not exported.
+    pub list_head: Gva,
+}
+
+impl EprocessLayout {
+    /// Decodes the offsets from the exports of the kernel.
+    ///
+    /// Must be called in VMX non-root operation, as it reads the kernel directly.
+    ///
+    /// # Returns
+    ///
+    /// The layout, or `HypervisorError::ProcessLayoutUnknown` if an accessor does not have the expected code.
+    pub fn resolve() -> Result<Self, HypervisorError> {
+        let unique_process_id = accessor_offset("PsGetProcessId", MOV_RAX_RCX_DISP32)?;
+        let image_file_name = accessor_offset("PsGetProcessImageFileName", LEA_RAX_RCX_DISP32)?;
+        let active_process_links = unique_process_id + 8;
+
+        // The System process is the first in the list, so its back link is the head.
+        let system = unsafe { PsInitialSystemProcess } as u64;
+        if system == 0 {
+            return Err(HypervisorError::ProcessLayoutUnknown);
+        }
+        let list_head = unsafe { *((system + active_process_links + 8) as *const u64) };
+
+        let layout = Self {
+            unique_process_id,
+            active_process_links,
+            image_file_name,
+            list_head: Gva::new(list_head),
+        };
+        log::debug!("EPROCESS layout: {:x?}", layout);
+
+        Ok(layout)
+    }
+}
+
+/// A guest process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestProcess {
+    /// The process ID.
+    pub pid: u64,
+
+    /// The CR3 of the address space of the process, its kernel page tables when the kernel isolates them.
+    pub cr3: u64,
+
+    /// The first characters of the image file name, padded with nulls.
+    pub image_name: [u8; IMAGE_NAME_LEN],
+
+    /// The guest linear address of the `EPROCESS`.
+    pub eprocess: Gva,
+}
+
+impl GuestProcess {
+    /// Returns the image name without its padding.
+    pub fn name(&self) -> &[u8] {
+        let len = self
+            .image_name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(IMAGE_NAME_LEN);
+        self.image_name.get(..len).unwrap_or_default()
+    }
+
+    /// Encodes the record copied by the `ListProcesses` hypercall.
+    pub fn encode(&self) -> [u8; PROCESS_RECORD_LEN] {
+        let mut bytes = [0u8; PROCESS_RECORD_LEN];
+        let pid = self.pid.to_le_bytes();
+        let cr3 = self.cr3.to_le_bytes();
+
+        let mut offset = 0;
+        for field in [&pid[..], &cr3[..], &self.image_name[..]] {
+            if let Some(slot) = bytes.get_mut(offset..offset + field.len()) {
+                slot.copy_from_slice(field);
+            }
+            offset += field.len();
+        }
+
+        bytes
+    }
+}
+
+/// The result of copying the process list into a buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessExport {
+    /// The number of records copied.
+    pub copied: usize,
+
+    /// The number of processes walked.
+    pub total: usize,
+
+    /// Whether the walk was cancelled before the end of the list.
+    pub cancelled: bool,
+}
+
+/// Lists the guest processes and queues the ones to terminate.
+pub struct ProcessControl {
+    /// The layout of `EPROCESS`, or `None` if process control is disabled.
+    layout: Option<EprocessLayout>,
+
+    /// The PIDs of the processes to terminate.
+    pending: SpinLock<[Option<u64>; MAX_PENDING_TERMINATIONS]>,
+
+    /// The thread object of the worker terminating the processes queued, or null if it is not running.
+    worker: AtomicPtr<core::ffi::c_void>,
+
+    /// Whether the worker has to exit.
+    stopping: AtomicBool,
+}
+
+impl ProcessControl {
+    /// Creates the process control.
+    ///
+    /// # Arguments
+    ///
+    /// * `layout` - The layout of `EPROCESS`, or `None` to disable process control.
+    pub fn new(layout: Option<EprocessLayout>) -> Self {
+        Self {
+            layout,
+            pending: SpinLock::new("pending_terminations", [None; MAX_PENDING_TERMINATIONS]),
+            worker: AtomicPtr::new(core::ptr::null_mut()),
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns whether the process hypercalls are served.
+    pub fn is_enabled(&self) -> bool {
+        self.layout.is_some()
+    }
+
+    /// Walks the process list of the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `cancellation` - Stops the walk once cancelled, see `utils::cancellation`.
+    /// * `visitor` - Called for each process, in the order of the list.
+    ///
+    /// # Returns
+    ///
+    /// The number of processes walked, `HypercallStatus::NotSupported` if process control is disabled, or
+    /// `HypercallStatus::Cancelled` if the walk was cancelled.
+    pub fn walk(
+        &self,
+        cancellation: &CancellationToken,
+        mut visitor: impl FnMut(&GuestProcess),
+    ) -> Result<usize, HypercallStatus> {
+        let layout = self.layout.ok_or(HypercallStatus::NotSupported)?;
+        let memory = GuestMemory::supervisor(NTOSKRNL_CR3.load(Ordering::Acquire));
+
+        let read_u64 = |address: Gva| {
+            let mut bytes = [0u8; 8];
+            memory.read(address, &mut bytes).ok()?;
+            Some(u64::from_le_bytes(bytes))
+        };
+
+        let mut walked = 0;
+        let mut link = read_u64(layout.list_head);
+
+        while let Some(entry) = link.filter(|&entry| entry != layout.list_head.as_u64()) {
+            if walked >= MAX_PROCESSES {
+                log::warn!("Process list longer than {} entries", MAX_PROCESSES);
+                break;
+            }
+
+            if cancellation.is_cancelled() {
+                return Err(HypercallStatus::Cancelled);
+            }
+
+            let eprocess = Gva::new(entry.wrapping_sub(layout.active_process_links));
+            let mut image_name = [0u8; IMAGE_NAME_LEN];
+
+            let (Some(pid), Some(cr3), Ok(())) = (
+                read_u64(eprocess + layout.unique_process_id),
+                read_u64(eprocess + DIRECTORY_TABLE_BASE_OFFSET),
+                memory.read(eprocess + layout.image_file_name, &mut image_name),
+            ) else {
+                log::warn!("Process list broken at {:#x}", entry);
+                break;
+            };
+
+            visitor(&GuestProcess {
+                pid,
+                cr3,
+                image_name,
+                eprocess,
+            });
+
+            walked += 1;
+            link = read_u64(Gva::new(entry));
+        }
+
+        Ok(walked)
+    }
+
+    /// Copies the process records from an index into a buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - Receives the records, as many as fit.
+    /// * `first` - The index of the first process copied, to read a list longer than the buffer.
+    /// * `cancellation` - Stops the walk once cancelled, keeping the records copied so far.
+    ///
+    /// # Returns
+    ///
+    /// The number of records copied and of processes walked.
+    pub fn export(
+        &self,
+        buffer: &mut [u8],
+        first: usize,
+        cancellation: &CancellationToken,
+    ) -> Result<ProcessExport, HypercallStatus> {
+        let mut records = buffer.chunks_exact_mut(PROCESS_RECORD_LEN);
+        let mut copied = 0;
+        let mut index = 0;
+
+        let walked = self.walk(cancellation, |process| {
+            if index >= first {
+                if let Some(record) = records.next() {
+                    record.copy_from_slice(&process.encode());
+                    copied += 1;
+                }
+            }
+            index += 1;
+        });
+
+        let (total, cancelled) = match walked {
+            Ok(total) => (total, false),
+            Err(HypercallStatus::Cancelled) => (index, true),
+            Err(status) => return Err(status),
+        };
+
+        Ok(ProcessExport {
+            copied,
+            total,
+            cancelled,
+        })
+    }
+
+    /// Queues a process for termination by the worker thread, see `ProcessControl::start_worker`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - The ID of the process.
+    /// * `cancellation` - Stops looking for the process once cancelled.
+    ///
+    /// # Returns
+    ///
+    /// * `HypercallStatus::NotSupported` - Process control is disabled.
+    /// * `HypercallStatus::AccessDenied` - The process is the System or Idle process.
+    /// * `HypercallStatus::InvalidParameter` - No process has the ID.
+    /// * `HypercallStatus::InsufficientResources` - Too many processes are queued already.
+    /// * `HypercallStatus::Cancelled` - The process list was not walked to the end.
+    pub fn request_termination(
+        &self,
+        pid: u64,
+        cancellation: &CancellationToken,
+    ) -> Result<(), HypercallStatus> {
+        let process = self.find(pid, cancellation)?;
+
+        let mut pending = self.pending.lock();
+        if pending.contains(&Some(pid)) {
+            return Ok(());
+        }
+
+        let slot = pending
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(HypercallStatus::InsufficientResources)?;
+        *slot = Some(pid);
+
+        log::warn!(
+            "Process {} ({}) queued for termination",
+            pid,
+            process.name().as_bstr()
+        );
+
+        Ok(())
+    }
+
+    /// Terminates a process right away, leaving the processes queued to the worker thread.
+    ///
+    /// Must be called at PASSIVE_LEVEL in VMX non-root operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - The ID of the process.
+    ///
+    /// # Returns
+    ///
+    /// The statuses of `ProcessControl::request_termination`, or `HypercallStatus::AccessDenied` if the process
+    /// is critical or could not be terminated.
+    pub fn terminate_now(&self, pid: u64) -> Result<(), HypercallStatus> {
+        // Outside of VMX root operation, the guest is not paused by the walk.
+        self.find(pid, &CancellationToken::new())?;
+
+        terminate(pid).map_err(|status| {
+            log::error!("Failed to terminate process {}: {:#x}", pid, status);
+            HypercallStatus::AccessDenied
+        })?;
+
+        log::warn!("Process {} terminated", pid);
+        Ok(())
+    }
+
+    /// Terminates the processes queued.
+    ///
+    /// Must be called at PASSIVE_LEVEL in VMX non-root operation.
+    ///
+    /// # Returns
+    ///
+    /// The number of processes terminated. Processes that exited in the meantime are skipped.
+    pub fn terminate_pending(&self) -> usize {
+        let pids = core::mem::replace(&mut *self.pending.lock(), [None; MAX_PENDING_TERMINATIONS]);
+
+        pids.into_iter()
+            .flatten()
+            .filter(|&pid| match terminate(pid) {
+                Ok(()) => {
+                    log::warn!("Process {} terminated", pid);
+                    true
+                }
+                Err(status) => {
+                    log::error!("Failed to terminate process {}: {:#x}", pid, status);
+                    false
+                }
+            })
+            .count()
+    }
+
+    /// Starts the system thread terminating the processes queued, if process control is enabled.
+    ///
+    /// Must be called at PASSIVE_LEVEL once the process control does not move anymore, and followed by
+    /// `ProcessControl::stop_worker` before it is dropped.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Err(HypervisorError::WorkerThreadFailed)` if the thread could not be created.
+    pub fn start_worker(&self) -> Result<(), HypervisorError> {
+        if !self.is_enabled() || !self.worker.load(Ordering::Acquire).is_null() {
+            return Ok(());
+        }
+
+        self.stopping.store(false, Ordering::Release);
+
+        let mut handle: HANDLE = core::ptr::null_mut();
+        let status = unsafe {
+            PsCreateSystemThread(
+                &mut handle,
+                0,
+                core::ptr::null_mut(),
+                core::ptr::null_mut(),
+                core::ptr::null_mut(),
+                worker_routine,
+                self as *const Self as PVOID,
+            )
+        };
+        if !NT_SUCCESS(status) {
+            log::error!("Failed to create the termination worker: {:#x}", status);
+            return Err(HypervisorError::WorkerThreadFailed);
+        }
+
+        // The thread object is referenced to wait for the thread, as its handle may be closed.
+        let mut thread: PVOID = core::ptr::null_mut();
+        let status = unsafe {
+            ObReferenceObjectByHandle(
+                handle,
+                SYNCHRONIZE,
+                core::ptr::null_mut(),
+                KERNEL_MODE,
+                &mut thread,
+                core::ptr::null_mut(),
+            )
+        };
+        unsafe { ZwClose(handle) };
+
+        if !NT_SUCCESS(status) {
+            // Without the thread object the thread cannot be waited for, so it is stopped right away.
+            log::error!("Failed to reference the termination worker: {:#x}", status);
+            self.stopping.store(true, Ordering::Release);
+            return Err(HypervisorError::WorkerThreadFailed);
+        }
+
+        self.worker.store(thread, Ordering::Release);
+        Ok(())
+    }
+
+    /// Stops the worker thread and waits for it to exit. Must be called at PASSIVE_LEVEL.
+    pub fn stop_worker(&self) {
+        self.stopping.store(true, Ordering::Release);
+
+        let thread = self.worker.swap(core::ptr::null_mut(), Ordering::AcqRel);
+        if thread.is_null() {
+            return;
+        }
+
+        unsafe {
+            KeWaitForSingleObject(thread, EXECUTIVE, KERNEL_MODE, 0, core::ptr::null_mut());
+            ObfDereferenceObject(thread);
+        }
+    }
+
+    /// Returns the process with an ID, if it can be terminated.
+    ///
+    /// # Returns
+    ///
+    /// The process, or the statuses of `ProcessControl::request_termination`.
+    fn find(
+        &self,
+        pid: u64,
+        cancellation: &CancellationToken,
+    ) -> Result<GuestProcess, HypercallStatus> {
+        if !self.is_enabled() {
+            return Err(HypercallStatus::NotSupported);
+        }
+
+        if pid == IDLE_PID || pid == SYSTEM_PID {
+            return Err(HypercallStatus::AccessDenied);
+        }
+
+        let mut found = None;
+        self.walk(cancellation, |process| {
+            if process.pid == pid {
+                found = Some(*process);
+            }
+        })?;
+
+        found.ok_or(HypercallStatus::InvalidParameter)
+    }
+}
+
+/// The worker thread, terminating the processes queued until `ProcessControl::stop_worker` is called.
+///
+/// # Arguments
+///
+/// * `context` - The `ProcessControl`, which outlives the thread.
+extern "system" fn worker_routine(context: PVOID) {
+    let control = unsafe { &*(context as *const ProcessControl) };
+
+    // A relative interval in units of 100 nanoseconds.
+    let mut interval = LARGE_INTEGER {
+        QuadPart: -WORKER_INTERVAL_MS * 10_000,
+    };
+
+    while !control.stopping.load(Ordering::Acquire) {
+        control.terminate_pending();
+        unsafe { KeDelayExecutionThread(KERNEL_MODE, 0, &mut interval) };
+    }
+
+    unsafe { PsTerminateSystemThread(0) };
+}
+
+/// Terminates a process.
+///
+/// # Returns
+///
+/// The status of the first call that failed.
+fn terminate(pid: u64) -> Result<(), NTSTATUS> {
+    let mut attributes = OBJECT_ATTRIBUTES {
+        Length: core::mem::size_of::<OBJECT_ATTRIBUTES>() as u32,
+        Attributes: OBJ_KERNEL_HANDLE,
+        ..Default::default()
+    };
+    let mut client_id = _CLIENT_ID {
+        UniqueProcess: pid as HANDLE,
+        UniqueThread: core::ptr::null_mut(),
+    };
+    let mut handle: HANDLE = core::ptr::null_mut();
+
+    let status = unsafe {
+        ZwOpenProcess(
+            &mut handle,
+            PROCESS_TERMINATE | PROCESS_QUERY_INFORMATION,
+            &mut attributes,
+            &mut client_id,
+        )
+    };
+    if !NT_SUCCESS(status) {
+        return Err(status);
+    }
+
+    // Terminating a critical process bug checks the system, so a process that cannot be queried is refused too.
+    let mut critical: u32 = 1;
+    let status = unsafe {
+        ZwQueryInformationProcess(
+            handle,
+            PROCESS_BREAK_ON_TERMINATION,
+            &mut critical as *mut u32 as PVOID,
+            core::mem::size_of::<u32>() as u32,
+            core::ptr::null_mut(),
+        )
+    };
+
+    let status = match (NT_SUCCESS(status), critical) {
+        (true, 0) => unsafe { ZwTerminateProcess(handle, TERMINATION_EXIT_STATUS) },
+        (true, _) => STATUS_ACCESS_DENIED,
+        (false, _) => status,
+    };
+    unsafe { ZwClose(handle) };
+
+    match NT_SUCCESS(status) {
+        true => Ok(()),
+        false => Err(status),
+    }
+}
+
+/// Decodes the displacement of the `[rcx + disp32]` operand of an accessor exported by the kernel.
+///
+/// # Arguments
+///
+/// * `name` - The name of the export.
+/// * `opcode` - The expected encoding of the instruction, without the displacement.
+fn accessor_offset(name: &str, opcode: [u8; 3]) -> Result<u64, HypervisorError> {
+    let address = get_ntoskrnl_export(name) as *const u8;
+    if address.is_null() {
+        return Err(HypervisorError::ProcessLayoutUnknown);
+    }
+
+    let code = unsafe { core::slice::from_raw_parts(address, 7) };
+    let (Some(prefix), Some(displacement)) = (code.get(..3), code.get(3..7)) else {
+        return Err(HypervisorError::ProcessLayoutUnknown);
+    };
+
+    if prefix != opcode {
+        log::error!("Unexpected code of {}: {:02x?}", name, code);
+        return Err(HypervisorError::ProcessLayoutUnknown);
+    }
+
+    let displacement = i32::from_le_bytes(
+        displacement
+            .try_into()
+            .map_err(|_| HypervisorError::ProcessLayoutUnknown)?,
+    );
+
+    u64::try_from(displacement).map_err(|_| HypervisorError::ProcessLayoutUnknown)
+}
+
+#[link(name = "ntoskrnl")]
+extern "system" {
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/ntddk/nf-ntddk-zwopenprocess
+    fn ZwOpenProcess(
+        process_handle: PHANDLE,
+        desired_access: u32,
+        object_attributes: POBJECT_ATTRIBUTES,
+        client_id: *mut _CLIENT_ID,
+    ) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/ntddk/nf-ntddk-zwterminateprocess
+    fn ZwTerminateProcess(process_handle: HANDLE, exit_status: NTSTATUS) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows/win32/procthread/zwqueryinformationprocess
+    fn ZwQueryInformationProcess(
+        process_handle: HANDLE,
+        process_information_class: u32,
+        process_information: PVOID,
+        process_information_length: u32,
+        return_length: *mut u32,
+    ) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-pscreatesystemthread
+    fn PsCreateSystemThread(
+        thread_handle: PHANDLE,
+        desired_access: u32,
+        object_attributes: POBJECT_ATTRIBUTES,
+        process_handle: HANDLE,
+        client_id: *mut _CLIENT_ID,
+        start_routine: extern "system" fn(PVOID),
+        start_context: PVOID,
+    ) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-psterminatesystemthread
+    fn PsTerminateSystemThread(exit_status: NTSTATUS) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-obreferenceobjectbyhandle
+    fn ObReferenceObjectByHandle(
+        handle: HANDLE,
+        desired_access: u32,
+        object_type: PVOID,
+        access_mode: i8,
+        object: *mut PVOID,
+        handle_information: PVOID,
+    ) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-obdereferenceobject
+    fn ObfDereferenceObject(object: PVOID) -> isize;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-kewaitforsingleobject
+    fn KeWaitForSingleObject(
+        object: PVOID,
+        wait_reason: u32,
+        wait_mode: i8,
+        alertable: u8,
+        timeout: *mut LARGE_INTEGER,
+    ) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-kedelayexecutionthread
+    fn KeDelayExecutionThread(
+        wait_mode: i8,
+        alertable: u8,
+        interval: *mut LARGE_INTEGER,
+    ) -> NTSTATUS;
+}
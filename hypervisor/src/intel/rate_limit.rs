@@ -0,0 +1,183 @@
+//! Per-processor rate limiting of expensive guest-triggerable VM exits.
+//!
+//! Some VM exits can be triggered at will by any guest process, e.g. VMCALL, or by touching a page
+//! monitored through the EPT. Each one of them costs a round trip through the hypervisor, and the handlers
+//! behind them may take locks shared between processors. A malicious guest spamming such exits could starve
+//! the host, so every processor counts these exits in fixed windows of TSC ticks and, once the configured
+//! quota of a window is exceeded, backs off: either by injecting #GP(0) into the guest instead of serving
+//! the exit, or by delaying the guest before serving it.
+//!
+//! Rate limiting is disabled unless a quota is configured for a class of exits.
+
+use crate::utils::instructions::rdtsc;
+
+/// A class of guest-triggerable VM exits that are rate limited together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitClass {
+    /// VMCALL, i.e. hypercalls.
+    Hypercall = 0,
+
+    /// EPT violations on monitored memory regions, see `SharedData::is_monitored`.
+    MonitoredFault = 1,
+}
+
+/// The number of exit classes.
+const EXIT_CLASS_COUNT: usize = 2;
+
+/// What to do with an exit over quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Inject #GP(0) into the guest instead of serving the exit.
+    ///
+    /// Only suitable for exits caused by instructions the guest expects to fail, such as hypercalls. Refused for
+    /// `ExitClass::MonitoredFault` by `HypervisorBuilder::build`, as those exits are caused by ordinary loads and
+    /// stores.
+    InjectGp,
+
+    /// Delay the guest by the given number of TSC ticks, then serve the exit.
+    Delay(u64),
+}
+
+/// The quota of a class of exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// The number of exits served per window without backing off.
+    pub max_exits: u32,
+
+    /// The length of a window in TSC ticks.
+    pub window: u64,
+
+    /// What to do with the exits over quota.
+    pub backoff: Backoff,
+}
+
+/// The quotas of all exit classes, shared between processors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimitPolicy {
+    /// The quota of hypercalls, or `None` for no limit.
+    pub hypercall: Option<Quota>,
+
+    /// The quota of EPT violations on monitored regions, or `None` for no limit.
+    pub monitored_fault: Option<Quota>,
+}
+
+impl RateLimitPolicy {
+    /// Returns the quota of a class of exits, if any.
+    pub fn quota(&self, class: ExitClass) -> Option<Quota> {
+        match class {
+            ExitClass::Hypercall => self.hypercall,
+            ExitClass::MonitoredFault => self.monitored_fault,
+        }
+    }
+}
+
+/// The decision taken for a rate limited exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The exit is within quota and is served.
+    Allow,
+
+    /// The exit is over quota and #GP(0) must be injected instead of serving it.
+    InjectGp,
+
+    /// The exit was over quota, the guest has been delayed and the exit is served.
+    Delayed,
+}
+
+/// The exits counted in the current window of a class.
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    /// The TSC at which the current window started.
+    window_start: u64,
+
+    /// The number of exits in the current window.
+    count: u32,
+
+    /// The total number of exits over quota.
+    throttled: u64,
+}
+
+/// The rate limiter of a single processor.
+///
+/// Only ever accessed by the VM-exit handler of its own processor, so no synchronization is needed.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimiter {
+    /// The quotas.
+    policy: RateLimitPolicy,
+
+    /// The counters, indexed by `ExitClass`.
+    buckets: [Bucket; EXIT_CLASS_COUNT],
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The quotas to enforce.
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        Self {
+            policy,
+            buckets: [Bucket::default(); EXIT_CLASS_COUNT],
+        }
+    }
+
+    /// Accounts for an exit and decides whether to serve it.
+    ///
+    /// # Arguments
+    ///
+    /// * `class` - The class of the exit.
+    ///
+    /// # Returns
+    ///
+    /// The `Verdict` for the exit. When the backoff is a delay, the delay has already elapsed on return.
+    pub fn check(&mut self, class: ExitClass) -> Verdict {
+        let Some(quota) = self.policy.quota(class) else {
+            return Verdict::Allow;
+        };
+
+        let Some(bucket) = self.buckets.get_mut(class as usize) else {
+            return Verdict::Allow;
+        };
+
+        let now = rdtsc();
+
+        if now.wrapping_sub(bucket.window_start) > quota.window {
+            bucket.window_start = now;
+            bucket.count = 0;
+        }
+
+        bucket.count = bucket.count.saturating_add(1);
+
+        if bucket.count <= quota.max_exits {
+            return Verdict::Allow;
+        }
+
+        bucket.throttled += 1;
+
+        log::trace!(
+            "{:?} exit over quota ({} in window), backing off: {:?}",
+            class,
+            bucket.count,
+            quota.backoff
+        );
+
+        match quota.backoff {
+            Backoff::InjectGp => Verdict::InjectGp,
+            Backoff::Delay(ticks) => {
+                let start = rdtsc();
+                while rdtsc().wrapping_sub(start) < ticks {
+                    core::hint::spin_loop();
+                }
+                Verdict::Delayed
+            }
+        }
+    }
+
+    /// Returns the total number of exits of a class that went over quota.
+    pub fn throttled(&self, class: ExitClass) -> u64 {
+        self.buckets
+            .get(class as usize)
+            .map_or(0, |bucket| bucket.throttled)
+    }
+}
@@ -0,0 +1,914 @@
+//! A sandbox detonating a caller-provided code blob on a virtual processor.
+//!
+//! The blob is copied into hypervisor-owned pages and executed in the context of the current guest, but
+//! in user mode, with its own address space and an isolated EPT view: the guest page tables of the sandbox only
+//! map the code and a small stack, as user pages, and the sandbox EPT only maps those pages and the sandbox page
+//! tables. Any other memory access therefore ends in a page fault or an EPT violation, which terminates the run,
+//! and privileged instructions fault instead of changing the state of the processor. The blob is single-stepped
+//! with the monitor trap flag, all exceptions are intercepted, and the run ends after a configurable number
+//! of instructions or when the blob returns. The outcome is recorded in an `ExecutionReport`, and the guest
+//! resumes where it was interrupted as if nothing happened.
+//!
+//! The run is also bounded in time, so a blob stalling in a single instruction, e.g. HLT, MWAIT or a long REP
+//! string instruction, cannot hold the processor forever. The VMX-preemption timer forces a VM exit once the
+//! configured number of TSC cycles has elapsed, and the elapsed cycles are checked on every step as well, which
+//! is the only bound on processors without the timer.
+//!
+//! Besides the registers, CR3 and the code and stack segments the sandbox sets, the data segment registers the
+//! blob can load from user mode are saved and restored. The x87, SSE and AVX state and PKRU are not switched on
+//! VM exits, so the blob is denied them instead: CR0.EM and the cleared CR4.OSFXSR, OSXSAVE, FSGSBASE and PKE
+//! make the instructions touching them or the FS and GS bases fault, which ends the run.
+//!
+//! NMIs ending a run are reflected to the guest once its state is restored, and NMIs kicking the processor, see
+//! `intel::ipi`, are consumed without ending it. Exceptions and software interrupts raised by the blob are
+//! discarded with it. Only the execution controls the sandbox sets are restored when the run ends, so the window
+//! exiting controls the event queue enabled meanwhile stay in place.
+//!
+//! The sandbox is a building block for hypervisor-based scanners. `Vcpu::detonate` creates it at PASSIVE_LEVEL,
+//! attaches it to the `Vmx` of the processor and starts it with a host call, see `intel::host_call`, which
+//! returns once the run ended.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.1 VMX-Preemption Timer,
+//! 26.5.2 Monitor Trap Flag and 29.3 THE EXTENDED PAGE TABLE MECHANISM (EPT).
+
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::{
+                mtrr::MemoryType,
+                paging::{AccessType, Entry},
+            },
+            event_queue::PendingEvent,
+            invept::{invept_all_contexts, invept_single_context},
+            invvpid::{invvpid_single_context, VPID_TAG},
+            ipi,
+            support::{try_vmread, try_vmwrite},
+            vmerror::{
+                ExceptionInterrupt, InterruptionType, VmExitInterruptionInformation,
+                VmxBasicExitReason,
+            },
+            vmexit::ExitType,
+            vmx::Vmx,
+        },
+        utils::{
+            addresses::PhysicalAddress,
+            alloc::PhysicalAllocator,
+            capture::GuestRegisters,
+            cpu,
+            footprint::{self, MemoryCategory},
+            instructions::rdtsc,
+        },
+    },
+    alloc::boxed::Box,
+    core::mem::size_of,
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        controlregs::{Cr0, Cr4},
+        vmx::vmcs::{
+            self,
+            control::{ExitControls, PinbasedControls, PrimaryControls},
+            guest, ro,
+        },
+    },
+};
+
+/// The maximum size of a code blob, in pages.
+pub const MAX_CODE_PAGES: usize = 4;
+
+/// The size of the sandbox stack, in pages.
+const STACK_PAGES: usize = 2;
+
+/// The number of EPT tables available to the sandbox view.
+const EPT_TABLE_POOL: usize = 32;
+
+/// The number of instruction pointers recorded in a report.
+pub const TRACE_LEN: usize = 64;
+
+/// The guest virtual address the code blob is mapped at.
+pub const SANDBOX_CODE_VA: u64 = 0x20_0000;
+
+/// The guest virtual address of the sandbox stack, leaving an unmapped guard page after the code.
+const SANDBOX_STACK_VA: u64 = SANDBOX_CODE_VA + ((MAX_CODE_PAGES + 1) * BASE_PAGE_SIZE) as u64;
+
+/// The unmapped return address pushed on the sandbox stack. Returning to it ends the run.
+const SANDBOX_RETURN_VA: u64 = 0x10_0000;
+
+/// Guest paging entry flags: present and writable.
+const PAGE_PRESENT_WRITABLE: u64 = 0b11;
+
+/// Guest paging entry flag: accessible from user mode, set at every level as the blob runs at CPL 3.
+const PAGE_USER: u64 = 1 << 2;
+
+/// Guest paging entry flag: execute disable.
+const PAGE_EXECUTE_DISABLE: u64 = 1 << 63;
+
+/// RFLAGS bit 1, which is reserved and always set.
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+/// The selectors of the user code and data segments, with an RPL of 3 as in the GDT of Windows.
+const USER_CODE_SELECTOR: u64 = 0x33;
+const USER_DATA_SELECTOR: u64 = 0x2B;
+
+/// The access rights of the user code segment: accessed execute/read code, DPL 3, present, 64-bit and page
+/// granular.
+const USER_CODE_ACCESS_RIGHTS: u64 = 0xA0FB;
+
+/// The access rights of the user stack segment: accessed read/write data, DPL 3, present, 32-bit and page
+/// granular.
+const USER_DATA_ACCESS_RIGHTS: u64 = 0xC0F3;
+
+/// The limit of a flat segment.
+const FLAT_LIMIT: u64 = 0xFFFF_FFFF;
+
+/// Blocking by STI and by MOV SS in the interruptibility state, which VM entry refuses with RFLAGS.IF clear.
+const BLOCKING_BY_STI_OR_MOV_SS: u64 = 0b11;
+
+/// The VMCS fields of the data segment registers: selector, base, limit and access rights.
+const DATA_SEGMENTS: [[u32; 4]; 4] = [
+    [
+        guest::DS_SELECTOR,
+        guest::DS_BASE,
+        guest::DS_LIMIT,
+        guest::DS_ACCESS_RIGHTS,
+    ],
+    [
+        guest::ES_SELECTOR,
+        guest::ES_BASE,
+        guest::ES_LIMIT,
+        guest::ES_ACCESS_RIGHTS,
+    ],
+    [
+        guest::FS_SELECTOR,
+        guest::FS_BASE,
+        guest::FS_LIMIT,
+        guest::FS_ACCESS_RIGHTS,
+    ],
+    [
+        guest::GS_SELECTOR,
+        guest::GS_BASE,
+        guest::GS_LIMIT,
+        guest::GS_ACCESS_RIGHTS,
+    ],
+];
+
+/// The CR4 bits cleared for the run, so the blob cannot change the SSE and AVX state, the FS and GS bases
+/// directly or PKRU.
+const CR4_DENIED: Cr4 = Cr4::CR4_ENABLE_SSE
+    .union(Cr4::CR4_ENABLE_OS_XSAVE)
+    .union(Cr4::CR4_ENABLE_FSGSBASE)
+    .union(Cr4::CR4_ENABLE_PROTECTION_KEY);
+
+/// The EPTP page walk length of 4 levels, encoded as levels minus one.
+const EPTP_PAGE_WALK_LENGTH_4: u64 = 3 << 3;
+
+/// Limits of a sandbox run.
+#[derive(Debug, Clone, Copy)]
+pub struct SandboxConfig {
+    /// The number of instructions after which the run is stopped.
+    pub max_instructions: u64,
+
+    /// The number of TSC cycles after which the run is stopped, including the time spent single-stepping it.
+    pub max_cycles: u64,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            max_instructions: 10_000,
+            // About 0.2 seconds at 2.5 GHz, well above what 10 000 single-stepped instructions take.
+            max_cycles: 500_000_000,
+        }
+    }
+}
+
+/// Why a sandbox run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SandboxExit {
+    /// The blob returned to its caller.
+    Completed,
+
+    /// The blob executed the configured number of instructions.
+    InstructionLimit,
+
+    /// The blob ran for the configured number of TSC cycles.
+    TimeLimit,
+
+    /// The blob accessed memory outside of the sandbox.
+    MemoryViolation { guest_pa: u64, qualification: u64 },
+
+    /// The blob raised an exception, or an NMI (vector 2) interrupted it.
+    Exception { vector: u32, error_code: u32 },
+}
+
+/// The outcome of a sandbox run.
+#[derive(Clone, Copy)]
+pub struct ExecutionReport {
+    /// Why the run ended.
+    pub exit: SandboxExit,
+
+    /// The number of instructions executed.
+    pub instructions: u64,
+
+    /// The number of TSC cycles the run took.
+    pub cycles: u64,
+
+    /// The instruction pointer at the end of the run.
+    pub final_rip: u64,
+
+    /// The general-purpose registers at the end of the run.
+    pub final_registers: GuestRegisters,
+
+    /// The instruction pointers of the first executed instructions.
+    trace: [u64; TRACE_LEN],
+
+    /// The number of valid entries in `trace`.
+    trace_len: usize,
+}
+
+impl ExecutionReport {
+    /// Returns the instruction pointers of the first `TRACE_LEN` executed instructions.
+    pub fn trace(&self) -> &[u64] {
+        self.trace.get(..self.trace_len).unwrap_or(&[])
+    }
+}
+
+/// The guest state replaced while the sandbox runs.
+#[derive(Clone, Copy)]
+struct SavedState {
+    registers: GuestRegisters,
+    rip: u64,
+    rsp: u64,
+    rflags: u64,
+    cr3: u64,
+    cs_selector: u64,
+    cs_limit: u64,
+    cs_access_rights: u64,
+    ss_selector: u64,
+    ss_limit: u64,
+    ss_access_rights: u64,
+    data_segments: [[u64; 4]; 4],
+    cr0: u64,
+    cr4: u64,
+    interruptibility: u64,
+    eptp: u64,
+    exception_bitmap: u64,
+    pfec_mask: u64,
+    pfec_match: u64,
+    procbased_controls: u64,
+    pinbased_controls: u64,
+    exit_controls: u64,
+    preemption_timer_value: u64,
+}
+
+/// The memory of the sandbox, allocated as a single physically contiguous block.
+#[repr(C, align(4096))]
+struct SandboxMemory {
+    /// The pages holding the code blob.
+    code: [[u8; BASE_PAGE_SIZE]; MAX_CODE_PAGES],
+
+    /// The pages of the sandbox stack.
+    stack: [[u8; BASE_PAGE_SIZE]; STACK_PAGES],
+
+    /// The guest PML4, PDPT, PD and PT mapping the code and the stack.
+    guest_tables: [[u64; 512]; 4],
+
+    /// The tables of the sandbox EPT view. The first table is the PML4.
+    ept_tables: [[Entry; 512]; EPT_TABLE_POOL],
+}
+
+/// A code blob ready to be detonated, and the report of its last run.
+pub struct Sandbox {
+    /// The sandbox memory.
+    memory: Box<SandboxMemory, PhysicalAllocator>,
+
+    /// The limits of a run.
+    config: SandboxConfig,
+
+    /// The EPTP of the sandbox view.
+    eptp: u64,
+
+    /// The number of EPT tables handed out from the pool.
+    ept_tables_used: usize,
+
+    /// The guest state to restore, while the sandbox runs.
+    saved: Option<SavedState>,
+
+    /// The report of the current or last run.
+    report: Option<ExecutionReport>,
+
+    /// The TSC when the current run started.
+    started_at: u64,
+}
+
+impl Sandbox {
+    /// Creates a sandbox for a code blob.
+    ///
+    /// Must be called at PASSIVE_LEVEL, as the sandbox memory is allocated and its physical addresses resolved here.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The code blob, executed from its first byte.
+    /// * `config` - The limits of a run.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the sandbox, or `HypervisorError::SandboxCodeTooLarge` if the blob exceeds `MAX_CODE_PAGES`.
+    pub fn new(code: &[u8], config: SandboxConfig) -> Result<Box<Self>, HypervisorError> {
+        log::debug!("Creating sandbox for {} bytes of code", code.len());
+
+        if code.is_empty() || code.len() > MAX_CODE_PAGES * BASE_PAGE_SIZE {
+            return Err(HypervisorError::SandboxCodeTooLarge);
+        }
+
+        let reservation =
+            footprint::reserve_all([(MemoryCategory::Ept, size_of::<SandboxMemory>() as u64)])?;
+
+        let memory: Box<SandboxMemory, PhysicalAllocator> =
+            unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
+
+        let mut sandbox = Box::new(Self {
+            memory,
+            config,
+            eptp: 0,
+            ept_tables_used: 1,
+            saved: None,
+            report: None,
+            started_at: 0,
+        });
+
+        // The charge is released when the sandbox is dropped.
+        reservation.keep();
+
+        sandbox.copy_code(code);
+        sandbox.build_guest_tables()?;
+        sandbox.build_ept()?;
+
+        Ok(sandbox)
+    }
+
+    /// Returns the TSC cycles elapsed since the current run started.
+    fn elapsed(&self) -> u64 {
+        rdtsc().wrapping_sub(self.started_at)
+    }
+
+    /// Returns whether the sandbox is currently running.
+    pub fn is_running(&self) -> bool {
+        self.saved.is_some()
+    }
+
+    /// Returns the report of the last completed run.
+    pub fn report(&self) -> Option<&ExecutionReport> {
+        match self.is_running() {
+            true => None,
+            false => self.report.as_ref(),
+        }
+    }
+
+    /// Copies the code blob into the code pages, padding the rest with INT3.
+    fn copy_code(&mut self, code: &[u8]) {
+        for (index, page) in self.memory.code.iter_mut().enumerate() {
+            page.fill(0xCC);
+
+            let start = index * BASE_PAGE_SIZE;
+            let chunk = code
+                .get(start..)
+                .and_then(|rest| rest.get(..rest.len().min(BASE_PAGE_SIZE)));
+
+            if let Some(chunk) = chunk {
+                if let Some(dst) = page.get_mut(..chunk.len()) {
+                    dst.copy_from_slice(chunk);
+                }
+            }
+        }
+    }
+
+    /// Builds the guest page tables mapping the code at `SANDBOX_CODE_VA` and the stack at `SANDBOX_STACK_VA`.
+    fn build_guest_tables(&mut self) -> Result<(), HypervisorError> {
+        let table_pa = |memory: &SandboxMemory, index: usize| -> Result<u64, HypervisorError> {
+            let table = memory
+                .guest_tables
+                .get(index)
+                .ok_or(HypervisorError::SandboxTablesExhausted)?;
+            Ok(PhysicalAddress::pa_from_va(table.as_ptr() as u64))
+        };
+
+        let pdpt_pa = table_pa(&self.memory, 1)?;
+        let pd_pa = table_pa(&self.memory, 2)?;
+        let pt_pa = table_pa(&self.memory, 3)?;
+
+        let code_pas = self
+            .memory
+            .code
+            .each_ref()
+            .map(|page| PhysicalAddress::pa_from_va(page.as_ptr() as u64));
+        let stack_pas = self
+            .memory
+            .stack
+            .each_ref()
+            .map(|page| PhysicalAddress::pa_from_va(page.as_ptr() as u64));
+
+        let [pml4, pdpt, pd, pt] = &mut self.memory.guest_tables;
+
+        // All sandbox addresses lie within the first PML4 and PDPT entries and a single PD entry.
+        let pd_index = (SANDBOX_CODE_VA >> 21) as usize & 0x1FF;
+        *pml4
+            .get_mut(0)
+            .ok_or(HypervisorError::SandboxTablesExhausted)? =
+            pdpt_pa | PAGE_PRESENT_WRITABLE | PAGE_USER;
+        *pdpt
+            .get_mut(0)
+            .ok_or(HypervisorError::SandboxTablesExhausted)? =
+            pd_pa | PAGE_PRESENT_WRITABLE | PAGE_USER;
+        *pd.get_mut(pd_index)
+            .ok_or(HypervisorError::SandboxTablesExhausted)? =
+            pt_pa | PAGE_PRESENT_WRITABLE | PAGE_USER;
+
+        let pt_index = |va: u64| (va >> 12) as usize & 0x1FF;
+
+        for (i, pa) in code_pas.into_iter().enumerate() {
+            let va = SANDBOX_CODE_VA + (i * BASE_PAGE_SIZE) as u64;
+            *pt.get_mut(pt_index(va))
+                .ok_or(HypervisorError::SandboxTablesExhausted)? =
+                pa | PAGE_PRESENT_WRITABLE | PAGE_USER;
+        }
+
+        for (i, pa) in stack_pas.into_iter().enumerate() {
+            let va = SANDBOX_STACK_VA + (i * BASE_PAGE_SIZE) as u64;
+            *pt.get_mut(pt_index(va))
+                .ok_or(HypervisorError::SandboxTablesExhausted)? =
+                pa | PAGE_PRESENT_WRITABLE | PAGE_USER | PAGE_EXECUTE_DISABLE;
+        }
+
+        Ok(())
+    }
+
+    /// Builds the isolated EPT view, mapping nothing but the sandbox pages.
+    fn build_ept(&mut self) -> Result<(), HypervisorError> {
+        let code_pas = self
+            .memory
+            .code
+            .each_ref()
+            .map(|page| PhysicalAddress::pa_from_va(page.as_ptr() as u64));
+        let stack_pas = self
+            .memory
+            .stack
+            .each_ref()
+            .map(|page| PhysicalAddress::pa_from_va(page.as_ptr() as u64));
+        let table_pas = self
+            .memory
+            .guest_tables
+            .each_ref()
+            .map(|table| PhysicalAddress::pa_from_va(table.as_ptr() as u64));
+
+        for pa in code_pas {
+            self.map_ept(pa, AccessType::READ_EXECUTE)?;
+        }
+
+        for pa in stack_pas {
+            self.map_ept(pa, AccessType::READ_WRITE)?;
+        }
+
+        // The processor writes the accessed and dirty flags of the guest paging entries.
+        for pa in table_pas {
+            self.map_ept(pa, AccessType::READ_WRITE)?;
+        }
+
+        let pml4_pa = self.ept_table_pa(0)?;
+        self.eptp = pml4_pa | EPTP_PAGE_WALK_LENGTH_4 | MemoryType::WriteBack as u64;
+
+        Ok(())
+    }
+
+    /// Maps a single 4KB page in the sandbox EPT view with an identity mapping.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to map.
+    /// * `access_type` - The permissions of the page.
+    fn map_ept(&mut self, guest_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
+        let indexes = [
+            (guest_pa >> 39) as usize & 0x1FF,
+            (guest_pa >> 30) as usize & 0x1FF,
+            (guest_pa >> 21) as usize & 0x1FF,
+        ];
+
+        let mut table = 0;
+
+        // Walk the PML4, PDPT and PD, allocating the next level from the pool where it is missing.
+        for index in indexes {
+            let entry = *self.ept_entry_mut(table, index)?;
+
+            table = if entry.readable() {
+                self.ept_table_index(entry.pfn() << 12)?
+            } else {
+                let next = self.ept_tables_used;
+                let next_pa = self.ept_table_pa(next)?;
+                self.ept_tables_used += 1;
+
+                let entry = self.ept_entry_mut(table, index)?;
+                entry.set_readable(true);
+                entry.set_writable(true);
+                entry.set_executable(true);
+                entry.set_pfn(next_pa >> 12);
+                next
+            };
+        }
+
+        let entry = self.ept_entry_mut(table, (guest_pa >> 12) as usize & 0x1FF)?;
+        entry.set_readable(access_type.contains(AccessType::READ));
+        entry.set_writable(access_type.contains(AccessType::WRITE));
+        entry.set_executable(access_type.contains(AccessType::EXECUTE));
+        entry.set_memory_type(MemoryType::WriteBack as u64);
+        entry.set_pfn(guest_pa >> 12);
+
+        Ok(())
+    }
+
+    /// Returns an entry of an EPT table of the pool.
+    fn ept_entry_mut(&mut self, table: usize, index: usize) -> Result<&mut Entry, HypervisorError> {
+        self.memory
+            .ept_tables
+            .get_mut(table)
+            .and_then(|table| table.get_mut(index))
+            .ok_or(HypervisorError::SandboxTablesExhausted)
+    }
+
+    /// Returns the physical address of an EPT table of the pool.
+    fn ept_table_pa(&self, table: usize) -> Result<u64, HypervisorError> {
+        let table = self
+            .memory
+            .ept_tables
+            .get(table)
+            .ok_or(HypervisorError::SandboxTablesExhausted)?;
+
+        Ok(PhysicalAddress::pa_from_va(table.as_ptr() as u64))
+    }
+
+    /// Returns the pool index of the EPT table at the given physical address.
+    fn ept_table_index(&self, pa: u64) -> Result<usize, HypervisorError> {
+        (0..self.ept_tables_used)
+            .find(|&table| {
+                self.ept_table_pa(table)
+                    .is_ok_and(|table_pa| table_pa == pa)
+            })
+            .ok_or(HypervisorError::SandboxTablesExhausted)
+    }
+
+    /// Records an executed instruction.
+    fn record_instruction(&mut self, rip: u64) {
+        if let Some(report) = self.report.as_mut() {
+            if let Some(slot) = report.trace.get_mut(report.trace_len) {
+                *slot = rip;
+                report.trace_len += 1;
+            }
+            report.instructions += 1;
+        }
+    }
+}
+
+impl Drop for Sandbox {
+    /// Releases the memory charged for the sandbox.
+    fn drop(&mut self) {
+        footprint::release(MemoryCategory::Ept, size_of::<SandboxMemory>() as u64);
+    }
+}
+
+/// Starts a run of the sandbox attached to `vmx` on the current processor.
+///
+/// The guest continues in the sandbox, in user mode, after the VM entry. When the run ends, the guest state is
+/// restored and execution resumes at `resume_rip`. No event may be pending, as it would be injected into the
+/// sandbox.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest's register state.
+/// * `vmx` - The VMX instance of the current processor, with the sandbox attached.
+/// * `resume_rip` - The guest instruction pointer to resume at after the run.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - The sandbox is entered on the next VM entry.
+/// * `Err(HypervisorError::SandboxUnavailable)` - If no sandbox is attached or it is already running.
+pub fn detonate(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+    resume_rip: u64,
+) -> Result<ExitType, HypervisorError> {
+    let Some(sandbox) = vmx.sandbox.as_mut().filter(|sandbox| !sandbox.is_running()) else {
+        return Err(HypervisorError::SandboxUnavailable);
+    };
+
+    log::debug!(
+        "Detonating sandbox, resuming at {:#x} afterwards",
+        resume_rip
+    );
+
+    let procbased_controls = try_vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS)?;
+    let pinbased_controls = try_vmread(vmcs::control::PINBASED_EXEC_CONTROLS)?;
+    let exit_controls = try_vmread(vmcs::control::VMEXIT_CONTROLS)?;
+    let interruptibility = try_vmread(guest::INTERRUPTIBILITY_STATE)?;
+    let cr0 = try_vmread(guest::CR0)?;
+    let cr4 = try_vmread(guest::CR4)?;
+
+    let mut data_segments = [[0u64; 4]; 4];
+    for (saved, fields) in data_segments.iter_mut().zip(DATA_SEGMENTS) {
+        for (value, field) in saved.iter_mut().zip(fields) {
+            *value = try_vmread(field)?;
+        }
+    }
+
+    sandbox.saved = Some(SavedState {
+        registers: *guest_registers,
+        rip: resume_rip,
+        rsp: try_vmread(guest::RSP)?,
+        rflags: try_vmread(guest::RFLAGS)?,
+        cr3: try_vmread(guest::CR3)?,
+        cs_selector: try_vmread(guest::CS_SELECTOR)?,
+        cs_limit: try_vmread(guest::CS_LIMIT)?,
+        cs_access_rights: try_vmread(guest::CS_ACCESS_RIGHTS)?,
+        ss_selector: try_vmread(guest::SS_SELECTOR)?,
+        ss_limit: try_vmread(guest::SS_LIMIT)?,
+        ss_access_rights: try_vmread(guest::SS_ACCESS_RIGHTS)?,
+        data_segments,
+        cr0,
+        cr4,
+        interruptibility,
+        eptp: try_vmread(vmcs::control::EPTP_FULL)?,
+        exception_bitmap: try_vmread(vmcs::control::EXCEPTION_BITMAP)?,
+        pfec_mask: try_vmread(vmcs::control::PAGE_FAULT_ERR_CODE_MASK)?,
+        pfec_match: try_vmread(vmcs::control::PAGE_FAULT_ERR_CODE_MATCH)?,
+        procbased_controls,
+        pinbased_controls,
+        exit_controls,
+        preemption_timer_value: try_vmread(guest::VMX_PREEMPTION_TIMER_VALUE)?,
+    });
+
+    sandbox.report = Some(ExecutionReport {
+        exit: SandboxExit::InstructionLimit,
+        instructions: 0,
+        cycles: 0,
+        final_rip: SANDBOX_CODE_VA,
+        final_registers: GuestRegisters::default(),
+        trace: [0; TRACE_LEN],
+        trace_len: 0,
+    });
+
+    // The blob returns to an unmapped address, which ends the run with a page fault.
+    let stack_top =
+        SANDBOX_STACK_VA + (STACK_PAGES * BASE_PAGE_SIZE) as u64 - size_of::<u64>() as u64;
+    if let Some(last_page) = sandbox.memory.stack.last_mut() {
+        if let Some(slot) = last_page.get_mut(BASE_PAGE_SIZE - size_of::<u64>()..) {
+            slot.copy_from_slice(&SANDBOX_RETURN_VA.to_le_bytes());
+        }
+    }
+
+    *guest_registers = GuestRegisters::default();
+    guest_registers.rip = SANDBOX_CODE_VA;
+    guest_registers.rsp = stack_top;
+    guest_registers.rflags = RFLAGS_RESERVED;
+
+    let guest_cr3 = PhysicalAddress::pa_from_va(sandbox.memory.guest_tables.as_ptr() as u64);
+
+    try_vmwrite(guest::RIP, guest_registers.rip)?;
+    try_vmwrite(guest::RSP, guest_registers.rsp)?;
+    try_vmwrite(guest::RFLAGS, guest_registers.rflags)?;
+    try_vmwrite(guest::CR3, guest_cr3)?;
+    try_vmwrite(vmcs::control::EPTP_FULL, sandbox.eptp)?;
+
+    // The blob runs at CPL 3, which is the DPL of SS, with interrupts disabled.
+    try_vmwrite(guest::CS_SELECTOR, USER_CODE_SELECTOR)?;
+    try_vmwrite(guest::CS_LIMIT, FLAT_LIMIT)?;
+    try_vmwrite(guest::CS_ACCESS_RIGHTS, USER_CODE_ACCESS_RIGHTS)?;
+    try_vmwrite(guest::SS_SELECTOR, USER_DATA_SELECTOR)?;
+    try_vmwrite(guest::SS_LIMIT, FLAT_LIMIT)?;
+    try_vmwrite(guest::SS_ACCESS_RIGHTS, USER_DATA_ACCESS_RIGHTS)?;
+
+    // The x87, SSE and AVX state, the FS and GS bases and PKRU stay the guest's, the instructions changing them
+    // fault instead.
+    try_vmwrite(guest::CR0, cr0 | Cr0::CR0_EMULATE_COPROCESSOR.bits() as u64)?;
+    try_vmwrite(guest::CR4, cr4 & !(CR4_DENIED.bits() as u64))?;
+
+    try_vmwrite(
+        guest::INTERRUPTIBILITY_STATE,
+        interruptibility & !BLOCKING_BY_STI_OR_MOV_SS,
+    )?;
+
+    // Intercept every exception, including all page faults, and single-step the blob.
+    try_vmwrite(vmcs::control::EXCEPTION_BITMAP, u32::MAX as u64)?;
+    try_vmwrite(vmcs::control::PAGE_FAULT_ERR_CODE_MASK, 0u64)?;
+    try_vmwrite(vmcs::control::PAGE_FAULT_ERR_CODE_MATCH, 0u64)?;
+    try_vmwrite(
+        vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+        procbased_controls | PrimaryControls::MONITOR_TRAP_FLAG.bits() as u64,
+    )?;
+
+    // The timer of the run replaces the one set with `Vcpu::set_preemption_timer`, restored by `finish`.
+    if cpu::has_preemption_timer() {
+        let ticks = sandbox.config.max_cycles >> cpu::preemption_timer_rate();
+        try_vmwrite(
+            guest::VMX_PREEMPTION_TIMER_VALUE,
+            ticks.min(u32::MAX as u64),
+        )?;
+        try_vmwrite(
+            vmcs::control::PINBASED_EXEC_CONTROLS,
+            pinbased_controls | PinbasedControls::VMX_PREEMPTION_TIMER.bits() as u64,
+        )?;
+        try_vmwrite(
+            vmcs::control::VMEXIT_CONTROLS,
+            exit_controls | ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits() as u64,
+        )?;
+    }
+
+    invept_single_context(sandbox.eptp);
+    invvpid_single_context(VPID_TAG);
+
+    sandbox.started_at = rdtsc();
+
+    Ok(ExitType::Continue)
+}
+
+/// Handles a VM exit while the sandbox of the current processor runs.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - The reason of the VM exit.
+/// * `guest_registers` - The guest's register state.
+/// * `vmx` - The VMX instance of the current processor.
+///
+/// # Returns
+///
+/// `Some(ExitType)` if the exit was consumed by the sandbox, or `None` if no sandbox is running or the
+/// exit is handled as usual.
+pub fn handle_sandbox_exit(
+    basic_exit_reason: VmxBasicExitReason,
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<Option<ExitType>, HypervisorError> {
+    let Some(sandbox) = vmx.sandbox.as_mut().filter(|sandbox| sandbox.is_running()) else {
+        return Ok(None);
+    };
+
+    let mut nmi = false;
+
+    let exit = match basic_exit_reason {
+        VmxBasicExitReason::MonitorTrapFlag => {
+            sandbox.record_instruction(guest_registers.rip);
+
+            let instructions = sandbox
+                .report
+                .as_ref()
+                .map_or(0, |report| report.instructions);
+            if instructions >= sandbox.config.max_instructions {
+                SandboxExit::InstructionLimit
+            } else if sandbox.elapsed() >= sandbox.config.max_cycles {
+                SandboxExit::TimeLimit
+            } else {
+                return Ok(Some(ExitType::Continue));
+            }
+        }
+        VmxBasicExitReason::VmxPreemptionTimerExpired => SandboxExit::TimeLimit,
+        VmxBasicExitReason::EptViolation => SandboxExit::MemoryViolation {
+            guest_pa: try_vmread(ro::GUEST_PHYSICAL_ADDR_FULL)?,
+            qualification: try_vmread(ro::EXIT_QUALIFICATION)?,
+        },
+        VmxBasicExitReason::ExceptionOrNmi => {
+            let info = try_vmread(ro::VMEXIT_INTERRUPTION_INFO)? as u32;
+            let info = VmExitInterruptionInformation::from_u32(info);
+            let vector = info.map_or(u32::MAX, |info| info.vector.into());
+            nmi = info.is_some_and(|info| {
+                info.interruption_type == InterruptionType::NonMaskableInterrupt
+            });
+
+            // The kick of another processor was served at the start of the exit, the run goes on.
+            if nmi && ipi::take_kick() {
+                return Ok(Some(ExitType::Continue));
+            }
+
+            let error_code = try_vmread(ro::VMEXIT_INTERRUPTION_ERR_CODE)? as u32;
+
+            // For page faults, the exit qualification holds the faulting linear address.
+            let returned = vector == ExceptionInterrupt::PageFault as u32
+                && try_vmread(ro::EXIT_QUALIFICATION)? == SANDBOX_RETURN_VA;
+
+            match returned {
+                true => SandboxExit::Completed,
+                false => SandboxExit::Exception { vector, error_code },
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    finish(sandbox, exit, guest_registers)?;
+
+    // The events whose delivery the blob interrupted are queued, see `EventQueue::capture_idt_vectoring`. Its
+    // exceptions and software interrupts are gone with it, while NMIs and interrupts are the guest's.
+    vmx.pending_events.retain_interrupts();
+
+    // An NMI ends the run, and is reflected to the guest once its state is restored.
+    if nmi {
+        vmx.pending_events.push(PendingEvent::nmi())?;
+    }
+
+    Ok(Some(ExitType::Continue))
+}
+
+/// Ends the run and restores the guest state saved by `detonate`.
+fn finish(
+    sandbox: &mut Sandbox,
+    exit: SandboxExit,
+    guest_registers: &mut GuestRegisters,
+) -> Result<(), HypervisorError> {
+    let Some(saved) = sandbox.saved.take() else {
+        return Ok(());
+    };
+
+    let cycles = sandbox.elapsed();
+    if let Some(report) = sandbox.report.as_mut() {
+        report.exit = exit;
+        report.cycles = cycles;
+        report.final_rip = guest_registers.rip;
+        report.final_registers = *guest_registers;
+
+        log::debug!(
+            "Sandbox run ended after {} instructions: {:?}",
+            report.instructions,
+            report.exit
+        );
+    }
+
+    *guest_registers = saved.registers;
+    guest_registers.rip = saved.rip;
+    guest_registers.rsp = saved.rsp;
+    guest_registers.rflags = saved.rflags;
+
+    try_vmwrite(guest::RIP, saved.rip)?;
+    try_vmwrite(guest::RSP, saved.rsp)?;
+    try_vmwrite(guest::RFLAGS, saved.rflags)?;
+    try_vmwrite(guest::CR3, saved.cr3)?;
+    try_vmwrite(guest::CS_SELECTOR, saved.cs_selector)?;
+    try_vmwrite(guest::CS_LIMIT, saved.cs_limit)?;
+    try_vmwrite(guest::CS_ACCESS_RIGHTS, saved.cs_access_rights)?;
+    try_vmwrite(guest::SS_SELECTOR, saved.ss_selector)?;
+    try_vmwrite(guest::SS_LIMIT, saved.ss_limit)?;
+    try_vmwrite(guest::SS_ACCESS_RIGHTS, saved.ss_access_rights)?;
+    for (values, fields) in saved.data_segments.iter().zip(DATA_SEGMENTS) {
+        for (&value, field) in values.iter().zip(fields) {
+            try_vmwrite(field, value)?;
+        }
+    }
+    try_vmwrite(guest::CR0, saved.cr0)?;
+    try_vmwrite(guest::CR4, saved.cr4)?;
+    try_vmwrite(guest::INTERRUPTIBILITY_STATE, saved.interruptibility)?;
+    try_vmwrite(vmcs::control::EPTP_FULL, saved.eptp)?;
+    try_vmwrite(vmcs::control::EXCEPTION_BITMAP, saved.exception_bitmap)?;
+    try_vmwrite(vmcs::control::PAGE_FAULT_ERR_CODE_MASK, saved.pfec_mask)?;
+    try_vmwrite(vmcs::control::PAGE_FAULT_ERR_CODE_MATCH, saved.pfec_match)?;
+
+    // The event queue enables and disables window exiting during the run, so only the controls set by `detonate`
+    // are restored.
+    restore_controls(
+        vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+        saved.procbased_controls,
+        PrimaryControls::MONITOR_TRAP_FLAG.bits() as u64,
+    )?;
+    restore_controls(
+        vmcs::control::PINBASED_EXEC_CONTROLS,
+        saved.pinbased_controls,
+        PinbasedControls::VMX_PREEMPTION_TIMER.bits() as u64,
+    )?;
+    restore_controls(
+        vmcs::control::VMEXIT_CONTROLS,
+        saved.exit_controls,
+        ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits() as u64,
+    )?;
+    try_vmwrite(
+        guest::VMX_PREEMPTION_TIMER_VALUE,
+        saved.preemption_timer_value,
+    )?;
+
+    // Drop the translations of the sandbox address space and view.
+    invept_all_contexts();
+    invvpid_single_context(VPID_TAG);
+
+    Ok(())
+}
+
+/// Restores some bits of a VMCS control field, keeping the others as they are.
+///
+/// # Arguments
+///
+/// * `field` - The control field.
+/// * `saved` - The value of the field saved by `detonate`.
+/// * `bits` - The bits to restore.
+fn restore_controls(field: u32, saved: u64, bits: u64) -> Result<(), HypervisorError> {
+    let current = try_vmread(field)?;
+    try_vmwrite(field, (current & !bits) | (saved & bits))
+}
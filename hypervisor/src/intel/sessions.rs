@@ -0,0 +1,315 @@
+//! Control client sessions of the hypercall interface.
+//!
+//! Several control clients can be attached at once, e.g. an administration tool alongside monitoring
+//! dashboards. A client opens a session with the `SessionOpen` hypercall and passes the returned token in R8
+//! with every other hypercall. The VMCALL dispatcher checks the session before serving a hypercall:
+//! - Admin sessions can use all hypercalls. Opening one requires the 128-bit admin key configured with
+//!   `HypervisorBuilder::client_sessions`, and only one admin session can be open at a time.
+//! - Observer sessions are restricted to the read-only hypercalls, such as reading the coverage bitmap, and
+//!   need no key. They can take all slots but one, so the admin can always open a session.
+//!
+//! Public hypercalls, i.e. identification, session management and the guest agent protocol, which is
+//! authenticated on its own, need no session. Unless client sessions are configured, no hypercall needs a
+//! session.
+//!
+//! Every session slot has a `CancellationToken`, armed by the long-running hypercalls of the session. Closing
+//! the session, including with `close_all` when the client died, cancels them, so the processors serving them
+//! return to the guest promptly.
+
+use {
+    crate::{
+        intel::hypercall::{HypercallAccess, HypercallStatus},
+        utils::{
+            cancellation::{CancelReason, CancellationToken},
+            sync::SpinLock,
+            timestamp::Timestamp,
+        },
+    },
+    alloc::vec::Vec,
+};
+
+#[cfg(feature = "introspection")]
+use crate::intel::{
+    capabilities::{Capability, Subsystem},
+    hypercall::HypercallCode,
+};
+
+/// The hypercalls of the client sessions, see `intel::capabilities`.
+#[cfg(feature = "introspection")]
+pub const CAPABILITIES: Subsystem = Subsystem {
+    name: "sessions",
+    enabled: |shared_data| shared_data.client_sessions.is_enabled(),
+    commands: &[
+        Capability {
+            code: HypercallCode::SessionOpen,
+            summary: "Opens an admin or observer client session",
+        },
+        Capability {
+            code: HypercallCode::SessionClose,
+            summary: "Closes the client session",
+        },
+    ],
+};
+
+/// The maximum number of sessions open at once.
+pub const MAX_SESSIONS: usize = 8;
+
+/// The maximum number of observer sessions open at once. The remaining slot is kept for the admin session.
+pub const MAX_OBSERVER_SESSIONS: usize = MAX_SESSIONS - 1;
+
+/// What a client is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum ClientRole {
+    /// Read-only access.
+    Observer = 0,
+
+    /// Full access.
+    Admin = 1,
+}
+
+impl ClientRole {
+    /// Decodes a role, or returns `None` if it is unknown.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(Self::Observer),
+            1 => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// An open session.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientSession {
+    /// The token identifying the session, handed to the client only.
+    token: u64,
+
+    /// What the client is allowed to do.
+    pub role: ClientRole,
+
+    /// When the session was opened.
+    pub opened: Timestamp,
+
+    /// The number of hypercalls served for the session.
+    pub hypercalls: u64,
+}
+
+/// The control client sessions.
+pub struct ClientSessions {
+    /// Whether hypercalls require a session.
+    enabled: bool,
+
+    /// The key required to open an admin session.
+    admin_key: [u64; 2],
+
+    /// The open sessions.
+    sessions: SpinLock<[Option<ClientSession>; MAX_SESSIONS]>,
+
+    /// The cancellation of the operations of the session in the slot of the same index.
+    operations: [CancellationToken; MAX_SESSIONS],
+}
+
+impl ClientSessions {
+    /// Creates the sessions.
+    ///
+    /// # Arguments
+    ///
+    /// * `admin_key` - The key required to open an admin session, or `None` if hypercalls require no session.
+    pub fn new(admin_key: Option<[u64; 2]>) -> Self {
+        Self {
+            enabled: admin_key.is_some(),
+            admin_key: admin_key.unwrap_or_default(),
+            sessions: SpinLock::new("client_sessions", [None; MAX_SESSIONS]),
+            operations: {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const IDLE: CancellationToken = CancellationToken::new();
+                [IDLE; MAX_SESSIONS]
+            },
+        }
+    }
+
+    /// Returns whether hypercalls require a session.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Opens a session.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - The role of the client.
+    /// * `key` - The admin key, ignored for observers.
+    /// * `token` - The token of the session, non-zero and drawn from the random number generator of the processor,
+    ///   see `Vmx::rng`.
+    ///
+    /// # Returns
+    ///
+    /// The token of the session, or the status of the refusal:
+    /// - `HypercallStatus::NotSupported` if client sessions are not configured.
+    /// - `HypercallStatus::AccessDenied` if the admin key is wrong or an admin session is open already.
+    /// - `HypercallStatus::InsufficientResources` if `MAX_SESSIONS` sessions, or `MAX_OBSERVER_SESSIONS`
+    ///   observer sessions, are open already.
+    pub fn open(
+        &self,
+        role: ClientRole,
+        key: [u64; 2],
+        token: u64,
+    ) -> Result<u64, HypercallStatus> {
+        if !self.enabled {
+            return Err(HypercallStatus::NotSupported);
+        }
+
+        // Compared without an early exit, so the time taken does not reveal how much of the key matched.
+        let ([k0, k1], [key0, key1]) = (self.admin_key, key);
+        if role == ClientRole::Admin && ((key0 ^ k0) | (key1 ^ k1)) != 0 {
+            log::warn!("Refusing an admin session with a wrong key");
+            return Err(HypercallStatus::AccessDenied);
+        }
+
+        let mut sessions = self.sessions.lock();
+        let open = sessions
+            .iter()
+            .flatten()
+            .filter(|session| session.role == role)
+            .count();
+
+        match role {
+            ClientRole::Admin if open != 0 => return Err(HypercallStatus::AccessDenied),
+            ClientRole::Observer if open >= MAX_OBSERVER_SESSIONS => {
+                return Err(HypercallStatus::InsufficientResources)
+            }
+            _ => {}
+        }
+
+        let Some(slot) = sessions.iter_mut().find(|slot| slot.is_none()) else {
+            return Err(HypercallStatus::InsufficientResources);
+        };
+
+        *slot = Some(ClientSession {
+            token,
+            role,
+            opened: Timestamp::now(),
+            hypercalls: 0,
+        });
+
+        log::info!("Opened {:?} client session", role);
+
+        Ok(token)
+    }
+
+    /// Closes a session.
+    ///
+    /// # Returns
+    ///
+    /// `HypercallStatus::InvalidParameter` if no session has the token.
+    pub fn close(&self, token: u64) -> Result<(), HypercallStatus> {
+        let mut sessions = self.sessions.lock();
+
+        let Some(index) = sessions
+            .iter()
+            .position(|slot| slot.is_some_and(|session| session.token == token))
+        else {
+            return Err(HypercallStatus::InvalidParameter);
+        };
+
+        if let Some(session) = sessions.get_mut(index).and_then(Option::take) {
+            log::info!("Closed {:?} client session", session.role);
+        }
+
+        if let Some(operation) = self.operations.get(index) {
+            operation.cancel(CancelReason::Disconnected);
+        }
+
+        Ok(())
+    }
+
+    /// Closes all sessions, e.g. when a client died without closing its session.
+    ///
+    /// # Returns
+    ///
+    /// The number of sessions closed.
+    pub fn close_all(&self) -> usize {
+        self.sessions
+            .lock()
+            .iter_mut()
+            .zip(self.operations.iter())
+            .filter_map(|(slot, operation)| {
+                let session = slot.take()?;
+                operation.cancel(CancelReason::Disconnected);
+                Some(session)
+            })
+            .count()
+    }
+
+    /// Arms the cancellation token of a session for a long-running hypercall.
+    ///
+    /// A session running operations on several processors at once shares the token, and the operation started
+    /// last sets the deadline of all of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token passed by the client.
+    /// * `timeout` - The time the operation may run, in TSC ticks, or zero for no deadline.
+    ///
+    /// # Returns
+    ///
+    /// The armed cancellation token, cancelled when the session is closed, or `None` if hypercalls require no
+    /// session, in which case the caller arms a token of its own.
+    pub fn begin_operation(&self, token: u64, timeout: u64) -> Option<&CancellationToken> {
+        if !self.enabled {
+            return None;
+        }
+
+        // Armed with the lock held, so a concurrent close cannot be lost.
+        let sessions = self.sessions.lock();
+        let index = sessions
+            .iter()
+            .position(|slot| slot.is_some_and(|session| session.token == token))?;
+
+        let operation = self.operations.get(index)?;
+        operation.arm(timeout);
+
+        Some(operation)
+    }
+
+    /// Checks that a session allows a hypercall, and counts the hypercall.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token passed by the client.
+    /// * `access` - The access the hypercall requires.
+    ///
+    /// # Returns
+    ///
+    /// `HypercallStatus::AccessDenied` if the hypercall is not allowed.
+    pub fn authorize(&self, token: u64, access: HypercallAccess) -> Result<(), HypercallStatus> {
+        if !self.enabled || access == HypercallAccess::Public {
+            return Ok(());
+        }
+
+        let mut sessions = self.sessions.lock();
+
+        let Some(session) = sessions
+            .iter_mut()
+            .flatten()
+            .find(|session| session.token == token)
+        else {
+            return Err(HypercallStatus::AccessDenied);
+        };
+
+        if access == HypercallAccess::Control && session.role != ClientRole::Admin {
+            log::warn!("Refusing a control hypercall to an observer session");
+            return Err(HypercallStatus::AccessDenied);
+        }
+
+        session.hypercalls += 1;
+
+        Ok(())
+    }
+
+    /// Returns the open sessions.
+    pub fn sessions(&self) -> Vec<ClientSession> {
+        self.sessions.lock().iter().flatten().copied().collect()
+    }
+}
@@ -6,14 +6,73 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            ept::{hooks::HookManager, paging::Ept},
+            agent_monitor::AgentMonitor,
+            controls::{EnclsExitingBitmap, PauseLoopConfig, VmcsControls},
+            cpu_set::CpuSet,
+            debug_registers::{HostBreakpoint, BREAKPOINT_SLOTS},
+            debugger::DebuggerMonitor,
+            driver_blocker::DriverBlocker,
+            entry_recovery::DEFAULT_ENTRY_RETRIES,
+            ept::{
+                hooks::{HookManager, HookTable},
+                mtrr::MemoryType,
+                paging::{AccessType, Ept, EPTP_ACCESSED_DIRTY_ENABLE},
+                policy::EptPolicy,
+                thrashing::{ThrashGuard, ThrashPolicy},
+            },
+            io_bitmap::IoBitmap,
+            lbr::LbrStack,
             msr_bitmap::MsrBitmap,
+            msr_policy::MsrPolicies,
+            paravirt::ParavirtInterface,
+            platform::{CoreCapabilities, PlatformInfo},
+            rate_limit::RateLimitPolicy,
+            sessions::ClientSessions,
+            smm::SmmMonitor,
+            topology::TopologyConfig,
+            tsc::TscConfig,
+            tsx::Tsx,
+            vmexit::{
+                cpuid::CpuidMasking, cr::Cr3Observer, ept::EptViolationCallback, hlt::HltCallback,
+                invd::CacheFlushPolicy, mwait::MwaitPolicy, triple_fault::TripleFaultPolicy,
+                vmx_instruction::VmxInstructionResponse,
+            },
+        },
+        utils::{
+            addresses::Gpa,
+            alloc::PhysicalAllocator,
+            cancellation::DEFAULT_ROOT_OPERATION_TIMEOUT,
+            chacha::ChaChaRng,
+            footprint::{self, MemoryCategory},
+            processor::{current_processor_index, MAX_VCPUS},
+            rcu::Rcu,
+            sync::{RwLock, SpinLock},
         },
-        utils::alloc::PhysicalAllocator,
     },
-    alloc::boxed::Box,
+    alloc::{boxed::Box, vec::Vec},
+    core::{mem::size_of, ops::Range},
 };
 
+#[cfg(feature = "devices")]
+use crate::intel::io_monitor::IoMonitor;
+#[cfg(feature = "devices")]
+use crate::intel::keyboard_guard::{KeyboardGuard, KeyboardProtection};
+
+#[cfg(feature = "introspection")]
+use crate::intel::coverage::CoverageMap;
+#[cfg(feature = "introspection")]
+use crate::intel::effective_config::ConfigSnapshot;
+#[cfg(feature = "introspection")]
+use crate::intel::fault_injection::FaultInjector;
+#[cfg(feature = "introspection")]
+use crate::intel::heap_poison::HeapPoison;
+#[cfg(feature = "introspection")]
+use crate::intel::heat_map::ExitHeatMap;
+#[cfg(feature = "introspection")]
+use crate::intel::metrics::ExitMetrics;
+#[cfg(feature = "introspection")]
+use crate::intel::processes::ProcessControl;
+
 /// Represents shared data structures for hypervisor operations.
 ///
 /// This struct manages the MSR (Model-Specific Register) bitmap and Extended Page Tables (EPT)
@@ -23,12 +82,18 @@ pub struct SharedData {
     /// A bitmap for handling MSRs.
     pub msr_bitmap: Box<MsrBitmap, PhysicalAllocator>,
 
+    /// The bitmaps for handling I/O ports.
+    pub io_bitmap: Box<IoBitmap, PhysicalAllocator>,
+
     /// The primary Extended Page Table.
     pub primary_ept: Box<Ept, PhysicalAllocator>,
 
     /// The pointer to the primary EPT (Extended Page Table Pointer).
     pub primary_eptp: u64,
 
+    /// Whether the processor maintains the accessed and dirty flags in the EPT entries.
+    pub ept_accessed_dirty: bool,
+
     /// The secondary Extended Page Table.
     #[cfg(feature = "secondary-ept")]
     pub secondary_ept: Box<Ept, PhysicalAllocator>,
@@ -37,8 +102,193 @@ pub struct SharedData {
     #[cfg(feature = "secondary-ept")]
     pub secondary_eptp: u64,
 
-    /// The hook manager.
-    pub hook_manager: Box<HookManager>,
+    /// The hook manager, locked by the updates of the hooks.
+    pub hook_manager: RwLock<Box<HookManager>>,
+
+    /// The function hooks looked up by the `#BP` exit handlers, republished on every update of the hooks.
+    pub hook_table: Rcu<HookTable>,
+
+    /// The platform MSRs and CPUID leaves captured at initialization.
+    pub platform_info: PlatformInfo,
+
+    /// The capabilities of each core, indexed by processor index, captured as the core is virtualized.
+    pub core_capabilities: SpinLock<[Option<CoreCapabilities>; MAX_VCPUS]>,
+
+    /// The paravirtual interface exposed to cooperative guests.
+    pub paravirt: ParavirtInterface,
+
+    /// Watches for guest debugging sessions and tracks whether the hooks are suspended.
+    pub debugger: DebuggerMonitor,
+
+    /// The quotas of the guest-triggerable exits, enforced by each processor.
+    pub rate_limits: RateLimitPolicy,
+
+    /// Checks the accesses to the keyboard controller when keyboard protection is enabled.
+    #[cfg(feature = "devices")]
+    pub keyboard_guard: KeyboardGuard,
+
+    /// Records the accesses to the monitored I/O ports.
+    #[cfg(feature = "devices")]
+    pub io_monitor: IoMonitor,
+
+    /// The VM exits per guest module, when enabled.
+    #[cfg(feature = "introspection")]
+    pub heat_map: ExitHeatMap,
+
+    /// The VM exit counters exported as metrics.
+    #[cfg(feature = "introspection")]
+    pub metrics: ExitMetrics,
+
+    /// The last snapshot of the effective configuration, read part by part.
+    #[cfg(feature = "introspection")]
+    pub config_snapshot: ConfigSnapshot,
+
+    /// Tracks the liveness and integrity of the guest agent.
+    pub agent_monitor: AgentMonitor,
+
+    /// The guest physical regions protected by permission profiles.
+    pub ept_policy: EptPolicy,
+
+    /// Blocks the execution of deny-listed guest drivers.
+    pub driver_blocker: DriverBlocker,
+
+    /// The code coverage of the fuzzing target, when enabled.
+    #[cfg(feature = "introspection")]
+    pub coverage: CoverageMap,
+
+    /// Injects the faults requested by a guest test controller, when enabled.
+    #[cfg(feature = "introspection")]
+    pub fault_injector: FaultInjector,
+
+    /// The guest memory poisoned by a driver under test, when enabled, see `intel::heap_poison`.
+    #[cfg(feature = "introspection")]
+    pub heap_poison: HeapPoison,
+
+    /// Lists the guest processes and queues the ones to terminate, when enabled.
+    #[cfg(feature = "introspection")]
+    pub processes: ProcessControl,
+
+    /// The control client sessions, checked by the hypercall dispatcher.
+    pub client_sessions: ClientSessions,
+
+    /// The configuration of the thrashing detection of hooked pages, or `None` if it is disabled.
+    pub thrash_policy: Option<ThrashPolicy>,
+
+    /// The hooks disabled because their page was thrashing.
+    pub thrash_guard: ThrashGuard,
+
+    /// The number of failed VM entries retried in a row before a processor is devirtualized.
+    pub entry_failure_retries: u32,
+
+    /// The LBR stack virtualized on every processor, or `None` if it is shared with root mode.
+    pub lbr_stack: Option<LbrStack>,
+
+    /// The TSX support of the processor and what the guest sees of it.
+    pub tsx: Tsx,
+
+    /// The topology reported to the guest through CPUID, or `None` to report the one of the processor, see
+    /// `intel::topology`.
+    pub cpuid_topology: Option<TopologyConfig>,
+
+    /// The policies applied to the RDMSR and WRMSR exits of the guest on processors virtualized from now on, see
+    /// `Vcpu::set_msr_policy` for those already virtualized.
+    pub msr_policies: MsrPolicies,
+
+    /// What CPUID hides from the guest about the hypervisor on processors virtualized from now on, see
+    /// `Vmx::set_cpuid_masking` for the processors running already.
+    pub cpuid_masking: CpuidMasking,
+
+    /// Receives the CR3 loads of the guest, or `None` if they do not exit.
+    pub cr3_observer: Option<Cr3Observer>,
+
+    /// The time a long-running hypercall may run in VMX root operation, in TSC ticks, or zero for no limit.
+    pub root_operation_timeout: u64,
+
+    /// How the guest reads the TSC on processors virtualized from now on.
+    pub tsc_config: TscConfig,
+
+    /// What the VMX instructions executed by the guest do.
+    pub vmx_instruction_response: VmxInstructionResponse,
+
+    /// The processors virtualized by `Hypervisor::virtualize_core`, see `intel::cpu_set`.
+    pub cpu_set: CpuSet,
+
+    /// The controls written to the VMCS of the processor virtualized last.
+    pub vmcs_controls: VmcsControls,
+
+    /// Implements a custom memory-access policy, or `None` to handle the EPT violations as usual.
+    pub ept_violation_callback: Option<EptViolationCallback>,
+
+    /// Counts and records the SMIs of the processors, see `intel::smm`.
+    pub smm_monitor: SmmMonitor,
+
+    /// Whether INVLPG and INVPCID exit, see `vmexit::invlpg`.
+    pub invlpg_exiting: bool,
+
+    /// Whether the instructions accessing the descriptor-table registers exit, see `vmexit::descriptor_table`.
+    pub descriptor_table_exiting: bool,
+
+    /// Called on every HLT of the guest, or `None` if HLT does not exit, see `vmexit::hlt`.
+    pub hlt_callback: Option<HltCallback>,
+
+    /// What INVD, WBINVD and WBNOINVD of the guest do, see `vmexit::invd`.
+    pub cache_flush_policy: CacheFlushPolicy,
+
+    /// What MONITOR and MWAIT of the guest do, see `vmexit::mwait`.
+    pub mwait_policy: MwaitPolicy,
+
+    /// The fixed seed of the random number generators of the processors, or `None` to seed them from the
+    /// processor, see `utils::chacha`.
+    pub rng_seed: Option<u64>,
+
+    /// The key of the host calls made by the `Hypervisor` API, see `intel::host_call`. Drawn from the processor
+    /// when the hypervisor is built, never from the fixed seed.
+    pub host_call_key: u64,
+
+    /// What happens once a triple fault of the guest was dumped, see `vmexit::triple_fault`.
+    pub triple_fault_policy: TripleFaultPolicy,
+
+    /// The hardware breakpoints owned by the hypervisor, by slot. MOV DR and debug exceptions exit if any is
+    /// set, see `intel::debug_registers`.
+    pub host_breakpoints: [Option<HostBreakpoint>; BREAKPOINT_SLOTS],
+
+    /// The PAUSE-loop exiting window, or `None` if PAUSE does not exit, see `intel::spin_monitor`.
+    pub pause_loop: Option<PauseLoopConfig>,
+
+    /// The ENCLS leaves that exit and are blocked, or `None` if ENCLS does not exit, see `vmexit::encls`.
+    pub encls_exiting: Option<EnclsExitingBitmap>,
+
+    /// Whether the writes to IA32_APIC_BASE exit, see `intel::apic_base`.
+    pub apic_base_tracking: bool,
+
+    /// Whether the x2APIC MSRs are intercepted in x2APIC mode.
+    pub x2apic_interception: bool,
+}
+
+/// The permissions of a 4KB page in the EPTs, see `SharedData::page_access`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageAccess {
+    /// The guest physical address of the page.
+    pub guest_pa: Gpa,
+
+    /// The permissions in the primary EPT.
+    pub primary: AccessType,
+
+    /// The permissions in the secondary EPT.
+    #[cfg(feature = "secondary-ept")]
+    pub secondary: AccessType,
+}
+
+impl PageAccess {
+    /// Returns the permissions with the given ones removed in all EPTs.
+    pub fn without(self, access: AccessType) -> Self {
+        Self {
+            primary: self.primary - access,
+            #[cfg(feature = "secondary-ept")]
+            secondary: self.secondary - access,
+            ..self
+        }
+    }
 }
 
 impl SharedData {
@@ -50,6 +300,10 @@ impl SharedData {
     ///
     /// * `primary_ept`: The primary EPT to be used.
     /// * `secondary_ept`: The secondary EPT to be used if the feature is enabled.
+    /// * `hook_manager`: The hook manager.
+    /// * `platform_info`: The platform snapshot captured at initialization.
+    /// * `paravirt`: The paravirtual interface exposed to cooperative guests.
+    /// * `debugger`: The guest debugger monitor.
     ///
     /// # Returns
     /// A result containing a boxed `SharedData` instance or an error of type `HypervisorError`.
@@ -58,23 +312,101 @@ impl SharedData {
         primary_ept: Box<Ept, PhysicalAllocator>,
         secondary_ept: Box<Ept, PhysicalAllocator>,
         hook_manager: Box<HookManager>,
+        platform_info: PlatformInfo,
+        paravirt: ParavirtInterface,
+        debugger: DebuggerMonitor,
     ) -> Result<Box<Self>, HypervisorError> {
         log::trace!("Initializing shared data");
 
-        let primary_eptp = primary_ept.create_eptp_with_wb_and_4lvl_walk()?;
-        let secondary_eptp = secondary_ept.create_eptp_with_wb_and_4lvl_walk()?;
+        let ept_accessed_dirty = platform_info.has_ept_accessed_dirty();
+        let primary_eptp = Self::with_accessed_dirty(
+            primary_ept.create_eptp_with_wb_and_4lvl_walk()?,
+            ept_accessed_dirty,
+        );
+        let secondary_eptp = Self::with_accessed_dirty(
+            secondary_ept.create_eptp_with_wb_and_4lvl_walk()?,
+            ept_accessed_dirty,
+        );
+
+        let reservation = footprint::reserve_all(Self::footprint())?;
 
         let bitmap = MsrBitmap::new();
         //bitmap.hook_msr(IA32_EFER);
 
-        Ok(Box::new(Self {
+        let io_bitmap = IoBitmap::new()?;
+
+        let shared_data = Box::new(Self {
             msr_bitmap: { bitmap },
+            io_bitmap,
             primary_ept,
             primary_eptp,
+            ept_accessed_dirty,
             secondary_ept,
             secondary_eptp,
-            hook_manager,
-        }))
+            hook_table: Rcu::new(hook_manager.table()),
+            hook_manager: RwLock::new("hook_manager", hook_manager),
+            platform_info,
+            core_capabilities: SpinLock::new("core_capabilities", [None; MAX_VCPUS]),
+            paravirt,
+            debugger,
+            rate_limits: RateLimitPolicy::default(),
+            #[cfg(feature = "devices")]
+            keyboard_guard: KeyboardGuard::new(KeyboardProtection::Disabled, Vec::new()),
+            #[cfg(feature = "devices")]
+            io_monitor: IoMonitor::new(),
+            #[cfg(feature = "introspection")]
+            heat_map: ExitHeatMap::disabled(),
+            #[cfg(feature = "introspection")]
+            metrics: ExitMetrics::new()?,
+            #[cfg(feature = "introspection")]
+            config_snapshot: ConfigSnapshot::new(),
+            agent_monitor: AgentMonitor::new(None),
+            ept_policy: EptPolicy::new(),
+            driver_blocker: DriverBlocker::new(Vec::new()),
+            #[cfg(feature = "introspection")]
+            coverage: CoverageMap::disabled(),
+            #[cfg(feature = "introspection")]
+            fault_injector: FaultInjector::new(false),
+            #[cfg(feature = "introspection")]
+            heap_poison: HeapPoison::new(None),
+            #[cfg(feature = "introspection")]
+            processes: ProcessControl::new(None),
+            client_sessions: ClientSessions::new(None),
+            thrash_policy: None,
+            thrash_guard: ThrashGuard::new(),
+            entry_failure_retries: DEFAULT_ENTRY_RETRIES,
+            lbr_stack: None,
+            tsx: Tsx::default(),
+            cpuid_topology: None,
+            msr_policies: MsrPolicies::new(),
+            cpuid_masking: CpuidMasking::HYPERVISOR_BIT,
+            cr3_observer: None,
+            root_operation_timeout: DEFAULT_ROOT_OPERATION_TIMEOUT,
+            tsc_config: TscConfig::default(),
+            vmx_instruction_response: VmxInstructionResponse::UndefinedOpcode,
+            cpu_set: CpuSet::all(),
+            vmcs_controls: VmcsControls::default(),
+            ept_violation_callback: None,
+            smm_monitor: SmmMonitor::new(false),
+            invlpg_exiting: false,
+            descriptor_table_exiting: false,
+            hlt_callback: None,
+            cache_flush_policy: CacheFlushPolicy::Execute,
+            mwait_policy: MwaitPolicy::Native,
+            rng_seed: None,
+            host_call_key: ChaChaRng::for_processor(None, current_processor_index()).next_u64(),
+            triple_fault_policy: TripleFaultPolicy::BugCheck,
+            host_breakpoints: [None; BREAKPOINT_SLOTS],
+            pause_loop: None,
+            encls_exiting: None,
+            apic_base_tracking: false,
+            x2apic_interception: false,
+        });
+
+        // The charges are released when the shared data is dropped.
+        reservation.keep();
+
+        Ok(shared_data)
     }
 
     /// Creates a new instance of `SharedData` with primary EPTs.
@@ -84,6 +416,10 @@ impl SharedData {
     /// # Arguments
     ///
     /// * `primary_ept`: The primary EPT to be used.
+    /// * `hook_manager`: The hook manager.
+    /// * `platform_info`: The platform snapshot captured at initialization.
+    /// * `paravirt`: The paravirtual interface exposed to cooperative guests.
+    /// * `debugger`: The guest debugger monitor.
     ///
     /// # Returns
     /// A result containing a boxed `SharedData` instance or an error of type `HypervisorError`.
@@ -91,19 +427,314 @@ impl SharedData {
     pub fn new(
         primary_ept: Box<Ept, PhysicalAllocator>,
         hook_manager: Box<HookManager>,
-    ) -> Result<Option<Box<Self>>, HypervisorError> {
+        platform_info: PlatformInfo,
+        paravirt: ParavirtInterface,
+        debugger: DebuggerMonitor,
+    ) -> Result<Box<Self>, HypervisorError> {
         log::trace!("Initializing shared data");
 
-        let primary_eptp = primary_ept.create_eptp_with_wb_and_4lvl_walk()?;
+        let ept_accessed_dirty = platform_info.has_ept_accessed_dirty();
+        let primary_eptp = Self::with_accessed_dirty(
+            primary_ept.create_eptp_with_wb_and_4lvl_walk()?,
+            ept_accessed_dirty,
+        );
+
+        let reservation = footprint::reserve_all(Self::footprint())?;
 
         let bitmap = MsrBitmap::new();
         //bitmap.hook_msr(IA32_EFER);
 
-        Ok(Some(Box::new(Self {
+        let io_bitmap = IoBitmap::new()?;
+
+        let shared_data = Box::new(Self {
             msr_bitmap: { bitmap },
+            io_bitmap,
             primary_ept,
             primary_eptp,
-            hook_manager,
-        })))
+            ept_accessed_dirty,
+            hook_table: Rcu::new(hook_manager.table()),
+            hook_manager: RwLock::new("hook_manager", hook_manager),
+            platform_info,
+            core_capabilities: SpinLock::new("core_capabilities", [None; MAX_VCPUS]),
+            paravirt,
+            debugger,
+            rate_limits: RateLimitPolicy::default(),
+            #[cfg(feature = "devices")]
+            keyboard_guard: KeyboardGuard::new(KeyboardProtection::Disabled, Vec::new()),
+            #[cfg(feature = "devices")]
+            io_monitor: IoMonitor::new(),
+            #[cfg(feature = "introspection")]
+            heat_map: ExitHeatMap::disabled(),
+            #[cfg(feature = "introspection")]
+            metrics: ExitMetrics::new()?,
+            #[cfg(feature = "introspection")]
+            config_snapshot: ConfigSnapshot::new(),
+            agent_monitor: AgentMonitor::new(None),
+            ept_policy: EptPolicy::new(),
+            driver_blocker: DriverBlocker::new(Vec::new()),
+            #[cfg(feature = "introspection")]
+            coverage: CoverageMap::disabled(),
+            #[cfg(feature = "introspection")]
+            fault_injector: FaultInjector::new(false),
+            #[cfg(feature = "introspection")]
+            heap_poison: HeapPoison::new(None),
+            #[cfg(feature = "introspection")]
+            processes: ProcessControl::new(None),
+            client_sessions: ClientSessions::new(None),
+            thrash_policy: None,
+            thrash_guard: ThrashGuard::new(),
+            entry_failure_retries: DEFAULT_ENTRY_RETRIES,
+            lbr_stack: None,
+            tsx: Tsx::default(),
+            cpuid_topology: None,
+            msr_policies: MsrPolicies::new(),
+            cpuid_masking: CpuidMasking::HYPERVISOR_BIT,
+            cr3_observer: None,
+            root_operation_timeout: DEFAULT_ROOT_OPERATION_TIMEOUT,
+            tsc_config: TscConfig::default(),
+            vmx_instruction_response: VmxInstructionResponse::UndefinedOpcode,
+            cpu_set: CpuSet::all(),
+            vmcs_controls: VmcsControls::default(),
+            ept_violation_callback: None,
+            smm_monitor: SmmMonitor::new(false),
+            invlpg_exiting: false,
+            descriptor_table_exiting: false,
+            hlt_callback: None,
+            cache_flush_policy: CacheFlushPolicy::Execute,
+            mwait_policy: MwaitPolicy::Native,
+            rng_seed: None,
+            host_call_key: ChaChaRng::for_processor(None, current_processor_index()).next_u64(),
+            triple_fault_policy: TripleFaultPolicy::BugCheck,
+            host_breakpoints: [None; BREAKPOINT_SLOTS],
+            pause_loop: None,
+            encls_exiting: None,
+            apic_base_tracking: false,
+            x2apic_interception: false,
+        });
+
+        // The charges are released when the shared data is dropped.
+        reservation.keep();
+
+        Ok(shared_data)
+    }
+
+    /// Returns whether a guest physical address lies in memory a feature monitors through the EPT: a region
+    /// protected by a permission profile, a page of the guest agent or a poisoned heap zone.
+    ///
+    /// The other EPT violations, e.g. the view swaps of hooked pages, are part of running the guest.
+    pub fn is_monitored(&self, guest_pa: Gpa) -> bool {
+        #[cfg(feature = "introspection")]
+        if self.heap_poison.is_poisoned(guest_pa) {
+            return true;
+        }
+
+        self.ept_policy.lookup(guest_pa).is_some() || self.agent_monitor.protects(guest_pa)
+    }
+
+    /// Changes the permissions of a 4KB page in all EPTs, splitting the 2MB page containing it if needed.
+    ///
+    /// The caller must invalidate the EPT afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address of the page, page aligned.
+    /// * `access_type` - The new permissions of the page.
+    ///
+    /// # Returns
+    /// A `Result` indicating whether the permissions were changed.
+    pub fn set_page_access(
+        &mut self,
+        guest_pa: Gpa,
+        access_type: AccessType,
+    ) -> Result<(), HypervisorError> {
+        Self::set_ept_page_access(&mut self.primary_ept, guest_pa, access_type)?;
+
+        #[cfg(feature = "secondary-ept")]
+        Self::set_ept_page_access(&mut self.secondary_ept, guest_pa, access_type)?;
+
+        Ok(())
+    }
+
+    /// Returns the permissions of a 4KB page in all EPTs, to restore them with
+    /// `SharedData::restore_page_access` once the page is no longer watched.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address of the page, page aligned.
+    ///
+    /// # Returns
+    /// A `Result` containing the permissions of the page.
+    pub fn page_access(&self, guest_pa: Gpa) -> Result<PageAccess, HypervisorError> {
+        Ok(PageAccess {
+            guest_pa,
+            primary: self.primary_ept.page_access(guest_pa)?,
+            #[cfg(feature = "secondary-ept")]
+            secondary: self.secondary_ept.page_access(guest_pa)?,
+        })
+    }
+
+    /// Restores the permissions of a 4KB page in all EPTs, as returned by `SharedData::page_access`.
+    ///
+    /// The caller must invalidate the EPT afterwards.
+    ///
+    /// # Returns
+    /// A `Result` indicating whether the permissions were restored.
+    pub fn restore_page_access(&mut self, saved: PageAccess) -> Result<(), HypervisorError> {
+        Self::set_ept_page_access(&mut self.primary_ept, saved.guest_pa, saved.primary)?;
+
+        #[cfg(feature = "secondary-ept")]
+        Self::set_ept_page_access(&mut self.secondary_ept, saved.guest_pa, saved.secondary)?;
+
+        Ok(())
+    }
+
+    /// Sets the memory type of a guest physical address range in the EPTs, see `Ept::set_memory_type`.
+    ///
+    /// The caller must invalidate the EPT afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The 4KB aligned guest physical address range.
+    /// * `memory_type` - The memory type to apply.
+    ///
+    /// # Returns
+    /// A `Result` indicating whether the memory type was changed.
+    pub fn set_memory_type(
+        &mut self,
+        range: Range<Gpa>,
+        memory_type: MemoryType,
+    ) -> Result<(), HypervisorError> {
+        self.primary_ept
+            .set_memory_type(range.clone(), memory_type)?;
+
+        #[cfg(feature = "secondary-ept")]
+        self.secondary_ept.set_memory_type(range, memory_type)?;
+
+        Ok(())
+    }
+
+    /// Removes the execute permission from the pages of the coverage target, so their next instruction fetch
+    /// is recorded.
+    ///
+    /// The caller must invalidate the EPT afterwards.
+    ///
+    /// # Returns
+    /// A `Result` indicating whether the permissions were changed.
+    #[cfg(feature = "introspection")]
+    pub fn arm_coverage(&mut self) -> Result<(), HypervisorError> {
+        for page in self.coverage.frames() {
+            Self::set_ept_page_access(&mut self.primary_ept, page, AccessType::READ_WRITE)?;
+
+            #[cfg(feature = "secondary-ept")]
+            Self::set_ept_page_access(&mut self.secondary_ept, page, AccessType::READ_WRITE)?;
+        }
+
+        Ok(())
+    }
+
+    /// Disables the hook of a thrashing page by making the page executable in the primary EPT.
+    ///
+    /// The caller must invalidate the EPT afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `page` - The guest physical address of the hooked page.
+    ///
+    /// # Returns
+    /// A `Result` containing whether the hook was disabled.
+    pub fn disable_thrashing_hook(&mut self, page: Gpa) -> Result<bool, HypervisorError> {
+        let Some(policy) = self.thrash_policy else {
+            return Ok(false);
+        };
+
+        if !self.thrash_guard.disable(page, &policy) {
+            return Ok(false);
+        }
+
+        self.primary_ept
+            .change_page_flags(page, AccessType::READ_WRITE_EXECUTE)?;
+
+        Ok(true)
+    }
+
+    /// Enables the hooks of thrashing pages again once their cooldown has elapsed, unless they were removed in
+    /// the meantime.
+    ///
+    /// The caller must invalidate the EPT on all processors afterwards if hooks were enabled, see
+    /// `invept_broadcast`.
+    ///
+    /// # Returns
+    /// A `Result` containing whether hooks were enabled.
+    pub fn restore_thrashing_hooks(&mut self) -> Result<bool, HypervisorError> {
+        let mut restored = false;
+
+        for page in self.thrash_guard.take_expired().into_iter().flatten() {
+            let hook_table = self.hook_table.read();
+            let Some(hooked) = hook_table.hooked_page(page) else {
+                continue;
+            };
+
+            // The page was writable without exiting while the hook was disabled.
+            hooked.sync();
+
+            log::debug!("Enabling the hook of {:#x} again", page);
+            self.primary_ept
+                .change_page_flags(page, hooked.read_write_view_access())?;
+            restored = true;
+        }
+
+        Ok(restored)
+    }
+
+    fn set_ept_page_access(
+        ept: &mut Ept,
+        guest_pa: Gpa,
+        access_type: AccessType,
+    ) -> Result<(), HypervisorError> {
+        match ept.split_2mb_to_4kb(guest_pa, AccessType::READ_WRITE_EXECUTE) {
+            Ok(()) | Err(HypervisorError::PageAlreadySplit) => {}
+            Err(error) => return Err(error),
+        }
+
+        ept.change_page_flags(guest_pa, access_type)
+    }
+
+    /// Enables the EPT accessed and dirty flags in an EPTP if the processor supports them.
+    ///
+    /// # Arguments
+    ///
+    /// * `eptp` - The EPTP.
+    /// * `supported` - Whether the processor supports the accessed and dirty flags.
+    fn with_accessed_dirty(eptp: u64, supported: bool) -> u64 {
+        if supported {
+            log::trace!("Enabling EPT accessed and dirty flags");
+            eptp | EPTP_ACCESSED_DIRTY_ENABLE
+        } else {
+            eptp
+        }
+    }
+
+    /// Returns the memory charged for the shared structures, per category.
+    fn footprint() -> [(MemoryCategory, u64); 2] {
+        #[cfg(feature = "secondary-ept")]
+        let ept_count = 2;
+
+        #[cfg(not(feature = "secondary-ept"))]
+        let ept_count = 1;
+
+        [
+            (MemoryCategory::Ept, ept_count * size_of::<Ept>() as u64),
+            (
+                MemoryCategory::Bitmap,
+                (size_of::<MsrBitmap>() + size_of::<IoBitmap>()) as u64,
+            ),
+        ]
+    }
+}
+
+impl Drop for SharedData {
+    /// Releases the memory charged for the shared structures.
+    fn drop(&mut self) {
+        footprint::release_all(&Self::footprint());
     }
 }
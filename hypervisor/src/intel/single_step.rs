@@ -0,0 +1,144 @@
+//! Single-stepping of the guest with the monitor trap flag (MTF).
+//!
+//! With the monitor trap flag set in the primary processor-based controls, the guest exits after every
+//! instruction, or before the first instruction of an event handler it enters. A `SingleStepCallback` is
+//! invoked in VMX root operation on each of these exits, with the registers of the guest, and decides whether
+//! stepping goes on. This is the building block of the "step over and restore" logic of EPT hooks and of
+//! instruction tracing.
+//!
+//! Stepping is requested with `Vcpu::single_step` from any context and starts at the next VM exit of the
+//! processor, or with `SingleStep::start` from an exit handler of the processor, where it starts when the guest
+//! resumes. The monitor trap flag is shared with the internal users stepping over EPT permissions, see
+//! `vmexit::ept::handle_monitor_trap_flag`, and stays set as long as any of them needs it.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.2 Monitor Trap Flag and
+//! 27.7.2 VM Exits Caused by the Monitor Trap Flag.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            support::{try_vmread, try_vmwrite},
+            vmx::Vmx,
+        },
+        utils::{capture::GuestRegisters, sync::SpinLock},
+    },
+    core::sync::atomic::{AtomicBool, Ordering},
+    x86::vmx::vmcs::{self, control::PrimaryControls},
+};
+
+/// What happens after a single-stepped instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepAction {
+    /// Step the next instruction as well.
+    Continue,
+
+    /// Stop stepping, the guest runs freely again.
+    Stop,
+}
+
+/// Called in VMX root operation after every instruction the guest single-stepped, with the guest registers,
+/// `GuestRegisters::rip` being the next instruction. A changed RIP, RSP or RFLAGS must also be written to the
+/// guest state of the VMCS.
+pub type SingleStepCallback = fn(&mut GuestRegisters, &mut Vmx) -> StepAction;
+
+/// The single-stepping state of a processor.
+pub struct SingleStep {
+    /// Whether a request is waiting in `requested`, checked on every VM exit without taking the lock.
+    pending: AtomicBool,
+
+    /// The callback requested from outside of the processor's exit handlers.
+    requested: SpinLock<Option<SingleStepCallback>>,
+
+    /// The callback of the current stepping, if any.
+    active: Option<SingleStepCallback>,
+}
+
+impl SingleStep {
+    /// Creates the state of a processor that is not stepping.
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            requested: SpinLock::new("single_step_request", None),
+            active: None,
+        }
+    }
+
+    /// Requests stepping from any context, replacing a request not started yet. It starts at the next VM exit
+    /// of the processor, see `apply_request`.
+    pub fn request(&self, callback: SingleStepCallback) {
+        *self.requested.lock() = Some(callback);
+        self.pending.store(true, Ordering::Release);
+    }
+
+    /// Starts the stepping requested with `request`, if any. Called on every VM exit.
+    pub fn apply_request(&mut self) -> Result<(), HypervisorError> {
+        if !self.pending.swap(false, Ordering::Acquire) {
+            return Ok(());
+        }
+
+        // The lock may be held by the guest code this exit interrupted, which cannot release it before the guest
+        // resumes. The request is then left pending for a later exit instead of spinning forever.
+        let Some(mut requested) = self.requested.try_lock() else {
+            self.pending.store(true, Ordering::Release);
+            return Ok(());
+        };
+        let callback = requested.take();
+        drop(requested);
+
+        match callback {
+            Some(callback) => self.start(callback),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts stepping from an exit handler of the processor, replacing the current callback if stepping already.
+    /// The first callback follows the instruction the guest resumes at.
+    pub fn start(&mut self, callback: SingleStepCallback) -> Result<(), HypervisorError> {
+        log::trace!("Starting single-stepping");
+
+        self.active = Some(callback);
+        set_monitor_trap_flag(true)
+    }
+
+    /// Returns whether the processor is stepping.
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Handles a monitor trap flag VM exit by invoking the callback, if stepping.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the processor is not stepping, otherwise whether stepping goes on. The caller clears the monitor
+    /// trap flag once no one needs it anymore.
+    pub fn step(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> Option<StepAction> {
+        let callback = vmx.single_step.active.take()?;
+
+        let action = callback(guest_registers, vmx);
+        if action == StepAction::Continue && vmx.single_step.active.is_none() {
+            vmx.single_step.active = Some(callback);
+        }
+
+        Some(action)
+    }
+}
+
+impl Default for SingleStep {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Enables or disables the monitor trap flag of the current processor.
+pub fn set_monitor_trap_flag(enable: bool) -> Result<(), HypervisorError> {
+    let controls = try_vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS)?;
+    let mtf = PrimaryControls::MONITOR_TRAP_FLAG.bits() as u64;
+
+    let controls = match enable {
+        true => controls | mtf,
+        false => controls & !mtf,
+    };
+
+    try_vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, controls)
+}
@@ -0,0 +1,247 @@
+//! Visibility of the system management mode (SMM) activity of the processors.
+//!
+//! With the default treatment of SMIs and SMM, which the hypervisor uses, an SMI arriving in VMX non-root or root
+//! operation is handled by the firmware without a VM exit: the processor saves its state, runs the SMM handler and
+//! resumes with RSM, invisibly but for the time it took. An SMI hitting a VM exit stretches it by the length of the
+//! SMM handler, and SMM code that touches the VMX state, e.g. a buggy firmware, corrupts it. Both are hard to tell
+//! apart from bugs of the hypervisor.
+//!
+//! MSR_SMI_COUNT counts the SMIs taken by the processor. When monitoring is enabled with
+//! `HypervisorBuilder::smm_monitoring`, every processor samples it at the start and the end of every VM exit:
+//! - SMIs taken while the guest ran are counted.
+//! - SMIs taken while the hypervisor handled an exit are counted and recorded as an `SmiEvent`, with the exit
+//!   reason and the RIP of the guest, so they can be correlated with misbehavior of that exit.
+//!
+//! The SMI and RSM exit reasons only occur with the dual-monitor treatment of SMIs and SMM, which the hypervisor
+//! never activates. Should an SMI exit occur anyway, it is recorded and the guest resumes. RSM outside of SMM raises
+//! #UD, which is what the guest gets.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 33.14 Default Treatment of SMIs and
+//! SMM with VMX Operation and SMX Operation, 33.15 Dual-Monitor Treatment of SMIs and SMM, and 2.17 MSRs in the
+//! Intel Core i7 Processor Family (MSR_SMI_COUNT).
+//!
+//! MSR_SMI_COUNT is not enumerated by CPUID, and reading it where it is not implemented, e.g. on Bonnell Atoms or
+//! Dunnington Xeons, raises #GP. Monitoring is therefore only offered on the models known to implement it.
+
+use {
+    crate::{
+        intel::vmerror::VmxBasicExitReason,
+        utils::{
+            cpu, event_log::EventLog, instructions::rdmsr, processor::current_processor_index,
+            timestamp::Timestamp,
+        },
+    },
+    core::sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The MSR counting the SMIs taken by the processor since reset, in bits 31:0.
+pub const MSR_SMI_COUNT: u32 = 0x34;
+
+/// The number of events kept until they are drained.
+const EVENT_LOG_LEN: usize = 64;
+
+/// Returns whether the processor implements MSR_SMI_COUNT. It is not enumerated by CPUID, so the processor is
+/// identified by its family and model, from the MSR tables of the SDM.
+pub fn has_smi_count() -> bool {
+    let (family, model) = cpu::family_model();
+    if family != 0x6 {
+        return false;
+    }
+
+    matches!(
+        model,
+        // Nehalem, Westmere, Sandy Bridge, Ivy Bridge, Haswell and Broadwell.
+        0x1A | 0x1E | 0x1F | 0x2E | 0x25 | 0x2C | 0x2F | 0x2A | 0x2D | 0x3A | 0x3E | 0x3C | 0x3F
+            | 0x45 | 0x46 | 0x3D | 0x47 | 0x4F | 0x56
+            // Skylake up to Raptor Lake, Sapphire Rapids and Emerald Rapids.
+            | 0x4E | 0x5E | 0x55 | 0x8E | 0x9E | 0x66 | 0x6A | 0x6C | 0x7D | 0x7E | 0x8C | 0x8D
+            | 0xA5 | 0xA6 | 0xA7 | 0x97 | 0x9A | 0xB7 | 0xBA | 0xBF | 0x8F | 0xCF
+            // Silvermont, Airmont, Goldmont, Goldmont Plus, Tremont and Knights Landing/Mill.
+            | 0x37 | 0x4A | 0x4C | 0x4D | 0x5A | 0x5D | 0x5C | 0x5F | 0x7A | 0x86 | 0x96 | 0x9C
+            | 0x57 | 0x85
+    )
+}
+
+/// Returns the number of SMIs taken by the current processor.
+fn smi_count() -> u32 {
+    rdmsr(MSR_SMI_COUNT) as u32
+}
+
+/// When an SMI was observed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmiWindow {
+    /// While the hypervisor handled a VM exit.
+    RootMode,
+
+    /// As a VM exit, which only happens with the dual-monitor treatment.
+    VmExit,
+}
+
+/// SMIs observed on a processor outside of the guest.
+#[derive(Debug, Clone, Copy)]
+pub struct SmiEvent {
+    /// The index of the processor.
+    pub processor: u32,
+
+    /// When the SMIs were observed.
+    pub window: SmiWindow,
+
+    /// The number of SMIs.
+    pub count: u32,
+
+    /// The basic reason of the VM exit.
+    pub exit_reason: VmxBasicExitReason,
+
+    /// The guest RIP of the VM exit.
+    pub rip: u64,
+
+    /// When the SMIs were observed.
+    pub timestamp: Timestamp,
+}
+
+/// The number of SMIs taken by all processors since monitoring started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SmiCounts {
+    /// The SMIs taken while the guest ran.
+    pub guest: u64,
+
+    /// The SMIs taken while the hypervisor handled a VM exit, or as a VM exit.
+    pub root: u64,
+}
+
+/// Counts and records the SMIs of all processors.
+pub struct SmmMonitor {
+    /// Whether the processors sample MSR_SMI_COUNT.
+    enabled: bool,
+
+    /// The SMIs taken while the guest ran.
+    guest_smis: AtomicU64,
+
+    /// The SMIs taken while the hypervisor handled a VM exit, or as a VM exit.
+    root_smis: AtomicU64,
+
+    /// The SMIs observed outside of the guest.
+    events: EventLog<SmiEvent, EVENT_LOG_LEN>,
+}
+
+impl SmmMonitor {
+    /// Creates the monitor.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether the processors sample MSR_SMI_COUNT.
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            guest_smis: AtomicU64::new(0),
+            root_smis: AtomicU64::new(0),
+            events: EventLog::new("smi_events"),
+        }
+    }
+
+    /// Returns whether the processors sample MSR_SMI_COUNT.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the number of SMIs taken by all processors since monitoring started.
+    pub fn counts(&self) -> SmiCounts {
+        SmiCounts {
+            guest: self.guest_smis.load(Ordering::Relaxed),
+            root: self.root_smis.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts SMIs taken while the guest ran.
+    pub fn record_guest(&self, count: u32) {
+        self.guest_smis
+            .fetch_add(u64::from(count), Ordering::Relaxed);
+    }
+
+    /// Counts and records SMIs observed outside of the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - When the SMIs were observed.
+    /// * `count` - The number of SMIs.
+    /// * `exit_reason` - The basic reason of the VM exit.
+    /// * `rip` - The guest RIP of the VM exit.
+    pub fn record(&self, window: SmiWindow, count: u32, exit_reason: VmxBasicExitReason, rip: u64) {
+        self.root_smis
+            .fetch_add(u64::from(count), Ordering::Relaxed);
+        self.events.push(SmiEvent {
+            processor: current_processor_index(),
+            window,
+            count,
+            exit_reason,
+            rip,
+            timestamp: Timestamp::now(),
+        });
+    }
+
+    /// Hands the recorded events to a consumer, oldest first, and removes them.
+    ///
+    /// # Returns
+    ///
+    /// The number of events handed to the consumer.
+    pub fn drain_events(&self, consumer: impl FnMut(&SmiEvent)) -> usize {
+        self.events.drain(consumer)
+    }
+}
+
+/// Samples MSR_SMI_COUNT around the VM exits of a processor.
+pub struct SmiTracker {
+    /// Whether MSR_SMI_COUNT is sampled.
+    enabled: bool,
+
+    /// MSR_SMI_COUNT at the end of the last VM exit, when the guest resumed.
+    resume_count: u32,
+
+    /// MSR_SMI_COUNT at the start of the current VM exit.
+    exit_count: u32,
+}
+
+impl SmiTracker {
+    /// Creates the tracker of the current processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether MSR_SMI_COUNT is sampled.
+    pub fn new(enabled: bool) -> Self {
+        let count = if enabled { smi_count() } else { 0 };
+
+        Self {
+            enabled,
+            resume_count: count,
+            exit_count: count,
+        }
+    }
+
+    /// Notes the start of the handling of a VM exit.
+    ///
+    /// # Returns
+    ///
+    /// The number of SMIs taken while the guest ran since the last VM exit.
+    pub fn begin_exit(&mut self) -> u32 {
+        if !self.enabled {
+            return 0;
+        }
+
+        self.exit_count = smi_count();
+        self.exit_count.wrapping_sub(self.resume_count)
+    }
+
+    /// Notes the end of the handling of a VM exit.
+    ///
+    /// # Returns
+    ///
+    /// The number of SMIs taken while the hypervisor handled the VM exit.
+    pub fn end_exit(&mut self) -> u32 {
+        if !self.enabled {
+            return 0;
+        }
+
+        self.resume_count = smi_count();
+        self.resume_count.wrapping_sub(self.exit_count)
+    }
+}
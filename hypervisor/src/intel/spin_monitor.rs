@@ -0,0 +1,88 @@
+//! Detection of lock contention in the guest with PAUSE-loop exiting.
+//!
+//! Spinlocks of the guest kernel execute PAUSE in their wait loops. With PAUSE-loop exiting enabled by
+//! `HypervisorBuilder::pause_loop_exiting`, the processor exits once a loop spun for longer than the window of
+//! `controls::PauseLoopConfig`, and the exit handler records the spin in the monitor of the processor. Frequent
+//! spins, or spins in the same function on every processor, point at a contended lock, without any cooperation
+//! of the guest.
+//!
+//! The counters are atomic, as the hypervisor reads them from other processors. A snapshot may mix two
+//! consecutive spins, which does not matter for statistics.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM
+//! Exits Conditionally (PAUSE) and Table C-1. Basic Exit Reasons 40.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The spins of the guest recorded on a processor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpinStats {
+    /// The number of spin loops that exceeded the window.
+    pub events: u64,
+
+    /// The guest RIP of the PAUSE of the last spin, zero if there was none.
+    pub last_rip: u64,
+
+    /// The guest CR3 of the last spin.
+    pub last_cr3: u64,
+
+    /// The TSC of the host when the last spin was recorded.
+    pub last_tsc: u64,
+}
+
+/// The spins of the guest on a processor, see the module documentation.
+pub struct SpinMonitor {
+    /// The number of spin loops that exceeded the window.
+    events: AtomicU64,
+
+    /// The guest RIP of the PAUSE of the last spin.
+    last_rip: AtomicU64,
+
+    /// The guest CR3 of the last spin.
+    last_cr3: AtomicU64,
+
+    /// The TSC of the host when the last spin was recorded.
+    last_tsc: AtomicU64,
+}
+
+impl SpinMonitor {
+    /// Creates a monitor without any spin recorded.
+    pub const fn new() -> Self {
+        Self {
+            events: AtomicU64::new(0),
+            last_rip: AtomicU64::new(0),
+            last_cr3: AtomicU64::new(0),
+            last_tsc: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a spin loop that exceeded the window.
+    ///
+    /// # Arguments
+    ///
+    /// * `rip` - The guest RIP of the PAUSE instruction.
+    /// * `cr3` - The guest CR3.
+    /// * `tsc` - The TSC of the host.
+    pub fn record(&self, rip: u64, cr3: u64, tsc: u64) {
+        self.last_rip.store(rip, Ordering::Relaxed);
+        self.last_cr3.store(cr3, Ordering::Relaxed);
+        self.last_tsc.store(tsc, Ordering::Relaxed);
+        self.events.fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns a snapshot of the spins recorded so far.
+    pub fn stats(&self) -> SpinStats {
+        SpinStats {
+            events: self.events.load(Ordering::Acquire),
+            last_rip: self.last_rip.load(Ordering::Relaxed),
+            last_cr3: self.last_cr3.load(Ordering::Relaxed),
+            last_tsc: self.last_tsc.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for SpinMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,44 +1,113 @@
-use super::vmcs::Vmcs;
+// Wrappers used from VMX root operation must not panic.
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
+use super::{
+    evmcs,
+    intrinsics::{self, VmFail},
+    vmcs::Vmcs,
+    vmerror::VmInstructionError,
+};
 use crate::error::HypervisorError;
 
+/// The failure reported for a field the enlightened VMCS has no room for, as VMREAD and VMWRITE report a field
+/// the processor does not support.
+const UNSUPPORTED_EVMCS_FIELD: VmFail =
+    VmFail::Valid(VmInstructionError::VmreadVmwriteUnsupportedVmcsComponent as u32);
+
 /// Enable VMX operation.
-pub fn vmxon(vmxon_region: u64) {
-    unsafe { x86::bits64::vmx::vmxon(vmxon_region).unwrap() };
+pub fn vmxon(vmxon_region: u64) -> Result<(), HypervisorError> {
+    match unsafe { intrinsics::vmxon(vmxon_region) } {
+        Ok(_) => Ok(()),
+        Err(fail) => Err(HypervisorError::VMXONFailed(fail)),
+    }
 }
 
 /// Disable VMX operation.
 pub fn vmxoff() -> Result<(), HypervisorError> {
-    match unsafe { x86::bits64::vmx::vmxoff() } {
+    match unsafe { intrinsics::vmxoff() } {
         Ok(_) => Ok(()),
-        Err(_) => Err(HypervisorError::VMXOFFFailed),
+        Err(fail) => Err(HypervisorError::VMXOFFFailed(fail)),
     }
 }
 
 /// Clear VMCS.
-pub fn vmclear(vmcs_region: u64) {
-    unsafe { x86::bits64::vmx::vmclear(vmcs_region).unwrap() };
+pub fn vmclear(vmcs_region: u64) -> Result<(), HypervisorError> {
+    match unsafe { intrinsics::vmclear(vmcs_region) } {
+        Ok(_) => Ok(()),
+        Err(fail) => Err(HypervisorError::VMCLEARFailed(fail)),
+    }
 }
 
 /// Load current VMCS pointer.
-pub fn vmptrld(vmcs_region: u64) {
-    unsafe { x86::bits64::vmx::vmptrld(vmcs_region).unwrap() }
+pub fn vmptrld(vmcs_region: u64) -> Result<(), HypervisorError> {
+    match unsafe { intrinsics::vmptrld(vmcs_region) } {
+        Ok(_) => Ok(()),
+        Err(fail) => Err(HypervisorError::VMPTRLDFailed(fail)),
+    }
 }
 
 /// Return current VMCS pointer.
 #[allow(dead_code)]
-pub fn vmptrst() -> *const Vmcs {
-    unsafe { x86::bits64::vmx::vmptrst().unwrap() as *const Vmcs }
+pub fn vmptrst() -> Result<*const Vmcs, HypervisorError> {
+    match unsafe { intrinsics::vmptrst() } {
+        Ok(vmcs_region) => Ok(vmcs_region as *const Vmcs),
+        Err(fail) => Err(HypervisorError::VMPTRSTFailed(fail)),
+    }
 }
 
 /// Read a specified field from a VMCS.
+///
+/// Returns 0 if the read fails. Use `try_vmread` where the caller has to tell a failed read apart.
 pub fn vmread(field: u32) -> u64 {
-    unsafe { x86::bits64::vmx::vmread(field) }.unwrap_or(0)
+    try_vmread(field).unwrap_or(0)
+}
+
+/// Read a specified field from a VMCS, reporting a failed read.
+///
+/// Reads the enlightened VMCS in memory instead if the processor has loaded one, see `intel::evmcs`.
+pub fn try_vmread(field: u32) -> Result<u64, HypervisorError> {
+    if let Some(evmcs) = evmcs::current() {
+        return unsafe { (*evmcs).read(field) }
+            .ok_or(HypervisorError::VMREADFailed(UNSUPPORTED_EVMCS_FIELD));
+    }
+
+    match unsafe { intrinsics::vmread(field) } {
+        Ok(value) => Ok(value),
+        Err(fail) => Err(HypervisorError::VMREADFailed(fail)),
+    }
 }
 
 /// Write to a specified field in a VMCS.
+///
+/// A failed write is logged. Use `try_vmwrite` where the caller has to react to a failed write.
 pub fn vmwrite<T: Into<u64>>(field: u32, val: T)
 where
     u64: From<T>,
 {
-    unsafe { x86::bits64::vmx::vmwrite(field, u64::from(val)) }.unwrap();
+    if try_vmwrite(field, val).is_err() {
+        log::error!("VMWRITE failed for field {:#x}", field);
+    }
+}
+
+/// Write to a specified field in a VMCS, reporting a failed write.
+///
+/// Writes the enlightened VMCS in memory instead if the processor has loaded one, see `intel::evmcs`.
+pub fn try_vmwrite<T: Into<u64>>(field: u32, val: T) -> Result<(), HypervisorError>
+where
+    u64: From<T>,
+{
+    if let Some(evmcs) = evmcs::current() {
+        return unsafe { (*evmcs).write(field, u64::from(val)) }
+            .ok_or(HypervisorError::VMWRITEFailed(UNSUPPORTED_EVMCS_FIELD));
+    }
+
+    match unsafe { intrinsics::vmwrite(field, u64::from(val)) } {
+        Ok(_) => Ok(()),
+        Err(fail) => Err(HypervisorError::VMWRITEFailed(fail)),
+    }
 }
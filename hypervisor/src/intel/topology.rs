@@ -0,0 +1,402 @@
+//! Processor topology discovery and the mapping between vCPU indexes and APIC IDs.
+//!
+//! The OS and the per-CPU table identify processors by their systemwide index, while the hardware (IPIs,
+//! interrupt routing) uses the x2APIC ID. This module discovers the x2APIC ID of every processor and
+//! decomposes it into package, core and thread (SMT) identifiers using CPUID leaf 0x1F, or leaf 0xB
+//! where 0x1F is not available, so both views can be correlated.
+//!
+//! The topology the guest reads through CPUID can be changed with a `TopologyConfig`, e.g. to hide SMT from the
+//! guest scheduler. Leaves 0BH and 1FH are then synthesized with an SMT and a core level, and the related fields of
+//! leaves 01H and 04H are made consistent with them. Processors left native keep reporting their own topology, so
+//! the configuration is meant for systems where every processor is virtualized.
+//!
+//! Only CPUID is rewritten. The APIC IDs are not synthesized, and the ACPI MADT still lists every logical
+//! processor, SMT siblings included. A guest counting processors from the MADT, or decomposing APIC IDs with the
+//! widths of the processor, sees the real topology. The configuration changes how the guest groups its
+//! processors, not how many it has.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 10.9 PROGRAMMING CONSIDERATIONS FOR HARDWARE MULTI-THREADING CAPABLE PROCESSORS
+//! and CPUID—CPU Identification, Leaf 01H, Leaf 04H, Leaf 0BH and Leaf 1FH.
+
+use {
+    crate::{
+        error::HypervisorError,
+        utils::{
+            cpu::{self, CoreType},
+            processor::{processor_count, ProcessorExecutor},
+        },
+    },
+    alloc::vec::Vec,
+    core::fmt,
+    x86::cpuid::{cpuid, CpuIdResult},
+};
+
+/// CPUID leaf reporting the feature information, with the addressable logical processor IDs in EBX[23:16].
+const CPUID_FEATURE_INFORMATION: u32 = 0x1;
+
+/// CPUID.01H:EDX bit indicating that the package has more than one logical processor (HTT).
+const CPUID_01_EDX_HTT: u32 = 1 << 28;
+
+/// CPUID leaf reporting the deterministic cache parameters.
+const CPUID_CACHE_PARAMETERS: u32 = 0x4;
+
+/// CPUID leaf reporting the extended topology.
+const CPUID_EXTENDED_TOPOLOGY: u32 = 0xB;
+
+/// CPUID leaf reporting the V2 extended topology.
+const CPUID_EXTENDED_TOPOLOGY_V2: u32 = 0x1F;
+
+/// The level type of the SMT level in ECX[15:8] of the extended topology leaves.
+const LEVEL_TYPE_SMT: u32 = 1;
+
+/// The level type of the core level in ECX[15:8] of the extended topology leaves.
+const LEVEL_TYPE_CORE: u32 = 2;
+
+/// The maximum number of sub-leaves walked in the extended topology leaves.
+const MAX_TOPOLOGY_LEVELS: u32 = 8;
+
+/// The topological identifiers of a single logical processor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// The systemwide processor index used by the OS.
+    pub index: u32,
+
+    /// The x2APIC ID, or the initial APIC ID on processors without the extended topology leaf.
+    pub apic_id: u32,
+
+    /// The package (socket) the processor belongs to.
+    pub package_id: u32,
+
+    /// The core within the package.
+    pub core_id: u32,
+
+    /// The thread (SMT sibling) within the core.
+    pub thread_id: u32,
+
+    /// The type of the core on hybrid processors.
+    pub core_type: Option<CoreType>,
+}
+
+impl CpuTopology {
+    /// Discovers the topology of the current processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The systemwide index of the current processor.
+    pub fn current(index: u32) -> Self {
+        let Some(leaf) = extended_topology_leaf() else {
+            // Without the extended topology leaves, only the initial APIC ID is known.
+            let apic_id = cpuid!(0x1).ebx >> 24;
+            return Self {
+                index,
+                apic_id,
+                core_id: apic_id,
+                core_type: cpu::core_type(),
+                ..Default::default()
+            };
+        };
+
+        let LevelShifts {
+            smt_shift,
+            core_shift,
+            apic_id,
+        } = LevelShifts::read(leaf);
+
+        Self {
+            index,
+            apic_id,
+            package_id: apic_id.checked_shr(core_shift).unwrap_or(0),
+            core_id: (apic_id & mask(core_shift)) >> smt_shift,
+            thread_id: apic_id & mask(smt_shift),
+            core_type: cpu::core_type(),
+        }
+    }
+}
+
+impl fmt::Display for CpuTopology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CPU {}: APIC ID {:#x}, Package {}, Core {}, Thread {}",
+            self.index, self.apic_id, self.package_id, self.core_id, self.thread_id
+        )?;
+
+        match self.core_type {
+            Some(core_type) => write!(f, ", {:?}", core_type),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The widths of the SMT and core fields of the x2APIC ID of the current processor.
+#[derive(Debug, Clone, Copy)]
+pub struct LevelShifts {
+    /// The number of bits to shift the x2APIC ID right to get the core ID.
+    smt_shift: u32,
+
+    /// The number of bits to shift the x2APIC ID right to get the package ID.
+    core_shift: u32,
+
+    /// The x2APIC ID.
+    apic_id: u32,
+}
+
+impl LevelShifts {
+    /// Walks the levels of an extended topology leaf.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The extended topology leaf, 0BH or 1FH.
+    fn read(leaf: u32) -> Self {
+        let mut smt_shift = 0;
+        let mut core_shift = 0;
+        let mut apic_id = 0;
+
+        for sub_leaf in 0..MAX_TOPOLOGY_LEVELS {
+            let level = cpuid!(leaf, sub_leaf);
+            let level_type = (level.ecx >> 8) & 0xFF;
+
+            if level_type == 0 {
+                break;
+            }
+
+            // EAX[4:0] is the number of bits to shift the x2APIC ID right to get the ID of the next level.
+            let shift = level.eax & 0x1F;
+            apic_id = level.edx;
+
+            match level_type {
+                LEVEL_TYPE_SMT => smt_shift = shift,
+                LEVEL_TYPE_CORE => core_shift = shift,
+                // Module, tile and die levels are folded into the core level.
+                _ => core_shift = core_shift.max(shift),
+            }
+        }
+
+        Self {
+            smt_shift,
+            core_shift: core_shift.max(smt_shift),
+            apic_id,
+        }
+    }
+}
+
+/// The topology leaves of a processor, read once when it is virtualized so that rewriting CPUID does not walk
+/// them on every exit.
+#[derive(Debug, Clone, Copy)]
+pub struct TopologyLeaves {
+    /// The widths of the fields of the x2APIC ID.
+    shifts: LevelShifts,
+
+    /// The highest basic leaf of the processor, from CPUID leaf 0.
+    max_basic_leaf: u32,
+}
+
+impl TopologyLeaves {
+    /// Reads the topology leaves of the current processor.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the processor has no extended topology leaf, in which case its CPUID is not rewritten.
+    pub fn current() -> Option<Self> {
+        let leaf = extended_topology_leaf()?;
+
+        Some(Self {
+            shifts: LevelShifts::read(leaf),
+            max_basic_leaf: cpuid!(0x0).eax,
+        })
+    }
+}
+
+/// The topology reported to the guest through CPUID, set with `HypervisorBuilder::cpuid_topology`.
+///
+/// The guest is the running OS, which routes interrupts by the real APIC IDs, so the APIC IDs and the widths of
+/// their SMT and core fields stay those of the processor. Only the number of logical processors reported at each
+/// level changes. The addressable IDs of leaf 01H and the cache sharing of leaf 04H are derived from the same
+/// widths, so every leaf decomposes an APIC ID the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopologyConfig {
+    /// The number of logical processors reported per core, 1 to hide SMT.
+    pub threads_per_core: u32,
+
+    /// The number of cores reported per package.
+    pub cores_per_package: u32,
+}
+
+impl TopologyConfig {
+    /// Rewrites the topology reported by a CPUID leaf executed on the current processor.
+    ///
+    /// The counts are capped to the number of IDs the fields of the APIC ID can address.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaves` - The topology leaves of the current processor, see `TopologyLeaves::current`.
+    /// * `leaf` - The leaf, from EAX.
+    /// * `sub_leaf` - The sub-leaf, from ECX.
+    /// * `result` - The result of the leaf on the processor, rewritten in place.
+    pub fn filter_cpuid(
+        &self,
+        leaves: &TopologyLeaves,
+        leaf: u32,
+        sub_leaf: u32,
+        result: &mut CpuIdResult,
+    ) {
+        if !matches!(
+            leaf,
+            CPUID_FEATURE_INFORMATION
+                | CPUID_CACHE_PARAMETERS
+                | CPUID_EXTENDED_TOPOLOGY
+                | CPUID_EXTENDED_TOPOLOGY_V2
+        ) {
+            return;
+        }
+
+        let shifts = leaves.shifts;
+        let threads = self.threads_per_core.clamp(1, capacity(shifts.smt_shift));
+        let cores = self
+            .cores_per_package
+            .clamp(1, capacity(shifts.core_shift - shifts.smt_shift));
+        let logical = threads.saturating_mul(cores);
+
+        match leaf {
+            CPUID_FEATURE_INFORMATION => {
+                // EDX.HTT tells whether the package has more than one logical processor, and that EBX[23:16] is valid.
+                let addressable = capacity(shifts.core_shift).min(0xFF);
+                result.ebx = (result.ebx & !0x00FF_0000) | (addressable << 16);
+                result.edx = match logical > 1 {
+                    true => result.edx | CPUID_01_EDX_HTT,
+                    false => result.edx & !CPUID_01_EDX_HTT,
+                };
+            }
+            // EAX[4:0] is zero past the last cache.
+            CPUID_CACHE_PARAMETERS if result.eax & 0x1F != 0 => {
+                // A cache shared by more logical processors than a core has is shared by the package.
+                let sharing = ((result.eax >> 14) & 0xFFF) + 1;
+                let sharing = match sharing <= capacity(shifts.smt_shift) {
+                    true => capacity(shifts.smt_shift),
+                    false => capacity(shifts.core_shift),
+                }
+                .min(0x1000);
+                let cores = cores.min(0x40);
+
+                result.eax = (result.eax & 0x3FFF) | ((sharing - 1) << 14) | ((cores - 1) << 26);
+            }
+            // Leaf 1FH is only answered by processors that have it, others return the highest basic leaf.
+            CPUID_EXTENDED_TOPOLOGY | CPUID_EXTENDED_TOPOLOGY_V2
+                if leaf <= leaves.max_basic_leaf =>
+            {
+                // EDX is the x2APIC ID of the processor in every sub-leaf.
+                let (shift, count, level_type) = match sub_leaf {
+                    0 => (shifts.smt_shift, threads, LEVEL_TYPE_SMT),
+                    1 => (shifts.core_shift, logical, LEVEL_TYPE_CORE),
+                    _ => (0, 0, 0),
+                };
+
+                result.eax = shift;
+                result.ebx = count;
+                result.ecx = (level_type << 8) | (sub_leaf & 0xFF);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The topology of all logical processors, ordered by systemwide processor index.
+#[derive(Debug, Default)]
+pub struct Topology {
+    cpus: Vec<CpuTopology>,
+}
+
+impl Topology {
+    /// Discovers the topology by running on every logical processor.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `Topology`, or `HypervisorError::ProcessorSwitchFailed` if a processor
+    /// could not be switched to.
+    pub fn discover() -> Result<Self, HypervisorError> {
+        log::trace!("Discovering processor topology");
+
+        let mut cpus = Vec::new();
+
+        for index in 0..processor_count() {
+            let Some(executor) = ProcessorExecutor::switch_to_processor(index) else {
+                return Err(HypervisorError::ProcessorSwitchFailed);
+            };
+
+            cpus.push(CpuTopology::current(index));
+
+            drop(executor);
+        }
+
+        Ok(Self { cpus })
+    }
+
+    /// Returns the topology of the processor with the given systemwide index.
+    pub fn by_index(&self, index: u32) -> Option<&CpuTopology> {
+        self.cpus.get(index as usize)
+    }
+
+    /// Returns the topology of the processor with the given APIC ID.
+    pub fn by_apic_id(&self, apic_id: u32) -> Option<&CpuTopology> {
+        self.cpus.iter().find(|cpu| cpu.apic_id == apic_id)
+    }
+
+    /// Returns the APIC ID of the processor with the given systemwide index.
+    pub fn apic_id(&self, index: u32) -> Option<u32> {
+        self.by_index(index).map(|cpu| cpu.apic_id)
+    }
+
+    /// Returns the systemwide index of the processor with the given APIC ID.
+    pub fn index(&self, apic_id: u32) -> Option<u32> {
+        self.by_apic_id(apic_id).map(|cpu| cpu.index)
+    }
+
+    /// Returns an iterator over the topology of all processors.
+    pub fn iter(&self) -> impl Iterator<Item = &CpuTopology> {
+        self.cpus.iter()
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, cpu) in self.cpus.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", cpu)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the APIC ID of the current processor.
+///
+/// Uses the 32-bit x2APIC ID if the extended topology leaf is available, otherwise the 8-bit initial
+/// APIC ID.
+pub fn current_apic_id() -> u32 {
+    match extended_topology_leaf() {
+        Some(leaf) => cpuid!(leaf, 0).edx,
+        None => cpuid!(0x1).ebx >> 24,
+    }
+}
+
+/// Returns the extended topology leaf to use, preferring leaf 0x1F over leaf 0xB.
+fn extended_topology_leaf() -> Option<u32> {
+    let max_leaf = cpuid!(0x0).eax;
+
+    // EBX[15:0] of sub-leaf 0 is zero if the leaf is not supported by the processor.
+    [CPUID_EXTENDED_TOPOLOGY_V2, CPUID_EXTENDED_TOPOLOGY]
+        .into_iter()
+        .find(|&leaf| max_leaf >= leaf && cpuid!(leaf, 0).ebx & 0xFFFF != 0)
+}
+
+/// Returns a mask of the lowest `bits` bits.
+fn mask(bits: u32) -> u32 {
+    1u32.checked_shl(bits).map_or(u32::MAX, |bit| bit - 1)
+}
+
+/// Returns the number of IDs a field of `bits` bits can address.
+fn capacity(bits: u32) -> u32 {
+    mask(bits).saturating_add(1)
+}
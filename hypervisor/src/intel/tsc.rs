@@ -0,0 +1,326 @@
+//! Virtualization of the time-stamp counter read by the guest with RDTSC and RDTSCP.
+//!
+//! The `TscMode` of `TscConfig` selects how the guest reads the TSC:
+//! - `TscMode::Native`: the guest reads the TSC of the processor, as without a hypervisor.
+//! - `TscMode::Offsetting`: the processor returns the TSC multiplied by the multiplier, plus the offset, without
+//!   exiting. A multiplier other than one requires the "use TSC scaling" control.
+//! - `TscMode::Exiting`: RDTSC and RDTSCP exit, and `VirtualTsc::guest_tsc` computes the same value in software,
+//!   which also works without TSC scaling and leaves room for other policies.
+//!
+//! Every processor applies the same offset, kept in `utils::timestamp`, so the TSCs of the processors of the
+//! guest stay synchronized. Outside of `TscMode::Native`, RDMSR and WRMSR of IA32_TIME_STAMP_COUNTER are
+//! intercepted as well: reads return the TSC of the guest, and writes change the offset of all processors instead
+//! of the TSC of one.
+//!
+//! Timing-based detection measures how long an exiting instruction such as CPUID takes. With
+//! `compensate_root_time`, every VM exit lowers the offset by the cycles spent in VMX root operation, measured
+//! from the start to the end of the exit handler, plus `transition_cycles` for the VM exit and VM entry
+//! themselves, which cannot be measured from root mode. The guest then sees the exit take almost no time. Exits
+//! handled at the same time on several processors are hidden once, so the offset never falls faster than the
+//! TSC rises and the TSC of the guest never goes backwards. It falls behind the wall clock by the time at least
+//! one processor spent in the hypervisor. In `TscMode::Offsetting`, a processor writes the offset lowered by the
+//! others to its VMCS on its next VM exit. The compensation is paused while a guest debugging session suspends
+//! the hooks, see `intel::debugger`.
+//!
+//! The deadlines of the TSC-deadline timer of the guest are translated into the TSC of the processor, see
+//! `intel::apic_timer`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.3 Changes to Instruction Behavior
+//! in VMX Non-Root Operation (RDTSC, RDTSCP, RDMSR, WRMSR), 25.6.5 Time-Stamp Counter Offset and Multiplier, and
+//! 27.6 TSC Scaling.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            apic_timer::{set_tsc_deadline, tsc_deadline},
+            controls::TscMode,
+            support::{try_vmwrite, vmwrite},
+        },
+        utils::{
+            instructions::rdtsc,
+            timestamp::{lower_tsc_offset, set_tsc_offset, tsc_offset},
+        },
+    },
+    core::sync::atomic::{AtomicU64, Ordering},
+    x86::vmx::vmcs::control,
+};
+
+/// The TSC of the processors up to which the time spent in VMX root operation is hidden from the guest.
+static HIDDEN_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+/// The TSC multiplier of one, a fixed-point number with 48 fractional bits.
+pub const TSC_MULTIPLIER_ONE: u64 = 1 << 48;
+
+/// How the guest reads the TSC, set with `HypervisorBuilder::tsc_virtualization`.
+#[derive(Debug, Clone, Copy)]
+pub struct TscConfig {
+    /// How RDTSC and RDTSCP are virtualized.
+    pub mode: TscMode,
+
+    /// The ratio of the TSC of the guest to the TSC of the processor, a fixed-point number with 48 fractional
+    /// bits, `TSC_MULTIPLIER_ONE` for none. Ignored in `TscMode::Native`.
+    pub multiplier: u64,
+
+    /// Whether the cycles spent in VMX root operation are hidden from the guest. Ignored in `TscMode::Native`.
+    pub compensate_root_time: bool,
+
+    /// The cycles of a VM exit and VM entry, hidden from the guest on every exit when compensating.
+    pub transition_cycles: u64,
+}
+
+impl Default for TscConfig {
+    fn default() -> Self {
+        Self {
+            mode: TscMode::Native,
+            multiplier: TSC_MULTIPLIER_ONE,
+            compensate_root_time: false,
+            transition_cycles: 0,
+        }
+    }
+}
+
+impl TscConfig {
+    /// Returns whether the processor has to scale the TSC the guest reads without exiting.
+    pub fn needs_scaling(&self) -> bool {
+        self.mode == TscMode::Offsetting && self.multiplier != TSC_MULTIPLIER_ONE
+    }
+}
+
+/// The TSC of the guest on one processor.
+pub struct VirtualTsc {
+    /// How the guest reads the TSC.
+    config: TscConfig,
+
+    /// The offset last written to the VMCS of the processor.
+    loaded_offset: i64,
+
+    /// The TSC of the processor when the current VM exit started to be handled.
+    exit_tsc: u64,
+
+    /// The deadline last written by the guest to IA32_TSC_DEADLINE, in guest ticks, or zero if disarmed.
+    guest_deadline: u64,
+
+    /// Whether the time spent in VMX root operation is shown to the guest despite `compensate_root_time`.
+    paused: bool,
+}
+
+impl VirtualTsc {
+    /// Creates the TSC of the guest of a processor, equal to the scaled TSC of the processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - How the guest reads the TSC.
+    pub fn new(config: TscConfig) -> Self {
+        Self {
+            config,
+            loaded_offset: 0,
+            exit_tsc: 0,
+            guest_deadline: 0,
+            paused: false,
+        }
+    }
+
+    /// Returns how the guest reads the TSC.
+    pub fn mode(&self) -> TscMode {
+        self.config.mode
+    }
+
+    /// Returns the value added to the scaled TSC of the processors.
+    pub fn offset(&self) -> i64 {
+        match self.config.mode {
+            TscMode::Native => 0,
+            TscMode::Offsetting | TscMode::Exiting => tsc_offset(),
+        }
+    }
+
+    /// Writes the offset and the multiplier to the current VMCS. Called when the processor is virtualized.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether the VMCS fields could be written.
+    pub fn load(&mut self) -> Result<(), HypervisorError> {
+        if self.config.mode == TscMode::Native {
+            return Ok(());
+        }
+
+        if self.config.needs_scaling() {
+            try_vmwrite(control::TSC_MULTIPLIER_FULL, self.config.multiplier)?;
+        }
+
+        self.write_offset()
+    }
+
+    /// Changes the offset of the TSC of the guest on all processors. Must be called in VMX root operation on the
+    /// processor the instance belongs to, the other processors write it to their VMCS on their next VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The value added to the scaled TSC of the processors.
+    pub fn set_offset(&mut self, offset: i64) -> Result<(), HypervisorError> {
+        match self.config.mode {
+            TscMode::Native => Ok(()),
+            TscMode::Offsetting | TscMode::Exiting => {
+                set_tsc_offset(offset);
+                self.write_offset()
+            }
+        }
+    }
+
+    /// Returns the TSC the guest reads.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The TSC of the processor.
+    pub fn guest_tsc(&self, tsc: u64) -> u64 {
+        self.scale(tsc).wrapping_add_signed(self.offset())
+    }
+
+    /// Returns whether IA32_TIME_STAMP_COUNTER has to be intercepted, as the guest reads a TSC other than the one of
+    /// the processor.
+    pub fn intercepts_tsc_msr(&self) -> bool {
+        self.config.mode != TscMode::Native
+    }
+
+    /// Sets the TSC of the guest on behalf of a WRMSR of IA32_TIME_STAMP_COUNTER, by changing the offset of all
+    /// processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_tsc` - The value written by the guest.
+    pub fn write_guest_tsc(&mut self, guest_tsc: u64) -> Result<(), HypervisorError> {
+        self.set_offset(guest_tsc.wrapping_sub(self.scale(rdtsc())) as i64)
+    }
+
+    /// Returns whether IA32_TSC_DEADLINE has to be intercepted and translated, as the guest reads a TSC other than
+    /// the one of the processor.
+    pub fn translates_deadline(&self) -> bool {
+        self.config.mode != TscMode::Native
+    }
+
+    /// Arms or disarms the TSC-deadline timer for the guest, on behalf of a WRMSR of IA32_TSC_DEADLINE.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_deadline` - The deadline in guest ticks, or zero to disarm the timer.
+    pub fn write_deadline(&mut self, guest_deadline: u64) {
+        self.guest_deadline = guest_deadline;
+        set_tsc_deadline(self.host_deadline(guest_deadline));
+    }
+
+    /// Returns the IA32_TSC_DEADLINE the guest reads: its deadline while pending, zero once it fired.
+    pub fn read_deadline(&mut self) -> u64 {
+        if tsc_deadline() == 0 {
+            self.guest_deadline = 0;
+        }
+
+        self.guest_deadline
+    }
+
+    /// Pauses or resumes hiding the time spent in VMX root operation, from the end of the current VM exit on.
+    ///
+    /// # Arguments
+    ///
+    /// * `paused` - Whether the guest sees the time spent in the hypervisor.
+    pub fn pause_compensation(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Notes the start of the handling of a VM exit.
+    pub fn begin_exit(&mut self) {
+        if self.compensating() {
+            self.exit_tsc = rdtsc();
+        }
+    }
+
+    /// Hides the time spent handling the VM exit from the guest, when compensating, and follows the offset changed
+    /// by the other processors.
+    pub fn end_exit(&mut self) {
+        if self.config.mode == TscMode::Native {
+            return;
+        }
+
+        if self.compensating() && !self.paused {
+            let end = rdtsc();
+            let start = self.exit_tsc.saturating_sub(self.config.transition_cycles);
+
+            // Only the part of the exit no other processor hid already.
+            let hidden_until = HIDDEN_UNTIL.fetch_max(end, Ordering::AcqRel);
+            let from = start.max(hidden_until);
+            if end > from {
+                lower_tsc_offset(self.scale(end - from));
+            }
+        }
+
+        let offset = tsc_offset();
+        if offset == self.loaded_offset {
+            return;
+        }
+
+        if self.config.mode == TscMode::Offsetting {
+            vmwrite(control::TSC_OFFSET_FULL, offset as u64);
+        }
+        self.loaded_offset = offset;
+
+        // A pending deadline of the guest moved further away in the TSC of the processor.
+        if self.guest_deadline != 0 {
+            match tsc_deadline() {
+                0 => self.guest_deadline = 0,
+                _ => set_tsc_deadline(self.host_deadline(self.guest_deadline)),
+            }
+        }
+    }
+
+    /// Returns whether the time spent in VMX root operation is hidden from the guest.
+    fn compensating(&self) -> bool {
+        self.config.compensate_root_time && self.config.mode != TscMode::Native
+    }
+
+    /// Converts cycles of the processor into cycles of the guest.
+    fn scale(&self, cycles: u64) -> u64 {
+        match self.config.mode {
+            TscMode::Native => cycles,
+            TscMode::Offsetting | TscMode::Exiting => {
+                ((cycles as u128 * self.config.multiplier as u128) >> 48) as u64
+            }
+        }
+    }
+
+    /// Converts a deadline of the guest into the TSC of the processor.
+    ///
+    /// # Returns
+    ///
+    /// Zero for zero, which disarms the timer, one for a deadline that has passed already, which fires at once,
+    /// and the first TSC of the processor at which the guest reads at least the deadline otherwise.
+    fn host_deadline(&self, guest_deadline: u64) -> u64 {
+        if guest_deadline == 0 {
+            return 0;
+        }
+
+        let remaining = guest_deadline as i128 - self.offset() as i128;
+        if remaining <= 0 {
+            return 1;
+        }
+
+        let multiplier = match self.config.mode {
+            TscMode::Native => TSC_MULTIPLIER_ONE,
+            TscMode::Offsetting | TscMode::Exiting => self.config.multiplier,
+        };
+
+        // Rounded up, so the timer never fires before the deadline of the guest.
+        let host = ((remaining << 48) + multiplier as i128 - 1) / multiplier.max(1) as i128;
+        u64::try_from(host).unwrap_or(u64::MAX).max(1)
+    }
+
+    /// Writes the offset to the VMCS when the processor applies it.
+    fn write_offset(&mut self) -> Result<(), HypervisorError> {
+        let offset = tsc_offset();
+
+        if self.config.mode == TscMode::Offsetting {
+            try_vmwrite(control::TSC_OFFSET_FULL, offset as u64)?;
+        }
+        self.loaded_offset = offset;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! Handling of Intel Transactional Synchronization Extensions (TSX) under virtualization.
+//!
+//! A VM exit aborts the RTM transaction the guest is in, and the guest resumes at the fallback address of the
+//! transaction with the abort status in EAX. The instructions the hypervisor intercepts (CPUID, RDMSR, WRMSR,
+//! VMCALL, XSETBV, I/O, INVD, GETSEC) abort a transaction on bare metal as well, before they execute, so they
+//! never run transactionally and need no special handling. HLE regions re-execute non-transactionally after an
+//! abort, so they always make progress.
+//!
+//! EPT violations are different: they abort the transaction, the hypervisor changes the EPT, and the guest
+//! retries. A retry succeeds, unless the hypervisor undoes its change before the guest gets there, which is
+//! what switching between the EPT views of a hooked page does when a transaction both executes from and
+//! accesses the page, and what `ThrashStrategy::SingleStep` does by design. A guest retrying its transaction
+//! without bound then livelocks. While RTM is visible to the guest, the single-step strategy is therefore
+//! replaced by disabling the thrashing hook, which lets the retried transaction complete. Without a thrash
+//! policy, nothing breaks the ping-pong, so hooking code that runs in RTM transactions requires either a policy
+//! with `ThrashStrategy::DisableHook` or `TsxPolicy::Disable`.
+//!
+//! With `TsxPolicy::Disable`, the guest runs without TSX entirely:
+//! - HLE and RTM are cleared from CPUID.(EAX=07H,ECX=0):EBX.
+//! - RTM is disabled on every processor through IA32_TSX_CTRL, or forced to always abort through
+//!   IA32_TSX_FORCE_ABORT on processors with the TSX microcode update but without IA32_TSX_CTRL. XBEGIN then
+//!   aborts immediately, so code ignoring CPUID falls back to its non-transactional path.
+//! - Writes of the guest to the control MSR are intercepted and cannot clear the disabling bits.
+//!
+//! The control MSR is restored when the processor is devirtualized.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 16.3 Intel® Transactional
+//! Synchronization Extensions (Intel® TSX) and 2.2 MSRs in the Intel® Core™ Processor Family (IA32_TSX_CTRL).
+
+use {
+    crate::utils::instructions::{rdmsr, wrmsr},
+    x86::cpuid::cpuid,
+};
+
+/// The MSR disabling RTM and hiding TSX from CPUID, enumerated by IA32_ARCH_CAPABILITIES.
+pub const IA32_TSX_CTRL: u32 = 0x122;
+
+/// The MSR forcing RTM to abort, enumerated by CPUID.(EAX=07H,ECX=0):EDX.
+pub const IA32_TSX_FORCE_ABORT: u32 = 0x10F;
+
+/// The MSR enumerating IA32_TSX_CTRL among other capabilities.
+const IA32_ARCH_CAPABILITIES: u32 = 0x10A;
+
+/// IA32_ARCH_CAPABILITIES bit indicating support for IA32_TSX_CTRL.
+const ARCH_CAPABILITIES_TSX_CTRL: u64 = 1 << 7;
+
+/// IA32_TSX_CTRL bit making XBEGIN abort immediately.
+const TSX_CTRL_RTM_DISABLE: u64 = 1 << 0;
+
+/// IA32_TSX_CTRL bit clearing HLE and RTM from CPUID.
+const TSX_CTRL_CPUID_CLEAR: u64 = 1 << 1;
+
+/// IA32_TSX_FORCE_ABORT bit making every RTM transaction abort.
+const TSX_FORCE_ABORT_RTM: u64 = 1 << 0;
+
+/// CPUID.(EAX=07H,ECX=0):EBX bit indicating support for HLE.
+const CPUID_07_EBX_HLE: u32 = 1 << 4;
+
+/// CPUID.(EAX=07H,ECX=0):EBX bit indicating support for RTM.
+const CPUID_07_EBX_RTM: u32 = 1 << 11;
+
+/// CPUID.(EAX=07H,ECX=0):EDX bit indicating support for IA32_TSX_FORCE_ABORT.
+const CPUID_07_EDX_TSX_FORCE_ABORT: u32 = 1 << 13;
+
+/// CPUID.(EAX=07H,ECX=0):EDX bit indicating support for IA32_ARCH_CAPABILITIES.
+const CPUID_07_EDX_ARCH_CAPABILITIES: u32 = 1 << 29;
+
+/// What the guest sees of TSX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TsxPolicy {
+    /// The guest uses TSX as on bare metal.
+    #[default]
+    Passthrough,
+
+    /// TSX is hidden from the guest and RTM is disabled.
+    Disable,
+}
+
+/// The MSR through which RTM is disabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsxControl {
+    /// IA32_TSX_CTRL, which also hides TSX from CPUID.
+    TsxCtrl,
+
+    /// IA32_TSX_FORCE_ABORT, which makes every transaction abort.
+    ForceAbort,
+}
+
+impl TsxControl {
+    /// Returns the index of the MSR.
+    pub fn msr(&self) -> u32 {
+        match self {
+            Self::TsxCtrl => IA32_TSX_CTRL,
+            Self::ForceAbort => IA32_TSX_FORCE_ABORT,
+        }
+    }
+
+    /// Returns the bits of the MSR disabling RTM.
+    fn disabling_bits(&self) -> u64 {
+        match self {
+            Self::TsxCtrl => TSX_CTRL_RTM_DISABLE | TSX_CTRL_CPUID_CLEAR,
+            Self::ForceAbort => TSX_FORCE_ABORT_RTM,
+        }
+    }
+}
+
+/// The TSX support of the processor and the policy applied to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Tsx {
+    /// What the guest sees of TSX.
+    pub policy: TsxPolicy,
+
+    /// Whether the processor supports HLE.
+    pub hle: bool,
+
+    /// Whether the processor supports RTM and it is enabled.
+    pub rtm: bool,
+
+    /// The MSR through which RTM can be disabled, if any.
+    pub control: Option<TsxControl>,
+
+    /// The value of the control MSR before virtualization, restored on devirtualization.
+    original: u64,
+}
+
+impl Tsx {
+    /// Detects the TSX support of the processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - What the guest sees of TSX.
+    pub fn detect(policy: TsxPolicy) -> Self {
+        if cpuid!(0x0).eax < 0x7 {
+            return Self {
+                policy,
+                ..Default::default()
+            };
+        }
+
+        let leaf7 = cpuid!(0x7, 0x0);
+
+        let control = if leaf7.edx & CPUID_07_EDX_ARCH_CAPABILITIES != 0
+            && rdmsr(IA32_ARCH_CAPABILITIES) & ARCH_CAPABILITIES_TSX_CTRL != 0
+        {
+            Some(TsxControl::TsxCtrl)
+        } else if leaf7.edx & CPUID_07_EDX_TSX_FORCE_ABORT != 0 {
+            Some(TsxControl::ForceAbort)
+        } else {
+            None
+        };
+
+        // The firmware or the OS may have disabled RTM through IA32_TSX_CTRL already.
+        let original = control.map_or(0, |control| rdmsr(control.msr()));
+        let rtm_disabled =
+            control == Some(TsxControl::TsxCtrl) && original & TSX_CTRL_RTM_DISABLE != 0;
+
+        Self {
+            policy,
+            hle: leaf7.ebx & CPUID_07_EBX_HLE != 0,
+            rtm: leaf7.ebx & CPUID_07_EBX_RTM != 0 && !rtm_disabled,
+            control,
+            original,
+        }
+    }
+
+    /// Returns whether the guest can run RTM transactions.
+    pub fn guest_rtm(&self) -> bool {
+        self.rtm && self.policy == TsxPolicy::Passthrough
+    }
+
+    /// Disables RTM on the current processor according to the policy. Called on every processor before it
+    /// is virtualized.
+    pub fn apply(&self) {
+        if self.policy != TsxPolicy::Disable {
+            return;
+        }
+
+        if let Some(control) = self.control {
+            wrmsr(control.msr(), self.original | control.disabling_bits());
+        }
+    }
+
+    /// Restores the control MSR of the current processor. Called on every processor when it is devirtualized.
+    pub fn restore(&self) {
+        if self.policy != TsxPolicy::Disable {
+            return;
+        }
+
+        if let Some(control) = self.control {
+            wrmsr(control.msr(), self.original);
+        }
+    }
+
+    /// Hides TSX from the extended feature leaf of CPUID according to the policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `sub_leaf` - The sub-leaf of leaf 7 queried by the guest.
+    /// * `ebx` - EBX of the result, holding the HLE and RTM bits of sub-leaf 0.
+    pub fn filter_cpuid(&self, sub_leaf: u32, ebx: &mut u32) {
+        if self.policy == TsxPolicy::Disable && sub_leaf == 0 {
+            *ebx &= !(CPUID_07_EBX_HLE | CPUID_07_EBX_RTM);
+        }
+    }
+
+    /// Returns the MSR whose guest writes must be intercepted to keep RTM disabled, if any.
+    pub fn intercepted_msr(&self) -> Option<u32> {
+        match self.policy {
+            TsxPolicy::Disable => self.control.map(|control| control.msr()),
+            TsxPolicy::Passthrough => None,
+        }
+    }
+
+    /// Keeps RTM disabled when the guest writes the control MSR.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR written by the guest.
+    /// * `value` - The value written by the guest.
+    ///
+    /// # Returns
+    ///
+    /// The value to write to the MSR, with the disabling bits set if it is the control MSR.
+    pub fn filter_write(&self, msr: u32, value: u64) -> u64 {
+        match self.control {
+            Some(control) if self.policy == TsxPolicy::Disable && control.msr() == msr => {
+                value | control.disabling_bits()
+            }
+            _ => value,
+        }
+    }
+}
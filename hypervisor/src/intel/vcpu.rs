@@ -9,16 +9,24 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            invept::invept_all_contexts, invvpid::invvpid_all_contexts, shared_data::SharedData,
-            support,
+            host_call::{host_call, HostCall},
+            invept::invept_all_contexts,
+            invvpid::invvpid_all_contexts,
+            msr_policy::{MsrPolicy, MsrRule},
+            percpu::VCPUS,
+            platform::CoreCapabilities,
+            preemption_timer::PreemptionTimerCallback,
+            sandbox::{ExecutionReport, Sandbox, SandboxConfig},
+            shared_data::SharedData,
+            single_step::SingleStepCallback,
         },
         utils::{
             capture::CONTEXT,
-            processor::{is_virtualized, set_virtualized},
+            cpu,
+            processor::{clear_virtualized, is_virtualized, set_virtualized, ProcessorExecutor},
         },
     },
-    alloc::boxed::Box,
-    core::cell::OnceCell,
+    alloc::{boxed::Box, vec::Vec},
     core::mem::MaybeUninit,
     wdk_sys::ntddk::RtlCaptureContext,
 };
@@ -28,8 +36,13 @@ pub struct Vcpu {
     /// The processor's unique identifier.
     index: u32,
 
-    /// The VMX instance associated with this VCPU.
-    vmx: OnceCell<Box<Vmx>>,
+    /// The APIC ID of the processor.
+    apic_id: u32,
+
+    /// The VMX instance associated with this VCPU, created when the processor is virtualized.
+    ///
+    /// Only ever accessed from the processor itself, so it needs no synchronization.
+    vmx: Option<Box<Vmx>>,
 }
 
 impl Vcpu {
@@ -38,16 +51,18 @@ impl Vcpu {
     /// # Arguments
     ///
     /// * `index` - Processor's unique identifier.
+    /// * `apic_id` - The APIC ID of the processor.
     ///
     /// # Returns
     ///
     /// A `Result` containing the initialized VCPU instance or a `HypervisorError`.
-    pub fn new(index: u32) -> Result<Self, HypervisorError> {
-        log::trace!("Creating processor {}", index);
+    pub fn new(index: u32, apic_id: u32) -> Result<Self, HypervisorError> {
+        log::trace!("Creating processor {} (APIC ID {:#x})", index, apic_id);
 
         Ok(Self {
             index,
-            vmx: OnceCell::new(),
+            apic_id,
+            vmx: None,
         })
     }
 
@@ -60,7 +75,11 @@ impl Vcpu {
     ///
     /// A `Result` indicating the success or failure of the virtualization process.
     pub fn virtualize_cpu(&mut self, shared_data: &mut SharedData) -> Result<(), HypervisorError> {
-        log::info!("Virtualizing processor {}", self.index);
+        log::debug!(
+            "Virtualizing processor {} (APIC ID {:#x})",
+            self.index,
+            self.apic_id
+        );
 
         // Capture the current processor's context. The Guest will resume from this point since we capture and write this context to the guest state for each vcpu.
         log::trace!("Capturing context");
@@ -74,17 +93,32 @@ impl Vcpu {
         if !is_virtualized() {
             // If we are here as Guest (non-root) then that will lead to undefined behavior (UB).
             log::trace!("Preparing for virtualization");
+
+            // Claim the per-CPU slot of this processor, so its data can be found from VM-exit context.
+            VCPUS.claim(self.index, self as *mut _)?;
+
             set_virtualized();
 
-            self.vmx
-                .get_or_try_init(|| Vmx::new(shared_data, &context))?;
+            // Processors virtualized before this one may already export the capabilities, hence the lock.
+            let capabilities = CoreCapabilities::capture(self.index, self.apic_id);
+            if let Some(slot) = shared_data
+                .core_capabilities
+                .lock()
+                .get_mut(self.index as usize)
+            {
+                *slot = Some(capabilities);
+            }
+
+            if self.vmx.is_none() {
+                self.vmx = Some(Vmx::new(shared_data, &context)?);
+            }
 
-            let vmx = match self.vmx.get_mut() {
-                Some(vmx) => vmx,
-                None => return Err(HypervisorError::VmxNotInitialized),
-            };
+            let vmx = self
+                .vmx
+                .as_mut()
+                .ok_or(HypervisorError::VmxNotInitialized)?;
 
-            log::info!("Virtualization complete for processor {}", self.index);
+            log::debug!("Virtualization complete for processor {}", self.index);
 
             vmx.run(self.index);
 
@@ -100,23 +134,40 @@ impl Vcpu {
     /// already in a non-root operation (devirtualized), the function will return early without performing
     /// the devirtualization again.
     ///
+    /// INVEPT, VMCLEAR and VMXOFF cause VM exits in VMX non-root operation, where this runs. The hypervisor is
+    /// therefore asked with a host call to tear down VMX operation in VMX root operation, see `Vmx::teardown`,
+    /// after which the processor resumes natively right after the call.
+    ///
     /// # Returns
     ///
     /// A `Result` indicating the success or failure of the operation. Returns `Ok(())` if the processor
     /// was successfully devirtualized or was already in a devirtualized state. Returns an `Err` if the
-    /// `vmxoff` operation fails.
+    /// hypervisor did not leave VMX operation.
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 30.3 VMXOFF—Leave VMX Operation.
     /// - Describes the `VMXOFF` instruction which is used to devirtualize a processor.
-    pub fn devirtualize_cpu(&self) -> Result<(), HypervisorError> {
+    pub fn devirtualize_cpu(&mut self) -> Result<(), HypervisorError> {
         // Determine if the processor is already devirtualized.
         if !is_virtualized() {
             log::trace!("Processor {} is already devirtualized", self.index);
             return Ok(());
         }
 
-        // Attempt to devirtualize the processor using the VMXOFF instruction.
-        support::vmxoff()?;
+        let vmx = self
+            .vmx
+            .as_mut()
+            .ok_or(HypervisorError::VmxNotInitialized)?;
+
+        host_call(HostCall::Devirtualize, vmx.shared_data().host_call_key)?;
+
+        if vmx.in_vmx_operation() {
+            log::error!("Processor {} is still in VMX operation", self.index);
+            return Err(HypervisorError::HostCallFailed);
+        }
+
+        // The exit handler released the slot already, as the processor may not exit again.
+        VCPUS.release(self.index);
+        clear_virtualized();
         log::trace!("Processor {} has been devirtualized", self.index);
 
         Ok(())
@@ -131,6 +182,165 @@ impl Vcpu {
         self.index
     }
 
+    /// Retrieves the APIC ID of the processor.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// Returns the VMX instance of this processor.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the VMX instance, or `HypervisorError::VmxNotInitialized` if the processor
+    /// has not been virtualized yet.
+    pub fn vmx(&self) -> Result<&Vmx, HypervisorError> {
+        self.vmx
+            .as_deref()
+            .ok_or(HypervisorError::VmxNotInitialized)
+    }
+
+    /// Returns the VMX instance of this processor mutably.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the VMX instance, or `HypervisorError::VmxNotInitialized` if the processor
+    /// has not been virtualized yet.
+    pub fn vmx_mut(&mut self) -> Result<&mut Vmx, HypervisorError> {
+        self.vmx
+            .as_deref_mut()
+            .ok_or(HypervisorError::VmxNotInitialized)
+    }
+
+    /// Single-steps the guest on this processor with the monitor trap flag, see `intel::single_step`.
+    ///
+    /// Can be called from any processor. Stepping starts when the guest resumes after the next VM exit of the
+    /// processor, and the callback is invoked in VMX root operation after every instruction until it returns
+    /// `StepAction::Stop`. A request replaces the one that has not started yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - Called with the guest registers after every stepped instruction.
+    ///
+    /// # Returns
+    ///
+    /// `HypervisorError::VmxNotInitialized` if the processor is not virtualized.
+    pub fn single_step(&self, callback: SingleStepCallback) -> Result<(), HypervisorError> {
+        self.vmx()?.single_step.request(callback);
+        Ok(())
+    }
+
+    /// Sets the VMX-preemption timer of this processor, to enter VMX root operation periodically, see
+    /// `intel::preemption_timer`.
+    ///
+    /// Can be called from any processor. The timer starts, or stops, when the guest resumes after the next VM exit
+    /// of the processor, and the callback is invoked in VMX root operation every time it expires. A request
+    /// replaces the one that has not been applied yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `ticks` - The period in ticks of the timer, which counts down at the rate given by
+    ///   `cpu::preemption_timer_rate`. Zero stops the timer, otherwise it is at least
+    ///   `preemption_timer::MIN_PREEMPTION_TIMER_TICKS`.
+    /// * `callback` - Called with the guest registers every time the timer expires.
+    ///
+    /// # Returns
+    ///
+    /// `HypervisorError::VmxNotInitialized` if the processor is not virtualized,
+    /// `HypervisorError::PreemptionTimerUnsupported` if its value cannot be saved on VM exits, or
+    /// `HypervisorError::PreemptionTimerPeriodTooShort` if the period is too short.
+    pub fn set_preemption_timer(
+        &self,
+        ticks: u32,
+        callback: PreemptionTimerCallback,
+    ) -> Result<(), HypervisorError> {
+        if !cpu::has_preemption_timer() {
+            return Err(HypervisorError::PreemptionTimerUnsupported);
+        }
+
+        self.vmx()?.preemption_timer.request(ticks, callback)
+    }
+
+    /// Sets what happens to the accesses of the guest to an MSR on this processor, see `intel::msr_policy`.
+    ///
+    /// Can be called from any processor. Denying or emulating the MSR intercepts it in the MSR bitmap, which is
+    /// shared by the processors and takes effect on the next access without any invalidation. Passing it through
+    /// again leaves the bitmap unchanged. The MSRs the hypervisor handles itself are not affected.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR.
+    /// * `policy` - The policy applied to the accesses of the guest to it.
+    ///
+    /// # Returns
+    ///
+    /// `HypervisorError::VmxNotInitialized` if the processor is not virtualized.
+    pub fn set_msr_policy(&mut self, msr: u32, policy: MsrPolicy) -> Result<(), HypervisorError> {
+        let vmx = self.vmx_mut()?;
+
+        // The policy is published before the MSR exits, so no intercepted access sees the previous one.
+        vmx.msr_policies.set(msr, policy);
+        if policy.is_intercepting() {
+            vmx.shared_data().msr_bitmap.intercept_msr(msr, true, true);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the policies of the MSRs that are denied or emulated on this processor, sorted by MSR.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the policies, or `HypervisorError::VmxNotInitialized` if the processor is not
+    /// virtualized.
+    pub fn msr_policies(&self) -> Result<Vec<MsrRule>, HypervisorError> {
+        Ok(self.vmx()?.msr_policies.rules())
+    }
+
+    /// Detonates a code blob in a sandbox on this processor, see `intel::sandbox`.
+    ///
+    /// Must be called at PASSIVE_LEVEL, from any processor: the sandbox is created, execution is switched to this
+    /// processor and the blob runs with a host call, which returns once the run ended. The sandbox is freed
+    /// afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The code blob, executed from its first byte.
+    /// * `config` - The limits of the run.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the report of the run, or `HypervisorError::VmxNotInitialized` if the processor is not
+    /// virtualized, `HypervisorError::SandboxCodeTooLarge` if the blob does not fit into the sandbox or
+    /// `HypervisorError::HostCallFailed` if the blob could not be run.
+    pub fn detonate(
+        &mut self,
+        code: &[u8],
+        config: SandboxConfig,
+    ) -> Result<ExecutionReport, HypervisorError> {
+        let sandbox = Sandbox::new(code, config)?;
+
+        let Some(executor) = ProcessorExecutor::switch_to_processor(self.index) else {
+            return Err(HypervisorError::ProcessorSwitchFailed);
+        };
+
+        if !is_virtualized() {
+            return Err(HypervisorError::VmxNotInitialized);
+        }
+
+        // The exit handlers of this processor only use the sandbox once the host call started it.
+        let vmx = self.vmx_mut()?;
+        vmx.sandbox = Some(sandbox);
+
+        let result = host_call(HostCall::Detonate, vmx.shared_data().host_call_key);
+        let sandbox = vmx.sandbox.take();
+        drop(executor);
+
+        result?;
+        sandbox
+            .and_then(|sandbox| sandbox.report().copied())
+            .ok_or(HypervisorError::SandboxUnavailable)
+    }
+
     /// Invalidates processor contexts to maintain consistency in virtualization environments.
     ///
     /// This function handles the invalidation of TLB and paging-structure caches using the INVVPID and INVEPT
@@ -161,3 +371,36 @@ impl Vcpu {
         log::debug!("Processor contexts invalidation successfully!");
     }
 }
+
+impl Drop for Vcpu {
+    /// Leaves VMX operation on the processor before its VMX structures are freed.
+    ///
+    /// If the processor can't be devirtualized, the VMX structures are leaked rather than freed while the
+    /// processor still uses them.
+    fn drop(&mut self) {
+        if !self.vmx.as_ref().is_some_and(|vmx| vmx.in_vmx_operation()) {
+            return;
+        }
+
+        let result = match ProcessorExecutor::switch_to_processor(self.index) {
+            Some(executor) => {
+                let result = self.devirtualize_cpu();
+                drop(executor);
+                result
+            }
+            None => Err(HypervisorError::ProcessorSwitchFailed),
+        };
+
+        if let Err(err) = result {
+            log::error!(
+                "Failed to devirtualize processor {}: {}, leaking its VMX structures",
+                self.index,
+                err
+            );
+
+            if let Some(vmx) = self.vmx.take() {
+                Box::leak(vmx);
+            }
+        }
+    }
+}
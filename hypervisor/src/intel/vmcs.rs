@@ -9,28 +9,38 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            controls::{adjust_vmx_controls, VmxControl},
+            controls::{
+                adjust_vmx_controls, encls_controls, pause_loop_controls, tsc_controls,
+                VmcsControls, VmxControl,
+            },
             descriptor::DescriptorTables,
+            evmcs::{self, VpAssistPage, EVMCS_VERSION},
             invept::invept_single_context,
             invvpid::{invvpid_single_context, VPID_TAG},
             paging::PageTables,
             segmentation::SegmentDescriptor,
             shared_data::SharedData,
-            support::{vmclear, vmptrld, vmread, vmwrite},
+            support::{try_vmwrite, vmclear, vmptrld, vmread, vmwrite},
             vmerror::ExceptionInterrupt,
+            vmexit::{
+                cr::{cr0_guest_host_mask, cr4_guest_host_mask, cr4_read_shadow},
+                invd::CacheFlushPolicy,
+                mwait::MwaitPolicy,
+            },
         },
         utils::capture::GuestRegisters,
         utils::{
             addresses::PhysicalAddress,
             alloc::{KernelAlloc, PhysicalAllocator},
             capture::CONTEXT,
+            cpu,
         },
     },
 
     // External crate usages
     alloc::boxed::Box,
     bitfield::BitMut,
-    core::fmt,
+    core::{fmt, ptr::NonNull},
     x86::{
         controlregs,
         current::paging::BASE_PAGE_SIZE,
@@ -60,12 +70,17 @@ pub struct Vmcs {
 impl Vmcs {
     /// Sets up the VMCS region.
     ///
+    /// Under Hyper-V, the region holds the enlightened VMCS instead, if the processor can load it, see
+    /// `intel::evmcs`.
+    ///
     /// # Arguments
     /// * `vmcs_region` - A mutable reference to the VMCS region in memory.
     ///
     /// # Returns
-    /// A result indicating success or an error.
-    pub fn setup(vmcs_region: &mut Box<Vmcs, PhysicalAllocator>) -> Result<(), HypervisorError> {
+    /// A result with the VP assist page the enlightened VMCS was loaded through, if any, or an error.
+    pub fn setup(
+        vmcs_region: &mut Box<Vmcs, PhysicalAllocator>,
+    ) -> Result<Option<NonNull<VpAssistPage>>, HypervisorError> {
         log::debug!("Setting up VMCS region");
 
         let vmcs_region_physical_address =
@@ -81,20 +96,28 @@ impl Vmcs {
             vmcs_region_physical_address
         );
 
-        vmcs_region.revision_id = Self::get_vmcs_revision_id();
+        let vp_assist = evmcs::vp_assist_page();
+
+        vmcs_region.revision_id = match vp_assist {
+            Some(_) => EVMCS_VERSION as u32,
+            None => Self::get_vmcs_revision_id(),
+        };
         vmcs_region.as_mut().revision_id.set_bit(31, false);
 
         // Clear the VMCS region.
-        vmclear(vmcs_region_physical_address);
+        vmclear(vmcs_region_physical_address)?;
         log::trace!("VMCLEAR successful!");
 
         // Load current VMCS pointer.
-        vmptrld(vmcs_region_physical_address);
-        log::trace!("VMPTRLD successful!");
+        match vp_assist {
+            Some(vp_assist) => evmcs::load(vmcs_region, vp_assist)?,
+            None => vmptrld(vmcs_region_physical_address)?,
+        }
+        log::trace!("VMCS loaded successfully!");
 
         log::trace!("VMCS setup successfully!");
 
-        Ok(())
+        Ok(vp_assist)
     }
 
     /// Initialize the guest state for the currently loaded VMCS.
@@ -226,7 +249,7 @@ impl Vmcs {
 
         // We can use custom page tables later, this is half implemented.
         let _pml4_pa = host_paging.get_pml4_pa()?;
-        unsafe { vmwrite(vmcs::host::CR3, crate::utils::nt::NTOSKRNL_CR3) };
+        vmwrite(vmcs::host::CR3, crate::utils::nt::NTOSKRNL_CR3.load(core::sync::atomic::Ordering::Acquire));
 
         vmwrite(vmcs::host::CR4, Cr4::read_raw());
 
@@ -273,29 +296,126 @@ impl Vmcs {
     pub fn setup_vmcs_control_fields(shared_data: &mut SharedData) -> Result<(), HypervisorError> {
         log::debug!("Setting up VMCS Control Fields");
 
-        const PRIMARY_CTL: u64 = (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits() | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits()) as u64;
+        const PRIMARY_CTL: u64 = (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits() | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits() | vmcs::control::PrimaryControls::USE_IO_BITMAPS.bits()) as u64;
         const SECONDARY_CTL: u64 = (vmcs::control::SecondaryControls::ENABLE_RDTSCP.bits()
             | vmcs::control::SecondaryControls::ENABLE_XSAVES_XRSTORS.bits()
             | vmcs::control::SecondaryControls::ENABLE_INVPCID.bits()
             | vmcs::control::SecondaryControls::ENABLE_VPID.bits()
             | vmcs::control::SecondaryControls::ENABLE_EPT.bits()) as u64;
-        const ENTRY_CTL: u64 = vmcs::control::EntryControls::IA32E_MODE_GUEST.bits() as u64;
-        const EXIT_CTL: u64 = vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits() as u64;
-        const PINBASED_CTL: u64 = 0;
+        // IA32_DEBUGCTL and DR7 are part of the guest state, so the LBRs of the guest keep recording across VM exits.
+        const ENTRY_CTL: u64 = (vmcs::control::EntryControls::IA32E_MODE_GUEST.bits() | vmcs::control::EntryControls::LOAD_DEBUG_CONTROLS.bits()) as u64;
+        const EXIT_CTL: u64 = (vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits() | vmcs::control::ExitControls::SAVE_DEBUG_CONTROLS.bits()) as u64;
+        // NMIs are intercepted, and reflected through the event queue, only if their blocking can be virtualized.
+        let pinbased_ctl: u64 = if cpu::has_virtual_nmis() {
+            (vmcs::control::PinbasedControls::NMI_EXITING.bits() | vmcs::control::PinbasedControls::VIRTUAL_NMIS.bits()) as u64
+        } else {
+            0
+        };
 
-        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased, PRIMARY_CTL));
-        vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased2, SECONDARY_CTL));
-        vmwrite(vmcs::control::VMENTRY_CONTROLS, adjust_vmx_controls(VmxControl::VmEntry, ENTRY_CTL));
-        vmwrite(vmcs::control::VMEXIT_CONTROLS, adjust_vmx_controls(VmxControl::VmExit, EXIT_CTL));
-        vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::PinBased, PINBASED_CTL));
+        // CR3 loads only exit when they are observed.
+        let primary_ctl = match shared_data.cr3_observer {
+            Some(_) => PRIMARY_CTL | vmcs::control::PrimaryControls::CR3_LOAD_EXITING.bits() as u64,
+            None => PRIMARY_CTL,
+        };
 
-        unsafe {
-            vmwrite(vmcs::control::CR0_READ_SHADOW, controlregs::cr0().bits() as u64);
-            vmwrite(vmcs::control::CR4_READ_SHADOW, Cr4::read_raw());
+        // With INVPCID enabled, INVLPG exiting makes INVPCID exit as well, see `vmexit::invlpg`.
+        let primary_ctl = match shared_data.invlpg_exiting {
+            true => primary_ctl | vmcs::control::PrimaryControls::INVLPG_EXITING.bits() as u64,
+            false => primary_ctl,
+        };
+
+        // HLT only exits when a callback observes it, see `vmexit::hlt`.
+        let primary_ctl = match shared_data.hlt_callback {
+            Some(_) => primary_ctl | vmcs::control::PrimaryControls::HLT_EXITING.bits() as u64,
+            None => primary_ctl,
+        };
+
+        // MONITOR and MWAIT only exit when they are not run natively, see `vmexit::mwait`.
+        let primary_ctl = match shared_data.mwait_policy {
+            MwaitPolicy::Native => primary_ctl,
+            MwaitPolicy::Pause => {
+                primary_ctl | (vmcs::control::PrimaryControls::MONITOR_EXITING.bits() | vmcs::control::PrimaryControls::MWAIT_EXITING.bits()) as u64
+            }
+        };
+
+        // MOV DR and debug exceptions only exit when the hypervisor owns hardware breakpoints, see `debug_registers`.
+        let host_breakpoints = shared_data.host_breakpoints.iter().any(Option::is_some);
+        let primary_ctl = match host_breakpoints {
+            true => primary_ctl | vmcs::control::PrimaryControls::MOV_DR_EXITING.bits() as u64,
+            false => primary_ctl,
+        };
+        let exception_bitmap = match host_breakpoints {
+            true => (1u64 << (ExceptionInterrupt::Breakpoint as u32)) | (1u64 << (ExceptionInterrupt::Debug as u32)),
+            false => 1u64 << (ExceptionInterrupt::Breakpoint as u32),
         };
 
+        // The offset and multiplier are written per processor by `VirtualTsc::load`.
+        let (tsc_primary, tsc_secondary) = tsc_controls(shared_data.tsc_config.mode, shared_data.tsc_config.needs_scaling());
+
+        // The window is written below, once the processor is known to support PAUSE-loop exiting.
+        let pause_secondary = pause_loop_controls(shared_data.pause_loop);
+
+        // The bitmap is written below, once the processor is known to support ENCLS exiting.
+        let encls_secondary = encls_controls(shared_data.encls_exiting);
+
+        // The descriptor-table instructions only exit when the registers are shadowed, see `vmexit::descriptor_table`.
+        let dtable_secondary = match shared_data.descriptor_table_exiting {
+            true => vmcs::control::SecondaryControls::DTABLE_EXITING.bits() as u64,
+            false => 0,
+        };
+
+        // WBINVD only exits when it is skipped, see `vmexit::invd`.
+        let wbinvd_secondary = match shared_data.cache_flush_policy {
+            CacheFlushPolicy::Skip => vmcs::control::SecondaryControls::WBINVD_EXITING.bits() as u64,
+            CacheFlushPolicy::Execute => 0,
+        };
+
+        let controls = VmcsControls {
+            pinbased: adjust_vmx_controls(VmxControl::PinBased, pinbased_ctl)?,
+            primary: adjust_vmx_controls(VmxControl::ProcessorBased, primary_ctl | tsc_primary)?,
+            secondary: adjust_vmx_controls(VmxControl::ProcessorBased2, SECONDARY_CTL | tsc_secondary | pause_secondary | encls_secondary | dtable_secondary | wbinvd_secondary)?,
+            exit: adjust_vmx_controls(VmxControl::VmExit, EXIT_CTL)?,
+            entry: adjust_vmx_controls(VmxControl::VmEntry, ENTRY_CTL)?,
+        };
+
+        try_vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, controls.primary)?;
+        try_vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, controls.secondary)?;
+        try_vmwrite(vmcs::control::VMENTRY_CONTROLS, controls.entry)?;
+        try_vmwrite(vmcs::control::VMEXIT_CONTROLS, controls.exit)?;
+        try_vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, controls.pinbased)?;
+
+        // Reported by the boot report, see `intel::boot_report`.
+        shared_data.vmcs_controls = controls;
+
+        // Processors without PAUSE-loop exiting let PAUSE run, and the window is left unset.
+        if let Some(config) = shared_data.pause_loop {
+            if controls.secondary & vmcs::control::SecondaryControls::PAUSE_LOOP_EXITING.bits() as u64 != 0 {
+                vmwrite(vmcs::control::PLE_GAP, config.gap);
+                vmwrite(vmcs::control::PLE_WINDOW, config.window);
+            } else {
+                log::warn!("PAUSE-loop exiting requested, but not supported by the processor");
+            }
+        }
+
+        // Processors without ENCLS exiting, e.g. without SGX, let ENCLS run, and the bitmap is left unset.
+        if let Some(bitmap) = shared_data.encls_exiting {
+            if controls.secondary & vmcs::control::SecondaryControls::ENCLS_EXITING.bits() as u64 != 0 {
+                vmwrite(vmcs::control::ENCLS_EXITING_BITMAP_FULL, bitmap.0);
+            } else {
+                log::warn!("ENCLS exiting requested, but not supported by the processor");
+            }
+        }
+
+        // The bits fixed by VMX operation are owned by the hypervisor, and the guest reads CR4.VMXE as clear.
+        vmwrite(vmcs::control::CR0_GUEST_HOST_MASK, cr0_guest_host_mask());
+        vmwrite(vmcs::control::CR4_GUEST_HOST_MASK, cr4_guest_host_mask());
+        unsafe { vmwrite(vmcs::control::CR0_READ_SHADOW, controlregs::cr0().bits() as u64) };
+        vmwrite(vmcs::control::CR4_READ_SHADOW, cr4_read_shadow(Cr4::read_raw()));
+
         vmwrite(vmcs::control::MSR_BITMAPS_ADDR_FULL, PhysicalAddress::pa_from_va(shared_data.msr_bitmap.as_ref() as *const _ as _));
-        vmwrite(vmcs::control::EXCEPTION_BITMAP, 1u64 << (ExceptionInterrupt::Breakpoint as u32));
+        vmwrite(vmcs::control::IO_BITMAP_A_ADDR_FULL, PhysicalAddress::pa_from_va(shared_data.io_bitmap.bitmap_a.as_ptr() as _));
+        vmwrite(vmcs::control::IO_BITMAP_B_ADDR_FULL, PhysicalAddress::pa_from_va(shared_data.io_bitmap.bitmap_b.as_ptr() as _));
+        vmwrite(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
 
         vmwrite(vmcs::control::EPTP_FULL, shared_data.primary_eptp);
         vmwrite(vmcs::control::VPID, VPID_TAG);
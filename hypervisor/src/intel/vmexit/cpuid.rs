@@ -1,14 +1,51 @@
 //! Handles CPU-related virtualization tasks, specifically intercepting and managing
 //! the `CPUID` instruction in a VM to control the exposure of CPU features to the guest.
+//!
+//! What the guest learns about the hypervisor through CPUID is configured per processor with `CpuidMasking`,
+//! and can be changed at runtime through `Vmx::set_cpuid_masking`, e.g. to switch a processor between stealth
+//! and testing behavior. The masking of all processors starts out as set with
+//! `HypervisorBuilder::cpuid_masking`, or hides the hypervisor present bit unless the paravirtual interface
+//! is exposed.
+//!
+//! The topology leaves 01H, 04H, 0BH and 1FH are rewritten with the `TopologyConfig` set with
+//! `HypervisorBuilder::cpuid_topology`, if any, see `intel::topology`.
 
 #![allow(dead_code)]
 
 use {
-    crate::{intel::vmexit::ExitType, utils::capture::GuestRegisters},
+    crate::{
+        error::HypervisorError,
+        intel::{
+            paravirt::{CPUID_HYPERVISOR_BASE, CPUID_HYPERVISOR_LIMIT},
+            vmexit::ExitType,
+            vmx::Vmx,
+        },
+        utils::capture::GuestRegisters,
+    },
     bitfield::BitMut,
-    x86::cpuid::cpuid,
+    bitflags::bitflags,
+    x86::{cpuid::cpuid, vmx::vmcs::control::SecondaryControls},
 };
 
+bitflags! {
+    /// What CPUID hides from the guest about the hypervisor.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CpuidMasking: u32 {
+        /// Clears the hypervisor present bit, CPUID.01H:ECX[31].
+        const HYPERVISOR_BIT = 1 << 0;
+
+        /// Answers the hypervisor leaves 0x40000000 to 0x4FFFFFFF as bare metal does, with the data of the
+        /// highest basic leaf, instead of the paravirtual interface.
+        const HYPERVISOR_LEAVES = 1 << 1;
+    }
+}
+
+/// The bit of CPUID.(EAX=07H,ECX=0):EBX indicating support for INVPCID.
+const CPUID_07_EBX_INVPCID_BIT: usize = 10;
+
+/// The range of CPUID leaves reserved for hypervisors.
+const HYPERVISOR_LEAVES: core::ops::RangeInclusive<u32> = 0x40000000..=0x4FFFFFFF;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 /// Enum representing the various CPUID leaves for feature and interface discovery.
 /// Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/feature-discovery
@@ -69,43 +106,53 @@ enum FeatureBits {
 /// # Arguments
 ///
 /// * `registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
 ///
 /// # Returns
 ///
-/// * `ExitType::IncrementRIP` - To move past the `CPUID` instruction in the VM.
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `CPUID` instruction in the VM.
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 10.
 #[rustfmt::skip]
-pub fn handle_cpuid(guest_registers: &mut GuestRegisters) -> ExitType {
+pub fn handle_cpuid(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> Result<ExitType, HypervisorError> {
     log::trace!("Handling CPUID VM exit...");
 
     let leaf = guest_registers.rax as u32;
     let sub_leaf = guest_registers.rcx as u32;
 
+    let masking = vmx.cpuid_masking();
+
     // Execute CPUID instruction on the host and retrieve the result
     let mut cpuid_result = cpuid!(leaf, sub_leaf);
 
+    let paravirt = &vmx.shared_data().paravirt;
+
     log::trace!("Before modification: CPUID Leaf: {:#x}, EAX: {:#x}, EBX: {:#x}, ECX: {:#x}, EDX: {:#x}", leaf, cpuid_result.eax, cpuid_result.ebx, cpuid_result.ecx, cpuid_result.edx);
 
     match leaf {
         // Handle CPUID for standard feature information.
         leaf if leaf == CpuidLeaf::FeatureInformation as u32 => {
             log::trace!("CPUID leaf 1 detected (Standard Feature Information).");
-            // Hide hypervisor presence by setting the appropriate bit in ECX.
-            cpuid_result.ecx.set_bit(FeatureBits::HypervisorPresentBit as usize, false);
+            // Advertise the hypervisor unless its presence is masked on this processor.
+            cpuid_result.ecx.set_bit(FeatureBits::HypervisorPresentBit as usize, !masking.contains(CpuidMasking::HYPERVISOR_BIT));
 
             // Hide VMX support by setting the appropriate bit in ECX.
             cpuid_result.ecx.set_bit(FeatureBits::HypervisorVmxSupportBit as usize, false);
         },
-        // Handle CPUID for hypervisor vendor information.
-        leaf if leaf == CpuidLeaf::HypervisorVendor as u32 => {
-            log::trace!("CPUID leaf 0x40000000 detected (Hypervisor Vendor Information).");
-            // Set the CPUID response to provide the hypervisor's vendor ID signature.
-            // We use the signature "MatrixVisor" encoded in a little-endian format.
-            // cpuid_result.eax = CpuidLeaf::HypervisorInterface as u32; // Maximum supported CPUID leaf range.
-            // cpuid_result.ebx = 0x69727461; // "atri", part of "MatrixVisor" (in reverse order due to little-endian storage).
-            // cpuid_result.ecx = 0x73695678; // "xVis", part of "MatrixVisor" (in reverse order due to little-endian storage).
-            // cpuid_result.edx = 0x0000726f; // "or", the final part of "MatrixVisor" followed by two null bytes (in reverse order).
+        // Answer the masked hypervisor leaves as a processor without a hypervisor does.
+        leaf if masking.contains(CpuidMasking::HYPERVISOR_LEAVES) && HYPERVISOR_LEAVES.contains(&leaf) => {
+            log::trace!("CPUID leaf {:#x} detected (Masked Hypervisor Leaf).", leaf);
+            cpuid_result = cpuid!(cpuid!(CpuidLeaf::VendorInfo as u32).eax, sub_leaf);
+        },
+        // Handle CPUID for the hypervisor leaves when the paravirtual interface is exposed.
+        leaf if paravirt.is_enabled() && (CPUID_HYPERVISOR_BASE..=CPUID_HYPERVISOR_LIMIT).contains(&leaf) => {
+            log::trace!("CPUID leaf {:#x} detected (Paravirtual Interface).", leaf);
+            if let Some(value) = paravirt.cpuid(leaf) {
+                cpuid_result.eax = value.eax;
+                cpuid_result.ebx = value.ebx;
+                cpuid_result.ecx = value.ecx;
+                cpuid_result.edx = value.edx;
+            }
         },
         // Handle CPUID for hypervisor interface identification.
         leaf if leaf == CpuidLeaf::HypervisorInterface as u32 => {
@@ -119,10 +166,20 @@ pub fn handle_cpuid(guest_registers: &mut GuestRegisters) -> ExitType {
         },
         leaf if leaf == CpuidLeaf::ExtendedFeatureInformation as u32 => {
             log::trace!("CPUID leaf 7 detected (Extended Feature Information).");
+            // Hide TSX if the guest runs without it.
+            vmx.shared_data().tsx.filter_cpuid(sub_leaf, &mut cpuid_result.ebx);
+            // INVPCID raises #UD in the guest unless it is enabled in the VMCS, so it is hidden as well.
+            if sub_leaf == 0 && vmx.shared_data().vmcs_controls.secondary & SecondaryControls::ENABLE_INVPCID.bits() as u64 == 0 {
+                cpuid_result.ebx.set_bit(CPUID_07_EBX_INVPCID_BIT, false);
+            }
         },
         _ => { /* Pass through other CPUID leaves unchanged. */ }
     }
 
+    if let (Some(topology), Some(leaves)) = (vmx.shared_data().cpuid_topology, vmx.topology_leaves) {
+        topology.filter_cpuid(&leaves, leaf, sub_leaf, &mut cpuid_result);
+    }
+
     log::trace!("After modification: CPUID Leaf: {:#x}, EAX: {:#x}, EBX: {:#x}, ECX: {:#x}, EDX: {:#x}", leaf, cpuid_result.eax, cpuid_result.ebx, cpuid_result.ecx, cpuid_result.edx);
 
     // Update the guest registers
@@ -133,5 +190,5 @@ pub fn handle_cpuid(guest_registers: &mut GuestRegisters) -> ExitType {
 
     log::trace!("CPUID VMEXIT handled successfully!");
 
-    ExitType::IncrementRIP
+    Ok(ExitType::IncrementRIP)
 }
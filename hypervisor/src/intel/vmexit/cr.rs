@@ -0,0 +1,360 @@
+//! Handles the control-register accesses of the guest: MOV to and from CR0, CR3, CR4 and CR8, CLTS and LMSW.
+//!
+//! VMX operation requires some bits of CR0 and CR4 to hold fixed values, among them CR4.VMXE. The hypervisor owns
+//! these bits, along with the bits the processor does not support, through the guest/host masks:
+//! - Reads of the guest return the read shadows, i.e. the values the guest wrote last, so CR4.VMXE reads as clear.
+//! - Writes changing an owned bit exit. Bits the processor does not support, and CR4.VMXE as VMX is hidden from
+//!   CPUID, raise #GP(0) as on bare metal. Otherwise the read shadow takes the value of the guest and the guest
+//!   register takes it with the fixed bits enforced.
+//! - A guest run from the INIT state with the "unrestricted guest" control, see `vmexit::init`, may clear CR0.PE
+//!   and CR0.PG. Setting or clearing CR0.PG with IA32_EFER.LME set then activates or deactivates IA-32e mode, as
+//!   the processor does.
+//!
+//! CR3-load exiting is optional and enabled with `HypervisorBuilder::cr3_observer`, e.g. to track the processes
+//! of the guest. The handler loads CR3, invalidates the TLB of the guest as the instruction would, and hands the
+//! old and new CR3 to the observer. MOV from CR3 and the CR8 accesses are handled as well, as processors without
+//! the true VMX capability MSRs force their exiting controls on.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.6 Guest/Host Masks and Read
+//! Shadows for CR0 and CR4, 10.8.5 Initializing IA-32e Mode, Table 28-3. Exit Qualification for Control-Register Accesses, and A.7/A.8 VMX-Fixed
+//! Bits in CR0/CR4.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            events::EventInjection,
+            invvpid::{invvpid_single_context, VPID_TAG},
+            support::{try_vmread, try_vmwrite},
+            vmexit::ExitType,
+            vmx::Vmx,
+        },
+        utils::{
+            capture::GuestRegisters,
+            instructions::{cr8, cr8_write, rdmsr},
+        },
+    },
+    x86::{
+        msr::{IA32_VMX_CR0_FIXED0, IA32_VMX_CR0_FIXED1, IA32_VMX_CR4_FIXED0, IA32_VMX_CR4_FIXED1},
+        vmx::vmcs::{
+            control::{self, EntryControls, SecondaryControls},
+            guest, ro,
+        },
+    },
+};
+
+/// Receives the CR3 loads of the guest, in VMX root operation.
+///
+/// # Arguments
+///
+/// * `old_cr3` - The CR3 of the guest before the load.
+/// * `new_cr3` - The CR3 loaded, without the no-flush bit.
+pub type Cr3Observer = fn(old_cr3: u64, new_cr3: u64);
+
+/// CR0.PE, which LMSW cannot clear.
+const CR0_PE: u64 = 1 << 0;
+
+/// CR0.PG, which an unrestricted guest can clear.
+const CR0_PG: u64 = 1 << 31;
+
+/// CR0.TS, cleared by CLTS.
+const CR0_TS: u64 = 1 << 3;
+
+/// The CR0 bits loaded by LMSW: PE, MP, EM and TS.
+const CR0_LMSW_BITS: u64 = 0xF;
+
+/// CR4.PAE, required by IA-32e mode.
+const CR4_PAE: u64 = 1 << 5;
+
+/// IA32_EFER.LME, enabling IA-32e mode once paging is enabled.
+const EFER_LME: u64 = 1 << 8;
+
+/// IA32_EFER.LMA, set while IA-32e mode is active.
+const EFER_LMA: u64 = 1 << 10;
+
+/// CR4.VMXE, required by VMX operation and hidden from the guest.
+const CR4_VMXE: u64 = 1 << 13;
+
+/// CR4.PCIDE, enabling the no-flush bit of MOV to CR3.
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// The bit of a value moved to CR3 keeping the TLB entries of the PCID when CR4.PCIDE is set.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// Returns the CR0 bits owned by the hypervisor: the bits fixed by VMX operation and the unsupported bits.
+pub fn cr0_guest_host_mask() -> u64 {
+    rdmsr(IA32_VMX_CR0_FIXED0) | !rdmsr(IA32_VMX_CR0_FIXED1)
+}
+
+/// Returns the CR4 bits owned by the hypervisor: the bits fixed by VMX operation and the unsupported bits.
+pub fn cr4_guest_host_mask() -> u64 {
+    rdmsr(IA32_VMX_CR4_FIXED0) | !rdmsr(IA32_VMX_CR4_FIXED1)
+}
+
+/// Returns the value of CR4 the guest reads, which does not reveal VMX operation.
+///
+/// # Arguments
+///
+/// * `cr4` - The value of CR4 the processor runs with.
+pub fn cr4_read_shadow(cr4: u64) -> u64 {
+    cr4 & !CR4_VMXE
+}
+
+/// The kind of control-register access, bits 5:4 of the exit qualification.
+enum CrAccessType {
+    MovToCr,
+    MovFromCr,
+    Clts,
+    Lmsw,
+}
+
+/// The exit qualification of a control-register access.
+struct CrAccessQualification {
+    /// The control register, bits 3:0.
+    register: u64,
+
+    /// The kind of access, bits 5:4.
+    access_type: CrAccessType,
+
+    /// The general-purpose register of a MOV, bits 11:8.
+    gpr: u64,
+
+    /// The source operand of LMSW, bits 31:16.
+    lmsw_source: u64,
+}
+
+impl CrAccessQualification {
+    /// Decodes the exit qualification of the current exit.
+    fn read() -> Result<Self, HypervisorError> {
+        let qualification = try_vmread(ro::EXIT_QUALIFICATION)?;
+
+        let access_type = match (qualification >> 4) & 0b11 {
+            0 => CrAccessType::MovToCr,
+            1 => CrAccessType::MovFromCr,
+            2 => CrAccessType::Clts,
+            _ => CrAccessType::Lmsw,
+        };
+
+        Ok(Self {
+            register: qualification & 0xF,
+            access_type,
+            gpr: (qualification >> 8) & 0xF,
+            lmsw_source: (qualification >> 16) & 0xFFFF,
+        })
+    }
+}
+
+/// Handles the control-register access VM exit.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the instruction in the VM.
+/// * `Ok(ExitType::Continue)` - If #GP(0) was injected instead.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 28.
+pub fn handle_cr_access(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling control-register access VM exit...");
+
+    let access = CrAccessQualification::read()?;
+
+    // Writes to CR0, CR3 and CR4 change the guest state, see `entry_recovery`.
+    if !matches!(access.access_type, CrAccessType::MovFromCr) && access.register != 8 {
+        vmx.entry_recovery.preserve(guest_registers)?;
+    }
+
+    match (access.access_type, access.register) {
+        (CrAccessType::MovToCr, 0) => {
+            let value = *gpr(guest_registers, access.gpr)?;
+            write_cr0(value)
+        }
+        (CrAccessType::MovToCr, 3) => {
+            let value = *gpr(guest_registers, access.gpr)?;
+            write_cr3(vmx, value)
+        }
+        (CrAccessType::MovToCr, 4) => {
+            let value = *gpr(guest_registers, access.gpr)?;
+            write_cr4(value)
+        }
+        (CrAccessType::MovToCr, 8) => {
+            cr8_write(*gpr(guest_registers, access.gpr)?);
+            Ok(ExitType::IncrementRIP)
+        }
+        (CrAccessType::MovFromCr, 3) => {
+            let cr3 = try_vmread(guest::CR3)?;
+            store_gpr(guest_registers, access.gpr, cr3)?;
+            Ok(ExitType::IncrementRIP)
+        }
+        (CrAccessType::MovFromCr, 8) => {
+            store_gpr(guest_registers, access.gpr, cr8())?;
+            Ok(ExitType::IncrementRIP)
+        }
+        (CrAccessType::Clts, _) => {
+            let shadow = try_vmread(control::CR0_READ_SHADOW)?;
+            write_cr0(shadow & !CR0_TS)
+        }
+        (CrAccessType::Lmsw, _) => {
+            // LMSW loads the low four bits of CR0, but can set PE only, not clear it.
+            let shadow = try_vmread(control::CR0_READ_SHADOW)?;
+            let value = (shadow & !CR0_LMSW_BITS)
+                | (access.lmsw_source & CR0_LMSW_BITS)
+                | (shadow & CR0_PE);
+            write_cr0(value)
+        }
+        _ => {
+            log::error!("Unsupported control-register access: CR{}", access.register);
+            Err(HypervisorError::UnsupportedControlRegisterAccess)
+        }
+    }
+}
+
+/// Emulates a write of the guest to CR0 that changes a bit owned by the hypervisor.
+fn write_cr0(value: u64) -> Result<ExitType, HypervisorError> {
+    let unrestricted = try_vmread(control::SECONDARY_PROCBASED_EXEC_CONTROLS)?
+        & SecondaryControls::UNRESTRICTED_GUEST.bits() as u64
+        != 0;
+    let fixed0 = match unrestricted {
+        true => rdmsr(IA32_VMX_CR0_FIXED0) & !(CR0_PE | CR0_PG),
+        false => rdmsr(IA32_VMX_CR0_FIXED0),
+    };
+
+    let paging_without_protection = value & CR0_PG != 0 && value & CR0_PE == 0;
+    if value & !rdmsr(IA32_VMX_CR0_FIXED1) != 0
+        || paging_without_protection
+        || (unrestricted && !switch_long_mode(value)?)
+    {
+        log::trace!("Invalid CR0 write: {:#x}", value);
+        EventInjection::vmentry_inject_gp(0)?;
+        return Ok(ExitType::Continue);
+    }
+
+    try_vmwrite(control::CR0_READ_SHADOW, value)?;
+    try_vmwrite(guest::CR0, value | fixed0)?;
+
+    // Such writes are rare, so the TLB of the guest is invalidated as if paging bits had changed.
+    invvpid_single_context(VPID_TAG);
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Activates or deactivates IA-32e mode when the guest sets or clears CR0.PG with IA32_EFER.LME set, as the
+/// processor would have if the write had not exited.
+///
+/// # Arguments
+///
+/// * `value` - The value of CR0 written by the guest.
+///
+/// # Returns
+///
+/// `false` if the write must raise #GP(0) instead, as it enables paging with IA32_EFER.LME set but CR4.PAE clear.
+fn switch_long_mode(value: u64) -> Result<bool, HypervisorError> {
+    let efer = try_vmread(guest::IA32_EFER_FULL)?;
+    let enable = value & CR0_PG != 0;
+    if efer & EFER_LME == 0 || enable == (try_vmread(guest::CR0)? & CR0_PG != 0) {
+        return Ok(true);
+    }
+
+    if enable && try_vmread(guest::CR4)? & CR4_PAE == 0 {
+        return Ok(false);
+    }
+
+    let entry = try_vmread(control::VMENTRY_CONTROLS)?;
+    let ia32e_mode = EntryControls::IA32E_MODE_GUEST.bits() as u64;
+    let (efer, entry) = match enable {
+        true => (efer | EFER_LMA, entry | ia32e_mode),
+        false => (efer & !EFER_LMA, entry & !ia32e_mode),
+    };
+    log::debug!(
+        "Guest IA-32e mode {}",
+        if enable { "activated" } else { "deactivated" }
+    );
+
+    try_vmwrite(guest::IA32_EFER_FULL, efer)?;
+    try_vmwrite(control::VMENTRY_CONTROLS, entry)?;
+    Ok(true)
+}
+
+/// Emulates a write of the guest to CR4 that changes a bit owned by the hypervisor.
+fn write_cr4(value: u64) -> Result<ExitType, HypervisorError> {
+    if value & CR4_VMXE != 0 || value & !rdmsr(IA32_VMX_CR4_FIXED1) != 0 {
+        log::trace!("Invalid CR4 write: {:#x}", value);
+        EventInjection::vmentry_inject_gp(0)?;
+        return Ok(ExitType::Continue);
+    }
+
+    try_vmwrite(control::CR4_READ_SHADOW, value)?;
+    try_vmwrite(guest::CR4, value | rdmsr(IA32_VMX_CR4_FIXED0))?;
+
+    // Such writes are rare, so the TLB of the guest is invalidated as if paging bits had changed.
+    invvpid_single_context(VPID_TAG);
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Emulates a MOV to CR3 and reports it to the observer.
+fn write_cr3(vmx: &mut Vmx, value: u64) -> Result<ExitType, HypervisorError> {
+    let pcide = try_vmread(guest::CR4)? & CR4_PCIDE != 0;
+    let new_cr3 = match pcide {
+        true => value & !CR3_NO_FLUSH,
+        false => value,
+    };
+
+    let old_cr3 = try_vmread(guest::CR3)?;
+    try_vmwrite(guest::CR3, new_cr3)?;
+
+    // The global translations are invalidated as well, which is more than the instruction does, but correct.
+    if !pcide || value & CR3_NO_FLUSH == 0 {
+        invvpid_single_context(VPID_TAG);
+    }
+
+    if let Some(observer) = vmx.shared_data().cr3_observer {
+        observer(old_cr3, new_cr3);
+    }
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Returns the general-purpose register of the guest with the given number, as encoded in exit qualifications.
+pub fn gpr(guest_registers: &mut GuestRegisters, number: u64) -> Result<&mut u64, HypervisorError> {
+    match number {
+        0 => Ok(&mut guest_registers.rax),
+        1 => Ok(&mut guest_registers.rcx),
+        2 => Ok(&mut guest_registers.rdx),
+        3 => Ok(&mut guest_registers.rbx),
+        4 => Ok(&mut guest_registers.rsp),
+        5 => Ok(&mut guest_registers.rbp),
+        6 => Ok(&mut guest_registers.rsi),
+        7 => Ok(&mut guest_registers.rdi),
+        8 => Ok(&mut guest_registers.r8),
+        9 => Ok(&mut guest_registers.r9),
+        10 => Ok(&mut guest_registers.r10),
+        11 => Ok(&mut guest_registers.r11),
+        12 => Ok(&mut guest_registers.r12),
+        13 => Ok(&mut guest_registers.r13),
+        14 => Ok(&mut guest_registers.r14),
+        15 => Ok(&mut guest_registers.r15),
+        _ => Err(HypervisorError::UnsupportedControlRegisterAccess),
+    }
+}
+
+/// Stores a value in a general-purpose register of the guest, RSP being part of the VMCS.
+pub fn store_gpr(
+    guest_registers: &mut GuestRegisters,
+    number: u64,
+    value: u64,
+) -> Result<(), HypervisorError> {
+    *gpr(guest_registers, number)? = value;
+
+    if number == 4 {
+        try_vmwrite(guest::RSP, value)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,345 @@
+//! Handles the instructions of the guest accessing the descriptor-table registers, when descriptor-table exiting
+//! is enabled with `HypervisorBuilder::descriptor_table_exiting`.
+//!
+//! SGDT, SIDT, LGDT, LIDT, SLDT, STR, LLDT and LTR exit, and are emulated against shadows of the GDTR and IDTR,
+//! see `TableShadows`:
+//! - SGDT and SIDT store the shadows, which hold the registers the guest was virtualized with until it loads
+//!   others. A guest comparing the IDTR with the one of a native system ("red pill") sees nothing unusual.
+//! - LGDT and LIDT only load the shadows. The processor keeps running the guest with its tables, so the guest
+//!   cannot relocate them under the hypervisor, e.g. to escape hooks of IDT entries, yet it reads back what it
+//!   loaded.
+//! - SLDT and STR store the selectors of the guest.
+//! - LTR always faults, as the TSS of the guest is busy. LLDT of the current LDT does nothing, any other faults,
+//!   so the guest cannot load a table under the hypervisor this way either.
+//!
+//! The privilege and UMIP checks of the instructions are made by the processor before the VM exit. Faults on the
+//! memory operand are injected as the processor would have raised them. In compatibility mode, the operands of
+//! SGDT, SIDT, LGDT and LIDT are 6 bytes long with a 32-bit base, segment bases other than those of FS and GS
+//! are taken as zero, as in the flat model of the guest, and an operand crossing the 4GB boundary wraps around
+//! to address zero, as linear addresses are 32 bits wide.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM
+//! Exits Conditionally (Descriptor-table exiting), Table 28-10. Format of the VM-Exit Instruction-Information
+//! Field as Used for LIDT, LGDT, SIDT, or SGDT and Table 28-11. Format of the VM-Exit Instruction-Information
+//! Field as Used for LLDT, LTR, SLDT, and STR.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            events::EventInjection,
+            guest_memory::{GuestMemory, GuestPageFault},
+            support::try_vmread,
+            vmexit::{
+                cr::{gpr, store_gpr},
+                invlpg::{guest_la57, is_canonical, memory_operand},
+                ExitType,
+            },
+            vmx::Vmx,
+        },
+        utils::{addresses::Gva, capture::GuestRegisters},
+    },
+    x86::vmx::vmcs::{guest, ro},
+};
+
+/// The L bit of the CS access rights, set in 64-bit mode.
+const ACCESS_RIGHTS_LONG_MODE: u64 = 1 << 13;
+
+/// The size of the linear address space outside of 64-bit mode, where the addresses wrap around.
+const COMPATIBILITY_ADDRESS_SPACE: u64 = 1 << 32;
+
+/// The instruction information bit set for a 32-bit operand size, outside of 64-bit mode.
+const INFO_OPERAND_SIZE_32: u64 = 1 << 11;
+
+/// The instruction information bit set for a register operand of LLDT, LTR, SLDT and STR.
+const INFO_REGISTER_OPERAND: u64 = 1 << 10;
+
+/// The RPL and TI bits of a selector, which are clear in the error code of a #GP.
+const SELECTOR_RPL_TI: u16 = 0b111;
+
+/// The value of a GDTR or IDTR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableRegister {
+    /// The linear address of the table.
+    pub base: u64,
+
+    /// The size of the table in bytes, minus one.
+    pub limit: u16,
+}
+
+/// The GDTR and IDTR the guest sees on a processor.
+pub struct TableShadows {
+    /// The GDTR last loaded by the guest, `None` if it is the one of the guest state.
+    gdtr: Option<TableRegister>,
+
+    /// The IDTR last loaded by the guest, `None` if it is the one of the guest state.
+    idtr: Option<TableRegister>,
+}
+
+impl TableShadows {
+    /// Creates the shadows of a processor whose guest did not load any table.
+    pub const fn new() -> Self {
+        Self {
+            gdtr: None,
+            idtr: None,
+        }
+    }
+
+    /// Returns the GDTR as seen by the guest.
+    pub fn gdtr(&self) -> Result<TableRegister, HypervisorError> {
+        match self.gdtr {
+            Some(gdtr) => Ok(gdtr),
+            None => Ok(TableRegister {
+                base: try_vmread(guest::GDTR_BASE)?,
+                limit: try_vmread(guest::GDTR_LIMIT)? as u16,
+            }),
+        }
+    }
+
+    /// Returns the IDTR as seen by the guest.
+    pub fn idtr(&self) -> Result<TableRegister, HypervisorError> {
+        match self.idtr {
+            Some(idtr) => Ok(idtr),
+            None => Ok(TableRegister {
+                base: try_vmread(guest::IDTR_BASE)?,
+                limit: try_vmread(guest::IDTR_LIMIT)? as u16,
+            }),
+        }
+    }
+}
+
+impl Default for TableShadows {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handles the SGDT, SIDT, LGDT and LIDT VM exits by storing or loading the shadow registers.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the instruction in the VM.
+/// * `Ok(ExitType::Continue)` - If a fault was injected instead.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 46.
+pub fn handle_gdtr_idtr_access(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    let info = try_vmread(ro::VMEXIT_INSTRUCTION_INFO)?;
+    let long_mode = try_vmread(guest::CS_ACCESS_RIGHTS)? & ACCESS_RIGHTS_LONG_MODE != 0;
+    let address = memory_operand(guest_registers, info)?;
+    let guest_memory = GuestMemory::current()?;
+
+    // The limit, followed by an 8-byte base in 64-bit mode, or a 4-byte base otherwise.
+    let mut operand = [0u8; 10];
+    let size = match long_mode {
+        true => 10,
+        false => 6,
+    };
+
+    match (info >> 28) & 0b11 {
+        identity @ (0 | 1) => {
+            let register = match identity {
+                0 => vmx.table_shadows.gdtr()?,
+                _ => vmx.table_shadows.idtr()?,
+            };
+            log::trace!("Handling SGDT/SIDT VM exit, storing {:x?}", register);
+
+            let fields = register
+                .limit
+                .to_le_bytes()
+                .into_iter()
+                .chain(register.base.to_le_bytes());
+            operand
+                .iter_mut()
+                .zip(fields)
+                .for_each(|(byte, field)| *byte = field);
+
+            let stored = operand.get(..size).unwrap_or_default();
+            if let Err(fault) = write_operand(&guest_memory, address, stored, long_mode) {
+                fault.inject(&mut vmx.pending_events)?;
+                return Ok(ExitType::Continue);
+            }
+        }
+        identity => {
+            let loaded = operand.get_mut(..size).unwrap_or_default();
+            if let Err(fault) = read_operand(&guest_memory, address, loaded, long_mode) {
+                fault.inject(&mut vmx.pending_events)?;
+                return Ok(ExitType::Continue);
+            }
+
+            let [limit_low, limit_high, base @ ..] = operand;
+            let limit = u16::from_le_bytes([limit_low, limit_high]);
+            let base = match (long_mode, info & INFO_OPERAND_SIZE_32 != 0) {
+                (true, _) => u64::from_le_bytes(base),
+                (false, true) => u64::from_le_bytes(base) & 0xFFFF_FFFF,
+                // A 16-bit operand size only loads 24 bits of the base.
+                (false, false) => u64::from_le_bytes(base) & 0xFF_FFFF,
+            };
+
+            if !is_canonical(base, guest_la57()?) {
+                EventInjection::vmentry_inject_gp(0)?;
+                return Ok(ExitType::Continue);
+            }
+
+            let register = TableRegister { base, limit };
+            match identity {
+                2 => {
+                    log::debug!("Shadowing the GDTR {:x?} loaded by the guest", register);
+                    vmx.table_shadows.gdtr = Some(register);
+                }
+                _ => {
+                    log::debug!("Shadowing the IDTR {:x?} loaded by the guest", register);
+                    vmx.table_shadows.idtr = Some(register);
+                }
+            }
+        }
+    }
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Handles the SLDT, STR, LLDT and LTR VM exits by storing the selectors, or refusing to load others.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the instruction in the VM.
+/// * `Ok(ExitType::Continue)` - If a fault was injected instead.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 47.
+pub fn handle_ldtr_tr_access(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    let info = try_vmread(ro::VMEXIT_INSTRUCTION_INFO)?;
+    let long_mode = try_vmread(guest::CS_ACCESS_RIGHTS)? & ACCESS_RIGHTS_LONG_MODE != 0;
+    let register_operand = info & INFO_REGISTER_OPERAND != 0;
+    let register = (info >> 3) & 0xF;
+
+    match (info >> 28) & 0b11 {
+        identity @ (0 | 1) => {
+            let selector = match identity {
+                0 => try_vmread(guest::LDTR_SELECTOR)?,
+                _ => try_vmread(guest::TR_SELECTOR)?,
+            } as u16;
+            log::trace!("Handling SLDT/STR VM exit, storing {:#x}", selector);
+
+            // A register operand is zero-extended, a memory operand is always 16 bits.
+            if register_operand {
+                store_gpr(guest_registers, register, u64::from(selector))?;
+            } else {
+                let address = memory_operand(guest_registers, info)?;
+                let guest_memory = GuestMemory::current()?;
+                if let Err(fault) =
+                    write_operand(&guest_memory, address, &selector.to_le_bytes(), long_mode)
+                {
+                    fault.inject(&mut vmx.pending_events)?;
+                    return Ok(ExitType::Continue);
+                }
+            }
+        }
+        identity => {
+            let selector = match register_operand {
+                true => *gpr(guest_registers, register)? as u16,
+                false => {
+                    let address = memory_operand(guest_registers, info)?;
+                    let guest_memory = GuestMemory::current()?;
+                    let mut selector = [0u8; 2];
+                    if let Err(fault) =
+                        read_operand(&guest_memory, address, &mut selector, long_mode)
+                    {
+                        fault.inject(&mut vmx.pending_events)?;
+                        return Ok(ExitType::Continue);
+                    }
+                    u16::from_le_bytes(selector)
+                }
+            };
+
+            // Reloading the current LDT changes nothing, the TSS of the guest is busy and cannot be reloaded.
+            let current = try_vmread(guest::LDTR_SELECTOR)? as u16;
+            if identity == 3 || selector != current {
+                log::debug!("Refusing LLDT/LTR of {:#x} by the guest", selector);
+                EventInjection::vmentry_inject_gp(u32::from(selector & !SELECTOR_RPL_TI))?;
+                return Ok(ExitType::Continue);
+            }
+        }
+    }
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Returns the length of the part of a memory operand below the 4GB boundary, where the linear addresses wrap
+/// around outside of 64-bit mode. The rest of the operand is at address zero.
+///
+/// # Arguments
+///
+/// * `address` - The linear address of the operand, below 4GB outside of 64-bit mode, see `memory_operand`.
+/// * `len` - The length of the operand.
+/// * `long_mode` - Whether the guest runs in 64-bit mode, where the operand never wraps around.
+fn unwrapped_len(address: Gva, len: usize, long_mode: bool) -> usize {
+    match long_mode {
+        true => len,
+        false => COMPATIBILITY_ADDRESS_SPACE
+            .saturating_sub(address.as_u64())
+            .min(len as u64) as usize,
+    }
+}
+
+/// Reads a memory operand of the guest, wrapping around at 4GB outside of 64-bit mode.
+///
+/// # Arguments
+///
+/// * `guest_memory` - The view of guest memory of the guest.
+/// * `address` - The linear address of the operand.
+/// * `buffer` - The buffer receiving the operand.
+/// * `long_mode` - Whether the guest runs in 64-bit mode.
+fn read_operand(
+    guest_memory: &GuestMemory,
+    address: Gva,
+    buffer: &mut [u8],
+    long_mode: bool,
+) -> Result<(), GuestPageFault> {
+    let (low, wrapped) = buffer.split_at_mut(unwrapped_len(address, buffer.len(), long_mode));
+
+    guest_memory.read(address, low)?;
+    if !wrapped.is_empty() {
+        guest_memory.read(Gva::new(0), wrapped)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a memory operand of the guest, wrapping around at 4GB outside of 64-bit mode. Nothing is written if
+/// either part of the operand faults.
+///
+/// # Arguments
+///
+/// * `guest_memory` - The view of guest memory of the guest.
+/// * `address` - The linear address of the operand.
+/// * `data` - The operand.
+/// * `long_mode` - Whether the guest runs in 64-bit mode.
+fn write_operand(
+    guest_memory: &GuestMemory,
+    address: Gva,
+    data: &[u8],
+    long_mode: bool,
+) -> Result<(), GuestPageFault> {
+    let (low, wrapped) = data.split_at(unwrapped_len(address, data.len(), long_mode));
+
+    guest_memory.probe(address, low.len(), true)?;
+    if !wrapped.is_empty() {
+        guest_memory.write(Gva::new(0), wrapped)?;
+    }
+    guest_memory.write(address, low)
+}
@@ -0,0 +1,129 @@
+//! Handles the debug-register accesses of the guest, MOV to and from DR0 to DR7, when MOV-DR exiting is enabled
+//! by a host breakpoint, see `intel::debug_registers`.
+//!
+//! The exceptions of the instruction that take priority over the VM exit are raised by the processor: #GP(0) at
+//! CPL above 0, #UD for DR4 and DR5 with CR4.DE set, and #DB with DR7.GD set. DR4 and DR5 therefore alias DR6
+//! and DR7 here. Writing a 1 to bits 63:32 of DR6 or DR7 raises #GP(0) as on bare metal.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 18.2.2 Debug Registers DR4 and DR5,
+//! 26.1.1 Relative Priority of Faults and VM Exits, and Table 28-4. Exit Qualification for MOV DR.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            debug_registers::DR_UPPER_BITS,
+            events::EventInjection,
+            support::try_vmread,
+            vmexit::{
+                cr::{gpr, store_gpr},
+                ExitType,
+            },
+            vmx::Vmx,
+        },
+        utils::{
+            capture::GuestRegisters,
+            instructions::{dr6, dr6_write},
+        },
+    },
+    x86::vmx::vmcs::ro,
+};
+
+/// The direction of a MOV DR, bit 4 of the exit qualification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DrAccessType {
+    MovToDr,
+    MovFromDr,
+}
+
+/// The exit qualification of a MOV DR, see Table 28-4.
+#[derive(Debug, Clone, Copy)]
+struct DrAccessQualification {
+    /// The debug register, bits 2:0, with DR4 and DR5 aliased to DR6 and DR7.
+    register: u64,
+
+    /// The direction, bit 4.
+    access_type: DrAccessType,
+
+    /// The general-purpose register, bits 11:8.
+    gpr: u64,
+}
+
+impl DrAccessQualification {
+    /// Decodes the exit qualification of the current exit.
+    fn read() -> Result<Self, HypervisorError> {
+        let qualification = try_vmread(ro::EXIT_QUALIFICATION)?;
+
+        let access_type = match (qualification >> 4) & 1 {
+            0 => DrAccessType::MovToDr,
+            _ => DrAccessType::MovFromDr,
+        };
+
+        let register = match qualification & 0b111 {
+            4 => 6,
+            5 => 7,
+            register => register,
+        };
+
+        Ok(Self {
+            register,
+            access_type,
+            gpr: (qualification >> 8) & 0xF,
+        })
+    }
+}
+
+/// Handles the MOV DR VM exit.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the instruction in the VM.
+/// * `Ok(ExitType::Continue)` - If #GP(0) was injected instead.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 29.
+pub fn handle_dr_access(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling debug-register access VM exit...");
+
+    let access = DrAccessQualification::read()?;
+
+    match (access.access_type, access.register) {
+        (DrAccessType::MovFromDr, 6) => store_gpr(guest_registers, access.gpr, dr6())?,
+        (DrAccessType::MovFromDr, 7) => {
+            let dr7 = vmx.debug_registers.guest_dr7()?;
+            store_gpr(guest_registers, access.gpr, dr7)?;
+        }
+        (DrAccessType::MovFromDr, slot) => {
+            let address = vmx.debug_registers.read_address(slot as usize);
+            store_gpr(guest_registers, access.gpr, address)?;
+        }
+        (DrAccessType::MovToDr, register @ (6 | 7)) => {
+            let value = *gpr(guest_registers, access.gpr)?;
+            if value & DR_UPPER_BITS != 0 {
+                EventInjection::vmentry_inject_gp(0)?;
+                return Ok(ExitType::Continue);
+            }
+
+            match register {
+                6 => dr6_write(value),
+                _ => {
+                    vmx.entry_recovery.preserve(guest_registers)?;
+                    vmx.debug_registers.write_dr7(value)?
+                }
+            }
+        }
+        (DrAccessType::MovToDr, slot) => {
+            let value = *gpr(guest_registers, access.gpr)?;
+            vmx.debug_registers.write_address(slot as usize, value);
+        }
+    }
+
+    Ok(ExitType::IncrementRIP)
+}
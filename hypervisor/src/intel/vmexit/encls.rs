@@ -0,0 +1,38 @@
+//! Handles the ENCLS instructions of the guest, when ENCLS exiting is enabled with `HypervisorBuilder::encls_exiting`.
+//!
+//! ENCLS runs the supervisor leaf functions of Intel SGX, selected by EAX. With the "enable ENCLS exiting" control,
+//! the leaves set in the ENCLS-exiting bitmap, `controls::EnclsExitingBitmap`, exit before they execute, and the
+//! others run natively. A leaf that exits cannot be completed by the hypervisor, as the enclave pages are only
+//! accessible to the processor, so it is blocked: the guest gets #GP(0), which SGX drivers already handle as the
+//! failure of the leaf. E.g. `EnclsExitingBitmap::ENCLAVE_CREATION` keeps the guest from building enclaves while
+//! the existing ones keep running.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM
+//! Exits Conditionally (ENCLS), 25.6.16 ENCLS-Exiting Bitmap and Table C-1. Basic Exit Reasons 60.
+
+use crate::{
+    error::HypervisorError,
+    intel::{events::EventInjection, vmexit::ExitType},
+    utils::capture::GuestRegisters,
+};
+
+/// Handles the ENCLS VM exit by blocking the leaf function.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - As #GP(0) is injected instead of executing the leaf.
+pub fn handle_encls(guest_registers: &mut GuestRegisters) -> Result<ExitType, HypervisorError> {
+    log::debug!(
+        "Blocking ENCLS leaf {:#x} at {:#x}",
+        guest_registers.rax as u32,
+        guest_registers.rip
+    );
+
+    EventInjection::vmentry_inject_gp(0)?;
+
+    Ok(ExitType::Continue)
+}
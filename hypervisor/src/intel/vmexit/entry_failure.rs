@@ -0,0 +1,56 @@
+//! Handles VM exits caused by a failed VM entry, see `entry_recovery`.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            entry_recovery::{EntryFailure, Recovery},
+            support::try_vmread,
+            vmerror::VmxBasicExitReason,
+            vmexit::ExitType,
+            vmx::Vmx,
+        },
+        utils::capture::GuestRegisters,
+    },
+    x86::vmx::vmcs::{guest, ro},
+};
+
+/// Handles a failed VM entry by retrying it with the last known-good guest state, or by devirtualizing the
+/// processor once the retries are exhausted.
+///
+/// # Arguments
+///
+/// * `exit_reason` - The exit reason, with the VM-entry failure bit set.
+/// * `guest_registers` - The general-purpose and XMM registers, restored from the snapshot for a retry.
+/// * `vmx` - The VMX instance of the processor.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - To retry the VM entry.
+/// * `Ok(ExitType::ExitHypervisor)` - To leave VMX operation and resume the guest natively.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.8 VM-Entry Failures During
+/// or After Loading Guest State.
+pub fn handle_vmentry_failure(
+    exit_reason: u64,
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling VM-entry failure...");
+
+    let Some(reason) = VmxBasicExitReason::from_u32(exit_reason as u16 as u32) else {
+        log::error!("Unknown VM-entry failure reason: {:#x}", exit_reason);
+        return Err(HypervisorError::UnknownVMExitReason);
+    };
+
+    let failure = EntryFailure {
+        reason,
+        qualification: try_vmread(ro::EXIT_QUALIFICATION)?,
+        rip: try_vmread(guest::RIP)?,
+    };
+
+    match vmx.entry_recovery.recover(failure, guest_registers)? {
+        Recovery::Retry => Ok(ExitType::Continue),
+        Recovery::Devirtualize => Ok(ExitType::ExitHypervisor),
+    }
+}
@@ -1,44 +1,206 @@
 use {
     crate::{
+        error::HypervisorError,
         intel::{
-            invept::invept_all_contexts, support::vmread, support::vmwrite,
-            vmerror::EptViolationExitQualification, vmexit::ExitType, vmx::Vmx,
+            driver_blocker::{DriverEvent, DriverEventKind, STATUS_ACCESS_DENIED},
+            ept::{
+                paging::{AccessType, Ept},
+                policy::{RegionViolation, ViolationResponse},
+                thrashing::ThrashStrategy,
+            },
+            event_queue::{raise_single_step_trap, retire_interrupt_shadow},
+            events::EventInjection,
+            guest_memory::GuestMemory,
+            invept::{
+                invept_all_contexts, invept_all_processors, invept_broadcast, invept_single_context,
+            },
+            shared_data::SharedData,
+            single_step::{set_monitor_trap_flag, SingleStep, StepAction},
+            support::{try_vmread, try_vmwrite, vmread},
+            vmerror::EptViolationExitQualification,
+            vmexit::ExitType,
+            vmx::Vmx,
+        },
+        utils::{
+            addresses::{Gpa, Gva},
+            capture::GuestRegisters,
+            timestamp::Timestamp,
         },
-        utils::{addresses::PhysicalAddress, capture::GuestRegisters},
     },
-    x86::vmx::vmcs,
+    x86::vmx::vmcs::{self, guest},
 };
 
+/// The bits of an EPTP holding the address of the PML4 table.
+#[cfg(feature = "secondary-ept")]
+const EPTP_ADDRESS_MASK: u64 = !0xFFF;
+
+#[cfg(feature = "introspection")]
+use crate::intel::event_queue::PendingEvent;
+#[cfg(feature = "introspection")]
+use crate::intel::fault_injection::{FaultEvent, FaultEventKind, FaultKind};
+#[cfg(feature = "introspection")]
+use crate::intel::guest_memory::GuestPageFault;
+#[cfg(feature = "introspection")]
+use crate::intel::heap_poison::PoisonTouch;
+#[cfg(feature = "introspection")]
+use crate::intel::shared_data::PageAccess;
+#[cfg(feature = "introspection")]
+use crate::intel::vmerror::ExceptionInterrupt;
+#[cfg(feature = "introspection")]
+use crate::utils::processor::current_processor_index;
+#[cfg(feature = "introspection")]
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+/// An EPT violation, passed to the `EptViolationCallback`.
+#[derive(Debug, Clone, Copy)]
+pub struct EptViolation {
+    /// The guest physical address accessed.
+    pub guest_pa: Gpa,
+
+    /// The guest linear address accessed, if the processor reported one.
+    pub guest_la: Option<Gva>,
+
+    /// The kind of access attempted.
+    pub access: AccessType,
+
+    /// The EPT permissions of the page at the time of the access.
+    pub permissions: AccessType,
+
+    /// The name of the permission profile governing the page, if any, see `ept::policy`.
+    pub profile: Option<&'static str>,
+}
+
+/// What the hypervisor does with an EPT violation after the `EptViolationCallback` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptViolationAction {
+    /// The violation is handled as without a callback: by the permission profiles and the hooks.
+    PassThrough,
+
+    /// The page gets the given EPT permissions on all processors, and the access is carried out again. Hooked
+    /// pages keep the permissions of their hooks, and the violation is handled as with `PassThrough`.
+    Grant(AccessType),
+
+    /// #GP(0) is injected instead of carrying out the access.
+    Deny,
+
+    /// The callback carried out the access by updating the registers of the guest, which resumes at the given
+    /// RIP. The VM-exit instruction length is undefined for EPT violations, so the callback decodes the
+    /// instruction itself. As for any emulated instruction, a single-stepping guest takes its trap, and an STI or
+    /// MOV SS shadow ends.
+    Emulated(u64),
+}
+
+/// Implements a custom memory-access policy, in VMX root operation. Set with
+/// `HypervisorBuilder::ept_violation_callback`.
+///
+/// Called for every EPT violation not claimed by the guest agent monitor, the driver blocker, the fault injector
+/// or the coverage, before the permission profiles and the hooks. Violations only happen on pages whose EPT
+/// permissions were restricted, e.g. with `Hypervisor::apply_profile`.
+///
+/// # Arguments
+///
+/// * `violation` - The violation.
+/// * `guest_registers` - The registers of the guest, which the callback may change.
+pub type EptViolationCallback =
+    fn(violation: &EptViolation, guest_registers: &mut GuestRegisters) -> EptViolationAction;
+
 /// Handle VM exits for EPT violations. Violations are thrown whenever an operation is performed on an EPT entry that does not provide permissions to access that page.
 /// 29.3.3.2 EPT Violations
 /// Table 28-7. Exit Qualification for EPT Violations
 #[rustfmt::skip]
-pub fn handle_ept_violation(_guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+pub fn handle_ept_violation(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> Result<ExitType, HypervisorError> {
     log::debug!("Handling EPT Violation VM exit...");
 
-    let guest_physical_address = vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL);
+    let guest_physical_address = Gpa::new(vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL));
     log::debug!("EPT Violation: Guest Physical Address: {:#x}", guest_physical_address);
 
     // Translate the page from a physical address to virtual so we can read its memory.
-    let va = PhysicalAddress::va_from_pa(guest_physical_address);
-    log::debug!("EPT Violation: Guest Virtual Address: {:#x}", va);
+    let va = guest_physical_address.to_hva().unwrap_or_default();
+    log::debug!("EPT Violation: Host Virtual Address: {:#x}", va);
 
     // Log the detailed information about the EPT violation
     let exit_qualification_value = vmread(vmcs::ro::EXIT_QUALIFICATION);
     let ept_violation_qualification = EptViolationExitQualification::from_exit_qualification(exit_qualification_value);
     log::debug!("Exit Qualification for EPT Violations: {}", ept_violation_qualification);
 
-    // If the page is Read/Write, then we need to swap it to the secondary EPTP
-    if ept_violation_qualification.readable && ept_violation_qualification.writable && !ept_violation_qualification.executable {
-        log::trace!("EPT Violation: Execute acccess attempted on Guest Physical Address: {:#x} / Guest Virtual Address: {:#x}", guest_physical_address, va);
+    // The accessing instruction is executed again, re-block NMIs if it is an IRET.
+    vmx.pending_events.restore_virtual_nmi_blocking(ept_violation_qualification.nmi_unblocking_due_to_iret)?;
+
+    // The violation may come from a translation of an entry another processor changed, which is gone now.
+    if vmx.ept_flush.flushed() {
+        return Ok(ExitType::Continue);
+    }
+
+    // A write to a page of the guest agent is a tamper event. Its protection is lifted so the write can complete.
+    if ept_violation_qualification.data_write {
+        let shared_data = vmx.shared_data();
+        if let Some(page) = shared_data.agent_monitor.handle_write(guest_physical_address, guest_registers.rip, &shared_data.debugger) {
+            shared_data.set_page_access(page, AccessType::READ_WRITE_EXECUTE)?;
+            invept_all_processors();
+            return Ok(ExitType::Continue);
+        }
+    }
+
+    if ept_violation_qualification.instruction_fetch {
+        if let Some(exit_type) = handle_blocked_driver(guest_registers, vmx)? {
+            return Ok(exit_type);
+        }
+
+        #[cfg(feature = "introspection")]
+        if let Some(exit_type) = handle_fault_trigger(guest_registers, vmx, guest_physical_address)? {
+            return Ok(exit_type);
+        }
+
+        // The first fetch from a page of the coverage target marks the page as executed for this iteration.
+        #[cfg(feature = "introspection")]
+        {
+            let shared_data = vmx.shared_data();
+            if let Some(page) = shared_data.coverage.record(guest_physical_address) {
+                shared_data.set_page_access(page, AccessType::READ_WRITE_EXECUTE)?;
+                invept_all_processors();
+                return Ok(ExitType::Continue);
+            }
+        }
+    }
+
+    #[cfg(feature = "introspection")]
+    if let Some(exit_type) = handle_poisoned_access(guest_registers, vmx, guest_physical_address, &ept_violation_qualification)? {
+        return Ok(exit_type);
+    }
+
+    if let Some(exit_type) = handle_callback(guest_registers, vmx, guest_physical_address, &ept_violation_qualification)? {
+        return Ok(exit_type);
+    }
+
+    if let Some(exit_type) = handle_region_violation(guest_registers, vmx, guest_physical_address, &ept_violation_qualification)? {
+        return Ok(exit_type);
+    }
+
+    if let Some(exit_type) = handle_hooked_page_write(vmx, guest_physical_address, &ept_violation_qualification)? {
+        return Ok(exit_type);
+    }
+
+    if let Some(exit_type) = handle_thrashing(vmx, guest_physical_address, &ept_violation_qualification)? {
+        return Ok(exit_type);
+    }
+
+    // If the page is Read/Write (or Read-Only for hooks with write sync), then we need to swap it to the secondary EPTP.
+    // Only hooked pages are mapped differently in the secondary EPT, so a fetch from any other page stays in the
+    // primary view.
+    #[cfg(feature = "secondary-ept")]
+    if ept_violation_qualification.instruction_fetch
+        && ept_violation_qualification.readable
+        && !ept_violation_qualification.executable
+        && vmx.shared_data().hook_table.read().hooked_page(guest_physical_address.page_base()).is_some()
+    {
+        log::trace!("EPT Violation: Execute acccess attempted on Guest Physical Address: {:#x} / Host Virtual Address: {:#x}", guest_physical_address, va);
         // Change to the secondary EPTP and invalidate the EPT cache.
         // The hooked page that is Execute-Only will be executed from the secondary EPTP.
         // if Read or Write occurs on that page, then a vmexit will occur
         // and we can swap the page back to the primary EPTP, (original page) with RW permissions.
         let secondary_eptp = unsafe { vmx.shared_data.as_mut().secondary_eptp };
-        vmwrite(vmcs::control::EPTP_FULL, secondary_eptp);
-        invept_all_contexts();
-        //invept_single_context(secondary_eptp);
+        try_vmwrite(vmcs::control::EPTP_FULL, secondary_eptp)?;
+        invalidate_ept(vmx, secondary_eptp);
     }
 
     // If the page is Execute-Only, then we need to swap it back to the primary EPTP
@@ -48,51 +210,705 @@ pub fn handle_ept_violation(_guest_registers: &mut GuestRegisters, vmx: &mut Vmx
         // if Execute occurs on that page, then a vmexit will occur
         // and we can swap the page back to the secondary EPTP, (hooked page) with X permissions.
         let primary_eptp = unsafe { vmx.shared_data.as_mut().primary_eptp };
-        vmwrite(vmcs::control::EPTP_FULL, primary_eptp);
-        invept_all_contexts();
-        //invept_single_context(primary_eptp);
+        try_vmwrite(vmcs::control::EPTP_FULL, primary_eptp)?;
+        invalidate_ept(vmx, primary_eptp);
     }
 
     log::debug!("EPT Violation handled successfully!");
 
     // Do not increment RIP, since we want it to execute the same instruction again.
-    ExitType::Continue
+    Ok(ExitType::Continue)
+}
+
+/// Lets the `EptViolationCallback` answer an EPT violation.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest's register state.
+/// * `vmx` - The VMX instance of the current processor.
+/// * `guest_pa` - The guest physical address accessed.
+/// * `qualification` - The exit qualification of the violation.
+///
+/// # Returns
+///
+/// `Some(ExitType)` if the callback answered the violation, or `None` if there is no callback or it passed the
+/// violation through.
+fn handle_callback(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+    guest_pa: Gpa,
+    qualification: &EptViolationExitQualification,
+) -> Result<Option<ExitType>, HypervisorError> {
+    let Some(callback) = vmx.shared_data().ept_violation_callback else {
+        return Ok(None);
+    };
+
+    let mut access = AccessType::empty();
+    access.set(AccessType::READ, qualification.data_read);
+    access.set(AccessType::WRITE, qualification.data_write);
+    access.set(AccessType::EXECUTE, qualification.instruction_fetch);
+
+    let mut permissions = AccessType::empty();
+    permissions.set(AccessType::READ, qualification.readable);
+    permissions.set(AccessType::WRITE, qualification.writable);
+    permissions.set(AccessType::EXECUTE, qualification.executable);
+
+    let violation = EptViolation {
+        guest_pa,
+        guest_la: match qualification.guest_linear_address_valid {
+            true => Some(Gva::new(try_vmread(vmcs::ro::GUEST_LINEAR_ADDR)?)),
+            false => None,
+        },
+        access,
+        permissions,
+        profile: vmx
+            .shared_data()
+            .ept_policy
+            .lookup(guest_pa)
+            .map(|region| region.profile.name),
+    };
+
+    match callback(&violation, guest_registers) {
+        EptViolationAction::PassThrough => return Ok(None),
+        EptViolationAction::Grant(access) => {
+            let page = guest_pa.page_base();
+            let shared_data = vmx.shared_data();
+
+            // The permissions of a hooked page switch between its views, see `ept::hooks`, so they are left to
+            // the hooks.
+            if shared_data.hook_table.read().hooked_page(page).is_some() {
+                log::trace!("EPT Violation: permissions of hooked page {:#x} not granted", page);
+                return Ok(None);
+            }
+
+            // The permissions may also have been taken away, so no other processor keeps using the previous ones.
+            shared_data.set_page_access(page, access)?;
+            invept_broadcast();
+        }
+        EptViolationAction::Deny => EventInjection::vmentry_inject_gp(0)?,
+        EptViolationAction::Emulated(next_rip) => {
+            guest_registers.rip = next_rip;
+            try_vmwrite(guest::RIP, next_rip)?;
+
+            // The emulated instruction completed, as in `VmExit::advance_guest_rip`.
+            raise_single_step_trap(guest_registers.rflags)?;
+            retire_interrupt_shadow()?;
+        }
+    }
+
+    Ok(Some(ExitType::Continue))
+}
+
+/// Answers an EPT violation on a region protected by a permission profile.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest's register state.
+/// * `vmx` - The VMX instance of the current processor.
+/// * `guest_pa` - The guest physical address accessed.
+/// * `qualification` - The exit qualification of the violation.
+///
+/// # Returns
+///
+/// `Some(ExitType)` if the violation was answered, or `None` if the address is not in a protected region.
+fn handle_region_violation(
+    guest_registers: &GuestRegisters,
+    vmx: &mut Vmx,
+    guest_pa: Gpa,
+    qualification: &EptViolationExitQualification,
+) -> Result<Option<ExitType>, HypervisorError> {
+    let Some(region) = vmx.shared_data().ept_policy.lookup(guest_pa) else {
+        return Ok(None);
+    };
+
+    let mut access = AccessType::empty();
+    access.set(AccessType::READ, qualification.data_read);
+    access.set(AccessType::WRITE, qualification.data_write);
+    access.set(AccessType::EXECUTE, qualification.instruction_fetch);
+
+    vmx.shared_data().ept_policy.record(RegionViolation {
+        guest_pa,
+        rip: guest_registers.rip,
+        profile: region.profile.name,
+        access,
+        response: region.profile.response,
+        timestamp: Timestamp::now(),
+    });
+
+    match region.profile.response {
+        ViolationResponse::Deny => EventInjection::vmentry_inject_gp(0)?,
+        ViolationResponse::Monitor => {
+            // Let the access through for a single instruction, see `handle_monitor_trap_flag`. Only the access
+            // attempted is added to the permissions of the profile, the others stay refused meanwhile.
+            let page = guest_pa.page_base();
+            vmx.shared_data()
+                .set_page_access(page, region.profile.access | access)?;
+            invept_all_processors();
+
+            vmx.region_step = Some(page);
+            set_monitor_trap_flag(true)?;
+        }
+    }
+
+    Ok(Some(ExitType::Continue))
+}
+
+/// Answers an access to a page holding poisoned guest memory, see `intel::heap_poison`.
+///
+/// An access to a poisoned zone is recorded and answered as configured. Any other access to the page is
+/// single-stepped with the permissions the page had before it was poisoned, which are removed again on the
+/// following MTF exit. An access these permissions do not allow is left to the usual handling.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest registers at the access.
+/// * `vmx` - The VMX instance of the current processor.
+/// * `guest_pa` - The guest physical address accessed.
+/// * `qualification` - The exit qualification of the violation.
+///
+/// # Returns
+///
+/// `Some(ExitType)` if the page holds poisoned memory, or `None` if the violation must be handled as usual.
+#[cfg(feature = "introspection")]
+fn handle_poisoned_access(
+    guest_registers: &GuestRegisters,
+    vmx: &mut Vmx,
+    guest_pa: Gpa,
+    qualification: &EptViolationExitQualification,
+) -> Result<Option<ExitType>, HypervisorError> {
+    let heap_poison = &vmx.shared_data().heap_poison;
+    let Some(saved) = heap_poison.saved_access(guest_pa) else {
+        return Ok(None);
+    };
+
+    let mut access = AccessType::empty();
+    access.set(AccessType::READ, qualification.data_read);
+    access.set(AccessType::WRITE, qualification.data_write);
+    access.set(AccessType::EXECUTE, qualification.instruction_fetch);
+
+    // The page did not allow the access before it was poisoned either.
+    if !saved_allows(&saved, access) {
+        return Ok(None);
+    }
+
+    let response = match heap_poison.lookup(guest_pa) {
+        Some(zone) => {
+            let response = heap_poison.response();
+            heap_poison.record(PoisonTouch {
+                zone,
+                guest_pa,
+                guest_la: match qualification.guest_linear_address_valid {
+                    true => Some(Gva::new(try_vmread(vmcs::ro::GUEST_LINEAR_ADDR)?)),
+                    false => None,
+                },
+                access,
+                response,
+                processor: current_processor_index(),
+                cr3: try_vmread(guest::CR3)?,
+                registers: *guest_registers,
+                timestamp: Timestamp::now(),
+            });
+
+            response
+        }
+        // A neighbour of a poisoned zone in the same page.
+        None => ViolationResponse::Monitor,
+    };
+
+    match response {
+        ViolationResponse::Deny => EventInjection::vmentry_inject_gp(0)?,
+        ViolationResponse::Monitor => {
+            // Let the access through for a single instruction, see `handle_monitor_trap_flag`.
+            vmx.shared_data().restore_page_access(saved)?;
+            invept_all_processors();
+
+            vmx.poison_step = Some(saved.guest_pa);
+            set_monitor_trap_flag(true)?;
+        }
+    }
+
+    Ok(Some(ExitType::Continue))
+}
+
+/// Returns whether the permissions a page had before it was poisoned allow an access in one of the EPTs.
+#[cfg(feature = "introspection")]
+fn saved_allows(saved: &PageAccess, access: AccessType) -> bool {
+    #[cfg(feature = "secondary-ept")]
+    if saved.secondary.contains(access) {
+        return true;
+    }
+
+    saved.primary.contains(access)
+}
+
+/// Applies the thrashing strategy to a hooked page switching between the EPT views too often, see
+/// `ept::thrashing`.
+///
+/// # Arguments
+///
+/// * `vmx` - The VMX instance of the current processor.
+/// * `guest_pa` - The guest physical address accessed.
+/// * `qualification` - The exit qualification of the violation.
+///
+/// # Returns
+///
+/// `Some(ExitType)` if the violation was answered, or `None` if the views must be switched as usual.
+fn handle_thrashing(
+    vmx: &mut Vmx,
+    guest_pa: Gpa,
+    qualification: &EptViolationExitQualification,
+) -> Result<Option<ExitType>, HypervisorError> {
+    // A fetch from the read/write view, or a data access from the execute view, of a hooked page.
+    let fetch =
+        qualification.instruction_fetch && qualification.readable && !qualification.executable;
+    let data_access =
+        !qualification.readable && !qualification.writable && qualification.executable;
+
+    if !fetch && !data_access {
+        return Ok(None);
+    }
+
+    let page = guest_pa.page_base();
+    let Some(strategy) = vmx.thrash_detector.record(page) else {
+        return Ok(None);
+    };
+
+    match strategy {
+        // Fetches switch to the execute view as usual, which the page then stays in.
+        ThrashStrategy::SingleStep if fetch => Ok(None),
+        ThrashStrategy::SingleStep => {
+            // Single-step the access in the read/write view, see `handle_monitor_trap_flag`.
+            let primary_eptp = vmx.shared_data().primary_eptp;
+            try_vmwrite(vmcs::control::EPTP_FULL, primary_eptp)?;
+            invalidate_ept(vmx, primary_eptp);
+
+            vmx.view_step = Some(page);
+            set_monitor_trap_flag(true)?;
+
+            Ok(Some(ExitType::Continue))
+        }
+        ThrashStrategy::DisableHook => {
+            if !vmx.shared_data().disable_thrashing_hook(page)? {
+                return Ok(None);
+            }
+            invept_all_processors();
+
+            // The page is executable in the read/write view now, which data accesses still switch to.
+            match fetch {
+                true => Ok(Some(ExitType::Continue)),
+                false => Ok(None),
+            }
+        }
+    }
+}
+
+/// Lets the guest write a hooked page whose writes are propagated to its shadow page, see `ept::hooks`.
+///
+/// The read/write view maps the page read-only. The write is single-stepped with write access, and the bytes
+/// it changed are merged into the shadow page on the following MTF exit.
+///
+/// # Arguments
+///
+/// * `vmx` - The VMX instance of the current processor.
+/// * `guest_pa` - The guest physical address written.
+/// * `qualification` - The exit qualification of the violation.
+///
+/// # Returns
+///
+/// `Some(ExitType)` if the write is single-stepped, or `None` if it is not a write to such a page.
+fn handle_hooked_page_write(
+    vmx: &mut Vmx,
+    guest_pa: Gpa,
+    qualification: &EptViolationExitQualification,
+) -> Result<Option<ExitType>, HypervisorError> {
+    if !qualification.data_write || !qualification.readable || qualification.writable {
+        return Ok(None);
+    }
+
+    let page = guest_pa.page_base();
+    let shared_data = vmx.shared_data();
+    let write_sync = shared_data
+        .hook_table
+        .read()
+        .hooked_page(page)
+        .is_some_and(|hooked| hooked.write_sync);
+    if !write_sync {
+        return Ok(None);
+    }
+
+    // Let the write through for a single instruction, see `handle_monitor_trap_flag`.
+    shared_data
+        .primary_ept
+        .change_page_flags(page, AccessType::READ_WRITE)?;
+    invept_all_processors();
+
+    vmx.hook_write_step = Some(page);
+    set_monitor_trap_flag(true)?;
+
+    Ok(Some(ExitType::Continue))
+}
+
+/// Fails a call into a driver blocked by the `DriverBlocker`, as if the called function returned
+/// `STATUS_ACCESS_DENIED`.
+///
+/// A failed entry point makes the load fail, so the image is about to be unloaded and its pages get back the
+/// permissions they had before it was blocked.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest's register state, at the first instruction of the called function.
+/// * `vmx` - The VMX instance of the current processor.
+///
+/// # Returns
+///
+/// `Some(ExitType)` if the instruction fetch was in a blocked image, or `None` otherwise.
+fn handle_blocked_driver(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<Option<ExitType>, HypervisorError> {
+    let Some(image) = vmx
+        .shared_data()
+        .driver_blocker
+        .find(Gva::new(guest_registers.rip))
+    else {
+        return Ok(None);
+    };
+
+    if !image.active {
+        // Another processor unblocked the image, retry without the stale translations.
+        invept_all_contexts();
+        return Ok(Some(ExitType::Continue));
+    }
+
+    let rip = guest_registers.rip;
+    let memory = GuestMemory::current()?;
+
+    let mut return_address = [0u8; 8];
+    if let Err(fault) = memory.read(Gva::new(guest_registers.rsp), &mut return_address) {
+        fault.inject(&mut vmx.pending_events)?;
+        return Ok(Some(ExitType::Continue));
+    }
+
+    guest_registers.rax = STATUS_ACCESS_DENIED;
+    guest_registers.rip = u64::from_le_bytes(return_address);
+    guest_registers.rsp = guest_registers.rsp.wrapping_add(8);
+    try_vmwrite(guest::RIP, guest_registers.rip)?;
+    try_vmwrite(guest::RSP, guest_registers.rsp)?;
+
+    let shared_data = vmx.shared_data();
+    shared_data.driver_blocker.record(DriverEvent {
+        kind: DriverEventKind::ExecutionBlocked,
+        name: image.name,
+        base: image.base,
+        size: image.size,
+        rip,
+        timestamp: Timestamp::now(),
+    });
+
+    if Gva::new(rip) == image.entry_point && shared_data.driver_blocker.unblock(image.base) {
+        let mut index = 0;
+        while let Some(page) = shared_data.driver_blocker.saved_page(image.base, index) {
+            shared_data.restore_page_access(page)?;
+            index += 1;
+        }
+
+        invept_all_processors();
+    }
+
+    Ok(Some(ExitType::Continue))
+}
+
+/// Injects the fault armed at a guest instruction by the `FaultInjector`.
+///
+/// Other instructions of the page are let through for a single instruction with the permissions the page had
+/// before, see `handle_monitor_trap_flag`. Once the last trigger of the page is spent, the page gets these
+/// permissions back.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest's register state, at the instruction fetched.
+/// * `vmx` - The VMX instance of the current processor.
+/// * `guest_pa` - The guest physical address fetched.
+///
+/// # Returns
+///
+/// `Some(ExitType)` if the page holds a trigger, or `None` otherwise.
+#[cfg(feature = "introspection")]
+fn handle_fault_trigger(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+    guest_pa: Gpa,
+) -> Result<Option<ExitType>, HypervisorError> {
+    let page = guest_pa.page_base();
+    let fault_injector = &vmx.shared_data().fault_injector;
+
+    let Some(saved) = fault_injector.saved_access(page) else {
+        return Ok(None);
+    };
+
+    let rip = guest_registers.rip;
+
+    let Some((kind, released)) = fault_injector.fire(page, Gva::new(rip)) else {
+        // The page did not allow the fetch before the trigger was armed either.
+        if !saved_allows(&saved, AccessType::EXECUTE) {
+            return Ok(None);
+        }
+
+        vmx.shared_data().restore_page_access(saved)?;
+        invept_all_processors();
+
+        vmx.fault_step = Some(page);
+        set_monitor_trap_flag(true)?;
+
+        return Ok(Some(ExitType::Continue));
+    };
+
+    match kind {
+        FaultKind::FailCall { return_value } => {
+            let mut return_address = [0u8; 8];
+            if let Err(fault) =
+                GuestMemory::current()?.read(Gva::new(guest_registers.rsp), &mut return_address)
+            {
+                fault.inject(&mut vmx.pending_events)?;
+                return Ok(Some(ExitType::Continue));
+            }
+
+            guest_registers.rax = return_value;
+            guest_registers.rip = u64::from_le_bytes(return_address);
+            guest_registers.rsp = guest_registers.rsp.wrapping_add(8);
+            try_vmwrite(guest::RIP, guest_registers.rip)?;
+            try_vmwrite(guest::RSP, guest_registers.rsp)?;
+        }
+        FaultKind::PageFault {
+            address,
+            error_code,
+        } => {
+            let fault = GuestPageFault {
+                address,
+                error_code,
+            };
+            fault.inject(&mut vmx.pending_events)?;
+        }
+        FaultKind::MachineCheck => {
+            let machine_check = PendingEvent::exception(ExceptionInterrupt::MachineCheck, None);
+            vmx.pending_events.push(machine_check)?;
+        }
+    }
+
+    let shared_data = vmx.shared_data();
+    shared_data.fault_injector.record(FaultEvent {
+        kind: FaultEventKind::Injected(kind),
+        rip,
+        timestamp: Timestamp::now(),
+    });
+
+    if let Some(released) = released {
+        shared_data.restore_page_access(released)?;
+        invept_all_processors();
+    }
+
+    Ok(Some(ExitType::Continue))
+}
+
+/// Handles a monitor trap flag VM exit, which ends the single-step over an access to a monitored region, over
+/// an instruction sharing its page with a fault trigger, over an access to a page holding poisoned guest memory,
+/// or over a data access to a thrashing hooked page, and
+/// reports the instruction to the callback of a `Vcpu::single_step` in progress.
+///
+/// The protection of the page is restored, unless its region was released or its triggers were spent in the
+/// meantime. After a data access to a thrashing hooked page, the processor returns to the execute view. The
+/// monitor trap flag stays set while the callback continues stepping.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest registers after the stepped instruction.
+/// * `vmx` - The VMX instance of the current processor.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - The protection was restored, or the callback was invoked.
+/// * `Err(HypervisorError::UnhandledVmExit)` - No single-step was in progress.
+pub fn handle_monitor_trap_flag(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling Monitor Trap Flag VM exit...");
+
+    let view_step = vmx.view_step.take();
+    #[cfg(feature = "secondary-ept")]
+    if view_step.is_some() {
+        let secondary_eptp = vmx.shared_data().secondary_eptp;
+        try_vmwrite(vmcs::control::EPTP_FULL, secondary_eptp)?;
+        invalidate_ept(vmx, secondary_eptp);
+    }
+
+    let hook_write_step = vmx.hook_write_step.take();
+    if let Some(page) = hook_write_step {
+        let shared_data = vmx.shared_data();
+        let hook_table = shared_data.hook_table.read();
+        if let Some(hooked) = hook_table.hooked_page(page) {
+            hooked.sync();
+            shared_data
+                .primary_ept
+                .change_page_flags(page, hooked.read_write_view_access())?;
+            invept_all_processors();
+        }
+    }
+
+    let region_step = vmx.region_step.take();
+    if let Some(page) = region_step {
+        let shared_data = vmx.shared_data();
+        if let Some(region) = shared_data.ept_policy.lookup(page) {
+            shared_data.set_page_access(page, region.profile.access)?;
+            invept_broadcast();
+        }
+    }
+
+    let fault_step = vmx.fault_step.take();
+    if let Some(page) = fault_step {
+        protect_fault_page(vmx.shared_data(), page)?;
+    }
+
+    let poison_step = vmx.poison_step.take();
+    if let Some(page) = poison_step {
+        protect_poisoned_page(vmx.shared_data(), page)?;
+    }
+
+    let step = SingleStep::step(guest_registers, vmx);
+    if view_step.is_none()
+        && hook_write_step.is_none()
+        && region_step.is_none()
+        && fault_step.is_none()
+        && poison_step.is_none()
+        && step.is_none()
+    {
+        return Err(HypervisorError::UnhandledVmExit);
+    }
+
+    set_monitor_trap_flag(step == Some(StepAction::Continue))?;
+
+    log::debug!("Monitor Trap Flag VM exit handled successfully!");
+
+    Ok(ExitType::Continue)
+}
+
+/// Removes the execute permission from a page again after a single-step over an instruction of it, unless its
+/// triggers were spent or disarmed in the meantime, which restored its permissions.
+#[cfg(feature = "introspection")]
+fn protect_fault_page(shared_data: &mut SharedData, page: Gpa) -> Result<(), HypervisorError> {
+    if let Some(saved) = shared_data.fault_injector.saved_access(page) {
+        shared_data.restore_page_access(saved.without(AccessType::EXECUTE))?;
+        invept_broadcast();
+    }
+
+    Ok(())
+}
+
+/// Never called without the `introspection` feature, as no fault trigger is armed.
+#[cfg(not(feature = "introspection"))]
+fn protect_fault_page(_shared_data: &mut SharedData, _page: Gpa) -> Result<(), HypervisorError> {
+    Ok(())
+}
+
+/// Removes the permissions of a page again after a single-step over an access to it, unless its poisoned zones
+/// were removed in the meantime, which restored its permissions.
+#[cfg(feature = "introspection")]
+fn protect_poisoned_page(shared_data: &mut SharedData, page: Gpa) -> Result<(), HypervisorError> {
+    if shared_data.heap_poison.is_poisoned(page) {
+        shared_data.set_page_access(page, AccessType::empty())?;
+        invept_broadcast();
+    }
+
+    Ok(())
+}
+
+/// Never called without the `introspection` feature, as no page is poisoned.
+#[cfg(not(feature = "introspection"))]
+fn protect_poisoned_page(_shared_data: &mut SharedData, _page: Gpa) -> Result<(), HypervisorError> {
+    Ok(())
+}
+
+/// Invalidates the EPT derived translations after switching to a new EPTP.
+///
+/// When nested under Hyper-V every INVEPT is emulated by L0, so only the mappings of the
+/// newly active EPTP are flushed. Otherwise all contexts are invalidated.
+///
+/// # Arguments
+///
+/// * `vmx` - The VMX instance of the current processor.
+/// * `eptp` - The EPTP that was switched to.
+fn invalidate_ept(vmx: &mut Vmx, eptp: u64) {
+    if vmx
+        .shared_data()
+        .platform_info
+        .enlightenments
+        .reduced_flush()
+    {
+        invept_single_context(eptp);
+    } else {
+        invept_all_contexts();
+    }
 }
 
 /// Handles an EPT misconfiguration VM exit.
 ///
-/// This function is invoked when an EPT misconfiguration VM exit occurs, indicating
-/// an issue with the Extended Page Tables (EPT) setup. It logs the faulting
-/// guest physical address and triggers a breakpoint exception for immediate debugging.
+/// A misconfiguration means the tables of the hypervisor are broken, so the guest cannot continue. The handler
+/// logs the walk of the EPT in use for the faulting guest physical address, with the bits of every entry and the
+/// problems found in them, and fails with the entry responsible.
 ///
-/// # Safety
+/// # Arguments
 ///
-/// This function executes an `int3` instruction, which triggers a breakpoint exception.
-/// This is used for debugging critical issues and should be employed cautiously.
-/// Appropriate debugging tools must be attached to handle the `int3` exception.
+/// * `vmx` - The VMX instance of the current processor.
 ///
-/// Note: EPT misconfigurations are critical errors that can lead to system instability or crashes.
-/// Continuing normal execution after such an exception is not recommended, as it may result in
-/// unpredictable behavior or a crashed operating system.
+/// # Returns
 ///
-/// Reference: 29.3.3.1 EPT Misconfigurations
-#[rustfmt::skip]
-pub fn handle_ept_misconfiguration() -> ExitType {
+/// * `Err(HypervisorError::EptMisconfiguration)` - With the faulting address, and the level and value of the
+///   entry responsible, or of the leaf entry if no problem was found.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.1 EPT Misconfigurations.
+pub fn handle_ept_misconfiguration(vmx: &mut Vmx) -> Result<ExitType, HypervisorError> {
     log::debug!("Handling EPT Misconfiguration VM exit...");
 
-    // Retrieve the guest physical address that caused the EPT misconfiguration.
-    let guest_physical_address = vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL);
+    let guest_pa = Gpa::new(try_vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL)?);
+    let eptp = try_vmread(vmcs::control::EPTP_FULL)?;
+
+    let shared_data = vmx.shared_data();
+    let ept = current_ept(shared_data, eptp);
+
+    #[cfg(feature = "introspection")]
+    let (level, entry) = {
+        let page = guest_pa.page_base();
+        log::error!(
+            "EPT misconfiguration at {:#x}, EPTP {:#x}:\n{}",
+            guest_pa,
+            eptp,
+            ept.dump(page..page + BASE_PAGE_SIZE as u64)
+        );
+        ept.misconfigured_entry(guest_pa)?
+    };
 
-    // Log the critical error information.
-    log::trace!("EPT Misconfiguration: Faulting guest address: {:#x}. This is a critical error that cannot be safely ignored.", guest_physical_address);
+    // Without the dump, only the entry mapping the page is reported.
+    #[cfg(not(feature = "introspection"))]
+    let (level, entry) = ept.walk(guest_pa)?.leaf();
 
-    // Trigger a breakpoint exception to halt execution for debugging.
-    // Continuing after this point is unsafe due to the potential for system instability.
-    unsafe {  core::arch::asm!("int3") };
+    Err(HypervisorError::EptMisconfiguration {
+        gpa: guest_pa.as_u64(),
+        level,
+        entry: entry.raw(),
+    })
+}
 
-    // Execution should not continue beyond this point.
-    // EPT misconfiguration is a fatal exception and continuing may lead to system crashes.
+/// Returns the EPT an EPTP references.
+#[cfg(feature = "secondary-ept")]
+pub fn current_ept(shared_data: &SharedData, eptp: u64) -> &Ept {
+    match eptp & EPTP_ADDRESS_MASK == shared_data.secondary_eptp & EPTP_ADDRESS_MASK {
+        true => &shared_data.secondary_ept,
+        false => &shared_data.primary_ept,
+    }
+}
 
-    // We may chose to exit the hypervisor here instead of triggering a breakpoint exception.
-    return ExitType::ExitHypervisor;
+/// Returns the EPT an EPTP references, always the primary one without the `secondary-ept` feature.
+#[cfg(not(feature = "secondary-ept"))]
+pub fn current_ept(shared_data: &SharedData, _eptp: u64) -> &Ept {
+    &shared_data.primary_ept
 }
@@ -1,24 +1,34 @@
 //! Module handling VM exits due to exceptions or non-maskable interrupts (NMIs).
-//! It includes handling for various types of exceptions such as page faults,
-//! general protection faults, breakpoints, and invalid opcodes.
+//! Breakpoints are checked against the hooks, debug exceptions against the host breakpoints, and every other
+//! exception is reflected into the guest with its original error code, along with the CR2 of a page fault and the
+//! DR6 of a debug exception, which the processor leaves in the exit qualification. Any exception can therefore be
+//! set in the exception bitmap.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.2.2 Information for VM Exits Due to
+//! Vectored Events, 27.2.1 Basic VM-Exit Information (Table 28-1, exit qualification for debug exceptions) and
+//! 27.6 EVENT INJECTION.
 
 use {
     crate::{
+        error::HypervisorError,
         intel::{
-            ept::hooks::HookType,
+            debug_registers::{BreakpointCondition, BREAKPOINT_SLOTS},
+            event_queue::{ExceptionPayload, PendingEvent, DR6_DEBUG_EXCEPTIONS},
             events::EventInjection,
-            support::{vmread, vmwrite},
-            vmerror::{
-                EptViolationExitQualification, ExceptionInterrupt, VmExitInterruptionInformation,
-            },
+            ipi,
+            support::{try_vmwrite, vmread},
+            vmerror::{ExceptionInterrupt, InterruptionType, VmExitInterruptionInformation},
             vmexit::ExitType,
             vmx::Vmx,
         },
-        utils::capture::GuestRegisters,
+        utils::{addresses::Gva, capture::GuestRegisters},
     },
     x86::vmx::vmcs,
 };
 
+/// RFLAGS.RF, suppressing the instruction breakpoints of the next instruction.
+const RFLAGS_RF: u64 = 1 << 16;
+
 /// Handles exceptions and NMIs that occur during VM execution.
 ///
 /// This function is called when the VM exits due to an exception or NMI.
@@ -32,46 +42,97 @@ use {
 ///
 /// # Returns
 ///
-/// * `ExitType::Continue` - Indicating that VM execution should continue after handling the exception
+/// * `Ok(ExitType::Continue)` - Indicating that VM execution should continue after handling the exception
+/// * `Err(HypervisorError)` - If the exception could not be decoded or reflected into the guest.
 #[rustfmt::skip]
-pub fn handle_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+pub fn handle_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> Result<ExitType, HypervisorError> {
     log::debug!("Handling ExceptionOrNmi VM exit...");
 
     let interruption_info_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
     let interruption_error_code_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE);
 
-    if let Some(interruption_info) = VmExitInterruptionInformation::from_u32(interruption_info_value as u32) {
-        if let Some(exception_interrupt) = ExceptionInterrupt::from_u32(interruption_info.vector.into()) {
-            match exception_interrupt {
-                ExceptionInterrupt::PageFault => {
-                    let exit_qualification_value = vmread(vmcs::ro::EXIT_QUALIFICATION);
-                    let ept_violation_qualification = EptViolationExitQualification::from_exit_qualification(exit_qualification_value);
-                    log::trace!("Exit Qualification for EPT Violations: {}", ept_violation_qualification);
-                    EventInjection::vmentry_inject_pf(interruption_error_code_value as u32);
-                },
-                ExceptionInterrupt::GeneralProtectionFault => {
-                    EventInjection::vmentry_inject_gp(interruption_error_code_value as u32);
-                },
-                ExceptionInterrupt::Breakpoint => {
-                    handle_breakpoint_exception(guest_registers, vmx);
-                },
-                ExceptionInterrupt::InvalidOpcode => {
-                    EventInjection::vmentry_inject_ud();
-                },
-                _ => {
-                    panic!("Unhandled exception: {:?}", exception_interrupt);
-                }
-            }
-        } else {
-            panic!("Invalid Exception Interrupt Vector: {}", interruption_info.vector);
+    let Some(interruption_info) = VmExitInterruptionInformation::from_u32(interruption_info_value as u32) else {
+        log::error!("Invalid VM Exit Interruption Information: {:#x}", interruption_info_value);
+        return Err(HypervisorError::InvalidInterruptionInformation);
+    };
+
+    // An NMI received by the processor while the guest was running is reflected to the guest, unless it is a kick
+    // of another processor, whose flush was done at the start of the VM exit.
+    if interruption_info.interruption_type == InterruptionType::NonMaskableInterrupt {
+        if ipi::take_kick() {
+            return Ok(ExitType::Continue);
         }
-    } else {
-        panic!("Invalid VM Exit Interruption Information");
+        log::trace!("Reflecting NMI");
+        vmx.pending_events.push(PendingEvent::nmi())?;
+        return Ok(ExitType::Continue);
+    }
+
+    // The faulting instruction is executed again, re-block NMIs if it is an IRET.
+    vmx.pending_events.restore_virtual_nmi_blocking(interruption_info.nmi_unblocking_due_to_iret)?;
+
+    let error_code = interruption_error_code_value as u32;
+
+    match ExceptionInterrupt::from_u32(interruption_info.vector.into()) {
+        Some(ExceptionInterrupt::Breakpoint) => {
+            handle_breakpoint_exception(guest_registers, vmx)?;
+        },
+        Some(ExceptionInterrupt::PageFault) => {
+            // CR2 is not loaded by a page fault causing a VM exit, the faulting address is in the exit qualification.
+            let address = Gva::new(vmread(vmcs::ro::EXIT_QUALIFICATION));
+            reflect_exception(vmx, interruption_info_value as u32, error_code, Some(ExceptionPayload::PageFault(address)))?;
+        },
+        Some(ExceptionInterrupt::Debug) => {
+            // Neither is DR6 by a debug exception, the bits it would have set are in the exit qualification.
+            let exceptions = vmread(vmcs::ro::EXIT_QUALIFICATION) & DR6_DEBUG_EXCEPTIONS;
+            let exceptions = handle_host_breakpoints(guest_registers, vmx, exceptions)?;
+            if exceptions != 0 {
+                reflect_exception(vmx, interruption_info_value as u32, error_code, Some(ExceptionPayload::Debug(exceptions)))?;
+            }
+        },
+        _ => reflect_exception(vmx, interruption_info_value as u32, error_code, None)?,
     }
 
     log::debug!("Exception Handled successfully!");
 
-    ExitType::Continue
+    Ok(ExitType::Continue)
+}
+
+/// Reflects the exception that caused the VM exit into the guest, as if it had been delivered without exiting.
+///
+/// The exception is queued with its original type, vector, error code and, for software exceptions, instruction
+/// length, so an exception raised while delivering another event is merged into a double fault by the event queue.
+///
+/// # Arguments
+///
+/// * `vmx` - The VMX instance of the processor.
+/// * `interruption_info` - The VM-exit interruption information.
+/// * `error_code` - The VM-exit interruption error code, used if the information says one is delivered.
+/// * `payload` - The CR2 or DR6 the exception loads when it is injected, if any.
+///
+/// # Returns
+///
+/// A `Result` which is `Err` if the exception could not be decoded or queued.
+fn reflect_exception(
+    vmx: &mut Vmx,
+    interruption_info: u32,
+    error_code: u32,
+    payload: Option<ExceptionPayload>,
+) -> Result<(), HypervisorError> {
+    let instruction_len = vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN) as u32;
+
+    let Some(event) =
+        PendingEvent::from_interruption_info(interruption_info, error_code, instruction_len)
+    else {
+        log::error!(
+            "Invalid VM Exit Interruption Information: {:#x}",
+            interruption_info
+        );
+        return Err(HypervisorError::InvalidInterruptionInformation);
+    };
+    let event = PendingEvent { payload, ..event };
+
+    log::trace!("Reflecting {:?}", event);
+    vmx.pending_events.push(event)
 }
 
 /// Handles breakpoint (`#BP`) exceptions specifically.
@@ -84,10 +145,19 @@ pub fn handle_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) ->
 ///
 /// * `guest_registers` - A mutable reference to the guest's current register state.
 /// * `vmx` - A mutable reference to the Vmx structure.
-fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) {
+///
+/// # Returns
+///
+/// A `Result` which is `Err` if the guest state could not be updated.
+fn handle_breakpoint_exception(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<(), HypervisorError> {
     log::debug!("Breakpoint Exception");
 
-    let hook_manager = unsafe { vmx.shared_data.as_mut().hook_manager.as_mut() };
+    let shared_data = vmx.shared_data();
+    let hook_table = shared_data.hook_table.read();
+    let hooks_suspended = shared_data.debugger.hooks_suspended();
 
     log::trace!("Finding hook for RIP: {:#x}", guest_registers.rip);
 
@@ -95,29 +165,68 @@ fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vmx: &mut V
     // transfer the execution to it. If we couldn't find a hook, we inject the
     // #BP exception.
     //
-    if let Some(Some(handler)) =
-        hook_manager
-            .find_hook_by_address(guest_registers.rip)
-            .map(|hook| {
-                log::trace!("Found hook for RIP: {:#x}", guest_registers.rip);
-                if let HookType::Function { inline_hook } = &hook.hook_type {
-                    log::trace!("Getting handler address");
-                    Some(inline_hook.handler_address())
-                } else {
-                    None
-                }
-            })
+    if let Some(handler) = hook_table
+        .lookup(Gva::new(guest_registers.rip))
+        .map(|entry| {
+            log::trace!("Found hook for RIP: {:#x}", guest_registers.rip);
+            entry.target(guest_registers, hooks_suspended)
+        })
+        .transpose()?
     {
         // Call our hook handle function (it will automatically call trampoline).
         log::trace!("Transferring execution to handler: {:#x}", handler);
-        guest_registers.rip = handler;
-        vmwrite(vmcs::guest::RIP, guest_registers.rip);
+        guest_registers.rip = handler.as_u64();
+        try_vmwrite(vmcs::guest::RIP, guest_registers.rip)?;
 
         log::debug!("Breakpoint (int3) hook handled successfully!");
     } else {
-        EventInjection::vmentry_inject_bp();
+        shared_data.debugger.record_foreign_breakpoint();
+        EventInjection::vmentry_inject_bp()?;
         log::debug!("Breakpoint exception handled successfully!");
     };
+
+    Ok(())
+}
+
+/// Invokes the callbacks of the host breakpoints that triggered a debug (`#DB`) exception, see
+/// `intel::debug_registers`.
+///
+/// An execution breakpoint is a fault, the guest resumes with RFLAGS.RF set so the instruction runs once
+/// without triggering it again.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+/// * `exceptions` - The debug exceptions of the exit qualification, in the DR6 format.
+///
+/// # Returns
+///
+/// A `Result` containing the debug exceptions the guest takes, zero if there are none, or an error if the guest
+/// RFLAGS could not be written.
+fn handle_host_breakpoints(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+    exceptions: u64,
+) -> Result<u64, HypervisorError> {
+    let hits = vmx.debug_registers.host_hits(exceptions);
+
+    for slot in (0..BREAKPOINT_SLOTS).filter(|slot| hits & (1 << slot) != 0) {
+        let Some(breakpoint) = vmx.debug_registers.host_breakpoint(slot) else {
+            continue;
+        };
+
+        log::trace!("Host breakpoint {} hit at {:#x}", slot, guest_registers.rip);
+
+        if breakpoint.condition == BreakpointCondition::Execute {
+            guest_registers.rflags |= RFLAGS_RF;
+            try_vmwrite(vmcs::guest::RFLAGS, guest_registers.rflags)?;
+        }
+
+        (breakpoint.callback)(guest_registers, vmx, slot);
+    }
+
+    Ok(vmx.debug_registers.guest_exceptions(exceptions))
 }
 
 /// Handles undefined opcode (`#UD`) exceptions.
@@ -127,13 +236,13 @@ fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vmx: &mut V
 ///
 /// # Returns
 ///
-/// * `ExitType::Continue` - Indicating that VM execution should continue.
-pub fn handle_undefined_opcode_exception() -> ExitType {
+/// * `Ok(ExitType::Continue)` - Indicating that VM execution should continue.
+pub fn handle_undefined_opcode_exception() -> Result<ExitType, HypervisorError> {
     log::debug!("Undefined Opcode Exception");
 
-    EventInjection::vmentry_inject_ud();
+    EventInjection::vmentry_inject_ud()?;
 
     log::debug!("Undefined Opcode Exception handled successfully!");
 
-    ExitType::Continue
+    Ok(ExitType::Continue)
 }
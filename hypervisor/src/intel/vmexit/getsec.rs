@@ -0,0 +1,107 @@
+//! Handles VM exits caused by the GETSEC instruction.
+//!
+//! GETSEC exits unconditionally in VMX non-root operation. The hypervisor does not expose Safer Mode
+//! Extensions (SMX) to the guest, so GETSEC is emulated the way a processor without a TXT-capable chipset
+//! behaves: #UD while CR4.SMXE is clear, capability and parameter queries report nothing, and the leaves
+//! that would enter or control a measured environment raise #GP(0).
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Chapter 7 SAFER MODE
+//! EXTENSIONS REFERENCE, 7.3 GETSEC LEAF FUNCTIONS.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{events::EventInjection, support::try_vmread, vmexit::ExitType},
+        utils::capture::GuestRegisters,
+    },
+    x86::{controlregs::Cr4, vmx::vmcs::guest},
+};
+
+/// The GETSEC leaf functions selected by EAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GetsecLeaf {
+    Capabilities,
+    EnterAccs,
+    ExitAc,
+    Senter,
+    Sexit,
+    Parameters,
+    SmCtrl,
+    Wakeup,
+}
+
+impl GetsecLeaf {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Capabilities),
+            2 => Some(Self::EnterAccs),
+            3 => Some(Self::ExitAc),
+            4 => Some(Self::Senter),
+            5 => Some(Self::Sexit),
+            6 => Some(Self::Parameters),
+            7 => Some(Self::SmCtrl),
+            8 => Some(Self::Wakeup),
+            _ => None,
+        }
+    }
+}
+
+/// Handles a GETSEC VM exit.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's register state.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - For the query leaves, which complete with their results in the guest registers.
+/// * `Ok(ExitType::Continue)` - If an exception was injected instead.
+pub fn handle_getsec(guest_registers: &mut GuestRegisters) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling GETSEC VM exit...");
+
+    let guest_cr4 = Cr4::from_bits_truncate(try_vmread(guest::CR4)? as usize);
+
+    // GETSEC raises #UD unless SMX operation is enabled in CR4.
+    if !guest_cr4.contains(Cr4::CR4_ENABLE_SMX) {
+        log::trace!("GETSEC with CR4.SMXE clear, injecting #UD");
+        EventInjection::vmentry_inject_ud()?;
+        return Ok(ExitType::Continue);
+    }
+
+    let leaf = guest_registers.rax as u32;
+
+    let Some(leaf) = GetsecLeaf::from_u32(leaf) else {
+        log::trace!("GETSEC with undefined leaf {:#x}, injecting #UD", leaf);
+        EventInjection::vmentry_inject_ud()?;
+        return Ok(ExitType::Continue);
+    };
+
+    log::trace!("GETSEC[{:?}] index {:#x}", leaf, guest_registers.rbx as u32);
+
+    match leaf {
+        // EAX = 0 reports that no TXT-capable chipset is present and that no leaf is available.
+        GetsecLeaf::Capabilities => {
+            guest_registers.rax = 0;
+        }
+        // A parameter type of 0 (NULL) terminates the parameter list.
+        GetsecLeaf::Parameters => {
+            guest_registers.rax = 0;
+            guest_registers.rbx = 0;
+            guest_registers.rcx = 0;
+        }
+        // Without a measured environment, the remaining leaves fail like on a processor without a chipset.
+        GetsecLeaf::EnterAccs
+        | GetsecLeaf::ExitAc
+        | GetsecLeaf::Senter
+        | GetsecLeaf::Sexit
+        | GetsecLeaf::SmCtrl
+        | GetsecLeaf::Wakeup => {
+            EventInjection::vmentry_inject_gp(0)?;
+            return Ok(ExitType::Continue);
+        }
+    }
+
+    log::debug!("GETSEC VM exit handled successfully!");
+
+    Ok(ExitType::IncrementRIP)
+}
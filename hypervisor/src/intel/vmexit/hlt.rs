@@ -0,0 +1,69 @@
+//! Handles the HLT instructions of the guest, when HLT exiting is enabled with `HypervisorBuilder::hlt_exiting`.
+//!
+//! The handler completes the instruction on behalf of the guest and invokes the `HltCallback` in VMX root
+//! operation, e.g. to account for the idle time of the guest or to schedule other work on the processor while it
+//! is idle. The callback decides how the guest resumes after the HLT:
+//! - `HltAction::Halt` resumes it in the HLT activity state, so the processor halts in VMX non-root operation until
+//!   an interrupt, NMI or SMI wakes it up, as it would have without the exit. Processors without the HLT activity
+//!   state, or with an event queued for injection, resume the guest instead.
+//! - `HltAction::Resume` resumes it at the next instruction right away, as if it had been woken up.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.4.2 Guest Non-Register State,
+//! 26.6.2 Activity State and Table C-1. Basic Exit Reasons 12.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{support::try_vmwrite, vmexit::ExitType, vmx::Vmx},
+        utils::{capture::GuestRegisters, cpu},
+    },
+    x86::vmx::vmcs::guest,
+};
+
+/// The HLT guest activity state.
+const ACTIVITY_STATE_HLT: u64 = 1;
+
+/// How the guest resumes after a HLT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HltAction {
+    /// Halt until the next interrupt, NMI or SMI.
+    Halt,
+
+    /// Run the next instruction right away.
+    Resume,
+}
+
+/// Called in VMX root operation on every HLT of the guest, with the guest registers, `GuestRegisters::rip` being
+/// the HLT instruction. Set with `HypervisorBuilder::hlt_exiting`.
+pub type HltCallback = fn(&mut GuestRegisters, &mut Vmx) -> HltAction;
+
+/// Handles the HLT VM exit by invoking the callback and resuming the guest after the instruction.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `HLT` instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 12.
+pub fn handle_hlt(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::trace!("Handling HLT VM exit at {:#x}", guest_registers.rip);
+
+    let action = match vmx.shared_data().hlt_callback {
+        Some(callback) => callback(guest_registers, vmx),
+        None => HltAction::Halt,
+    };
+
+    // Only some events can be injected into a halted guest, a queued event is delivered before it halts again.
+    if action == HltAction::Halt && cpu::has_hlt_activity_state() && vmx.pending_events.is_empty() {
+        try_vmwrite(guest::ACTIVITY_STATE, ACTIVITY_STATE_HLT)?;
+    }
+
+    Ok(ExitType::IncrementRIP)
+}
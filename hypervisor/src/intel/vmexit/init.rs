@@ -0,0 +1,291 @@
+//! Handles the INIT signals and start-up IPIs (SIPIs) received by the guest, so application processors can be
+//! started while they are virtualized, e.g. when the hypervisor is loaded from a UEFI or boot context before the
+//! operating system starts them.
+//!
+//! INIT always exits in VMX non-root operation, with nothing done to the processor. The handler carries out the
+//! INIT on the guest state instead: the registers take their values of the INIT state, the guest runs in real
+//! mode without paging, and it waits for a SIPI in the wait-for-SIPI activity state. A SIPI then exits, and the
+//! handler starts the guest in real mode at the page given by the vector, as the processor would have.
+//!
+//! Running the guest in real mode requires the "unrestricted guest" control, and the IA32_EFER of the INIT state
+//! is only loaded on VM entry with the "load IA32_EFER" control. Both are enabled on the processor by its first
+//! INIT, see `enter_init_state`, and stay enabled: the IA32_EFER of the guest is then saved on every VM exit and
+//! the one of the host loaded, as the guest may leave IA32_EFER.NXE clear while the host relies on it.
+//!
+//! The x87, SSE and AVX state, the MTRRs and the MSRs other than IA32_EFER are left to the guest, as the
+//! processor leaves them on INIT. Processors without these controls or the wait-for-SIPI activity state fail
+//! with `HypervisorError::WaitForSipiUnsupported`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 10.1.1 Processor State After Reset
+//! (Table 10-1), 26.2 Other Causes of VM Exits (INIT signals, Start-up IPIs), 26.6.2 Activity State and 27.3.1.1
+//! Checks on Guest Control Registers, Debug Registers, and MSRs (Unrestricted guest).
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            invvpid::{invvpid_single_context, VPID_TAG},
+            support::{try_vmread, try_vmwrite},
+            vmexit::{cr::cr4_read_shadow, descriptor_table::TableShadows, ExitType},
+            vmx::Vmx,
+        },
+        utils::{capture::GuestRegisters, cpu, instructions::rdmsr},
+    },
+    x86::{
+        cpuid::cpuid,
+        msr::{IA32_EFER, IA32_VMX_CR0_FIXED0, IA32_VMX_CR4_FIXED0},
+        vmx::vmcs::{
+            control::{self, EntryControls, ExitControls, SecondaryControls},
+            guest, host, ro,
+        },
+    },
+};
+
+/// The wait-for-SIPI guest activity state.
+const ACTIVITY_STATE_WAIT_FOR_SIPI: u64 = 3;
+
+/// The active guest activity state.
+const ACTIVITY_STATE_ACTIVE: u64 = 0;
+
+/// CR0.PE, CR0.PG, clear in the INIT state, and the only fixed bits the unrestricted guest is allowed to clear.
+const CR0_PE_PG: u64 = (1 << 0) | (1 << 31);
+
+/// CR0.CD and CR0.NW, which are left unchanged by INIT.
+const CR0_CD_NW: u64 = (1 << 30) | (1 << 29);
+
+/// CR0.ET, set in the INIT state.
+const CR0_ET: u64 = 1 << 4;
+
+/// RFLAGS in the INIT state, with only the reserved bit 1 set.
+const RFLAGS_INIT: u64 = 1 << 1;
+
+/// RIP in the INIT state, the reset vector below the CS base.
+const RIP_INIT: u64 = 0xFFF0;
+
+/// DR7 in the INIT state.
+const DR7_INIT: u64 = 0x400;
+
+/// The limit of the segments and descriptor tables in the INIT state.
+const REAL_MODE_LIMIT: u64 = 0xFFFF;
+
+/// The access rights of CS in the INIT state: present, accessed, readable code.
+const ACCESS_RIGHTS_CODE: u64 = 0x9B;
+
+/// The access rights of the data segments in the INIT state: present, accessed, writable data.
+const ACCESS_RIGHTS_DATA: u64 = 0x93;
+
+/// The access rights of LDTR in the INIT state: present, LDT.
+const ACCESS_RIGHTS_LDT: u64 = 0x82;
+
+/// The access rights of TR in the INIT state: present, busy 32-bit TSS.
+const ACCESS_RIGHTS_TSS: u64 = 0x8B;
+
+/// Handles the INIT signal VM exit by putting the guest in the INIT state, waiting for a SIPI.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - To resume the guest in the wait-for-SIPI activity state.
+/// * `Err(HypervisorError::WaitForSipiUnsupported)` - If the processor cannot run the guest from the INIT state.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 3.
+pub fn handle_init_signal(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling INIT signal VM exit at {:#x}", guest_registers.rip);
+
+    if !cpu::has_wait_for_sipi() {
+        log::error!("INIT received, but the processor cannot run the guest in the INIT state");
+        return Err(HypervisorError::WaitForSipiUnsupported);
+    }
+
+    enter_init_state(guest_registers, vmx)?;
+    try_vmwrite(guest::ACTIVITY_STATE, ACTIVITY_STATE_WAIT_FOR_SIPI)?;
+
+    Ok(ExitType::Continue)
+}
+
+/// Handles the start-up IPI VM exit by starting the guest in real mode at the page given by the SIPI vector.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - To resume the guest at the start-up code.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 4.
+pub fn handle_sipi(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    // The exit qualification holds the vector, the page of the start-up code below 1 MiB.
+    let vector = try_vmread(ro::EXIT_QUALIFICATION)? & 0xFF;
+    log::debug!("Handling SIPI VM exit with vector {:#x}", vector);
+
+    vmx.entry_recovery.preserve(guest_registers)?;
+
+    try_vmwrite(guest::CS_SELECTOR, vector << 8)?;
+    try_vmwrite(guest::CS_BASE, vector << 12)?;
+    guest_registers.rip = 0;
+    try_vmwrite(guest::RIP, 0u64)?;
+    try_vmwrite(guest::ACTIVITY_STATE, ACTIVITY_STATE_ACTIVE)?;
+
+    Ok(ExitType::Continue)
+}
+
+/// Loads the guest state with the values of the INIT state, and enables the controls needed to run it.
+fn enter_init_state(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<(), HypervisorError> {
+    vmx.entry_recovery.preserve(guest_registers)?;
+
+    let secondary = try_vmread(control::SECONDARY_PROCBASED_EXEC_CONTROLS)?;
+    try_vmwrite(
+        control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+        secondary | SecondaryControls::UNRESTRICTED_GUEST.bits() as u64,
+    )?;
+
+    // The guest leaves IA-32e mode. The processor sets the control again on the VM exit after it enters it.
+    let entry = try_vmread(control::VMENTRY_CONTROLS)?;
+    try_vmwrite(
+        control::VMENTRY_CONTROLS,
+        (entry | EntryControls::LOAD_IA32_EFER.bits() as u64)
+            & !(EntryControls::IA32E_MODE_GUEST.bits() as u64),
+    )?;
+
+    let exit = try_vmread(control::VMEXIT_CONTROLS)?;
+    try_vmwrite(
+        control::VMEXIT_CONTROLS,
+        exit | (ExitControls::SAVE_IA32_EFER.bits() | ExitControls::LOAD_IA32_EFER.bits()) as u64,
+    )?;
+    try_vmwrite(host::IA32_EFER_FULL, rdmsr(IA32_EFER))?;
+    try_vmwrite(guest::IA32_EFER_FULL, 0u64)?;
+
+    // The fixed bits other than PE and PG stay set, NE in particular, but the guest reads the INIT state.
+    let cr0 = (try_vmread(control::CR0_READ_SHADOW)? & CR0_CD_NW) | CR0_ET;
+    try_vmwrite(control::CR0_READ_SHADOW, cr0)?;
+    try_vmwrite(guest::CR0, cr0 | (rdmsr(IA32_VMX_CR0_FIXED0) & !CR0_PE_PG))?;
+    try_vmwrite(control::CR4_READ_SHADOW, cr4_read_shadow(0))?;
+    try_vmwrite(guest::CR4, rdmsr(IA32_VMX_CR4_FIXED0))?;
+    try_vmwrite(guest::CR3, 0u64)?;
+
+    try_vmwrite(guest::CS_SELECTOR, 0xF000u64)?;
+    try_vmwrite(guest::CS_BASE, 0xFFFF_0000u64)?;
+    try_vmwrite(guest::CS_LIMIT, REAL_MODE_LIMIT)?;
+    try_vmwrite(guest::CS_ACCESS_RIGHTS, ACCESS_RIGHTS_CODE)?;
+
+    // The other segments have a null selector and base.
+    for (selector, base, limit, access_rights, rights) in [
+        (
+            guest::DS_SELECTOR,
+            guest::DS_BASE,
+            guest::DS_LIMIT,
+            guest::DS_ACCESS_RIGHTS,
+            ACCESS_RIGHTS_DATA,
+        ),
+        (
+            guest::ES_SELECTOR,
+            guest::ES_BASE,
+            guest::ES_LIMIT,
+            guest::ES_ACCESS_RIGHTS,
+            ACCESS_RIGHTS_DATA,
+        ),
+        (
+            guest::FS_SELECTOR,
+            guest::FS_BASE,
+            guest::FS_LIMIT,
+            guest::FS_ACCESS_RIGHTS,
+            ACCESS_RIGHTS_DATA,
+        ),
+        (
+            guest::GS_SELECTOR,
+            guest::GS_BASE,
+            guest::GS_LIMIT,
+            guest::GS_ACCESS_RIGHTS,
+            ACCESS_RIGHTS_DATA,
+        ),
+        (
+            guest::SS_SELECTOR,
+            guest::SS_BASE,
+            guest::SS_LIMIT,
+            guest::SS_ACCESS_RIGHTS,
+            ACCESS_RIGHTS_DATA,
+        ),
+        (
+            guest::LDTR_SELECTOR,
+            guest::LDTR_BASE,
+            guest::LDTR_LIMIT,
+            guest::LDTR_ACCESS_RIGHTS,
+            ACCESS_RIGHTS_LDT,
+        ),
+        (
+            guest::TR_SELECTOR,
+            guest::TR_BASE,
+            guest::TR_LIMIT,
+            guest::TR_ACCESS_RIGHTS,
+            ACCESS_RIGHTS_TSS,
+        ),
+    ] {
+        try_vmwrite(selector, 0u64)?;
+        try_vmwrite(base, 0u64)?;
+        try_vmwrite(limit, REAL_MODE_LIMIT)?;
+        try_vmwrite(access_rights, rights)?;
+    }
+
+    try_vmwrite(guest::GDTR_BASE, 0u64)?;
+    try_vmwrite(guest::GDTR_LIMIT, REAL_MODE_LIMIT)?;
+    try_vmwrite(guest::IDTR_BASE, 0u64)?;
+    try_vmwrite(guest::IDTR_LIMIT, REAL_MODE_LIMIT)?;
+    vmx.table_shadows = TableShadows::new();
+
+    try_vmwrite(guest::IA32_SYSENTER_CS, 0u64)?;
+    try_vmwrite(guest::IA32_SYSENTER_ESP, 0u64)?;
+    try_vmwrite(guest::IA32_SYSENTER_EIP, 0u64)?;
+    vmx.debug_registers.write_dr7(DR7_INIT)?;
+
+    // EDX holds the processor signature, the other general-purpose registers are cleared. The XMM registers are
+    // left unchanged by INIT.
+    *guest_registers = GuestRegisters {
+        rdx: u64::from(cpuid!(0x1).eax),
+        rip: RIP_INIT,
+        rflags: RFLAGS_INIT,
+        xmm0: guest_registers.xmm0,
+        xmm1: guest_registers.xmm1,
+        xmm2: guest_registers.xmm2,
+        xmm3: guest_registers.xmm3,
+        xmm4: guest_registers.xmm4,
+        xmm5: guest_registers.xmm5,
+        xmm6: guest_registers.xmm6,
+        xmm7: guest_registers.xmm7,
+        xmm8: guest_registers.xmm8,
+        xmm9: guest_registers.xmm9,
+        xmm10: guest_registers.xmm10,
+        xmm11: guest_registers.xmm11,
+        xmm12: guest_registers.xmm12,
+        xmm13: guest_registers.xmm13,
+        xmm14: guest_registers.xmm14,
+        xmm15: guest_registers.xmm15,
+        ..Default::default()
+    };
+    try_vmwrite(guest::RIP, RIP_INIT)?;
+    try_vmwrite(guest::RSP, 0u64)?;
+    try_vmwrite(guest::RFLAGS, RFLAGS_INIT)?;
+
+    // Events pending for the state the INIT discarded are dropped, none can be injected while waiting for a SIPI.
+    vmx.pending_events.clear();
+    try_vmwrite(guest::INTERRUPTIBILITY_STATE, 0u64)?;
+    try_vmwrite(guest::PENDING_DBG_EXCEPTIONS, 0u64)?;
+
+    invvpid_single_context(VPID_TAG);
+
+    Ok(())
+}
@@ -1,26 +1,94 @@
-//! Manages INVD VM exits to handle guest VM cache invalidation requests securely.
+//! Manages the cache-invalidation instructions of the guest: INVD, which always exits, and WBINVD and WBNOINVD,
+//! which exit with the "WBINVD exiting" control.
+//!
+//! INVD discards the caches without writing them back, which would destroy the dirty lines of the hypervisor and
+//! of any memory the EPT maps with a write-back type behind the guest's back. It is never executed on behalf of
+//! the guest. What the guest gets instead is decided by the `CacheFlushPolicy` set with
+//! `HypervisorBuilder::cache_flush_policy`:
+//! - `CacheFlushPolicy::Execute` runs WBINVD for INVD, which writes back the dirty lines before invalidating
+//!   them, and lets WBINVD and WBNOINVD run without exiting.
+//! - `CacheFlushPolicy::Skip` makes WBINVD and WBNOINVD exit, and completes all three instructions without
+//!   touching the caches. Flushing all caches of a processor takes long and slows down the other processors
+//!   sharing them, which a guest flushing in a loop could abuse. The caches stay coherent, so only guests relying
+//!   on the flush for memory the EPT maps uncacheable or write-combining behind their back are affected.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM
+//! Exits Conditionally (WBINVD exiting), Table 28-1. Exit Qualification for WBINVD and WBNOINVD, and Table C-1.
+//! Basic Exit Reasons 13 and 54.
 
-use crate::{intel::vmexit::ExitType, utils::capture::GuestRegisters, utils::instructions::wbinvd};
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{support::try_vmread, vmexit::ExitType, vmx::Vmx},
+        utils::{capture::GuestRegisters, instructions::wbinvd},
+    },
+    x86::vmx::vmcs::ro,
+};
 
-/// Manages the INVD instruction VM exit by logging the event, performing a controlled
-/// cache invalidation, and advancing the guest's instruction pointer.
+/// What the cache-invalidation instructions of the guest do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheFlushPolicy {
+    /// INVD writes back and invalidates the caches with WBINVD, WBINVD and WBNOINVD run natively.
+    #[default]
+    Execute,
+
+    /// INVD, WBINVD and WBNOINVD are skipped.
+    Skip,
+}
+
+/// Manages the INVD instruction VM exit by flushing the caches with WBINVD, or skipping it, depending on the
+/// policy.
 ///
 /// # Arguments
 ///
-/// * `registers` - General-purpose registers of the guest VM at the VM exit.
+/// * `_guest_registers` - General-purpose registers of the guest VM at the VM exit.
+/// * `vmx` - A mutable reference to the Vmx structure.
 ///
 /// # Returns
 ///
-/// * `ExitType::IncrementRIP` - To move past the `INVD` instruction in the VM.
-pub fn handle_invd(_guest_registers: &mut GuestRegisters) -> ExitType {
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `INVD` instruction in the VM.
+pub fn handle_invd(
+    _guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
     log::debug!("Handling INVD VM exit...");
 
-    // Perform WBINVD to write back and invalidate the hypervisor's caches.
-    // This ensures that any modified data is written to memory before cache lines are invalidated.
-    wbinvd();
-    // Advances the guest's instruction pointer to the next instruction to be executed.
+    // WBINVD writes the modified data back to memory before the cache lines are invalidated.
+    if vmx.shared_data().cache_flush_policy == CacheFlushPolicy::Execute {
+        wbinvd();
+    }
 
     log::debug!("INVD VMEXIT handled successfully!");
 
-    ExitType::IncrementRIP
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Manages the WBINVD and WBNOINVD instruction VM exits by flushing the caches, or skipping it, depending on the
+/// policy.
+///
+/// # Arguments
+///
+/// * `_guest_registers` - General-purpose registers of the guest VM at the VM exit.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `WBINVD` or `WBNOINVD` instruction in the VM.
+pub fn handle_wbinvd(
+    _guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    // The exit qualification is 0 for WBINVD and 1 for WBNOINVD.
+    let wbnoinvd = try_vmread(ro::EXIT_QUALIFICATION)? & 1 != 0;
+    log::trace!(
+        "Handling {} VM exit",
+        if wbnoinvd { "WBNOINVD" } else { "WBINVD" }
+    );
+
+    // WBNOINVD may keep the lines cached, WBINVD writes back the same lines and is available everywhere.
+    if vmx.shared_data().cache_flush_policy == CacheFlushPolicy::Execute {
+        wbinvd();
+    }
+
+    Ok(ExitType::IncrementRIP)
 }
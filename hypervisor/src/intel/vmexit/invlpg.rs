@@ -0,0 +1,236 @@
+//! Handles the TLB invalidations of the guest: INVLPG and INVPCID.
+//!
+//! INVLPG exiting is optional and enabled with `HypervisorBuilder::invlpg_exiting`. With INVPCID enabled in the
+//! secondary controls, it makes INVPCID exit as well. The guest runs with `VPID_TAG`, so its translations are
+//! tagged in the TLB and the handlers invalidate them with INVVPID on behalf of the guest:
+//! - INVLPG invalidates the address with an individual-address INVVPID, or a single-context INVVPID on
+//!   processors without the individual-address type.
+//! - INVPCID individual-address invalidations do the same, the other types a single-context INVVPID. The
+//!   translations of all PCIDs of the guest are invalidated, including the global ones, which is more than the
+//!   instruction does, but correct.
+//!
+//! Without the "enable INVPCID" control INVPCID raises #UD, so the instruction is then hidden from CPUID, see
+//! `vmexit::cpuid`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.3.3.3 Guidelines for Use of the
+//! INVVPID Instruction, Table 28-9. Format of the VM-Exit Instruction-Information Field as Used for INVEPT,
+//! INVPCID, and INVVPID, and INVLPG and INVPCID in Volume 2.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            events::EventInjection,
+            guest_memory::GuestMemory,
+            invvpid::{invvpid_individual_address, invvpid_single_context, VPID_TAG},
+            support::try_vmread,
+            vmexit::{cr::gpr, ExitType},
+            vmx::Vmx,
+        },
+        utils::{addresses::Gva, capture::GuestRegisters},
+    },
+    x86::vmx::vmcs::{guest, ro},
+};
+
+/// The L bit of the CS access rights, set in 64-bit mode.
+const ACCESS_RIGHTS_LONG_MODE: u64 = 1 << 13;
+
+/// The linear addresses outside of 64-bit mode, which wrap around at 4GB.
+const COMPATIBILITY_ADDRESS_MASK: u64 = 0xFFFF_FFFF;
+
+/// CR4.LA57, set with 5-level paging, which widens canonical addresses to 57 bits.
+const CR4_LA57: u64 = 1 << 12;
+
+/// CR4.PCIDE, without which only PCID 0 may be invalidated.
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// The bits of the first quadword of the INVPCID descriptor holding the PCID, the others are reserved.
+const DESCRIPTOR_PCID_MASK: u64 = 0xFFF;
+
+/// The segment registers, as encoded in the instruction information.
+const SEGMENT_FS: u64 = 4;
+const SEGMENT_GS: u64 = 5;
+
+/// The INVPCID types, held in the register operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InvpcidType {
+    /// Invalidates a linear address of a PCID.
+    IndividualAddress,
+
+    /// Invalidates the translations of a PCID, except the global ones.
+    SingleContext,
+
+    /// Invalidates the translations of all PCIDs, including the global ones.
+    AllContextsIncludingGlobals,
+
+    /// Invalidates the translations of all PCIDs, except the global ones.
+    AllContexts,
+}
+
+impl InvpcidType {
+    /// Decodes the type, or returns `None` for the reserved types, which raise #GP(0).
+    fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(Self::IndividualAddress),
+            1 => Some(Self::SingleContext),
+            2 => Some(Self::AllContextsIncludingGlobals),
+            3 => Some(Self::AllContexts),
+            _ => None,
+        }
+    }
+}
+
+/// Handles the INVLPG VM exit by invalidating the translations of the address in the TLB of the guest.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `INVLPG` instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 14.
+pub fn handle_invlpg(_guest_registers: &mut GuestRegisters) -> Result<ExitType, HypervisorError> {
+    // The exit qualification is the linear-address operand of the instruction.
+    let address = try_vmread(ro::EXIT_QUALIFICATION)?;
+    log::trace!("Handling INVLPG VM exit for {:#x}", address);
+
+    // INVLPG of a non-canonical address does nothing, while INVVPID would fail.
+    if is_canonical(address, guest_la57()?) {
+        invvpid_individual_address(VPID_TAG, address);
+    }
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Handles the INVPCID VM exit by performing the invalidation of the instruction with INVVPID, or raising the
+/// fault of the instruction.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `INVPCID` instruction in the VM.
+/// * `Ok(ExitType::Continue)` - If a fault was injected instead.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 58.
+pub fn handle_invpcid(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    let info = try_vmread(ro::VMEXIT_INSTRUCTION_INFO)?;
+
+    let Some(invpcid_type) = InvpcidType::from_u64(*gpr(guest_registers, (info >> 28) & 0xF)?)
+    else {
+        EventInjection::vmentry_inject_gp(0)?;
+        return Ok(ExitType::Continue);
+    };
+
+    // The descriptor holds the PCID in the first quadword and the linear address in the second.
+    let descriptor_address = memory_operand(guest_registers, info)?;
+    let guest_memory = GuestMemory::current()?;
+    let (mut pcid, mut address) = ([0u8; 8], [0u8; 8]);
+    if let Err(fault) = guest_memory
+        .read(descriptor_address, &mut pcid)
+        .and_then(|()| guest_memory.read(descriptor_address + 8, &mut address))
+    {
+        fault.inject(&mut vmx.pending_events)?;
+        return Ok(ExitType::Continue);
+    }
+
+    let pcid = u64::from_le_bytes(pcid);
+    let address = u64::from_le_bytes(address);
+    log::trace!(
+        "Handling INVPCID {:?} of PCID {:#x} at {:#x}",
+        invpcid_type,
+        pcid,
+        address
+    );
+
+    // The reserved bits of the descriptor must be clear, and only PCID 0 exists without CR4.PCIDE.
+    let pcide = try_vmread(guest::CR4)? & CR4_PCIDE != 0;
+    let valid = match invpcid_type {
+        InvpcidType::IndividualAddress => {
+            is_canonical(address, guest_la57()?) && (pcide || pcid == 0)
+        }
+        InvpcidType::SingleContext => pcide || pcid == 0,
+        InvpcidType::AllContextsIncludingGlobals | InvpcidType::AllContexts => true,
+    };
+    if !valid || pcid & !DESCRIPTOR_PCID_MASK != 0 {
+        EventInjection::vmentry_inject_gp(0)?;
+        return Ok(ExitType::Continue);
+    }
+
+    match invpcid_type {
+        InvpcidType::IndividualAddress => invvpid_individual_address(VPID_TAG, address),
+        _ => invvpid_single_context(VPID_TAG),
+    }
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Computes the linear address of the memory operand of the instruction that caused the VM exit, e.g. the
+/// descriptor of INVPCID. Only FS and GS have a base, as in 64-bit mode and the flat model of compatibility mode,
+/// where the linear address wraps around at 4GB.
+pub fn memory_operand(
+    guest_registers: &mut GuestRegisters,
+    info: u64,
+) -> Result<Gva, HypervisorError> {
+    let scaling = info & 0b11;
+    let address_size = (info >> 7) & 0b111;
+    let segment = (info >> 15) & 0b111;
+    let index_invalid = info & (1 << 22) != 0;
+    let base_invalid = info & (1 << 27) != 0;
+
+    // The exit qualification is the displacement of the operand.
+    let mut offset = try_vmread(ro::EXIT_QUALIFICATION)?;
+    if !base_invalid {
+        offset = offset.wrapping_add(*gpr(guest_registers, (info >> 23) & 0xF)?);
+    }
+    if !index_invalid {
+        offset = offset.wrapping_add(*gpr(guest_registers, (info >> 18) & 0xF)? << scaling);
+    }
+
+    let offset = match address_size {
+        0 => offset & 0xFFFF,
+        1 => offset & 0xFFFF_FFFF,
+        _ => offset,
+    };
+
+    let segment_base = match segment {
+        SEGMENT_FS => try_vmread(guest::FS_BASE)?,
+        SEGMENT_GS => try_vmread(guest::GS_BASE)?,
+        _ => 0,
+    };
+
+    let address = segment_base.wrapping_add(offset);
+    match try_vmread(guest::CS_ACCESS_RIGHTS)? & ACCESS_RIGHTS_LONG_MODE != 0 {
+        true => Ok(Gva::new(address)),
+        false => Ok(Gva::new(address & COMPATIBILITY_ADDRESS_MASK)),
+    }
+}
+
+/// Returns whether a linear address is canonical for the paging mode of the guest: its bits above bit 47, or
+/// bit 56 with 5-level paging (CR4.LA57), are copies of that bit.
+///
+/// # Arguments
+///
+/// * `address` - The linear address.
+/// * `la57` - Whether the guest uses 5-level paging, see `guest_la57`.
+pub fn is_canonical(address: u64, la57: bool) -> bool {
+    let unused = match la57 {
+        true => 7,
+        false => 16,
+    };
+
+    ((address << unused) as i64 >> unused) as u64 == address
+}
+
+/// Returns whether the guest uses 5-level paging.
+pub fn guest_la57() -> Result<bool, HypervisorError> {
+    Ok(try_vmread(guest::CR4)? & CR4_LA57 != 0)
+}
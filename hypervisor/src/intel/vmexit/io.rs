@@ -0,0 +1,557 @@
+//! Handles VM exits caused by I/O instructions (IN, INS, OUT, OUTS) on intercepted ports.
+//!
+//! Ports are intercepted for keyboard protection and for the I/O monitor. Accesses to the keyboard controller
+//! are checked by the `KeyboardGuard` and then either carried out on behalf of the guest or suppressed, the
+//! others are carried out. Accesses to monitored ports are recorded as an `IoAccess` by the `IoMonitor`. Both
+//! are compiled out without the `devices` feature, and the accesses intercepted otherwise are carried out.
+//!
+//! String instructions are emulated element by element: INS reads the port and stores the data at ES:rDI,
+//! OUTS loads the data from the segment of the instruction (DS by default) at rSI and writes it to the port,
+//! and the index register moves up or down with RFLAGS.DF. The address size of the instruction selects SI,
+//! ESI or RSI (DI, EDI or RDI) and CX, ECX or RCX. A REP prefix repeats the transfer rCX times; long
+//! repetitions are split across VM exits by leaving RIP on the instruction, so the guest can take interrupts
+//! in between, as on hardware. A page fault on the memory operand is injected with the registers reflecting
+//! the elements already transferred. Suppressed string instructions store zeros (INS) or drop the data
+//! (OUTS) but still update the registers.
+//!
+//! Each element is checked as the processor would before it is transferred: in 64-bit mode the address must be
+//! canonical, otherwise the segment must be usable, writable for INS and readable for OUTS, and the element must
+//! lie within its limit. A failed check raises #SS(0) for SS and #GP(0) for the other segments. INS stores
+//! through the EPT of the current view, see `GuestMemory::write_checked`, so it cannot write a page the guest
+//! is denied writes to, e.g. a hooked or protected page. Such a store raises #GP(0) instead of being carried
+//! out.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.1 Basic VM-Exit Information,
+//! Table 28-5. Exit Qualification for I/O Instructions, 28.2.5 Information for VM Exits Due to Instruction
+//! Execution, Table 28-8. Format of the VM-Exit Instruction-Information Field as Used for INS and OUTS, and
+//! INS/INSB/INSW/INSD, OUTS/OUTSB/OUTSW/OUTSD and REP/REPE/REPZ/REPNE/REPNZ in Volume 2.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            event_queue::PendingEvent,
+            guest_memory::{GuestMemory, GuestWrite},
+            support::try_vmread,
+            vmerror::ExceptionInterrupt,
+            vmexit::{
+                ept::current_ept,
+                invlpg::{guest_la57, is_canonical},
+                ExitType,
+            },
+            vmx::Vmx,
+        },
+        utils::{addresses::Gva, capture::GuestRegisters, cpu},
+    },
+    x86::{
+        io::{inb, inl, inw, outb, outl, outw},
+        vmx::vmcs::{self, guest, ro},
+    },
+};
+
+#[cfg(feature = "devices")]
+use crate::{
+    intel::{
+        io_monitor::IoAccess,
+        keyboard_guard::{KeyboardAccess, KeyboardProtection},
+    },
+    utils::{processor::current_processor_index, timestamp::Timestamp},
+};
+
+/// The maximum number of elements transferred by a REP string instruction per VM exit.
+const MAX_STRING_ELEMENTS: u64 = 1024;
+
+/// RFLAGS.DF.
+const RFLAGS_DF: u64 = 1 << 10;
+
+/// The L bit of the CS access rights, set in 64-bit mode.
+const ACCESS_RIGHTS_LONG_MODE: u64 = 1 << 13;
+
+/// The D/B bit of the CS access rights, set for a 32-bit default operand and address size. For an expand-down
+/// data segment, it selects the upper bound of the segment.
+const ACCESS_RIGHTS_DEFAULT_BIG: u64 = 1 << 14;
+
+/// The code bit of the segment type in the access rights.
+const ACCESS_RIGHTS_CODE: u64 = 1 << 3;
+
+/// The bit of the segment type in the access rights that makes a data segment writable, or a code segment
+/// readable.
+const ACCESS_RIGHTS_WRITABLE_OR_READABLE: u64 = 1 << 1;
+
+/// The bit of the segment type in the access rights that makes a data segment expand down.
+const ACCESS_RIGHTS_EXPAND_DOWN: u64 = 1 << 2;
+
+/// The bit of the access rights marking a segment unusable, e.g. after a null selector was loaded.
+const ACCESS_RIGHTS_UNUSABLE: u64 = 1 << 16;
+
+/// The encodings of the segment registers in the VM-exit instruction information.
+const SEGMENT_ES: u64 = 0;
+const SEGMENT_CS: u64 = 1;
+const SEGMENT_SS: u64 = 2;
+const SEGMENT_DS: u64 = 3;
+const SEGMENT_FS: u64 = 4;
+const SEGMENT_GS: u64 = 5;
+
+/// The decoded exit qualification of an I/O instruction.
+#[derive(Debug, Clone, Copy)]
+struct IoQualification {
+    /// The size of the access in bytes (1, 2 or 4).
+    size: u8,
+
+    /// Whether the instruction reads from the port (IN, INS).
+    input: bool,
+
+    /// Whether the instruction is a string instruction (INS, OUTS).
+    string: bool,
+
+    /// Whether the instruction has a REP prefix.
+    rep: bool,
+
+    /// The accessed port.
+    port: u16,
+}
+
+impl IoQualification {
+    fn from_exit_qualification(value: u64) -> Self {
+        Self {
+            size: (value & 0b111) as u8 + 1,
+            input: value & (1 << 3) != 0,
+            string: value & (1 << 4) != 0,
+            rep: value & (1 << 5) != 0,
+            port: (value >> 16) as u16,
+        }
+    }
+}
+
+/// Handles an I/O instruction VM exit.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's register state.
+/// * `vmx` - A mutable reference to the Vmx structure representing the current VM.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - The access was carried out or suppressed.
+pub fn handle_io_instruction(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling I/O instruction VM exit...");
+
+    let io = IoQualification::from_exit_qualification(try_vmread(ro::EXIT_QUALIFICATION)?);
+    log::trace!("I/O instruction: {:?}", io);
+
+    #[cfg(feature = "devices")]
+    let (blocked, access) = check_device_access(guest_registers, vmx, &io)?;
+    #[cfg(not(feature = "devices"))]
+    let blocked = false;
+
+    if io.string {
+        // String instructions are recorded without the values transferred.
+        #[cfg(feature = "devices")]
+        if let Some(access) = access {
+            vmx.shared_data().io_monitor.record(access);
+        }
+        return handle_string_io(guest_registers, vmx, &io, blocked);
+    }
+
+    #[cfg_attr(not(feature = "devices"), allow(unused_variables))]
+    let value = if blocked {
+        if io.input {
+            write_accumulator(guest_registers, io.size, 0);
+        }
+        0
+    } else if io.input {
+        let value = port_in(io.port, io.size);
+        write_accumulator(guest_registers, io.size, value);
+        value
+    } else {
+        let value = guest_registers.rax as u32;
+        port_out(io.port, io.size, value);
+        value
+    };
+
+    #[cfg(feature = "devices")]
+    if let Some(mut access) = access {
+        access.value = Some(value & size_mask(io.size));
+        vmx.shared_data().io_monitor.record(access);
+    }
+
+    log::debug!("I/O instruction VM exit handled successfully!");
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Checks an access against keyboard protection, and prepares its record if the port is monitored.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest's register state.
+/// * `vmx` - A mutable reference to the Vmx structure representing the current VM.
+/// * `io` - The decoded exit qualification.
+///
+/// # Returns
+///
+/// Whether the access is suppressed, and the record of the access without its value if the port is monitored.
+#[cfg(feature = "devices")]
+fn check_device_access(
+    guest_registers: &GuestRegisters,
+    vmx: &mut Vmx,
+    io: &IoQualification,
+) -> Result<(bool, Option<IoAccess>), HypervisorError> {
+    let shared_data = vmx.shared_data();
+    let guard = &shared_data.keyboard_guard;
+
+    let blocked = if !guard.guards(io.port) || guard.is_allowed(guest_registers.rip) {
+        false
+    } else {
+        let blocked = guard.protection() == KeyboardProtection::Block;
+
+        guard.record(KeyboardAccess {
+            rip: guest_registers.rip,
+            cr3: try_vmread(guest::CR3)?,
+            port: io.port,
+            write: !io.input,
+            blocked,
+            timestamp: Timestamp::now(),
+        });
+
+        blocked
+    };
+
+    if !shared_data.io_monitor.is_monitored(io.port) {
+        return Ok((blocked, None));
+    }
+
+    let access = IoAccess {
+        port: io.port,
+        size: io.size,
+        write: !io.input,
+        string: io.string,
+        rep: io.rep,
+        value: None,
+        blocked,
+        rip: guest_registers.rip,
+        cr3: try_vmread(guest::CR3)?,
+        processor: current_processor_index(),
+        timestamp: Timestamp::now(),
+    };
+
+    Ok((blocked, Some(access)))
+}
+
+/// The memory operand of a string I/O instruction.
+#[derive(Debug, Clone, Copy)]
+struct StringOperand {
+    /// The mask of the address size, applied to the index and count registers.
+    address_mask: u64,
+
+    /// The base of the segment of the memory operand.
+    segment_base: u64,
+
+    /// The limit of the segment, or `None` in 64-bit mode, where segments have no limit.
+    segment_limit: Option<u64>,
+
+    /// The access rights of the segment.
+    access_rights: u64,
+
+    /// Whether the segment is SS, whose violations raise #SS instead of #GP.
+    stack: bool,
+
+    /// Whether the guest uses 5-level paging, which widens canonical addresses.
+    la57: bool,
+}
+
+impl StringOperand {
+    /// Decodes the memory operand of the string I/O instruction that caused the VM exit.
+    ///
+    /// Processors that do not report the instruction information for INS and OUTS are assumed to use the
+    /// default address size and segment.
+    fn current(input: bool) -> Result<Self, HypervisorError> {
+        let cs_access_rights = try_vmread(guest::CS_ACCESS_RIGHTS)?;
+        let long_mode = cs_access_rights & ACCESS_RIGHTS_LONG_MODE != 0;
+
+        let (address_size, segment) = if cpu::has_ins_outs_info() {
+            let info = try_vmread(ro::VMEXIT_INSTRUCTION_INFO)?;
+            ((info >> 7) & 0b111, (info >> 15) & 0b111)
+        } else if long_mode {
+            (2, SEGMENT_DS)
+        } else if cs_access_rights & ACCESS_RIGHTS_DEFAULT_BIG != 0 {
+            (1, SEGMENT_DS)
+        } else {
+            (0, SEGMENT_DS)
+        };
+
+        // INS always stores through ES, whatever the prefixes.
+        let segment = if input { SEGMENT_ES } else { segment };
+
+        let address_mask = match address_size {
+            0 => 0xFFFF,
+            1 => 0xFFFF_FFFF,
+            _ => u64::MAX,
+        };
+
+        let (base, limit, access_rights) = match segment {
+            SEGMENT_ES => (guest::ES_BASE, guest::ES_LIMIT, guest::ES_ACCESS_RIGHTS),
+            SEGMENT_CS => (guest::CS_BASE, guest::CS_LIMIT, guest::CS_ACCESS_RIGHTS),
+            SEGMENT_SS => (guest::SS_BASE, guest::SS_LIMIT, guest::SS_ACCESS_RIGHTS),
+            SEGMENT_FS => (guest::FS_BASE, guest::FS_LIMIT, guest::FS_ACCESS_RIGHTS),
+            SEGMENT_GS => (guest::GS_BASE, guest::GS_LIMIT, guest::GS_ACCESS_RIGHTS),
+            _ => (guest::DS_BASE, guest::DS_LIMIT, guest::DS_ACCESS_RIGHTS),
+        };
+
+        // Only FS and GS have a base in 64-bit mode.
+        let segment_base = match segment {
+            SEGMENT_FS | SEGMENT_GS => try_vmread(base)?,
+            _ if long_mode => 0,
+            _ => try_vmread(base)?,
+        };
+
+        Ok(Self {
+            address_mask,
+            segment_base,
+            segment_limit: match long_mode {
+                true => None,
+                false => Some(try_vmread(limit)?),
+            },
+            access_rights: try_vmread(access_rights)?,
+            stack: segment == SEGMENT_SS,
+            la57: guest_la57()?,
+        })
+    }
+
+    /// Checks an element of the operand as the processor does before accessing it.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset of the element in the segment, the index register masked to the address size.
+    /// * `size` - The size of the element in bytes.
+    /// * `write` - Whether the element is stored (INS) rather than loaded (OUTS).
+    ///
+    /// # Returns
+    ///
+    /// The exception to raise, #SS(0) or #GP(0), or `None` if the element can be accessed.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 5.3 LIMIT CHECKING, 5.4 TYPE
+    /// CHECKING and 3.3.7.1 Canonical Addressing.
+    fn check(&self, offset: u64, size: u64, write: bool) -> Option<ExceptionInterrupt> {
+        let fault = match self.stack {
+            true => ExceptionInterrupt::StackSegmentFault,
+            false => ExceptionInterrupt::GeneralProtectionFault,
+        };
+
+        let Some(limit) = self.segment_limit else {
+            let first = self.segment_base.wrapping_add(offset);
+            let last = first.wrapping_add(size - 1);
+            return match is_canonical(first, self.la57) && is_canonical(last, self.la57) {
+                true => None,
+                false => Some(fault),
+            };
+        };
+
+        let rights = self.access_rights;
+        let code = rights & ACCESS_RIGHTS_CODE != 0;
+        let writable_or_readable = rights & ACCESS_RIGHTS_WRITABLE_OR_READABLE != 0;
+
+        // Code segments are never writable, and only readable with the R bit. Data segments are always readable.
+        let permitted = match write {
+            true => !code && writable_or_readable,
+            false => !code || writable_or_readable,
+        };
+        if rights & ACCESS_RIGHTS_UNUSABLE != 0 || !permitted {
+            return Some(fault);
+        }
+
+        let last = offset + size - 1;
+        let within = match !code && rights & ACCESS_RIGHTS_EXPAND_DOWN != 0 {
+            // Expand-down segments span the offsets above the limit, up to 64 KBytes or 4 GBytes.
+            true => {
+                let upper = match rights & ACCESS_RIGHTS_DEFAULT_BIG != 0 {
+                    true => 0xFFFF_FFFF,
+                    false => 0xFFFF,
+                };
+                offset > limit && last <= upper
+            }
+            false => last <= limit,
+        };
+
+        match within {
+            true => None,
+            false => Some(fault),
+        }
+    }
+}
+
+/// Emulates INS or OUTS, with or without a REP prefix.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's register state.
+/// * `vmx` - A mutable reference to the Vmx structure representing the current VM.
+/// * `io` - The decoded exit qualification.
+/// * `blocked` - Whether the port access is suppressed.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - All the elements were transferred.
+/// * `Ok(ExitType::Continue)` - Elements remain, or a page fault is injected, and the instruction is
+///   executed again.
+fn handle_string_io(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+    io: &IoQualification,
+    blocked: bool,
+) -> Result<ExitType, HypervisorError> {
+    let operand = StringOperand::current(io.input)?;
+    let mask = operand.address_mask;
+
+    let count = match io.rep {
+        true => guest_registers.rcx & mask,
+        false => 1,
+    };
+
+    if count == 0 {
+        return Ok(ExitType::IncrementRIP);
+    }
+
+    let memory = GuestMemory::current()?;
+    let size = io.size as usize;
+
+    // The EPT of the current view, which stores of INS must honor.
+    let eptp = try_vmread(vmcs::control::EPTP_FULL)?;
+    let ept = current_ept(unsafe { vmx.shared_data.as_ref() }, eptp);
+
+    let step = match guest_registers.rflags & RFLAGS_DF != 0 {
+        true => u64::from(io.size).wrapping_neg(),
+        false => u64::from(io.size),
+    };
+
+    for _ in 0..count.min(MAX_STRING_ELEMENTS) {
+        let index = match io.input {
+            true => guest_registers.rdi,
+            false => guest_registers.rsi,
+        };
+        let address = Gva::new(operand.segment_base.wrapping_add(index & mask));
+
+        if let Some(exception) = operand.check(index & mask, u64::from(io.size), io.input) {
+            log::trace!("{:?} on string I/O at {:#x}", exception, address);
+            vmx.pending_events
+                .push(PendingEvent::exception(exception, Some(0)))?;
+            return Ok(ExitType::Continue);
+        }
+
+        // The element, in the low bytes.
+        let mut data = [0u8; 4];
+
+        let transferred = if io.input {
+            // The memory is checked first, so a faulting INS does not consume data from the port.
+            match memory.probe_checked(address, size, ept) {
+                Ok(GuestWrite::Permitted) => {}
+                Ok(GuestWrite::DeniedByEpt(guest_pa)) => {
+                    log::warn!("INS to {:#x} denied by the EPT, raising #GP", guest_pa);
+                    let exception = ExceptionInterrupt::GeneralProtectionFault;
+                    vmx.pending_events
+                        .push(PendingEvent::exception(exception, Some(0)))?;
+                    return Ok(ExitType::Continue);
+                }
+                Err(fault) => {
+                    fault.inject(&mut vmx.pending_events)?;
+                    return Ok(ExitType::Continue);
+                }
+            }
+
+            if !blocked {
+                data = port_in(io.port, io.size).to_le_bytes();
+            }
+            // The probe found the store permitted, which nothing changes in between.
+            memory
+                .write_checked(address, data.get(..size).unwrap_or_default(), ept)
+                .map(|_| ())
+        } else {
+            let element = data.get_mut(..size).unwrap_or_default();
+            memory.read(address, element).map(|()| {
+                if !blocked {
+                    port_out(io.port, io.size, u32::from_le_bytes(data));
+                }
+            })
+        };
+
+        if let Err(fault) = transferred {
+            fault.inject(&mut vmx.pending_events)?;
+            return Ok(ExitType::Continue);
+        }
+
+        let index = advance(index, step, mask);
+        match io.input {
+            true => guest_registers.rdi = index,
+            false => guest_registers.rsi = index,
+        }
+
+        if io.rep {
+            guest_registers.rcx = advance(guest_registers.rcx, u64::MAX, mask);
+        }
+    }
+
+    if io.rep && guest_registers.rcx & mask != 0 {
+        log::trace!("{} elements remaining", guest_registers.rcx & mask);
+        return Ok(ExitType::Continue);
+    }
+
+    log::debug!("String I/O instruction VM exit handled successfully!");
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Returns the mask of the bytes transferred by an access of the given size.
+#[cfg(feature = "devices")]
+fn size_mask(size: u8) -> u32 {
+    match size {
+        1 => 0xFF,
+        2 => 0xFFFF,
+        _ => u32::MAX,
+    }
+}
+
+/// Adds a delta to an index or count register, with the wrap-around of the address size.
+///
+/// A 32-bit update clears the upper half of the register and a 16-bit update preserves it, as in 64-bit mode.
+fn advance(register: u64, delta: u64, mask: u64) -> u64 {
+    let value = register.wrapping_add(delta) & mask;
+
+    match mask {
+        0xFFFF => (register & !mask) | value,
+        _ => value,
+    }
+}
+
+/// Reads a byte, word or doubleword from a port.
+fn port_in(port: u16, size: u8) -> u32 {
+    unsafe {
+        match size {
+            1 => inb(port) as u32,
+            2 => inw(port) as u32,
+            _ => inl(port),
+        }
+    }
+}
+
+/// Writes a byte, word or doubleword to a port.
+fn port_out(port: u16, size: u8, value: u32) {
+    unsafe {
+        match size {
+            1 => outb(port, value as u8),
+            2 => outw(port, value as u16),
+            _ => outl(port, value),
+        }
+    }
+}
+
+/// Writes the result of IN to AL, AX or EAX. Writing EAX clears the upper half of RAX, as in 64-bit mode.
+fn write_accumulator(guest_registers: &mut GuestRegisters, size: u8, value: u32) {
+    guest_registers.rax = match size {
+        1 => (guest_registers.rax & !0xFF) | (value as u64 & 0xFF),
+        2 => (guest_registers.rax & !0xFFFF) | (value as u64 & 0xFFFF),
+        _ => value as u64,
+    };
+}
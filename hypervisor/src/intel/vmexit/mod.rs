@@ -2,39 +2,91 @@
 //!
 //! This module focuses on the reasons for VM exits, VM instruction errors, and the associated handlers for each exit type.
 //! The handlers interpret and respond to different VM exit reasons, ensuring the safe and correct execution of the virtual machine.
+//!
+//! Everything below runs in VMX root operation, where a panic takes down the whole system. Handlers report
+//! failures as `HypervisorError` instead, which is enforced by the lints below.
+
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
 
 use {
-    super::{support::vmwrite, vmerror::VmxBasicExitReason},
+    super::{support::try_vmwrite, vmerror::VmxBasicExitReason},
     crate::{
         error::HypervisorError,
         intel::{
-            support::vmread,
+            entry_recovery::VM_ENTRY_FAILURE,
+            event_queue::{raise_single_step_trap, retire_interrupt_shadow},
+            invept::invept_broadcast,
+            rate_limit::ExitClass,
+            sandbox::handle_sandbox_exit,
+            support::try_vmread,
             vmexit::{
                 cpuid::handle_cpuid,
-                ept::{handle_ept_misconfiguration, handle_ept_violation},
-                exception::{handle_exception, handle_undefined_opcode_exception},
-                invd::handle_invd,
-                invept::handle_invept,
-                invvpid::handle_invvpid,
+                cr::handle_cr_access,
+                descriptor_table::{handle_gdtr_idtr_access, handle_ldtr_tr_access},
+                dr::handle_dr_access,
+                encls::handle_encls,
+                entry_failure::handle_vmentry_failure,
+                ept::{
+                    handle_ept_misconfiguration, handle_ept_violation, handle_monitor_trap_flag,
+                },
+                exception::handle_exception,
+                getsec::handle_getsec,
+                hlt::handle_hlt,
+                init::{handle_init_signal, handle_sipi},
+                invd::{handle_invd, handle_wbinvd},
+                invlpg::{handle_invlpg, handle_invpcid},
+                io::handle_io_instruction,
                 msr::{handle_msr_access, MsrAccessType},
-                rdtsc::handle_rdtsc,
+                mwait::{handle_monitor, handle_mwait},
+                pause::handle_pause,
+                preemption_timer::handle_preemption_timer,
+                rdtsc::{handle_rdtsc, handle_rdtscp},
+                smm::{handle_rsm, handle_smi},
+                triple_fault::handle_triple_fault,
+                vmcall::handle_vmcall,
+                vmx_instruction::handle_vmx_instruction,
                 xsetbv::handle_xsetbv,
             },
             vmx::Vmx,
         },
-        utils::capture::GuestRegisters,
+        utils::{addresses::Gpa, capture::GuestRegisters},
     },
     x86::vmx::vmcs::{guest, ro},
 };
 
+#[cfg(feature = "tracing")]
+use crate::intel::exit_history::ExitRecord;
+#[cfg(any(feature = "introspection", feature = "tracing"))]
+use crate::utils::instructions::rdtsc;
+
 pub mod cpuid;
+pub mod cr;
+pub mod descriptor_table;
+pub mod dr;
+pub mod encls;
+pub mod entry_failure;
 pub mod ept;
 pub mod exception;
+pub mod getsec;
+pub mod hlt;
+pub mod init;
 pub mod invd;
-pub mod invept;
-pub mod invvpid;
+pub mod invlpg;
+pub mod io;
 pub mod msr;
+pub mod mwait;
+pub mod pause;
+pub mod preemption_timer;
 pub mod rdtsc;
+pub mod smm;
+pub mod triple_fault;
+pub mod vmcall;
+pub mod vmx_instruction;
 pub mod xsetbv;
 
 /// Represents the type of VM exit.
@@ -66,7 +118,8 @@ impl VmExit {
     ///
     /// # Returns
     ///
-    /// A result containing the VM exit reason if the handling was successful or an error if the VM exit reason is unknown or unsupported.
+    /// A result containing `ExitType::ExitHypervisor` if the processor has to leave VMX operation after a failed
+    /// VM entry, a triple fault or a `HostCall::Devirtualize`, `ExitType::Continue` otherwise, or an error if the VM exit reason is unknown or unsupported.
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.9 VM-EXIT INFORMATION FIELDS
     /// - APPENDIX C VMX BASIC EXIT REASONS
@@ -75,15 +128,74 @@ impl VmExit {
         &self,
         guest_registers: &mut GuestRegisters,
         vmx: &mut Vmx,
-    ) -> Result<(), HypervisorError> {
+    ) -> Result<ExitType, HypervisorError> {
+        // The time spent in root operation is measured from here on, see `intel::metrics`.
+        #[cfg(feature = "introspection")]
+        let start = rdtsc();
+
+        // Translations of EPT entries another processor changed since the last exit are dropped first.
+        vmx.ept_flush.sync();
+
+        // A failed VM entry saves nothing but the exit reason and qualification, so the rest is skipped.
+        let exit_reason = try_vmread(ro::EXIT_REASON)?;
+        if exit_reason & VM_ENTRY_FAILURE != 0 {
+            return handle_vmentry_failure(exit_reason, guest_registers, vmx);
+        }
+
+        // The guest state saved on this exit is only captured by the handlers about to change it, see
+        // `entry_recovery`.
+        vmx.entry_recovery.begin_exit();
+
+        // The event whose delivery caused the exit is lost unless it is injected again.
+        vmx.pending_events.capture_idt_vectoring()?;
+
+        // Single-stepping requested with `Vcpu::single_step` starts when the guest resumes.
+        vmx.single_step.apply_request()?;
+
+        // The timer set with `Vcpu::set_preemption_timer` starts counting when the guest resumes. While a sandbox
+        // runs, it owns the timer and the request waits for the end of the run.
+        if !vmx
+            .sandbox
+            .as_ref()
+            .is_some_and(|sandbox| sandbox.is_running())
+        {
+            vmx.preemption_timer.apply_request()?;
+        }
+
+        if self.dispatch_vmexit(guest_registers, vmx)? == ExitType::ExitHypervisor {
+            return Ok(ExitType::ExitHypervisor);
+        }
+
+        // Only one event can be injected per VM entry, the queue picks the most urgent one the guest can take.
+        vmx.pending_events.inject()?;
+
+        #[cfg(feature = "introspection")]
+        vmx.shared_data()
+            .metrics
+            .record(exit_reason as u16, rdtsc().wrapping_sub(start));
+
+        Ok(ExitType::Continue)
+    }
+
+    /// Reads the exit reason and invokes the appropriate handler.
+    ///
+    /// # Returns
+    ///
+    /// The `ExitType` of the handler, `ExitType::ExitHypervisor` if the processor has to leave VMX operation.
+    fn dispatch_vmexit(
+        &self,
+        guest_registers: &mut GuestRegisters,
+        vmx: &mut Vmx,
+    ) -> Result<ExitType, HypervisorError> {
         log::debug!("Handling VMEXIT...");
 
         // Upon VM-exit, transfer the guest register values from VMCS to `self.registers` to ensure it reflects the latest and complete state.
-        guest_registers.rip = vmread(guest::RIP);
-        guest_registers.rsp = vmread(guest::RSP);
-        guest_registers.rflags = vmread(guest::RFLAGS);
+        guest_registers.rip = try_vmread(guest::RIP)?;
+        guest_registers.rsp = try_vmread(guest::RSP)?;
+        guest_registers.rflags = try_vmread(guest::RFLAGS)?;
 
-        let exit_reason = vmread(ro::EXIT_REASON) as u32;
+        // The basic exit reason is the low 16 bits, the upper bits are flags.
+        let exit_reason = try_vmread(ro::EXIT_REASON)? as u16 as u32;
 
         let Some(basic_exit_reason) = VmxBasicExitReason::from_u32(exit_reason) else {
             log::error!("Unknown exit reason: {:#x}", exit_reason);
@@ -92,6 +204,63 @@ impl VmExit {
 
         log::debug!("Basic Exit Reason: {}", basic_exit_reason);
 
+        #[cfg(feature = "tracing")]
+        vmx.exit_history.record(ExitRecord {
+            reason: basic_exit_reason,
+            rip: guest_registers.rip,
+            tsc: rdtsc(),
+        });
+
+        // Watch for guest debugging sessions, which suspend the hooks depending on the policy.
+        // The guest DR7 holds the host breakpoints as well while they are set, the debugger looks at the shadow.
+        if vmx.shared_data().debugger.is_enabled() {
+            let guest_dr7 = match vmx.debug_registers.is_virtualized() {
+                true => vmx.debug_registers.guest_dr7()?,
+                false => try_vmread(guest::DR7)?,
+            };
+            vmx.shared_data().debugger.observe(guest_dr7);
+        }
+
+        // The spoofing stays paused as long as the hooks are suspended for a debugging session.
+        let debugging = vmx.shared_data().debugger.spoofing_suspended();
+        vmx.debug_registers.set_yielded(debugging)?;
+        vmx.tsc.pause_compensation(debugging);
+
+        #[cfg(feature = "introspection")]
+        {
+            let heat_map = &vmx.shared_data().heat_map;
+            if heat_map.is_enabled() {
+                heat_map.record(guest_registers.rip, basic_exit_reason);
+            }
+        }
+
+        // Any exit is a chance to notice that the guest agent stopped answering its challenges.
+        let shared_data = vmx.shared_data();
+        shared_data
+            .agent_monitor
+            .check_liveness(guest_registers.rip, &shared_data.debugger);
+
+        // Hooks disabled because their page was thrashing are enabled again once their cooldown has elapsed. The
+        // execute permission is taken away again, so the other processors are kicked to flush their translations.
+        if shared_data.thrash_policy.is_some() && shared_data.restore_thrashing_hooks()? {
+            invept_broadcast();
+        }
+
+        // While a code blob is detonated, its single-step, EPT violation and exception exits belong to the sandbox.
+        if handle_sandbox_exit(basic_exit_reason, guest_registers, vmx)?.is_some() {
+            return Ok(ExitType::Continue);
+        }
+
+        // Back off from guests spamming faults on the monitored memory. The EPT violations outside of it, e.g. the
+        // view swaps of hooked pages, which any code runs into, are never throttled. The backoff is always a delay,
+        // see `Backoff::InjectGp`. Hypercalls are throttled by `handle_vmcall`, once they are known to be served.
+        if basic_exit_reason == VmxBasicExitReason::EptViolation {
+            let guest_pa = Gpa::new(try_vmread(ro::GUEST_PHYSICAL_ADDR_FULL)?);
+            if vmx.shared_data().is_monitored(guest_pa) {
+                vmx.rate_limiter.check(ExitClass::MonitoredFault);
+            }
+        }
+
         log::debug!(
             "Guest Registers before handling vmexit: {:#x?}",
             guest_registers
@@ -104,33 +273,69 @@ impl VmExit {
         // 26.1.3 Instructions That Cause VM Exits Conditionally: Certain instructions cause VM exits in VMX non-root operation depending on the setting of the VM-execution controls.
         let exit_type = match basic_exit_reason {
             VmxBasicExitReason::ExceptionOrNmi => handle_exception(guest_registers, vmx),
-            VmxBasicExitReason::Cpuid => handle_cpuid(guest_registers),
+            VmxBasicExitReason::TripleFault => handle_triple_fault(guest_registers, vmx),
+            VmxBasicExitReason::InitSignal => handle_init_signal(guest_registers, vmx),
+            VmxBasicExitReason::StartupIpi => handle_sipi(guest_registers, vmx),
+            VmxBasicExitReason::Cpuid => handle_cpuid(guest_registers, vmx),
+            VmxBasicExitReason::Getsec => handle_getsec(guest_registers),
+            VmxBasicExitReason::Vmcall => handle_vmcall(guest_registers, vmx),
 
             // Grouping multiple exit reasons that are handled by the same function
-            VmxBasicExitReason::Getsec
-            | VmxBasicExitReason::Vmcall
-            | VmxBasicExitReason::Vmclear
+            VmxBasicExitReason::Vmclear
             | VmxBasicExitReason::Vmlaunch
             | VmxBasicExitReason::Vmptrld
             | VmxBasicExitReason::Vmptrst
+            | VmxBasicExitReason::Vmread
             | VmxBasicExitReason::Vmresume
+            | VmxBasicExitReason::Vmwrite
             | VmxBasicExitReason::Vmxon
-            | VmxBasicExitReason::Vmxoff => handle_undefined_opcode_exception(),
+            | VmxBasicExitReason::Vmxoff
+            | VmxBasicExitReason::Invept
+            | VmxBasicExitReason::Invvpid
+            | VmxBasicExitReason::Vmfunc => handle_vmx_instruction(guest_registers, vmx),
 
-            VmxBasicExitReason::Rdmsr => handle_msr_access(guest_registers, MsrAccessType::Read),
-            VmxBasicExitReason::Wrmsr => handle_msr_access(guest_registers, MsrAccessType::Write),
-            VmxBasicExitReason::Invd => handle_invd(guest_registers),
-            VmxBasicExitReason::Rdtsc => handle_rdtsc(guest_registers),
+            VmxBasicExitReason::Rdmsr => {
+                handle_msr_access(guest_registers, vmx, MsrAccessType::Read)
+            }
+            VmxBasicExitReason::Wrmsr => {
+                handle_msr_access(guest_registers, vmx, MsrAccessType::Write)
+            }
+            VmxBasicExitReason::Hlt => handle_hlt(guest_registers, vmx),
+            VmxBasicExitReason::Invd => handle_invd(guest_registers, vmx),
+            VmxBasicExitReason::WbinvdOrWbnoinvd => handle_wbinvd(guest_registers, vmx),
+            VmxBasicExitReason::Monitor => handle_monitor(guest_registers, vmx),
+            VmxBasicExitReason::Mwait => handle_mwait(guest_registers, vmx),
+            VmxBasicExitReason::Encls => handle_encls(guest_registers),
+            VmxBasicExitReason::Invlpg => handle_invlpg(guest_registers),
+            VmxBasicExitReason::Invpcid => handle_invpcid(guest_registers, vmx),
+            VmxBasicExitReason::Rdtsc => handle_rdtsc(guest_registers, vmx),
+            VmxBasicExitReason::Rdtscp => handle_rdtscp(guest_registers, vmx),
             VmxBasicExitReason::EptViolation => handle_ept_violation(guest_registers, vmx),
-            VmxBasicExitReason::EptMisconfiguration => handle_ept_misconfiguration(),
-            VmxBasicExitReason::Invept => handle_invept(),
-            VmxBasicExitReason::Invvpid => handle_invvpid(),
+            VmxBasicExitReason::EptMisconfiguration => handle_ept_misconfiguration(vmx),
+            VmxBasicExitReason::MonitorTrapFlag => handle_monitor_trap_flag(guest_registers, vmx),
             VmxBasicExitReason::Xsetbv => handle_xsetbv(guest_registers),
+            VmxBasicExitReason::IoInstruction => handle_io_instruction(guest_registers, vmx),
+            VmxBasicExitReason::ControlRegisterAccesses => handle_cr_access(guest_registers, vmx),
+            VmxBasicExitReason::MovDr => handle_dr_access(guest_registers, vmx),
+            VmxBasicExitReason::AccessToGdtrOrIdtr => handle_gdtr_idtr_access(guest_registers, vmx),
+            VmxBasicExitReason::AccessToLdtrOrTr => handle_ldtr_tr_access(guest_registers, vmx),
+            VmxBasicExitReason::Pause => handle_pause(guest_registers, vmx),
+            VmxBasicExitReason::VmxPreemptionTimerExpired => {
+                handle_preemption_timer(guest_registers, vmx)
+            }
+            // The guest can take a waiting interrupt, which is injected on VM entry.
+            VmxBasicExitReason::InterruptWindow => Ok(ExitType::Continue),
+            // The guest unblocked NMIs, a waiting NMI is injected on VM entry.
+            VmxBasicExitReason::NmiWindow => Ok(ExitType::Continue),
+            VmxBasicExitReason::IoSystemManagementInterrupt | VmxBasicExitReason::OtherSmi => {
+                handle_smi(guest_registers, vmx, basic_exit_reason)
+            }
+            VmxBasicExitReason::Rsm => handle_rsm(),
             _ => return Err(HypervisorError::UnhandledVmExit),
-        };
+        }?;
 
         if exit_type == ExitType::IncrementRIP {
-            self.advance_guest_rip(guest_registers);
+            self.advance_guest_rip(guest_registers)?;
         }
 
         log::debug!(
@@ -139,7 +344,7 @@ impl VmExit {
         );
         log::debug!("VMEXIT handled successfully.");
 
-        return Ok(());
+        Ok(exit_type)
     }
 
     /// Advances the guest's instruction pointer (RIP) after a VM exit.
@@ -148,11 +353,17 @@ impl VmExit {
     /// to the hypervisor. To ensure that the guest does not re-execute the instruction that
     /// caused the VM exit, the hypervisor needs to advance the guest's RIP to the next instruction.
     #[rustfmt::skip]
-    fn advance_guest_rip(&self, guest_registers: &mut GuestRegisters) {
+    fn advance_guest_rip(&self, guest_registers: &mut GuestRegisters) -> Result<(), HypervisorError> {
         log::trace!("Advancing guest RIP...");
-        let len = vmread(ro::VMEXIT_INSTRUCTION_LEN);
-        guest_registers.rip += len;
-        vmwrite(guest::RIP, guest_registers.rip);
-        log::trace!("Guest RIP advanced to: {:#x}", vmread(guest::RIP));
+        let len = try_vmread(ro::VMEXIT_INSTRUCTION_LEN)?;
+        guest_registers.rip = guest_registers.rip.wrapping_add(len);
+        try_vmwrite(guest::RIP, guest_registers.rip)?;
+        log::trace!("Guest RIP advanced to: {:#x}", guest_registers.rip);
+
+        // The emulated instruction completed, a single-stepping guest takes its trap and an STI or MOV SS
+        // shadow on it ends.
+        raise_single_step_trap(guest_registers.rflags)?;
+        retire_interrupt_shadow()?;
+        Ok(())
     }
 }
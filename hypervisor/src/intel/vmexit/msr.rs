@@ -2,9 +2,22 @@
 //! read and write operations. It ensures that guest MSR accesses are properly
 //! intercepted and handled, with support for injecting faults for unauthorized accesses.
 
-use crate::{
-    intel::{events::EventInjection, vmexit::ExitType},
-    utils::capture::GuestRegisters,
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            apic_base,
+            apic_timer::IA32_TSC_DEADLINE,
+            events::EventInjection,
+            hypercall_page::{self, HYPERCALL_PAGE_MSR},
+            msr_policy::{MsrAccess, MsrPolicy},
+            vmexit::ExitType,
+            vmx::Vmx,
+            x2apic::{self, X2ApicAccess},
+        },
+        utils::{capture::GuestRegisters, instructions::rdtsc},
+    },
+    x86::msr::{IA32_APIC_BASE, IA32_TIME_STAMP_COUNTER},
 };
 
 /// Enum representing the type of MSR access.
@@ -17,8 +30,10 @@ pub enum MsrAccessType {
 
 /// Handles MSR access based on the provided access type.
 ///
-/// This function checks if the requested MSR address is within a valid
-/// range, a reserved range, or a synthetic MSR range used by Hyper-V.
+/// The MSRs the hypervisor virtualizes itself, such as the TSC or the hypercall page, are handled first. The
+/// policy of the processor for the MSR, see `intel::msr_policy`, is applied to the other accesses: denied
+/// accesses raise #GP(0) and emulated accesses are answered by the emulator. Otherwise, this function checks if the
+/// requested MSR address is within a valid range, a reserved range, or a synthetic MSR range used by Hyper-V.
 /// For valid MSRs, the function will either read or write to the MSR based
 /// on the access type. For reserved or synthetic MSRs, a general protection
 /// fault is injected.
@@ -26,18 +41,20 @@ pub enum MsrAccessType {
 /// # Arguments
 ///
 /// * `registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
 /// * `access_type` - The type of MSR access (read or write).
 ///
 /// # Returns
 ///
-/// * `ExitType::IncrementRIP` - To move past the `rdmsr` or `wrmsr` instruction in the VM.
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `rdmsr` or `wrmsr` instruction in the VM.
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: RDMSR—Read From Model Specific Register or WRMSR—Write to Model Specific Register
 /// and Table C-1. Basic Exit Reasons 31 and 32.
 pub fn handle_msr_access(
     guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
     access_type: MsrAccessType,
-) -> ExitType {
+) -> Result<ExitType, HypervisorError> {
     log::debug!("Handling MSR VM exit...");
 
     /// Constants related to MSR addresses and ranges.
@@ -52,12 +69,136 @@ pub fn handle_msr_access(
 
     let msr_id = guest_registers.rcx;
 
+    // The hypercall page MSR is emulated while the paravirtual interface is exposed.
+    let paravirt = &vmx.shared_data().paravirt;
+    if paravirt.is_enabled() && msr_id == HYPERCALL_PAGE_MSR as u64 {
+        log::trace!("Hypercall page MSR access attempted: {:#x}", msr_id);
+        match access_type {
+            MsrAccessType::Read => {
+                let msr_value = hypercall_page::read_msr(paravirt);
+                guest_registers.rdx = msr_value >> 32;
+                guest_registers.rax = msr_value & MSR_MASK_LOW;
+            }
+            MsrAccessType::Write => {
+                let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
+                if !hypercall_page::write_msr(paravirt, msr_value) {
+                    EventInjection::vmentry_inject_gp(0)?;
+                    return Ok(ExitType::Continue);
+                }
+            }
+        }
+        return Ok(ExitType::IncrementRIP);
+    }
+
+    // The TSC-deadline timer of a guest with a virtualized TSC is armed in guest ticks, see `intel::apic_timer`.
+    if msr_id == IA32_TSC_DEADLINE as u64 && vmx.tsc.translates_deadline() {
+        match access_type {
+            MsrAccessType::Read => {
+                let msr_value = vmx.tsc.read_deadline();
+                guest_registers.rdx = msr_value >> 32;
+                guest_registers.rax = msr_value & MSR_MASK_LOW;
+            }
+            MsrAccessType::Write => {
+                let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
+                vmx.tsc.write_deadline(msr_value);
+            }
+        }
+        return Ok(ExitType::IncrementRIP);
+    }
+
+    // The guest reads and writes the virtualized TSC, not the one of the processor, see `intel::tsc`.
+    if msr_id == IA32_TIME_STAMP_COUNTER as u64 && vmx.tsc.intercepts_tsc_msr() {
+        match access_type {
+            MsrAccessType::Read => {
+                let msr_value = vmx.tsc.guest_tsc(rdtsc());
+                guest_registers.rdx = msr_value >> 32;
+                guest_registers.rax = msr_value & MSR_MASK_LOW;
+            }
+            MsrAccessType::Write => {
+                let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
+                vmx.tsc.write_guest_tsc(msr_value)?;
+            }
+        }
+        return Ok(ExitType::IncrementRIP);
+    }
+
+    // Relocations and mode switches of the local APIC are followed, see `intel::apic_base`.
+    if msr_id == IA32_APIC_BASE as u64
+        && matches!(access_type, MsrAccessType::Write)
+        && vmx.shared_data().apic_base_tracking
+    {
+        let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
+        if !apic_base::write(vmx, msr_value)? {
+            EventInjection::vmentry_inject_gp(0)?;
+            return Ok(ExitType::Continue);
+        }
+        return Ok(ExitType::IncrementRIP);
+    }
+
+    // Accesses to intercepted x2APIC registers that the hardware would fault on are reflected to the guest, as
+    // are all of them while the local APIC is not in x2APIC mode, e.g. after the guest switched it back.
+    if x2apic::is_x2apic_msr(msr_id as u32) {
+        if !x2apic::is_x2apic_enabled() {
+            log::trace!("x2APIC MSR access outside of x2APIC mode: {:#x}", msr_id);
+            EventInjection::vmentry_inject_gp(0)?;
+            return Ok(ExitType::Continue);
+        }
+
+        let allowed = matches!(
+            (x2apic::register(msr_id as u32), &access_type),
+            (Some((_, X2ApicAccess::ReadWrite)), _)
+                | (Some((_, X2ApicAccess::ReadOnly)), MsrAccessType::Read)
+                | (Some((_, X2ApicAccess::WriteOnly)), MsrAccessType::Write)
+        );
+
+        if !allowed {
+            log::trace!("Invalid x2APIC MSR access attempted: {:#x}", msr_id);
+            EventInjection::vmentry_inject_gp(0)?;
+            return Ok(ExitType::Continue);
+        }
+
+        if let Some((name, _)) = x2apic::register(msr_id as u32) {
+            log::trace!("x2APIC {} register access: {:#x}", name, msr_id);
+        }
+    }
+
+    // Accesses to MSRs that are denied or emulated never reach the hardware. The policies only see the accesses
+    // the hypervisor left to the hardware above, so they cannot override its own handling.
+    match vmx.msr_policies.lookup(msr_id as u32) {
+        MsrPolicy::Passthrough => {}
+        MsrPolicy::Deny => {
+            log::trace!("Denied MSR access attempted: {:#x}", msr_id);
+            EventInjection::vmentry_inject_gp(0)?;
+            return Ok(ExitType::Continue);
+        }
+        MsrPolicy::Emulate(emulator) => {
+            let access = match access_type {
+                MsrAccessType::Read => MsrAccess::Read,
+                MsrAccessType::Write => MsrAccess::Write(
+                    (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW),
+                ),
+            };
+
+            let Some(msr_value) = emulator(msr_id as u32, access) else {
+                log::trace!("Emulated MSR access refused: {:#x}", msr_id);
+                EventInjection::vmentry_inject_gp(0)?;
+                return Ok(ExitType::Continue);
+            };
+
+            if matches!(access_type, MsrAccessType::Read) {
+                guest_registers.rdx = msr_value >> 32;
+                guest_registers.rax = msr_value & MSR_MASK_LOW;
+            }
+            return Ok(ExitType::IncrementRIP);
+        }
+    }
+
     // If the MSR address falls within a synthetic or reserved range, inject a general protection fault.
     /*
         if (msr_id >= HYPERV_MSR_START) && (msr_id <= HYPERV_MSR_END) {
             log::trace!("Synthetic MSR access attempted: {:#x}", msr_id);
-            EventInjection::vmentry_inject_gp(0);
-            return ExitType::Continue;
+            EventInjection::vmentry_inject_gp(0)?;
+            return Ok(ExitType::Continue);
         }
     */
 
@@ -76,17 +217,19 @@ pub fn handle_msr_access(
             }
             MsrAccessType::Write => {
                 let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
+                // The guest cannot enable TSX again if it runs without it.
+                let msr_value = vmx.shared_data().tsx.filter_write(msr_id as u32, msr_value);
                 unsafe { x86::msr::wrmsr(msr_id as _, msr_value) };
             }
         }
     } else {
         // If the MSR is neither a known valid MSR nor a synthetic MSR, inject a general protection fault.
         log::trace!("Invalid MSR access attempted: {:#x}", msr_id);
-        EventInjection::vmentry_inject_gp(0);
-        return ExitType::Continue;
+        EventInjection::vmentry_inject_gp(0)?;
+        return Ok(ExitType::Continue);
     }
 
     log::debug!("MSR VMEXIT handled successfully.");
 
-    ExitType::IncrementRIP
+    Ok(ExitType::IncrementRIP)
 }
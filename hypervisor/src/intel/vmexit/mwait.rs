@@ -0,0 +1,81 @@
+//! Manages the MONITOR and MWAIT instructions of the guest, which exit with the "MONITOR exiting" and "MWAIT
+//! exiting" controls.
+//!
+//! MWAIT lets a processor enter an optimized state, possibly a deep C-state, until the address armed with MONITOR
+//! is written or an interrupt arrives. What the guest gets is decided by the `MwaitPolicy` set with
+//! `HypervisorBuilder::mwait_policy`:
+//! - `MwaitPolicy::Native` lets both instructions run without exiting.
+//! - `MwaitPolicy::Pause` makes both exit, completes MONITOR without arming anything, and emulates MWAIT as a
+//!   short PAUSE loop in VMX root operation. MWAIT may return before the monitored address is written, so the
+//!   guest re-checks its wake-up condition and waits again, without the processor leaving C0.
+//!
+//! There is no policy raising #UD. The hypervisor is loaded into a running OS, which has read CPUID and chose its
+//! idle routine at boot, so hiding the feature now would not keep it from executing MWAIT and faulting.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM
+//! Exits Conditionally (MONITOR, MWAIT), 9.10.4 MONITOR/MWAIT Instruction, CPUID—CPU Identification, Leaf 05H,
+//! and Table C-1. Basic Exit Reasons 36 and 39.
+
+use crate::{
+    error::HypervisorError,
+    intel::{vmexit::ExitType, vmx::Vmx},
+    utils::capture::GuestRegisters,
+};
+
+/// The number of PAUSE instructions an emulated MWAIT spins for.
+const MWAIT_PAUSE_ITERATIONS: u32 = 128;
+
+/// What the MONITOR and MWAIT instructions of the guest do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MwaitPolicy {
+    /// MONITOR and MWAIT run natively.
+    #[default]
+    Native,
+
+    /// MONITOR completes without effect and MWAIT spins in a PAUSE loop.
+    Pause,
+}
+
+/// Manages the MONITOR instruction VM exit by skipping it.
+///
+/// # Arguments
+///
+/// * `_guest_registers` - General-purpose registers of the guest VM at the VM exit.
+/// * `_vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `MONITOR` instruction in the VM.
+pub fn handle_monitor(
+    _guest_registers: &mut GuestRegisters,
+    _vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::trace!("Handling MONITOR VM exit");
+
+    // Nothing is armed, the next MWAIT returns on its own.
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Manages the MWAIT instruction VM exit by spinning in a PAUSE loop.
+///
+/// # Arguments
+///
+/// * `_guest_registers` - General-purpose registers of the guest VM at the VM exit.
+/// * `_vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `MWAIT` instruction in the VM.
+pub fn handle_mwait(
+    _guest_registers: &mut GuestRegisters,
+    _vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::trace!("Handling MWAIT VM exit");
+
+    // A spurious wake-up is architectural, the guest waits again if its condition is not met yet.
+    for _ in 0..MWAIT_PAUSE_ITERATIONS {
+        core::hint::spin_loop();
+    }
+
+    Ok(ExitType::IncrementRIP)
+}
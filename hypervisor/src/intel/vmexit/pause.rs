@@ -0,0 +1,40 @@
+//! Handles the PAUSE-loop exits of the guest, when enabled with `HypervisorBuilder::pause_loop_exiting`.
+//!
+//! The spin is recorded in the monitor of the processor, see `intel::spin_monitor`, and the guest resumes after
+//! the PAUSE. A new loop window starts with the next PAUSE, so a lock that stays contended exits again.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM
+//! Exits Conditionally (PAUSE) and Table C-1. Basic Exit Reasons 40.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{support::try_vmread, vmexit::ExitType, vmx::Vmx},
+        utils::{capture::GuestRegisters, instructions::rdtsc},
+    },
+    x86::vmx::vmcs::guest,
+};
+
+/// Handles the PAUSE VM exit by recording the spin.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `PAUSE` instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 40.
+pub fn handle_pause(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::trace!("Handling PAUSE-loop VM exit at {:#x}", guest_registers.rip);
+
+    let cr3 = try_vmread(guest::CR3)?;
+    vmx.spin_monitor.record(guest_registers.rip, cr3, rdtsc());
+
+    Ok(ExitType::IncrementRIP)
+}
@@ -0,0 +1,39 @@
+//! Handles the expiry of the VMX-preemption timer, when set with `Vcpu::set_preemption_timer`.
+//!
+//! The timer is loaded with its period again and the callback runs in VMX root operation, see
+//! `intel::preemption_timer`. The guest resumes where it was preempted.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.1 VMX-Preemption Timer and
+//! Table C-1. Basic Exit Reasons 52.
+
+use crate::{
+    error::HypervisorError,
+    intel::{preemption_timer::PreemptionTimer, vmexit::ExitType, vmx::Vmx},
+    utils::capture::GuestRegisters,
+};
+
+/// Handles the VMX-preemption timer VM exit by invoking the callback of the timer.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - The guest resumes at the instruction it was preempted at.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 52.
+pub fn handle_preemption_timer(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::trace!(
+        "Handling VMX-preemption timer VM exit at {:#x}",
+        guest_registers.rip
+    );
+
+    PreemptionTimer::expire(guest_registers, vmx)?;
+
+    Ok(ExitType::Continue)
+}
@@ -1,10 +1,20 @@
 //! Handles RDTSC virtualization tasks, specifically intercepting and managing
 //! the `RDTSC` (Read Time-Stamp Counter) instruction in a VM to ensure appropriate time
 //! information is provided to the guest while maintaining the integrity of the hypervisor.
+//!
+//! RDTSC and RDTSCP only exit with `TscMode::Exiting`, see `intel::tsc`. The guest then reads the TSC scaled
+//! by the `VirtualTsc` of the processor, plus the offset shared by all processors.
 
 use {
-    crate::{intel::vmexit::ExitType, utils::capture::GuestRegisters},
-    x86::time::rdtsc,
+    crate::{
+        error::HypervisorError,
+        intel::{vmexit::ExitType, vmx::Vmx},
+        utils::{
+            capture::GuestRegisters,
+            instructions::{rdmsr, rdtsc},
+        },
+    },
+    x86::msr::IA32_TSC_AUX,
 };
 
 /*
@@ -16,23 +26,28 @@ User can add the following later:
 /// Handles the `RDTSC` VM-exit.
 ///
 /// This function is invoked when the guest executes the `RDTSC` instruction.
-/// It reads the current value of the host's time-stamp counter and updates the guest's
-/// RAX and RDX registers with the low and high 32-bits of the counter, respectively.
+/// It reads the current value of the host's time-stamp counter, converts it into the guest's
+/// time-stamp counter and updates the guest's RAX and RDX registers with the low and high
+/// 32-bits of the counter, respectively.
 ///
 /// # Arguments
 ///
 /// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
 ///
 /// # Returns
 ///
-/// * `ExitType::IncrementRIP` - To move past the `RDTSC` instruction in the VM.
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `RDTSC` instruction in the VM.
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 10.
-pub fn handle_rdtsc(guest_registers: &mut GuestRegisters) -> ExitType {
+pub fn handle_rdtsc(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
     log::debug!("Handling RDTSC VM exit...");
 
-    // Read the time stamp counter.
-    let rdtsc_value: u64 = unsafe { rdtsc() };
+    // Read the time stamp counter and convert it into the guest's one.
+    let rdtsc_value = vmx.tsc.guest_tsc(rdtsc());
 
     // Update the guest's RAX and RDX registers.
     guest_registers.rax = rdtsc_value & 0xFFFFFFFF; // Low 32 bits
@@ -40,5 +55,36 @@ pub fn handle_rdtsc(guest_registers: &mut GuestRegisters) -> ExitType {
 
     log::debug!("RDTSC VMEXIT handled successfully!");
 
-    ExitType::IncrementRIP
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Handles the `RDTSCP` VM-exit.
+///
+/// Like `handle_rdtsc`, and additionally loads RCX with IA32_TSC_AUX, which the guest shares with the host.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `RDTSCP` instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 51.
+pub fn handle_rdtscp(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling RDTSCP VM exit...");
+
+    let rdtsc_value = vmx.tsc.guest_tsc(rdtsc());
+
+    guest_registers.rax = rdtsc_value & 0xFFFFFFFF;
+    guest_registers.rdx = rdtsc_value >> 32;
+    guest_registers.rcx = rdmsr(IA32_TSC_AUX) & 0xFFFFFFFF;
+
+    log::debug!("RDTSCP VMEXIT handled successfully!");
+
+    Ok(ExitType::IncrementRIP)
 }
@@ -0,0 +1,60 @@
+//! Handles the VM exits related to system management mode (SMM).
+//!
+//! The SMI exit reasons only occur with the dual-monitor treatment of SMIs and SMM, which the hypervisor never
+//! activates, and RSM only exits in the SMM of a guest running under an SMM-transfer monitor. An SMI exit is
+//! recorded in the SMM monitor and the guest resumes, since the SMI was already handled by the firmware. RSM is
+//! answered with #UD, which is what it raises outside of SMM.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 33.15.2 SMM VM Exits and 33.15.4
+//! Enabling the Dual-Monitor Treatment.
+
+use crate::{
+    error::HypervisorError,
+    intel::{
+        smm::SmiWindow,
+        vmerror::VmxBasicExitReason,
+        vmexit::{exception::handle_undefined_opcode_exception, ExitType},
+        vmx::Vmx,
+    },
+    utils::capture::GuestRegisters,
+};
+
+/// Handles an I/O SMI or other SMI VM exit by recording it.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest's register state at the VM exit.
+/// * `vmx` - The VMX instance of the processor.
+/// * `exit_reason` - The basic reason of the VM exit.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - The SMI was handled by the firmware, and the guest resumes where it was.
+pub fn handle_smi(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+    exit_reason: VmxBasicExitReason,
+) -> Result<ExitType, HypervisorError> {
+    log::warn!(
+        "Unexpected {:?} VM exit at {:#x}",
+        exit_reason,
+        guest_registers.rip
+    );
+
+    vmx.shared_data()
+        .smm_monitor
+        .record(SmiWindow::VmExit, 1, exit_reason, guest_registers.rip);
+
+    Ok(ExitType::Continue)
+}
+
+/// Handles an RSM VM exit by raising #UD in the guest.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - The #UD is injected on VM entry.
+pub fn handle_rsm() -> Result<ExitType, HypervisorError> {
+    log::warn!("Unexpected RSM VM exit");
+
+    handle_undefined_opcode_exception()
+}
@@ -0,0 +1,319 @@
+//! Handles the triple faults of the guest.
+//!
+//! A triple fault exits instead of shutting down the processor. Without a handler, the machine went down with no
+//! trace of what the guest was doing, even though a triple fault under a hypervisor is often caused by the
+//! hypervisor itself, e.g. by an event injected into a broken guest state. The handler therefore logs a
+//! `GuestStateDump` first: the registers, the control registers, the segments and descriptor tables, the event
+//! being delivered and, with the `tracing` feature, the last VM exits of the processor, see `exit_history`. The
+//! state of the guest is only read here, on the triple fault itself. The guest cannot continue, so the handler
+//! then stops it in a controlled way, as set with `HypervisorBuilder::triple_fault_policy`:
+//! - `TripleFaultPolicy::BugCheck` leaves VMX operation on the processor and bug checks with `HYPERVISOR_ERROR`,
+//!   so the system writes a crash dump, with the guest RIP, RSP, CR3 and the event being delivered as parameters.
+//! - `TripleFaultPolicy::Halt` parks the guest in the shutdown activity state, which a triple fault enters on
+//!   bare metal. The processor stays virtualized and waits for an INIT, so the rest of the system keeps running
+//!   until it needs the processor. Processors without the shutdown activity state bug check instead.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.2 Other Causes of VM Exits
+//! (Triple fault) and 28.2.4 Information for VM Exits During Event Delivery.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            support::{try_vmread, try_vmwrite},
+            vmexit::ExitType,
+            vmx::Vmx,
+        },
+        utils::{capture::GuestRegisters, cpu},
+    },
+    core::fmt,
+    x86::vmx::vmcs::{guest, ro},
+};
+
+#[cfg(feature = "tracing")]
+use crate::intel::exit_history::{ExitRecord, EXIT_HISTORY_LEN};
+
+/// The bug check code of a fatal error detected by a hypervisor, `HYPERVISOR_ERROR`.
+pub const HYPERVISOR_ERROR: u32 = 0x0002_0001;
+
+/// The shutdown guest activity state.
+const ACTIVITY_STATE_SHUTDOWN: u64 = 2;
+
+/// Blocking by STI and by MOV SS in the interruptibility state, only allowed in the active state.
+const BLOCKING_BY_STI_OR_MOV_SS: u64 = 0b11;
+
+/// What happens once a triple fault was dumped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TripleFaultPolicy {
+    /// Leave VMX operation and bug check with `HYPERVISOR_ERROR`.
+    #[default]
+    BugCheck,
+
+    /// Park the guest in the shutdown activity state until an INIT.
+    Halt,
+}
+
+/// A segment register of the guest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SegmentState {
+    pub selector: u64,
+    pub base: u64,
+    pub limit: u64,
+    pub access_rights: u64,
+}
+
+impl SegmentState {
+    /// Reads a segment register from the current VMCS.
+    fn capture(
+        selector: u32,
+        base: u32,
+        limit: u32,
+        access_rights: u32,
+    ) -> Result<Self, HypervisorError> {
+        Ok(Self {
+            selector: try_vmread(selector)?,
+            base: try_vmread(base)?,
+            limit: try_vmread(limit)?,
+            access_rights: try_vmread(access_rights)?,
+        })
+    }
+}
+
+/// The state of the guest when it triple faulted.
+pub struct GuestStateDump {
+    /// The general-purpose registers.
+    pub registers: GuestRegisters,
+
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub dr7: u64,
+    pub efer: u64,
+
+    /// CS, SS, DS, ES, FS, GS, LDTR and TR, in this order.
+    pub segments: [SegmentState; 8],
+
+    pub gdtr_base: u64,
+    pub gdtr_limit: u64,
+    pub idtr_base: u64,
+    pub idtr_limit: u64,
+    pub interruptibility_state: u64,
+    pub activity_state: u64,
+
+    /// The IDT-vectoring information, the event being delivered when the triple fault happened, if valid.
+    pub idt_vectoring_info: u64,
+    pub idt_vectoring_error_code: u64,
+
+    /// The last VM exits of the processor, oldest first.
+    #[cfg(feature = "tracing")]
+    pub exits: [Option<ExitRecord>; EXIT_HISTORY_LEN],
+}
+
+impl GuestStateDump {
+    /// The names of the segment registers, in the order of `segments`.
+    const SEGMENT_NAMES: [&'static str; 8] = ["CS", "SS", "DS", "ES", "FS", "GS", "LDTR", "TR"];
+
+    /// Captures the guest state from the current VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_registers` - The general-purpose registers saved on the VM exit.
+    /// * `vmx` - The VMX instance of the processor, holding the exit history.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the dump, or an error if a field could not be read.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub fn capture(guest_registers: &GuestRegisters, vmx: &Vmx) -> Result<Self, HypervisorError> {
+        #[cfg(feature = "tracing")]
+        let mut exits = [None; EXIT_HISTORY_LEN];
+        #[cfg(feature = "tracing")]
+        for (slot, record) in exits.iter_mut().zip(vmx.exit_history.iter()) {
+            *slot = Some(*record);
+        }
+
+        Ok(Self {
+            registers: *guest_registers,
+            rip: try_vmread(guest::RIP)?,
+            rsp: try_vmread(guest::RSP)?,
+            rflags: try_vmread(guest::RFLAGS)?,
+            cr0: try_vmread(guest::CR0)?,
+            cr3: try_vmread(guest::CR3)?,
+            cr4: try_vmread(guest::CR4)?,
+            dr7: try_vmread(guest::DR7)?,
+            efer: try_vmread(guest::IA32_EFER_FULL)?,
+            segments: [
+                SegmentState::capture(
+                    guest::CS_SELECTOR,
+                    guest::CS_BASE,
+                    guest::CS_LIMIT,
+                    guest::CS_ACCESS_RIGHTS,
+                )?,
+                SegmentState::capture(
+                    guest::SS_SELECTOR,
+                    guest::SS_BASE,
+                    guest::SS_LIMIT,
+                    guest::SS_ACCESS_RIGHTS,
+                )?,
+                SegmentState::capture(
+                    guest::DS_SELECTOR,
+                    guest::DS_BASE,
+                    guest::DS_LIMIT,
+                    guest::DS_ACCESS_RIGHTS,
+                )?,
+                SegmentState::capture(
+                    guest::ES_SELECTOR,
+                    guest::ES_BASE,
+                    guest::ES_LIMIT,
+                    guest::ES_ACCESS_RIGHTS,
+                )?,
+                SegmentState::capture(
+                    guest::FS_SELECTOR,
+                    guest::FS_BASE,
+                    guest::FS_LIMIT,
+                    guest::FS_ACCESS_RIGHTS,
+                )?,
+                SegmentState::capture(
+                    guest::GS_SELECTOR,
+                    guest::GS_BASE,
+                    guest::GS_LIMIT,
+                    guest::GS_ACCESS_RIGHTS,
+                )?,
+                SegmentState::capture(
+                    guest::LDTR_SELECTOR,
+                    guest::LDTR_BASE,
+                    guest::LDTR_LIMIT,
+                    guest::LDTR_ACCESS_RIGHTS,
+                )?,
+                SegmentState::capture(
+                    guest::TR_SELECTOR,
+                    guest::TR_BASE,
+                    guest::TR_LIMIT,
+                    guest::TR_ACCESS_RIGHTS,
+                )?,
+            ],
+            gdtr_base: try_vmread(guest::GDTR_BASE)?,
+            gdtr_limit: try_vmread(guest::GDTR_LIMIT)?,
+            idtr_base: try_vmread(guest::IDTR_BASE)?,
+            idtr_limit: try_vmread(guest::IDTR_LIMIT)?,
+            interruptibility_state: try_vmread(guest::INTERRUPTIBILITY_STATE)?,
+            activity_state: try_vmread(guest::ACTIVITY_STATE)?,
+            idt_vectoring_info: try_vmread(ro::IDT_VECTORING_INFO)?,
+            idt_vectoring_error_code: try_vmread(ro::IDT_VECTORING_ERR_CODE)?,
+            #[cfg(feature = "tracing")]
+            exits,
+        })
+    }
+}
+
+impl fmt::Display for GuestStateDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = &self.registers;
+
+        writeln!(
+            f,
+            "RIP: {:#018x} RSP: {:#018x} RFLAGS: {:#x}",
+            self.rip, self.rsp, self.rflags
+        )?;
+        writeln!(
+            f,
+            "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x} RDX: {:#018x}",
+            r.rax, r.rbx, r.rcx, r.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI: {:#018x} RDI: {:#018x} RBP: {:#018x}",
+            r.rsi, r.rdi, r.rbp
+        )?;
+        writeln!(
+            f,
+            "R8:  {:#018x} R9:  {:#018x} R10: {:#018x} R11: {:#018x}",
+            r.r8, r.r9, r.r10, r.r11
+        )?;
+        writeln!(
+            f,
+            "R12: {:#018x} R13: {:#018x} R14: {:#018x} R15: {:#018x}",
+            r.r12, r.r13, r.r14, r.r15
+        )?;
+        writeln!(
+            f,
+            "CR0: {:#x} CR3: {:#x} CR4: {:#x} DR7: {:#x} EFER: {:#x}",
+            self.cr0, self.cr3, self.cr4, self.dr7, self.efer
+        )?;
+
+        for (name, segment) in Self::SEGMENT_NAMES.iter().zip(&self.segments) {
+            writeln!(
+                f,
+                "{:<4} {:#06x} base {:#018x} limit {:#010x} access {:#x}",
+                name, segment.selector, segment.base, segment.limit, segment.access_rights
+            )?;
+        }
+
+        writeln!(
+            f,
+            "GDTR {:#018x}/{:#x} IDTR {:#018x}/{:#x}",
+            self.gdtr_base, self.gdtr_limit, self.idtr_base, self.idtr_limit
+        )?;
+        writeln!(
+            f,
+            "Interruptibility: {:#x} Activity: {:#x}",
+            self.interruptibility_state, self.activity_state
+        )?;
+        writeln!(
+            f,
+            "IDT vectoring: {:#x} error code {:#x}",
+            self.idt_vectoring_info, self.idt_vectoring_error_code
+        )?;
+
+        #[cfg(feature = "tracing")]
+        {
+            writeln!(f, "Last VM exits, oldest first:")?;
+            for exit in self.exits.iter().flatten() {
+                writeln!(
+                    f,
+                    "  {:#018x} at RIP {:#018x}: {}",
+                    exit.tsc, exit.rip, exit.reason
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Handles a triple fault VM exit by dumping the guest state and applying the `TripleFaultPolicy`.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - To resume the guest in the shutdown activity state.
+/// * `Ok(ExitType::ExitHypervisor)` - To leave VMX operation and bug check, see `Vmx::bug_check`.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 2.
+#[rustfmt::skip]
+pub fn handle_triple_fault(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> Result<ExitType, HypervisorError> {
+    vmx.triple_faults += 1;
+
+    let dump = GuestStateDump::capture(guest_registers, vmx)?;
+    log::error!("Guest triple fault #{} at RIP {:#x}:\n{}", vmx.triple_faults, dump.rip, dump);
+
+    // Only an NMI, a machine check or an INIT leaves the shutdown state, the events queued would never be delivered.
+    if vmx.shared_data().triple_fault_policy == TripleFaultPolicy::Halt && cpu::has_shutdown_activity_state() {
+        log::error!("Halting the processor in the shutdown state");
+        vmx.pending_events.clear();
+        let interruptibility = try_vmread(guest::INTERRUPTIBILITY_STATE)?;
+        try_vmwrite(guest::INTERRUPTIBILITY_STATE, interruptibility & !BLOCKING_BY_STI_OR_MOV_SS)?;
+        try_vmwrite(guest::ACTIVITY_STATE, ACTIVITY_STATE_SHUTDOWN)?;
+        return Ok(ExitType::Continue);
+    }
+
+    vmx.bug_check = Some([dump.rip, dump.rsp, dump.cr3, dump.idt_vectoring_info]);
+    Ok(ExitType::ExitHypervisor)
+}
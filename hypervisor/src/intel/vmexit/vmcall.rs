@@ -0,0 +1,930 @@
+//! Handles VMCALL, the hypercall instruction of cooperative guests.
+//!
+//! Hypercalls are only served while the paravirtual interface advertises them. Otherwise VMCALL raises #UD,
+//! as it does on a processor that is not in VMX non-root operation, so the hypervisor stays hidden.
+//! See `intel::hypercall` for the calling convention.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            agent_monitor::MAX_AGENT_PAGES,
+            ept::paging::AccessType,
+            events::EventInjection,
+            host_call::{HostCall, HostCallStatus, HOST_CALL_MAGIC},
+            hypercall::{HypercallCode, HypercallStatus},
+            invept::{invept_all_contexts, invept_all_processors, invept_broadcast},
+            paravirt::ParavirtFeatures,
+            rate_limit::{ExitClass, Verdict},
+            sandbox::detonate,
+            sessions::ClientRole,
+            support::{try_vmread, try_vmwrite},
+            vmexit::{exception::handle_undefined_opcode_exception, ExitType},
+            vmx::Vmx,
+        },
+        utils::{addresses::Gpa, capture::GuestRegisters},
+    },
+    x86::vmx::vmcs::{guest, ro},
+};
+
+#[cfg(feature = "introspection")]
+use crate::intel::capabilities::CapabilityList;
+#[cfg(feature = "introspection")]
+use crate::intel::effective_config::{export_text, EffectiveConfig, VmcsControls};
+#[cfg(feature = "introspection")]
+use crate::intel::fault_injection::{FaultKind, FaultTrigger};
+#[cfg(feature = "introspection")]
+use crate::intel::guest_memory::{output_page, GuestMemory, GuestPageFault};
+#[cfg(feature = "introspection")]
+use crate::intel::heap_poison::{PoisonedZone, MAX_POISONED_ZONES};
+#[cfg(feature = "introspection")]
+use crate::intel::metrics;
+#[cfg(feature = "introspection")]
+use crate::intel::shared_data::SharedData;
+#[cfg(feature = "introspection")]
+use crate::intel::vmexit::ept::current_ept;
+#[cfg(feature = "introspection")]
+use crate::utils::addresses::Gva;
+#[cfg(feature = "introspection")]
+use crate::utils::cancellation::CancellationToken;
+#[cfg(feature = "introspection")]
+use crate::utils::text::TextWindow;
+#[cfg(feature = "introspection")]
+use x86::vmx::vmcs::control;
+
+/// Handles a VMCALL VM exit.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's register state.
+/// * `vmx` - A mutable reference to the Vmx structure representing the current VM.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - The hypercall was served, its status is in RAX.
+/// * `Ok(ExitType::Continue)` - An exception was injected instead, #UD for an unknown hypercall code.
+pub fn handle_vmcall(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling VMCALL VM exit...");
+
+    // The host calls of the `Hypervisor` API are served regardless of the paravirtual interface.
+    if is_host_call(guest_registers, vmx)? {
+        return handle_host_call(guest_registers, vmx);
+    }
+
+    if !vmx
+        .shared_data()
+        .paravirt
+        .offers(ParavirtFeatures::HYPERCALLS)
+    {
+        return handle_undefined_opcode_exception();
+    }
+
+    // Hypercalls are reserved to the guest kernel.
+    if try_vmread(guest::CS_SELECTOR)? & 0b11 != 0 {
+        EventInjection::vmentry_inject_gp(0)?;
+        return Ok(ExitType::Continue);
+    }
+
+    // A VMCALL of other software, e.g. meant for another hypervisor, faults as on bare metal instead of having
+    // its RAX overwritten.
+    let Some(code) = HypercallCode::from_u64(guest_registers.rax) else {
+        log::trace!("Unknown hypercall {:#x}", guest_registers.rax);
+        return handle_undefined_opcode_exception();
+    };
+
+    // Back off from guests spamming hypercalls. Only the hypercalls that would be served are counted, so the ones
+    // refused above fault as they would without a quota. The #GP faults on the VMCALL, so RIP stays.
+    if vmx.rate_limiter.check(ExitClass::Hypercall) == Verdict::InjectGp {
+        EventInjection::vmentry_inject_gp(0)?;
+        return Ok(ExitType::Continue);
+    }
+
+    // Client sessions are checked before the hypercall has any effect.
+    let status = match vmx
+        .shared_data()
+        .client_sessions
+        .authorize(guest_registers.r8, code.access())
+    {
+        Ok(()) => dispatch(code, guest_registers, vmx)?,
+        Err(status) => status,
+    };
+
+    log::trace!("Hypercall {:#x}: {:?}", guest_registers.rax, status);
+    guest_registers.rax = status as u64;
+
+    log::debug!("VMCALL VM exit handled successfully!");
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Returns whether a VMCALL is a host call of the `Hypervisor` API, see `intel::host_call`.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest's register state at the VMCALL.
+/// * `vmx` - The VMX instance of the current processor.
+pub fn is_host_call(
+    guest_registers: &GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<bool, HypervisorError> {
+    Ok(guest_registers.rax == HOST_CALL_MAGIC
+        && guest_registers.rcx == vmx.shared_data().host_call_key
+        && try_vmread(guest::CS_SELECTOR)? & 0b11 == 0)
+}
+
+/// Serves a host call of the `Hypervisor` API.
+///
+/// # Arguments
+///
+/// * `guest_registers` - The guest's register state at the VMCALL.
+/// * `vmx` - The VMX instance of the current processor.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - The call was served, its `HostCallStatus` is in RAX.
+/// * `Ok(ExitType::ExitHypervisor)` - The processor leaves VMX operation and resumes the guest natively after the
+///   VMCALL, see `vmlaunch::devirtualize_to_guest`.
+/// * `Ok(ExitType::Continue)` - The guest enters the sandbox, and resumes after the VMCALL once the run ended.
+fn handle_host_call(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    let status = match HostCall::from_u64(guest_registers.rdx) {
+        Some(HostCall::FlushEpt) => {
+            invept_all_contexts();
+            HostCallStatus::Success
+        }
+        // The events would be lost, so they are injected first and the guest calls again.
+        Some(HostCall::Devirtualize) if !vmx.pending_events.is_empty() => HostCallStatus::Retry,
+        Some(HostCall::Devirtualize) => {
+            log::trace!("Leaving VMX operation on request");
+
+            guest_registers.rax = HostCallStatus::Success as u64;
+            guest_registers.rip = guest_registers
+                .rip
+                .wrapping_add(try_vmread(ro::VMEXIT_INSTRUCTION_LEN)?);
+            try_vmwrite(guest::RIP, guest_registers.rip)?;
+
+            // The guest resumes natively from the state of the last exit, which is this one past the VMCALL.
+            vmx.entry_recovery.capture(guest_registers)?;
+            vmx.teardown_requested = true;
+
+            return Ok(ExitType::ExitHypervisor);
+        }
+        // The events would be injected into the sandbox, so they are injected first and the guest calls again.
+        Some(HostCall::Detonate) if !vmx.pending_events.is_empty() => HostCallStatus::Retry,
+        Some(HostCall::Detonate) => {
+            // The saved registers are restored when the run ends, with RAX holding the status of the call.
+            guest_registers.rax = HostCallStatus::Success as u64;
+            let resume_rip = guest_registers
+                .rip
+                .wrapping_add(try_vmread(ro::VMEXIT_INSTRUCTION_LEN)?);
+
+            match detonate(guest_registers, vmx, resume_rip) {
+                Err(HypervisorError::SandboxUnavailable) => HostCallStatus::Failed,
+                result => return result,
+            }
+        }
+        None => HostCallStatus::Unknown,
+    };
+
+    guest_registers.rax = status as u64;
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Serves an authorized hypercall.
+///
+/// # Returns
+///
+/// The status of the hypercall, returned in RAX.
+fn dispatch(
+    code: HypercallCode,
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<HypercallStatus, HypervisorError> {
+    let status = match code {
+        HypercallCode::Identify => match vmx.shared_data().paravirt.identify() {
+            Some((rbx, rcx)) => {
+                guest_registers.rbx = rbx;
+                guest_registers.rcx = rcx;
+                HypercallStatus::Success
+            }
+            None => HypercallStatus::NotSupported,
+        },
+        HypercallCode::SessionOpen => session_open(guest_registers, vmx),
+        HypercallCode::SessionClose => {
+            match vmx.shared_data().client_sessions.close(guest_registers.r8) {
+                Ok(()) => HypercallStatus::Success,
+                Err(status) => status,
+            }
+        }
+        HypercallCode::AgentRegister => agent_register(guest_registers, vmx)?,
+        HypercallCode::AgentChallenge => {
+            let nonce = vmx.rng.next_u64();
+            match vmx
+                .shared_data()
+                .agent_monitor
+                .challenge(guest_registers.rbx, nonce)
+            {
+                Ok(nonce) => {
+                    guest_registers.rbx = nonce;
+                    HypercallStatus::Success
+                }
+                Err(status) => status,
+            }
+        }
+        HypercallCode::AgentRespond => {
+            match vmx.shared_data().agent_monitor.respond(guest_registers.rbx) {
+                Ok(()) => HypercallStatus::Success,
+                Err(status) => status,
+            }
+        }
+        #[cfg(feature = "introspection")]
+        HypercallCode::CoverageReset => coverage_reset(guest_registers, vmx)?,
+        #[cfg(feature = "introspection")]
+        HypercallCode::CoverageRead => coverage_read(guest_registers, vmx),
+        #[cfg(feature = "introspection")]
+        HypercallCode::FaultFlipBits => fault_flip_bits(guest_registers, vmx),
+        #[cfg(feature = "introspection")]
+        HypercallCode::FaultFailCalls => fault_fail_calls(guest_registers, vmx)?,
+        #[cfg(feature = "introspection")]
+        HypercallCode::FaultRaiseException => fault_raise_exception(guest_registers, vmx)?,
+        #[cfg(feature = "introspection")]
+        HypercallCode::FaultClear => fault_clear(vmx)?,
+        #[cfg(feature = "introspection")]
+        HypercallCode::EptDump => ept_dump(guest_registers, vmx),
+        #[cfg(feature = "introspection")]
+        HypercallCode::GetEffectiveConfig => get_effective_config(guest_registers, vmx)?,
+        #[cfg(feature = "introspection")]
+        HypercallCode::HeapPoison => heap_poison(guest_registers, vmx)?,
+        #[cfg(feature = "introspection")]
+        HypercallCode::HeapUnpoison => heap_unpoison(guest_registers, vmx)?,
+        #[cfg(feature = "introspection")]
+        HypercallCode::GetMetrics => get_metrics(guest_registers, vmx),
+        #[cfg(feature = "introspection")]
+        HypercallCode::ListProcesses => list_processes(guest_registers, vmx),
+        #[cfg(feature = "introspection")]
+        HypercallCode::TerminateProcess => terminate_process(guest_registers, vmx),
+        #[cfg(feature = "introspection")]
+        HypercallCode::ListCapabilities => list_capabilities(guest_registers, vmx),
+    };
+
+    Ok(status)
+}
+
+/// Dumps the EPT entries of the RCX pages at RBX into the page at RDX, which the guest must be allowed to write.
+///
+/// On success, the length of the text is returned in RBX and the number of problems found in RCX. A dump
+/// cancelled by its deadline or the closing of the session returns the same for the part dumped.
+#[cfg(feature = "introspection")]
+fn ept_dump(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> HypercallStatus {
+    let start = Gpa::new(guest_registers.rbx).page_base();
+    let pages = guest_registers.rcx;
+    let buffer = Gpa::new(guest_registers.rdx);
+
+    let Some(end) = pages
+        .checked_mul(0x1000)
+        .and_then(|size| start.as_u64().checked_add(size))
+    else {
+        return HypercallStatus::InvalidParameter;
+    };
+
+    if pages == 0 {
+        return HypercallStatus::InvalidParameter;
+    }
+
+    let Some(buffer) = hypercall_output(vmx, buffer) else {
+        return HypercallStatus::InvalidParameter;
+    };
+    let mut text = TextWindow::until_full(buffer);
+
+    let shared_data = vmx.shared_data();
+    let local = CancellationToken::new();
+    let cancellation = begin_operation(shared_data, guest_registers.r8, &local);
+
+    // A dump that does not fit is cut off where the page is full, without the summary line.
+    let summary = shared_data
+        .primary_ept
+        .dump(start..Gpa::new(end))
+        .cancellable(cancellation)
+        .write_to(&mut text);
+
+    guest_registers.rbx = text.copied() as u64;
+    guest_registers.rcx = summary.issues as u64;
+
+    match summary.cancelled {
+        true => HypercallStatus::Cancelled,
+        false => HypercallStatus::Success,
+    }
+}
+
+/// Copies the snapshot of the effective configuration, from the offset in RCX on, into the page at RBX. Offset 0
+/// takes a new snapshot, the following offsets read the one whose generation is in RDX.
+///
+/// On success, the number of bytes copied is returned in RBX, the length of the whole text in RCX and the
+/// generation of the snapshot in RDX.
+#[cfg(feature = "introspection")]
+fn get_effective_config(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<HypercallStatus, HypervisorError> {
+    let Ok(offset) = usize::try_from(guest_registers.rcx) else {
+        return Ok(HypercallStatus::InvalidParameter);
+    };
+
+    let vmcs = VmcsControls::capture()?;
+    let Some(buffer) = hypercall_output(vmx, Gpa::new(guest_registers.rbx)) else {
+        return Ok(HypercallStatus::InvalidParameter);
+    };
+
+    let shared_data = vmx.shared_data();
+    let config = EffectiveConfig::new(shared_data).with_vmcs(vmcs);
+    let Some(export) =
+        shared_data
+            .config_snapshot
+            .read(&config, buffer, offset, guest_registers.rdx)
+    else {
+        return Ok(HypercallStatus::Retry);
+    };
+
+    guest_registers.rbx = export.copied as u64;
+    guest_registers.rcx = export.total as u64;
+    guest_registers.rdx = export.generation;
+
+    Ok(HypercallStatus::Success)
+}
+
+/// Copies the list of commands from the offset in RCX into the page at RBX.
+///
+/// On success, the number of bytes copied is returned in RBX and the length of the whole text in RCX.
+#[cfg(feature = "introspection")]
+fn list_capabilities(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> HypercallStatus {
+    let Ok(offset) = usize::try_from(guest_registers.rcx) else {
+        return HypercallStatus::InvalidParameter;
+    };
+
+    let Some(buffer) = hypercall_output(vmx, Gpa::new(guest_registers.rbx)) else {
+        return HypercallStatus::InvalidParameter;
+    };
+
+    let export = export_text(&CapabilityList::new(vmx.shared_data()), buffer, offset);
+
+    guest_registers.rbx = export.copied as u64;
+    guest_registers.rcx = export.total as u64;
+
+    HypercallStatus::Success
+}
+
+/// Copies the metrics snapshot from the offset in RCX into the page at RBX.
+///
+/// On success, the number of bytes copied is returned in RBX and the length of the whole snapshot in RCX.
+#[cfg(feature = "introspection")]
+fn get_metrics(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> HypercallStatus {
+    let Ok(offset) = usize::try_from(guest_registers.rcx) else {
+        return HypercallStatus::InvalidParameter;
+    };
+
+    let Some(buffer) = hypercall_output(vmx, Gpa::new(guest_registers.rbx)) else {
+        return HypercallStatus::InvalidParameter;
+    };
+
+    let export = metrics::export(vmx.shared_data(), buffer, offset);
+
+    guest_registers.rbx = export.copied as u64;
+    guest_registers.rcx = export.total as u64;
+
+    HypercallStatus::Success
+}
+
+/// Copies the process records, from the index in RCX on, into the page at RBX.
+///
+/// On success, the number of records copied is returned in RBX and the number of processes in RCX. A listing
+/// cancelled by its deadline or the closing of the session returns the same for the processes walked.
+#[cfg(feature = "introspection")]
+fn list_processes(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> HypercallStatus {
+    let Ok(first) = usize::try_from(guest_registers.rcx) else {
+        return HypercallStatus::InvalidParameter;
+    };
+
+    let Some(buffer) = hypercall_output(vmx, Gpa::new(guest_registers.rbx)) else {
+        return HypercallStatus::InvalidParameter;
+    };
+
+    let shared_data = vmx.shared_data();
+    let local = CancellationToken::new();
+    let cancellation = begin_operation(shared_data, guest_registers.r8, &local);
+
+    match shared_data.processes.export(buffer, first, cancellation) {
+        Ok(export) => {
+            guest_registers.rbx = export.copied as u64;
+            guest_registers.rcx = export.total as u64;
+
+            match export.cancelled {
+                true => HypercallStatus::Cancelled,
+                false => HypercallStatus::Success,
+            }
+        }
+        Err(status) => status,
+    }
+}
+
+/// Queues the process with the ID in RBX for termination.
+#[cfg(feature = "introspection")]
+fn terminate_process(guest_registers: &GuestRegisters, vmx: &mut Vmx) -> HypercallStatus {
+    let shared_data = vmx.shared_data();
+    let local = CancellationToken::new();
+    let cancellation = begin_operation(shared_data, guest_registers.r8, &local);
+
+    match shared_data
+        .processes
+        .request_termination(guest_registers.rbx, cancellation)
+    {
+        Ok(()) => HypercallStatus::Success,
+        Err(status) => status,
+    }
+}
+
+/// Arms the cancellation of a long-running hypercall, see `utils::cancellation`.
+///
+/// # Arguments
+///
+/// * `session` - The token of the client session, passed in R8.
+/// * `local` - The token used without client sessions.
+///
+/// # Returns
+///
+/// The token of the session, also cancelled when the session is closed, or `local`, which can only time out.
+#[cfg(feature = "introspection")]
+fn begin_operation<'a>(
+    shared_data: &'a SharedData,
+    session: u64,
+    local: &'a CancellationToken,
+) -> &'a CancellationToken {
+    let timeout = shared_data.root_operation_timeout;
+
+    shared_data
+        .client_sessions
+        .begin_operation(session, timeout)
+        .unwrap_or_else(|| {
+            local.arm(timeout);
+            local
+        })
+}
+
+/// Maps the page a hypercall copies its output to, which the EPT of the current view must let the guest write,
+/// see `guest_memory::output_page`.
+#[cfg(feature = "introspection")]
+fn hypercall_output(vmx: &mut Vmx, address: Gpa) -> Option<&'static mut [u8]> {
+    let eptp = try_vmread(control::EPTP_FULL).ok()?;
+    output_page(address, current_ept(vmx.shared_data(), eptp))
+}
+
+/// Write-protects the pages of the guest agent and registers it.
+///
+/// RBX holds the guest physical address of the array of page addresses and RCX the number of entries,
+/// which must not cross a page boundary. On success, the key is returned in RBX and RCX.
+fn agent_register(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<HypercallStatus, HypervisorError> {
+    let list = Gpa::new(guest_registers.rbx);
+    let count = guest_registers.rcx as usize;
+
+    if count > MAX_AGENT_PAGES
+        || list.as_u64() & 0b111 != 0
+        || list.page_offset() as usize + count * 8 > 0x1000
+    {
+        return Ok(HypercallStatus::InvalidParameter);
+    }
+
+    let mut pages = [Gpa::default(); MAX_AGENT_PAGES];
+    let Some(pages) = pages.get_mut(..count) else {
+        return Ok(HypercallStatus::InvalidParameter);
+    };
+
+    if count != 0 {
+        let Some(va) = list.to_hva() else {
+            return Ok(HypercallStatus::InvalidParameter);
+        };
+
+        for (i, page) in pages.iter_mut().enumerate() {
+            *page = Gpa::new(unsafe { core::ptr::read_volatile(va.as_ptr::<u64>().add(i)) });
+        }
+    }
+
+    let key = [vmx.rng.next_u64(), vmx.rng.next_u64()];
+    let shared_data = vmx.shared_data();
+
+    if let Err(status) = shared_data.agent_monitor.check_registration(pages) {
+        return Ok(status);
+    }
+
+    // The pages are protected before the agent is registered, so a registered agent never has unprotected pages.
+    let mut saved = [None; MAX_AGENT_PAGES];
+    let mut result = Ok(());
+
+    for (&page, saved) in pages.iter().zip(saved.iter_mut()) {
+        result = shared_data.page_access(page).and_then(|access| {
+            *saved = Some(access);
+            shared_data.set_page_access(page, AccessType::READ_EXECUTE)
+        });
+        if result.is_err() {
+            break;
+        }
+    }
+
+    // A failure is the status or the error to return once the protection is lifted again.
+    let registered = match result {
+        Ok(()) => shared_data.agent_monitor.register(pages, key).map_err(Ok),
+        Err(error) => Err(Err(error)),
+    };
+
+    let key = match registered {
+        Ok(key) => key,
+        Err(failure) => {
+            // Only permissions are given back, which the other processors pick up at their next exit.
+            for access in saved.into_iter().flatten() {
+                shared_data.restore_page_access(access)?;
+            }
+            invept_all_processors();
+            return failure;
+        }
+    };
+
+    // The pages are made read-only, which must not wait for the next exit of the other processors.
+    invept_broadcast();
+
+    let [low, high] = key;
+    guest_registers.rbx = low;
+    guest_registers.rcx = high;
+
+    Ok(HypercallStatus::Success)
+}
+
+/// Ends a fuzzing iteration and makes the pages of the coverage target non-executable again.
+///
+/// The other processors are kicked to flush their translations, so no fetch from the target goes unrecorded. On
+/// success, the coverage of the iteration is returned in RBX and RCX.
+#[cfg(feature = "introspection")]
+fn coverage_reset(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<HypercallStatus, HypervisorError> {
+    let shared_data = vmx.shared_data();
+    if !shared_data.coverage.is_enabled() {
+        return Ok(HypercallStatus::NotSupported);
+    }
+
+    shared_data.arm_coverage()?;
+    invept_broadcast();
+
+    let summary = shared_data.coverage.reset();
+    guest_registers.rbx = summary.hit_pages;
+    guest_registers.rcx = summary.new_pages;
+
+    Ok(HypercallStatus::Success)
+}
+
+/// Copies the coverage bitmap into a guest buffer.
+///
+/// RBX holds the guest physical address of the buffer and RCX its size, which must not cross a page
+/// boundary and must be writable by the guest, see `hypercall_output`. On success, the number of bits of the
+/// bitmap is returned in RBX.
+#[cfg(feature = "introspection")]
+fn coverage_read(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> HypercallStatus {
+    let coverage = &vmx.shared_data().coverage;
+    if !coverage.is_enabled() {
+        return HypercallStatus::NotSupported;
+    }
+
+    let buffer = Gpa::new(guest_registers.rbx);
+    let len = guest_registers.rcx as usize;
+
+    if len < coverage.bitmap_len() {
+        return HypercallStatus::InvalidParameter;
+    }
+
+    let offset = buffer.page_offset() as usize;
+    let Some(buffer) = hypercall_output(vmx, buffer.page_base())
+        .and_then(|page| page.get_mut(offset..offset.checked_add(len)?))
+    else {
+        return HypercallStatus::InvalidParameter;
+    };
+
+    let coverage = &vmx.shared_data().coverage;
+    coverage.copy_bitmap(buffer);
+
+    guest_registers.rbx = coverage.page_count() as u64;
+
+    HypercallStatus::Success
+}
+
+/// Flips the bits in RCX of the guest quadword at the guest physical address in RBX.
+#[cfg(feature = "introspection")]
+fn fault_flip_bits(guest_registers: &GuestRegisters, vmx: &mut Vmx) -> HypercallStatus {
+    let fault_injector = &vmx.shared_data().fault_injector;
+    if !fault_injector.is_enabled() {
+        return HypercallStatus::NotSupported;
+    }
+
+    match fault_injector.flip_bits(
+        Gpa::new(guest_registers.rbx),
+        guest_registers.rcx,
+        guest_registers.rip,
+    ) {
+        Ok(()) => HypercallStatus::Success,
+        Err(status) => status,
+    }
+}
+
+/// Fails the next RCX calls to the routine at RBX, which returns RDX instead.
+#[cfg(feature = "introspection")]
+fn fault_fail_calls(
+    guest_registers: &GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<HypercallStatus, HypervisorError> {
+    if !vmx.shared_data().fault_injector.is_enabled() {
+        return Ok(HypercallStatus::NotSupported);
+    }
+
+    if guest_registers.rcx == 0 {
+        return Ok(HypercallStatus::InvalidParameter);
+    }
+
+    let kind = FaultKind::FailCall {
+        return_value: guest_registers.rdx,
+    };
+
+    fault_arm(
+        Gva::new(guest_registers.rbx),
+        kind,
+        guest_registers.rcx,
+        vmx,
+    )
+}
+
+/// Raises the exception with the vector in RCX when the instruction at RBX is executed. For a page fault,
+/// RDX holds the faulting address.
+#[cfg(feature = "introspection")]
+fn fault_raise_exception(
+    guest_registers: &GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<HypercallStatus, HypervisorError> {
+    if !vmx.shared_data().fault_injector.is_enabled() {
+        return Ok(HypercallStatus::NotSupported);
+    }
+
+    // The page fault is raised as a supervisor read of a non-present page.
+    let kind = match guest_registers.rcx {
+        14 => FaultKind::PageFault {
+            address: Gva::new(guest_registers.rdx),
+            error_code: 0,
+        },
+        18 => FaultKind::MachineCheck,
+        _ => return Ok(HypercallStatus::InvalidParameter),
+    };
+
+    fault_arm(Gva::new(guest_registers.rbx), kind, 1, vmx)
+}
+
+/// Arms a fault at a guest instruction and makes the page holding it non-executable. The caller checked that
+/// fault injection is enabled.
+#[cfg(feature = "introspection")]
+fn fault_arm(
+    address: Gva,
+    kind: FaultKind,
+    count: u64,
+    vmx: &mut Vmx,
+) -> Result<HypercallStatus, HypervisorError> {
+    let shared_data = vmx.shared_data();
+
+    let Ok(guest_pa) = GuestMemory::current()?.translate(address, false) else {
+        return Ok(HypercallStatus::InvalidParameter);
+    };
+
+    let trigger = FaultTrigger {
+        address,
+        page: guest_pa.page_base(),
+        kind,
+        remaining: count,
+    };
+
+    let access = shared_data.page_access(trigger.page)?;
+    let saved = match shared_data.fault_injector.arm(trigger, access) {
+        Ok(saved) => saved,
+        Err(status) => return Ok(status),
+    };
+
+    shared_data.restore_page_access(saved.without(AccessType::EXECUTE))?;
+    invept_broadcast();
+
+    Ok(HypercallStatus::Success)
+}
+
+/// Disarms all call and exception faults and restores the permissions of their pages.
+#[cfg(feature = "introspection")]
+fn fault_clear(vmx: &mut Vmx) -> Result<HypercallStatus, HypervisorError> {
+    let shared_data = vmx.shared_data();
+    if !shared_data.fault_injector.is_enabled() {
+        return Ok(HypercallStatus::NotSupported);
+    }
+
+    for saved in shared_data
+        .fault_injector
+        .disarm_all()
+        .into_iter()
+        .flatten()
+    {
+        shared_data.restore_page_access(saved)?;
+    }
+    invept_all_processors();
+
+    Ok(HypercallStatus::Success)
+}
+
+/// Poisons the RCX bytes of guest memory at the linear address in RBX and removes all permissions from the
+/// pages holding them. Nothing is poisoned if a page of the range is not mapped, too many zones are poisoned or the
+/// translation of the range is cancelled.
+#[cfg(feature = "introspection")]
+fn heap_poison(
+    guest_registers: &GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<HypercallStatus, HypervisorError> {
+    let shared_data = vmx.shared_data();
+    if !shared_data.heap_poison.is_enabled() {
+        return Ok(HypercallStatus::NotSupported);
+    }
+
+    let address = Gva::new(guest_registers.rbx);
+    let len = guest_registers.rcx;
+    if len == 0 || address.as_u64().checked_add(len).is_none() {
+        return Ok(HypercallStatus::InvalidParameter);
+    }
+
+    let memory = GuestMemory::current()?;
+    let local = CancellationToken::new();
+    let cancellation = begin_operation(shared_data, guest_registers.r8, &local);
+
+    for piece in poison_pieces(memory, address, len) {
+        if cancellation.is_cancelled() {
+            return Ok(HypercallStatus::Cancelled);
+        }
+        if piece.is_err() {
+            return Ok(HypercallStatus::InvalidParameter);
+        }
+    }
+
+    for (piece, guest_pa, piece_len) in poison_pieces(memory, address, len).flatten() {
+        let zone = PoisonedZone {
+            address: piece,
+            guest_pa,
+            len: piece_len,
+        };
+
+        let access = shared_data.page_access(guest_pa.page_base())?;
+        if let Err(status) = shared_data.heap_poison.poison(zone, access) {
+            remove_poison(shared_data, memory, address, piece - address)?;
+            invept_broadcast();
+            return Ok(status);
+        }
+
+        shared_data.set_page_access(guest_pa.page_base(), AccessType::empty())?;
+    }
+    invept_broadcast();
+
+    Ok(HypercallStatus::Success)
+}
+
+/// Removes the poison from the RCX bytes of guest memory at the linear address in RBX, and restores the
+/// permissions of the pages no longer holding a poisoned zone. Stops at the first zone that cannot be split for
+/// lack of free slots, answering `HypercallStatus::InsufficientResources`, or once cancelled.
+///
+/// The number of bytes the poison was removed from is returned in RBX.
+#[cfg(feature = "introspection")]
+fn heap_unpoison(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<HypercallStatus, HypervisorError> {
+    let shared_data = vmx.shared_data();
+    if !shared_data.heap_poison.is_enabled() {
+        return Ok(HypercallStatus::NotSupported);
+    }
+
+    let address = Gva::new(guest_registers.rbx);
+    let len = guest_registers.rcx;
+    if address.as_u64().checked_add(len).is_none() {
+        return Ok(HypercallStatus::InvalidParameter);
+    }
+
+    let memory = GuestMemory::current()?;
+    let local = CancellationToken::new();
+    let cancellation = begin_operation(shared_data, guest_registers.r8, &local);
+
+    // Each page released held a zone, so their permissions are restored once the token is no longer needed.
+    let mut released = [None; MAX_POISONED_ZONES];
+    let mut slots = released.iter_mut();
+    let mut status = HypercallStatus::Success;
+    let mut removed = len;
+
+    for (piece, guest_pa, piece_len) in poison_pieces(memory, address, len).flatten() {
+        let result = match cancellation.is_cancelled() {
+            true => Err(HypercallStatus::Cancelled),
+            false => shared_data.heap_poison.unpoison(guest_pa, piece_len),
+        };
+
+        match result {
+            Ok(Some(saved)) => {
+                if let Some(slot) = slots.next() {
+                    *slot = Some(saved);
+                }
+            }
+            Ok(None) => {}
+            Err(error) => {
+                status = error;
+                removed = piece - address;
+                break;
+            }
+        }
+    }
+
+    for saved in released.into_iter().flatten() {
+        shared_data.restore_page_access(saved)?;
+    }
+    invept_all_processors();
+
+    guest_registers.rbx = removed;
+    Ok(status)
+}
+
+/// Removes the zones poisoned over a range of guest memory by `heap_poison`, and restores the permissions of
+/// the pages no longer holding a poisoned zone. The caller must invalidate the EPT.
+#[cfg(feature = "introspection")]
+fn remove_poison(
+    shared_data: &mut SharedData,
+    memory: GuestMemory,
+    address: Gva,
+    len: u64,
+) -> Result<(), HypervisorError> {
+    for (piece, guest_pa, piece_len) in poison_pieces(memory, address, len).flatten() {
+        let zone = PoisonedZone {
+            address: piece,
+            guest_pa,
+            len: piece_len,
+        };
+
+        if let Some(saved) = shared_data.heap_poison.remove(zone) {
+            shared_data.restore_page_access(saved)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a range of guest memory into its pieces within a page.
+///
+/// # Returns
+///
+/// The guest linear address, the guest physical address and the length of each piece, or the page fault
+/// translating it.
+#[cfg(feature = "introspection")]
+fn poison_pieces(
+    memory: GuestMemory,
+    address: Gva,
+    len: u64,
+) -> impl Iterator<Item = Result<(Gva, Gpa, u32), GuestPageFault>> {
+    let end = address.as_u64().saturating_add(len);
+
+    core::iter::successors(Some(address), |piece| Some(piece.page_base() + 0x1000))
+        .take_while(move |piece| piece.as_u64() < end && piece.as_u64() >= address.as_u64())
+        .map(move |piece| {
+            let page_end = piece.page_base().as_u64().saturating_add(0x1000);
+            let piece_len = (end.min(page_end) - piece.as_u64()) as u32;
+
+            memory
+                .translate(piece, false)
+                .map(|guest_pa| (piece, guest_pa, piece_len))
+        })
+}
+
+/// Opens a client session with the role in RBX, and for an admin the key in RCX and RDX. On success, the
+/// session token is returned in RBX.
+fn session_open(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> HypercallStatus {
+    let Some(role) = ClientRole::from_u64(guest_registers.rbx) else {
+        return HypercallStatus::InvalidParameter;
+    };
+
+    let key = [guest_registers.rcx, guest_registers.rdx];
+    // Never zero, so a hypercall made with R8 cleared matches no session.
+    let token = vmx.rng.below(u64::MAX) + 1;
+    match vmx.shared_data().client_sessions.open(role, key, token) {
+        Ok(token) => {
+            guest_registers.rbx = token;
+            HypercallStatus::Success
+        }
+        Err(status) => status,
+    }
+}
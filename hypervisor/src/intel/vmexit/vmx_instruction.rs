@@ -0,0 +1,74 @@
+//! Handles the VMX instructions executed by the guest: VMXON, VMXOFF, VMCLEAR, VMPTRLD, VMPTRST, VMREAD,
+//! VMWRITE, VMLAUNCH, VMRESUME, INVEPT, INVVPID and VMFUNC.
+//!
+//! The hypervisor does not support nested virtualization, and hides VMX from the guest. Executed outside of
+//! VMX operation, these instructions raise #UD on bare metal, which is what the guest sees by default, so
+//! probing for a hypervisor with them reveals nothing and cannot reach the VMCS of the hypervisor.
+//!
+//! `VmxInstructionResponse::FailInvalid` makes them fail with VMfailInvalid instead, as on a processor in VMX
+//! operation without a current VMCS, for guests that expect VMX to be available.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.2 Conventions (VMfailInvalid)
+//! and 26.1.2 Instructions That Cause VM Exits Unconditionally.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            support::{try_vmread, try_vmwrite},
+            vmexit::{exception::handle_undefined_opcode_exception, ExitType},
+            vmx::Vmx,
+        },
+        utils::capture::GuestRegisters,
+    },
+    x86::vmx::vmcs::guest,
+};
+
+/// RFLAGS.CF, set by VMfailInvalid.
+const RFLAGS_CF: u64 = 1 << 0;
+
+/// The arithmetic flags cleared by VMfailInvalid: PF, AF, ZF, SF and OF.
+const RFLAGS_VMFAIL_CLEARED: u64 = (1 << 2) | (1 << 4) | (1 << 6) | (1 << 7) | (1 << 11);
+
+/// What a VMX instruction executed by the guest does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VmxInstructionResponse {
+    /// The instruction raises #UD, as on a processor outside of VMX operation.
+    #[default]
+    UndefinedOpcode,
+
+    /// The instruction fails with VMfailInvalid: CF is set and the other arithmetic flags are cleared.
+    FailInvalid,
+}
+
+/// Handles the VM exit of a VMX instruction executed by the guest.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::Continue)` - If #UD was injected.
+/// * `Ok(ExitType::IncrementRIP)` - If the instruction failed with VMfailInvalid.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 19 to
+/// 27, 50, 53 and 59.
+pub fn handle_vmx_instruction(
+    guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
+) -> Result<ExitType, HypervisorError> {
+    log::debug!("Handling VMX instruction VM exit...");
+
+    match vmx.shared_data().vmx_instruction_response {
+        VmxInstructionResponse::UndefinedOpcode => handle_undefined_opcode_exception(),
+        VmxInstructionResponse::FailInvalid => {
+            let rflags = try_vmread(guest::RFLAGS)?;
+            guest_registers.rflags = (rflags & !RFLAGS_VMFAIL_CLEARED) | RFLAGS_CF;
+            try_vmwrite(guest::RFLAGS, guest_registers.rflags)?;
+
+            Ok(ExitType::IncrementRIP)
+        }
+    }
+}
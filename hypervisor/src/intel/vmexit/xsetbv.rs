@@ -1,15 +1,54 @@
 //! Provides handlers for managing VM exits due to the XSETBV instruction, ensuring
 //! controlled manipulation of the XCR0 register by guest VMs.
+//!
+//! XSETBV exits unconditionally, so the hypervisor executes it on behalf of the guest. The value is validated first,
+//! as executing an invalid one in VMX root operation would fault in the hypervisor instead of the guest. A value the
+//! processor would refuse raises #GP(0) in the guest, as on bare metal:
+//! - An XCR other than XCR0.
+//! - A bit not reported as supported by CPUID.(EAX=0DH,ECX=0):EDX:EAX.
+//! - x87 state cleared, AVX state without SSE state, AVX-512 state without AVX state or not all three of its
+//!   components together, only one of the MPX components, or only one of the AMX components, XTILECFG and
+//!   XTILEDATA.
+//!
+//! A valid value is loaded as is, including the bits `x86::controlregs::Xcr0` does not know, such as PKRU and AMX.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 13.3 Enabling the XSAVE Feature Set and
+//! XSAVE-Enabled Features, and XSETBV—Set Extended Control Register.
 
 use {
     crate::{
-        intel::vmexit::ExitType,
+        error::HypervisorError,
+        intel::{events::EventInjection, vmexit::ExitType},
         utils::capture::GuestRegisters,
         utils::instructions::{cr4, cr4_write, xsetbv},
     },
-    x86::controlregs::{Cr4, Xcr0},
+    x86::{
+        controlregs::{Cr4, Xcr0},
+        cpuid::cpuid,
+    },
 };
 
+/// The CPUID leaf enumerating the XSAVE feature set.
+const CPUID_XSAVE_LEAF: u32 = 0xD;
+
+/// XCR0 bit enabling x87 state, which must always be set.
+const XCR0_X87: u64 = 1 << 0;
+
+/// XCR0 bit enabling SSE state.
+const XCR0_SSE: u64 = 1 << 1;
+
+/// XCR0 bit enabling AVX state.
+const XCR0_AVX: u64 = 1 << 2;
+
+/// XCR0 bits enabling the MPX state: BNDREGS and BNDCSR.
+const XCR0_MPX: u64 = 0b11 << 3;
+
+/// XCR0 bits enabling the AVX-512 state: opmask, ZMM_Hi256 and Hi16_ZMM.
+const XCR0_AVX512: u64 = 0b111 << 5;
+
+/// XCR0 bits enabling the AMX state: XTILECFG and XTILEDATA.
+const XCR0_AMX: u64 = 0b11 << 17;
+
 /// Manages the XSETBV instruction during a VM exit. It logs the event, updates
 /// CR4 to enable the necessary feature, sets the XCR0 value, and advances the
 /// guest's instruction pointer.
@@ -20,8 +59,9 @@ use {
 ///
 /// # Returns
 ///
-/// * `ExitType::IncrementRIP` - To move past the `XSETBV` instruction in the VM.
-pub fn handle_xsetbv(guest_registers: &mut GuestRegisters) -> ExitType {
+/// * `Ok(ExitType::IncrementRIP)` - To move past the `XSETBV` instruction in the VM.
+/// * `Ok(ExitType::Continue)` - If the value is invalid and #GP(0) was injected instead.
+pub fn handle_xsetbv(guest_registers: &mut GuestRegisters) -> Result<ExitType, HypervisorError> {
     log::debug!("Handling XSETBV VM VM exit...");
 
     // Extract the XCR (extended control register) number from the guest's RCX register.
@@ -30,11 +70,17 @@ pub fn handle_xsetbv(guest_registers: &mut GuestRegisters) -> ExitType {
     // Combine the guest's RAX and RDX registers to form the 64-bit value for the XCR0 register.
     let value = (guest_registers.rax & 0xffff_ffff) | ((guest_registers.rdx & 0xffff_ffff) << 32);
 
-    // Attempt to create an Xcr0 structure from the given bits.
-    let value = Xcr0::from_bits_truncate(value);
-
     log::trace!("XSETBV executed with xcr: {:#x}, value: {:#x}", xcr, value);
 
+    if xcr != 0 || !is_valid_xcr0(value) {
+        log::trace!("Invalid XSETBV: xcr: {:#x}, value: {:#x}", xcr, value);
+        EventInjection::vmentry_inject_gp(0)?;
+        return Ok(ExitType::Continue);
+    }
+
+    // The value was validated against CPUID, so the bits unknown to `Xcr0` are kept rather than truncated.
+    let value = unsafe { Xcr0::from_bits_unchecked(value) };
+
     // Enable the OS XSAVE feature in CR4 before setting the extended control register value.
     cr4_write(cr4() | Cr4::CR4_ENABLE_OS_XSAVE);
 
@@ -44,5 +90,30 @@ pub fn handle_xsetbv(guest_registers: &mut GuestRegisters) -> ExitType {
     log::debug!("XSETBV VM exit handled successfully!");
 
     // Advance the guest's instruction pointer to the next instruction to be executed.
-    return ExitType::IncrementRIP;
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Checks whether the processor accepts a value for XCR0.
+///
+/// # Arguments
+///
+/// * `value` - The value the guest attempts to load into XCR0.
+///
+/// # Returns
+///
+/// `true` if loading the value does not raise #GP(0), `false` otherwise.
+fn is_valid_xcr0(value: u64) -> bool {
+    let leaf = cpuid!(CPUID_XSAVE_LEAF, 0);
+    let supported = ((leaf.edx as u64) << 32) | leaf.eax as u64;
+
+    let avx512 = value & XCR0_AVX512;
+    let mpx = value & XCR0_MPX;
+    let amx = value & XCR0_AMX;
+
+    value & !supported == 0
+        && value & XCR0_X87 != 0
+        && (value & XCR0_AVX == 0 || value & XCR0_SSE != 0)
+        && (avx512 == 0 || (avx512 == XCR0_AVX512 && value & XCR0_AVX != 0))
+        && (mpx == 0 || mpx == XCR0_MPX)
+        && (amx == 0 || amx == XCR0_AMX)
 }
@@ -10,9 +10,28 @@
 //! Drew: https://github.com/drew-gpf
 
 use crate::{
-    intel::{support::vmread, vmerror::VmInstructionError, vmexit::VmExit, vmx::Vmx},
-    utils::capture::GuestRegisters,
+    intel::{
+        evmcs, ipi,
+        percpu::VCPUS,
+        smm::SmiWindow,
+        support::vmread,
+        vmerror::{VmInstructionError, VmxBasicExitReason},
+        vmexit::{triple_fault::HYPERVISOR_ERROR, ExitType, VmExit},
+        vmx::Vmx,
+    },
+    utils::{
+        capture::GuestRegisters,
+        early_console,
+        processor::{clear_virtualized, current_processor_index},
+        rcu,
+        timestamp::{enter_root_mode, leave_root_mode},
+    },
 };
+use wdk_sys::ntddk::KeBugCheckEx;
+
+/// The bytes `launch_vm` pushes below `host_rsp` before launching the guest: the host general-purpose registers and
+/// the pointer to the guest registers, which `vmexit_stub` finds at HOST_RSP.
+pub const LAUNCH_VM_FRAME_SIZE: u64 = 16 * 8;
 
 extern "C" {
     /// Launches the VM using VMX instructions.
@@ -25,11 +44,24 @@ extern "C" {
     /// # Arguments
     ///
     /// * `general_purpose_registers` - A pointer to the `GuestRegisters` structure
-    /// * `host_rsp` - A pointer to the end of `stack_contents` in the `VmStack` structure.
+    /// * `host_rsp` - A pointer to the end of `stack_contents` in the `VmStack` structure. HOST_RSP must be
+    ///   `LAUNCH_VM_FRAME_SIZE` bytes below it, and HOST_RIP must be `vmexit_stub`.
     pub fn launch_vm(guest_registers: &mut GuestRegisters, host_rsp: *mut u64);
 
     /// Assembly stub for handling VM exits.
     pub fn vmexit_stub();
+
+    /// Resumes the guest natively, once the processor left VMX operation.
+    ///
+    /// Loads the general-purpose and XMM registers and returns to the guest with IRETQ, which loads RIP, CS,
+    /// RFLAGS, RSP and SS.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_registers` - The registers of the guest, including RIP, RSP and RFLAGS.
+    /// * `cs` - The code segment selector of the guest.
+    /// * `ss` - The stack segment selector of the guest.
+    pub fn resume_guest(guest_registers: &GuestRegisters, cs: u64, ss: u64) -> !;
 }
 
 core::arch::global_asm!(
@@ -129,12 +161,7 @@ launch_vm:
     movdqa  xmm14, [r15 + registers_xmm14]
     movdqa  xmm15, [r15 + registers_xmm15]
 
-    // Prepare VMCS for VM launch: set HOST_RSP and HOST_RIP.
-    mov     r14, 0x6C14 // VMCS_HOST_RSP
-    vmwrite r14, rsp
-    lea     r13, [rip + vmexit_stub]
-    mov     r14, 0x6C16 // VMCS_HOST_RIP
-    vmwrite r14, r13
+    // HOST_RSP and HOST_RIP were written by `Vmx::run`, HOST_RSP pointing to this frame.
 
     // Restore additional guest registers.
     mov     r13, [r15 + registers_r13]
@@ -249,6 +276,57 @@ vmexit_stub:
 
     // If VMRESUME fails, handle the failure.
     call vmresume_failed
+
+.global resume_guest
+resume_guest:
+    // Load pointer to guest's register state into r15.
+    mov     r15, rcx
+
+    // Build the frame popped by IRETQ: SS, RSP, RFLAGS, CS and RIP of the guest.
+    push    r8
+    push    qword ptr [r15 + registers_rsp]
+    push    qword ptr [r15 + registers_rflags]
+    push    rdx
+    push    qword ptr [r15 + registers_rip]
+
+    // Restore guest registers.
+    mov     rax, [r15 + registers_rax]
+    mov     rbx, [r15 + registers_rbx]
+    mov     rcx, [r15 + registers_rcx]
+    mov     rdx, [r15 + registers_rdx]
+    mov     rdi, [r15 + registers_rdi]
+    mov     rsi, [r15 + registers_rsi]
+    mov     rbp, [r15 + registers_rbp]
+    mov      r8, [r15 + registers_r8]
+    mov      r9, [r15 + registers_r9]
+    mov     r10, [r15 + registers_r10]
+    mov     r11, [r15 + registers_r11]
+    mov     r12, [r15 + registers_r12]
+    mov     r13, [r15 + registers_r13]
+    mov     r14, [r15 + registers_r14]
+
+    movdqa  xmm0, [r15 + registers_xmm0]
+    movdqa  xmm1, [r15 + registers_xmm1]
+    movdqa  xmm2, [r15 + registers_xmm2]
+    movdqa  xmm3, [r15 + registers_xmm3]
+    movdqa  xmm4, [r15 + registers_xmm4]
+    movdqa  xmm5, [r15 + registers_xmm5]
+    movdqa  xmm6, [r15 + registers_xmm6]
+    movdqa  xmm7, [r15 + registers_xmm7]
+    movdqa  xmm8, [r15 + registers_xmm8]
+    movdqa  xmm9, [r15 + registers_xmm9]
+    movdqa  xmm10, [r15 + registers_xmm10]
+    movdqa  xmm11, [r15 + registers_xmm11]
+    movdqa  xmm12, [r15 + registers_xmm12]
+    movdqa  xmm13, [r15 + registers_xmm13]
+    movdqa  xmm14, [r15 + registers_xmm14]
+    movdqa  xmm15, [r15 + registers_xmm15]
+
+    // Do this last to avoid overwriting r15.
+    mov     r15, [r15 + registers_r15]
+
+    // Return to the guest, outside of VMX operation.
+    iretq
 "#
 );
 
@@ -263,7 +341,10 @@ vmexit_stub:
 ///
 /// # Panics
 ///
-/// Panics if `registers` is a null pointer.
+/// Panics if `registers` or `vmx` is a null pointer, if the VM exit could not be handled, or if the processor
+/// could not leave VMX operation after failed VM entries or a triple fault.
+/// The exit handlers themselves never panic and report failures as `HypervisorError`; this is
+/// the single place where such a failure becomes fatal, as there is no caller to return it to.
 #[no_mangle]
 pub unsafe extern "C" fn vmexit_handler(registers: *mut GuestRegisters, vmx: *mut u64) {
     if registers.is_null() {
@@ -277,9 +358,119 @@ pub unsafe extern "C" fn vmexit_handler(registers: *mut GuestRegisters, vmx: *mu
     let vmx = &mut *(vmx as *mut Vmx);
     let vmexit = VmExit::new();
 
-    if let Err(e) = vmexit.handle_vmexit(registers, vmx) {
-        panic!("Failed to handle VMEXIT: {:?}", e);
+    enter_root_mode();
+    evmcs::begin_exit();
+    ipi::register_processor();
+    vmx.tsc.begin_exit();
+    let guest_smis = vmx.smi_tracker.begin_exit();
+    if guest_smis != 0 {
+        vmx.shared_data().smm_monitor.record_guest(guest_smis);
+    }
+    rcu::begin_exit();
+
+    match vmexit.handle_vmexit(registers, vmx) {
+        Ok(ExitType::ExitHypervisor) => devirtualize_to_guest(registers, vmx),
+        Ok(_) => {}
+        Err(e) => {
+            early_console::emergency(format_args!(
+                "Failed to handle VMEXIT at RIP {:#x}: {:?}",
+                registers.rip, e
+            ));
+            panic!("Failed to handle VMEXIT: {:?}", e);
+        }
+    }
+
+    rcu::end_exit();
+    let root_smis = vmx.smi_tracker.end_exit();
+    if root_smis != 0 {
+        // An SMI during the handling of the exit stretched it, and firmware touching the VMX state may have
+        // corrupted it. Record which exit it was, to correlate with misbehavior of that exit.
+        if let Some(exit_reason) =
+            VmxBasicExitReason::from_u32(vmread(x86::vmx::vmcs::ro::EXIT_REASON) as u32)
+        {
+            vmx.shared_data().smm_monitor.record(
+                SmiWindow::RootMode,
+                root_smis,
+                exit_reason,
+                registers.rip,
+            );
+        }
+    }
+    vmx.tsc.end_exit();
+    leave_root_mode();
+}
+
+/// Leaves VMX operation on the current processor and resumes the guest natively from the last known-good
+/// guest state, after VM entries failed repeatedly or the `Hypervisor` API requested it with a host call. After a
+/// triple fault, bug checks instead, see `Vmx::bug_check`.
+///
+/// The processor is marked as devirtualized, so devirtualizing the system later skips it.
+///
+/// # Arguments
+///
+/// * `registers` - The guest's register state, overwritten with the known-good one.
+/// * `vmx` - The VMX instance of the processor.
+///
+/// # Panics
+///
+/// Panics if no known-good guest state was captured or if VMX operation could not be left.
+unsafe fn devirtualize_to_guest(registers: &mut GuestRegisters, vmx: &mut Vmx) -> ! {
+    if let Some(parameters) = vmx.bug_check {
+        leave_vmx_operation(vmx);
+        let [rip, rsp, cr3, idt_vectoring_info] = parameters;
+        KeBugCheckEx(HYPERVISOR_ERROR, rip, rsp, cr3, idt_vectoring_info);
+    }
+
+    let Some(snapshot) = vmx.entry_recovery.snapshot().copied() else {
+        panic!("No known-good guest state to resume natively");
+    };
+
+    // Decided in VMX root operation, where the KPCR of the guest is still at hand.
+    let cr3 = snapshot.resume_cr3();
+
+    leave_vmx_operation(vmx);
+
+    match vmx.teardown_requested {
+        true => log::trace!(
+            "Devirtualized the processor, resuming the guest at {:#x}",
+            snapshot.rip
+        ),
+        false => log::warn!(
+            "Devirtualized the processor after {} failed VM entries, resuming the guest at {:#x}",
+            vmx.entry_recovery.failures(),
+            snapshot.rip
+        ),
     }
+
+    *registers = snapshot.registers;
+    registers.rip = snapshot.rip;
+    registers.rsp = snapshot.rsp;
+    registers.rflags = snapshot.rflags;
+
+    snapshot.load(cr3);
+    vmx.debug_registers.restore_guest();
+    resume_guest(registers, snapshot.cs_selector, snapshot.ss_selector)
+}
+
+/// Leaves VMX operation on the current processor from the VM-exit handler and marks it as devirtualized.
+///
+/// # Panics
+///
+/// Panics if VMX operation could not be left.
+unsafe fn leave_vmx_operation(vmx: &mut Vmx) {
+    ipi::unregister_processor();
+    if let Err(e) = vmx.teardown() {
+        early_console::emergency(format_args!(
+            "Failed to leave VMX operation to resume the guest natively: {:?}",
+            e
+        ));
+        panic!("Failed to leave VMX operation: {:?}", e);
+    }
+
+    VCPUS.release(current_processor_index());
+    clear_virtualized();
+    rcu::end_exit();
+    leave_root_mode();
 }
 
 /// Handles the failure of the `VMLAUNCH` instruction.
@@ -297,6 +488,11 @@ pub extern "C" fn vmlaunch_failed() {
     //unsafe { core::arch::asm!("int3") };
     let instruction_error = vmread(x86::vmx::vmcs::ro::VM_INSTRUCTION_ERROR) as u32;
 
+    early_console::emergency(format_args!(
+        "VMLAUNCH failed with instruction error {:#x}",
+        instruction_error
+    ));
+
     if let Some(error) = VmInstructionError::from_u32(instruction_error) {
         panic!("VMLAUNCH instruction error: {}", error);
     } else {
@@ -319,6 +515,11 @@ pub extern "C" fn vmresume_failed() {
     //unsafe { core::arch::asm!("int3") };
     let instruction_error = vmread(x86::vmx::vmcs::ro::VM_INSTRUCTION_ERROR) as u32;
 
+    early_console::emergency(format_args!(
+        "VMRESUME failed with instruction error {:#x}",
+        instruction_error
+    ));
+
     if let Some(error) = VmInstructionError::from_u32(instruction_error) {
         panic!("VMRESUME instruction error: {}", error);
     } else {
@@ -4,127 +4,1668 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            ept::{hooks::HookManager, paging::Ept},
+            agent_monitor::{AgentMonitor, AgentMonitorConfig, AgentStatus, TamperEvent},
+            apic_timer,
+            boot_report::BootReport,
+            controls::{EnclsExitingBitmap, PauseLoopConfig, TscMode},
+            cpu_set::CpuSet,
+            debug_registers::{HostBreakpoint, BREAKPOINT_SLOTS},
+            debugger::{DebuggerMonitor, DebuggerPolicy, SuspendReason},
+            driver_blocker::{self, DeniedDriver, DriverBlocker, DriverEvent},
+            entry_recovery::resolve_thread_layout,
+            ept::{
+                hooks::HookManager,
+                paging::{AccessType, Ept, PageAccess},
+                policy::{PermissionProfile, ProtectedRegion, RegionViolation},
+                thrashing::{DisabledHook, ThrashPolicy, ThrashStrategy},
+            },
+            evmcs,
+            host_call, ipi,
+            lbr::LbrStack,
+            msr_policy::{MsrPolicy, MsrRule},
+            nested::HostHypervisor,
+            paravirt::{ParavirtFeatures, ParavirtInterface},
+            platform::PlatformInfo,
+            rate_limit::{Backoff, Quota, RateLimitPolicy},
+            sessions::{ClientSession, ClientSessions},
             shared_data::SharedData,
+            smm::{self, SmiCounts, SmiEvent, SmmMonitor},
+            spin_monitor::SpinStats,
+            topology::{Topology, TopologyConfig},
+            tsc::TscConfig,
+            tsx::{Tsx, TsxPolicy},
             vcpu::Vcpu,
+            vmexit::{
+                cpuid::CpuidMasking, cr::Cr3Observer, ept::EptViolationCallback, hlt::HltCallback,
+                invd::CacheFlushPolicy, mwait::MwaitPolicy, triple_fault::TripleFaultPolicy,
+                vmx_instruction::VmxInstructionResponse,
+            },
+            x2apic,
         },
         utils::{
+            addresses::Gpa,
             alloc::PhysicalAllocator,
-            processor::{processor_count, ProcessorExecutor},
+            chacha,
+            cpu::{self, CoreType, CpuVendor},
+            footprint::{set_memory_cap, MemoryFootprint},
+            processor::{processor_count, ProcessorExecutor, MAX_VCPUS},
+            rcu,
         },
     },
     alloc::{boxed::Box, vec::Vec},
+    core::{mem::ManuallyDrop, ops::Range},
+    x86::msr,
 };
 
+#[cfg(feature = "devices")]
+use crate::intel::io_monitor::IoAccess;
+#[cfg(feature = "devices")]
+use crate::intel::keyboard_guard::{
+    KeyboardAccess, KeyboardGuard, KeyboardProtection, DEFAULT_ALLOWED_MODULES, I8042_COMMAND_PORT,
+    I8042_DATA_PORT,
+};
+#[cfg(feature = "devices")]
+use crate::utils::ssdt::sys_info::Sysinfo;
+#[cfg(feature = "devices")]
+use core::ops::RangeInclusive;
+
+#[cfg(feature = "introspection")]
+use crate::intel::capabilities::CapabilityList;
+#[cfg(feature = "introspection")]
+use crate::intel::coverage::{CoverageMap, CoverageSummary};
+#[cfg(feature = "introspection")]
+use crate::intel::effective_config::{export_text, ConfigExport, EffectiveConfig};
+#[cfg(feature = "introspection")]
+use crate::intel::ept::policy::ViolationResponse;
+#[cfg(feature = "introspection")]
+use crate::intel::fault_injection::{FaultEvent, FaultInjector};
+#[cfg(feature = "introspection")]
+use crate::intel::heap_poison::{HeapPoison, PoisonTouch};
+#[cfg(feature = "introspection")]
+use crate::intel::heat_map::ExitHeatMap;
+#[cfg(feature = "introspection")]
+use crate::intel::hypercall::HypercallStatus;
+#[cfg(feature = "introspection")]
+use crate::intel::metrics::{self, MetricsExport};
+#[cfg(feature = "introspection")]
+use crate::intel::processes::{EprocessLayout, GuestProcess, ProcessControl};
+#[cfg(feature = "introspection")]
+use crate::utils::cancellation::CancellationToken;
+
 #[derive(Default)]
 pub struct HypervisorBuilder {
     /// The primary extended page table.
     primary_ept: Option<Box<Ept, PhysicalAllocator>>,
 
-    #[cfg(feature = "secondary-ept")]
-    /// The secondary extended page table.
-    secondary_ept: Option<Box<Ept, PhysicalAllocator>>,
+    #[cfg(feature = "secondary-ept")]
+    /// The secondary extended page table.
+    secondary_ept: Option<Box<Ept, PhysicalAllocator>>,
+
+    /// The hook manager.
+    hook_manager: Option<Box<HookManager>>,
+
+    /// The maximum amount of memory in bytes the hypervisor is allowed to reserve.
+    memory_cap: Option<u64>,
+
+    /// The features of the paravirtual interface, or `None` to stay in stealth mode.
+    paravirt_features: Option<ParavirtFeatures>,
+
+    /// Whether the x2APIC MSRs are intercepted when the system runs in x2APIC mode.
+    intercept_x2apic: bool,
+
+    /// What to do when a guest debugging session is detected.
+    debugger_policy: DebuggerPolicy,
+
+    /// The quotas of the guest-triggerable exits.
+    rate_limits: RateLimitPolicy,
+
+    /// What to do with keyboard controller accesses from code that is not allow-listed.
+    #[cfg(feature = "devices")]
+    keyboard_protection: KeyboardProtection,
+
+    /// The guest modules allowed to access the keyboard controller, in addition to `DEFAULT_ALLOWED_MODULES`.
+    #[cfg(feature = "devices")]
+    keyboard_allowed_modules: Vec<&'static str>,
+
+    /// The I/O ports whose accesses are intercepted and recorded.
+    #[cfg(feature = "devices")]
+    io_intercepts: Vec<RangeInclusive<u16>>,
+
+    /// Whether the VM exits are counted per guest module.
+    #[cfg(feature = "introspection")]
+    exit_heat_map: bool,
+
+    /// The configuration of the guest agent monitor, or `None` to refuse the agent hypercalls.
+    agent_monitor: Option<AgentMonitorConfig>,
+
+    /// The guest drivers whose execution is blocked.
+    denied_drivers: Vec<DeniedDriver>,
+
+    /// The guest module whose code coverage is collected, if any.
+    #[cfg(feature = "introspection")]
+    coverage_target: Option<&'static str>,
+
+    /// Whether a guest test controller can inject faults through hypercalls.
+    #[cfg(feature = "introspection")]
+    fault_injection: bool,
+
+    /// How accesses to guest memory poisoned through hypercalls are answered, or `None` to not serve them.
+    #[cfg(feature = "introspection")]
+    heap_poisoning: Option<ViolationResponse>,
+
+    /// Whether the guest processes can be listed and terminated through hypercalls.
+    #[cfg(feature = "introspection")]
+    process_control: bool,
+
+    /// The key required to open an admin client session, or `None` if hypercalls require no session.
+    client_sessions: Option<[u64; 2]>,
+
+    /// The configuration of the thrashing detection of hooked pages, or `None` to switch views as usual.
+    thrash_policy: Option<ThrashPolicy>,
+
+    /// The number of failed VM entries retried in a row before a processor is devirtualized, or `None` for
+    /// `DEFAULT_ENTRY_RETRIES`.
+    entry_failure_retries: Option<u32>,
+
+    /// Whether the LBR stack is part of the guest state, saved on VM exit and loaded on VM entry.
+    lbr_virtualization: bool,
+
+    /// What the guest sees of TSX.
+    tsx_policy: TsxPolicy,
+
+    /// The topology reported to the guest through CPUID, or `None` to report the one of the processor.
+    cpuid_topology: Option<TopologyConfig>,
+
+    /// What CPUID hides from the guest about the hypervisor, or `None` to hide the hypervisor present bit
+    /// unless the paravirtual interface is exposed.
+    cpuid_masking: Option<CpuidMasking>,
+
+    /// The policies of the MSRs that are denied or emulated.
+    msr_policies: Vec<(u32, MsrPolicy)>,
+
+    /// Receives the CR3 loads of the guest, or `None` to let them run without exiting.
+    cr3_observer: Option<Cr3Observer>,
+
+    /// The time a long-running hypercall may run in VMX root operation, or `None` for
+    /// `DEFAULT_ROOT_OPERATION_TIMEOUT`.
+    root_operation_timeout: Option<u64>,
+
+    /// How the guest reads the TSC, or `None` for the TSC of the processor.
+    tsc_config: Option<TscConfig>,
+
+    /// What the VMX instructions executed by the guest do.
+    vmx_instruction_response: VmxInstructionResponse,
+
+    /// The processors to virtualize, or `None` for every processor.
+    cpu_set: Option<CpuSet>,
+
+    /// The types of the cores left native on hybrid processors.
+    excluded_core_types: Vec<CoreType>,
+
+    /// Implements a custom memory-access policy, or `None` to handle the EPT violations as usual.
+    ept_violation_callback: Option<EptViolationCallback>,
+
+    /// Whether the processors sample MSR_SMI_COUNT around the VM exits.
+    smm_monitoring: bool,
+
+    /// Whether INVLPG, and INVPCID, exit to invalidate the TLB of the guest, see `vmexit::invlpg`.
+    invlpg_exiting: bool,
+
+    /// Whether the instructions accessing the descriptor-table registers exit, see `vmexit::descriptor_table`.
+    descriptor_table_exiting: bool,
+
+    /// Called on every HLT of the guest, or `None` to let HLT run without exiting.
+    hlt_callback: Option<HltCallback>,
+
+    /// What INVD, WBINVD and WBNOINVD of the guest do.
+    cache_flush_policy: CacheFlushPolicy,
+
+    /// What MONITOR and MWAIT of the guest do.
+    mwait_policy: MwaitPolicy,
+
+    /// The fixed seed of the random number generators of the processors, or `None` to seed them from the processor.
+    rng_seed: Option<u64>,
+
+    /// What happens once a triple fault of the guest was dumped.
+    triple_fault_policy: TripleFaultPolicy,
+
+    /// The hardware breakpoints owned by the hypervisor, in the order they were set.
+    host_breakpoints: Vec<HostBreakpoint>,
+
+    /// The PAUSE-loop exiting window, or `None` to let PAUSE run without exiting.
+    pause_loop: Option<PauseLoopConfig>,
+
+    /// The ENCLS leaves that exit, or `None` to let ENCLS run without exiting.
+    encls_exiting: Option<EnclsExitingBitmap>,
+
+    /// Whether the writes to IA32_APIC_BASE exit, see `apic_base`.
+    apic_base_tracking: bool,
+}
+
+impl HypervisorBuilder {
+    /// Creates a new HypervisorBuilder instance.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if hypervisor initialization was successful, or `Err` if there was an error.
+    pub fn build(self) -> Result<Hypervisor, HypervisorError> {
+        log::debug!("Building hypervisor");
+
+        Hypervisor::check_supported_cpu()?;
+
+        // The session tokens and the agent keys and nonces are drawn from the generators of the processors.
+        if !chacha::self_test() {
+            return Err(HypervisorError::RngSelfTestFailed);
+        }
+
+        set_memory_cap(self.memory_cap);
+
+        let platform_info = PlatformInfo::capture();
+        log::debug!("Platform information:\n{}", platform_info);
+
+        // Under Hyper-V, the VMCS is accessed in memory instead of trapping every VMREAD and VMWRITE.
+        if platform_info.enlightenments.evmcs_supported() {
+            log::debug!("Using the enlightened VMCS");
+            evmcs::enable();
+        }
+
+        if processor_count() as usize > MAX_VCPUS {
+            log::error!(
+                "Found {} processors, but at most {} are supported",
+                processor_count(),
+                MAX_VCPUS
+            );
+            return Err(HypervisorError::TooManyProcessors);
+        }
+
+        // The grace periods of the snapshots read by the exit handlers wait on every active processor.
+        rcu::init();
+
+        // Needed to resume the guest natively from user mode after a failed VM entry, see `entry_recovery`.
+        resolve_thread_layout();
+
+        let topology = Topology::discover()?;
+        log::debug!("Processor topology:\n{}", topology);
+
+        let mut processors: Vec<Vcpu> = Vec::new();
+
+        for i in 0..processor_count() {
+            let apic_id = topology.apic_id(i).ok_or(HypervisorError::VcpuIsNone)?;
+
+            processors.push(Vcpu::new(i, apic_id)?);
+        }
+
+        log::debug!("Found {} processors", processors.len());
+
+        let cpu_set = Self::resolve_cpu_set(
+            &topology,
+            self.cpu_set.unwrap_or_default(),
+            &self.excluded_core_types,
+        );
+        if cpu_set.is_empty_within(processor_count()) {
+            return Err(HypervisorError::NoProcessorSelected);
+        }
+        let partial = !cpu_set.is_full_within(processor_count());
+        if partial {
+            log::debug!("Virtualizing processors {}", cpu_set);
+
+            // A thread migrating to a native processor would escape the protection, see `cpu_set`.
+            if let Some(feature) = self.security_feature() {
+                return Err(HypervisorError::PartialSecurityFeature(feature));
+            }
+        }
+
+        let hook_manager = self
+            .hook_manager
+            .ok_or(HypervisorError::HookManagerNotProvided)?;
+
+        let primary_ept = self
+            .primary_ept
+            .ok_or(HypervisorError::PrimaryEPTNotProvided)?;
+
+        let paravirt = ParavirtInterface::new(self.paravirt_features);
+        if paravirt.offers(ParavirtFeatures::DIAGNOSTICS) {
+            log::warn!("Developer mode: the hypervisor identifies itself to the guest");
+        }
+        let debugger = DebuggerMonitor::new(self.debugger_policy);
+
+        #[cfg(not(feature = "secondary-ept"))]
+        let mut shared_data =
+            SharedData::new(primary_ept, hook_manager, platform_info, paravirt, debugger)?;
+
+        #[cfg(feature = "secondary-ept")]
+        let mut shared_data = {
+            let secondary_ept = self
+                .secondary_ept
+                .ok_or(HypervisorError::SecondaryEPTNotProvided)?;
+
+            SharedData::new(
+                primary_ept,
+                secondary_ept,
+                hook_manager,
+                platform_info,
+                paravirt,
+                debugger,
+            )?
+        };
+
+        // The faults on monitored memory are ordinary loads and stores, which must not fail, see `Backoff::InjectGp`.
+        if matches!(
+            self.rate_limits.monitored_fault,
+            Some(Quota {
+                backoff: Backoff::InjectGp,
+                ..
+            })
+        ) {
+            return Err(HypervisorError::InvalidRateLimit);
+        }
+        shared_data.rate_limits = self.rate_limits;
+        shared_data.thrash_policy = self.thrash_policy;
+
+        if let Some(retries) = self.entry_failure_retries {
+            shared_data.entry_failure_retries = retries;
+        }
+
+        if let Some(timeout) = self.root_operation_timeout {
+            shared_data.root_operation_timeout = timeout;
+        }
+
+        if let Some(config) = self.tsc_config {
+            // A thread migrating to a native processor would see the TSC jump, see `cpu_set`.
+            if partial && config.mode != TscMode::Native {
+                return Err(HypervisorError::PartialTscVirtualization);
+            }
+            if config.multiplier == 0 || (config.needs_scaling() && !cpu::has_tsc_scaling()) {
+                return Err(HypervisorError::TscScalingUnsupported);
+            }
+            log::debug!("Virtualizing the TSC: {:?}", config);
+            shared_data.tsc_config = config;
+
+            // RDMSR and WRMSR of the TSC would bypass the offset, see `intel::tsc`.
+            if config.mode != TscMode::Native {
+                shared_data
+                    .msr_bitmap
+                    .intercept_msr(msr::IA32_TIME_STAMP_COUNTER, true, true);
+            }
+
+            // The deadlines of the guest are translated into the TSC of the processor, see `apic_timer`.
+            if config.mode != TscMode::Native && apic_timer::has_tsc_deadline() {
+                log::debug!("APIC timer mode: {:?}", apic_timer::timer_mode());
+                shared_data
+                    .msr_bitmap
+                    .intercept_msr(apic_timer::IA32_TSC_DEADLINE, true, true);
+            }
+        }
+
+        if self.lbr_virtualization {
+            shared_data.lbr_stack =
+                Some(LbrStack::detect().ok_or(HypervisorError::LbrUnsupported)?);
+        }
+
+        shared_data.cpuid_masking =
+            self.cpuid_masking
+                .unwrap_or(match shared_data.paravirt.is_enabled() {
+                    true => CpuidMasking::empty(),
+                    false => CpuidMasking::HYPERVISOR_BIT,
+                });
+
+        shared_data.tsx = Tsx::detect(self.tsx_policy);
+        shared_data.cpuid_topology = self.cpuid_topology;
+        log::debug!("TSX: {:?}", shared_data.tsx);
+
+        if self.tsx_policy == TsxPolicy::Disable {
+            match shared_data.tsx.intercepted_msr() {
+                Some(msr) => shared_data.msr_bitmap.intercept_msr(msr, false, true),
+                None if shared_data.tsx.rtm => {
+                    log::warn!(
+                        "TSX disabled, but RTM can only be hidden from CPUID on this processor"
+                    )
+                }
+                None => {}
+            }
+        }
+
+        // A single-stepped data access aborts the transaction it is part of on every retry, see `tsx`.
+        if shared_data.tsx.guest_rtm() {
+            if let Some(policy) = shared_data
+                .thrash_policy
+                .as_mut()
+                .filter(|policy| policy.strategy == ThrashStrategy::SingleStep)
+            {
+                log::warn!("RTM is visible to the guest, disabling thrashing hooks instead of single-stepping them");
+                policy.strategy = ThrashStrategy::DisableHook;
+            }
+        }
+
+        #[cfg(feature = "introspection")]
+        if self.exit_heat_map {
+            shared_data.heat_map = ExitHeatMap::capture()?;
+        }
+
+        if self.agent_monitor.is_some() {
+            if !self
+                .paravirt_features
+                .is_some_and(|features| features.contains(ParavirtFeatures::HYPERCALLS))
+            {
+                log::warn!("Guest agent monitoring requested, but hypercalls are not offered");
+            }
+            shared_data.agent_monitor = AgentMonitor::new(self.agent_monitor);
+        }
+
+        #[cfg(feature = "devices")]
+        if self.keyboard_protection != KeyboardProtection::Disabled {
+            log::debug!(
+                "Enabling keyboard protection: {:?}",
+                self.keyboard_protection
+            );
+
+            let allowed = Self::resolve_modules(
+                DEFAULT_ALLOWED_MODULES
+                    .iter()
+                    .chain(self.keyboard_allowed_modules.iter()),
+            )?;
+
+            shared_data.keyboard_guard = KeyboardGuard::new(self.keyboard_protection, allowed);
+            shared_data.io_bitmap.intercept_port(I8042_DATA_PORT, true);
+            shared_data
+                .io_bitmap
+                .intercept_port(I8042_COMMAND_PORT, true);
+        }
+
+        #[cfg(feature = "devices")]
+        for ports in self.io_intercepts.iter().cloned() {
+            log::debug!("Intercepting I/O ports {:#x?}", ports);
+            shared_data.io_monitor.set_monitored(ports.clone(), true);
+            for port in ports {
+                shared_data.io_bitmap.intercept_port(port, true);
+            }
+        }
+
+        if self.apic_base_tracking {
+            log::debug!("Tracking IA32_APIC_BASE writes");
+            shared_data.apic_base_tracking = true;
+            shared_data
+                .msr_bitmap
+                .intercept_msr(msr::IA32_APIC_BASE, false, true);
+        }
+
+        shared_data.x2apic_interception = self.intercept_x2apic;
+        if self.intercept_x2apic {
+            if x2apic::is_x2apic_enabled() {
+                log::debug!("Intercepting x2APIC MSRs");
+                for msr in x2apic::x2apic_msrs() {
+                    shared_data.msr_bitmap.intercept_msr(msr, true, true);
+                }
+            } else if self.apic_base_tracking {
+                log::debug!("Intercepting x2APIC MSRs once the local APIC switches to x2APIC mode");
+            } else {
+                log::warn!(
+                    "x2APIC interception requested, but the local APIC is not in x2APIC mode"
+                );
+            }
+        }
+
+        shared_data.cr3_observer = self.cr3_observer;
+        shared_data.vmx_instruction_response = self.vmx_instruction_response;
+        shared_data.cpu_set = cpu_set;
+        shared_data.ept_violation_callback = self.ept_violation_callback;
+        shared_data.invlpg_exiting = self.invlpg_exiting;
+        shared_data.descriptor_table_exiting = self.descriptor_table_exiting;
+        shared_data.hlt_callback = self.hlt_callback;
+        shared_data.cache_flush_policy = self.cache_flush_policy;
+        shared_data.mwait_policy = self.mwait_policy;
+        shared_data.rng_seed = self.rng_seed;
+        shared_data.triple_fault_policy = self.triple_fault_policy;
+        shared_data.pause_loop = self.pause_loop;
+        shared_data.encls_exiting = self.encls_exiting;
+
+        // Debuggers allocate the breakpoint slots from DR0 up, the host takes them from DR3 down.
+        if self.host_breakpoints.len() > BREAKPOINT_SLOTS {
+            return Err(HypervisorError::TooManyHostBreakpoints);
+        }
+        for breakpoint in self.host_breakpoints.iter() {
+            breakpoint.validate()?;
+        }
+        for (slot, breakpoint) in shared_data
+            .host_breakpoints
+            .iter_mut()
+            .rev()
+            .zip(self.host_breakpoints)
+        {
+            *slot = Some(breakpoint);
+        }
+
+        if self.smm_monitoring {
+            // Under another hypervisor, MSR_SMI_COUNT is emulated if at all, and the SMIs are not ours.
+            if shared_data.platform_info.host_hypervisor.is_present() {
+                log::warn!("SMM monitoring requested, but running under another hypervisor");
+            } else if !smm::has_smi_count() {
+                log::warn!("SMM monitoring requested, but MSR_SMI_COUNT is not implemented");
+            } else {
+                log::debug!("Enabling SMM monitoring");
+                shared_data.smm_monitor = SmmMonitor::new(true);
+            }
+        }
+
+        for (msr, policy) in self.msr_policies {
+            shared_data.msr_policies.set(msr, policy);
+            if policy.is_intercepting() {
+                shared_data.msr_bitmap.intercept_msr(msr, true, true);
+            }
+        }
+
+        let block_drivers = !self.denied_drivers.is_empty();
+        if block_drivers {
+            log::debug!("Blocking {} guest drivers", self.denied_drivers.len());
+            shared_data.driver_blocker = DriverBlocker::new(self.denied_drivers);
+            shared_data.driver_blocker.scan_loaded()?;
+        }
+
+        #[cfg(feature = "introspection")]
+        if self.fault_injection {
+            log::warn!("Enabling fault injection, the guest may crash");
+            shared_data.fault_injector = FaultInjector::new(true);
+        }
+
+        #[cfg(feature = "introspection")]
+        if let Some(response) = self.heap_poisoning {
+            log::debug!("Enabling heap poisoning: {:?}", response);
+            shared_data.heap_poison = HeapPoison::new(Some(response));
+        }
+
+        #[cfg(feature = "introspection")]
+        if self.process_control {
+            if self.client_sessions.is_none() {
+                return Err(HypervisorError::ProcessControlWithoutSessions);
+            }
+
+            log::debug!("Enabling process control");
+            shared_data.processes = ProcessControl::new(Some(EprocessLayout::resolve()?));
+        }
+
+        if self.client_sessions.is_some() {
+            log::debug!("Requiring client sessions for hypercalls");
+            shared_data.client_sessions = ClientSessions::new(self.client_sessions);
+        }
+
+        // The processors are not virtualized yet, so the EPT needs no invalidation.
+        #[cfg(feature = "introspection")]
+        if let Some(module) = self.coverage_target {
+            shared_data.coverage = CoverageMap::capture(module)?;
+            shared_data.arm_coverage()?;
+        }
+
+        log::debug!("Memory footprint: {}", MemoryFootprint::current());
+
+        // Unmapped when the hypervisor is dropped, after its processors left VMX operation.
+        ipi::map_xapic()?;
+
+        let mut hypervisor = Hypervisor {
+            processors,
+            topology,
+            shared_data: ManuallyDrop::new(shared_data),
+        };
+
+        // The shared data does not move anymore, the load notifications can reference it.
+        if block_drivers {
+            driver_blocker::register(hypervisor.shared_data.as_mut())?;
+        }
+
+        #[cfg(feature = "introspection")]
+        hypervisor.shared_data.processes.start_worker()?;
+
+        Ok(hypervisor)
+    }
+
+    pub fn primary_ept(mut self, ept: Box<Ept, PhysicalAllocator>) -> Self {
+        self.primary_ept = Some(ept);
+        self
+    }
+
+    #[cfg(feature = "secondary-ept")]
+    pub fn secondary_ept(mut self, ept: Box<Ept, PhysicalAllocator>) -> Self {
+        self.secondary_ept = Some(ept);
+        self
+    }
+
+    pub fn hook_manager(mut self, hook_manager: Box<HookManager>) -> Self {
+        self.hook_manager = Some(hook_manager);
+        self
+    }
+
+    /// Caps the amount of memory in bytes the hypervisor is allowed to reserve.
+    pub fn memory_cap(mut self, bytes: u64) -> Self {
+        self.memory_cap = Some(bytes);
+        self
+    }
+
+    /// Exposes the paravirtual interface at CPUID leaves 0x40000000 and up to cooperative guests.
+    ///
+    /// Without this, the hypervisor runs in stealth mode and does not identify itself.
+    pub fn paravirt_interface(mut self, features: ParavirtFeatures) -> Self {
+        self.paravirt_features = Some(features);
+        self
+    }
+
+    /// Deliberately exposes the hypervisor to test automation, the inverse of stealth mode.
+    ///
+    /// Adds `ParavirtFeatures::DEVELOPER_MODE` to the paravirtual interface: the CPUID hypervisor bit is set,
+    /// and the diagnostics leaf and the identification hypercall are provided. This is the default of builds
+    /// with the `developer-mode` feature.
+    pub fn developer_mode(mut self) -> Self {
+        self.paravirt_features = Some(
+            self.paravirt_features.unwrap_or(ParavirtFeatures::empty())
+                | ParavirtFeatures::DEVELOPER_MODE,
+        );
+        self
+    }
+
+    /// Intercepts accesses to the x2APIC MSRs (0x800 to 0x8FF) when the system runs in x2APIC mode.
+    pub fn x2apic_interception(mut self, enabled: bool) -> Self {
+        self.intercept_x2apic = enabled;
+        self
+    }
+
+    /// Makes the writes to IA32_APIC_BASE exit, so relocations and mode switches of the local APIC are checked
+    /// and followed by the EPT and the x2APIC interception, see `apic_base`.
+    pub fn apic_base_tracking(mut self, enabled: bool) -> Self {
+        self.apic_base_tracking = enabled;
+        self
+    }
+
+    /// Sets what to do when a guest kernel debugging session is detected, e.g. suspend the hooks and the spoofing
+    /// the debugger would trip over.
+    pub fn debugger_policy(mut self, policy: DebuggerPolicy) -> Self {
+        self.debugger_policy = policy;
+        self
+    }
+
+    /// Sets the per-processor quotas of the guest-triggerable exits, such as hypercalls.
+    ///
+    /// The quota of the faults on monitored memory must back off with a delay, `build` fails otherwise.
+    pub fn rate_limits(mut self, policy: RateLimitPolicy) -> Self {
+        self.rate_limits = policy;
+        self
+    }
+
+    /// Protects the keyboard controller (ports 0x60 and 0x64) against accesses from code outside of the allow list.
+    #[cfg(feature = "devices")]
+    pub fn keyboard_protection(mut self, protection: KeyboardProtection) -> Self {
+        self.keyboard_protection = protection;
+        self
+    }
+
+    /// Allows a guest module to access the keyboard controller when keyboard protection is enabled.
+    #[cfg(feature = "devices")]
+    pub fn keyboard_allow_module(mut self, module_name: &'static str) -> Self {
+        self.keyboard_allowed_modules.push(module_name);
+        self
+    }
+
+    /// Intercepts the accesses of the guest to a range of I/O ports and records them, see `io_monitor`. Can be
+    /// changed at runtime with `Hypervisor::set_io_intercept`.
+    #[cfg(feature = "devices")]
+    pub fn io_intercept(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.io_intercepts.push(ports);
+        self
+    }
+
+    /// Counts the VM exits per guest kernel module, see `Hypervisor::exit_heat_map`.
+    #[cfg(feature = "introspection")]
+    pub fn exit_heat_map(mut self, enabled: bool) -> Self {
+        self.exit_heat_map = enabled;
+        self
+    }
+
+    /// Serves the guest agent hypercalls and watches the agent for tampering, see `Hypervisor::drain_tamper_events`.
+    ///
+    /// Requires the paravirtual interface with `ParavirtFeatures::HYPERCALLS`.
+    pub fn agent_monitor(mut self, config: AgentMonitorConfig) -> Self {
+        self.agent_monitor = Some(config);
+        self
+    }
+
+    /// Blocks the execution of a guest driver loaded from now on, see `Hypervisor::drain_driver_events`.
+    ///
+    /// Denied drivers that are already loaded are only reported.
+    pub fn deny_driver(mut self, driver: DeniedDriver) -> Self {
+        self.denied_drivers.push(driver);
+        self
+    }
+
+    /// Collects the code coverage of a guest module for fuzzing, see `Hypervisor::coverage`.
+    ///
+    /// A guest fuzzing controller reads and resets the coverage through hypercalls, which must be offered with
+    /// `HypervisorBuilder::paravirt_interface`.
+    #[cfg(feature = "introspection")]
+    pub fn coverage_target(mut self, module: &'static str) -> Self {
+        self.coverage_target = Some(module);
+        self
+    }
+
+    /// Lets a guest test controller inject faults through hypercalls, see `intel::fault_injection`.
+    ///
+    /// The hypercalls must be offered with `HypervisorBuilder::paravirt_interface`. For test systems only, as
+    /// the faults can crash the guest.
+    #[cfg(feature = "introspection")]
+    pub fn fault_injection(mut self, enabled: bool) -> Self {
+        self.fault_injection = enabled;
+        self
+    }
+
+    /// Lets a driver under test poison the red zones of its allocations through hypercalls, and reports its
+    /// accesses to them, see `intel::heap_poison`.
+    ///
+    /// The hypercalls must be offered with `HypervisorBuilder::paravirt_interface`.
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - Whether accesses to poisoned memory complete or raise #GP(0) once recorded.
+    #[cfg(feature = "introspection")]
+    pub fn heap_poisoning(mut self, response: ViolationResponse) -> Self {
+        self.heap_poisoning = Some(response);
+        self
+    }
+
+    /// Lets an incident response client list the guest processes and terminate them through hypercalls, see
+    /// `intel::processes`.
+    ///
+    /// The hypercalls must be offered with `HypervisorBuilder::paravirt_interface`, and are restricted to an
+    /// admin session: building fails with `HypervisorError::ProcessControlWithoutSessions` unless
+    /// `HypervisorBuilder::client_sessions` is set. Queued terminations are carried out by a worker thread.
+    #[cfg(feature = "introspection")]
+    pub fn process_control(mut self, enabled: bool) -> Self {
+        self.process_control = enabled;
+        self
+    }
+
+    /// Requires a client session for the hypercalls that are not public, see `intel::sessions`.
+    ///
+    /// # Arguments
+    ///
+    /// * `admin_key` - The 128-bit key required to open an admin session. Observer sessions need no key.
+    pub fn client_sessions(mut self, admin_key: [u64; 2]) -> Self {
+        self.client_sessions = Some(admin_key);
+        self
+    }
+
+    /// Detects hooked pages thrashing between the EPT views and applies a strategy to them, see
+    /// `ept::thrashing`.
+    pub fn thrash_policy(mut self, policy: ThrashPolicy) -> Self {
+        self.thrash_policy = Some(policy);
+        self
+    }
+
+    /// Sets the number of failed VM entries retried in a row with the last known-good guest state before a
+    /// processor is devirtualized, see `entry_recovery`. Zero devirtualizes on the first failure.
+    pub fn entry_failure_retries(mut self, retries: u32) -> Self {
+        self.entry_failure_retries = Some(retries);
+        self
+    }
+
+    /// Virtualizes the LBR stack, so that the guest finds its branch records as it left them on every VM entry,
+    /// see `lbr`. Fails to build on processors without a legacy LBR stack.
+    pub fn lbr_virtualization(mut self) -> Self {
+        self.lbr_virtualization = true;
+        self
+    }
+
+    /// Sets what CPUID hides from the guest about the hypervisor on every processor. It can be changed per
+    /// processor at runtime with `Vmx::set_cpuid_masking`.
+    pub fn cpuid_masking(mut self, masking: CpuidMasking) -> Self {
+        self.cpuid_masking = Some(masking);
+        self
+    }
+
+    /// Sets what the guest sees of TSX, see `tsx`. `TsxPolicy::Disable` hides TSX from the guest and disables
+    /// RTM on every processor.
+    pub fn tsx_policy(mut self, policy: TsxPolicy) -> Self {
+        self.tsx_policy = policy;
+        self
+    }
+
+    /// Sets the number of threads per core and cores per package the virtualized processors report through CPUID,
+    /// e.g. one thread per core to hide SMT, see `intel::topology`. The APIC IDs stay those of the processors, and
+    /// the ACPI MADT still lists every logical processor, so only the grouping seen through CPUID changes.
+    pub fn cpuid_topology(mut self, config: TopologyConfig) -> Self {
+        self.cpuid_topology = Some(config);
+        self
+    }
+
+    /// Sets what happens to the accesses of the guest to an MSR, see `msr_policy`. The policy can be changed at
+    /// runtime with `Vcpu::set_msr_policy` for a single processor, or `Hypervisor::set_msr_policy` for all of them.
+    pub fn msr_policy(mut self, msr: u32, policy: MsrPolicy) -> Self {
+        self.msr_policies.push((msr, policy));
+        self
+    }
+
+    /// Makes the CR3 loads of the guest exit and hands them to an observer, e.g. to track processes, see
+    /// `vmexit::cr`. Every context switch of the guest then exits.
+    pub fn cr3_observer(mut self, observer: Cr3Observer) -> Self {
+        self.cr3_observer = Some(observer);
+        self
+    }
+
+    /// Sets the time a long-running hypercall, such as an EPT dump, may keep the guest paused before it is
+    /// cancelled, see `utils::cancellation`.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time in TSC ticks, or zero for no limit, in which case only the closing of the session
+    ///   of the client cancels the hypercall.
+    pub fn root_operation_timeout(mut self, timeout: u64) -> Self {
+        self.root_operation_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how the guest reads the TSC with RDTSC and RDTSCP, see `tsc`. Fails to build if the multiplier
+    /// requires TSC scaling and the processor does not support it.
+    pub fn tsc_virtualization(mut self, config: TscConfig) -> Self {
+        self.tsc_config = Some(config);
+        self
+    }
+
+    /// Sets what the VMX instructions executed by the guest do, see `vmexit::vmx_instruction`. They raise #UD by
+    /// default.
+    pub fn vmx_instruction_response(mut self, response: VmxInstructionResponse) -> Self {
+        self.vmx_instruction_response = response;
+        self
+    }
+
+    /// Restricts virtualization to a set of processors, e.g. `CpuSet::all().without(0)` to keep core 0 native, see
+    /// `cpu_set`. Fails to build if the set selects no processor, if it leaves a processor native while a feature
+    /// protecting the guest is enabled, such as EPT hooks or the driver deny list, or while the TSC is virtualized.
+    pub fn virtualized_processors(mut self, cpu_set: CpuSet) -> Self {
+        self.cpu_set = Some(cpu_set);
+        self
+    }
+
+    /// Sets the callback implementing a custom memory-access policy, called on EPT violations in VMX root
+    /// operation, see `vmexit::ept::EptViolationCallback`.
+    pub fn ept_violation_callback(mut self, callback: EptViolationCallback) -> Self {
+        self.ept_violation_callback = Some(callback);
+        self
+    }
+
+    /// Counts the SMIs of every processor and records those taken while a VM exit is handled, see `smm`. Ignored
+    /// with a warning if the processor does not implement MSR_SMI_COUNT or runs under another hypervisor.
+    pub fn smm_monitoring(mut self, enabled: bool) -> Self {
+        self.smm_monitoring = enabled;
+        self
+    }
+
+    /// Makes INVLPG and INVPCID exit, the hypervisor then invalidates the TLB of the guest with INVVPID on its
+    /// behalf, see `vmexit::invlpg`. Every page invalidation of the guest then exits.
+    pub fn invlpg_exiting(mut self, enabled: bool) -> Self {
+        self.invlpg_exiting = enabled;
+        self
+    }
+
+    /// Makes SGDT, SIDT, LGDT, LIDT, SLDT, STR, LLDT and LTR exit, so the guest only sees shadows of the GDTR and
+    /// IDTR and cannot relocate its tables, see `vmexit::descriptor_table`.
+    pub fn descriptor_table_exiting(mut self, enabled: bool) -> Self {
+        self.descriptor_table_exiting = enabled;
+        self
+    }
+
+    /// Sets what INVD, WBINVD and WBNOINVD of the guest do, see `vmexit::invd`. INVD writes back the caches with
+    /// WBINVD instead of discarding them, and WBINVD runs without exiting, by default.
+    pub fn cache_flush_policy(mut self, policy: CacheFlushPolicy) -> Self {
+        self.cache_flush_policy = policy;
+        self
+    }
+
+    /// Sets what MONITOR and MWAIT of the guest do, see `vmexit::mwait`. Both run without exiting by default;
+    /// `MwaitPolicy::Pause` keeps the processor in C0.
+    pub fn mwait_policy(mut self, policy: MwaitPolicy) -> Self {
+        self.mwait_policy = policy;
+        self
+    }
+
+    /// Makes HLT exit and sets the callback invoked on it in VMX root operation, e.g. to measure the idle time of
+    /// the guest, see `vmexit::hlt::HltCallback`. Every halt of the guest then exits.
+    pub fn hlt_exiting(mut self, callback: HltCallback) -> Self {
+        self.hlt_callback = Some(callback);
+        self
+    }
+
+    /// Seeds the random number generators of the processors with a fixed seed instead of RDSEED, so a run can be
+    /// reproduced, see `utils::chacha`. For debugging only, as the numbers become predictable, and with them the
+    /// tokens of the client sessions and the keys and nonces of the guest agent.
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Sets what happens once a triple fault of the guest was dumped: a `HYPERVISOR_ERROR` bug check, the default,
+    /// or halting the processor in the shutdown state, see `vmexit::triple_fault`.
+    pub fn triple_fault_policy(mut self, policy: TripleFaultPolicy) -> Self {
+        self.triple_fault_policy = policy;
+        self
+    }
+
+    /// Sets a hardware breakpoint in the guest that the guest can neither see nor clobber, see
+    /// `intel::debug_registers`. Up to four can be set, the first one taking DR3. MOV DR then exits and the
+    /// guest works on shadow debug registers, and its breakpoints in the slots of the host never trigger. Building
+    /// fails if the breakpoint is misaligned or not canonical, or executes more than a byte, see
+    /// `HostBreakpoint::validate`.
+    pub fn host_breakpoint(mut self, breakpoint: HostBreakpoint) -> Self {
+        self.host_breakpoints.push(breakpoint);
+        self
+    }
+
+    /// Makes the spin loops of the guest kernel exit once they spun for longer than the window, to detect lock
+    /// contention, see `intel::spin_monitor`. The spins are read with `Hypervisor::spin_stats`.
+    pub fn pause_loop_exiting(mut self, config: PauseLoopConfig) -> Self {
+        self.pause_loop = Some(config);
+        self
+    }
+
+    /// Makes the ENCLS leaves set in the bitmap exit, where they fail with #GP(0), e.g.
+    /// `EnclsExitingBitmap::ENCLAVE_CREATION` to keep SGX-aware guests from building enclaves, see
+    /// `vmexit::encls`. The other leaves run natively.
+    pub fn encls_exiting(mut self, bitmap: EnclsExitingBitmap) -> Self {
+        self.encls_exiting = Some(bitmap);
+        self
+    }
+
+    /// Leaves the cores of a type native on hybrid processors, e.g. `CoreType::Efficiency` for the E-cores.
+    /// Combines with `virtualized_processors`, and has no effect on processors that are not hybrid.
+    pub fn exclude_core_type(mut self, core_type: CoreType) -> Self {
+        self.excluded_core_types.push(core_type);
+        self
+    }
+
+    /// Returns the first enabled feature protecting the guest that only applies on virtualized processors.
+    ///
+    /// # Returns
+    ///
+    /// The name of the feature, or `None` if none is enabled.
+    fn security_feature(&self) -> Option<&'static str> {
+        #[cfg(feature = "devices")]
+        if self.keyboard_protection != KeyboardProtection::Disabled {
+            return Some("Keyboard protection");
+        }
+
+        [
+            (
+                self.hook_manager
+                    .as_ref()
+                    .is_some_and(|hook_manager| !hook_manager.hooks.is_empty()),
+                "EPT hooking",
+            ),
+            (self.agent_monitor.is_some(), "The guest agent monitor"),
+            (!self.denied_drivers.is_empty(), "The driver deny list"),
+            (
+                self.ept_violation_callback.is_some(),
+                "The EPT violation callback",
+            ),
+            (!self.host_breakpoints.is_empty(), "Host breakpoints"),
+        ]
+        .into_iter()
+        .find_map(|(enabled, feature)| enabled.then_some(feature))
+    }
+
+    /// Removes the processors whose core type is excluded from the set of processors to virtualize.
+    ///
+    /// # Arguments
+    ///
+    /// * `topology` - The topology of the processors.
+    /// * `cpu_set` - The processors selected by index.
+    /// * `excluded_core_types` - The types of the cores left native.
+    ///
+    /// # Returns
+    ///
+    /// The processors to virtualize.
+    fn resolve_cpu_set(
+        topology: &Topology,
+        cpu_set: CpuSet,
+        excluded_core_types: &[CoreType],
+    ) -> CpuSet {
+        topology
+            .iter()
+            .filter(|cpu| {
+                cpu.core_type
+                    .is_some_and(|core_type| excluded_core_types.contains(&core_type))
+            })
+            .fold(cpu_set, |cpu_set, cpu| cpu_set.without(cpu.index))
+    }
+
+    /// Resolves guest modules to the address ranges of their images.
+    ///
+    /// Modules that are not loaded are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `module_names` - The names of the modules.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the address ranges of the loaded modules.
+    #[cfg(feature = "devices")]
+    fn resolve_modules<'a>(
+        module_names: impl Iterator<Item = &'a &'static str>,
+    ) -> Result<Vec<core::ops::Range<u64>>, HypervisorError> {
+        let mut sys_info = Sysinfo::new()?;
+        let mut ranges = Vec::new();
+
+        for name in module_names {
+            match sys_info.get_module_base(name) {
+                Some((base, size)) => ranges.push(base as u64..base as u64 + size as u64),
+                None => log::warn!("Module {} is not loaded, not allowing it", name),
+            }
+        }
+
+        Ok(ranges)
+    }
+}
+
+/// The main struct representing the hypervisor.
+pub struct Hypervisor {
+    /// The processors to virtualize.
+    processors: Vec<Vcpu>,
+
+    /// The topology of the processors, mapping vCPU indexes to APIC IDs.
+    topology: Topology,
+
+    /// The shared data between processors, freed only once every processor left VMX operation.
+    shared_data: ManuallyDrop<Box<SharedData>>,
+}
+
+// The per-processor state is only touched from its own processor, and the shared data is handed
+// out to the VM-exit handlers through raw pointers, so moving the hypervisor between threads is sound.
+unsafe impl Send for Hypervisor {}
+
+impl Hypervisor {
+    /// Creates a new HypervisorBuilder instance.
+    ///
+    /// Builds with the `developer-mode` feature start in developer mode, see `HypervisorBuilder::developer_mode`.
+    pub fn builder() -> HypervisorBuilder {
+        if cfg!(feature = "developer-mode") {
+            HypervisorBuilder::default().developer_mode()
+        } else {
+            HypervisorBuilder::default()
+        }
+    }
+
+    /// Virtualizes the system's processors, except those left out by `HypervisorBuilder::virtualized_processors`
+    /// and `HypervisorBuilder::exclude_core_type`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if the virtualization was successful, or `Err` if there was an error.
+    pub fn virtualize_core(&mut self) -> Result<(), HypervisorError> {
+        log::trace!("Virtualizing processors");
+
+        let cpu_set = self.shared_data.cpu_set;
+
+        for processor in self.processors.iter_mut() {
+            if !cpu_set.contains(processor.id()) {
+                log::debug!("Leaving processor {} native", processor.id());
+                continue;
+            }
+
+            let Some(executor) = ProcessorExecutor::switch_to_processor(processor.id()) else {
+                return Err(HypervisorError::ProcessorSwitchFailed);
+            };
+
+            processor.virtualize_cpu(self.shared_data.as_mut())?;
+
+            drop(executor);
+        }
+
+        log::info!(
+            "{}",
+            BootReport::new(&self.shared_data, self.processors.len())
+        );
 
-    /// The hook manager.
-    hook_manager: Option<Box<HookManager>>,
-}
+        Ok(())
+    }
 
-impl HypervisorBuilder {
-    /// Creates a new HypervisorBuilder instance.
+    /// Returns the virtual processor with the given index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the processor.
     ///
     /// # Returns
     ///
-    /// A `Result` which is `Ok` if hypervisor initialization was successful, or `Err` if there was an error.
-    pub fn build(self) -> Result<Hypervisor, HypervisorError> {
-        log::debug!("Building hypervisor");
+    /// A `Result` containing the processor, or `HypervisorError::VcpuIsNone` if there is no such processor.
+    pub fn processor(&self, index: usize) -> Result<&Vcpu, HypervisorError> {
+        self.processors
+            .get(index)
+            .ok_or(HypervisorError::VcpuIsNone)
+    }
 
-        Hypervisor::check_supported_cpu()?;
+    /// Returns the virtual processor with the given index mutably.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the processor.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the processor, or `HypervisorError::VcpuIsNone` if there is no such processor.
+    pub fn processor_mut(&mut self, index: usize) -> Result<&mut Vcpu, HypervisorError> {
+        self.processors
+            .get_mut(index)
+            .ok_or(HypervisorError::VcpuIsNone)
+    }
 
-        let mut processors: Vec<Vcpu> = Vec::new();
+    /// Changes what CPUID hides from the guest about the hypervisor on every virtualized processor, e.g. to
+    /// switch between stealth and testing behavior. See `Vmx::set_cpuid_masking` for a single processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `masking` - What CPUID hides from the guest.
+    pub fn set_cpuid_masking(&self, masking: CpuidMasking) {
+        for processor in self.processors.iter() {
+            if let Ok(vmx) = processor.vmx() {
+                vmx.set_cpuid_masking(masking);
+            }
+        }
+    }
 
-        for i in 0..processor_count() {
-            processors.push(Vcpu::new(i)?);
+    /// Sets what happens to the accesses of the guest to an MSR on every virtualized processor and on those
+    /// virtualized from now on, see `msr_policy`. See `Vcpu::set_msr_policy` for a single processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR.
+    /// * `policy` - The policy applied to the accesses of the guest to it.
+    pub fn set_msr_policy(&mut self, msr: u32, policy: MsrPolicy) {
+        let shared_data = self.shared_data.as_mut();
+
+        // The policies are published before the MSR exits, so no intercepted access sees the previous one.
+        shared_data.msr_policies.set(msr, policy);
+        for processor in self.processors.iter() {
+            if let Ok(vmx) = processor.vmx() {
+                vmx.msr_policies.set(msr, policy);
+            }
         }
 
-        log::info!("Found {} processors", processors.len());
+        if policy.is_intercepting() {
+            shared_data.msr_bitmap.intercept_msr(msr, true, true);
+        }
+    }
 
-        let hook_manager = self
+    /// Starts or stops intercepting and recording the accesses of the guest to a range of I/O ports on all
+    /// processors, see `io_monitor`.
+    ///
+    /// The I/O bitmaps are shared by the processors and the change takes effect on the next access without any
+    /// invalidation. The ports of the keyboard controller stay intercepted while keyboard protection is enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `ports` - The ports.
+    /// * `intercept` - Whether the accesses to the ports exit and are recorded.
+    #[cfg(feature = "devices")]
+    pub fn set_io_intercept(&mut self, ports: RangeInclusive<u16>, intercept: bool) {
+        let shared_data = self.shared_data.as_mut();
+
+        // The ports are monitored before they exit, so no intercepted access goes unrecorded.
+        shared_data
+            .io_monitor
+            .set_monitored(ports.clone(), intercept);
+        for port in ports {
+            if intercept || !shared_data.keyboard_guard.guards(port) {
+                shared_data.io_bitmap.intercept_port(port, intercept);
+            }
+        }
+    }
+
+    /// Returns the policies of the MSRs that are denied or emulated on processors virtualized from now on, sorted
+    /// by MSR. See `Vcpu::msr_policies` for a single processor.
+    pub fn msr_policies(&self) -> Vec<MsrRule> {
+        self.shared_data.msr_policies.rules()
+    }
+
+    /// Returns the spin loops of the guest recorded on every processor, by processor index, zero unless enabled
+    /// with `HypervisorBuilder::pause_loop_exiting`.
+    pub fn spin_stats(&self) -> Vec<SpinStats> {
+        self.processors
+            .iter()
+            .map(|processor| {
+                processor
+                    .vmx()
+                    .map(|vmx| vmx.spin_monitor.stats())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Returns the number of virtual processors.
+    pub fn processor_count(&self) -> usize {
+        self.processors.len()
+    }
+
+    /// Returns the processors virtualized by `virtualize_core`. Threads relying on hooks or hypercalls have to be
+    /// pinned to them, see `cpu_set`.
+    pub fn cpu_set(&self) -> CpuSet {
+        self.shared_data.cpu_set
+    }
+
+    /// Returns the processor topology, mapping vCPU indexes to APIC IDs.
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// Reports the memory currently consumed by the hypervisor, per category.
+    ///
+    /// # Returns
+    ///
+    /// A `MemoryFootprint` snapshot.
+    pub fn memory_footprint(&self) -> MemoryFootprint {
+        MemoryFootprint::current()
+    }
+
+    /// Evicts a hook namespace, tearing down all hooks registered by its client.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace to evict.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if the namespace was evicted, or `Err` if there was an error.
+    #[cfg(feature = "secondary-ept")]
+    pub fn evict_hook_namespace(
+        &mut self,
+        namespace: crate::intel::ept::hooks::NamespaceId,
+    ) -> Result<(), HypervisorError> {
+        let shared_data = self.shared_data.as_mut();
+        shared_data.hook_manager.write().release_namespace(
+            namespace,
+            &mut shared_data.primary_ept,
+            &mut shared_data.secondary_ept,
+        )?;
+
+        // The lock disables interrupts, so the processors are flushed once it is released, before the shadow
+        // pages are freed.
+        self.invalidate_ept()?;
+
+        let shared_data = self.shared_data.as_ref();
+        shared_data
             .hook_manager
-            .ok_or(HypervisorError::HookManagerNotProvided)?;
+            .write()
+            .evict_namespace(namespace, &shared_data.hook_table)
+    }
 
-        let primary_ept = self
-            .primary_ept
-            .ok_or(HypervisorError::PrimaryEPTNotProvided)?;
+    /// Suspends the hooks, so hooked functions run their original code until `resume_hooks` is called. The host
+    /// breakpoints and the hiding of the time spent in the hypervisor are paused as well, see `intel::debugger`.
+    pub fn suspend_hooks(&self) {
+        self.shared_data.debugger.suspend(SuspendReason::Manual);
+    }
 
-        #[cfg(not(feature = "secondary-ept"))]
-        let mut shared_data = SharedData::new(primary_ept, hook_manager)?;
+    /// Resumes the hooks after they were suspended, manually or due to debugger activity.
+    pub fn resume_hooks(&self) {
+        self.shared_data.debugger.resume();
+    }
+
+    /// Returns whether the hooks are currently suspended.
+    pub fn hooks_suspended(&self) -> bool {
+        self.shared_data.debugger.hooks_suspended()
+    }
+
+    /// Exports the configuration the hypervisor is running with, e.g. to answer an IOCTL of the driver.
+    ///
+    /// A read from offset 0 takes a new snapshot of the configuration, and the following reads copy from it, see
+    /// `ConfigSnapshot`. The export omits the `[vmcs]` section, which is only available through the
+    /// `GetEffectiveConfig` hypercall, served in root mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - Receives the text from `offset` on, as much as fits.
+    /// * `offset` - The offset into the text of the first byte to copy.
+    /// * `generation` - The generation returned by the read from offset 0, ignored at offset 0.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes copied, the length of the whole text and the generation of the snapshot, or `None`
+    /// if the snapshot was replaced in between, in which case the caller starts over from offset 0.
+    #[cfg(feature = "introspection")]
+    pub fn effective_config(
+        &self,
+        buffer: &mut [u8],
+        offset: usize,
+        generation: u64,
+    ) -> Option<ConfigExport> {
+        let config = EffectiveConfig::new(&self.shared_data);
+        self.shared_data
+            .config_snapshot
+            .read(&config, buffer, offset, generation)
+    }
+
+    /// Lists the commands offered by the hypervisor, with the access they require and whether their subsystem is
+    /// enabled, see `intel::capabilities`. Served in root mode by the `ListCapabilities` hypercall as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - Receives the text from `offset` on, as much as fits.
+    /// * `offset` - The offset into the text of the first byte to copy.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes copied and the length of the whole text.
+    #[cfg(feature = "introspection")]
+    pub fn capabilities(&self, buffer: &mut [u8], offset: usize) -> ConfigExport {
+        export_text(&CapabilityList::new(&self.shared_data), buffer, offset)
+    }
+
+    /// Exports a snapshot of the metrics of the hypervisor, e.g. to answer an IOCTL of the driver. The snapshot
+    /// is converted to the Prometheus text format with `metrics::write_prometheus`.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - Receives the snapshot from `offset` on, as much as fits.
+    /// * `offset` - The offset into the snapshot of the first byte to copy.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes copied and the length of the whole snapshot.
+    #[cfg(feature = "introspection")]
+    pub fn metrics(&self, buffer: &mut [u8], offset: usize) -> MetricsExport {
+        metrics::export(&self.shared_data, buffer, offset)
+    }
+
+    /// Returns the VM exits per guest module, empty unless enabled with `HypervisorBuilder::exit_heat_map`.
+    #[cfg(feature = "introspection")]
+    pub fn exit_heat_map(&self) -> &ExitHeatMap {
+        &self.shared_data.heat_map
+    }
+
+    /// Hands the pending keyboard protection events to a consumer, oldest first.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    #[cfg(feature = "devices")]
+    pub fn drain_keyboard_events(&self, consumer: impl FnMut(&KeyboardAccess)) -> usize {
+        self.shared_data.keyboard_guard.drain_events(consumer)
+    }
+
+    /// Hands the pending accesses to the monitored I/O ports to a consumer, oldest first, see `set_io_intercept`.
+    ///
+    /// # Returns
+    ///
+    /// The number of accesses drained.
+    #[cfg(feature = "devices")]
+    pub fn drain_io_accesses(&self, consumer: impl FnMut(&IoAccess)) -> usize {
+        self.shared_data.io_monitor.drain_accesses(consumer)
+    }
+
+    /// Hands the SMIs taken while a VM exit was handled to a consumer, oldest first, empty unless enabled with
+    /// `HypervisorBuilder::smm_monitoring`.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    pub fn drain_smi_events(&self, consumer: impl FnMut(&SmiEvent)) -> usize {
+        self.shared_data.smm_monitor.drain_events(consumer)
+    }
+
+    /// Returns the number of SMIs taken by the virtualized processors, zero unless enabled with
+    /// `HypervisorBuilder::smm_monitoring`.
+    pub fn smi_counts(&self) -> SmiCounts {
+        self.shared_data.smm_monitor.counts()
+    }
+
+    /// Applies a permission profile to a set of guest physical regions.
+    ///
+    /// # Arguments
+    ///
+    /// * `profile` - The profile, e.g. `PermissionProfile::DENY_WRITE`.
+    /// * `regions` - The guest physical regions, page aligned and not overlapping any protected region.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if the regions are protected, or `Err` if a processor is left native, a region is
+    /// invalid or the EPT could not be updated.
+    pub fn apply_profile(
+        &mut self,
+        profile: PermissionProfile,
+        regions: &[Range<Gpa>],
+    ) -> Result<(), HypervisorError> {
+        // A thread migrating to a native processor would escape the profile, see `cpu_set`.
+        if !self.shared_data.cpu_set.is_full_within(processor_count()) {
+            return Err(HypervisorError::PartialSecurityFeature(
+                "A permission profile",
+            ));
+        }
+
+        let shared_data = self.shared_data.as_mut();
+
+        for region in shared_data.ept_policy.add(profile, regions)? {
+            for page in region.pages() {
+                shared_data.set_page_access(page, profile.access)?;
+            }
+        }
+
+        self.invalidate_ept()
+    }
+
+    /// Releases the regions of a permission profile, restoring full access to them.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the profile.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of regions released, or `Err` if the EPT could not be updated.
+    pub fn release_profile(&mut self, name: &str) -> Result<usize, HypervisorError> {
+        let shared_data = self.shared_data.as_mut();
+
+        // The regions stay known until their pages are accessible again, so no violation goes unanswered.
+        for region in shared_data.ept_policy.regions_of(name) {
+            for page in region.pages() {
+                shared_data.set_page_access(page, AccessType::READ_WRITE_EXECUTE)?;
+            }
+        }
+
+        self.invalidate_ept()?;
+
+        Ok(self.shared_data.ept_policy.remove(name))
+    }
+
+    /// Returns the guest physical regions protected by permission profiles, sorted by address.
+    pub fn protected_regions(&self) -> Vec<ProtectedRegion> {
+        self.shared_data.ept_policy.regions()
+    }
+
+    /// Hands the pending violations of the permission profiles to a consumer, oldest first.
+    ///
+    /// # Returns
+    ///
+    /// The number of violations drained.
+    pub fn drain_region_violations(&self, consumer: impl FnMut(&RegionViolation)) -> usize {
+        self.shared_data.ept_policy.drain_violations(consumer)
+    }
+
+    /// Reads, and optionally clears, the accessed and dirty flags the processor set on the pages of a guest
+    /// physical range, without changing their permissions, see `Ept::harvest_accessed_dirty_range`.
+    ///
+    /// The flags of the secondary EPT are reported after the ones of the primary EPT, so a page accessed through
+    /// both is reported twice. A 2MB page is reported once per EPT, with its base address.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The guest physical address range to walk.
+    /// * `clear` - Whether to clear the flags after reading them, to track the accesses from now on.
+    /// * `consumer` - Called with the guest physical address and the flags of every accessed page.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of accessed pages, or `Err` if the processor does not maintain the flags
+    /// or the range is beyond the mapped memory.
+    pub fn harvest_accessed_dirty(
+        &mut self,
+        range: Range<Gpa>,
+        clear: bool,
+        mut consumer: impl FnMut(Gpa, PageAccess),
+    ) -> Result<usize, HypervisorError> {
+        let shared_data = self.shared_data.as_mut();
+        if !shared_data.ept_accessed_dirty {
+            return Err(HypervisorError::EptAccessedDirtyUnsupported);
+        }
+
+        let accessed = shared_data.primary_ept.harvest_accessed_dirty_range(
+            range.clone(),
+            clear,
+            &mut consumer,
+        )?;
 
         #[cfg(feature = "secondary-ept")]
-        let shared_data = {
-            let secondary_ept = self
-                .secondary_ept
-                .ok_or(HypervisorError::SecondaryEPTNotProvided)?;
+        let accessed = accessed
+            + shared_data.secondary_ept.harvest_accessed_dirty_range(
+                range,
+                clear,
+                &mut consumer,
+            )?;
 
-            SharedData::new(primary_ept, secondary_ept, hook_manager)?
-        };
+        // The processor only sets the flags again once its cached translations are gone.
+        if clear {
+            self.invalidate_ept()?;
+        }
 
-        Ok(Hypervisor {
-            processors,
-            shared_data,
-        })
+        Ok(accessed)
     }
 
-    pub fn primary_ept(mut self, ept: Box<Ept, PhysicalAllocator>) -> Self {
-        self.primary_ept = Some(ept);
-        self
+    /// Returns the state of the guest agent.
+    pub fn agent_status(&self) -> AgentStatus {
+        self.shared_data.agent_monitor.status()
     }
 
-    #[cfg(feature = "secondary-ept")]
-    pub fn secondary_ept(mut self, ept: Box<Ept, PhysicalAllocator>) -> Self {
-        self.secondary_ept = Some(ept);
-        self
+    /// Returns the number of guest agent challenges and answers refused for a wrong MAC, e.g. issued by other
+    /// kernel code than the agent.
+    pub fn agent_rejected_calls(&self) -> u64 {
+        self.shared_data.agent_monitor.rejected_calls()
     }
 
-    pub fn hook_manager(mut self, hook_manager: Box<HookManager>) -> Self {
-        self.hook_manager = Some(hook_manager);
-        self
+    /// Hands the pending guest agent tamper events to a consumer, oldest first.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    pub fn drain_tamper_events(&self, consumer: impl FnMut(&TamperEvent)) -> usize {
+        self.shared_data.agent_monitor.drain_events(consumer)
     }
-}
 
-/// The main struct representing the hypervisor.
-pub struct Hypervisor {
-    /// The processors to virtualize.
-    processors: Vec<Vcpu>,
+    /// Hands the pending events of the denied drivers to a consumer, oldest first.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    pub fn drain_driver_events(&self, consumer: impl FnMut(&DriverEvent)) -> usize {
+        self.shared_data.driver_blocker.drain_events(consumer)
+    }
 
-    /// The shared data between processors.
-    shared_data: Box<SharedData>,
-}
+    /// Hands the pending faults injected into the guest to a consumer, oldest first.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    #[cfg(feature = "introspection")]
+    pub fn drain_fault_events(&self, consumer: impl FnMut(&FaultEvent)) -> usize {
+        self.shared_data.fault_injector.drain_events(consumer)
+    }
 
-impl Hypervisor {
-    /// Creates a new HypervisorBuilder instance.
-    pub fn builder() -> HypervisorBuilder {
-        HypervisorBuilder::default()
+    /// Hands the pending accesses of the guest to poisoned memory to a consumer, oldest first.
+    ///
+    /// # Returns
+    ///
+    /// The number of touches drained.
+    #[cfg(feature = "introspection")]
+    pub fn drain_poison_touches(&self, consumer: impl FnMut(&PoisonTouch)) -> usize {
+        self.shared_data.heap_poison.drain_touches(consumer)
+    }
+
+    /// Returns the guest processes, empty unless enabled with `HypervisorBuilder::process_control`.
+    #[cfg(feature = "introspection")]
+    pub fn list_processes(&self) -> Vec<GuestProcess> {
+        let mut processes = Vec::new();
+        let _ = self
+            .shared_data
+            .processes
+            .walk(&CancellationToken::new(), |process| processes.push(*process));
+        processes
     }
 
-    /// Virtualizes the system's processors.
+    /// Terminates a guest process. Must be called at PASSIVE_LEVEL.
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - The ID of the process.
     ///
     /// # Returns
     ///
-    /// A `Result` which is `Ok` if the virtualization was successful, or `Err` if there was an error.
-    pub fn virtualize_core(&mut self) -> Result<(), HypervisorError> {
-        log::trace!("Virtualizing processors");
+    /// The status of the request, see `ProcessControl::terminate_now`.
+    #[cfg(feature = "introspection")]
+    pub fn terminate_process(&self, pid: u64) -> Result<(), HypercallStatus> {
+        self.shared_data.processes.terminate_now(pid)
+    }
 
-        for processor in self.processors.iter_mut() {
-            let Some(executor) = ProcessorExecutor::switch_to_processor(processor.id()) else {
-                return Err(HypervisorError::ProcessorSwitchFailed);
-            };
+    /// Terminates the guest processes queued by the `TerminateProcess` hypercall right away, instead of waiting
+    /// for the worker thread. Must be called at PASSIVE_LEVEL.
+    ///
+    /// # Returns
+    ///
+    /// The number of processes terminated.
+    #[cfg(feature = "introspection")]
+    pub fn terminate_pending_processes(&self) -> usize {
+        self.shared_data.processes.terminate_pending()
+    }
 
-            processor.virtualize_cpu(self.shared_data.as_mut())?;
+    /// Returns the open client sessions.
+    pub fn client_sessions(&self) -> Vec<ClientSession> {
+        self.shared_data.client_sessions.sessions()
+    }
 
-            drop(executor);
+    /// Closes all client sessions, e.g. after a client died without closing its session.
+    ///
+    /// # Returns
+    ///
+    /// The number of sessions closed.
+    pub fn close_client_sessions(&self) -> usize {
+        self.shared_data.client_sessions.close_all()
+    }
+
+    /// Returns the hooks disabled because their page was thrashing, including those enabled again since, whose
+    /// backoff is remembered.
+    pub fn thrashing_hooks(&self) -> Vec<DisabledHook> {
+        self.shared_data.thrash_guard.hooks()
+    }
+
+    /// Returns the code coverage of the fuzzing target, disabled unless enabled with
+    /// `HypervisorBuilder::coverage_target`.
+    #[cfg(feature = "introspection")]
+    pub fn coverage(&self) -> &CoverageMap {
+        &self.shared_data.coverage
+    }
+
+    /// Ends a fuzzing iteration, making the pages of the fuzzing target non-executable again on all processors.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the coverage of the iteration, or `Err` if the EPT could not be updated.
+    #[cfg(feature = "introspection")]
+    pub fn reset_coverage(&mut self) -> Result<CoverageSummary, HypervisorError> {
+        let shared_data = self.shared_data.as_mut();
+        if !shared_data.coverage.is_enabled() {
+            return Ok(CoverageSummary::default());
         }
 
-        Ok(())
+        shared_data.arm_coverage()?;
+        self.invalidate_ept()?;
+
+        Ok(self.shared_data.coverage.reset())
+    }
+
+    /// Flushes the EPT derived translations on all virtualized processors after the EPT was changed.
+    ///
+    /// INVEPT executed by the guest would cause a VM exit, so each processor is asked to flush with a host call.
+    fn invalidate_ept(&self) -> Result<(), HypervisorError> {
+        host_call::flush_ept_on_all_processors(self.shared_data.host_call_key)
     }
 
     /// Reverts the virtualization of the system's processors.
@@ -156,13 +1697,32 @@ impl Hypervisor {
     fn check_supported_cpu() -> Result<(), HypervisorError> {
         /* Intel® 64 and IA-32 Architectures Software Developer's Manual: 24.6 DISCOVERING SUPPORT FOR VMX */
         Self::has_intel_cpu()?;
-        log::info!("CPU is Intel");
+        log::debug!("CPU is Intel");
+
+        let host_hypervisor = HostHypervisor::detect();
+        if host_hypervisor.is_present() {
+            log::warn!(
+                "Running as L1 under another hypervisor: {}",
+                host_hypervisor
+            );
+        }
+
+        if let Err(err) = Self::has_vmx_support() {
+            // Hyper-V/VBS hides VMX from the root partition unless nested virtualization is enabled.
+            if host_hypervisor.is_hyperv() {
+                return Err(HypervisorError::HyperVActive);
+            }
+            return Err(err);
+        }
+        log::debug!("Virtual Machine Extension (VMX) technology is supported");
 
-        Self::has_vmx_support()?;
-        log::info!("Virtual Machine Extension (VMX) technology is supported");
+        Self::has_ept_support()?;
+        log::debug!("Extended Page Tables (EPT) are supported");
 
         Self::has_mtrr()?;
-        log::info!("Memory Type Range Registers (MTRRs) are supported");
+        log::debug!("Memory Type Range Registers (MTRRs) are supported");
+
+        log::debug!("CPU features: {:?}", cpu::features());
 
         Ok(())
     }
@@ -173,13 +1733,10 @@ impl Hypervisor {
     ///
     /// A `Result` which is `Ok` if the CPU is Intel, or `Err` if it's not.
     fn has_intel_cpu() -> Result<(), HypervisorError> {
-        let cpuid = x86::cpuid::CpuId::new();
-        if let Some(vi) = cpuid.get_vendor_info() {
-            if vi.as_str() == "GenuineIntel" {
-                return Ok(());
-            }
+        match cpu::vendor() {
+            Some(CpuVendor::Intel) => Ok(()),
+            _ => Err(HypervisorError::CPUUnsupported),
         }
-        Err(HypervisorError::CPUUnsupported)
     }
 
     /// Check processor support for Virtual Machine Extension (VMX) technology.
@@ -188,13 +1745,24 @@ impl Hypervisor {
     ///
     /// A `Result` which is `Ok` if VMX technology is supported, or `Err` if it's not.
     fn has_vmx_support() -> Result<(), HypervisorError> {
-        let cpuid = x86::cpuid::CpuId::new();
-        if let Some(fi) = cpuid.get_feature_info() {
-            if fi.has_vmx() {
-                return Ok(());
-            }
+        if cpu::has_vmx() {
+            Ok(())
+        } else {
+            Err(HypervisorError::VMXUnsupported)
+        }
+    }
+
+    /// Check processor support for Extended Page Tables (EPT).
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if EPT is supported, or `Err` if it's not.
+    fn has_ept_support() -> Result<(), HypervisorError> {
+        if cpu::has_ept() {
+            Ok(())
+        } else {
+            Err(HypervisorError::EPTUnsupported)
         }
-        Err(HypervisorError::VMXUnsupported)
     }
 
     /// Check processor support for Memory Type Range Registers (MTRRs).
@@ -203,13 +1771,11 @@ impl Hypervisor {
     ///
     /// A `Result` which is `Ok` if MTRRs are supported, or `Err` if it's not.
     fn has_mtrr() -> Result<(), HypervisorError> {
-        let cpuid = x86::cpuid::CpuId::new();
-        if let Some(fi) = cpuid.get_feature_info() {
-            if fi.has_mtrr() {
-                return Ok(());
-            }
+        if cpu::has_mtrr() {
+            Ok(())
+        } else {
+            Err(HypervisorError::MTRRUnsupported)
         }
-        Err(HypervisorError::MTRRUnsupported)
     }
 }
 
@@ -218,10 +1784,26 @@ impl Drop for Hypervisor {
     ///
     /// When a `Hypervisor` instance goes out of scope or is explicitly dropped,
     /// this method attempts to devirtualize the system and logs the result.
+    ///
+    /// The EPTs and the MSR bitmap are referenced by the VMCS of every processor, so the shared data is only
+    /// freed once all processors left VMX operation, and leaked otherwise. The processors are dropped after
+    /// this method, each one retrying the devirtualization before freeing its own VMX structures.
     fn drop(&mut self) {
+        driver_blocker::unregister();
+
+        #[cfg(feature = "introspection")]
+        self.shared_data.processes.stop_worker();
+
         match self.devirtualize_system() {
-            Ok(_) => log::trace!("Devirtualized successfully!"),
-            Err(err) => log::trace!("Failed to devirtualize {}", err),
+            Ok(_) => {
+                log::trace!("Devirtualized successfully!");
+                ipi::unmap_xapic();
+                unsafe { ManuallyDrop::drop(&mut self.shared_data) };
+            }
+            Err(err) => log::error!(
+                "Failed to devirtualize {}, leaking the shared data still in use",
+                err
+            ),
         }
     }
 }
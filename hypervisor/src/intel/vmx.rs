@@ -6,25 +6,57 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
+            apic_base::ApicBase,
+            debug_registers::DebugRegisters,
             descriptor::DescriptorTables,
+            entry_recovery::EntryRecovery,
+            ept::thrashing::ThrashDetector,
+            evmcs::{self, VpAssistPage},
+            event_queue::EventQueue,
+            invept::EptFlush,
+            lbr::MsrArea,
+            msr_policy::MsrPolicies,
             paging::PageTables,
+            preemption_timer::PreemptionTimer,
+            rate_limit::RateLimiter,
+            sandbox::Sandbox,
             shared_data::SharedData,
+            single_step::SingleStep,
+            smm::SmiTracker,
+            spin_monitor::SpinMonitor,
+            support::{vmclear, vmwrite, vmxoff},
+            topology::TopologyLeaves,
+            tsc::VirtualTsc,
             vcpu::Vcpu,
             vmcs::Vmcs,
-            vmlaunch::launch_vm,
+            vmexit::{cpuid::CpuidMasking, descriptor_table::TableShadows},
+            vmlaunch::{launch_vm, vmexit_stub, LAUNCH_VM_FRAME_SIZE},
             vmstack::{VmStack, STACK_CONTENTS_SIZE},
             vmxon::Vmxon,
         },
         utils::capture::GuestRegisters,
         utils::{
+            addresses::{Gpa, PhysicalAddress},
             alloc::{KernelAlloc, PhysicalAllocator},
             capture::CONTEXT,
+            chacha::ChaChaRng,
+            footprint::{self, MemoryCategory},
+            processor::current_processor_index,
         },
     },
     alloc::boxed::Box,
-    core::ptr::NonNull,
+    core::{
+        mem::{offset_of, size_of},
+        ptr::NonNull,
+        sync::atomic::{AtomicU32, Ordering},
+    },
+    static_assertions::const_assert,
+    x86::vmx::vmcs,
 };
 
+#[cfg(feature = "tracing")]
+use crate::intel::exit_history::ExitHistory;
+
 /// Represents the VMX structure with essential components for VMX virtualization.
 ///
 /// This structure contains the VMXON region, VMCS region, descriptor tables, Host RSP, Guest registers and Extened Page Tables (EPT) required for VMX operations.
@@ -36,8 +68,135 @@ use {
 /// - `KernelAlloc` utilizes `ExAllocatePool` or `ExAllocatePoolWithTag` for memory operations.
 ///
 /// Care is taken to prevent premature deallocations, especially at high IRQLs.
+///
+/// # Layout
+///
+/// The fields are split into two regions, in declaration order as the structure is `repr(C)`:
+/// - The hot region, starting at the page-aligned beginning of the structure, holds the state the exit handlers
+///   read or write: the guest registers, the shared data pointer, the pending events, the rate limiter counters,
+///   the per-exit flags and the per-feature state. It measures 2744 bytes with the default features, 2352
+///   without `tracing`, so it stays within the first page of the structure, which is checked at compile time.
+/// - The cold region, `VmxRegions`, holds the setup data only used when virtualizing and devirtualizing the
+///   processor, after the hot region.
+///
+/// New fields go into the region matching how often exits touch them.
 #[repr(C, align(4096))]
 pub struct Vmx {
+    // Hot region: touched on every VM exit.
+    /// The guest's general-purpose registers state.
+    pub guest_registers: GuestRegisters,
+
+    /// The shared data between processors.
+    pub shared_data: NonNull<SharedData>,
+
+    /// The recovery from failed VM entries of the processor.
+    pub entry_recovery: EntryRecovery,
+
+    /// The events waiting to be injected into the guest.
+    pub pending_events: EventQueue,
+
+    /// The generation of the shared EPTs the processor last flushed its translations for, see
+    /// `invept::invept_all_processors`.
+    pub ept_flush: EptFlush,
+
+    /// The rate limiter of the guest-triggerable exits of the processor.
+    pub rate_limiter: RateLimiter,
+
+    /// The code sandbox attached to the processor, if any.
+    /// Its memory is allocated using `MmAllocateContiguousMemorySpecifyCacheNode` when created at PASSIVE_LEVEL.
+    pub sandbox: Option<Box<Sandbox>>,
+
+    /// What CPUID hides from the guest about the hypervisor on this processor, as `CpuidMasking` bits. Atomic,
+    /// as it is changed from other processors at runtime.
+    cpuid_masking: AtomicU32,
+
+    /// The topology leaves of the processor, read when it is virtualized if the topology reported through CPUID
+    /// is configured, see `topology::TopologyConfig`.
+    pub topology_leaves: Option<TopologyLeaves>,
+
+    /// The page of a monitored region whose protection is lifted for the access the guest single-steps over.
+    pub region_step: Option<Gpa>,
+
+    /// The page holding a fault trigger whose previous permissions are restored while the guest single-steps
+    /// over another instruction of the page, see `fault_injection`.
+    pub fault_step: Option<Gpa>,
+
+    /// The page holding poisoned guest memory whose previous permissions are restored while the guest
+    /// single-steps over an access to the rest of the page, see `heap_poison`.
+    pub poison_step: Option<Gpa>,
+
+    /// The thrashing hooked page whose data access is single-stepped in the read/write view, before returning
+    /// to the execute view.
+    pub view_step: Option<Gpa>,
+
+    /// The hooked page whose write by the guest is single-stepped in the read/write view, before the write is
+    /// propagated to its shadow page, see `ept::hooks`.
+    pub hook_write_step: Option<Gpa>,
+
+    /// The single-stepping of the guest requested with `Vcpu::single_step`.
+    pub single_step: SingleStep,
+
+    /// The VMX-preemption timer set with `Vcpu::set_preemption_timer`.
+    pub preemption_timer: PreemptionTimer,
+
+    /// The thrashing detector of the hooked pages of the processor.
+    pub thrash_detector: ThrashDetector,
+
+    /// The TSC read by the guest on the processor.
+    pub tsc: VirtualTsc,
+
+    /// The SMIs taken by the processor around VM exits.
+    pub smi_tracker: SmiTracker,
+
+    /// The IA32_APIC_BASE of the processor, as last written by the guest.
+    pub apic_base: ApicBase,
+
+    /// The policies applied to the RDMSR and WRMSR exits of the guest on the processor, see
+    /// `Vcpu::set_msr_policy`.
+    pub msr_policies: MsrPolicies,
+
+    /// The random number generator of the processor, for code running in VMX root operation, see `utils::chacha`.
+    pub rng: ChaChaRng,
+
+    /// The last VM exits of the processor, see `exit_history`.
+    #[cfg(feature = "tracing")]
+    pub exit_history: ExitHistory,
+
+    /// The number of triple faults of the guest on the processor, see `vmexit::triple_fault`.
+    pub triple_faults: u32,
+
+    /// Whether the `Hypervisor` API asked the processor to leave VMX operation, see `host_call::HostCall`.
+    pub teardown_requested: bool,
+
+    /// The parameters of the `HYPERVISOR_ERROR` bug check to raise once the processor left VMX operation, set on
+    /// a triple fault, see `vmexit::triple_fault`.
+    pub bug_check: Option<[u64; 4]>,
+
+    /// The debug registers of the guest, shadowed while the hypervisor owns hardware breakpoints, see
+    /// `debug_registers`.
+    pub debug_registers: DebugRegisters,
+
+    /// The spin loops of the guest on the processor, see `spin_monitor`. Read from other processors.
+    pub spin_monitor: SpinMonitor,
+
+    /// The GDTR and IDTR seen by the guest while descriptor-table exiting is enabled, see
+    /// `vmexit::descriptor_table`.
+    pub table_shadows: TableShadows,
+
+    /// The setup data of the processor, after the hot state.
+    pub regions: VmxRegions,
+
+    /// Whether the processor is in VMX operation with the VMXON region and the VMCS of this instance.
+    vmx_operation: bool,
+}
+
+/// The setup data of a processor, only used when virtualizing and devirtualizing it, kept apart from the state
+/// `Vmx` touches on every VM exit.
+///
+/// The VMXON and VMCS regions, the descriptor tables, the host stack and paging structures and the LBR area each
+/// have their own allocation, with the allocator and alignment the processor requires, so only the pointers to
+/// them live here.
+pub struct VmxRegions {
     /// Virtual address of the VMXON region, aligned to a 4-KByte boundary.
     /// Allocated using `MmAllocateContiguousMemorySpecifyCacheNode`.
     pub vmxon_region: Box<Vmxon, PhysicalAllocator>,
@@ -62,13 +221,18 @@ pub struct Vmx {
     /// Allocated using `MmAllocateContiguousMemorySpecifyCacheNode`.
     pub host_paging: Box<PageTables, PhysicalAllocator>,
 
-    /// The guest's general-purpose registers state.
-    pub guest_registers: GuestRegisters,
+    /// The MSR area holding the LBR stack of the guest, if it is virtualized.
+    /// Allocated using `MmAllocateContiguousMemorySpecifyCacheNode`.
+    pub lbr_area: Option<Box<MsrArea, PhysicalAllocator>>,
 
-    /// The shared data between processors.
-    pub shared_data: NonNull<SharedData>,
+    /// The VP assist page through which the VMCS region was loaded as the enlightened VMCS, if it was.
+    /// Owned by Windows.
+    pub vp_assist_page: Option<NonNull<VpAssistPage>>,
 }
 
+// The state the exit handlers touch fits in the first page of the page-aligned structure.
+const_assert!(offset_of!(Vmx, regions) <= 0x1000);
+
 impl Vmx {
     /// Creates a new instance of the `Vmx` struct.
     ///
@@ -80,6 +244,10 @@ impl Vmx {
     pub fn new(shared_data: &mut SharedData, context: &CONTEXT) -> Result<Box<Self>, HypervisorError> {
         log::debug!("Setting up VMX");
 
+        // Account for the per-processor structures before allocating them, so that the memory cap is honored. The
+        // reservation is released again if the setup fails before the instance is built.
+        let reservation = footprint::reserve_all(Self::footprint())?;
+
         // Allocate memory for the hypervisor's needs
         let vmxon_region = unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
         let vmcs_region = unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
@@ -88,6 +256,13 @@ impl Vmx {
         let vmstack = unsafe { Box::try_new_zeroed_in(KernelAlloc)?.assume_init() };
         let mut host_paging: Box<PageTables, PhysicalAllocator> = unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
         let guest_registers = GuestRegisters::default();
+        let (lbr_area, lbr_reservation) = match shared_data.lbr_stack {
+            Some(stack) => {
+                let lbr_reservation = footprint::reserve_all([(MemoryCategory::VmxRegion, size_of::<MsrArea>() as u64)])?;
+                (Some(MsrArea::for_lbr_stack(&stack)?), Some(lbr_reservation))
+            }
+            None => (None, None),
+        };
 
         // To capture the current GDT and IDT for the guest the order is important so we can setup up a new GDT and IDT for the host.
         // This is done here instead of `setup_virtualization` because it uses a vec to allocate memory for the new GDT
@@ -99,23 +274,68 @@ impl Vmx {
         log::trace!("Creating Vmx instance");
 
         let instance = Self {
-            vmxon_region,
-            vmcs_region,
-            guest_descriptor_table,
-            host_descriptor_table,
-            vmstack,
-            host_paging,
             guest_registers,
             shared_data: unsafe { NonNull::new_unchecked(shared_data as *mut _) },
+            entry_recovery: EntryRecovery::new(shared_data.entry_failure_retries),
+            pending_events: EventQueue::new(),
+            ept_flush: EptFlush::default(),
+            rate_limiter: RateLimiter::new(shared_data.rate_limits),
+            sandbox: None,
+            cpuid_masking: AtomicU32::new(shared_data.cpuid_masking.bits()),
+            topology_leaves: shared_data.cpuid_topology.and_then(|_| TopologyLeaves::current()),
+            region_step: None,
+            fault_step: None,
+            poison_step: None,
+            view_step: None,
+            hook_write_step: None,
+            single_step: SingleStep::new(),
+            preemption_timer: PreemptionTimer::new(),
+            thrash_detector: ThrashDetector::new(shared_data.thrash_policy),
+            tsc: VirtualTsc::new(shared_data.tsc_config),
+            smi_tracker: SmiTracker::new(shared_data.smm_monitor.is_enabled()),
+            apic_base: ApicBase::current(),
+            msr_policies: shared_data.msr_policies.clone(),
+            rng: ChaChaRng::for_processor(shared_data.rng_seed, current_processor_index()),
+            #[cfg(feature = "tracing")]
+            exit_history: ExitHistory::new(),
+            triple_faults: 0,
+            teardown_requested: false,
+            bug_check: None,
+            debug_registers: DebugRegisters::new(),
+            spin_monitor: SpinMonitor::new(),
+            table_shadows: TableShadows::new(),
+            regions: VmxRegions {
+                vmxon_region,
+                vmcs_region,
+                guest_descriptor_table,
+                host_descriptor_table,
+                vmstack,
+                host_paging,
+                lbr_area,
+                vp_assist_page: None,
+            },
+            vmx_operation: false,
         };
 
+        // The charges are released when the instance is dropped.
+        reservation.keep();
+        if let Some(lbr_reservation) = lbr_reservation {
+            lbr_reservation.keep();
+        }
+
         let mut instance = Box::new(instance);
 
-        instance.vmstack.vmx = &mut *instance as *mut _ as _;
+        instance.regions.vmstack.vmx = &mut *instance as *mut _ as _;
+
+        // Leave VMX operation again if the setup fails midway, before the structures are freed.
+        if let Err(err) = instance.setup_virtualization(shared_data, context) {
+            let _ = instance.teardown();
+            return Err(err);
+        }
 
-        instance.setup_virtualization(shared_data, context)?;
+        shared_data.tsx.apply();
 
-        log::debug!("Dumping VMCS: {:#x?}", instance.vmcs_region);
+        log::debug!("Dumping VMCS: {:#x?}", instance.regions.vmcs_region);
         log::debug!("Dumping CONTEXT: {:#x?}", &context);
 
         log::debug!("VMX setup successfully!");
@@ -140,21 +360,22 @@ impl Vmx {
     ) -> Result<(), HypervisorError> {
         log::debug!("Setting up virtualization");
 
-        Vmxon::setup(&mut self.vmxon_region)?;
+        Vmxon::setup(&mut self.regions.vmxon_region)?;
+        self.vmx_operation = true;
         Vcpu::invalidate_contexts();
 
-        Vmcs::setup(&mut self.vmcs_region)?;
-        VmStack::setup(&mut self.vmstack)?;
+        self.regions.vp_assist_page = Vmcs::setup(&mut self.regions.vmcs_region)?;
+        VmStack::setup(&mut self.regions.vmstack)?;
 
         /* Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.4 GUEST-STATE AREA */
         Vmcs::setup_guest_registers_state(
             &context,
-            &self.guest_descriptor_table,
+            &self.regions.guest_descriptor_table,
             &mut self.guest_registers,
         );
 
         /* Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.5 HOST-STATE AREA */
-        Vmcs::setup_host_registers_state(&context, &self.host_descriptor_table, &self.host_paging)?;
+        Vmcs::setup_host_registers_state(&context, &self.regions.host_descriptor_table, &self.regions.host_paging)?;
 
         /*
          * VMX controls:
@@ -164,6 +385,13 @@ impl Vmx {
          * - 25.8 VM-ENTRY CONTROL FIELDS
          */
         Vmcs::setup_vmcs_control_fields(shared_data)?;
+        self.tsc.load()?;
+        self.debug_registers
+            .virtualize(&shared_data.host_breakpoints)?;
+
+        if let Some(lbr_area) = &self.regions.lbr_area {
+            lbr_area.activate()?;
+        }
 
         log::debug!("Virtualization setup successfully!");
 
@@ -178,12 +406,16 @@ impl Vmx {
     pub fn run(&mut self, cpu_index: u32) {
         log::trace!("Executing VMLAUNCH to run the guest until a VM-exit event occurs");
 
-        let stack_contents_ptr = self.vmstack.stack_contents.as_mut_ptr();
+        let stack_contents_ptr = self.regions.vmstack.stack_contents.as_mut_ptr();
         let vmcs_host_rsp = unsafe { stack_contents_ptr.offset(STACK_CONTENTS_SIZE as isize) };
 
-        log::trace!("Vmx: {:#p}", self.vmstack.vmx);
+        log::trace!("Vmx: {:#p}", self.regions.vmstack.vmx);
 
-        log::info!("Launching VM for processor {}", cpu_index);
+        // Written here rather than by `launch_vm`, as the enlightened VMCS can't be written with VMWRITE.
+        vmwrite(vmcs::host::RSP, vmcs_host_rsp as u64 - LAUNCH_VM_FRAME_SIZE);
+        vmwrite(vmcs::host::RIP, vmexit_stub as *const () as u64);
+
+        log::debug!("Launching VM for processor {}", cpu_index);
         unsafe { launch_vm(&mut self.guest_registers, vmcs_host_rsp as *mut u64) };
     }
 
@@ -195,4 +427,95 @@ impl Vmx {
     pub fn shared_data(&mut self) -> &mut SharedData {
         unsafe { self.shared_data.as_mut() }
     }
+
+    /// Returns what CPUID hides from the guest about the hypervisor on this processor.
+    pub fn cpuid_masking(&self) -> CpuidMasking {
+        CpuidMasking::from_bits_truncate(self.cpuid_masking.load(Ordering::Relaxed))
+    }
+
+    /// Changes what CPUID hides from the guest about the hypervisor on this processor, from its next CPUID on.
+    ///
+    /// # Arguments
+    ///
+    /// * `masking` - What CPUID hides, e.g. `CpuidMasking::all()` for stealth or `CpuidMasking::empty()` for
+    ///   testing.
+    pub fn set_cpuid_masking(&self, masking: CpuidMasking) {
+        self.cpuid_masking.store(masking.bits(), Ordering::Relaxed);
+    }
+
+    /// Returns whether the processor is in VMX operation with the VMXON region and the VMCS of this instance.
+    pub fn in_vmx_operation(&self) -> bool {
+        self.vmx_operation
+    }
+
+    /// Leaves VMX operation, so that the VMXON region, the VMCS and the EPTs may be freed.
+    ///
+    /// Must be called on the processor this instance belongs to, in VMX root operation: from an exit handler,
+    /// see `host_call::HostCall::Devirtualize`, or before the guest was launched. The steps are ordered so that no
+    /// processor state refers to memory about to be freed:
+    /// 1. INVEPT and INVVPID (all contexts) drop the translations derived from the EPTs.
+    /// 2. VMCLEAR writes the VMCS data back to its region and makes it inactive. An enlightened VMCS is also
+    ///    removed from the VP assist page, see `intel::evmcs`.
+    /// 3. VMXOFF leaves VMX operation, releasing the VMXON region.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.11.3 Initializing a VMCS
+    /// and 29.4.3.4 Guidelines for Use of the INVEPT Instruction.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether the processor left VMX operation.
+    pub fn teardown(&mut self) -> Result<(), HypervisorError> {
+        if !self.vmx_operation {
+            return Ok(());
+        }
+
+        log::trace!("Tearing down VMX operation");
+
+        Vcpu::invalidate_contexts();
+
+        vmclear(PhysicalAddress::pa_from_va(
+            self.regions.vmcs_region.as_ref() as *const _ as _,
+        ))?;
+        if let Some(vp_assist_page) = self.regions.vp_assist_page.take() {
+            evmcs::unload(vp_assist_page);
+        }
+        vmxoff()?;
+
+        self.vmx_operation = false;
+
+        self.debug_registers.restore_guest();
+
+        self.shared_data().tsx.restore();
+
+        log::trace!("VMX operation torn down");
+
+        Ok(())
+    }
+
+    /// Returns the memory charged for the per-processor structures, per category.
+    fn footprint() -> [(MemoryCategory, u64); 4] {
+        [
+            (MemoryCategory::VmxRegion, (size_of::<Vmxon>() + size_of::<Vmcs>()) as u64),
+            (MemoryCategory::DescriptorTables, 2 * size_of::<DescriptorTables>() as u64),
+            (MemoryCategory::Stack, size_of::<VmStack>() as u64),
+            (MemoryCategory::PageTables, size_of::<PageTables>() as u64),
+        ]
+    }
+}
+
+impl Drop for Vmx {
+    /// Releases the memory charged for the per-processor structures.
+    ///
+    /// The owning `Vcpu` tears down VMX operation on the right processor first, or leaks the instance if it can't.
+    fn drop(&mut self) {
+        if self.vmx_operation {
+            log::error!("Freeing the VMX structures of a processor still in VMX operation");
+        }
+
+        footprint::release_all(&Self::footprint());
+
+        if self.regions.lbr_area.is_some() {
+            footprint::release(MemoryCategory::VmxRegion, size_of::<MsrArea>() as u64);
+        }
+    }
 }
@@ -8,7 +8,7 @@ use x86_64::registers::control::Cr4;
 use {
     crate::{
         error::HypervisorError,
-        intel::{support::vmxon, vmcs::Vmcs},
+        intel::{nested::HostHypervisor, support::vmxon, vmcs::Vmcs},
         utils::{addresses::PhysicalAddress, alloc::PhysicalAllocator},
     },
     alloc::boxed::Box,
@@ -61,7 +61,13 @@ impl Vmxon {
         vmxon_region.as_mut().revision_id.set_bit(31, false);
 
         // Enable VMX operation.
-        vmxon(vmxon_region_physical_address);
+        if let Err(err) = vmxon(vmxon_region_physical_address) {
+            let host_hypervisor = HostHypervisor::detect();
+            if host_hypervisor.is_present() {
+                log::error!("VMXON failed while running under {}", host_hypervisor);
+            }
+            return Err(err);
+        }
 
         log::debug!("VMXON setup successfully!");
 
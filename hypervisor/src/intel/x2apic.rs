@@ -0,0 +1,94 @@
+//! Interception of the x2APIC MSR interface.
+//!
+//! Systems that boot in x2APIC mode access the local APIC through the MSRs 0x800 to 0x8FF instead of the
+//! memory mapped APIC page. When interception is enabled, these MSRs are set in the MSR bitmap and all
+//! accesses reach the MSR exit handler, which forwards valid accesses to the physical APIC and injects
+//! #GP for the accesses the hardware would fault on, instead of faulting in VMX root operation.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 11.12 EXTENDED XAPIC (X2APIC)
+//! and Table 11-6. Local APIC Register Address Map Supported by x2APIC.
+
+use {crate::utils::instructions::rdmsr, x86::msr};
+
+/// The first MSR of the x2APIC register range.
+pub const X2APIC_MSR_START: u32 = 0x800;
+
+/// The last MSR of the x2APIC register range.
+pub const X2APIC_MSR_END: u32 = 0x8FF;
+
+/// IA32_APIC_BASE bit enabling x2APIC mode (EXTD).
+const APIC_BASE_EXTD: u64 = 1 << 10;
+
+/// IA32_APIC_BASE bit enabling the local APIC (EN).
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// The x2APIC registers and how they may be accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum X2ApicAccess {
+    /// The register can be read and written.
+    ReadWrite,
+
+    /// The register can only be read; writes raise #GP.
+    ReadOnly,
+
+    /// The register can only be written; reads raise #GP.
+    WriteOnly,
+}
+
+/// Returns the MSRs of the x2APIC register range.
+pub fn x2apic_msrs() -> impl Iterator<Item = u32> {
+    X2APIC_MSR_START..=X2APIC_MSR_END
+}
+
+/// Returns whether the local APIC of the current processor runs in x2APIC mode.
+pub fn is_x2apic_enabled() -> bool {
+    let apic_base = rdmsr(msr::IA32_APIC_BASE);
+    apic_base & (APIC_BASE_EXTD | APIC_BASE_ENABLE) == (APIC_BASE_EXTD | APIC_BASE_ENABLE)
+}
+
+/// Returns whether the given MSR belongs to the x2APIC register range.
+pub fn is_x2apic_msr(msr: u32) -> bool {
+    (X2APIC_MSR_START..=X2APIC_MSR_END).contains(&msr)
+}
+
+/// Returns the name and the access rights of an x2APIC register.
+///
+/// # Arguments
+///
+/// * `msr` - The MSR of the register.
+///
+/// # Returns
+///
+/// The name and the access rights, or `None` if the MSR is reserved.
+pub fn register(msr: u32) -> Option<(&'static str, X2ApicAccess)> {
+    use X2ApicAccess::*;
+
+    let register = match msr {
+        0x802 => ("APIC ID", ReadOnly),
+        0x803 => ("Version", ReadOnly),
+        0x808 => ("TPR", ReadWrite),
+        0x80A => ("PPR", ReadOnly),
+        0x80B => ("EOI", WriteOnly),
+        0x80D => ("LDR", ReadOnly),
+        0x80F => ("SVR", ReadWrite),
+        0x810..=0x817 => ("ISR", ReadOnly),
+        0x818..=0x81F => ("TMR", ReadOnly),
+        0x820..=0x827 => ("IRR", ReadOnly),
+        0x828 => ("ESR", ReadWrite),
+        0x82F => ("LVT CMCI", ReadWrite),
+        0x830 => ("ICR", ReadWrite),
+        0x832 => ("LVT Timer", ReadWrite),
+        0x833 => ("LVT Thermal Sensor", ReadWrite),
+        0x834 => ("LVT Performance Monitoring", ReadWrite),
+        0x835 => ("LVT LINT0", ReadWrite),
+        0x836 => ("LVT LINT1", ReadWrite),
+        0x837 => ("LVT Error", ReadWrite),
+        0x838 => ("Initial Count", ReadWrite),
+        0x839 => ("Current Count", ReadOnly),
+        0x83E => ("Divide Configuration", ReadWrite),
+        0x83F => ("Self IPI", WriteOnly),
+        _ => return None,
+    };
+
+    Some(register)
+}
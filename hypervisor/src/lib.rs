@@ -7,7 +7,6 @@
 #![feature(const_mut_refs)]
 #![feature(naked_functions)]
 #![feature(asm_const)]
-#![feature(once_cell_try)]
 #![feature(decl_macro)]
 
 extern crate alloc;
@@ -3,16 +3,219 @@
 //! This module introduces the `PhysicalAddress` structure that simplifies operations around
 //! physical addresses. It provides conversions between virtual addresses (VAs) and physical addresses (PAs),
 //! as well as methods for extracting page frame numbers (PFNs) and other address-related information.
+//!
+//! The address spaces of the guest and the host are told apart by distinct types, converted into each other
+//! by explicit functions only:
+//! - `Gva`: a guest virtual address, translated with the guest page tables (see `GuestMemory`).
+//! - `Gpa`: a guest physical address, translated with the EPT.
+//! - `Hva`: a host virtual address.
+//! - `Hpa`: a host physical address.
+//!
+//! The EPT identity maps guest physical memory, so a `Gpa` converts to the `Hpa` of the same value. The host
+//! runs on the page tables of the system process, so the kernel half of the guest virtual address space is
+//! the host virtual address space; kernel addresses obtained by the driver, such as module bases, are `Hva`s.
 
 use {
-    core::ops::{Deref, DerefMut},
+    core::{
+        fmt,
+        ops::{Add, AddAssign, Deref, DerefMut, Sub},
+    },
     wdk_sys::{
         ntddk::{MmGetPhysicalAddress, MmGetVirtualForPhysical},
         PHYSICAL_ADDRESS,
     },
-    x86::bits64::paging::{PAddr, BASE_PAGE_SHIFT},
+    x86::bits64::paging::{PAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, LARGE_PAGE_SIZE},
 };
 
+/// The first address of the kernel half of the canonical address space.
+const KERNEL_SPACE_START: u64 = 0xFFFF_8000_0000_0000;
+
+/// Defines an address type with the operations shared by all address spaces.
+macro_rules! address_type {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+        #[repr(transparent)]
+        pub struct $name(u64);
+
+        impl $name {
+            /// Constructs the address from its raw value.
+            pub const fn new(address: u64) -> Self {
+                Self(address)
+            }
+
+            /// Returns the raw value of the address.
+            pub const fn as_u64(self) -> u64 {
+                self.0
+            }
+
+            /// Returns the address of the 4KB page containing the address.
+            pub const fn page_base(self) -> Self {
+                Self(self.0 & !(BASE_PAGE_SIZE as u64 - 1))
+            }
+
+            /// Returns the address of the 2MB page containing the address.
+            pub const fn large_page_base(self) -> Self {
+                Self(self.0 & !(LARGE_PAGE_SIZE as u64 - 1))
+            }
+
+            /// Returns the offset of the address in its 4KB page.
+            pub const fn page_offset(self) -> u64 {
+                self.0 & (BASE_PAGE_SIZE as u64 - 1)
+            }
+
+            /// Returns whether the address is 4KB aligned.
+            pub const fn is_page_aligned(self) -> bool {
+                self.page_offset() == 0
+            }
+
+            /// Returns the page frame number of the address.
+            pub const fn pfn(self) -> u64 {
+                self.0 >> BASE_PAGE_SHIFT
+            }
+        }
+
+        impl Add<u64> for $name {
+            type Output = Self;
+
+            fn add(self, offset: u64) -> Self {
+                Self(self.0.wrapping_add(offset))
+            }
+        }
+
+        impl AddAssign<u64> for $name {
+            fn add_assign(&mut self, offset: u64) {
+                self.0 = self.0.wrapping_add(offset);
+            }
+        }
+
+        impl Sub for $name {
+            type Output = u64;
+
+            /// Returns the distance between two addresses of the same address space.
+            fn sub(self, other: Self) -> u64 {
+                self.0.wrapping_sub(other.0)
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, concat!(stringify!($name), "({:#x})"), self.0)
+            }
+        }
+
+        impl fmt::LowerHex for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::LowerHex::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
+address_type!(
+    /// A guest virtual address.
+    Gva
+);
+
+address_type!(
+    /// A guest physical address.
+    Gpa
+);
+
+address_type!(
+    /// A host virtual address.
+    Hva
+);
+
+address_type!(
+    /// A host physical address.
+    Hpa
+);
+
+impl Gva {
+    /// Converts a guest kernel address to the host virtual address of the same memory.
+    ///
+    /// # Returns
+    ///
+    /// The host virtual address, or `None` for a user-mode address, which belongs to a guest process and
+    /// must be translated with `GuestMemory`.
+    pub const fn kernel_to_hva(self) -> Option<Hva> {
+        match self.0 >= KERNEL_SPACE_START {
+            true => Some(Hva(self.0)),
+            false => None,
+        }
+    }
+}
+
+impl Gpa {
+    /// Converts the address to the host physical address it is mapped to by the identity mapped EPT.
+    pub const fn to_hpa(self) -> Hpa {
+        Hpa(self.0)
+    }
+
+    /// Converts the address to the host virtual address mapping it.
+    ///
+    /// # Returns
+    ///
+    /// The host virtual address, or `None` if the memory is not mapped in the host.
+    pub fn to_hva(self) -> Option<Hva> {
+        self.to_hpa().to_hva()
+    }
+}
+
+impl Hva {
+    /// Constructs the address of a pointer.
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        Self(ptr as u64)
+    }
+
+    /// Returns the address as a pointer.
+    pub const fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    /// Returns the address as a mutable pointer.
+    pub const fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// Converts the address to the host physical address it maps.
+    ///
+    /// # Returns
+    ///
+    /// The host physical address, or `None` if the address is not mapped.
+    pub fn to_hpa(self) -> Option<Hpa> {
+        match PhysicalAddress::pa_from_va(self.0) {
+            0 => None,
+            pa => Some(Hpa(pa)),
+        }
+    }
+
+    /// Converts the address to the guest kernel address of the same memory.
+    pub const fn to_gva(self) -> Gva {
+        Gva(self.0)
+    }
+}
+
+impl Hpa {
+    /// Converts the address to the guest physical address mapped to it by the identity mapped EPT.
+    pub const fn to_gpa(self) -> Gpa {
+        Gpa(self.0)
+    }
+
+    /// Converts the address to the host virtual address mapping it.
+    ///
+    /// # Returns
+    ///
+    /// The host virtual address, or `None` if the memory is not mapped in the host.
+    pub fn to_hva(self) -> Option<Hva> {
+        match PhysicalAddress::va_from_pa(self.0) {
+            0 => None,
+            va => Some(Hva(va)),
+        }
+    }
+}
+
 /// A representation of physical addresses.
 ///
 /// Provides utility methods to work with physical addresses,
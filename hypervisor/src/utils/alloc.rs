@@ -12,6 +12,7 @@
 //! https://github.com/not-matthias/kernel-alloc-rs
 
 use {
+    crate::utils::footprint::{self, MemoryCategory},
     alloc::alloc::handle_alloc_error,
     core::alloc::{AllocError, Allocator, GlobalAlloc, Layout},
     core::ptr::NonNull,
@@ -145,6 +146,8 @@ unsafe impl GlobalAlloc for KernelAlloc {
             handle_alloc_error(layout);
         }
 
+        footprint::charge(MemoryCategory::Heap, layout.size() as u64);
+
         memory as _
     }
 
@@ -156,8 +159,9 @@ unsafe impl GlobalAlloc for KernelAlloc {
     /// # Parameters
     ///
     /// * `ptr` - Raw pointer to the memory block to be released.
-    /// * `_layout` - Memory layout specifications (not used in this implementation).
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+    /// * `layout` - Memory layout specifications.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         ExFreePool(ptr as _);
+        footprint::release(MemoryCategory::Heap, layout.size() as u64);
     }
 }
@@ -0,0 +1,121 @@
+//! Cancellation of long-running operations in VMX root operation.
+//!
+//! While a processor serves a hypercall, its guest is paused, and so is every processor of the guest waiting
+//! for it, e.g. on a spinlock or a TLB shootdown. Operations whose duration depends on the parameters of the
+//! control client check a `CancellationToken` as they progress and stop early once it is cancelled, e.g.
+//! `Ept::dump` of a large range, the translation of a range poisoned or unpoisoned and `ProcessControl::walk`:
+//! - By its deadline, armed with `HypervisorBuilder::root_operation_timeout` when the operation starts, so a
+//!   request that is too large cannot pause the guest indefinitely.
+//! - By another processor, e.g. when the control client that requested the operation closes its session or
+//!   is found dead, see `ClientSessions::close`.
+//!
+//! A cancelled operation returns what it completed so far, and the hypercall reports
+//! `HypercallStatus::Cancelled`.
+
+use {
+    crate::utils::instructions::rdtsc,
+    core::sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+/// The default time an operation may run in VMX root operation, in TSC ticks, i.e. around 100 ms at 3 GHz.
+pub const DEFAULT_ROOT_OPERATION_TIMEOUT: u64 = 300_000_000;
+
+/// Why an operation was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CancelReason {
+    /// The deadline of the operation passed.
+    TimedOut = 1,
+
+    /// The control client that requested the operation went away.
+    Disconnected = 2,
+}
+
+impl CancelReason {
+    /// Decodes a reason, or returns `None` for a token that is not cancelled.
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::TimedOut),
+            2 => Some(Self::Disconnected),
+            _ => None,
+        }
+    }
+}
+
+/// Cancels an operation running on one processor, from its deadline or from another processor.
+pub struct CancellationToken {
+    /// The `CancelReason` of the cancellation, or zero while the operation may run.
+    reason: AtomicU8,
+
+    /// The TSC at which the operation times out, or zero for no deadline.
+    deadline: AtomicU64,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled and has no deadline.
+    pub const fn new() -> Self {
+        Self {
+            reason: AtomicU8::new(0),
+            deadline: AtomicU64::new(0),
+        }
+    }
+
+    /// Rearms the token for a new operation, clearing a previous cancellation.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time the operation may run from now, in TSC ticks, or zero for no deadline.
+    pub fn arm(&self, timeout: u64) {
+        let deadline = match timeout {
+            0 => 0,
+            timeout => rdtsc().saturating_add(timeout),
+        };
+
+        self.deadline.store(deadline, Ordering::Relaxed);
+        self.reason.store(0, Ordering::Release);
+    }
+
+    /// Cancels the operation. The first reason given is kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the operation is cancelled.
+    pub fn cancel(&self, reason: CancelReason) {
+        if self
+            .reason
+            .compare_exchange(0, reason as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            log::debug!("Cancelling root operation: {:?}", reason);
+        }
+    }
+
+    /// Returns why the operation was cancelled, cancelling it first if its deadline passed.
+    ///
+    /// # Returns
+    ///
+    /// The reason of the cancellation, or `None` while the operation may continue.
+    pub fn reason(&self) -> Option<CancelReason> {
+        if let Some(reason) = CancelReason::from_u8(self.reason.load(Ordering::Acquire)) {
+            return Some(reason);
+        }
+
+        let deadline = self.deadline.load(Ordering::Relaxed);
+        if deadline != 0 && rdtsc() >= deadline {
+            self.cancel(CancelReason::TimedOut);
+        }
+
+        CancelReason::from_u8(self.reason.load(Ordering::Acquire))
+    }
+
+    /// Returns whether the operation must stop, see `reason`.
+    pub fn is_cancelled(&self) -> bool {
+        self.reason().is_some()
+    }
+}
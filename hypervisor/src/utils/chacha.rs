@@ -0,0 +1,225 @@
+//! A ChaCha20 random number generator for code running in VMX root operation.
+//!
+//! The OS random number generators cannot be called from an exit handler, and RDRAND and RDSEED can fail or be
+//! slow under contention. Each processor therefore runs its own generator, the keystream of ChaCha20 for a key
+//! seeded once when the processor is virtualized, see `Vmx::rng`. Numbers are then drawn without locks or
+//! instructions that can fail, for the tokens of the client sessions, see `intel::sessions`, and the keys and
+//! nonces of the guest agent, see `intel::agent_monitor`.
+//!
+//! The seed comes from RDSEED, RDRAND, or the TSC as a last resort, unless a fixed seed is configured with
+//! `HypervisorBuilder::rng_seed`. A fixed seed makes the numbers of every processor reproducible from one run to
+//! the next, which is meant for debugging and must not be used where the numbers have to be unpredictable.
+//!
+//! The block counter and the stream take 64 bits each, as in the original ChaCha, instead of the 32-bit counter
+//! and 96-bit nonce of RFC 8439. The block function is the same, and is checked against the test vector of the RFC
+//! before the hypervisor is built, see `self_test`.
+//!
+//! Reference: https://www.rfc-editor.org/rfc/rfc8439 (2.3. The ChaCha20 Block Function)
+
+use crate::utils::{
+    cpu,
+    instructions::{rdrand, rdseed, rdtsc},
+    siphash::siphash24,
+};
+
+/// The constants of the first row of the ChaCha state ("expand 32-byte k").
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// The number of 32-bit words of a ChaCha block.
+const BLOCK_WORDS: usize = 16;
+
+/// The number of double rounds of ChaCha20.
+const DOUBLE_ROUNDS: usize = 10;
+
+/// The block of the test vector of RFC 8439, 2.3.2.
+#[rustfmt::skip]
+const TEST_VECTOR_BLOCK: [u32; BLOCK_WORDS] = [
+    0xe4e7_f110, 0x1559_3bd1, 0x1fdd_0f50, 0xc471_20a3, 0xc7f4_d1c7, 0x0368_c033, 0x9aaa_2204, 0x4e6c_d4c3,
+    0x4664_82d2, 0x09aa_9f07, 0x05d7_c214, 0xa202_8bd9, 0xd19c_12b5, 0xb94e_16de, 0xe883_d0cb, 0x4e3c_50a2,
+];
+
+/// A ChaCha20 keystream used as a random number generator.
+pub struct ChaChaRng {
+    /// The 256-bit key.
+    key: [u32; 8],
+
+    /// The stream, distinguishing the generators sharing a key.
+    stream: u64,
+
+    /// The block counter of the next block.
+    counter: u64,
+
+    /// The current block of the keystream.
+    block: [u32; BLOCK_WORDS],
+
+    /// The index of the next unused word of `block`.
+    index: usize,
+}
+
+impl ChaChaRng {
+    /// Creates a generator from a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The 256-bit key.
+    /// * `stream` - The stream, so generators with the same key draw different numbers.
+    pub fn new(key: [u32; 8], stream: u64) -> Self {
+        Self {
+            key,
+            stream,
+            counter: 0,
+            block: [0; BLOCK_WORDS],
+            index: BLOCK_WORDS,
+        }
+    }
+
+    /// Creates the generator of a processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - The fixed seed shared by all processors, or `None` to seed from the processor.
+    /// * `processor` - The index of the processor, the stream of the generator.
+    pub fn for_processor(seed: Option<u64>, processor: u32) -> Self {
+        let key = match seed {
+            Some(seed) => {
+                core::array::from_fn(|i| siphash24([seed, 0], &[processor as u64, i as u64]) as u32)
+            }
+            None => hardware_key(),
+        };
+
+        Self::new(key, processor as u64)
+    }
+
+    /// Returns the next random 32-bit number.
+    pub fn next_u32(&mut self) -> u32 {
+        if self.index >= BLOCK_WORDS {
+            self.refill();
+        }
+
+        let value = self.block.get(self.index).copied().unwrap_or_default();
+        self.index += 1;
+        value
+    }
+
+    /// Returns the next random 64-bit number.
+    pub fn next_u64(&mut self) -> u64 {
+        let low = self.next_u32() as u64;
+        let high = self.next_u32() as u64;
+        (high << 32) | low
+    }
+
+    /// Returns a random number below a bound, without the bias of a plain modulo.
+    ///
+    /// # Arguments
+    ///
+    /// * `bound` - The exclusive upper bound, at least 1.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        if bound <= 1 {
+            return 0;
+        }
+
+        // Numbers from the incomplete last range of `bound` values are drawn again.
+        let limit = u64::MAX - u64::MAX % bound;
+        loop {
+            let value = self.next_u64();
+            if value < limit {
+                return value % bound;
+            }
+        }
+    }
+
+    /// Fills a buffer with random bytes.
+    pub fn fill_bytes(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(bytes.get(..chunk.len()).unwrap_or_default());
+        }
+    }
+
+    /// Computes the next block of the keystream.
+    fn refill(&mut self) {
+        let mut input = [0u32; BLOCK_WORDS];
+        input[..4].copy_from_slice(&SIGMA);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter as u32;
+        input[13] = (self.counter >> 32) as u32;
+        input[14] = self.stream as u32;
+        input[15] = (self.stream >> 32) as u32;
+
+        let mut state = input;
+        for _ in 0..DOUBLE_ROUNDS {
+            quarter_round(&mut state, 0, 4, 8, 12);
+            quarter_round(&mut state, 1, 5, 9, 13);
+            quarter_round(&mut state, 2, 6, 10, 14);
+            quarter_round(&mut state, 3, 7, 11, 15);
+            quarter_round(&mut state, 0, 5, 10, 15);
+            quarter_round(&mut state, 1, 6, 11, 12);
+            quarter_round(&mut state, 2, 7, 8, 13);
+            quarter_round(&mut state, 3, 4, 9, 14);
+        }
+
+        for (word, input) in state.iter_mut().zip(input) {
+            *word = word.wrapping_add(input);
+        }
+
+        self.block = state;
+        self.index = 0;
+        self.counter = self.counter.wrapping_add(1);
+    }
+}
+
+/// Checks the block function against the test vector of RFC 8439, 2.3.2.
+///
+/// # Returns
+///
+/// Whether the block computed for the key, block count and nonce of the test vector is the one of the RFC.
+pub fn self_test() -> bool {
+    // The key 00:01:02:..:1f, the block count 1 and the nonce 00:00:00:09:00:00:00:4a:00:00:00:00, whose first
+    // word is the high half of the 64-bit counter and the others the stream.
+    let key =
+        core::array::from_fn(|i| u32::from_le_bytes(core::array::from_fn(|j| (4 * i + j) as u8)));
+    let mut rng = ChaChaRng {
+        counter: 0x0900_0000_0000_0001,
+        ..ChaChaRng::new(key, 0x4a00_0000)
+    };
+
+    TEST_VECTOR_BLOCK
+        .iter()
+        .all(|&expected| rng.next_u32() == expected)
+}
+
+/// The ChaCha quarter round on four words of the state.
+fn quarter_round(state: &mut [u32; BLOCK_WORDS], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Draws a key from the random number generators of the processor.
+///
+/// RDSEED delivers full entropy and is preferred, RDRAND is the output of a generator reseeded by the same source.
+/// Without either, the key is derived from the TSC, which is only unpredictable to a guest that cannot observe
+/// the time of the virtualization.
+fn hardware_key() -> [u32; 8] {
+    let mut counter = 0u64;
+    let mut random = || {
+        counter += 1;
+
+        cpu::has_rdseed()
+            .then(rdseed)
+            .flatten()
+            .or_else(|| cpu::has_rdrand().then(rdrand).flatten())
+            .unwrap_or_else(|| siphash24([rdtsc(), counter], &[rdtsc()]))
+    };
+
+    let words = [random(), random(), random(), random()];
+    core::array::from_fn(|i| {
+        let word = words.get(i / 2).copied().unwrap_or_default();
+        (word >> (32 * (i % 2))) as u32
+    })
+}
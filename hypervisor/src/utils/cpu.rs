@@ -0,0 +1,491 @@
+//! Detection of the processor vendor and of the features the hypervisor depends on.
+//!
+//! The features are detected once, on the first query, and cached for the lifetime of the driver, so the
+//! rest of the hypervisor asks `has_vmx()`, `has_ept()` and friends instead of issuing its own `cpuid` and
+//! `rdmsr`. The ISA features are identical on all processors of the system, including on hybrid processors,
+//! so detecting them on any processor is enough. The core type of hybrid processors is the exception and is
+//! queried on the current processor every time, see `core_type`.
+//!
+//! The VMX capabilities (EPT, VPID, TSC scaling, virtual NMIs, INS/OUTS information, TRUE controls) are decoded from
+//! the capability MSRs captured by `VmxCapabilities`, only on processors that report VMX, as reading the VMX
+//! capability MSRs raises #GP otherwise.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: CPUID—CPU Identification
+//! and Appendix A VMX CAPABILITY REPORTING FACILITY.
+
+use {
+    crate::{
+        intel::{evmcs, platform::VmxCapabilities},
+        utils::instructions::rdmsr,
+    },
+    bitflags::bitflags,
+    core::sync::atomic::{AtomicU64, Ordering},
+    x86::{cpuid::cpuid, msr},
+};
+
+/// CPUID leaf reporting the hybrid core type.
+const CPUID_HYBRID_INFO: u32 = 0x1A;
+
+/// CPUID leaf reporting the maximum extended leaf.
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+
+/// The first CPUID leaf reporting the brand string.
+const CPUID_BRAND_STRING_FIRST: u32 = 0x8000_0002;
+
+/// The last CPUID leaf reporting the brand string.
+const CPUID_BRAND_STRING_LAST: u32 = 0x8000_0004;
+
+/// CPUID.01H:ECX bit indicating support for VMX.
+const CPUID_01_ECX_VMX: u32 = 1 << 5;
+
+/// CPUID.01H:ECX bit indicating support for RDRAND.
+const CPUID_01_ECX_RDRAND: u32 = 1 << 30;
+
+/// CPUID.01H:ECX bit indicating that a hypervisor is present.
+const CPUID_01_ECX_HYPERVISOR: u32 = 1 << 31;
+
+/// CPUID.01H:EDX bit indicating support for the MTRRs.
+const CPUID_01_EDX_MTRR: u32 = 1 << 12;
+
+/// CPUID.(EAX=07H,ECX=0):EBX bit indicating support for INVPCID.
+const CPUID_07_EBX_INVPCID: u32 = 1 << 10;
+
+/// CPUID.(EAX=07H,ECX=0):EBX bit indicating support for RDSEED.
+const CPUID_07_EBX_RDSEED: u32 = 1 << 18;
+
+/// CPUID.(EAX=07H,ECX=0):EDX bit indicating a hybrid processor.
+const CPUID_07_EDX_HYBRID: u32 = 1 << 15;
+
+/// IA32_VMX_BASIC bit indicating that INS and OUTS exits report the VM-exit instruction information.
+const VMX_BASIC_INS_OUTS_INFO: u64 = 1 << 54;
+
+/// IA32_VMX_PINBASED_CTLS allowed-1 bit of the "NMI exiting" control.
+const PINBASED_CTLS_NMI_EXITING: u64 = 1 << (32 + 3);
+
+/// IA32_VMX_PINBASED_CTLS allowed-1 bit of the "virtual NMIs" control.
+const PINBASED_CTLS_VIRTUAL_NMIS: u64 = 1 << (32 + 5);
+
+/// IA32_VMX_PINBASED_CTLS allowed-1 bit of the "activate VMX-preemption timer" control.
+const PINBASED_CTLS_PREEMPTION_TIMER: u64 = 1 << (32 + 6);
+
+/// IA32_VMX_EXIT_CTLS allowed-1 bit of the "save VMX-preemption timer value" control.
+const EXIT_CTLS_SAVE_PREEMPTION_TIMER: u64 = 1 << (32 + 22);
+
+/// IA32_VMX_EXIT_CTLS allowed-1 bits of the "save IA32_EFER" and "load IA32_EFER" controls.
+const EXIT_CTLS_SAVE_LOAD_EFER: u64 = (1 << (32 + 20)) | (1 << (32 + 21));
+
+/// IA32_VMX_ENTRY_CTLS allowed-1 bit of the "load IA32_EFER" control.
+const ENTRY_CTLS_LOAD_EFER: u64 = 1 << (32 + 15);
+
+/// IA32_VMX_PROCBASED_CTLS2 allowed-1 bit of the "enable EPT" control.
+const PROCBASED_CTLS2_ENABLE_EPT: u64 = 1 << (32 + 1);
+
+/// IA32_VMX_PROCBASED_CTLS2 allowed-1 bit of the "enable VPID" control.
+const PROCBASED_CTLS2_ENABLE_VPID: u64 = 1 << (32 + 5);
+
+/// IA32_VMX_PROCBASED_CTLS2 allowed-1 bit of the "unrestricted guest" control.
+const PROCBASED_CTLS2_UNRESTRICTED_GUEST: u64 = 1 << (32 + 7);
+
+/// IA32_VMX_PROCBASED_CTLS2 allowed-1 bit of the "use TSC scaling" control.
+const PROCBASED_CTLS2_USE_TSC_SCALING: u64 = 1 << (32 + 25);
+
+/// IA32_VMX_EPT_VPID_CAP bit indicating support for the individual-address INVVPID type.
+const EPT_VPID_CAP_INVVPID_INDIVIDUAL_ADDRESS: u64 = 1 << 40;
+
+/// IA32_VMX_MISC bit indicating support for the HLT activity state.
+const VMX_MISC_ACTIVITY_HLT: u64 = 1 << 6;
+
+/// IA32_VMX_MISC bit indicating support for the shutdown activity state.
+const VMX_MISC_ACTIVITY_SHUTDOWN: u64 = 1 << 7;
+
+/// IA32_VMX_MISC bit indicating support for the wait-for-SIPI activity state.
+const VMX_MISC_ACTIVITY_WAIT_FOR_SIPI: u64 = 1 << 8;
+
+/// IA32_VMX_MISC bits 4:0, the TSC bit whose changes count the VMX-preemption timer down.
+const VMX_MISC_PREEMPTION_TIMER_RATE: u64 = 0x1F;
+
+/// Set in the cache once the features have been detected.
+const CACHE_VALID: u64 = 1 << 63;
+
+/// The position of the vendor in the cache.
+const CACHE_VENDOR_SHIFT: u32 = 32;
+
+/// The cached features in bits 31:0, the vendor in bits 39:32 and `CACHE_VALID`.
+static CACHE: AtomicU64 = AtomicU64::new(0);
+
+bitflags! {
+    /// The processor features the hypervisor depends on or takes advantage of.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CpuFeatures: u32 {
+        /// Virtual Machine Extensions.
+        const VMX = 1 << 0;
+
+        /// Extended Page Tables.
+        const EPT = 1 << 1;
+
+        /// Virtual Processor Identifiers.
+        const VPID = 1 << 2;
+
+        /// The INVPCID instruction.
+        const INVPCID = 1 << 3;
+
+        /// Memory Type Range Registers.
+        const MTRR = 1 << 4;
+
+        /// The RDRAND instruction.
+        const RDRAND = 1 << 5;
+
+        /// Performance and efficiency cores, see `core_type`.
+        const HYBRID = 1 << 6;
+
+        /// Another hypervisor runs underneath us.
+        const HYPERVISOR = 1 << 7;
+
+        /// The TRUE VMX capability MSRs.
+        const TRUE_VMX_CONTROLS = 1 << 8;
+
+        /// The "NMI exiting" and "virtual NMIs" VM-execution controls, and thus NMI-window exiting.
+        const VIRTUAL_NMIS = 1 << 9;
+
+        /// The VM-exit instruction information of INS and OUTS exits.
+        const INS_OUTS_INFO = 1 << 10;
+
+        /// The "use TSC scaling" VM-execution control.
+        const TSC_SCALING = 1 << 11;
+
+        /// The HLT guest activity state, entered on VM entry.
+        const HLT_ACTIVITY_STATE = 1 << 12;
+
+        /// The RDSEED instruction.
+        const RDSEED = 1 << 13;
+
+        /// The VMX-preemption timer, with its value saved on VM exits.
+        const PREEMPTION_TIMER = 1 << 14;
+
+        /// The wait-for-SIPI activity state, along with the unrestricted guest and IA32_EFER loading controls
+        /// needed to run the guest from the INIT state.
+        const WAIT_FOR_SIPI = 1 << 15;
+
+        /// The individual-address type of INVVPID, which processors with VPIDs are not required to support.
+        const INVVPID_INDIVIDUAL_ADDRESS = 1 << 16;
+
+        /// The shutdown guest activity state, entered on VM entry.
+        const SHUTDOWN_ACTIVITY_STATE = 1 << 17;
+    }
+}
+
+/// The processor vendor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CpuVendor {
+    Intel = 1,
+    Amd = 2,
+}
+
+/// The type of a core of a hybrid processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoreType {
+    /// An efficiency core (Atom).
+    Efficiency,
+
+    /// A performance core (Core).
+    Performance,
+
+    /// A core type not known to the hypervisor.
+    Unknown(u8),
+}
+
+/// Returns the features of the processor.
+pub fn features() -> CpuFeatures {
+    CpuFeatures::from_bits_truncate(cache() as u32)
+}
+
+/// Returns the vendor of the processor, `None` if it is neither Intel nor AMD.
+pub fn vendor() -> Option<CpuVendor> {
+    match (cache() >> CACHE_VENDOR_SHIFT) as u8 {
+        1 => Some(CpuVendor::Intel),
+        2 => Some(CpuVendor::Amd),
+        _ => None,
+    }
+}
+
+/// Returns whether the processor supports VMX.
+pub fn has_vmx() -> bool {
+    features().contains(CpuFeatures::VMX)
+}
+
+/// Returns whether the processor supports EPT.
+pub fn has_ept() -> bool {
+    features().contains(CpuFeatures::EPT)
+}
+
+/// Returns whether the processor supports VPIDs.
+pub fn has_vpid() -> bool {
+    features().contains(CpuFeatures::VPID)
+}
+
+/// Returns whether INVVPID can invalidate the translations of a single linear address.
+pub fn has_invvpid_individual_address() -> bool {
+    features().contains(CpuFeatures::INVVPID_INDIVIDUAL_ADDRESS)
+}
+
+/// Returns whether the processor supports the INVPCID instruction.
+pub fn has_invpcid() -> bool {
+    features().contains(CpuFeatures::INVPCID)
+}
+
+/// Returns whether the processor supports the MTRRs.
+pub fn has_mtrr() -> bool {
+    features().contains(CpuFeatures::MTRR)
+}
+
+/// Returns whether the processor supports the RDRAND instruction.
+pub fn has_rdrand() -> bool {
+    features().contains(CpuFeatures::RDRAND)
+}
+
+/// Returns whether the processor supports RDSEED.
+pub fn has_rdseed() -> bool {
+    features().contains(CpuFeatures::RDSEED)
+}
+
+/// Returns whether another hypervisor runs underneath us.
+pub fn has_hypervisor() -> bool {
+    features().contains(CpuFeatures::HYPERVISOR)
+}
+
+/// Returns whether the processor has performance and efficiency cores.
+pub fn is_hybrid() -> bool {
+    features().contains(CpuFeatures::HYBRID)
+}
+
+/// Returns whether the VMX controls must be adjusted with the TRUE capability MSRs.
+pub fn has_true_vmx_controls() -> bool {
+    features().contains(CpuFeatures::TRUE_VMX_CONTROLS)
+}
+
+/// Returns whether NMIs can be intercepted and virtualized, see `CpuFeatures::VIRTUAL_NMIS`.
+pub fn has_virtual_nmis() -> bool {
+    features().contains(CpuFeatures::VIRTUAL_NMIS)
+}
+
+/// Returns whether INS and OUTS exits report their address size and segment.
+pub fn has_ins_outs_info() -> bool {
+    features().contains(CpuFeatures::INS_OUTS_INFO)
+}
+
+/// Returns whether the TSC read by the guest can be scaled without exiting. The enlightened VMCS has no TSC
+/// multiplier, see `intel::evmcs`.
+pub fn has_tsc_scaling() -> bool {
+    features().contains(CpuFeatures::TSC_SCALING) && !evmcs::is_enabled()
+}
+
+/// Returns whether the guest can be resumed in the HLT activity state.
+pub fn has_hlt_activity_state() -> bool {
+    features().contains(CpuFeatures::HLT_ACTIVITY_STATE)
+}
+
+/// Returns whether the guest can be resumed in the shutdown activity state.
+pub fn has_shutdown_activity_state() -> bool {
+    features().contains(CpuFeatures::SHUTDOWN_ACTIVITY_STATE)
+}
+
+/// Returns whether the VMX-preemption timer can count across VM exits, see `CpuFeatures::PREEMPTION_TIMER`. The
+/// enlightened VMCS has no VMX-preemption timer, see `intel::evmcs`.
+pub fn has_preemption_timer() -> bool {
+    features().contains(CpuFeatures::PREEMPTION_TIMER) && !evmcs::is_enabled()
+}
+
+/// Returns whether INIT and SIPI can be emulated for the guest, see `CpuFeatures::WAIT_FOR_SIPI`.
+pub fn has_wait_for_sipi() -> bool {
+    features().contains(CpuFeatures::WAIT_FOR_SIPI)
+}
+
+/// Returns the rate of the VMX-preemption timer: it counts down by 1 every time the bit of the TSC at this
+/// position changes.
+pub fn preemption_timer_rate() -> u32 {
+    (rdmsr(msr::IA32_VMX_MISC) & VMX_MISC_PREEMPTION_TIMER_RATE) as u32
+}
+
+/// Returns the type of the current core.
+///
+/// # Returns
+///
+/// The core type, or `None` if the processor is not hybrid.
+pub fn core_type() -> Option<CoreType> {
+    if !is_hybrid() || cpuid!(0x0).eax < CPUID_HYBRID_INFO {
+        return None;
+    }
+
+    // EAX[31:24] of leaf 0x1A is the core type.
+    match (cpuid!(CPUID_HYBRID_INFO).eax >> 24) as u8 {
+        0x20 => Some(CoreType::Efficiency),
+        0x40 => Some(CoreType::Performance),
+        other => Some(CoreType::Unknown(other)),
+    }
+}
+
+/// Returns the brand string of the processor, e.g. "Intel(R) Core(TM) i7-12700K", padded with NUL bytes.
+///
+/// # Returns
+///
+/// The 48 bytes of CPUID leaves 0x80000002 to 0x80000004, all NUL if the processor does not report them.
+pub fn brand_string() -> [u8; 48] {
+    let mut brand = [0u8; 48];
+    if cpuid!(CPUID_EXTENDED_MAX).eax < CPUID_BRAND_STRING_LAST {
+        return brand;
+    }
+
+    for (leaf, chunk) in
+        (CPUID_BRAND_STRING_FIRST..=CPUID_BRAND_STRING_LAST).zip(brand.chunks_exact_mut(16))
+    {
+        let result = cpuid!(leaf);
+        for (bytes, register) in chunk
+            .chunks_exact_mut(4)
+            .zip([result.eax, result.ebx, result.ecx, result.edx])
+        {
+            bytes.copy_from_slice(&register.to_le_bytes());
+        }
+    }
+
+    brand
+}
+
+/// Returns the display family and display model of the processor, which identify the MSRs it implements that
+/// CPUID does not enumerate.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Figure 3-6. Version Information
+/// Returned by CPUID in EAX.
+pub fn family_model() -> (u32, u32) {
+    let signature = cpuid!(0x1).eax;
+    let family = (signature >> 8) & 0xF;
+    let model = (signature >> 4) & 0xF;
+    let extended_model = (signature >> 16) & 0xF;
+    let extended_family = (signature >> 20) & 0xFF;
+
+    match family {
+        0xF => (family + extended_family, (extended_model << 4) | model),
+        0x6 => (family, (extended_model << 4) | model),
+        _ => (family, model),
+    }
+}
+
+/// Returns the cache, detecting the features on the first call.
+///
+/// Concurrent first calls detect the same values, so the race is harmless.
+fn cache() -> u64 {
+    let cache = CACHE.load(Ordering::Relaxed);
+    if cache & CACHE_VALID != 0 {
+        return cache;
+    }
+
+    let cache = CACHE_VALID
+        | (detect_vendor().map_or(0, |vendor| vendor as u64) << CACHE_VENDOR_SHIFT)
+        | u64::from(detect_features().bits());
+
+    CACHE.store(cache, Ordering::Relaxed);
+    cache
+}
+
+/// Detects the vendor from the signature of CPUID leaf 0.
+fn detect_vendor() -> Option<CpuVendor> {
+    let leaf = cpuid!(0x0);
+
+    let mut signature = [0u8; 12];
+    signature[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    signature[4..8].copy_from_slice(&leaf.edx.to_le_bytes());
+    signature[8..12].copy_from_slice(&leaf.ecx.to_le_bytes());
+
+    match &signature {
+        b"GenuineIntel" => Some(CpuVendor::Intel),
+        b"AuthenticAMD" => Some(CpuVendor::Amd),
+        _ => None,
+    }
+}
+
+/// Detects the features from the CPUID leaves and the VMX capability MSRs.
+fn detect_features() -> CpuFeatures {
+    let mut features = CpuFeatures::empty();
+
+    let leaf1 = cpuid!(0x1);
+    features.set(CpuFeatures::VMX, leaf1.ecx & CPUID_01_ECX_VMX != 0);
+    features.set(CpuFeatures::RDRAND, leaf1.ecx & CPUID_01_ECX_RDRAND != 0);
+    features.set(
+        CpuFeatures::HYPERVISOR,
+        leaf1.ecx & CPUID_01_ECX_HYPERVISOR != 0,
+    );
+    features.set(CpuFeatures::MTRR, leaf1.edx & CPUID_01_EDX_MTRR != 0);
+
+    if cpuid!(0x0).eax >= 0x7 {
+        let leaf7 = cpuid!(0x7, 0x0);
+        features.set(CpuFeatures::INVPCID, leaf7.ebx & CPUID_07_EBX_INVPCID != 0);
+        features.set(CpuFeatures::RDSEED, leaf7.ebx & CPUID_07_EBX_RDSEED != 0);
+        features.set(CpuFeatures::HYBRID, leaf7.edx & CPUID_07_EDX_HYBRID != 0);
+    }
+
+    if !features.contains(CpuFeatures::VMX) {
+        return features;
+    }
+
+    // The capability MSRs are those of the platform snapshot, see `PlatformInfo`. The allowed-1 settings do not
+    // depend on whether the TRUE capability MSRs were read.
+    let vmx = VmxCapabilities::capture();
+    features.set(CpuFeatures::TRUE_VMX_CONTROLS, vmx.has_true_controls());
+    features.set(
+        CpuFeatures::INS_OUTS_INFO,
+        vmx.basic & VMX_BASIC_INS_OUTS_INFO != 0,
+    );
+    features.set(
+        CpuFeatures::HLT_ACTIVITY_STATE,
+        vmx.misc & VMX_MISC_ACTIVITY_HLT != 0,
+    );
+    features.set(
+        CpuFeatures::SHUTDOWN_ACTIVITY_STATE,
+        vmx.misc & VMX_MISC_ACTIVITY_SHUTDOWN != 0,
+    );
+
+    // A timer restarting on every VM entry would never expire under frequent exits.
+    features.set(
+        CpuFeatures::PREEMPTION_TIMER,
+        vmx.pinbased_ctls & PINBASED_CTLS_PREEMPTION_TIMER != 0
+            && vmx.exit_ctls & EXIT_CTLS_SAVE_PREEMPTION_TIMER != 0,
+    );
+
+    // Virtual NMIs require NMI exiting.
+    let virtual_nmis = PINBASED_CTLS_NMI_EXITING | PINBASED_CTLS_VIRTUAL_NMIS;
+    features.set(
+        CpuFeatures::VIRTUAL_NMIS,
+        vmx.pinbased_ctls & virtual_nmis == virtual_nmis,
+    );
+
+    // The secondary controls, which hold both EPT and VPID, are zero without the "activate secondary controls"
+    // control, as is the capability MSR without EPT and VPID.
+    features.set(
+        CpuFeatures::EPT,
+        vmx.procbased_ctls2 & PROCBASED_CTLS2_ENABLE_EPT != 0,
+    );
+    features.set(
+        CpuFeatures::VPID,
+        vmx.procbased_ctls2 & PROCBASED_CTLS2_ENABLE_VPID != 0,
+    );
+    features.set(
+        CpuFeatures::INVVPID_INDIVIDUAL_ADDRESS,
+        vmx.ept_vpid_cap & EPT_VPID_CAP_INVVPID_INDIVIDUAL_ADDRESS != 0,
+    );
+
+    // The guest leaves the INIT state in real mode, without paging and with the IA32_EFER of the INIT state.
+    features.set(
+        CpuFeatures::WAIT_FOR_SIPI,
+        vmx.misc & VMX_MISC_ACTIVITY_WAIT_FOR_SIPI != 0
+            && vmx.procbased_ctls2 & PROCBASED_CTLS2_UNRESTRICTED_GUEST != 0
+            && vmx.entry_ctls & ENTRY_CTLS_LOAD_EFER != 0
+            && vmx.exit_ctls & EXIT_CTLS_SAVE_LOAD_EFER == EXIT_CTLS_SAVE_LOAD_EFER,
+    );
+
+    features.set(
+        CpuFeatures::TSC_SCALING,
+        vmx.procbased_ctls2 & PROCBASED_CTLS2_USE_TSC_SCALING != 0,
+    );
+
+    features
+}
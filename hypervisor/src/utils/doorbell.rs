@@ -0,0 +1,40 @@
+//! A doorbell telling consumers in guest context that events are waiting, without polling the event sources.
+//!
+//! VM-exit handlers cannot signal an OS event, so ringing the doorbell only bumps a counter, which is lock-free
+//! and allocation-free. A consumer remembers the count it last handled and only drains the event sources when
+//! the count moved, e.g. the inverted-call IOCTLs of the driver.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Rung by every `EventLog` when an event is raised.
+pub static EVENTS: Doorbell = Doorbell::new();
+
+/// A counter of the rings since the doorbell was created.
+pub struct Doorbell {
+    rings: AtomicU64,
+}
+
+impl Doorbell {
+    /// Creates a doorbell that was never rung.
+    pub const fn new() -> Self {
+        Self {
+            rings: AtomicU64::new(0),
+        }
+    }
+
+    /// Rings the doorbell. Can be called from VM-exit context.
+    pub fn ring(&self) {
+        self.rings.fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns the number of rings so far, to be compared with the count the consumer last handled.
+    pub fn rings(&self) -> u64 {
+        self.rings.load(Ordering::Acquire)
+    }
+}
+
+impl Default for Doorbell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
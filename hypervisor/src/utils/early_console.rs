@@ -0,0 +1,116 @@
+//! A raw debug console for the earliest initialization stages and for emergencies in VMX root operation.
+//!
+//! The logger allocates its ring buffer in `logger::init` and delivers records through the `log` crate, so
+//! nothing logged before it is initialized is ever seen, and it cannot be relied upon once the hypervisor
+//! is about to panic. The early console writes formatted text straight to an I/O port instead: the debug
+//! port 0xE9 understood by Bochs, QEMU (`-debugcon`) and some VMMs, or a 16550 serial port. It neither
+//! allocates nor takes locks, so it is usable at any time, at the cost of the output of concurrent
+//! processors possibly interleaving.
+//!
+//! The console writes to port 0xE9 until configured otherwise. Writes to the port are ignored by hardware
+//! that does not implement it.
+
+use {
+    crate::utils::{instructions::outb, logger::SerialPort, processor::current_processor_index},
+    core::{
+        fmt::{self, Write},
+        sync::atomic::{AtomicU16, AtomicU8, Ordering},
+    },
+};
+
+/// The debug port of Bochs and QEMU.
+pub const DEBUG_PORT: u16 = 0xE9;
+
+/// The port the console writes to, see `ConsolePort`.
+static PORT: AtomicU16 = AtomicU16::new(DEBUG_PORT);
+
+/// The kind of port the console writes to, see `ConsolePort`.
+static KIND: AtomicU8 = AtomicU8::new(ConsoleKind::DebugPort as u8);
+
+/// The kind of port, stored separately from the port number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum ConsoleKind {
+    Disabled = 0,
+    DebugPort = 1,
+    Serial = 2,
+}
+
+/// Where the early console writes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsolePort {
+    /// Nowhere.
+    Disabled,
+
+    /// A debug port that accepts one byte per `OUT`, such as `DEBUG_PORT`.
+    DebugPort(u16),
+
+    /// A 16550 compatible serial port, given by its base port, e.g. `logger::COM2`.
+    Serial(u16),
+}
+
+/// Selects where the early console writes to.
+///
+/// A serial port is initialized for 115200 baud, as the logger does, so the console and the logger can
+/// share the same port.
+///
+/// # Arguments
+///
+/// * `port` - The port to write to.
+pub fn init(port: ConsolePort) {
+    let (kind, number) = match port {
+        ConsolePort::Disabled => (ConsoleKind::Disabled, 0),
+        ConsolePort::DebugPort(number) => (ConsoleKind::DebugPort, number),
+        ConsolePort::Serial(number) => {
+            SerialPort(number).init();
+            (ConsoleKind::Serial, number)
+        }
+    };
+
+    PORT.store(number, Ordering::Relaxed);
+    KIND.store(kind as u8, Ordering::Release);
+}
+
+/// Writes formatted text to the console.
+///
+/// # Arguments
+///
+/// * `args` - The text, as built by `format_args!`.
+pub fn print(args: fmt::Arguments) {
+    let _ = Console.write_fmt(args);
+}
+
+/// Writes a formatted line to the console, prefixed with the index of the current processor.
+///
+/// Meant for the last words of the hypervisor before it panics or gives up, e.g. in VMX root operation.
+///
+/// # Arguments
+///
+/// * `args` - The message, as built by `format_args!`.
+pub fn emergency(args: fmt::Arguments) {
+    let _ = write!(
+        Console,
+        "[EMERG] [{}] {}\r\n",
+        current_processor_index(),
+        args
+    );
+}
+
+/// Writes raw bytes to the configured port.
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let port = PORT.load(Ordering::Relaxed);
+
+        match KIND.load(Ordering::Acquire) {
+            kind if kind == ConsoleKind::DebugPort as u8 => {
+                s.bytes().for_each(|byte| outb(port, byte));
+            }
+            kind if kind == ConsoleKind::Serial as u8 => SerialPort(port).write_bytes(s.as_bytes()),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
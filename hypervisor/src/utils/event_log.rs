@@ -0,0 +1,91 @@
+//! A bounded log of the most recent events, shared between processors.
+//!
+//! Events are raised in VM-exit context and drained at PASSIVE_LEVEL. Once the log is full, the oldest
+//! events are overwritten, while the total number of events raised keeps being counted. Every event rings
+//! `doorbell::EVENTS`, so consumers know when to drain.
+
+use {
+    crate::utils::{doorbell, sync::SpinLock},
+    core::sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The slots of the log, overwritten in FIFO order once full.
+struct Slots<T, const N: usize> {
+    events: [Option<T>; N],
+    next: usize,
+}
+
+/// Keeps the last `N` events until they are drained.
+pub struct EventLog<T: Copy, const N: usize> {
+    /// The events not drained yet.
+    slots: SpinLock<Slots<T, N>>,
+
+    /// The total number of events raised.
+    total: AtomicU64,
+}
+
+impl<T: Copy, const N: usize> EventLog<T, N> {
+    /// Creates an empty log.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the lock protecting the log, reported in lock diagnostics.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            slots: SpinLock::new(
+                name,
+                Slots {
+                    events: [None; N],
+                    next: 0,
+                },
+            ),
+            total: AtomicU64::new(0),
+        }
+    }
+
+    /// Appends an event, overwriting the oldest one if the log is full.
+    pub fn push(&self, event: T) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+
+        let mut slots = self.slots.lock();
+        let next = slots.next;
+
+        if let Some(slot) = slots.events.get_mut(next % N) {
+            *slot = Some(event);
+        }
+
+        slots.next = (next + 1) % N;
+        drop(slots);
+
+        doorbell::EVENTS.ring();
+    }
+
+    /// Hands the pending events to a consumer, oldest first, and removes them.
+    ///
+    /// # Arguments
+    ///
+    /// * `consumer` - Called for each event.
+    ///
+    /// # Returns
+    ///
+    /// The number of events drained.
+    pub fn drain(&self, mut consumer: impl FnMut(&T)) -> usize {
+        let mut slots = self.slots.lock();
+        let next = slots.next;
+        let mut drained = 0;
+
+        for i in 0..N {
+            if let Some(event) = slots.events.get_mut((next + i) % N).and_then(Option::take) {
+                consumer(&event);
+                drained += 1;
+            }
+        }
+
+        drained
+    }
+
+    /// Returns the total number of events raised, including the ones that were overwritten.
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
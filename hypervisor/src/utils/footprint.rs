@@ -0,0 +1,248 @@
+//! Accounting of the memory consumed by the hypervisor.
+//!
+//! Every long-lived structure allocated by the hypervisor (EPTs, host stacks, bitmaps, VMX regions,
+//! host page tables and descriptor tables) is charged against a category when it is created and
+//! released when it is dropped. Heap allocations served by the global `KernelAlloc` are tracked as well.
+//! An optional cap can be configured to refuse reservations that would grow the footprint past a limit,
+//! which is useful when deploying on memory-constrained endpoints.
+
+use {
+    crate::error::HypervisorError,
+    core::{
+        fmt,
+        sync::atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// The categories the hypervisor's memory usage is broken down into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryCategory {
+    /// Extended Page Tables.
+    Ept = 0,
+
+    /// Host stacks used while handling VM exits.
+    Stack = 1,
+
+    /// MSR and I/O bitmaps.
+    Bitmap = 2,
+
+    /// VMXON and VMCS regions.
+    VmxRegion = 3,
+
+    /// The host's page tables.
+    PageTables = 4,
+
+    /// Guest and host descriptor tables.
+    DescriptorTables = 5,
+
+    /// General purpose heap allocations.
+    Heap = 6,
+}
+
+/// The number of memory categories.
+const CATEGORY_COUNT: usize = 7;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// The bytes currently charged to each category, indexed by `MemoryCategory`.
+static USAGE: [AtomicU64; CATEGORY_COUNT] = [ZERO; CATEGORY_COUNT];
+
+/// The configured cap in bytes. A value of zero means the footprint is not capped.
+static CAP: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the maximum amount of memory the hypervisor is allowed to reserve.
+///
+/// # Arguments
+///
+/// * `cap` - The cap in bytes, or `None` to remove the cap.
+pub fn set_memory_cap(cap: Option<u64>) {
+    CAP.store(cap.unwrap_or(0), Ordering::SeqCst);
+}
+
+/// Returns the configured memory cap in bytes, if any.
+pub fn memory_cap() -> Option<u64> {
+    match CAP.load(Ordering::SeqCst) {
+        0 => None,
+        cap => Some(cap),
+    }
+}
+
+/// Returns the total amount of memory currently charged across all categories.
+fn total() -> u64 {
+    USAGE.iter().map(|usage| usage.load(Ordering::SeqCst)).sum()
+}
+
+/// Reserves `size` bytes in the given category, enforcing the configured cap.
+///
+/// # Arguments
+///
+/// * `category` - The category to charge.
+/// * `size` - The number of bytes to charge.
+///
+/// # Returns
+///
+/// `Ok(())` if the reservation fits within the cap, or `HypervisorError::MemoryCapExceeded` otherwise.
+pub fn reserve(category: MemoryCategory, size: u64) -> Result<(), HypervisorError> {
+    if let Some(cap) = memory_cap() {
+        if total().saturating_add(size) > cap {
+            log::error!(
+                "Reserving {:#x} bytes for {:?} would exceed the memory cap of {:#x} bytes",
+                size,
+                category,
+                cap
+            );
+            return Err(HypervisorError::MemoryCapExceeded);
+        }
+    }
+
+    charge(category, size);
+
+    Ok(())
+}
+
+/// Charges `size` bytes to the given category without enforcing the cap.
+///
+/// This is used for allocations that cannot fail gracefully, such as the global allocator.
+///
+/// # Arguments
+///
+/// * `category` - The category to charge.
+/// * `size` - The number of bytes to charge.
+pub fn charge(category: MemoryCategory, size: u64) {
+    USAGE[category as usize].fetch_add(size, Ordering::SeqCst);
+}
+
+/// Releases `size` bytes previously charged to the given category.
+///
+/// # Arguments
+///
+/// * `category` - The category to release from.
+/// * `size` - The number of bytes to release.
+pub fn release(category: MemoryCategory, size: u64) {
+    let _ = USAGE[category as usize].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |usage| {
+        Some(usage.saturating_sub(size))
+    });
+}
+
+/// Reserves a set of charges at once, rolling back the already reserved ones on failure.
+///
+/// # Arguments
+///
+/// * `charges` - The categories and sizes to reserve.
+///
+/// # Returns
+///
+/// The reservation, released again when dropped unless it is kept, or `HypervisorError::MemoryCapExceeded` if
+/// the charges do not fit within the cap.
+pub fn reserve_all<const N: usize>(
+    charges: [(MemoryCategory, u64); N],
+) -> Result<Reservation<N>, HypervisorError> {
+    for (index, (category, size)) in charges.iter().enumerate() {
+        if let Err(err) = reserve(*category, *size) {
+            release_all(&charges[..index]);
+            return Err(err);
+        }
+    }
+
+    Ok(Reservation { charges })
+}
+
+/// Releases a set of charges previously reserved with `reserve_all`.
+///
+/// # Arguments
+///
+/// * `charges` - The categories and sizes to release.
+pub fn release_all(charges: &[(MemoryCategory, u64)]) {
+    for (category, size) in charges.iter() {
+        release(*category, *size);
+    }
+}
+
+/// Charges reserved with `reserve_all` for a structure being built.
+///
+/// The charges are released when the reservation is dropped, e.g. when an allocation fails before the
+/// structure is built. Once it is, the reservation is kept and the structure releases the charges itself.
+#[must_use]
+pub struct Reservation<const N: usize> {
+    /// The categories and sizes reserved.
+    charges: [(MemoryCategory, u64); N],
+}
+
+impl<const N: usize> Reservation<N> {
+    /// Keeps the charges, which the structure they were reserved for releases when it is dropped.
+    pub fn keep(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl<const N: usize> Drop for Reservation<N> {
+    fn drop(&mut self) {
+        release_all(&self.charges);
+    }
+}
+
+/// A snapshot of the memory consumed by the hypervisor, per category.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryFootprint {
+    pub ept: u64,
+    pub stacks: u64,
+    pub bitmaps: u64,
+    pub vmx_regions: u64,
+    pub page_tables: u64,
+    pub descriptor_tables: u64,
+    pub heap: u64,
+    pub cap: Option<u64>,
+}
+
+impl MemoryFootprint {
+    /// Captures the current memory footprint.
+    pub fn current() -> Self {
+        let load = |category: MemoryCategory| USAGE[category as usize].load(Ordering::SeqCst);
+
+        Self {
+            ept: load(MemoryCategory::Ept),
+            stacks: load(MemoryCategory::Stack),
+            bitmaps: load(MemoryCategory::Bitmap),
+            vmx_regions: load(MemoryCategory::VmxRegion),
+            page_tables: load(MemoryCategory::PageTables),
+            descriptor_tables: load(MemoryCategory::DescriptorTables),
+            heap: load(MemoryCategory::Heap),
+            cap: memory_cap(),
+        }
+    }
+
+    /// Returns the total amount of memory in bytes.
+    pub fn total(&self) -> u64 {
+        self.ept
+            + self.stacks
+            + self.bitmaps
+            + self.vmx_regions
+            + self.page_tables
+            + self.descriptor_tables
+            + self.heap
+    }
+}
+
+impl fmt::Display for MemoryFootprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "EPT: {:#x}, Stacks: {:#x}, Bitmaps: {:#x}, VMX Regions: {:#x}, Page Tables: {:#x}, Descriptor Tables: {:#x}, Heap: {:#x}, Total: {:#x}",
+            self.ept,
+            self.stacks,
+            self.bitmaps,
+            self.vmx_regions,
+            self.page_tables,
+            self.descriptor_tables,
+            self.heap,
+            self.total()
+        )?;
+
+        if let Some(cap) = self.cap {
+            write!(f, ", Cap: {:#x}", cap)?;
+        }
+
+        Ok(())
+    }
+}
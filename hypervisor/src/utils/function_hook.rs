@@ -11,9 +11,9 @@ use {
     },
     wdk_sys::{
         ntddk::{IoAllocateMdl, IoFreeMdl, MmProbeAndLockPages, MmUnlockPages},
-        PMDL,
         _LOCK_OPERATION::IoReadAccess,
         _MODE::KernelMode,
+        PMDL,
     },
     x86::bits64::paging::BASE_PAGE_SIZE,
 };
@@ -301,6 +301,41 @@ impl FunctionHook {
         Ok(unsafe { memory.assume_init() })
     }
 
+    /// Moves the hook to a different address, e.g. onto a copied page shared with other hooks.
+    ///
+    /// ## Parameters
+    /// - `hook_address`: The new address where the hook will be placed.
+    ///
+    /// ## Safety
+    /// The hook must not be enabled yet, as the shellcode already written to the old address is left behind.
+    pub fn relocate(&mut self, hook_address: u64) {
+        log::trace!(
+            "Relocating hook from {:#x} to {:#x}",
+            self.hook_address,
+            hook_address
+        );
+        self.hook_address = hook_address;
+    }
+
+    /// Provides a constant function to retrieve the address where the hook is installed.
+    ///
+    /// ## Returns
+    /// Returns the hook address as a 64-bit unsigned integer.
+    pub const fn hook_address(&self) -> u64 {
+        self.hook_address
+    }
+
+    /// Provides a constant function to retrieve the length of the shellcode written by `enable`.
+    ///
+    /// ## Returns
+    /// Returns the number of bytes overwritten at the hook address.
+    pub const fn shellcode_len(&self) -> usize {
+        match self.hook_type {
+            HookType::Jmp => JMP_SHELLCODE_LEN,
+            HookType::Breakpoint => BP_SHELLCODE_LEN,
+        }
+    }
+
     /// Provides a constant function to retrieve the address of the trampoline.
     ///
     /// ## Returns
@@ -26,6 +26,39 @@ pub fn rdtsc() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() }
 }
 
+/// Returns a random number from the processor's random number generator, or `None` if it has not
+/// delivered one after a few retries. The caller must ensure the processor supports RDRAND.
+pub fn rdrand() -> Option<u64> {
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {0}", "setc {1}", out(reg) value, out(reg_byte) ok, options(nostack, nomem));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Returns a random number from the processor's entropy source, or `None` if it has not delivered one after a
+/// few retries. The caller must ensure the processor supports RDSEED.
+pub fn rdseed() -> Option<u64> {
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {0}", "setc {1}", out(reg) value, out(reg_byte) ok, options(nostack, nomem));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
 /// Reads an MSR.
 pub fn rdmsr(msr: u32) -> u64 {
     unsafe { x86::msr::rdmsr(msr) }
@@ -46,11 +79,21 @@ pub fn cr0_write(val: Cr0) {
     unsafe { x86::controlregs::cr0_write(val) };
 }
 
+/// Writes a value to the CR2 register.
+pub fn cr2_write(val: u64) {
+    unsafe { x86::controlregs::cr2_write(val) };
+}
+
 /// Reads the CR3 register.
 pub fn cr3() -> u64 {
     unsafe { x86::controlregs::cr3() }
 }
 
+/// Writes a value to the CR3 register.
+pub fn cr3_write(val: u64) {
+    unsafe { x86::controlregs::cr3_write(val) };
+}
+
 /// Reads the CR4 register.
 pub fn cr4() -> Cr4 {
     unsafe { x86::controlregs::cr4() }
@@ -61,11 +104,33 @@ pub fn cr4_write(val: Cr4) {
     unsafe { x86::controlregs::cr4_write(val) };
 }
 
+/// Reads the CR8 register, the task priority of the processor.
+pub fn cr8() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, cr8", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Writes a value to the CR8 register.
+pub fn cr8_write(val: u64) {
+    unsafe { asm!("mov cr8, {}", in(reg) val, options(nomem, nostack, preserves_flags)) };
+}
+
 /// Disables maskable interrupts.
 pub fn cli() {
     unsafe { x86::irq::disable() };
 }
 
+/// Enables maskable interrupts.
+pub fn sti() {
+    unsafe { x86::irq::enable() };
+}
+
+/// Returns whether maskable interrupts are enabled (RFLAGS.IF).
+pub fn interrupts_enabled() -> bool {
+    x86::bits64::rflags::read().contains(x86::bits64::rflags::RFlags::FLAGS_IF)
+}
+
 /// Halts execution of the processor.
 pub fn hlt() {
     unsafe { x86::halt() };
@@ -95,6 +160,78 @@ pub fn sgdt() -> DescriptorTablePointer<u64> {
     gdtr
 }
 
+/// Loads the GDTR.
+pub fn lgdt(gdtr: &DescriptorTablePointer<u64>) {
+    unsafe { x86::dtables::lgdt(gdtr) };
+}
+
+/// Loads the IDTR.
+pub fn lidt(idtr: &DescriptorTablePointer<u64>) {
+    unsafe { x86::dtables::lidt(idtr) };
+}
+
+/// Reads the DR6 register.
+pub fn dr6() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mov {}, dr6", out(reg) val, options(nomem, nostack, preserves_flags))
+    };
+    val
+}
+
+/// Writes a value to the DR6 register.
+pub fn dr6_write(val: u64) {
+    unsafe { core::arch::asm!("mov dr6, {}", in(reg) val, options(nostack, preserves_flags)) };
+}
+
+/// Writes a value to the DR7 register.
+pub fn dr7_write(val: u64) {
+    unsafe { core::arch::asm!("mov dr7, {}", in(reg) val, options(nostack, preserves_flags)) };
+}
+
+/// Reads one of the debug address registers, DR0 to DR3.
+///
+/// # Arguments
+///
+/// * `index` - The index of the register, 0 to 3. Other values read DR3.
+pub fn dr_address(index: usize) -> u64 {
+    let val: u64;
+    unsafe {
+        match index {
+            0 => {
+                core::arch::asm!("mov {}, dr0", out(reg) val, options(nomem, nostack, preserves_flags))
+            }
+            1 => {
+                core::arch::asm!("mov {}, dr1", out(reg) val, options(nomem, nostack, preserves_flags))
+            }
+            2 => {
+                core::arch::asm!("mov {}, dr2", out(reg) val, options(nomem, nostack, preserves_flags))
+            }
+            _ => {
+                core::arch::asm!("mov {}, dr3", out(reg) val, options(nomem, nostack, preserves_flags))
+            }
+        }
+    };
+    val
+}
+
+/// Writes a value to one of the debug address registers, DR0 to DR3.
+///
+/// # Arguments
+///
+/// * `index` - The index of the register, 0 to 3. Other values write DR3.
+/// * `val` - The linear address to load.
+pub fn dr_address_write(index: usize, val: u64) {
+    unsafe {
+        match index {
+            0 => core::arch::asm!("mov dr0, {}", in(reg) val, options(nostack, preserves_flags)),
+            1 => core::arch::asm!("mov dr1, {}", in(reg) val, options(nostack, preserves_flags)),
+            2 => core::arch::asm!("mov dr2, {}", in(reg) val, options(nostack, preserves_flags)),
+            _ => core::arch::asm!("mov dr3, {}", in(reg) val, options(nostack, preserves_flags)),
+        }
+    };
+}
+
 /// Read the RIP register (instruction pointer).
 #[inline(always)]
 pub fn rip() -> u64 {
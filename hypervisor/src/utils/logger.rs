@@ -0,0 +1,255 @@
+//! A logger that funnels the log records of all processors through a shared ring buffer.
+//!
+//! Logging from VM-exit context must neither block nor allocate. Producers only format their
+//! record into the ring of their processor (see `ring`). Writing to the serial port waits for the
+//! transmitter on every byte, so records are only written outside of VM-exit context: whichever
+//! processor logging from the guest first finds the consumer free writes the pending records, those
+//! produced in VM-exit context included, to the serial port in the order they were produced. The
+//! records of VM exits thus wait for the next record logged from the guest, or for `Log::flush`.
+//! The records are written either as text or in the binary format described in `telemetry`.
+
+use {
+    crate::{
+        error::HypervisorError,
+        utils::{
+            instructions::{inb, outb},
+            processor::{current_processor_index, processor_count},
+            ring::{MpscRing, Record},
+            telemetry::{self, MAX_RECORD_LEN},
+            timestamp::in_root_mode,
+        },
+    },
+    alloc::boxed::Box,
+    core::{
+        fmt::{self, Write},
+        ptr,
+        sync::atomic::{AtomicPtr, AtomicU16, AtomicU64, AtomicU8, Ordering},
+    },
+    log::{Level, LevelFilter, Log, Metadata},
+};
+
+/// The default base port, COM2.
+pub const COM2: u16 = 0x2f8;
+
+/// The logger installed by `init`.
+static LOGGER: RingLogger = RingLogger {
+    ring: AtomicPtr::new(ptr::null_mut()),
+    port: AtomicU16::new(COM2),
+    format: AtomicU8::new(LogFormat::Text as u8),
+    reported_dropped: AtomicU64::new(0),
+};
+
+/// The format the records are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum LogFormat {
+    /// One human readable line per record.
+    #[default]
+    Text = 0,
+
+    /// The versioned binary format described in `telemetry`, for offline parsing.
+    Binary = 1,
+}
+
+/// Logs the records of all processors through a shared ring buffer to a serial port.
+pub struct RingLogger {
+    /// The ring buffer, leaked on initialization so it stays valid for the lifetime of the driver.
+    ring: AtomicPtr<MpscRing>,
+
+    /// The base port of the serial port the records are written to.
+    port: AtomicU16,
+
+    /// The `LogFormat` the records are written in.
+    format: AtomicU8,
+
+    /// The number of dropped records that were already reported.
+    reported_dropped: AtomicU64,
+}
+
+/// Installs the ring buffer logger.
+///
+/// # Arguments
+///
+/// * `port` - The base port of the serial port to write to, e.g. `COM2`.
+/// * `level` - The maximum level to log.
+///
+/// # Returns
+///
+/// A `Result` indicating whether the logger was installed.
+pub fn init(port: u16, level: LevelFilter) -> Result<(), HypervisorError> {
+    let ring = Box::into_raw(Box::new(MpscRing::new(processor_count())?));
+
+    if LOGGER
+        .ring
+        .compare_exchange(ptr::null_mut(), ring, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        drop(unsafe { Box::from_raw(ring) });
+        return Err(HypervisorError::LoggerAlreadyInitialized);
+    }
+
+    LOGGER.port.store(port, Ordering::Relaxed);
+    SerialPort(port).init();
+
+    log::set_logger(&LOGGER).map_err(|_| HypervisorError::LoggerAlreadyInitialized)?;
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+/// Selects the format the records are written in.
+///
+/// Switching to `LogFormat::Binary` writes the telemetry file header, so a capture of the serial port
+/// started before the switch can be parsed from that point on.
+///
+/// # Arguments
+///
+/// * `format` - The format to write the records in.
+pub fn set_format(format: LogFormat) {
+    let previous = LOGGER.format.swap(format as u8, Ordering::AcqRel);
+
+    if format == LogFormat::Binary && previous != LogFormat::Binary as u8 {
+        let serial = SerialPort(LOGGER.port.load(Ordering::Relaxed));
+        serial.write_bytes(&telemetry::encode_file_header());
+    }
+}
+
+impl RingLogger {
+    /// Returns the ring buffer, if the logger is initialized.
+    fn ring(&self) -> Option<&MpscRing> {
+        unsafe { self.ring.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Writes the pending records to the serial port, unless another processor already does or
+    /// the current one handles a VM exit.
+    fn drain(&self) {
+        if in_root_mode() {
+            return;
+        }
+
+        let Some(ring) = self.ring() else {
+            return;
+        };
+
+        let mut serial = SerialPort(self.port.load(Ordering::Relaxed));
+        let binary = self.format.load(Ordering::Acquire) == LogFormat::Binary as u8;
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+
+        ring.drain(|record| {
+            if binary {
+                serial.write_bytes(telemetry::encode_record(record, &mut buffer));
+            } else {
+                let _ = write_record(&mut serial, record);
+            }
+        });
+
+        let dropped = ring.dropped();
+        let reported = self.reported_dropped.swap(dropped, Ordering::Relaxed);
+
+        if dropped > reported {
+            if binary {
+                let cpu = current_processor_index();
+                serial.write_bytes(telemetry::encode_dropped(
+                    dropped - reported,
+                    cpu,
+                    &mut buffer,
+                ));
+            } else {
+                let _ = write!(
+                    serial,
+                    "[WARN ] {} log records dropped\r\n",
+                    dropped - reported
+                );
+            }
+        }
+    }
+}
+
+impl Log for RingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let Some(ring) = self.ring() else {
+            return;
+        };
+
+        ring.push(
+            current_processor_index(),
+            record.level() as u8,
+            *record.args(),
+        );
+
+        self.drain();
+    }
+
+    fn flush(&self) {
+        self.drain();
+    }
+}
+
+/// Formats a record for the serial port.
+fn write_record(serial: &mut SerialPort, record: &Record) -> fmt::Result {
+    let level = match record.level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    };
+
+    write!(
+        serial,
+        "[{:<5}] [{}] [host {:#x} guest {:#x}] {}\r\n",
+        level,
+        record.cpu,
+        record.timestamp.host_tsc,
+        record.timestamp.guest_tsc,
+        record.message_str()
+    )
+}
+
+/// A 16550 compatible serial port.
+pub struct SerialPort(pub u16);
+
+impl SerialPort {
+    /// Line status register bit indicating that the transmitter holding register is empty.
+    const LINE_STATUS_THRE: u8 = 1 << 5;
+
+    /// Initializes the port for 115200 baud, 8 data bits, no parity and one stop bit.
+    pub fn init(&self) {
+        outb(self.0 + 1, 0x00); // Disable interrupts.
+        outb(self.0 + 3, 0x80); // Enable DLAB to set the divisor.
+        outb(self.0, 0x01); // Divisor low byte, 115200 baud.
+        outb(self.0 + 1, 0x00); // Divisor high byte.
+        outb(self.0 + 3, 0x03); // 8 data bits, no parity, one stop bit.
+        outb(self.0 + 2, 0xC7); // Enable and clear the FIFOs.
+        outb(self.0 + 4, 0x03); // Assert DTR and RTS.
+    }
+
+    /// Writes a byte once the transmitter is ready.
+    pub fn write_byte(&self, byte: u8) {
+        while inb(self.0 + 5) & Self::LINE_STATUS_THRE == 0 {
+            core::hint::spin_loop();
+        }
+
+        outb(self.0, byte);
+    }
+
+    /// Writes raw bytes.
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        bytes.iter().for_each(|&byte| self.write_byte(byte));
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.write_byte(byte));
+        Ok(())
+    }
+}
@@ -1,8 +1,26 @@
 pub mod addresses;
 pub mod alloc;
+pub mod cancellation;
 pub mod capture;
+pub mod chacha;
+pub mod cpu;
+pub mod doorbell;
+pub mod early_console;
+pub mod event_log;
+pub mod footprint;
 pub mod function_hook;
 pub mod instructions;
+pub mod logger;
 pub mod nt;
+#[cfg(feature = "introspection")]
+pub mod ntfs;
 pub mod processor;
+pub mod rcu;
+pub mod ring;
+pub mod sha256;
+pub mod siphash;
 pub mod ssdt;
+pub mod sync;
+pub mod telemetry;
+pub mod text;
+pub mod timestamp;
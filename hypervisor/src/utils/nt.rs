@@ -5,6 +5,7 @@
 use {
     crate::error::HypervisorError,
     alloc::vec::Vec,
+    core::sync::atomic::{AtomicU64, Ordering},
     wdk_sys::{
         ntddk::{
             KeLowerIrql, KeStackAttachProcess, KeUnstackDetachProcess, MmGetSystemRoutineAddress,
@@ -74,7 +75,7 @@ pub fn lower_irql_to_old_level(old_irql: KIRQL) {
 ///
 /// This is typically used to store the page table root physical address
 /// of the system process for use in virtual-to-physical address translation.
-pub static mut NTOSKRNL_CR3: u64 = 0;
+pub static NTOSKRNL_CR3: AtomicU64 = AtomicU64::new(0);
 
 /// Updates the `NTOSKRNL_CR3` static with the CR3 of the system process.
 ///
@@ -94,11 +95,9 @@ pub fn update_ntoskrnl_cr3() {
 
     // Update the NTOSKRNL_CR3 static with the current CR3 value.
     // Accessing CR3 is an unsafe operation as it involves reading a control register.
-    unsafe {
-        NTOSKRNL_CR3 = x86::controlregs::cr3();
-    }
+    NTOSKRNL_CR3.store(unsafe { x86::controlregs::cr3() }, Ordering::Release);
 
-    log::trace!("NTOSKRNL_CR3: {:#x}", unsafe { NTOSKRNL_CR3 });
+    log::trace!("NTOSKRNL_CR3: {:#x}", NTOSKRNL_CR3.load(Ordering::Acquire));
 
     // Detach from the system process's stack safely.
     // `KeUnstackDetachProcess` is unsafe as it restores the previous thread execution context.
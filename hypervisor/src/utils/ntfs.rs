@@ -0,0 +1,986 @@
+//! Read-only access to the files of the guest by parsing NTFS below the file system.
+//!
+//! Artifacts such as prefetch files, registry hives or event logs are collected without an agent in the guest
+//! and without asking the file system for them: the volume is read as raw sectors from the device at the bottom
+//! of its stack, see `GuestVolume`, and the file is located by walking the NTFS structures from the root
+//! directory. Files opened exclusively, or hidden by a file system or volume filter, are therefore read as they
+//! are on disk. `read_guest_file` keeps the volumes it parsed, so only the first read of a volume parses its boot
+//! sector and MFT.
+//!
+//! The parser follows the path from the root directory (MFT record 5) through the `$I30` indexes of the
+//! directories, then reads the unnamed `$DATA` attribute of the file, resident or described by its runlist.
+//! Attributes spread over several MFT records by an `$ATTRIBUTE_LIST` are supported. Every node of an index is
+//! scanned instead of descending the B+ tree, so the lookup does not depend on the upcase table of the volume.
+//! Compressed and encrypted files are not supported, and sparse runs read as zeros.
+//!
+//! What is read is the state on disk: changes still held in the cache manager of the guest are not seen, and a
+//! file written during the read may be inconsistent. Reading the volume requires PASSIVE_LEVEL, so this only
+//! runs in VMX non-root operation.
+//!
+//! Reference: The on-disk layout of NTFS as documented by the Linux-NTFS project (Documentation/filesystems/ntfs.rst
+//! of the Linux kernel) and Microsoft Windows Driver Kit: IoGetDeviceObjectPointer, IoGetDeviceAttachmentBaseRef
+//! and IoBuildSynchronousFsdRequest.
+
+#![deny(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)]
+
+use {
+    crate::error::HypervisorError,
+    alloc::{boxed::Box, vec, vec::Vec},
+    core::sync::atomic::{AtomicPtr, Ordering},
+    wdk_sys::{
+        IO_STATUS_BLOCK, IRP_MJ_READ, KEVENT, LARGE_INTEGER, NTSTATUS, NT_SUCCESS, PDEVICE_OBJECT,
+        PFILE_OBJECT, PIO_STATUS_BLOCK, PIRP, PLARGE_INTEGER, PVOID, STATUS_PENDING,
+        UNICODE_STRING,
+    },
+};
+
+/// The OEM ID of an NTFS boot sector.
+const NTFS_OEM_ID: &[u8; 8] = b"NTFS    ";
+
+/// The magic of an MFT record.
+const FILE_MAGIC: &[u8; 4] = b"FILE";
+
+/// The magic of an index record.
+const INDX_MAGIC: &[u8; 4] = b"INDX";
+
+/// The length of the boot sector read.
+const BOOT_SECTOR_LEN: usize = 512;
+
+/// The stride of the update sequence array, whatever the sector size.
+const UPDATE_SEQUENCE_STRIDE: usize = 512;
+
+/// The MFT record of the root directory.
+const ROOT_DIRECTORY_RECORD: u64 = 5;
+
+/// The bits of a file reference holding the MFT record number.
+const FILE_REFERENCE_MASK: u64 = 0x0000_FFFF_FFFF_FFFF;
+
+/// The `in use` flag of an MFT record.
+const RECORD_IN_USE: u16 = 0x0001;
+
+/// The `directory` flag of an MFT record.
+const RECORD_DIRECTORY: u16 = 0x0002;
+
+/// The attribute types read by the parser.
+const ATTRIBUTE_LIST: u32 = 0x20;
+const ATTRIBUTE_DATA: u32 = 0x80;
+const ATTRIBUTE_INDEX_ROOT: u32 = 0x90;
+const ATTRIBUTE_INDEX_ALLOCATION: u32 = 0xA0;
+const ATTRIBUTE_END: u32 = 0xFFFF_FFFF;
+
+/// The flags of a compressed or encrypted attribute.
+const ATTRIBUTE_COMPRESSED: u16 = 0x0001;
+const ATTRIBUTE_ENCRYPTED: u16 = 0x4000;
+
+/// The flag of the last entry of an index node.
+const INDEX_ENTRY_LAST: u16 = 0x0002;
+
+/// The maximum number of MFT records an attribute list can spread the data over.
+const MAX_ATTRIBUTE_RECORDS: usize = 64;
+
+/// The maximum size of the non-resident attribute values read whole, i.e. attribute lists and index roots.
+const MAX_ATTRIBUTE_VALUE: u64 = 0x10_0000;
+
+/// The alignment of the reads from the volume device, a multiple of every sector size.
+const VOLUME_READ_ALIGNMENT: u64 = 0x1000;
+
+/// Reads the raw bytes of a volume.
+pub trait VolumeReader {
+    /// Reads bytes at an offset of the volume.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset in bytes from the start of the volume, not necessarily sector aligned.
+    /// * `buffer` - Receives the bytes.
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), HypervisorError>;
+}
+
+/// A run of clusters of a non-resident attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Run {
+    /// The first virtual cluster of the run.
+    vcn: u64,
+
+    /// The first logical cluster of the run on the volume, or `None` for a sparse run.
+    lcn: Option<u64>,
+
+    /// The number of clusters.
+    clusters: u64,
+}
+
+/// Where the data of a file is.
+#[derive(Debug, Clone)]
+enum FileData {
+    /// In the MFT record.
+    Resident(Vec<u8>),
+
+    /// In clusters of the volume.
+    NonResident(Vec<Run>),
+}
+
+/// A file opened on an NTFS volume.
+#[derive(Debug, Clone)]
+pub struct NtfsFile {
+    /// The MFT record number of the file.
+    pub record: u64,
+
+    /// The size of the file in bytes.
+    pub size: u64,
+
+    /// Where the data is.
+    data: FileData,
+}
+
+/// An NTFS volume of the guest.
+pub struct NtfsVolume<R: VolumeReader> {
+    /// Reads the volume.
+    reader: R,
+
+    /// The size of a cluster in bytes.
+    cluster_size: u64,
+
+    /// The size of an MFT record in bytes.
+    record_size: u64,
+
+    /// The runs of the MFT.
+    mft_runs: Vec<Run>,
+}
+
+impl<R: VolumeReader> NtfsVolume<R> {
+    /// Mounts a volume read-only.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - Reads the volume.
+    ///
+    /// # Returns
+    ///
+    /// The volume, or `HypervisorError::NtfsCorrupted` if it is not a valid NTFS volume.
+    pub fn new(mut reader: R) -> Result<Self, HypervisorError> {
+        let mut boot = [0u8; BOOT_SECTOR_LEN];
+        reader.read_at(0, &mut boot)?;
+
+        if slice(&boot, 3, NTFS_OEM_ID.len())? != NTFS_OEM_ID {
+            log::error!("Not an NTFS volume");
+            return Err(HypervisorError::NtfsCorrupted);
+        }
+
+        let bytes_per_sector = u64::from(u16_at(&boot, 0x0B)?);
+        let sectors_per_cluster = match *boot.get(0x0D).ok_or(HypervisorError::NtfsCorrupted)? {
+            // Clusters above 64KB store the shift, negated.
+            value if value > 0x80 => 1u64 << (256 - u32::from(value)).min(31),
+            value => u64::from(value),
+        };
+        let cluster_size = bytes_per_sector * sectors_per_cluster;
+
+        let clusters_per_record = *boot.get(0x40).ok_or(HypervisorError::NtfsCorrupted)? as i8;
+        let record_size = match clusters_per_record {
+            n if n > 0 => n as u64 * cluster_size,
+            n => 1u64 << u32::from(n.unsigned_abs()).min(31),
+        };
+
+        if !bytes_per_sector.is_power_of_two()
+            || cluster_size == 0
+            || !(UPDATE_SEQUENCE_STRIDE as u64..=0x1_0000).contains(&record_size)
+        {
+            return Err(HypervisorError::NtfsCorrupted);
+        }
+
+        let mft_lcn = u64_at(&boot, 0x30)?;
+
+        let mut volume = Self {
+            reader,
+            cluster_size,
+            record_size,
+            // Until the runs of the MFT are known, its first record is read where the boot sector says.
+            mft_runs: vec![Run {
+                vcn: 0,
+                lcn: Some(mft_lcn),
+                clusters: record_size.div_ceil(cluster_size),
+            }],
+        };
+
+        // The first extent of the MFT locates the extension records of a fragmented MFT.
+        let mft = volume.read_record(0)?;
+        if let Some(extent) = unnamed(attributes(&mft, ATTRIBUTE_DATA)?).next() {
+            volume.mft_runs = decode_runs(extent)?;
+        }
+
+        let FileData::NonResident(runs) = volume.data(0, &mft)?.data else {
+            return Err(HypervisorError::NtfsCorrupted);
+        };
+        volume.mft_runs = runs;
+
+        log::debug!(
+            "NTFS volume: {} byte clusters, {} byte records, MFT in {} runs",
+            cluster_size,
+            record_size,
+            volume.mft_runs.len()
+        );
+
+        Ok(volume)
+    }
+
+    /// Opens a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file from the root of the volume, e.g. `\Windows\Prefetch\CMD.EXE-0BD30981.pf`.
+    ///   Components are separated by backslashes or slashes and compared case-insensitively.
+    ///
+    /// # Returns
+    ///
+    /// The file, or `HypervisorError::NtfsFileNotFound` if the path does not name a file.
+    pub fn open(&mut self, path: &str) -> Result<NtfsFile, HypervisorError> {
+        let mut number = ROOT_DIRECTORY_RECORD;
+
+        for component in path.split(['\\', '/']).filter(|c| !c.is_empty()) {
+            let directory = self.read_record(number)?;
+            if u16_at(&directory, 0x16)? & RECORD_DIRECTORY == 0 {
+                return Err(HypervisorError::NtfsFileNotFound);
+            }
+
+            number = self
+                .lookup(&directory, component)?
+                .ok_or(HypervisorError::NtfsFileNotFound)?;
+        }
+
+        let record = self.read_record(number)?;
+        if u16_at(&record, 0x16)? & RECORD_DIRECTORY != 0 {
+            return Err(HypervisorError::NtfsFileNotFound);
+        }
+
+        self.data(number, &record)
+    }
+
+    /// Reads a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The file, opened with `NtfsVolume::open`.
+    /// * `offset` - The offset in the file of the first byte read.
+    /// * `buffer` - Receives the bytes.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes read, less than the buffer at the end of the file.
+    pub fn read(
+        &mut self,
+        file: &NtfsFile,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, HypervisorError> {
+        let len = file.size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let buffer = buffer.get_mut(..len).unwrap_or_default();
+        if len == 0 {
+            return Ok(0);
+        }
+
+        match &file.data {
+            FileData::Resident(data) => {
+                let start = offset as usize;
+                buffer.copy_from_slice(slice(data, start, len)?);
+            }
+            FileData::NonResident(runs) => self.read_runs(runs, offset, buffer)?,
+        }
+
+        Ok(len)
+    }
+
+    /// Reads an MFT record and applies its fixups.
+    fn read_record(&mut self, number: u64) -> Result<Vec<u8>, HypervisorError> {
+        let offset = number
+            .checked_mul(self.record_size)
+            .ok_or(HypervisorError::NtfsCorrupted)?;
+
+        let mut record = vec![0u8; self.record_size as usize];
+        let runs = core::mem::take(&mut self.mft_runs);
+        let result = self.read_runs(&runs, offset, &mut record);
+        self.mft_runs = runs;
+        result?;
+
+        if slice(&record, 0, FILE_MAGIC.len())? != FILE_MAGIC {
+            log::error!("MFT record {} has no FILE magic", number);
+            return Err(HypervisorError::NtfsCorrupted);
+        }
+        apply_fixups(&mut record)?;
+
+        if u16_at(&record, 0x16)? & RECORD_IN_USE == 0 {
+            return Err(HypervisorError::NtfsFileNotFound);
+        }
+
+        Ok(record)
+    }
+
+    /// Reads bytes of a non-resident attribute.
+    fn read_runs(
+        &mut self,
+        runs: &[Run],
+        mut offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<(), HypervisorError> {
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let vcn = offset / self.cluster_size;
+            let run = runs
+                .iter()
+                .find(|run| (run.vcn..run.vcn.saturating_add(run.clusters)).contains(&vcn))
+                .ok_or(HypervisorError::NtfsCorrupted)?;
+
+            let in_run = offset - run.vcn * self.cluster_size;
+            let len = run
+                .clusters
+                .saturating_mul(self.cluster_size)
+                .saturating_sub(in_run)
+                .min((buffer.len() - done) as u64) as usize;
+            let chunk = buffer
+                .get_mut(done..done + len)
+                .ok_or(HypervisorError::NtfsCorrupted)?;
+
+            match run.lcn {
+                Some(lcn) => {
+                    let position = lcn
+                        .checked_mul(self.cluster_size)
+                        .and_then(|start| start.checked_add(in_run))
+                        .ok_or(HypervisorError::NtfsCorrupted)?;
+                    self.reader.read_at(position, chunk)?
+                }
+                None => chunk.fill(0),
+            }
+
+            done += len;
+            offset += len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the value of an attribute, resident or not.
+    fn attribute_value(&mut self, attribute: &[u8]) -> Result<Vec<u8>, HypervisorError> {
+        if *attribute.get(8).ok_or(HypervisorError::NtfsCorrupted)? == 0 {
+            let len = u32_at(attribute, 0x10)? as usize;
+            let start = usize::from(u16_at(attribute, 0x14)?);
+            return Ok(slice(attribute, start, len)?.to_vec());
+        }
+
+        let size = u64_at(attribute, 0x30)?;
+        if size > MAX_ATTRIBUTE_VALUE {
+            return Err(HypervisorError::NtfsUnsupportedFile);
+        }
+
+        let runs = decode_runs(attribute)?;
+        let mut value = vec![0u8; size as usize];
+        self.read_runs(&runs, 0, &mut value)?;
+        Ok(value)
+    }
+
+    /// Locates the unnamed `$DATA` attribute of a file, following its attribute list if it has one.
+    fn data(&mut self, number: u64, record: &[u8]) -> Result<NtfsFile, HypervisorError> {
+        let mut extents = Vec::new();
+
+        match attributes(record, ATTRIBUTE_LIST)?.first() {
+            Some(list) => {
+                let list = self.attribute_value(list)?;
+                let mut records = Vec::new();
+
+                let mut position = 0;
+                while position + 0x1A <= list.len() {
+                    let entry = slice(&list, position, list.len() - position)?;
+                    let length = usize::from(u16_at(entry, 4)?);
+                    if length == 0 {
+                        break;
+                    }
+
+                    let name_length = *entry.get(6).ok_or(HypervisorError::NtfsCorrupted)?;
+                    let reference = u64_at(entry, 0x10)? & FILE_REFERENCE_MASK;
+                    if u32_at(entry, 0)? == ATTRIBUTE_DATA
+                        && name_length == 0
+                        && !records.contains(&reference)
+                    {
+                        records.push(reference);
+                    }
+
+                    position += length;
+                }
+
+                if records.len() > MAX_ATTRIBUTE_RECORDS {
+                    return Err(HypervisorError::NtfsUnsupportedFile);
+                }
+
+                for reference in records {
+                    let extension = match reference == number {
+                        true => record.to_vec(),
+                        false => self.read_record(reference)?,
+                    };
+                    for attribute in unnamed(attributes(&extension, ATTRIBUTE_DATA)?) {
+                        extents.push(attribute.to_vec());
+                    }
+                }
+            }
+            None => {
+                for attribute in unnamed(attributes(record, ATTRIBUTE_DATA)?) {
+                    extents.push(attribute.to_vec());
+                }
+            }
+        }
+
+        let first = extents.first().ok_or(HypervisorError::NtfsFileNotFound)?;
+        if u16_at(first, 0x0C)? & (ATTRIBUTE_COMPRESSED | ATTRIBUTE_ENCRYPTED) != 0 {
+            return Err(HypervisorError::NtfsUnsupportedFile);
+        }
+
+        if *first.get(8).ok_or(HypervisorError::NtfsCorrupted)? == 0 {
+            let data = self.attribute_value(first)?;
+            return Ok(NtfsFile {
+                record: number,
+                size: data.len() as u64,
+                data: FileData::Resident(data),
+            });
+        }
+
+        // Only the extent starting at VCN 0 holds the size of the data.
+        let mut size = None;
+        let mut runs = Vec::new();
+        for extent in &extents {
+            if u64_at(extent, 0x10)? == 0 {
+                size = Some(u64_at(extent, 0x30)?);
+            }
+            runs.extend(decode_runs(extent)?);
+        }
+        runs.sort_unstable_by_key(|run| run.vcn);
+
+        Ok(NtfsFile {
+            record: number,
+            size: size.ok_or(HypervisorError::NtfsCorrupted)?,
+            data: FileData::NonResident(runs),
+        })
+    }
+
+    /// Looks up a name in the `$I30` index of a directory.
+    ///
+    /// # Returns
+    ///
+    /// The MFT record number of the entry, or `None` if the directory has no such entry.
+    fn lookup(&mut self, directory: &[u8], name: &str) -> Result<Option<u64>, HypervisorError> {
+        let root = attributes(directory, ATTRIBUTE_INDEX_ROOT)?;
+        let root = root.first().ok_or(HypervisorError::NtfsCorrupted)?;
+        let root = self.attribute_value(root)?;
+
+        // The index header follows the 16 bytes of the index root.
+        if let Some(found) = find_entry(slice(&root, 0x10, root.len().saturating_sub(0x10))?, name)?
+        {
+            return Ok(Some(found));
+        }
+
+        let Some(allocation) = attributes(directory, ATTRIBUTE_INDEX_ALLOCATION)?
+            .first()
+            .copied()
+        else {
+            return Ok(None);
+        };
+
+        let index_record_size = u32_at(&root, 8)? as usize;
+        if !(UPDATE_SEQUENCE_STRIDE..=0x1_0000).contains(&index_record_size) {
+            return Err(HypervisorError::NtfsCorrupted);
+        }
+
+        let size = u64_at(allocation, 0x30)?;
+        let runs = decode_runs(allocation)?;
+        let mut block = vec![0u8; index_record_size];
+
+        let mut offset = 0;
+        while offset + index_record_size as u64 <= size {
+            self.read_runs(&runs, offset, &mut block)?;
+            offset += index_record_size as u64;
+
+            // Blocks not in use by the index are left as they were.
+            if slice(&block, 0, INDX_MAGIC.len())? != INDX_MAGIC {
+                continue;
+            }
+            apply_fixups(&mut block)?;
+
+            if let Some(found) = find_entry(slice(&block, 0x18, index_record_size - 0x18)?, name)? {
+                return Ok(Some(found));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Reads a file of the guest. Must be called at PASSIVE_LEVEL.
+///
+/// The volume is parsed on the first read of one of its files and kept until `release_volumes`. A read failing
+/// on a kept volume is retried once on the volume parsed again, e.g. after the MFT grew.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file with its drive letter, e.g. `C:\Windows\Prefetch\CMD.EXE-0BD30981.pf`.
+/// * `offset` - The offset in the file of the first byte read.
+/// * `buffer` - Receives the bytes.
+///
+/// # Returns
+///
+/// The number of bytes read and the size of the file.
+pub fn read_guest_file(
+    path: &str,
+    offset: u64,
+    buffer: &mut [u8],
+) -> Result<(usize, u64), HypervisorError> {
+    let mut chars = path.chars();
+    let (Some(drive), Some(':')) = (chars.next(), chars.next()) else {
+        return Err(HypervisorError::NtfsFileNotFound);
+    };
+    let path = chars.as_str();
+
+    let drive = drive.to_ascii_uppercase();
+    let slot = match drive.is_ascii_uppercase() {
+        true => VOLUMES.get(usize::from(drive as u8 - b'A')),
+        false => None,
+    }
+    .ok_or(HypervisorError::NtfsFileNotFound)?;
+
+    let cached = slot.swap(core::ptr::null_mut(), Ordering::AcqRel);
+    let mut volume = match cached.is_null() {
+        true => Box::new(NtfsVolume::new(GuestVolume::open(drive)?)?),
+        false => unsafe { Box::from_raw(cached) },
+    };
+
+    let result = match volume.read_file(path, offset, buffer) {
+        // A file that does not exist is no reason to parse the volume again.
+        Err(error) if !cached.is_null() && !matches!(error, HypervisorError::NtfsFileNotFound) => {
+            log::debug!("Parsing volume {} again after {:?}", drive, error);
+            volume = Box::new(NtfsVolume::new(GuestVolume::open(drive)?)?);
+            volume.read_file(path, offset, buffer)
+        }
+        result => result,
+    };
+
+    // Another read may have kept its own volume in the meantime, in which case this one is dropped.
+    let volume = Box::into_raw(volume);
+    if slot
+        .compare_exchange(
+            core::ptr::null_mut(),
+            volume,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+    {
+        drop(unsafe { Box::from_raw(volume) });
+    }
+
+    result
+}
+
+/// Drops the volumes kept by `read_guest_file`. Must be called at PASSIVE_LEVEL before the driver unloads, and
+/// not while files are read.
+pub fn release_volumes() {
+    for slot in &VOLUMES {
+        let volume = slot.swap(core::ptr::null_mut(), Ordering::AcqRel);
+        if !volume.is_null() {
+            drop(unsafe { Box::from_raw(volume) });
+        }
+    }
+}
+
+/// The number of drive letters.
+const DRIVE_LETTERS: usize = 26;
+
+/// The volumes parsed by `read_guest_file`, by drive letter. A volume is taken out of its slot while one of its
+/// files is read, so concurrent reads of the same volume parse it on their own instead of waiting.
+static VOLUMES: [AtomicPtr<NtfsVolume<GuestVolume>>; DRIVE_LETTERS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicPtr<NtfsVolume<GuestVolume>> = AtomicPtr::new(core::ptr::null_mut());
+    [EMPTY; DRIVE_LETTERS]
+};
+
+impl<R: VolumeReader> NtfsVolume<R> {
+    /// Opens and reads a file, see `NtfsVolume::open` and `NtfsVolume::read`.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes read and the size of the file.
+    fn read_file(
+        &mut self,
+        path: &str,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<(usize, u64), HypervisorError> {
+        let file = self.open(path)?;
+        let read = self.read(&file, offset, buffer)?;
+
+        Ok((read, file.size))
+    }
+}
+
+/// A volume of the guest, read as raw sectors from the device at the bottom of its device stack.
+///
+/// The file system and the filters of the volume are bypassed. A volume encrypted with BitLocker is therefore
+/// read as ciphertext, and is not recognized as NTFS.
+pub struct GuestVolume {
+    /// The device at the bottom of the stack of the volume, referenced.
+    device: PDEVICE_OBJECT,
+}
+
+// The device is only used to build requests, which the I/O manager serializes as needed.
+unsafe impl Send for GuestVolume {}
+
+impl GuestVolume {
+    /// Opens the volume of a drive letter. Must be called at PASSIVE_LEVEL.
+    ///
+    /// # Arguments
+    ///
+    /// * `drive` - The drive letter, e.g. `C`.
+    pub fn open(drive: char) -> Result<Self, HypervisorError> {
+        if !drive.is_ascii_alphabetic() {
+            return Err(HypervisorError::NtfsFileNotFound);
+        }
+
+        let mut path: Vec<u16> = "\\??\\".encode_utf16().collect();
+        path.extend([drive.to_ascii_uppercase() as u16, u16::from(b':')]);
+
+        let mut name = UNICODE_STRING {
+            Length: (path.len() * 2) as u16,
+            MaximumLength: (path.len() * 2) as u16,
+            Buffer: path.as_mut_ptr(),
+        };
+        let mut file_object: PFILE_OBJECT = core::ptr::null_mut();
+        let mut top: PDEVICE_OBJECT = core::ptr::null_mut();
+
+        let status = unsafe {
+            IoGetDeviceObjectPointer(&mut name, FILE_READ_DATA, &mut file_object, &mut top)
+        };
+        if !NT_SUCCESS(status) {
+            log::error!("Failed to open volume {}: {:#x}", drive, status);
+            return Err(HypervisorError::NtfsVolumeUnreadable);
+        }
+
+        // The file object references the volume device, below the file system mounted on it.
+        let device = unsafe {
+            let device = IoGetDeviceAttachmentBaseRef((*file_object).DeviceObject);
+            ObfDereferenceObject(file_object as PVOID);
+            device
+        };
+
+        if device.is_null() {
+            log::error!("Volume {} has no device", drive);
+            return Err(HypervisorError::NtfsVolumeUnreadable);
+        }
+
+        Ok(Self { device })
+    }
+}
+
+impl VolumeReader for GuestVolume {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), HypervisorError> {
+        // The device only reads whole sectors.
+        let start = offset & !(VOLUME_READ_ALIGNMENT - 1);
+        let end = (offset + buffer.len() as u64).next_multiple_of(VOLUME_READ_ALIGNMENT);
+        let mut aligned = vec![0u8; (end - start) as usize];
+
+        let mut io_status = IO_STATUS_BLOCK::default();
+        let mut byte_offset = LARGE_INTEGER {
+            QuadPart: start as i64,
+        };
+        let mut event: KEVENT = unsafe { core::mem::zeroed() };
+
+        let status = unsafe {
+            KeInitializeEvent(&mut event, NOTIFICATION_EVENT, 0);
+
+            let irp = IoBuildSynchronousFsdRequest(
+                IRP_MJ_READ,
+                self.device,
+                aligned.as_mut_ptr() as PVOID,
+                aligned.len() as u32,
+                &mut byte_offset,
+                &mut event,
+                &mut io_status,
+            );
+            if irp.is_null() {
+                return Err(HypervisorError::NtfsVolumeUnreadable);
+            }
+
+            match IofCallDriver(self.device, irp) {
+                STATUS_PENDING => {
+                    KeWaitForSingleObject(
+                        &mut event as *mut KEVENT as PVOID,
+                        EXECUTIVE,
+                        KERNEL_MODE,
+                        0,
+                        core::ptr::null_mut(),
+                    );
+                    io_status.__bindgen_anon_1.Status
+                }
+                status => status,
+            }
+        };
+
+        if !NT_SUCCESS(status) {
+            log::error!("Failed to read the volume at {:#x}: {:#x}", start, status);
+            return Err(HypervisorError::NtfsVolumeUnreadable);
+        }
+
+        buffer.copy_from_slice(slice(&aligned, (offset - start) as usize, buffer.len())?);
+
+        Ok(())
+    }
+}
+
+impl Drop for GuestVolume {
+    fn drop(&mut self) {
+        unsafe { ObfDereferenceObject(self.device as PVOID) };
+    }
+}
+
+/// Checks and removes the update sequence of a multi-sector structure, an MFT or index record.
+fn apply_fixups(block: &mut [u8]) -> Result<(), HypervisorError> {
+    let array_offset = usize::from(u16_at(block, 4)?);
+    let count = usize::from(u16_at(block, 6)?);
+    let usn = u16_at(block, array_offset)?;
+
+    // The first entry is the update sequence number, followed by the original end of each stride.
+    for i in 1..count {
+        let end = i * UPDATE_SEQUENCE_STRIDE - 2;
+        if u16_at(block, end)? != usn {
+            log::error!("Torn write in the stride {} of a record", i);
+            return Err(HypervisorError::NtfsCorrupted);
+        }
+
+        let original = u16_at(block, array_offset + 2 * i)?;
+        block
+            .get_mut(end..end + 2)
+            .ok_or(HypervisorError::NtfsCorrupted)?
+            .copy_from_slice(&original.to_le_bytes());
+    }
+
+    Ok(())
+}
+
+/// Returns the attributes of a type in an MFT record.
+fn attributes(record: &[u8], kind: u32) -> Result<Vec<&[u8]>, HypervisorError> {
+    let mut found = Vec::new();
+    let mut offset = usize::from(u16_at(record, 0x14)?);
+
+    loop {
+        let attribute_type = u32_at(record, offset)?;
+        if attribute_type == ATTRIBUTE_END {
+            break;
+        }
+
+        let length = u32_at(record, offset + 4)? as usize;
+        if length == 0 {
+            return Err(HypervisorError::NtfsCorrupted);
+        }
+
+        if attribute_type == kind {
+            found.push(slice(record, offset, length)?);
+        }
+
+        offset += length;
+    }
+
+    Ok(found)
+}
+
+/// Keeps the attributes without a name, e.g. the default data stream.
+fn unnamed(attributes: Vec<&[u8]>) -> impl Iterator<Item = &[u8]> {
+    attributes
+        .into_iter()
+        .filter(|attribute| attribute.get(9) == Some(&0))
+}
+
+/// Decodes the runlist of a non-resident attribute.
+fn decode_runs(attribute: &[u8]) -> Result<Vec<Run>, HypervisorError> {
+    let mut vcn = u64_at(attribute, 0x10)?;
+    let mut position = usize::from(u16_at(attribute, 0x20)?);
+    let mut lcn = 0i64;
+    let mut runs = Vec::new();
+
+    loop {
+        let header = *attribute
+            .get(position)
+            .ok_or(HypervisorError::NtfsCorrupted)?;
+        if header == 0 {
+            break;
+        }
+
+        let length_size = usize::from(header & 0xF);
+        let offset_size = usize::from(header >> 4);
+        if length_size == 0 || length_size > 8 || offset_size > 8 {
+            return Err(HypervisorError::NtfsCorrupted);
+        }
+
+        let clusters = le_unsigned(slice(attribute, position + 1, length_size)?);
+        let run_lcn = match offset_size {
+            // A run without an offset is sparse.
+            0 => None,
+            _ => {
+                lcn += le_signed(slice(attribute, position + 1 + length_size, offset_size)?);
+                Some(u64::try_from(lcn).map_err(|_| HypervisorError::NtfsCorrupted)?)
+            }
+        };
+
+        runs.push(Run {
+            vcn,
+            lcn: run_lcn,
+            clusters,
+        });
+
+        vcn = vcn
+            .checked_add(clusters)
+            .ok_or(HypervisorError::NtfsCorrupted)?;
+        position += 1 + length_size + offset_size;
+    }
+
+    Ok(runs)
+}
+
+/// Looks up a name among the entries of an index node.
+///
+/// # Arguments
+///
+/// * `header` - The index node, starting with its index header.
+/// * `name` - The name looked up.
+fn find_entry(header: &[u8], name: &str) -> Result<Option<u64>, HypervisorError> {
+    let mut offset = u32_at(header, 0)? as usize;
+    let end = (u32_at(header, 4)? as usize).min(header.len());
+
+    while offset < end {
+        let entry = slice(header, offset, end - offset)?;
+        let length = usize::from(u16_at(entry, 8)?);
+        let key_length = usize::from(u16_at(entry, 0x0A)?);
+        let flags = u16_at(entry, 0x0C)?;
+
+        if flags & INDEX_ENTRY_LAST != 0 || length == 0 {
+            break;
+        }
+
+        if key_length >= 0x42 {
+            let key = slice(entry, 0x10, key_length)?;
+            let name_length = usize::from(*key.get(0x40).ok_or(HypervisorError::NtfsCorrupted)?);
+            let entry_name = slice(key, 0x42, name_length * 2)?;
+
+            if name_matches(entry_name, name) {
+                return Ok(Some(u64_at(entry, 0)? & FILE_REFERENCE_MASK));
+            }
+        }
+
+        offset += length;
+    }
+
+    Ok(None)
+}
+
+/// Compares a UTF-16 name of the volume with a name, case-insensitively.
+fn name_matches(utf16: &[u8], name: &str) -> bool {
+    let units = utf16.chunks_exact(2).map(|unit| le_unsigned(unit) as u16);
+    let mut decoded = char::decode_utf16(units);
+    let mut expected = name.chars();
+
+    loop {
+        match (decoded.next(), expected.next()) {
+            (None, None) => return true,
+            (Some(Ok(a)), Some(b)) if a.to_uppercase().eq(b.to_uppercase()) => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Returns a subslice, or `HypervisorError::NtfsCorrupted` if it is out of bounds.
+fn slice(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], HypervisorError> {
+    offset
+        .checked_add(len)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or(HypervisorError::NtfsCorrupted)
+}
+
+/// Reads a little-endian 16-bit field.
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, HypervisorError> {
+    Ok(le_unsigned(slice(bytes, offset, 2)?) as u16)
+}
+
+/// Reads a little-endian 32-bit field.
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, HypervisorError> {
+    Ok(le_unsigned(slice(bytes, offset, 4)?) as u32)
+}
+
+/// Reads a little-endian 64-bit field.
+fn u64_at(bytes: &[u8], offset: usize) -> Result<u64, HypervisorError> {
+    Ok(le_unsigned(slice(bytes, offset, 8)?))
+}
+
+/// Decodes a little-endian unsigned number of up to 8 bytes.
+fn le_unsigned(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| (value << 8) | u64::from(byte))
+}
+
+/// Decodes a little-endian signed number of 1 to 8 bytes.
+fn le_signed(bytes: &[u8]) -> i64 {
+    let shift = 64 - 8 * bytes.len() as u32;
+    ((le_unsigned(bytes) << shift) as i64) >> shift
+}
+
+/// The access right the volume is opened with, to find its device.
+const FILE_READ_DATA: u32 = 0x0000_0001;
+
+/// `NotificationEvent` of `EVENT_TYPE`.
+const NOTIFICATION_EVENT: u32 = 0;
+
+/// `Executive` of `KWAIT_REASON`.
+const EXECUTIVE: u32 = 0;
+
+/// `KernelMode` of `KPROCESSOR_MODE`.
+const KERNEL_MODE: i8 = 0;
+
+#[link(name = "ntoskrnl")]
+extern "system" {
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-iogetdeviceobjectpointer
+    fn IoGetDeviceObjectPointer(
+        object_name: *mut UNICODE_STRING,
+        desired_access: u32,
+        file_object: *mut PFILE_OBJECT,
+        device_object: *mut PDEVICE_OBJECT,
+    ) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/ntddk/nf-ntddk-iogetdeviceattachmentbaseref
+    fn IoGetDeviceAttachmentBaseRef(device_object: PDEVICE_OBJECT) -> PDEVICE_OBJECT;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-iobuildsynchronousfsdrequest
+    fn IoBuildSynchronousFsdRequest(
+        major_function: u32,
+        device_object: PDEVICE_OBJECT,
+        buffer: PVOID,
+        length: u32,
+        starting_offset: PLARGE_INTEGER,
+        event: *mut KEVENT,
+        io_status_block: PIO_STATUS_BLOCK,
+    ) -> PIRP;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-iocalldriver
+    fn IofCallDriver(device_object: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-keinitializeevent
+    fn KeInitializeEvent(event: *mut KEVENT, event_type: u32, state: u8);
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-kewaitforsingleobject
+    fn KeWaitForSingleObject(
+        object: PVOID,
+        wait_reason: u32,
+        wait_mode: i8,
+        alertable: u8,
+        timeout: *mut LARGE_INTEGER,
+    ) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-obdereferenceobject
+    fn ObfDereferenceObject(object: PVOID) -> isize;
+}
@@ -22,6 +22,9 @@ extern "system" {
     fn ZwYieldExecution() -> NTSTATUS;
 }
 
+/// The maximum number of virtual processors.
+pub const MAX_VCPUS: usize = 64;
+
 /// Atomic bitset used to track which processors have been virtualized.
 static VIRTUALIZED_BITSET: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
 
@@ -43,6 +46,22 @@ pub fn set_virtualized() {
     VIRTUALIZED_BITSET.fetch_or(bit, core::sync::atomic::Ordering::Relaxed);
 }
 
+/// Marks the current processor as no longer virtualized.
+pub fn clear_virtualized() {
+    let bit = 1 << current_processor_index();
+
+    VIRTUALIZED_BITSET.fetch_and(!bit, core::sync::atomic::Ordering::Relaxed);
+}
+
+/// Determines if any processor is still virtualized.
+///
+/// # Returns
+///
+/// `true` if at least one processor is virtualized, otherwise `false`.
+pub fn any_virtualized() -> bool {
+    VIRTUALIZED_BITSET.load(core::sync::atomic::Ordering::Relaxed) != 0
+}
+
 /// Returns the number of active logical processors in a specified group in a multiprocessor system or in the entire system.
 pub fn processor_count() -> u32 {
     unsafe { KeQueryActiveProcessorCountEx(ALL_PROCESSOR_GROUPS as _) }
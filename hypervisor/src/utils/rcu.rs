@@ -0,0 +1,274 @@
+//! Read-copy-update snapshots of shared state read on the VM-exit hot path.
+//!
+//! State that the exit handlers of all processors consult, such as the hook table or the regions of the EPT
+//! policy, changes rarely. Instead of a lock, which every exit would acquire, such state is kept in an immutable
+//! snapshot behind a pointer:
+//! - Readers load the pointer and use the snapshot without writing to any shared cache line.
+//! - Writers copy the current snapshot, change the copy, swap the pointer, and free the previous snapshot
+//!   once no reader can still hold it.
+//!
+//! Handling a VM exit is the read-side critical section: a processor holds no reference to a snapshot once it
+//! resumed the guest. Every processor counts the read-side critical sections it is in, which nest, e.g. when an
+//! exit handler devirtualizes the processor and leaves the section of the exit before resuming the guest natively,
+//! and counts how often it left the outermost one. After swapping the pointer, a writer waits for every other
+//! processor inside a section to leave its outermost one, which ends the grace period.
+//!
+//! Readers outside of VM-exit handling, i.e. the `Hypervisor` API called by the driver in the guest, enter a
+//! section for the duration of the read with interrupts disabled.
+//!
+//! The state of the processors is allocated by `init` for the number of active processors, so the grace period
+//! only waits on processors that exist.
+
+use {
+    crate::utils::{
+        processor::{current_processor_index, processor_count},
+        sync::InterruptState,
+    },
+    alloc::{boxed::Box, vec::Vec},
+    core::{
+        ops::Deref,
+        ptr,
+        sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+/// The read-side state of a processor.
+struct ReaderState {
+    /// The number of read-side critical sections the processor is in.
+    nesting: AtomicU32,
+
+    /// The number of times the processor left its outermost read-side critical section.
+    quiescent: AtomicU64,
+}
+
+impl ReaderState {
+    fn enter(&self) {
+        self.nesting.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn leave(&self) {
+        if self.nesting.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.quiescent.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// The read-side state of each processor, allocated once by `init`.
+static READERS: AtomicPtr<ReaderState> = AtomicPtr::new(ptr::null_mut());
+
+/// The number of elements of `READERS`.
+static READER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocates the read-side state of the active processors, unless it already is.
+///
+/// Must be called before any processor is virtualized. The state lives until the driver is unloaded, so
+/// building the hypervisor again reuses it.
+pub fn init() {
+    if !READERS.load(Ordering::Acquire).is_null() {
+        return;
+    }
+
+    let readers: Vec<ReaderState> = (0..processor_count())
+        .map(|_| ReaderState {
+            nesting: AtomicU32::new(0),
+            quiescent: AtomicU64::new(0),
+        })
+        .collect();
+
+    let count = readers.len();
+    let readers = Box::into_raw(readers.into_boxed_slice()) as *mut ReaderState;
+
+    READER_COUNT.store(count, Ordering::Release);
+    READERS.store(readers, Ordering::Release);
+}
+
+/// Returns the read-side state of all processors, empty before `init`.
+fn readers() -> &'static [ReaderState] {
+    let readers = READERS.load(Ordering::Acquire);
+    if readers.is_null() {
+        return &[];
+    }
+
+    unsafe { core::slice::from_raw_parts(readers, READER_COUNT.load(Ordering::Acquire)) }
+}
+
+/// Returns the read-side state of the current processor.
+fn current_reader() -> Option<&'static ReaderState> {
+    readers().get(current_processor_index() as usize)
+}
+
+/// Marks the current processor as handling a VM exit, during which it may read any snapshot.
+pub fn begin_exit() {
+    if let Some(reader) = current_reader() {
+        reader.enter();
+    }
+}
+
+/// Marks the current processor as about to resume the guest, releasing all snapshots it read.
+pub fn end_exit() {
+    if let Some(reader) = current_reader() {
+        reader.leave();
+    }
+}
+
+/// Waits until every other processor that was reading snapshots finished doing so.
+///
+/// The current processor is skipped, so a writer running in an exit handler must not hold on to a snapshot it
+/// read before its own update.
+pub fn synchronize() {
+    let current = current_processor_index() as usize;
+
+    for (cpu, reader) in readers().iter().enumerate() {
+        if cpu == current {
+            continue;
+        }
+
+        // Loaded before the nesting, so leaving the section in between is noticed.
+        let observed = reader.quiescent.load(Ordering::SeqCst);
+        if reader.nesting.load(Ordering::SeqCst) == 0 {
+            continue;
+        }
+
+        while reader.quiescent.load(Ordering::SeqCst) == observed {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The read-side critical section of a reader outside of VM-exit handling.
+struct ReadSection {
+    /// The interrupt state before the section, restored at its end.
+    interrupts: InterruptState,
+
+    /// The state of the processor the section was entered on.
+    reader: Option<&'static ReaderState>,
+}
+
+impl ReadSection {
+    /// Enters a read-side critical section on the current processor.
+    fn enter() -> Self {
+        let interrupts = InterruptState::save_and_disable();
+
+        let reader = current_reader();
+        if let Some(reader) = reader {
+            reader.enter();
+        }
+
+        Self { interrupts, reader }
+    }
+}
+
+impl Drop for ReadSection {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader {
+            reader.leave();
+        }
+
+        self.interrupts.restore();
+    }
+}
+
+/// A value read without locks and updated by swapping whole snapshots.
+pub struct Rcu<T> {
+    /// The current snapshot, never null.
+    current: AtomicPtr<T>,
+
+    /// Whether an update is in progress. Updates are serialized, so only the writer frees snapshots.
+    updating: AtomicBool,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+unsafe impl<T: Send> Send for Rcu<T> {}
+
+impl<T> Rcu<T> {
+    /// Creates the first snapshot of the value.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The initial value.
+    pub fn new(value: T) -> Self {
+        Self {
+            current: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            updating: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the current snapshot.
+    ///
+    /// Inside a VM-exit handler, this is a single load. Elsewhere, interrupts stay disabled until the guard is
+    /// dropped, so it must not be held across allocations.
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        let section = ReadSection::enter();
+        let data = unsafe { &*self.current.load(Ordering::Acquire) };
+
+        RcuReadGuard {
+            data,
+            _section: section,
+        }
+    }
+
+    /// Replaces the snapshot with a new one derived from the current one, and frees the current one after a
+    /// grace period.
+    ///
+    /// Updates are serialized with each other, and may allocate, as interrupts stay enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `update` - Builds the new snapshot from the current one, along with a result for the caller.
+    ///
+    /// # Returns
+    ///
+    /// The result of `update`, or its error, in which case the snapshot is left unchanged.
+    pub fn update<R, E>(&self, update: impl FnOnce(&T) -> Result<(T, R), E>) -> Result<R, E> {
+        while self
+            .updating
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+
+        // Only the writer frees snapshots, so the current one stays valid until it is swapped out below.
+        let current = unsafe { &*self.current.load(Ordering::Acquire) };
+
+        let result = update(current).map(|(value, result)| {
+            let previous = self
+                .current
+                .swap(Box::into_raw(Box::new(value)), Ordering::SeqCst);
+
+            synchronize();
+            drop(unsafe { Box::from_raw(previous) });
+
+            result
+        });
+
+        self.updating.store(false, Ordering::Release);
+
+        result
+    }
+
+    /// Returns a mutable reference to the current snapshot, which is safe as no reader can exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut **self.current.get_mut() }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(*self.current.get_mut()) });
+    }
+}
+
+/// A snapshot of an `Rcu`, valid until the guard is dropped.
+pub struct RcuReadGuard<'a, T> {
+    data: &'a T,
+    _section: ReadSection,
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
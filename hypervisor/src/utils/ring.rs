@@ -0,0 +1,302 @@
+//! A multi-producer single-consumer ring buffer shared between all processors.
+//!
+//! Every processor produces into its own ring of fixed-size slots, so producers on different
+//! processors never contend on the same slots and no lock is taken in VM-exit context. Each record
+//! is stamped with a global sequence number when it is produced, which the single consumer uses to
+//! merge the per-processor rings back into the order the records were produced in. The sequence
+//! numbers are handed out without gaps, so the consumer waits for the record with the next one
+//! instead of passing it while its producer has not published it yet.
+//!
+//! Records are formatted directly into their slot and handed to the consumer by reference, so no
+//! intermediate copy is made. A full ring drops new records instead of overwriting records the
+//! consumer has not seen yet; dropped records are counted.
+
+use {
+    crate::{error::HypervisorError, utils::timestamp::Timestamp},
+    alloc::{boxed::Box, vec::Vec},
+    core::{
+        cell::UnsafeCell,
+        fmt::{self, Write},
+        sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+/// The number of slots in the ring of each processor.
+pub const SLOTS_PER_CPU: usize = 256;
+
+/// The maximum size of a message in bytes. Longer messages are truncated.
+pub const MESSAGE_SIZE: usize = 192;
+
+/// A single record in the ring.
+///
+/// All fields are valid when zeroed, which allows the rings to be allocated zeroed on the heap.
+#[repr(C)]
+pub struct Record {
+    /// The global sequence number of the record.
+    pub sequence: u64,
+
+    /// The index of the processor that produced the record.
+    pub cpu: u32,
+
+    /// The level of the record, as `log::Level as u8`.
+    pub level: u8,
+
+    /// When the record was produced, on the host and on the guest time line.
+    pub timestamp: Timestamp,
+
+    /// The number of valid bytes in `message`.
+    len: u16,
+
+    /// The formatted message.
+    message: [u8; MESSAGE_SIZE],
+}
+
+impl Record {
+    /// Returns the formatted message.
+    pub fn message(&self) -> &[u8] {
+        self.message
+            .get(..self.len as usize)
+            .unwrap_or(&self.message)
+    }
+
+    /// Returns the formatted message as a string, up to the first invalid UTF-8 sequence.
+    ///
+    /// Truncation may split a multi-byte character at the end of the message.
+    pub fn message_str(&self) -> &str {
+        match core::str::from_utf8(self.message()) {
+            Ok(message) => message,
+            Err(error) => self
+                .message()
+                .get(..error.valid_up_to())
+                .and_then(|bytes| core::str::from_utf8(bytes).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Write for Record {
+    /// Appends to the message, silently truncating once it is full.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let start = self.len as usize;
+        let count = s.len().min(MESSAGE_SIZE.saturating_sub(start));
+
+        if let (Some(dst), Some(src)) = (
+            self.message.get_mut(start..start + count),
+            s.as_bytes().get(..count),
+        ) {
+            dst.copy_from_slice(src);
+            self.len += count as u16;
+        }
+
+        Ok(())
+    }
+}
+
+/// A slot holding one record.
+#[repr(C)]
+struct Slot {
+    /// The position of the record in the ring plus one, once the record is published.
+    published: AtomicU64,
+
+    /// The record.
+    record: UnsafeCell<Record>,
+}
+
+/// The ring of a single processor.
+#[repr(C, align(64))]
+struct CpuRing {
+    /// The next position to be reserved by a producer.
+    head: AtomicU64,
+
+    /// The next position to be read by the consumer.
+    tail: AtomicU64,
+
+    /// The number of records dropped because the ring was full.
+    dropped: AtomicU64,
+
+    /// The slots of the ring.
+    slots: [Slot; SLOTS_PER_CPU],
+}
+
+impl CpuRing {
+    /// Returns the slot for the given position.
+    fn slot(&self, position: u64) -> Option<&Slot> {
+        self.slots.get((position % SLOTS_PER_CPU as u64) as usize)
+    }
+}
+
+/// A multi-producer single-consumer ring buffer with one ring per processor.
+pub struct MpscRing {
+    /// The rings, indexed by processor.
+    rings: Vec<Box<CpuRing>>,
+
+    /// The global sequence counter.
+    sequence: AtomicU64,
+
+    /// The sequence number of the next record to consume, only used by the consumer.
+    consumed: AtomicU64,
+
+    /// Whether a consumer is currently draining the rings.
+    consuming: AtomicBool,
+}
+
+// The slots are only written by the producer that reserved them and only read by the single
+// consumer after they have been published.
+unsafe impl Sync for MpscRing {}
+unsafe impl Send for MpscRing {}
+
+impl MpscRing {
+    /// Allocates a ring for each processor.
+    ///
+    /// Must not be called from VMX root operation, as it allocates.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpu_count` - The number of processors.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the ring buffer or an error if the allocation failed.
+    pub fn new(cpu_count: u32) -> Result<Self, HypervisorError> {
+        let mut rings = Vec::new();
+
+        for _ in 0..cpu_count.max(1) {
+            // Allocated zeroed on the heap, the rings are far too large for a kernel stack.
+            let ring = Box::<CpuRing>::try_new_zeroed()?;
+            rings.push(unsafe { ring.assume_init() });
+        }
+
+        Ok(Self {
+            rings,
+            sequence: AtomicU64::new(0),
+            consumed: AtomicU64::new(0),
+            consuming: AtomicBool::new(false),
+        })
+    }
+
+    /// Produces a record into the ring of the given processor.
+    ///
+    /// Lock-free and allocation-free, so it can be called from VM-exit context.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpu` - The index of the processor producing the record.
+    /// * `level` - The level of the record.
+    /// * `args` - The message.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the record was produced, `false` if it was dropped because the ring was full.
+    pub fn push(&self, cpu: u32, level: u8, args: fmt::Arguments) -> bool {
+        let Some(ring) = self.rings.get(cpu as usize % self.rings.len()) else {
+            return false;
+        };
+
+        // Reserve a position, unless that would overwrite a record the consumer has not read yet.
+        let mut head = ring.head.load(Ordering::Acquire);
+        loop {
+            if head.wrapping_sub(ring.tail.load(Ordering::Acquire)) >= SLOTS_PER_CPU as u64 {
+                ring.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+
+            match ring.head.compare_exchange_weak(
+                head,
+                head + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+
+        let Some(slot) = ring.slot(head) else {
+            return false;
+        };
+
+        // The position is reserved for us alone until it is published.
+        let record = unsafe { &mut *slot.record.get() };
+        record.sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        record.timestamp = Timestamp::now();
+        record.cpu = cpu;
+        record.level = level;
+        record.len = 0;
+        let _ = record.write_fmt(args);
+
+        slot.published.store(head + 1, Ordering::Release);
+
+        true
+    }
+
+    /// Returns the sequence number of the oldest record of the given ring, if it is published.
+    fn front(ring: &CpuRing) -> Option<u64> {
+        let tail = ring.tail.load(Ordering::Relaxed);
+
+        if ring.head.load(Ordering::Acquire) == tail {
+            return None;
+        }
+
+        ring.slot(tail)
+            .filter(|slot| slot.published.load(Ordering::Acquire) == tail + 1)
+            .map(|slot| unsafe { (*slot.record.get()).sequence })
+    }
+
+    /// Consumes all published records in the order they were produced.
+    ///
+    /// Only one consumer runs at a time. If another consumer is already draining the rings, this
+    /// returns immediately, and the records will be consumed by the other consumer. Draining stops
+    /// early at the record with the next sequence number if it is not yet published, to keep the
+    /// order intact.
+    ///
+    /// # Arguments
+    ///
+    /// * `consumer` - Called with each record, which is borrowed directly from its slot.
+    ///
+    /// # Returns
+    ///
+    /// The number of records consumed.
+    pub fn drain(&self, mut consumer: impl FnMut(&Record)) -> usize {
+        if self
+            .consuming
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return 0;
+        }
+
+        let mut consumed = 0;
+        let mut sequence = self.consumed.load(Ordering::Relaxed);
+
+        // A record is only consumed once all records with a lower sequence number are, even if
+        // one of them is not published yet, e.g. on a processor whose ring looked empty before.
+        while let Some(ring) = self
+            .rings
+            .iter()
+            .find(|ring| Self::front(ring) == Some(sequence))
+        {
+            let tail = ring.tail.load(Ordering::Relaxed);
+
+            if let Some(slot) = ring.slot(tail) {
+                consumer(unsafe { &*slot.record.get() });
+            }
+
+            ring.tail.store(tail + 1, Ordering::Release);
+            sequence += 1;
+            consumed += 1;
+        }
+
+        self.consumed.store(sequence, Ordering::Relaxed);
+        self.consuming.store(false, Ordering::Release);
+
+        consumed
+    }
+
+    /// Returns the total number of records dropped because a ring was full.
+    pub fn dropped(&self) -> u64 {
+        self.rings
+            .iter()
+            .map(|ring| ring.dropped.load(Ordering::Relaxed))
+            .sum()
+    }
+}
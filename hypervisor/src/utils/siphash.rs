@@ -0,0 +1,75 @@
+//! SipHash-2-4, a keyed hash suitable as a MAC over short messages.
+//!
+//! Only messages made of whole 64-bit words are supported, which is all the hypervisor needs. The result is
+//! the same as hashing the little-endian bytes of the words with the reference implementation.
+//!
+//! Reference: https://cr.yp.to/siphash/siphash-20120918.pdf
+
+/// The internal state of SipHash.
+struct SipState {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+}
+
+impl SipState {
+    fn new(key: [u64; 2]) -> Self {
+        let [k0, k1] = key;
+
+        Self {
+            v0: k0 ^ 0x736f_6d65_7073_6575,
+            v1: k1 ^ 0x646f_7261_6e64_6f6d,
+            v2: k0 ^ 0x6c79_6765_6e65_7261,
+            v3: k1 ^ 0x7465_6462_7974_6573,
+        }
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13) ^ self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16) ^ self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21) ^ self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17) ^ self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, word: u64) {
+        self.v3 ^= word;
+        self.round();
+        self.round();
+        self.v0 ^= word;
+    }
+}
+
+/// Computes the SipHash-2-4 of a message.
+///
+/// # Arguments
+///
+/// * `key` - The 128-bit key, as two little-endian halves.
+/// * `message` - The message, as little-endian 64-bit words.
+///
+/// # Returns
+///
+/// The 64-bit hash.
+pub fn siphash24(key: [u64; 2], message: &[u64]) -> u64 {
+    let mut state = SipState::new(key);
+
+    for &word in message {
+        state.compress(word);
+    }
+
+    // The final block only holds the length of the message in bytes, modulo 256.
+    state.compress(((message.len() as u64 * 8) & 0xFF) << 56);
+
+    state.v2 ^= 0xFF;
+    for _ in 0..4 {
+        state.round();
+    }
+
+    state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+}
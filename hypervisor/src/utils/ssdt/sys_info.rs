@@ -95,6 +95,14 @@ impl Sysinfo {
 
         None
     }
+
+    /// Returns the loaded modules.
+    pub fn modules(&self) -> impl Iterator<Item = &SystemModule> {
+        let module_info = unsafe { &*self.module_info };
+        let count = (module_info.modules_count as usize).min(module_info.modules.len());
+
+        module_info.modules.iter().take(count)
+    }
 }
 
 impl Drop for Sysinfo {
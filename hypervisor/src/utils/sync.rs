@@ -0,0 +1,387 @@
+//! Spinlock primitives usable in VMX root operation.
+//!
+//! VM-exit handlers cannot call into the OS to wait, so these locks spin. Interrupts are disabled
+//! while a lock is held and the previous interrupt state is restored on release, so an interrupt
+//! handler on the same processor can never spin on a lock its own processor holds. There is no
+//! priority inheritance and no fairness; critical sections are expected to be short.
+//!
+//! Spinning is bounded in the sense that a lock which stays contended for too long is reported,
+//! together with the processor holding it, and `lock_bounded` gives up after a given number of spins.
+
+use {
+    crate::{
+        error::HypervisorError,
+        utils::{
+            instructions::{cli, interrupts_enabled, sti},
+            processor::current_processor_index,
+        },
+    },
+    core::{
+        cell::UnsafeCell,
+        ops::{Deref, DerefMut},
+        sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    },
+};
+
+/// The number of spins after which a contended lock is reported, a power of two.
+pub const SPIN_WARN_LIMIT: u64 = 1 << 24;
+
+/// The owner value of a lock that is not held.
+const NO_OWNER: u32 = u32::MAX;
+
+/// The interrupt state of the processor before a lock was acquired.
+pub struct InterruptState(bool);
+
+impl InterruptState {
+    /// Saves the interrupt state and disables interrupts.
+    pub fn save_and_disable() -> Self {
+        let enabled = interrupts_enabled();
+        cli();
+        Self(enabled)
+    }
+
+    /// Restores the saved interrupt state.
+    pub fn restore(&self) {
+        if self.0 {
+            sti();
+        }
+    }
+}
+
+/// Contention diagnostics of a lock.
+struct Diagnostics {
+    /// The name of the lock, used in reports.
+    name: &'static str,
+
+    /// The processor holding the lock exclusively, or `NO_OWNER`.
+    owner: AtomicU32,
+
+    /// The number of acquisitions that had to spin.
+    contentions: AtomicU64,
+}
+
+impl Diagnostics {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            owner: AtomicU32::new(NO_OWNER),
+            contentions: AtomicU64::new(0),
+        }
+    }
+
+    /// Spins until `try_acquire` succeeds or `max_spins` is reached.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the lock was acquired.
+    fn spin(&self, max_spins: Option<u64>, mut try_acquire: impl FnMut() -> bool) -> bool {
+        let mut spins: u64 = 0;
+
+        while !try_acquire() {
+            if spins == 0 {
+                self.contentions.fetch_add(1, Ordering::Relaxed);
+
+                if self.owner.load(Ordering::Relaxed) == current_processor_index() {
+                    log::error!("Lock '{}' is already held by this processor", self.name);
+                }
+            }
+
+            spins += 1;
+
+            if max_spins.is_some_and(|max| spins >= max) {
+                return false;
+            }
+
+            if spins & (SPIN_WARN_LIMIT - 1) == 0 {
+                log::warn!(
+                    "Lock '{}' still contended after {} spins, held by processor {}",
+                    self.name,
+                    spins,
+                    self.owner.load(Ordering::Relaxed)
+                );
+            }
+
+            core::hint::spin_loop();
+        }
+
+        true
+    }
+}
+
+/// A mutual exclusion spinlock.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    diagnostics: Diagnostics,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    /// Creates a new unlocked spinlock.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the lock, used in contention reports.
+    /// * `data` - The protected data.
+    pub const fn new(name: &'static str, data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            diagnostics: Diagnostics::new(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn guard(&self, interrupts: InterruptState) -> SpinLockGuard<'_, T> {
+        self.diagnostics
+            .owner
+            .store(current_processor_index(), Ordering::Relaxed);
+
+        SpinLockGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    /// Acquires the lock, spinning until it is available.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts = InterruptState::save_and_disable();
+        self.diagnostics.spin(None, || self.try_acquire());
+        self.guard(interrupts)
+    }
+
+    /// Acquires the lock, giving up after `max_spins` spins.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the guard, or `HypervisorError::LockTimeout` if the lock stayed contended.
+    pub fn lock_bounded(&self, max_spins: u64) -> Result<SpinLockGuard<'_, T>, HypervisorError> {
+        let interrupts = InterruptState::save_and_disable();
+
+        if self
+            .diagnostics
+            .spin(Some(max_spins), || self.try_acquire())
+        {
+            Ok(self.guard(interrupts))
+        } else {
+            interrupts.restore();
+            Err(HypervisorError::LockTimeout)
+        }
+    }
+
+    /// Acquires the lock if it is available, without spinning.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts = InterruptState::save_and_disable();
+
+        if self.try_acquire() {
+            Some(self.guard(interrupts))
+        } else {
+            interrupts.restore();
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the data, which needs no locking as the lock is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Returns the number of acquisitions that had to spin.
+    pub fn contentions(&self) -> u64 {
+        self.diagnostics.contentions.load(Ordering::Relaxed)
+    }
+}
+
+/// Releases the `SpinLock` and restores the interrupt state when dropped.
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    interrupts: InterruptState,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock
+            .diagnostics
+            .owner
+            .store(NO_OWNER, Ordering::Relaxed);
+        self.lock.locked.store(false, Ordering::Release);
+        self.interrupts.restore();
+    }
+}
+
+/// A reader-writer spinlock.
+///
+/// Any number of readers or a single writer may hold the lock. Writers are not prioritized, so a
+/// steady stream of readers can delay a writer.
+pub struct RwLock<T> {
+    /// `WRITER` if held by a writer, otherwise the number of readers.
+    state: AtomicU32,
+    diagnostics: Diagnostics,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Send> Send for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// The state of a lock held by a writer.
+    const WRITER: u32 = 1 << 31;
+
+    /// Creates a new unlocked reader-writer lock.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the lock, used in contention reports.
+    /// * `data` - The protected data.
+    pub const fn new(name: &'static str, data: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            diagnostics: Diagnostics::new(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn try_acquire_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+
+        state & Self::WRITER == 0
+            && state + 1 < Self::WRITER
+            && self
+                .state
+                .compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn try_acquire_write(&self) -> bool {
+        self.state
+            .compare_exchange_weak(0, Self::WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Acquires the lock for reading, spinning until no writer holds it.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let interrupts = InterruptState::save_and_disable();
+        self.diagnostics.spin(None, || self.try_acquire_read());
+
+        RwLockReadGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    /// Acquires the lock for reading if no writer holds it, without waiting for the writer.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let interrupts = InterruptState::save_and_disable();
+
+        // `try_acquire_read` can fail spuriously or race with another reader, so it is retried for as
+        // long as no writer holds the lock.
+        while self.state.load(Ordering::Relaxed) & Self::WRITER == 0 {
+            if self.try_acquire_read() {
+                return Some(RwLockReadGuard {
+                    lock: self,
+                    interrupts,
+                });
+            }
+        }
+
+        interrupts.restore();
+        None
+    }
+
+    /// Acquires the lock for writing, spinning until neither readers nor a writer hold it.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let interrupts = InterruptState::save_and_disable();
+        self.diagnostics.spin(None, || self.try_acquire_write());
+        self.diagnostics
+            .owner
+            .store(current_processor_index(), Ordering::Relaxed);
+
+        RwLockWriteGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    /// Returns a mutable reference to the data, which needs no locking as the lock is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Returns the number of acquisitions that had to spin.
+    pub fn contentions(&self) -> u64 {
+        self.diagnostics.contentions.load(Ordering::Relaxed)
+    }
+}
+
+/// Releases a read lock of the `RwLock` and restores the interrupt state when dropped.
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    interrupts: InterruptState,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        self.interrupts.restore();
+    }
+}
+
+/// Releases the write lock of the `RwLock` and restores the interrupt state when dropped.
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    interrupts: InterruptState,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock
+            .diagnostics
+            .owner
+            .store(NO_OWNER, Ordering::Relaxed);
+        self.lock.state.store(0, Ordering::Release);
+        self.interrupts.restore();
+    }
+}
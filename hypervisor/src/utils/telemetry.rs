@@ -0,0 +1,61 @@
+//! The binary event stream of the logger.
+//!
+//! The format is defined in `hypervisor_core::telemetry`, so the `telemetry-parser` crate can be
+//! tested against the encoder; this module encodes the records of the ring buffer in it.
+
+use crate::utils::ring::{Record, MESSAGE_SIZE};
+
+pub use hypervisor_core::telemetry::{
+    encode_file_header, RecordHeader, RecordKind, FILE_HEADER_LEN, FILE_MAGIC, RECORD_HEADER_LEN,
+    RECORD_MAGIC, SCHEMA_VERSION,
+};
+
+/// The maximum length of an encoded record in bytes.
+pub const MAX_RECORD_LEN: usize = RECORD_HEADER_LEN + MESSAGE_SIZE;
+
+/// Encodes a log record.
+///
+/// # Arguments
+///
+/// * `record` - The record to encode.
+/// * `buffer` - The buffer to encode into.
+///
+/// # Returns
+///
+/// The encoded bytes, a prefix of `buffer`.
+pub fn encode_record<'a>(record: &Record, buffer: &'a mut [u8; MAX_RECORD_LEN]) -> &'a [u8] {
+    let header = RecordHeader {
+        kind: RecordKind::Log,
+        level: record.level,
+        sequence: record.sequence,
+        cpu: record.cpu,
+        host_tsc: record.timestamp.host_tsc,
+        guest_tsc: record.timestamp.guest_tsc,
+    };
+
+    header.encode(record.message(), buffer)
+}
+
+/// Encodes a record reporting dropped records.
+///
+/// # Arguments
+///
+/// * `count` - The number of dropped records.
+/// * `cpu` - The processor reporting the drop.
+/// * `buffer` - The buffer to encode into.
+///
+/// # Returns
+///
+/// The encoded bytes, a prefix of `buffer`.
+pub fn encode_dropped(count: u64, cpu: u32, buffer: &mut [u8; MAX_RECORD_LEN]) -> &[u8] {
+    let header = RecordHeader {
+        kind: RecordKind::Dropped,
+        level: log::Level::Warn as u8,
+        sequence: count,
+        cpu,
+        host_tsc: 0,
+        guest_tsc: 0,
+    };
+
+    header.encode(&[], buffer)
+}
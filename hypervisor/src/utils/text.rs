@@ -0,0 +1,89 @@
+//! Text formatted in place into a buffer handed to the guest, without allocating.
+//!
+//! Hypercalls exporting text copy it into a single page, so long texts are either copied in parts, from an offset
+//! the caller advances, or cut off where the page is full. Binary exports, e.g. the metrics snapshot, are copied
+//! the same way with `write_bytes`.
+
+use core::fmt::{self, Write};
+
+/// Copies the part of a text starting at an offset into a buffer, while counting the length of the whole text.
+pub struct TextWindow<'a> {
+    /// Receives the text from `offset` on, as much as fits.
+    buffer: &'a mut [u8],
+
+    /// The offset into the text of the first byte to copy.
+    offset: usize,
+
+    /// The number of bytes copied.
+    copied: usize,
+
+    /// The length of the text written so far.
+    total: usize,
+
+    /// Whether a write that does not fit fails, which ends the formatting there.
+    until_full: bool,
+}
+
+impl<'a> TextWindow<'a> {
+    /// Creates a window copying the text from an offset on, which never fails and counts the whole text.
+    pub fn new(buffer: &'a mut [u8], offset: usize) -> Self {
+        Self {
+            buffer,
+            offset,
+            copied: 0,
+            total: 0,
+            until_full: false,
+        }
+    }
+
+    /// Creates a window copying the text from its start, which fails the first write that does not fit
+    /// entirely and copies nothing of it.
+    pub fn until_full(buffer: &'a mut [u8]) -> Self {
+        Self {
+            until_full: true,
+            ..Self::new(buffer, 0)
+        }
+    }
+
+    /// Returns the number of bytes copied.
+    pub fn copied(&self) -> usize {
+        self.copied
+    }
+
+    /// Returns the length of the text written so far, including the parts that were not copied.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Writes bytes that are not text, copied like the text written with `write_str`.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> fmt::Result {
+        if self.until_full && self.buffer.len() - self.copied < bytes.len() {
+            return Err(fmt::Error);
+        }
+
+        let start = self.total;
+        self.total += bytes.len();
+
+        // The part of `bytes` at or after the offset, copied after what was copied so far.
+        let skip = self.offset.saturating_sub(start).min(bytes.len());
+        let source = bytes.get(skip..).unwrap_or_default();
+
+        if let Some(destination) = self.buffer.get_mut(self.copied..) {
+            let len = source.len().min(destination.len());
+            if let (Some(destination), Some(source)) =
+                (destination.get_mut(..len), source.get(..len))
+            {
+                destination.copy_from_slice(source);
+                self.copied += len;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for TextWindow<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes())
+    }
+}
@@ -0,0 +1,106 @@
+//! Time stamps that correlate hypervisor events with the guest's view of time.
+//!
+//! The guest reads `TSC + TSC offset` (Intel® 64 and IA-32 Architectures Software Developer's Manual:
+//! 26.3 CHANGES TO INSTRUCTION BEHAVIOR IN VMX NON-ROOT OPERATION - RDTSC), while the hypervisor reads
+//! the raw TSC in VMX root operation. Every event is stamped with both values, so it can be placed on
+//! the host time line as well as next to in-guest logs.
+//!
+//! The guest reads the TSC with a single offset shared by all processors, see `intel::tsc`, which is
+//! tracked here whenever it changes, and whether a processor currently runs in VMX root operation is
+//! tracked around VM-exit handling, since reading the VMCS from the guest would itself cause a VM exit.
+
+use {
+    crate::utils::processor::{current_processor_index, MAX_VCPUS},
+    core::sync::atomic::{AtomicBool, AtomicI64, Ordering},
+    x86::time::rdtsc,
+};
+
+/// Whether each processor is currently handling a VM exit, by processor index.
+static ROOT_MODE: [AtomicBool; MAX_VCPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const GUEST: AtomicBool = AtomicBool::new(false);
+    [GUEST; MAX_VCPUS]
+};
+
+/// The TSC offset of the guest, the same on every processor.
+static TSC_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// Marks the current processor as running in VMX root operation.
+pub fn enter_root_mode() {
+    if let Some(root_mode) = ROOT_MODE.get(current_processor_index() as usize) {
+        root_mode.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Marks the current processor as about to resume the guest.
+pub fn leave_root_mode() {
+    if let Some(root_mode) = ROOT_MODE.get(current_processor_index() as usize) {
+        root_mode.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Returns whether the current processor runs in VMX root operation, i.e. handles a VM exit.
+pub fn in_root_mode() -> bool {
+    ROOT_MODE
+        .get(current_processor_index() as usize)
+        .is_some_and(|root_mode| root_mode.load(Ordering::Relaxed))
+}
+
+/// Sets the TSC offset of the guest.
+///
+/// Without TSC virtualization the guest reads the raw TSC, which corresponds to an offset of 0.
+///
+/// # Arguments
+///
+/// * `offset` - The value added to the TSC of the processors.
+pub fn set_tsc_offset(offset: i64) {
+    TSC_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Lowers the TSC offset of the guest, hiding cycles from it.
+///
+/// # Arguments
+///
+/// * `cycles` - The cycles of the guest TSC to hide.
+pub fn lower_tsc_offset(cycles: u64) {
+    TSC_OFFSET.fetch_sub(cycles as i64, Ordering::Relaxed);
+}
+
+/// Returns the TSC offset of the guest.
+pub fn tsc_offset() -> i64 {
+    TSC_OFFSET.load(Ordering::Relaxed)
+}
+
+/// A point in time as seen by the host and by the guest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timestamp {
+    /// The raw TSC of the processor.
+    pub host_tsc: u64,
+
+    /// The TSC as read by the guest on the same processor, i.e. including the TSC offset.
+    pub guest_tsc: u64,
+}
+
+impl Timestamp {
+    /// Takes a time stamp on the current processor.
+    ///
+    /// # Returns
+    ///
+    /// The `Timestamp`, correct in VMX root operation as well as in the guest.
+    pub fn now() -> Self {
+        let tsc = unsafe { rdtsc() };
+        let offset = tsc_offset();
+
+        if in_root_mode() {
+            Self {
+                host_tsc: tsc,
+                guest_tsc: tsc.wrapping_add_signed(offset),
+            }
+        } else {
+            Self {
+                host_tsc: tsc.wrapping_add_signed(offset.wrapping_neg()),
+                guest_tsc: tsc,
+            }
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! Parser for the hypervisor telemetry format.
+//!
+//! The hypervisor writes its event stream in a versioned little-endian binary format (see
+//! `hypervisor_core::telemetry` for the layout). This crate reads that format back in std
+//! environments, so captures of the serial port can be processed offline.
+//!
+//! The reader tolerates captures that were started mid-stream or contain garbage between records:
+//! it resynchronizes on the record magic and reports the skipped bytes.
+//!
+//! Guest addresses in the messages can be symbolized with the maps of the guest modules, see `symbols`.
+
+pub mod symbols;
+
+use std::{
+    fmt,
+    io::{self, Read},
+};
+
+/// The magic at the start of the stream.
+pub const FILE_MAGIC: [u8; 4] = *b"MVTL";
+
+/// The magic at the start of each record.
+pub const RECORD_MAGIC: [u8; 4] = *b"MVRC";
+
+/// The newest schema version this parser understands.
+pub const SCHEMA_VERSION: u16 = 1;
+
+/// The length of the file header of schema version 1 in bytes.
+pub const FILE_HEADER_LEN: usize = 16;
+
+/// The length of the record header of schema version 1 in bytes.
+pub const RECORD_HEADER_LEN: usize = 40;
+
+/// The kind of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    /// A log record.
+    Log,
+
+    /// Records were dropped; `Record::sequence` holds the number of dropped records.
+    Dropped,
+
+    /// A kind introduced by a newer schema version.
+    Unknown(u8),
+}
+
+impl From<u8> for RecordKind {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Log,
+            2 => Self::Dropped,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// The severity of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<u8> for Level {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Error,
+            2 => Self::Warn,
+            3 => Self::Info,
+            4 => Self::Debug,
+            _ => Self::Trace,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        };
+
+        f.pad(name)
+    }
+}
+
+/// The file header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    /// The schema version the stream was written with.
+    pub version: u16,
+}
+
+/// A decoded record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub kind: RecordKind,
+    pub level: Level,
+    pub sequence: u64,
+    pub cpu: u32,
+    pub host_tsc: u64,
+    pub guest_tsc: u64,
+    pub message: String,
+}
+
+/// An event read from the stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A file header, which may appear again if the stream was restarted.
+    Header(FileHeader),
+
+    /// A record.
+    Record(Record),
+
+    /// Bytes not belonging to any header or record were skipped.
+    Skipped(usize),
+}
+
+/// An error while parsing the stream.
+#[derive(Debug)]
+pub enum ParseError {
+    /// Reading the underlying stream failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "I/O error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// Reads events from a telemetry stream.
+pub struct Reader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Reader<R> {
+    /// Creates a reader over the given stream.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Reads the next event.
+    ///
+    /// # Returns
+    ///
+    /// The next event, or `None` at the end of the stream. A truncated record at the end of the
+    /// stream is reported as skipped bytes.
+    pub fn next_event(&mut self) -> Result<Option<Event>, ParseError> {
+        loop {
+            if self.buffer.is_empty() && !self.fill(1)? {
+                return Ok(None);
+            }
+
+            // Skip ahead to the next magic, keeping a partial magic at the end of the buffer.
+            match find_magic(&self.buffer) {
+                Some(0) => {}
+                Some(offset) => return Ok(Some(self.skip(offset))),
+                None if self.eof => {
+                    let len = self.buffer.len();
+                    return Ok(Some(self.skip(len)));
+                }
+                None => {
+                    let keep = self.buffer.len().min(FILE_MAGIC.len() - 1);
+                    let skipped = self.buffer.len() - keep;
+                    self.fill(self.buffer.len() + 1)?;
+                    if skipped > 0 {
+                        return Ok(Some(self.skip(skipped)));
+                    }
+                    continue;
+                }
+            }
+
+            if self.buffer.starts_with(&FILE_MAGIC) {
+                return self.parse_file_header();
+            }
+
+            return self.parse_record();
+        }
+    }
+
+    /// Parses the file header at the start of the buffer.
+    fn parse_file_header(&mut self) -> Result<Option<Event>, ParseError> {
+        if !self.fill(FILE_HEADER_LEN)? {
+            let len = self.buffer.len();
+            return Ok(Some(self.skip(len)));
+        }
+
+        let version = u16::from_le_bytes([self.buffer[4], self.buffer[5]]);
+        let header_len = u16::from_le_bytes([self.buffer[6], self.buffer[7]]) as usize;
+
+        // A newer version or a corrupt length most likely means the magic was part of a message, as
+        // the stream is only ever written with one version; skip it and resynchronize.
+        if version > SCHEMA_VERSION || header_len < FILE_HEADER_LEN {
+            return Ok(Some(self.skip(FILE_MAGIC.len())));
+        }
+
+        if !self.fill(header_len)? {
+            let len = self.buffer.len();
+            return Ok(Some(self.skip(len)));
+        }
+
+        self.buffer.drain(..header_len);
+
+        Ok(Some(Event::Header(FileHeader { version })))
+    }
+
+    /// Parses the record at the start of the buffer.
+    fn parse_record(&mut self) -> Result<Option<Event>, ParseError> {
+        if !self.fill(RECORD_HEADER_LEN)? {
+            let len = self.buffer.len();
+            return Ok(Some(self.skip(len)));
+        }
+
+        let b = &self.buffer;
+        let record_len = u16::from_le_bytes([b[4], b[5]]) as usize;
+
+        // A corrupt length most likely means the magic was part of a message; skip it and resynchronize.
+        if record_len < RECORD_HEADER_LEN {
+            return Ok(Some(self.skip(RECORD_MAGIC.len())));
+        }
+
+        if !self.fill(record_len)? {
+            let len = self.buffer.len();
+            return Ok(Some(self.skip(len)));
+        }
+
+        let b = &self.buffer;
+        let record = Record {
+            kind: RecordKind::from(b[6]),
+            level: Level::from(b[7]),
+            sequence: u64_at(b, 8),
+            cpu: u32_at(b, 16),
+            host_tsc: u64_at(b, 24),
+            guest_tsc: u64_at(b, 32),
+            message: String::from_utf8_lossy(&b[RECORD_HEADER_LEN..record_len]).into_owned(),
+        };
+
+        self.buffer.drain(..record_len);
+
+        Ok(Some(Event::Record(record)))
+    }
+
+    /// Removes `len` bytes from the front of the buffer.
+    fn skip(&mut self, len: usize) -> Event {
+        self.buffer.drain(..len);
+        Event::Skipped(len)
+    }
+
+    /// Reads until the buffer holds at least `len` bytes.
+    ///
+    /// # Returns
+    ///
+    /// Whether the buffer holds `len` bytes, i.e. `false` if the stream ended first.
+    fn fill(&mut self, len: usize) -> Result<bool, ParseError> {
+        let mut chunk = [0u8; 4096];
+
+        while self.buffer.len() < len && !self.eof {
+            match self.inner.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(self.buffer.len() >= len)
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Event, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+/// Returns the offset of the first file or record magic in the buffer.
+fn find_magic(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(RECORD_MAGIC.len())
+        .position(|window| window == RECORD_MAGIC || window == FILE_MAGIC)
+}
+
+fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buffer: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buffer[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        hypervisor_core::telemetry::{self, encode_file_header, RecordHeader},
+    };
+
+    /// Encodes a record, as the hypervisor writes it.
+    fn record(kind: telemetry::RecordKind, sequence: u64, message: &str) -> Vec<u8> {
+        let header = RecordHeader {
+            kind,
+            level: 3,
+            sequence,
+            cpu: 2,
+            host_tsc: 0x1000 + sequence,
+            guest_tsc: 0x2000 + sequence,
+        };
+
+        let mut buffer = [0u8; 256];
+        header.encode(message.as_bytes(), &mut buffer).to_vec()
+    }
+
+    fn events(bytes: &[u8]) -> Vec<Event> {
+        Reader::new(bytes).collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = encode_file_header().to_vec();
+        bytes.extend(record(telemetry::RecordKind::Log, 1, "VM exit"));
+        bytes.extend(record(telemetry::RecordKind::Dropped, 7, ""));
+
+        assert_eq!(
+            events(&bytes),
+            [
+                Event::Header(FileHeader {
+                    version: SCHEMA_VERSION
+                }),
+                Event::Record(Record {
+                    kind: RecordKind::Log,
+                    level: Level::Info,
+                    sequence: 1,
+                    cpu: 2,
+                    host_tsc: 0x1001,
+                    guest_tsc: 0x2001,
+                    message: "VM exit".into(),
+                }),
+                Event::Record(Record {
+                    kind: RecordKind::Dropped,
+                    level: Level::Info,
+                    sequence: 7,
+                    cpu: 2,
+                    host_tsc: 0x1007,
+                    guest_tsc: 0x2007,
+                    message: String::new(),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn resynchronizes_on_garbage() {
+        let mut bytes = b"garbage".to_vec();
+        bytes.extend(record(telemetry::RecordKind::Log, 1, "first"));
+        // A file magic with a version from the future and one with a corrupt length.
+        bytes.extend(b"MVTL\xff\xff\x10\x00");
+        bytes.extend(b"MVTL\x01\x00\x02\x00");
+        bytes.extend([0u8; 8]);
+        bytes.extend(record(telemetry::RecordKind::Log, 2, "second"));
+
+        let events = events(&bytes);
+        let sequences: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Record(record) => Some(record.sequence),
+                _ => None,
+            })
+            .collect();
+        let skipped: usize = events
+            .iter()
+            .map(|event| match event {
+                Event::Skipped(len) => *len,
+                _ => 0,
+            })
+            .sum();
+
+        assert_eq!(sequences, [1, 2]);
+        assert_eq!(skipped, 7 + 8 + 16);
+    }
+
+    #[test]
+    fn reports_truncated_tail_as_skipped() {
+        let mut bytes = record(telemetry::RecordKind::Log, 1, "complete");
+        let truncated = record(telemetry::RecordKind::Log, 2, "truncated");
+        bytes.extend(&truncated[..truncated.len() - 3]);
+
+        let events = events(&bytes);
+
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], Event::Record(record) if record.sequence == 1));
+        assert_eq!(events[1], Event::Skipped(truncated.len() - 3));
+    }
+}
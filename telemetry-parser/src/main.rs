@@ -0,0 +1,82 @@
+//! Converts a hypervisor telemetry capture to CSV.
+//!
+//! Usage: `telemetry-parser [--symbols <map>]... [<capture>]`, reading from stdin if no capture is given.
+//! Guest addresses in the messages are symbolized with the given symbol maps.
+
+use {
+    std::{
+        env, fs,
+        io::{self, BufWriter, Read, Write},
+        process::ExitCode,
+    },
+    telemetry_parser::{symbols::SymbolMap, Event, Reader},
+};
+
+fn main() -> ExitCode {
+    let mut symbols = SymbolMap::new();
+    let mut capture = None;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg != "--symbols" {
+            capture = Some(arg);
+            continue;
+        }
+
+        let Some(path) = args.next() else {
+            eprintln!("Missing path after --symbols");
+            return ExitCode::FAILURE;
+        };
+
+        let loaded = fs::File::open(&path)
+            .map_err(Into::into)
+            .and_then(|file| symbols.load(io::BufReader::new(file)));
+
+        if let Err(error) = loaded {
+            eprintln!("Failed to load symbols from {}: {}", path, error);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let input: Box<dyn Read> = match capture {
+        Some(path) => match fs::File::open(&path) {
+            Ok(file) => Box::new(file),
+            Err(error) => {
+                eprintln!("Failed to open {}: {}", path, error);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(io::stdin().lock()),
+    };
+
+    let mut output = BufWriter::new(io::stdout().lock());
+    let _ = writeln!(output, "sequence,cpu,level,kind,host_tsc,guest_tsc,message");
+
+    for event in Reader::new(io::BufReader::new(input)) {
+        match event {
+            Ok(Event::Header(header)) => eprintln!("Schema version {}", header.version),
+            Ok(Event::Skipped(len)) => eprintln!("Skipped {} bytes", len),
+            Ok(Event::Record(record)) => {
+                let _ = writeln!(
+                    output,
+                    "{},{},{},{:?},{:#x},{:#x},\"{}\"",
+                    record.sequence,
+                    record.cpu,
+                    record.level,
+                    record.kind,
+                    record.host_tsc,
+                    record.guest_tsc,
+                    symbols
+                        .symbolize_message(&record.message)
+                        .replace('"', "\"\"")
+                );
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,215 @@
+//! Symbolization of guest addresses in event messages.
+//!
+//! The hypervisor logs raw guest addresses (e.g. RIPs). Given address to symbol maps for the guest
+//! modules, this module rewrites them as `module!symbol+offset`, e.g. `ntoskrnl!NtCreateFile+0x12`.
+//!
+//! Maps are loaded from a simple text format, one file per module or several modules per file:
+//!
+//! ```text
+//! # Comments and empty lines are ignored.
+//! module ntoskrnl 0xfffff80012200000 0xa00000
+//! 0x1f2a0 NtCreateFile
+//! 0x1f4c0 NtOpenFile
+//! ```
+//!
+//! A `module` line gives the name, the load base and the size of a module; the following lines give the
+//! RVA and the name of its symbols.
+
+use std::{
+    fmt,
+    io::{self, BufRead},
+};
+
+/// A symbol within a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Symbol {
+    rva: u64,
+    name: String,
+}
+
+/// A loaded guest module and its symbols.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+    symbols: Vec<Symbol>,
+}
+
+impl Module {
+    /// Creates a module without symbols.
+    pub fn new(name: &str, base: u64, size: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            base,
+            size,
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Adds a symbol by its RVA.
+    pub fn add_symbol(&mut self, rva: u64, name: &str) {
+        let index = self.symbols.partition_point(|symbol| symbol.rva <= rva);
+        self.symbols.insert(
+            index,
+            Symbol {
+                rva,
+                name: name.to_string(),
+            },
+        );
+    }
+
+    /// Returns whether the address lies within the module.
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.base && address - self.base < self.size
+    }
+}
+
+/// An error while loading a symbol map.
+#[derive(Debug)]
+pub enum SymbolMapError {
+    /// Reading the map failed.
+    Io(io::Error),
+
+    /// A line could not be parsed.
+    InvalidLine(usize),
+
+    /// A symbol line appeared before the first `module` line.
+    SymbolOutsideModule(usize),
+}
+
+impl fmt::Display for SymbolMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "I/O error: {}", error),
+            Self::InvalidLine(line) => write!(f, "invalid symbol map line {}", line),
+            Self::SymbolOutsideModule(line) => {
+                write!(f, "symbol outside of a module on line {}", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SymbolMapError {}
+
+impl From<io::Error> for SymbolMapError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// The address to symbol maps of all known guest modules.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolMap {
+    modules: Vec<Module>,
+}
+
+impl SymbolMap {
+    /// Creates an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a module, replacing a previously added module of the same name.
+    pub fn add_module(&mut self, module: Module) {
+        self.modules.retain(|m| m.name != module.name);
+        self.modules.push(module);
+    }
+
+    /// Loads the modules from a map in the text format described above.
+    pub fn load(&mut self, reader: impl BufRead) -> Result<(), SymbolMapError> {
+        let mut current: Option<Module> = None;
+
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line_number = index + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+
+            if line.starts_with("module ") {
+                fields.next();
+
+                let (Some(name), Some(base), Some(size)) = (
+                    fields.next(),
+                    fields.next().and_then(parse_hex),
+                    fields.next().and_then(parse_hex),
+                ) else {
+                    return Err(SymbolMapError::InvalidLine(line_number));
+                };
+
+                if let Some(module) = current.replace(Module::new(name, base, size)) {
+                    self.add_module(module);
+                }
+
+                continue;
+            }
+
+            let (Some(rva), Some(name)) = (fields.next().and_then(parse_hex), fields.next()) else {
+                return Err(SymbolMapError::InvalidLine(line_number));
+            };
+
+            current
+                .as_mut()
+                .ok_or(SymbolMapError::SymbolOutsideModule(line_number))?
+                .add_symbol(rva, name);
+        }
+
+        if let Some(module) = current {
+            self.add_module(module);
+        }
+
+        Ok(())
+    }
+
+    /// Resolves an address to `module!symbol+offset`, or `module+offset` if no symbol precedes it.
+    pub fn symbolize(&self, address: u64) -> Option<String> {
+        let module = self.modules.iter().find(|m| m.contains(address))?;
+        let rva = address - module.base;
+
+        let index = module.symbols.partition_point(|symbol| symbol.rva <= rva);
+        let symbolized = match index.checked_sub(1).map(|i| &module.symbols[i]) {
+            Some(symbol) if rva == symbol.rva => format!("{}!{}", module.name, symbol.name),
+            Some(symbol) => format!("{}!{}+{:#x}", module.name, symbol.name, rva - symbol.rva),
+            None => format!("{}+{:#x}", module.name, rva),
+        };
+
+        Some(symbolized)
+    }
+
+    /// Rewrites every hexadecimal address (`0x...`) in a message that falls within a known module.
+    pub fn symbolize_message(&self, message: &str) -> String {
+        let mut output = String::with_capacity(message.len());
+        let mut rest = message;
+
+        while let Some(start) = rest.find("0x") {
+            let (before, candidate) = rest.split_at(start);
+            output.push_str(before);
+
+            let digits = candidate[2..]
+                .find(|c: char| !c.is_ascii_hexdigit())
+                .unwrap_or(candidate.len() - 2);
+            let (token, after) = candidate.split_at(2 + digits);
+
+            match parse_hex(token).and_then(|address| self.symbolize(address)) {
+                Some(symbol) => output.push_str(&symbol),
+                None => output.push_str(token),
+            }
+
+            rest = after;
+        }
+
+        output.push_str(rest);
+        output
+    }
+}
+
+/// Parses a hexadecimal number with an optional `0x` prefix.
+fn parse_hex(value: &str) -> Option<u64> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    u64::from_str_radix(digits, 16).ok()
+}
@@ -0,0 +1,16 @@
+@echo off
+rem Guest-side test runner, copied to the virtual FAT drive by `cargo xtask test`.
+rem
+rem The Windows image must be prepared once:
+rem   - test signing enabled: bcdedit.exe /set testsigning on
+rem   - automatic logon and a scheduled task running this script from the virtual drive at logon.
+rem
+rem COM1 receives the guest assertion results; the hypervisor logs to COM2.
+
+copy /Y "%~dp0matrix.sys" C:\Windows\System32\drivers\matrix.sys
+sc.exe create matrix type= kernel binPath= C:\Windows\System32\drivers\matrix.sys
+sc.exe start matrix
+
+"%~dp0guest-tests.exe" > COM1
+
+shutdown /s /t 0
@@ -0,0 +1,54 @@
+//! Development tasks for the hypervisor, run via `cargo xtask <task>`.
+//!
+//! # Tasks
+//!
+//! * `test` - Boots a Windows image in QEMU/KVM with nested VMX, loads the hypervisor driver,
+//!   runs the guest assertion binary (`guest-tests`) and reports the results.
+//!
+//! # Requirements
+//!
+//! * A Linux host with KVM and nested VMX enabled (`kvm_intel nested=1`).
+//! * `qemu-system-x86_64`.
+//! * A prepared Windows image, see `run_tests.cmd` for the expected guest setup.
+
+mod qemu;
+
+use std::{env, process::ExitCode};
+
+fn usage() {
+    eprintln!(
+        "Usage: cargo xtask test --image <windows.qcow2> [--driver <matrix.sys>] [--guest <guest-tests.exe>] [--qemu <binary>] [--timeout <seconds>] [--out <directory>]"
+    );
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+
+    match args.next().as_deref() {
+        Some("test") => match qemu::TestConfig::from_args(args) {
+            Ok(config) => match qemu::run(&config) {
+                Ok(report) => {
+                    println!("{}", report);
+                    if report.success() {
+                        ExitCode::SUCCESS
+                    } else {
+                        ExitCode::FAILURE
+                    }
+                }
+                Err(err) => {
+                    eprintln!("error: {}", err);
+                    ExitCode::FAILURE
+                }
+            },
+            Err(err) => {
+                eprintln!("error: {}", err);
+                usage();
+                ExitCode::FAILURE
+            }
+        },
+        _ => {
+            usage();
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! Boots the hypervisor inside QEMU/KVM and collects the integration test results.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Log lines the hypervisor must emit during a successful run.
+const HYPERVISOR_EXPECTATIONS: [(&str, &str); 3] = [
+    ("hypervisor_virtualized", "Virtualized system successfully!"),
+    ("mm_is_address_valid_hook", "MmIsAddressValid called from"),
+    ("nt_create_file_hook", "NtCreateFile called from"),
+];
+
+/// The configuration of an integration test run.
+pub struct TestConfig {
+    /// The prepared Windows image.
+    pub image: PathBuf,
+
+    /// The signed hypervisor driver.
+    pub driver: PathBuf,
+
+    /// The guest assertion binary.
+    pub guest: PathBuf,
+
+    /// The QEMU binary.
+    pub qemu: String,
+
+    /// How long the guest is allowed to run before it is killed.
+    pub timeout: Duration,
+
+    /// The directory receiving the staged files and the serial logs.
+    pub out: PathBuf,
+}
+
+impl TestConfig {
+    /// Parses the configuration from the command line arguments.
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut config = Self {
+            image: std::env::var_os("XTASK_WINDOWS_IMAGE")
+                .map(PathBuf::from)
+                .unwrap_or_default(),
+            driver: PathBuf::from("target/debug/package/matrix.sys"),
+            guest: PathBuf::from("target/x86_64-pc-windows-gnu/release/guest-tests.exe"),
+            qemu: String::from("qemu-system-x86_64"),
+            timeout: Duration::from_secs(600),
+            out: PathBuf::from("target/xtask"),
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value for {}", arg));
+
+            match arg.as_str() {
+                "--image" => config.image = PathBuf::from(value()?),
+                "--driver" => config.driver = PathBuf::from(value()?),
+                "--guest" => config.guest = PathBuf::from(value()?),
+                "--qemu" => config.qemu = value()?,
+                "--timeout" => {
+                    let seconds = value()?
+                        .parse()
+                        .map_err(|_| String::from("invalid timeout"))?;
+                    config.timeout = Duration::from_secs(seconds);
+                }
+                "--out" => config.out = PathBuf::from(value()?),
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+
+        if config.image.as_os_str().is_empty() {
+            return Err(String::from(
+                "no Windows image given (--image or XTASK_WINDOWS_IMAGE)",
+            ));
+        }
+
+        for path in [&config.image, &config.driver, &config.guest] {
+            if !path.exists() {
+                return Err(format!("{} does not exist", path.display()));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// The result of a single check.
+pub struct TestResult {
+    pub name: String,
+    pub passed: bool,
+    pub details: String,
+}
+
+/// The results of an integration test run.
+pub struct Report {
+    pub results: Vec<TestResult>,
+}
+
+impl Report {
+    /// Returns whether every check passed.
+    pub fn success(&self) -> bool {
+        !self.results.is_empty() && self.results.iter().all(|r| r.passed)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in self.results.iter() {
+            let status = if result.passed { "ok" } else { "FAILED" };
+            writeln!(f, "test {} ... {} {}", result.name, status, result.details)?;
+        }
+
+        let passed = self.results.iter().filter(|r| r.passed).count();
+        write!(
+            f,
+            "\ntest result: {}. {} passed; {} failed",
+            if self.success() { "ok" } else { "FAILED" },
+            passed,
+            self.results.len() - passed
+        )
+    }
+}
+
+/// Copies the driver, the guest assertion binary and the runner script to the virtual FAT drive.
+fn stage(config: &TestConfig, stage_dir: &Path) -> Result<(), String> {
+    let _ = fs::remove_dir_all(stage_dir);
+    fs::create_dir_all(stage_dir).map_err(|e| e.to_string())?;
+
+    let runner = Path::new(env!("CARGO_MANIFEST_DIR")).join("run_tests.cmd");
+
+    for (from, to) in [
+        (config.driver.as_path(), "matrix.sys"),
+        (config.guest.as_path(), "guest-tests.exe"),
+        (runner.as_path(), "run_tests.cmd"),
+    ] {
+        fs::copy(from, stage_dir.join(to))
+            .map_err(|e| format!("failed to stage {}: {}", from.display(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Boots the guest and runs the integration tests.
+pub fn run(config: &TestConfig) -> Result<Report, String> {
+    let stage_dir = config.out.join("stage");
+    let guest_log = config.out.join("guest.log");
+    let hypervisor_log = config.out.join("hypervisor.log");
+    let early_log = config.out.join("early.log");
+
+    stage(config, &stage_dir)?;
+
+    let _ = fs::remove_file(&guest_log);
+    let _ = fs::remove_file(&hypervisor_log);
+    let _ = fs::remove_file(&early_log);
+
+    println!("Booting {} with nested VMX...", config.image.display());
+
+    // COM1 receives the guest assertion results, COM2 (0x2f8) the hypervisor log and the debug port (0xE9)
+    // the early console, which reports the failures happening before the logger is up.
+    let mut child = Command::new(&config.qemu)
+        .args([
+            "-machine",
+            "q35,accel=kvm",
+            "-cpu",
+            "host,+vmx",
+            "-smp",
+            "2",
+            "-m",
+            "4096",
+        ])
+        .args(["-display", "none", "-no-reboot", "-snapshot"])
+        .arg("-drive")
+        .arg(format!("file={},if=ide", config.image.display()))
+        .arg("-drive")
+        .arg(format!(
+            "file=fat:{},format=raw,if=ide",
+            stage_dir.display()
+        ))
+        .arg("-serial")
+        .arg(format!("file:{}", guest_log.display()))
+        .arg("-serial")
+        .arg(format!("file:{}", hypervisor_log.display()))
+        .arg("-debugcon")
+        .arg(format!("file:{}", early_log.display()))
+        .spawn()
+        .map_err(|e| format!("failed to start {}: {}", config.qemu, e))?;
+
+    let start = Instant::now();
+    let mut timed_out = false;
+
+    while child.try_wait().map_err(|e| e.to_string())?.is_none() {
+        if start.elapsed() > config.timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            timed_out = true;
+            break;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    let guest_output = fs::read_to_string(&guest_log).unwrap_or_default();
+    let hypervisor_output = fs::read_to_string(&hypervisor_log).unwrap_or_default();
+    let early_output = fs::read_to_string(&early_log).unwrap_or_default();
+
+    if !early_output.is_empty() {
+        println!("Early console output:\n{}", early_output);
+    }
+
+    let mut results = parse_guest_output(&guest_output);
+
+    for (name, needle) in HYPERVISOR_EXPECTATIONS {
+        results.push(TestResult {
+            name: String::from(name),
+            passed: hypervisor_output.contains(needle),
+            details: String::new(),
+        });
+    }
+
+    if timed_out {
+        results.push(TestResult {
+            name: String::from("guest_shutdown"),
+            passed: false,
+            details: format!("timed out after {:?}", config.timeout),
+        });
+    }
+
+    Ok(Report { results })
+}
+
+/// Parses the `TEST <name> PASS|FAIL [details]` lines printed by the guest assertion binary.
+fn parse_guest_output(output: &str) -> Vec<TestResult> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().splitn(4, ' ');
+
+            if parts.next()? != "TEST" {
+                return None;
+            }
+
+            let name = parts.next()?;
+            let passed = parts.next()? == "PASS";
+            let details = parts.next().unwrap_or_default();
+
+            Some(TestResult {
+                name: String::from(name),
+                passed,
+                details: String::from(details),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the arguments of `TestConfig::from_args`.
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| String::from(*arg))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn result(name: &str, passed: bool) -> TestResult {
+        TestResult {
+            name: String::from(name),
+            passed,
+            details: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_guest_results() {
+        let output = "Loading driver...\r\n\
+                      TEST cpuid_hidden PASS\r\n\
+                      TEST hypercall_version FAIL got 0x0 expected 0x1\r\n\
+                      TESTING something else\r\n\
+                      TEST truncated\r\n";
+
+        let results = parse_guest_output(output);
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "cpuid_hidden");
+        assert!(results[0].passed);
+        assert!(results[0].details.is_empty());
+        assert_eq!(results[1].name, "hypercall_version");
+        assert!(!results[1].passed);
+        assert_eq!(results[1].details, "got 0x0 expected 0x1");
+    }
+
+    #[test]
+    fn report_succeeds_only_if_every_check_passed() {
+        assert!(!Report { results: vec![] }.success());
+
+        let passed = Report {
+            results: vec![result("a", true), result("b", true)],
+        };
+        assert!(passed.success());
+
+        let failed = Report {
+            results: vec![result("a", true), result("b", false)],
+        };
+        assert!(!failed.success());
+        assert!(failed
+            .to_string()
+            .ends_with("test result: FAILED. 1 passed; 1 failed"));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert_eq!(
+            TestConfig::from_args(args(&["--image", "a.qcow2", "--bogus"])).err(),
+            Some(String::from("unknown argument --bogus"))
+        );
+        assert_eq!(
+            TestConfig::from_args(args(&["--image"])).err(),
+            Some(String::from("missing value for --image"))
+        );
+        assert_eq!(
+            TestConfig::from_args(args(&["--timeout", "soon"])).err(),
+            Some(String::from("invalid timeout"))
+        );
+    }
+}